@@ -16,6 +16,48 @@ fn main() {
     // Fix for "environment variable `TARGET` not defined at compile time"
     println!("cargo:rustc-env=TARGET={}", target);
 
+    // Commit the kernel was built from, reported in sys:uname
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    // HEAD usually names a branch, whose ref moves on commit while HEAD itself stays the same.
+    // A branch packed by git gc has no file of its own but a line in packed-refs. Missing files
+    // are not watched, as cargo would rerun the script on every build for them.
+    let head_ref = fs::read_to_string(".git/HEAD")
+        .ok()
+        .and_then(|head| Some(format!(".git/{}", head.strip_prefix("ref: ")?.trim())));
+    if let Some(head_ref) = head_ref.filter(|path| Path::new(path).exists()) {
+        println!("cargo:rerun-if-changed={}", head_ref);
+    }
+    if Path::new(".git/packed-refs").exists() {
+        println!("cargo:rerun-if-changed=.git/packed-refs");
+    }
+    let git_hash = std::process::Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .filter(|hash| !hash.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash);
+
+    // Kernel symbol table embedded by the ksyms feature, from the symbols of a previous link
+    if env::var_os("CARGO_FEATURE_KSYMS").is_some() {
+        println!("cargo:rerun-if-env-changed=KSYMS_FILE");
+        let listing = match env::var("KSYMS_FILE") {
+            Ok(path) => {
+                println!("cargo:rerun-if-changed={}", path);
+                fs::read_to_string(&path)
+                    .unwrap_or_else(|err| panic!("Failed to read KSYMS_FILE {}: {}", path, err))
+            }
+            Err(_) => {
+                println!("cargo:warning=KSYMS_FILE not set, sys:ksyms will be empty");
+                String::new()
+            }
+        };
+        fs::write(out_path.join("ksyms.bin"), ksyms_table(&listing)).unwrap();
+    }
+
     // Config parsing
     let config_path = Path::new("config.toml");
     let config_example_path = Path::new("config.toml.example");
@@ -73,4 +115,39 @@ fn main() {
 
     println!("cargo:rustc-link-search={}", out_dir);
     println!("cargo:rustc-link-arg=-T{}", linker_script);
-}
\ No newline at end of file
+}
+/// Build the table read by src/ksyms.rs out of the output of `nm -n -S --defined-only`: the magic
+/// "KSYM", the number of symbols as a u32, an entry of address (u64), size (u32) and name offset
+/// (u32) per symbol sorted by address, then the names back to back, all little endian. Only
+/// functions with a size are kept, and the first of several at the same address.
+fn ksyms_table(listing: &str) -> Vec<u8> {
+    let mut symbols: Vec<(u64, u32, &str)> = listing
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let addr = u64::from_str_radix(fields.next()?, 16).ok()?;
+            let size = u64::from_str_radix(fields.next()?, 16).ok()?;
+            let kind = fields.next()?;
+            let name = fields.next()?;
+            let is_function = matches!(kind, "t" | "T" | "w" | "W");
+            (is_function && size > 0).then(|| (addr, u32::try_from(size).unwrap_or(u32::MAX), name))
+        })
+        .collect();
+    symbols.sort_by_key(|&(addr, _, _)| addr);
+    symbols.dedup_by_key(|&mut (addr, _, _)| addr);
+
+    let count = u32::try_from(symbols.len()).expect("too many kernel symbols");
+    let mut table = Vec::new();
+    table.extend_from_slice(b"KSYM");
+    table.extend_from_slice(&count.to_le_bytes());
+    let mut names = Vec::new();
+    for &(addr, size, name) in &symbols {
+        let offset = u32::try_from(names.len()).expect("kernel symbol names too long");
+        table.extend_from_slice(&addr.to_le_bytes());
+        table.extend_from_slice(&size.to_le_bytes());
+        table.extend_from_slice(&offset.to_le_bytes());
+        names.extend_from_slice(name.as_bytes());
+    }
+    table.extend_from_slice(&names);
+    table
+}
@@ -0,0 +1,77 @@
+//! # IVRS
+//! The I/O Virtualization Reporting Structure describes the AMD IOMMUs of the platform
+
+use core::slice;
+
+use super::{find_sdt, sdt::Sdt};
+
+/// Length of the IVRS header: the SDT header, the IVinfo field and 8 reserved bytes
+const IVRS_HEADER_LEN: usize = 48;
+
+/// IVHD block types, which each describe one IOMMU
+const IVHD_TYPES: [u8; 3] = [0x10, 0x11, 0x40];
+
+/// Physical base address of the first IOMMU's MMIO registers, if an IVRS table describes one
+pub fn get_iommu_base() -> Option<usize> {
+    let ivrs: &'static Sdt = find_sdt("IVRS").into_iter().next()?;
+    let bytes =
+        unsafe { slice::from_raw_parts((ivrs as *const Sdt).cast::<u8>(), ivrs.length as usize) };
+    iommu_base(bytes)
+}
+
+fn iommu_base(ivrs: &[u8]) -> Option<usize> {
+    let mut blocks = ivrs.get(IVRS_HEADER_LEN..)?;
+    while blocks.len() >= 4 {
+        let kind = blocks[0];
+        let len = u16::from_le_bytes([blocks[2], blocks[3]]) as usize;
+        if len < 4 || len > blocks.len() {
+            return None;
+        }
+        if IVHD_TYPES.contains(&kind) {
+            let base = blocks.get(8..16)?;
+            return Some(u64::from_le_bytes(base.try_into().ok()?) as usize);
+        }
+        blocks = &blocks[len..];
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn ivrs(blocks: &[&[u8]]) -> Vec<u8> {
+        let mut table = alloc::vec![0; IVRS_HEADER_LEN];
+        for block in blocks {
+            table.extend_from_slice(block);
+        }
+        table
+    }
+
+    fn block(kind: u8, len: u16, base: u64) -> Vec<u8> {
+        let mut block = alloc::vec![kind, 0];
+        block.extend_from_slice(&len.to_le_bytes());
+        block.extend_from_slice(&[0; 4]);
+        block.extend_from_slice(&base.to_le_bytes());
+        block.resize(len as usize, 0);
+        block
+    }
+
+    #[test]
+    fn test_iommu_base_skips_other_blocks() {
+        let ivmd = block(0x20, 32, 0x1234);
+        let ivhd = block(0x11, 40, 0xfeb8_0000);
+        assert_eq!(iommu_base(&ivrs(&[&ivmd, &ivhd])), Some(0xfeb8_0000));
+    }
+
+    #[test]
+    fn test_iommu_base_malformed() {
+        assert_eq!(iommu_base(&[0; 16]), None);
+        assert_eq!(iommu_base(&ivrs(&[&block(0x20, 32, 0)])), None);
+        // Block claiming to be longer than the table
+        let mut truncated = block(0x10, 24, 0xfeb8_0000);
+        truncated[2] = 200;
+        assert_eq!(iommu_base(&ivrs(&[&truncated])), None);
+    }
+}
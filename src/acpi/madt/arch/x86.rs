@@ -82,6 +82,7 @@ pub(super) fn init(madt: Madt) {
                 let idt_ptr = crate::arch::idt::allocate_and_init_idt(cpu_id);
 
                 let args = KernelArgsAp {
+                    stack_start: stack_start as *mut u8,
                     stack_end: stack_end as *mut u8,
                     cpu_id,
                     pcr_ptr,
@@ -7,8 +7,11 @@ use hashbrown::HashMap;
 use spin::{Once, RwLock};
 
 use crate::{
-    memory::KernelMapper,
-    paging::{PageFlags, PhysicalAddress, RmmA, RmmArch},
+    memory::{
+        iomap::{CacheMode, IoMapFlags, IoMapping},
+        KernelMapper,
+    },
+    paging::PhysicalAddress,
 };
 
 use self::{hpet::Hpet, madt::Madt, rsdp::Rsdp, rsdt::Rsdt, rxsdt::Rxsdt, sdt::Sdt, xsdt::Xsdt};
@@ -16,54 +19,34 @@ use self::{hpet::Hpet, madt::Madt, rsdp::Rsdp, rsdt::Rsdt, rxsdt::Rxsdt, sdt::Sd
 #[cfg(target_arch = "aarch64")]
 mod gtdt;
 pub mod hpet;
+pub mod ivrs;
 pub mod madt;
 mod rsdp;
 mod rsdt;
 mod rxsdt;
 pub mod sdt;
+mod slit;
 #[cfg(target_arch = "aarch64")]
 mod spcr;
+mod srat;
 mod xsdt;
 
-unsafe fn map_linearly(addr: PhysicalAddress, len: usize, mapper: &mut crate::paging::PageMapper) {
-    unsafe {
-        let base = PhysicalAddress::new(crate::paging::round_down_pages(addr.data()));
-        let aligned_len = crate::paging::round_up_pages(len + (addr.data() - base.data()));
-
-        for page_idx in 0..aligned_len / crate::memory::PAGE_SIZE {
-            let (_, flush) = mapper
-                .map_linearly(
-                    base.add(page_idx * crate::memory::PAGE_SIZE),
-                    PageFlags::new(),
-                )
-                .expect("failed to linearly map SDT");
-            flush.flush();
-        }
-    }
-}
-
-pub fn get_sdt(sdt_address: usize, mapper: &mut KernelMapper) -> &'static Sdt {
-    let mapper = mapper
-        .get_mut()
-        .expect("KernelMapper mapper locked re-entrant in get_sdt");
-
+/// Map the SDT at `sdt_address` for the rest of the kernel's life
+pub fn get_sdt(sdt_address: usize) -> &'static Sdt {
+    const SDT_SIZE: usize = core::mem::size_of::<Sdt>();
     let physaddr = PhysicalAddress::new(sdt_address);
-
-    let sdt;
-
-    unsafe {
-        const SDT_SIZE: usize = core::mem::size_of::<Sdt>();
-        map_linearly(physaddr, SDT_SIZE, mapper);
-
-        sdt = &*(RmmA::phys_to_virt(physaddr).data() as *const Sdt);
-
-        map_linearly(
-            physaddr.add(SDT_SIZE),
-            sdt.length as usize - SDT_SIZE,
-            mapper,
-        );
-    }
-    sdt
+    let map = |len| {
+        IoMapping::with_flags(physaddr, len, CacheMode::WriteBack, IoMapFlags::READ_ONLY)
+            .expect("failed to map SDT")
+    };
+
+    // The header tells how much of the table to map
+    let header = map(SDT_SIZE);
+    let length = unsafe { (*header.as_ptr().cast::<Sdt>()).length } as usize;
+    let table = map(length.max(SDT_SIZE));
+    drop(header);
+
+    unsafe { &*table.leak().as_ptr().cast::<Sdt>() }
 }
 
 #[repr(C, packed)]
@@ -104,7 +87,7 @@ pub unsafe fn init(already_supplied_rsdp: Option<*const u8>) {
 
         if let Some(rsdp) = rsdp_opt {
             debug!("SDT address: {:#x}", rsdp.sdt_address());
-            let rxsdt = get_sdt(rsdp.sdt_address(), &mut KernelMapper::lock());
+            let rxsdt = get_sdt(rsdp.sdt_address());
 
             let rxsdt = if let Some(rsdt) = Rsdt::new(rxsdt) {
                 let mut initialized = false;
@@ -141,7 +124,7 @@ pub unsafe fn init(already_supplied_rsdp: Option<*const u8>) {
             // TODO: Don't touch ACPI tables in kernel?
 
             for sdt in rxsdt.iter() {
-                get_sdt(sdt, &mut KernelMapper::lock());
+                get_sdt(sdt);
             }
 
             for sdt_address in rxsdt.iter() {
@@ -155,6 +138,9 @@ pub unsafe fn init(already_supplied_rsdp: Option<*const u8>) {
 
             // TODO: Enumerate processors in userspace, and then provide an ACPI-independent interface
             // to initialize enumerated processors to userspace?
+            srat::init();
+            slit::init();
+            crate::memory::assign_nodes();
             Madt::init();
             //TODO: support this on any arch
             // SPCR must be initialized after MADT for interrupt controllers
@@ -206,6 +192,18 @@ macro_rules! find_one_sdt {
     }};
 }
 
+/// Every table listed in the RSDT or XSDT, in the order listed
+pub fn tables() -> Vec<&'static Sdt> {
+    let Some(rxsdt) = RXSDT_ENUM.get() else {
+        return Vec::new();
+    };
+    rxsdt
+        .iter()
+        // Mapped by `init`
+        .map(|sdt_address| unsafe { &*((sdt_address + crate::PHYS_OFFSET) as *const Sdt) })
+        .collect()
+}
+
 pub fn get_sdt_signature(sdt: &'static Sdt) -> SdtSignature {
     let signature =
         String::from_utf8(sdt.signature.to_vec()).expect("Error converting signature to string");
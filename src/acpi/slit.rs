@@ -0,0 +1,60 @@
+//! # SLIT
+//! The System Locality Information Table gives the relative distance between NUMA proximity
+//! domains, as a matrix with a row per domain
+
+use alloc::vec::Vec;
+use core::slice;
+
+use super::{find_sdt, sdt::Sdt};
+use crate::topology::CPU_TOPOLOGY;
+
+/// Offset of the number of localities, right after the SDT header
+const LOCALITIES_OFFSET: usize = 36;
+const MATRIX_OFFSET: usize = LOCALITIES_OFFSET + 8;
+
+/// Record the distances between NUMA nodes, if a SLIT describes them
+pub fn init() {
+    let Some(slit) = find_sdt("SLIT").into_iter().next() else {
+        return;
+    };
+    let bytes =
+        unsafe { slice::from_raw_parts((slit as *const Sdt).cast::<u8>(), slit.length as usize) };
+
+    match distances(bytes) {
+        Some((localities, distances)) => CPU_TOPOLOGY.set_distances(localities, distances),
+        None => warn!("SLIT is truncated"),
+    }
+}
+
+fn distances(slit: &[u8]) -> Option<(usize, Vec<u8>)> {
+    let localities = slit.get(LOCALITIES_OFFSET..MATRIX_OFFSET)?;
+    let localities = usize::try_from(u64::from_le_bytes(localities.try_into().ok()?)).ok()?;
+    let len = localities.checked_mul(localities)?;
+    let matrix = slit.get(MATRIX_OFFSET..MATRIX_OFFSET.checked_add(len)?)?;
+    Some((localities, matrix.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slit(localities: u64, matrix: &[u8]) -> Vec<u8> {
+        let mut table = alloc::vec![0; LOCALITIES_OFFSET];
+        table.extend_from_slice(&localities.to_le_bytes());
+        table.extend_from_slice(matrix);
+        table
+    }
+
+    #[test]
+    fn parses_matrix() {
+        let table = slit(2, &[10, 21, 21, 10]);
+        assert_eq!(distances(&table), Some((2, alloc::vec![10, 21, 21, 10])));
+    }
+
+    #[test]
+    fn rejects_truncated_matrix() {
+        assert_eq!(distances(&slit(3, &[10, 20, 20, 10])), None);
+        assert_eq!(distances(&slit(u64::MAX, &[])), None);
+        assert_eq!(distances(&[0; 40]), None);
+    }
+}
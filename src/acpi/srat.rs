@@ -0,0 +1,207 @@
+//! # SRAT
+//! The System Resource Affinity Table assigns CPUs and memory ranges to NUMA proximity domains
+
+use alloc::vec::Vec;
+use core::slice;
+
+use super::{find_sdt, sdt::Sdt};
+use crate::topology::{NumaNodeId, CPU_TOPOLOGY};
+
+/// Length of the SRAT header: the SDT header, 4 reserved bytes for compatibility and 8 reserved
+const SRAT_HEADER_LEN: usize = 48;
+
+const LOCAL_APIC_AFFINITY: u8 = 0;
+const MEMORY_AFFINITY: u8 = 1;
+const X2APIC_AFFINITY: u8 = 2;
+
+/// Bit 0 of the flags of each affinity structure, clear for entries to ignore
+const ENABLED: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Affinity {
+    Cpu { apic_id: u32, domain: u32 },
+    Memory { base: u64, length: u64, domain: u32 },
+}
+
+/// Record the NUMA nodes of CPUs and memory, if an SRAT describes them
+pub fn init() {
+    let Some(srat) = find_sdt("SRAT").into_iter().next() else {
+        return;
+    };
+    let bytes =
+        unsafe { slice::from_raw_parts((srat as *const Sdt).cast::<u8>(), srat.length as usize) };
+
+    for affinity in affinities(bytes) {
+        match affinity {
+            Affinity::Cpu { apic_id, domain } => {
+                CPU_TOPOLOGY.set_cpu_node(apic_id, NumaNodeId(domain));
+            }
+            Affinity::Memory {
+                base,
+                length,
+                domain,
+            } => {
+                let end = base.saturating_add(length);
+                debug!("SRAT: memory {:#x}:{:#x} in node {}", base, end, domain);
+                CPU_TOPOLOGY.add_memory(base as usize..end as usize, NumaNodeId(domain));
+            }
+        }
+    }
+}
+
+fn affinities(srat: &[u8]) -> Vec<Affinity> {
+    let mut affinities = Vec::new();
+    let mut entries = srat.get(SRAT_HEADER_LEN..).unwrap_or(&[]);
+    while let [kind, len, ..] = *entries {
+        let len = usize::from(len);
+        let (Some(entry), Some(rest)) = (entries.get(..len), entries.get(len..)) else {
+            break;
+        };
+        if len < 2 {
+            break;
+        }
+        entries = rest;
+        // Malformed entries are skipped like unknown ones
+        affinities.extend(affinity(kind, entry));
+    }
+    affinities
+}
+
+/// The affinity an entry of type `kind` describes, if it is enabled and long enough
+fn affinity(kind: u8, entry: &[u8]) -> Option<Affinity> {
+    let u32_at = |offset: usize| {
+        let bytes = entry.get(offset..offset.checked_add(4)?)?;
+        Some(u32::from_le_bytes(bytes.try_into().ok()?))
+    };
+    let u64_at = |offset: usize| {
+        let bytes = entry.get(offset..offset.checked_add(8)?)?;
+        Some(u64::from_le_bytes(bytes.try_into().ok()?))
+    };
+
+    match kind {
+        LOCAL_APIC_AFFINITY => {
+            // Bits 31:8 of the domain are split off at offset 9
+            let [_, _, low, apic_id, ..] = *entry else {
+                return None;
+            };
+            let domain = (u32_at(8)? & !0xFF) | u32::from(low);
+            (u32_at(4)? & ENABLED != 0).then_some(Affinity::Cpu {
+                apic_id: apic_id.into(),
+                domain,
+            })
+        }
+        X2APIC_AFFINITY => (u32_at(12)? & ENABLED != 0).then_some(Affinity::Cpu {
+            apic_id: u32_at(8)?,
+            domain: u32_at(4)?,
+        }),
+        MEMORY_AFFINITY => (u32_at(28)? & ENABLED != 0).then_some(Affinity::Memory {
+            base: u64_at(8)?,
+            length: u64_at(16)?,
+            domain: u32_at(2)?,
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn srat(entries: &[&[u8]]) -> Vec<u8> {
+        let mut table = alloc::vec![0; SRAT_HEADER_LEN];
+        for entry in entries {
+            table.extend_from_slice(entry);
+        }
+        table
+    }
+
+    fn apic(apic_id: u8, domain: u32, flags: u32) -> Vec<u8> {
+        let domain = domain.to_le_bytes();
+        let mut entry = alloc::vec![LOCAL_APIC_AFFINITY, 16, domain[0], apic_id];
+        entry.extend_from_slice(&flags.to_le_bytes());
+        entry.extend_from_slice(&[0, domain[1], domain[2], domain[3], 0, 0, 0, 0]);
+        entry
+    }
+
+    fn x2apic(apic_id: u32, domain: u32, flags: u32) -> Vec<u8> {
+        let mut entry = alloc::vec![X2APIC_AFFINITY, 24, 0, 0];
+        entry.extend_from_slice(&domain.to_le_bytes());
+        entry.extend_from_slice(&apic_id.to_le_bytes());
+        entry.extend_from_slice(&flags.to_le_bytes());
+        entry.extend_from_slice(&[0; 8]);
+        entry
+    }
+
+    fn memory(base: u64, length: u64, domain: u32, flags: u32) -> Vec<u8> {
+        let mut entry = alloc::vec![MEMORY_AFFINITY, 40];
+        entry.extend_from_slice(&domain.to_le_bytes());
+        entry.extend_from_slice(&[0; 2]);
+        entry.extend_from_slice(&base.to_le_bytes());
+        entry.extend_from_slice(&length.to_le_bytes());
+        entry.extend_from_slice(&[0; 4]);
+        entry.extend_from_slice(&flags.to_le_bytes());
+        entry.extend_from_slice(&[0; 8]);
+        entry
+    }
+
+    #[test]
+    fn parses_enabled_entries() {
+        let table = srat(&[
+            &apic(3, 0x0102_0304, ENABLED),
+            &apic(4, 1, 0),
+            &x2apic(300, 2, ENABLED),
+            &memory(0x1_0000_0000, 0x4000_0000, 1, ENABLED),
+            &memory(0, 0x1000, 0, 0),
+        ]);
+        assert_eq!(
+            affinities(&table),
+            [
+                Affinity::Cpu {
+                    apic_id: 3,
+                    domain: 0x0102_0304,
+                },
+                Affinity::Cpu {
+                    apic_id: 300,
+                    domain: 2,
+                },
+                Affinity::Memory {
+                    base: 0x1_0000_0000,
+                    length: 0x4000_0000,
+                    domain: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_unknown_and_stops_at_bad_length() {
+        let mut truncated = x2apic(7, 0, ENABLED);
+        truncated[1] = 200;
+        let table = srat(&[&[0xFF, 4, 0, 0], &apic(1, 0, ENABLED), &truncated]);
+        assert_eq!(
+            affinities(&table),
+            [Affinity::Cpu {
+                apic_id: 1,
+                domain: 0,
+            }]
+        );
+        assert!(affinities(&[0; 10]).is_empty());
+    }
+
+    #[test]
+    fn skips_entries_too_short_for_their_type() {
+        let short_memory = [MEMORY_AFFINITY, 12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let table = srat(&[
+            &short_memory,
+            &[X2APIC_AFFINITY, 4, 0, 0],
+            &apic(2, 5, ENABLED),
+        ]);
+        assert_eq!(
+            affinities(&table),
+            [Affinity::Cpu {
+                apic_id: 2,
+                domain: 5,
+            }]
+        );
+    }
+}
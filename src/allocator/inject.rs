@@ -0,0 +1,118 @@
+//! # Kernel heap failure injection
+//!
+//! Makes one chosen allocation fail, so that the paths handling a failed allocation can be tested.
+//! Writing "fail-after <n>" to `sys:kheap` makes the nth allocation from then on return null, and
+//! "fail-after <n> <min>-<max>" only counts allocations of `min` to `max` bytes. Once the
+//! allocation has failed, or after "fail-after 0", allocations are left alone again.
+//!
+//! Only built with the `kheap_fault_injection` feature, so that the allocator does not even check
+//! otherwise.
+
+use core::{
+    ops::RangeInclusive,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Matching allocations left until the one to fail, 0 if none is to fail
+static REMAINING: AtomicUsize = AtomicUsize::new(0);
+static MIN_SIZE: AtomicUsize = AtomicUsize::new(0);
+static MAX_SIZE: AtomicUsize = AtomicUsize::new(usize::MAX);
+/// Allocations failed on purpose so far
+static INJECTED: AtomicUsize = AtomicUsize::new(0);
+
+/// Fail the `after`th allocation from now with a size in `sizes`, or none if `after` is 0
+pub fn arm(after: usize, sizes: RangeInclusive<usize>) {
+    REMAINING.store(0, Ordering::Relaxed);
+    MIN_SIZE.store(*sizes.start(), Ordering::Relaxed);
+    MAX_SIZE.store(*sizes.end(), Ordering::Relaxed);
+    // Allocations that see the count see the sizes it goes with
+    REMAINING.store(after, Ordering::Release);
+}
+
+/// Whether an allocation of `size` bytes is the one to fail. Any number of CPUs may allocate at
+/// once, and exactly one of them takes the count to zero.
+pub fn should_fail(size: usize) -> bool {
+    if REMAINING.load(Ordering::Acquire) == 0 {
+        return false;
+    }
+    let sizes = MIN_SIZE.load(Ordering::Relaxed)..=MAX_SIZE.load(Ordering::Relaxed);
+    if !sizes.contains(&size) {
+        return false;
+    }
+    let taken = REMAINING.fetch_update(Ordering::AcqRel, Ordering::Acquire, |remaining| {
+        remaining.checked_sub(1)
+    });
+    if taken == Ok(1) {
+        INJECTED.fetch_add(1, Ordering::Relaxed);
+        return true;
+    }
+    false
+}
+
+/// Matching allocations left until the one to fail, 0 if none is to fail
+pub fn remaining() -> usize {
+    REMAINING.load(Ordering::Relaxed)
+}
+
+/// Allocations failed on purpose so far
+pub fn injected() -> usize {
+    INJECTED.load(Ordering::Relaxed)
+}
+
+/// Parse a command written to `sys:kheap` and act on it
+pub fn command(command: &str) -> Option<()> {
+    let mut words = command.split_ascii_whitespace();
+    if words.next()? != "fail-after" {
+        return None;
+    }
+    let after = words.next()?.parse().ok()?;
+    let sizes = match words.next() {
+        Some(range) => {
+            let (min, max) = range.split_once('-')?;
+            let (min, max) = (min.parse().ok()?, max.parse().ok()?);
+            if min > max {
+                return None;
+            }
+            min..=max
+        }
+        None => 0..=usize::MAX,
+    };
+    if words.next().is_some() {
+        return None;
+    }
+    arm(after, sizes);
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The injection state is global, so everything is checked in one test
+    #[test]
+    fn test_kheap_fail_after() {
+        assert_eq!(command("fail-after 3"), Some(()));
+        assert!(!should_fail(8));
+        assert!(!should_fail(8));
+        assert!(should_fail(8));
+        // Only the one allocation fails
+        assert!(!should_fail(8));
+        assert_eq!(injected(), 1);
+
+        assert_eq!(command("fail-after 2 64-128"), Some(()));
+        assert!(!should_fail(32));
+        assert!(!should_fail(64));
+        assert!(!should_fail(4096));
+        assert!(should_fail(128));
+        assert_eq!(remaining(), 0);
+
+        assert_eq!(command("fail-after 1"), Some(()));
+        assert_eq!(command("fail-after 0"), Some(()));
+        assert!(!should_fail(8));
+
+        assert_eq!(command("fail-after"), None);
+        assert_eq!(command("fail-after 1 128-64"), None);
+        assert_eq!(command("fail-after 1 64-128 extra"), None);
+        assert_eq!(command("fail-before 1"), None);
+    }
+}
@@ -20,10 +20,18 @@ impl Allocator {
 
 unsafe impl GlobalAlloc for Allocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "kheap_fault_injection")]
+        if super::inject::should_fail(layout.size()) {
+            return core::ptr::null_mut();
+        }
+
         unsafe {
             while let Some(ref mut heap) = *HEAP.lock() {
                 match heap.allocate_first_fit(layout) {
-                    Ok(ptr) => return ptr.as_ptr(),
+                    Ok(ptr) => {
+                        super::stats::count_alloc(layout.size());
+                        return ptr.as_ptr();
+                    }
                     Err(()) => {
                         let size = heap.size();
                         super::map_heap(
@@ -40,11 +48,12 @@ unsafe impl GlobalAlloc for Allocator {
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut heap = HEAP.lock();
         unsafe {
-            HEAP.lock()
-                .as_mut()
+            heap.as_mut()
                 .expect("heap not initialized")
                 .deallocate(NonNull::new_unchecked(ptr), layout)
         }
+        super::stats::count_free(layout.size());
     }
 }
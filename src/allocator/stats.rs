@@ -0,0 +1,134 @@
+//! # Kernel heap statistics
+//!
+//! Allocations are counted by size class in the [`PercpuBlock`] of the CPU making them, so CPUs
+//! never contend on the counters, and the counters of all CPUs are only folded together when
+//! read. The bytes in use and their peak are kept with the heap instead, as they are updated
+//! under the heap lock every allocation takes anyway.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{cpu_set::LogicalCpuId, percpu::PercpuBlock};
+
+/// Number of size classes allocations are counted in: powers of two from 16 bytes to 4 KiB, and
+/// everything larger
+pub const SIZE_CLASSES: usize = 10;
+/// log2 of the upper bound of the smallest size class
+const SMALLEST_CLASS_SHIFT: u32 = 4;
+
+/// Bytes allocated and not freed yet. Only changed with the heap locked.
+static CURRENT_BYTES: AtomicUsize = AtomicUsize::new(0);
+/// Highest value `CURRENT_BYTES` has had. Only changed with the heap locked.
+static PEAK_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// The size class of an allocation of `size` bytes
+pub fn size_class(size: usize) -> usize {
+    let shift = size
+        .max(1)
+        .checked_next_power_of_two()
+        .map_or(usize::BITS, usize::trailing_zeros);
+    (shift.saturating_sub(SMALLEST_CLASS_SHIFT) as usize).min(SIZE_CLASSES - 1)
+}
+
+/// Upper bound of a size class, `None` for the last one
+pub fn size_class_limit(class: usize) -> Option<usize> {
+    if class >= SIZE_CLASSES - 1 {
+        return None;
+    }
+    1_usize.checked_shl(SMALLEST_CLASS_SHIFT.saturating_add(class as u32))
+}
+
+/// Heap counters of one CPU
+#[derive(Debug)]
+pub struct HeapCounters {
+    allocs: [AtomicUsize; SIZE_CLASSES],
+    frees: AtomicUsize,
+}
+
+impl HeapCounters {
+    pub const fn new() -> Self {
+        Self {
+            allocs: [const { AtomicUsize::new(0) }; SIZE_CLASSES],
+            frees: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Default for HeapCounters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Count an allocation of `size` bytes. Must be called with the heap locked.
+pub fn count_alloc(size: usize) {
+    let current = CURRENT_BYTES.load(Ordering::Relaxed).saturating_add(size);
+    CURRENT_BYTES.store(current, Ordering::Relaxed);
+    if current > PEAK_BYTES.load(Ordering::Relaxed) {
+        PEAK_BYTES.store(current, Ordering::Relaxed);
+    }
+
+    if let Some(count) = PercpuBlock::current().heap.allocs.get(size_class(size)) {
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Count a deallocation of `size` bytes. Must be called with the heap locked.
+pub fn count_free(size: usize) {
+    let current = CURRENT_BYTES.load(Ordering::Relaxed).saturating_sub(size);
+    CURRENT_BYTES.store(current, Ordering::Relaxed);
+
+    PercpuBlock::current()
+        .heap
+        .frees
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+/// A snapshot of the heap statistics of all CPUs
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HeapStats {
+    pub current_bytes: usize,
+    pub peak_bytes: usize,
+    /// Allocations made in each size class
+    pub allocs: [usize; SIZE_CLASSES],
+    pub frees: usize,
+}
+
+pub fn heap_stats() -> HeapStats {
+    let mut stats = HeapStats {
+        current_bytes: CURRENT_BYTES.load(Ordering::Relaxed),
+        peak_bytes: PEAK_BYTES.load(Ordering::Relaxed),
+        ..HeapStats::default()
+    };
+    for id in 0..crate::cpu_count() {
+        let Some(block) = crate::percpu::get_percpu_block(LogicalCpuId::new(id)) else {
+            continue;
+        };
+        for (total, count) in stats.allocs.iter_mut().zip(&block.heap.allocs) {
+            *total = total.saturating_add(count.load(Ordering::Relaxed));
+        }
+        stats.frees = stats
+            .frees
+            .saturating_add(block.heap.frees.load(Ordering::Relaxed));
+    }
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heap_size_class() {
+        assert_eq!(size_class(0), 0);
+        assert_eq!(size_class(16), 0);
+        assert_eq!(size_class(17), 1);
+        assert_eq!(size_class(2048), SIZE_CLASSES - 3);
+        assert_eq!(size_class(4096), SIZE_CLASSES - 2);
+        assert_eq!(size_class(4097), SIZE_CLASSES - 1);
+        assert_eq!(size_class(usize::MAX), SIZE_CLASSES - 1);
+
+        assert_eq!(size_class_limit(0), Some(16));
+        assert_eq!(size_class_limit(SIZE_CLASSES - 2), Some(4096));
+        assert_eq!(size_class_limit(SIZE_CLASSES - 1), None);
+    }
+}
@@ -22,6 +22,12 @@ pub const KERNEL_HEAP_PML4: usize = (KERNEL_HEAP_OFFSET & PML4_MASK) / PML4_SIZE
 /// Size of kernel heap
 pub const KERNEL_HEAP_SIZE: usize = 1 * 1024 * 1024; // 1 MB
 
+/// Offset of kernel stacks, each mapped with an unmapped guard page below it, above where the
+/// heap grows
+pub const KERNEL_KSTACK_OFFSET: usize = KERNEL_HEAP_OFFSET + PML4_SIZE / 2;
+/// Size of the kernel stack region
+pub const KERNEL_KSTACK_SIZE: usize = PML4_SIZE / 4;
+
 /// Offset of temporary mapping for misc kernel bring-up actions
 pub const KERNEL_TMP_MISC_OFFSET: usize = KERNEL_HEAP_OFFSET - PML4_SIZE;
 
@@ -82,6 +82,15 @@ pub unsafe fn tpidr_el1_write(val: u64) {
     }
 }
 
+/// Affinity of the current CPU, as used to route interrupts to it
+pub unsafe fn mpidr_el1() -> u64 {
+    unsafe {
+        let ret: u64;
+        asm!("mrs {}, mpidr_el1", out(reg) ret);
+        ret
+    }
+}
+
 pub unsafe fn tpidrro_el0() -> u64 {
     unsafe {
         let ret: u64;
@@ -130,8 +130,11 @@ impl InterruptHandler for GenericTimer {
         {
             *time::OFFSET.lock() += self.clk_freq as u128;
         }
+        crate::watchdog::tick();
 
         timeout::trigger(token);
+        crate::log::wake_kmsg_readers(token);
+        crate::deferred::tick(token);
         context::switch::tick(token);
 
         unsafe {
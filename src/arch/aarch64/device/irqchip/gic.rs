@@ -1,6 +1,6 @@
 //! # Generic Interrupt Controller (GIC)
 
-use crate::dtb::irqchip::{InterruptHandler, InterruptController, IrqCell, IrqDesc};
+use crate::dtb::irqchip::{InterruptController, InterruptHandler, IrqCell, IrqDesc, SgiTarget};
 use crate::sync::CleanLockToken;
 use fdt::Fdt;
 use syscall::{
@@ -98,22 +98,20 @@ impl InterruptController for GenericInterruptController {
     fn irq_to_virq(&self, _hwirq: u32) -> Option<usize> {
         None
     }
-    fn send_sgi(&mut self, irq: u32, target_cpu: u32) {
+    fn send_sgi(&mut self, intid: u8, target: SgiTarget) {
         // SGIR at 0xF00 in Distributor (GICv2)
         // Bit 24-16: Target List Filter (0=List, 1=All others, 2=Self)
         // Bit 15-0: CPU Target List (if Filter=0)
         // Bit 3-0: SGIINTID
 
-        let sgi_int_id = irq & 0xF;
+        let sgi_int_id = u32::from(intid & 0xF);
         let mut val = sgi_int_id;
 
-        if target_cpu == u32::MAX {
-            val |= 1 << 24; // Target List Filter = 1 (All others)
-        } else {
+        match target {
+            SgiTarget::Others => val |= 1 << 24, // Target List Filter = 1 (All others)
             // CPU Target List (1 bit per CPU)
-            // GICv2 supports up to 8 CPUs.
-            // target_cpu is logical ID (0-7).
-            val |= (1 << (target_cpu + 16));
+            // GICv2 supports up to 8 CPUs, numbered as the logical IDs are.
+            SgiTarget::Cpu(cpu) => val |= 1 << ((cpu.get() & 0x7) + 16),
         }
 
         if self.dist.address != 0 {
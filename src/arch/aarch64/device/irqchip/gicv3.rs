@@ -1,13 +1,21 @@
-use alloc::vec::Vec;
-use core::arch::asm;
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    arch::asm,
+    ptr::{read_volatile, write_volatile},
+    sync::atomic::Ordering,
+};
 use fdt::{node::NodeProperty, Fdt};
 
 use super::{gic::GicDistIf, InterruptController};
 use crate::{
+    device::cpu::registers::control_regs,
     dtb::{
         get_mmio_address,
-        irqchip::{InterruptHandler, IrqCell, IrqDesc},
+        irqchip::{InterruptHandler, IrqCell, IrqDesc, SgiTarget},
     },
+    interrupt::ipi::IpiHandler,
+    ipi::IpiKind,
+    percpu,
     sync::CleanLockToken,
 };
 use syscall::{
@@ -15,13 +23,33 @@ use syscall::{
     Result,
 };
 
+// Redistributor registers. Each CPU has its own redistributor, whose SGI and PPI registers are in
+// the second of its 64 KiB frames.
+const GICR_TYPER: usize = 0x0008;
+const GICR_WAKER: usize = 0x0014;
+const GICR_SGI_BASE: usize = 0x1_0000;
+const GICR_IGROUPR0: usize = GICR_SGI_BASE + 0x0080;
+const GICR_ISENABLER0: usize = GICR_SGI_BASE + 0x0100;
+const GICR_ICENABLER0: usize = GICR_SGI_BASE + 0x0180;
+
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+const GICR_TYPER_VLPIS: u64 = 1 << 1;
+const GICR_TYPER_LAST: u64 = 1 << 4;
+
+/// SGIs and PPIs, which are masked in the redistributor of each CPU rather than the distributor
+const PRIVATE_IRQS: u32 = 32;
+
 #[derive(Debug)]
 pub struct GicV3 {
     pub gic_dist_if: GicDistIf,
     pub gic_cpu_if: GicV3CpuIf,
+    /// Physical base and size of each redistributor region
     pub gicrs: Vec<(usize, usize)>,
     //TODO: GICC, GICH, GICV?
     pub irq_range: (usize, usize),
+    /// SGIs and PPIs to enable on each CPU, including those that come up later
+    private_enabled: u32,
 }
 
 impl GicV3 {
@@ -31,9 +59,75 @@ impl GicV3 {
             gic_cpu_if: GicV3CpuIf,
             gicrs: Vec::new(),
             irq_range: (0, 0),
+            private_enabled: 0,
+        }
+    }
+
+    /// Redistributor of the CPU with affinity `mpidr`, found by its GICR_TYPER
+    fn redistributor(&self, mpidr: u64) -> Option<usize> {
+        let affinity = ((mpidr >> 32) & 0xFF) << 24 | (mpidr & 0xFF_FFFF);
+        for &(base, size) in &self.gicrs {
+            let mut offset = 0;
+            while offset < size {
+                let frame = crate::PHYS_OFFSET + base + offset;
+                let typer = unsafe { read_volatile((frame + GICR_TYPER) as *const u64) };
+                if typer >> 32 == affinity {
+                    return Some(frame);
+                }
+                if typer & GICR_TYPER_LAST != 0 {
+                    break;
+                }
+                offset += if typer & GICR_TYPER_VLPIS != 0 {
+                    0x4_0000
+                } else {
+                    0x2_0000
+                };
+            }
+        }
+        None
+    }
+
+    fn current_redistributor(&self) -> Option<usize> {
+        self.redistributor(unsafe { control_regs::mpidr_el1() })
+    }
+
+    /// Wake the current CPU's redistributor, put its SGIs and PPIs in group 1 and enable those
+    /// wanted on every CPU
+    unsafe fn init_redistributor(&mut self) {
+        let Some(rd) = self.current_redistributor() else {
+            error!("no GICv3 redistributor for MPIDR {:#x}", unsafe {
+                control_regs::mpidr_el1()
+            });
+            return;
+        };
+        unsafe {
+            let waker = (rd + GICR_WAKER) as *mut u32;
+            write_volatile(waker, read_volatile(waker) & !GICR_WAKER_PROCESSOR_SLEEP);
+            while read_volatile(waker) & GICR_WAKER_CHILDREN_ASLEEP != 0 {
+                core::hint::spin_loop();
+            }
+
+            write_volatile((rd + GICR_IGROUPR0) as *mut u32, u32::MAX);
+            write_volatile((rd + GICR_ICENABLER0) as *mut u32, !self.private_enabled);
+            write_volatile((rd + GICR_ISENABLER0) as *mut u32, self.private_enabled);
         }
     }
 
+    /// Value of ICC_SGI1R_EL1 routing `intid` to the CPU with affinity `mpidr`
+    fn sgi1r_for(mpidr: u64, intid: u8) -> u64 {
+        let aff0 = mpidr & 0xFF;
+        let aff1 = (mpidr >> 8) & 0xFF;
+        let aff2 = (mpidr >> 16) & 0xFF;
+        let aff3 = (mpidr >> 32) & 0xFF;
+        // The target list covers 16 Aff0 values, the range selector picks which 16
+        aff3 << 48
+            | (aff0 >> 4) << 44
+            | aff2 << 32
+            | u64::from(intid & 0xF) << 24
+            | aff1 << 16
+            | 1 << (aff0 & 0xF)
+    }
+
     pub fn parse(&mut self, fdt: &Fdt) -> Result<()> {
         let Some(node) = fdt.find_compatible(&["arm,gic-v3"]) else {
             return Err(Error::new(EINVAL));
@@ -93,7 +187,11 @@ impl InterruptController for GicV3 {
         }
         info!("{:X?}", self);
 
+        for kind in IpiKind::ALL {
+            self.private_enabled |= 1 << kind as u8;
+        }
         unsafe {
+            self.init_redistributor();
             self.gic_cpu_if.init();
         }
         let idx = *irq_idx;
@@ -112,6 +210,10 @@ impl InterruptController for GicV3 {
             i += 1;
         }
 
+        for kind in IpiKind::ALL {
+            irq_desc[idx + kind as usize].handler = Some(Box::new(IpiHandler(kind)));
+        }
+
         info!("gic irq_range = ({}, {})", idx, idx + cnt);
         self.irq_range = (idx, idx + cnt);
         *irq_idx = idx + cnt;
@@ -125,10 +227,23 @@ impl InterruptController for GicV3 {
         unsafe { self.gic_cpu_if.irq_eoi(irq_num) }
     }
     fn irq_enable(&mut self, irq_num: u32) {
-        unsafe { self.gic_dist_if.irq_enable(irq_num) }
+        if irq_num >= PRIVATE_IRQS {
+            return unsafe { self.gic_dist_if.irq_enable(irq_num) };
+        }
+        // Other CPUs enable it when they come up
+        self.private_enabled |= 1 << irq_num;
+        if let Some(rd) = self.current_redistributor() {
+            unsafe { write_volatile((rd + GICR_ISENABLER0) as *mut u32, 1 << irq_num) };
+        }
     }
     fn irq_disable(&mut self, irq_num: u32) {
-        unsafe { self.gic_dist_if.irq_disable(irq_num) }
+        if irq_num >= PRIVATE_IRQS {
+            return unsafe { self.gic_dist_if.irq_disable(irq_num) };
+        }
+        // Only masked on this CPU
+        if let Some(rd) = self.current_redistributor() {
+            unsafe { write_volatile((rd + GICR_ICENABLER0) as *mut u32, 1 << irq_num) };
+        }
     }
     fn irq_xlate(&self, irq_data: IrqCell) -> Result<usize> {
         let off = match irq_data {
@@ -146,9 +261,28 @@ impl InterruptController for GicV3 {
         }
     }
 
-    fn send_sgi(&mut self, irq: u32, target_cpu: u32) {
+    fn send_sgi(&mut self, intid: u8, target: SgiTarget) {
+        let value = match target {
+            // IRM: every CPU taking part in the GIC but this one
+            SgiTarget::Others => 1 << 40 | u64::from(intid & 0xF) << 24,
+            SgiTarget::Cpu(cpu) => {
+                let Some(block) = percpu::get_percpu_block(cpu) else {
+                    warn!("SGI {} for CPU {} which is not up", intid, cpu);
+                    return;
+                };
+                let mpidr = block.misc_arch_info.mpidr.load(Ordering::Relaxed);
+                Self::sgi1r_for(mpidr, intid)
+            }
+        };
+        unsafe {
+            self.gic_cpu_if.send_sgi(value);
+        }
+    }
+
+    fn init_cpu(&mut self) {
         unsafe {
-            self.gic_cpu_if.send_sgi(irq, target_cpu);
+            self.init_redistributor();
+            self.gic_cpu_if.init();
         }
     }
 }
@@ -157,69 +291,10 @@ impl InterruptController for GicV3 {
 pub struct GicV3CpuIf;
 
 impl GicV3CpuIf {
-    unsafe fn send_sgi(&mut self, irq: u32, target_cpu: u32) {
-        // GICv3 SGI: ICC_SGI1R_EL1
-        // Target List[15:0], IRQ ID[27:24], Mode[40] (0=TargetList, 1=AllOthers)
-        // Aff3[55:48], Aff2[39:32], Aff1[23:16]
-
-        // Simplified: We assume Aff0 is the target_cpu and others are 0 for now.
-        // TODO: Proper MPIDR based affinity mapping.
-
-        // IRQ ID is the IPI kind (0 or 1).
-
-        let mut val: u64 = (irq as u64 & 0xF) << 24;
-
-        // Target CPU mask (Aff0)
-        // If target_cpu is 0 (Other), we use IRM bit (all except self)?
-        // src/arch/aarch64/ipi.rs calls ipi(kind, Other) -> send_sgi(kind, 0)
-        // In GICv3, to send to "others", we can use IRM=1?
-        // But for specific CPU, we need MPIDR.
-        // We assume target_cpu is a logical ID here or MPIDR?
-        // ipi_single passes `target.cpu_id.get()`. This is logical ID.
-        // We need logical->MPIDR mapping.
-        // For now, assuming 1:1 mapping with Aff0 for simplicity in QEMU/Virt.
-
-        // If target_cpu is 0 and we mean "others", we assume it's handled by caller passing appropriate mask or logic.
-        // But ipi(Other) passed 0.
-        // GICv3 SGI1R:
-        // Bit 40 (IRM): Interrupt Routing Mode. 0=List, 1=All except self.
-
-        // If we want broadcast to others:
-        // val |= 1 << 40;
-
-        // But the `target_cpu` argument is a single u32.
-        // If we want to support "Others", we need a special value.
-        // `src/arch/aarch64/ipi.rs` passes 0 for `ipi(Other)`.
-        // But 0 is also CPU 0.
-        // We need a convention.
-        // Let's assume if IPI target is "Others", the caller logic in ipi.rs should invoke this differently?
-        // I modified `ipi.rs` to call `send_sgi(kind, 0)`.
-        // If I want to support broadcast, I should change `send_sgi` signature or `ipi.rs` logic.
-        // Or I assume `target_cpu` as a bitmask if not GICv3?
-        // GICv3 uses Affinity.
-
-        // For this task, I'll implement basic SGI sending.
-        // If target_cpu == 0, I'll assume broadcast to others?
-        // No, CPU 0 is a valid target.
-        // I should have passed a special flag from `ipi.rs`.
-        // But I can't change `ipi` function signature easily without affecting other archs?
-        // No, `ipi` is arch-specific.
-
-        // Let's use `u32::MAX` for broadcast?
-        // `ipi.rs`: `IRQ_CHIP.send_sgi(kind as u32, u32::MAX);` for Other.
-
-        // Updating this block to assume u32::MAX means broadcast.
-
-        if target_cpu == u32::MAX {
-             val |= 1 << 40; // IRM = 1 (Broadcast to all except self)
-        } else {
-             // Target List (Aff0)
-             // We assume target_cpu is the Aff0 value (0-15)
-             val |= (1 << (target_cpu & 0xF)) as u64;
-             // Set Affinity levels if needed (from percpu data)
+    unsafe fn send_sgi(&mut self, value: u64) {
+        unsafe {
+            asm!("msr icc_sgi1r_el1, {}", "isb", in(reg) value);
         }
-
-        asm!("msr icc_sgi1r_el1, {}", in(reg) val);
     }
 
     pub unsafe fn init(&mut self) {
@@ -239,7 +314,7 @@ impl GicV3CpuIf {
                 let value = 1_usize;
                 asm!("msr icc_igrpen1_el1, {}", in(reg) value);
             }
-            // Set CPU0's Interrupt Priority Mask
+            // Set this CPU's Interrupt Priority Mask
             {
                 let value = 0xFF_usize;
                 asm!("msr icc_pmr_el1, {}", in(reg) value);
@@ -5,7 +5,7 @@ use super::InterruptController;
 use crate::{
     dtb::{
         get_interrupt, get_mmio_address,
-        irqchip::{InterruptHandler, IrqCell, IrqDesc, IRQ_CHIP},
+        irqchip::{InterruptHandler, IrqCell, IrqDesc, SgiTarget, IRQ_CHIP},
     },
     sync::CleanLockToken,
 };
@@ -273,7 +273,7 @@ impl InterruptController for Bcm2835ArmInterruptController {
         }
     }
 
-    fn send_sgi(&mut self, _irq: u32, _target_cpu: u32) {
+    fn send_sgi(&mut self, _intid: u8, _target: SgiTarget) {
         // BCM2835 does not support SGIs in the ARMCTRL.
         // BCM2836+ handles this in irq_bcm2836.rs.
     }
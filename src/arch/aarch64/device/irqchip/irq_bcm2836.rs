@@ -3,7 +3,7 @@ use crate::{
     arch::device::{ROOT_IC_IDX, ROOT_IC_IDX_IS_SET},
     dtb::{
         get_mmio_address,
-        irqchip::{InterruptHandler, IrqCell, IrqDesc},
+        irqchip::{InterruptHandler, IrqCell, IrqDesc, SgiTarget},
     },
     sync::CleanLockToken,
 };
@@ -229,7 +229,7 @@ impl InterruptController for Bcm2836ArmInterruptController {
         }
     }
 
-    fn send_sgi(&mut self, _irq: u32, _target_cpu: u32) {
+    fn send_sgi(&mut self, _intid: u8, _target: SgiTarget) {
         // TODO: Implement IPI for BCM2836 using Mailboxes (0x80..0xBC)
     }
 }
@@ -1,5 +1,5 @@
 use crate::info;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use fdt::Fdt;
 
 pub mod cpu;
@@ -51,10 +51,23 @@ pub unsafe fn init_devicetree(fdt: &Fdt) {
     }
 }
 
-pub struct ArchPercpuMisc;
+/// Bring up the interrupt controller interface of an AP, so that it takes its own timer interrupts
+/// and IPIs
+pub unsafe fn init_ap() {
+    unsafe {
+        IRQ_CHIP.init_cpu();
+    }
+}
+
+pub struct ArchPercpuMisc {
+    /// MPIDR_EL1 of the CPU, for routing SGIs to it
+    pub mpidr: AtomicU64,
+}
 
 impl ArchPercpuMisc {
     pub const fn default() -> Self {
-        Self
+        Self {
+            mpidr: AtomicU64::new(0),
+        }
     }
 }
@@ -221,6 +221,9 @@ impl ArchIntCtx for InterruptStack {
     fn ip(&self) -> usize {
         self.iret.elr_el1
     }
+    fn sp(&self) -> usize {
+        self.stack_pointer()
+    }
     fn recover_and_efault(&mut self) {
         // Set the return value to nonzero to indicate usercopy failure (EFAULT), and emulate the
         // return instruction by setting the return pointer to the saved LR value.
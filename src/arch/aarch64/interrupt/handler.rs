@@ -383,6 +383,7 @@ macro_rules! exception_stack {
         #[unsafe(no_mangle)]
         pub unsafe extern "C" fn $name(stack: &mut $crate::arch::aarch64::interrupt::InterruptStack) {
             unsafe extern "C" fn inner($stack: &mut $crate::arch::aarch64::interrupt::InterruptStack) { unsafe {
+                let _locks = $crate::sync::InterruptedLocks::set_aside();
                 $code
             }}
             core::arch::naked_asm!(concat!(
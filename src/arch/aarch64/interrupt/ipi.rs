@@ -0,0 +1,26 @@
+//! Handlers of the SGIs that IPIs are sent as
+
+use crate::{
+    context,
+    dtb::irqchip::{InterruptHandler, IRQ_CHIP},
+    ipi::IpiKind,
+    percpu::PercpuBlock,
+    sync::CleanLockToken,
+};
+
+pub struct IpiHandler(pub IpiKind);
+
+impl InterruptHandler for IpiHandler {
+    fn irq_handler(&mut self, virq: u32, token: &mut CleanLockToken) {
+        unsafe { IRQ_CHIP.irq_eoi(virq) };
+
+        match self.0 {
+            // Taking the interrupt is enough to leave the idle loop
+            IpiKind::Wakeup => (),
+            IpiKind::Tlb => PercpuBlock::current().maybe_handle_tlb_shootdown(),
+            IpiKind::Switch => {
+                let _ = unsafe { context::switch(token) };
+            }
+        }
+    }
+}
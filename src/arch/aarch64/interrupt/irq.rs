@@ -4,7 +4,7 @@ use crate::{
 };
 use core::sync::atomic::Ordering;
 
-// use crate::percpu::PercpuBlock;
+use crate::percpu::PercpuBlock;
 
 unsafe fn irq_ack() -> (u32, Option<usize>) {
     unsafe {
@@ -41,8 +41,10 @@ exception_stack!(irq_at_el1, |_stack| {
 //TODO
 pub unsafe fn trigger(irq: u32, token: &mut CleanLockToken) {
     unsafe {
-        // FIXME add_irq accepts a u8 as irq number
-        // PercpuBlock::current().stats.add_irq(irq);
+        // FIXME: Interrupts past 255 are not counted, as add_irq accepts a u8 as irq number
+        if let Ok(irq) = u8::try_from(irq) {
+            PercpuBlock::current().stats.add_irq(irq);
+        }
 
         irq_trigger(irq.try_into().unwrap(), token);
         IRQ_CHIP.irq_eoi(irq);
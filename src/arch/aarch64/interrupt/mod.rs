@@ -6,6 +6,7 @@ use core::arch::asm;
 pub mod handler;
 
 pub mod exception;
+pub mod ipi;
 pub mod irq;
 pub mod syscall;
 pub mod trace;
@@ -20,6 +21,17 @@ pub unsafe fn disable() {
     }
 }
 
+/// Whether interrupts are enabled on this CPU
+#[inline(always)]
+pub fn are_enabled() -> bool {
+    let daif: usize;
+    unsafe {
+        asm!("mrs {}, daif", out(reg) daif, options(nomem, nostack, preserves_flags));
+    }
+    // The I mask bit
+    daif & (1 << 7) == 0
+}
+
 /// Set interrupts and halt
 /// This will atomically wait for the next interrupt
 /// Performing enable followed by halt is not guaranteed to be atomic, use this instead!
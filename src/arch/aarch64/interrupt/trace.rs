@@ -1,32 +1,39 @@
-use core::{arch::asm, mem};
+use core::{arch::asm, mem, ops::Range};
 
+/// A frame in the frame pointer chain. `x29` points at the frame record, the caller's saved
+/// `x29` followed by the return address into the caller.
 pub struct StackTrace {
+    /// Address of the saved frame pointer
     pub fp: usize,
+    /// Address of the return address
     pub pc_ptr: *const usize,
 }
 
 impl StackTrace {
+    /// The frame of the function this is inlined into
     #[inline(always)]
     pub unsafe fn start() -> Option<Self> {
-        unsafe {
-            let fp: usize;
-            asm!("mov {}, fp", out(reg) fp);
-            let pc_ptr = fp.checked_add(mem::size_of::<usize>())?;
-            Some(StackTrace {
-                fp,
-                pc_ptr: pc_ptr as *const usize,
-            })
-        }
+        let fp: usize;
+        unsafe { asm!("mov {}, x29", out(reg) fp) };
+        Self::from_fp(fp)
     }
 
+    /// The frame whose frame pointer register held `fp`
+    pub fn from_fp(fp: usize) -> Option<Self> {
+        let pc_ptr = fp.checked_add(mem::size_of::<usize>())?;
+        Some(StackTrace {
+            fp,
+            pc_ptr: pc_ptr as *const usize,
+        })
+    }
+
+    /// The caller's frame. `self.fp` must be readable.
     pub unsafe fn next(self) -> Option<Self> {
-        unsafe {
-            let fp = *(self.fp as *const usize);
-            let pc_ptr = fp.checked_add(mem::size_of::<usize>())?;
-            Some(StackTrace {
-                fp: fp,
-                pc_ptr: pc_ptr as *const usize,
-            })
-        }
+        Self::from_fp(unsafe { *(self.fp as *const usize) })
     }
 }
+
+/// The stacks of this CPU other than the kernel stacks of contexts, which are not tracked here
+pub fn cpu_stacks() -> impl Iterator<Item = Range<usize>> {
+    core::iter::empty()
+}
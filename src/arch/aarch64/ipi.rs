@@ -1,6 +1,9 @@
-use crate::dtb::irqchip::IRQ_CHIP;
+use crate::{
+    dtb::irqchip::{SgiTarget, IRQ_CHIP},
+    percpu::PercpuBlock,
+};
 
-/// The kind of IPI to send.
+/// The kind of IPI to send, which is also the SGI number it is sent as.
 #[derive(Clone, Copy, Debug)]
 #[repr(u8)]
 pub enum IpiKind {
@@ -8,38 +11,54 @@ pub enum IpiKind {
     Wakeup = 0,
     /// A TLB shootdown IPI.
     Tlb = 1,
+    /// A context switch IPI.
+    Switch = 2,
+}
+
+impl IpiKind {
+    pub const ALL: [Self; 3] = [Self::Wakeup, Self::Tlb, Self::Switch];
 }
 
 /// The target of an IPI.
 #[derive(Clone, Copy, Debug)]
 #[repr(u8)]
 pub enum IpiTarget {
+    /// The current CPU.
+    Current = 1,
+    /// All CPUs.
+    All = 2,
     /// All other CPUs.
     Other = 3,
 }
 
 /// Sends an IPI to the specified target.
 #[inline(always)]
-pub fn ipi(kind: IpiKind, _target: IpiTarget) {
+pub fn ipi(kind: IpiKind, target: IpiTarget) {
     if cfg!(not(feature = "multi_core")) {
         return;
     }
 
+    let current = SgiTarget::Cpu(PercpuBlock::current().cpu_id);
     unsafe {
-        // TODO: Map IpiTarget to SGI target mask (CPUIDs)
-        // For now we assume a broadcast or specific implementation in the driver
-        IRQ_CHIP.send_sgi(kind as u32, u32::MAX);
+        match target {
+            IpiTarget::Current => IRQ_CHIP.send_sgi(kind as u8, current),
+            IpiTarget::All => {
+                IRQ_CHIP.send_sgi(kind as u8, SgiTarget::Others);
+                IRQ_CHIP.send_sgi(kind as u8, current);
+            }
+            IpiTarget::Other => IRQ_CHIP.send_sgi(kind as u8, SgiTarget::Others),
+        }
     }
 }
 
 /// Sends an IPI to a single CPU.
 #[inline(always)]
-pub fn ipi_single(kind: IpiKind, target: &crate::percpu::PercpuBlock) {
+pub fn ipi_single(kind: IpiKind, target: &PercpuBlock) {
     if cfg!(not(feature = "multi_core")) {
         return;
     }
 
     unsafe {
-        IRQ_CHIP.send_sgi(kind as u32, target.cpu_id.get());
+        IRQ_CHIP.send_sgi(kind as u8, SgiTarget::Cpu(target.cpu_id));
     }
 }
@@ -19,6 +19,10 @@ pub unsafe fn init(cpu_id: LogicalCpuId) {
         let virt = RmmA::phys_to_virt(frame.base()).data() as *mut PercpuBlock;
 
         virt.write(PercpuBlock::init(cpu_id));
+        (*virt).misc_arch_info.mpidr.store(
+            crate::device::cpu::registers::control_regs::mpidr_el1(),
+            core::sync::atomic::Ordering::Relaxed,
+        );
 
         crate::device::cpu::registers::control_regs::tpidr_el1_write(virt as u64);
     }
@@ -123,6 +123,7 @@ unsafe extern "C" fn start(args_ptr: *const KernelArgs) -> ! {
                 Ok(dtb) => {
                     dtb::init(hwdesc_data.map(|slice| (slice.as_ptr() as usize, slice.len())));
                     device::init_devicetree(&dtb);
+                    crate::topology::devicetree::init(&dtb);
                 }
                 Err(err) => {
                     dtb::init(None);
@@ -0,0 +1,6 @@
+//! # ARMv7 CPU feature dependent sizes
+
+/// Size of the kfx area saved on context switch, holding the VFP registers
+pub fn kfx_size() -> usize {
+    crate::context::arch::KFX_SIZE
+}
@@ -3,6 +3,7 @@
 //! This module provides ARMv7-A (32-bit ARM) architecture support for the Redox kernel.
 //! Designed for single-board computers like Raspberry Pi 2, BeagleBone, and similar devices.
 
+pub mod alternative;
 pub mod consts;
 pub mod debug;
 pub mod device;
@@ -25,10 +26,14 @@ pub use crate::arch::armv7::linker::*;
 ///
 /// This function is unsafe because it directly manipulates CPU registers and stack pointers.
 pub unsafe fn switch_to(prev: *mut crate::context::Context, next: *mut crate::context::Context) {
-    extern "C" {
-        fn __context_switch(prev: *mut usize, next: *const usize);
-    }
+    unsafe { crate::context::arch::switch_to(&mut *prev, &mut *next) }
+}
 
-    // ARMv7 context switch will be implemented in assembly
-    // For now, this is a placeholder
+/// Switch to the first context on this CPU, abandoning the boot context
+///
+/// # Safety
+///
+/// This function is unsafe because it directly manipulates CPU registers and stack pointers.
+pub unsafe fn switch_to_first(next: *mut crate::context::Context) {
+    unsafe { crate::context::arch::switch_to_first(&mut *next) }
 }
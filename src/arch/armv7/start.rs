@@ -39,6 +39,9 @@ unsafe fn init_cpu(cpu_id: usize) {
     // Set up exception vectors
     super::vectors::init();
 
+    // Enable the VFP, if present, before any context switch saves its state
+    crate::context::arch::vfp_init();
+
     // Enable caches and MMU (will be implemented)
     // enable_mmu();
 
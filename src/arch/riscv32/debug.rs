@@ -17,6 +17,13 @@ impl<'a> Writer<'a> {
         }
     }
 
+    /// Creates a new `Writer` if the serial port is not locked.
+    pub fn try_new() -> Option<Writer<'a>> {
+        Some(Writer {
+            serial: COM1.try_lock()?,
+        })
+    }
+
     /// Writes to the serial port.
     pub fn write(&mut self, buf: &[u8]) {
         self.serial.write(buf);
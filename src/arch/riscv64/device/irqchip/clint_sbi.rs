@@ -1,11 +1,8 @@
 use crate::{
-    context,
-    context::timeout,
     dtb::irqchip::{register_irq, InterruptHandler, IrqCell, IRQ_CHIP},
     sync::CleanLockToken,
 };
-use alloc::{boxed::Box, vec::Vec};
-use core::{arch::asm, cmp::max};
+use alloc::boxed::Box;
 use fdt::node::FdtNode;
 use spin::Mutex;
 // This is a Core-Local Interruptor (CLINT). A single device directly routed into each HLIC
@@ -18,13 +15,12 @@ use spin::Mutex;
 //     reg = <0x200000000 0x10000>;
 //     compatible = "sifive,clint0", "riscv,clint0";
 
+// Timer interrupts are programmed through SBI by arch::time, the CLINT only routes them.
 pub struct Clint {
-    freq: u64,
-    next_event: Vec<u64>,
+    hart_count: usize,
 }
 
 pub static CLINT: Mutex<Option<Clint>> = Mutex::new(None);
-const TICKS_PER_SECOND: u64 = 100;
 const IRQ_IPI: usize = 0;
 const IRQ_TIMER: usize = 1;
 
@@ -41,10 +37,8 @@ impl InterruptHandler for ClintConnector {
             .unwrap()
             .irq_handler(self.hart_id, self.irq);
         if self.irq == IRQ_TIMER {
-            // a bit of hack, but it is a really bad idea to call scheduler
-            // from inside clint irq handler
-            timeout::trigger(token);
-            context::switch::tick(token);
+            // Preempting may switch away, so the CLINT lock must already be released here
+            crate::arch::time::timer_interrupt(token);
         }
     }
 }
@@ -58,16 +52,13 @@ fn map_interrupt(irq: u32) -> u32 {
 }
 
 impl Clint {
-    pub fn new(freq: usize, node: &FdtNode) -> Self {
+    pub fn new(node: &FdtNode) -> Self {
         // TODO IPI
         // let reg = clint_node.reg().unwrap().next().unwrap();
         // reg.starting_address.add(crate::PHYS_OFFSET) as *mut u8;
         // reg.size.unwrap();
 
-        let mut me = Self {
-            freq: freq as u64,
-            next_event: Vec::new(),
-        };
+        let mut me = Self { hart_count: 0 };
         let mut interrupts = node
             .property("interrupts-extended")
             .unwrap()
@@ -108,7 +99,7 @@ impl Clint {
             register_irq(virq1 as u32, Box::new(ClintConnector { hart_id, irq: 1 }));
             hart_id += 1;
         }
-        me.next_event.resize_with(hart_id, || 0);
+        me.hart_count = hart_id;
         me
     }
 
@@ -117,19 +108,8 @@ impl Clint {
             IRQ_IPI => {
                 println!("IPI interrupt at {}", hart_id);
             }
-            IRQ_TIMER => {
-                let mtime: usize;
-                unsafe {
-                    asm!(
-                    "rdtime t0",
-                    lateout("t0") mtime
-                    )
-                };
-
-                self.next_event[hart_id] =
-                    max(self.next_event[hart_id], mtime as u64) + self.freq / TICKS_PER_SECOND;
-                sbi_rt::set_timer(self.next_event[hart_id]).expect("SBI timer cannot be set!");
-            }
+            // Rearmed by arch::time::timer_interrupt once the connector releases the CLINT
+            IRQ_TIMER => {}
             _ => {
                 panic!("Unexpected CLINT irq")
             }
@@ -137,14 +117,7 @@ impl Clint {
     }
 
     pub fn init(self: &mut Self, hart: usize) {
-        let mtime: usize;
-        unsafe {
-            asm!(
-            "rdtime t0",
-            lateout("t0") mtime
-            )
-        };
-        self.next_event[hart] = mtime as u64 + (self.freq / TICKS_PER_SECOND);
-        sbi_rt::set_timer(self.next_event[hart]).expect("SBI timer cannot be set!");
+        assert!(hart < self.hart_count, "CLINT does not serve hart {}", hart);
+        crate::arch::time::rearm();
     }
 }
@@ -23,13 +23,6 @@ pub fn new_irqchip(ic_str: &str) -> Option<Box<dyn InterruptController>> {
 }
 
 pub unsafe fn init_clint(fdt: &Fdt) {
-    let cpus = fdt.find_node("/cpus").unwrap();
-    let clock_freq = cpus
-        .property("timebase-frequency")
-        .unwrap()
-        .as_usize()
-        .unwrap();
-
     let clint_node = fdt.find_node("/soc/clint").unwrap();
     assert!(clint_node
         .compatible()
@@ -38,7 +31,7 @@ pub unsafe fn init_clint(fdt: &Fdt) {
         .find(|x| ((*x).eq("riscv,clint0")))
         .is_some());
 
-    let clint = Clint::new(clock_freq, &clint_node);
+    let clint = Clint::new(&clint_node);
     *clint::CLINT.lock() = Some(clint);
     clint::CLINT.lock().as_mut().unwrap().init(0);
 }
@@ -1,3 +1,5 @@
+use core::cell::Cell;
+
 use crate::{
     arch::{device::irqchip::hlic, time},
     dtb::DTB_BINARY,
@@ -101,10 +103,15 @@ pub unsafe fn init_noncore() {
     }
 }
 
-pub struct ArchPercpuMisc;
+pub struct ArchPercpuMisc {
+    /// Earliest wake deadline asked of the timer on this hart, in nanoseconds, or 0 if none
+    pub wake_deadline: Cell<u64>,
+}
 
 impl ArchPercpuMisc {
     pub const fn default() -> Self {
-        Self
+        Self {
+            wake_deadline: Cell::new(0),
+        }
     }
 }
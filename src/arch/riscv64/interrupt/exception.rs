@@ -3,7 +3,7 @@ use core::{arch::naked_asm, sync::atomic::Ordering};
 use rmm::VirtualAddress;
 
 use crate::{
-    arch::{device::irqchip, start::BOOT_HART_ID},
+    arch::{device::irqchip, start::BOOT_HART_ID, SSTATUS_SUM},
     context::signal::excp_handler,
     memory::GenericPfFlags,
     panic::stack_trace,
@@ -40,6 +40,8 @@ pub unsafe extern "C" fn exception_handler() {
             "ld      sp, 8(tp)",
 
             push_registers!(),
+            "li      t1, {1}",
+            "csrc    sstatus, t1", // a trap in usercopy must not leave user memory accessible
             "ld      t0, 0(tp)",
             "sd      t0, (1 * 8)(sp)", // save original SP
             "csrrw   t0, sscratch, tp",
@@ -61,6 +63,8 @@ pub unsafe extern "C" fn exception_handler() {
             "addi    sp, sp, -2 * 8", // fake stack frame for the stack tracer
 
             push_registers!(),
+            "li      t1, {1}",
+            "csrc    sstatus, t1",
 
             "addi    t1, sp, 34 * 8",
             "sd      t1, (1 * 8)(sp)", // save original SP
@@ -83,7 +87,8 @@ pub unsafe extern "C" fn exception_handler() {
         "4:",
             pop_registers!(),
             "sret",
-            sym exception_handler_inner
+            sym exception_handler_inner,
+            const SSTATUS_SUM,
         );
     }
 }
@@ -112,6 +117,13 @@ unsafe fn exception_handler_inner(regs: &mut InterruptStack) {
         } else {
             handle_system_exception(scause, regs);
         }
+
+        // SUM was cleared on entry, and is only set again when returning into a usercopy
+        let usercopy_region = crate::kernel_executable_offsets::__usercopy_start()
+            ..crate::kernel_executable_offsets::__usercopy_end();
+        if !user_mode && usercopy_region.contains(&regs.iret.sepc) {
+            core::arch::asm!("csrs sstatus, {}", in(reg) SSTATUS_SUM, options(nostack));
+        }
         //info!("Exception handler outgoing");
     }
 }
@@ -216,14 +216,27 @@ impl ArchIntCtx for InterruptStack {
     fn ip(&self) -> usize {
         self.iret.sepc
     }
+    fn sp(&self) -> usize {
+        self.stack_pointer()
+    }
     fn recover_and_efault(&mut self) {
-        // Set the return value to nonzero to indicate usercopy failure (EFAULT), and emulate the
-        // return instruction by setting the return pointer to the saved LR value.
-        self.iret.sepc = self.registers.x1; // ra
-        self.registers.x10 = 1; // a0
+        // The fault came from the copy loop in arch_copy_to_user, which takes no stack, so
+        // returning through the trampoline finishes the copy function with the count in a2.
+        self.iret.sepc = usercopy_trampoline as usize;
     }
 }
 
+#[unsafe(naked)]
+unsafe extern "C" fn usercopy_trampoline() {
+    core::arch::naked_asm!(
+        "
+        # The trap handler already cleared SUM, and a2 still holds the bytes not copied
+        mv a0, a2
+        ret
+    "
+    );
+}
+
 /// Except for sp and tp
 #[macro_export]
 macro_rules! push_registers {
@@ -22,6 +22,15 @@ pub unsafe fn enable() {
     unsafe { asm!("csrsi sstatus, 1 << 1") }
 }
 
+/// Whether interrupts are enabled on this hart
+#[inline(always)]
+pub fn are_enabled() -> bool {
+    let sstatus: usize;
+    unsafe { asm!("csrr {}, sstatus", out(reg) sstatus, options(nomem, nostack)) };
+    // SIE
+    sstatus & (1 << 1) != 0
+}
+
 /// Set interrupts and halt
 /// This will atomically wait for the next interrupt
 /// Performing enable followed by halt is not guaranteed to be atomic, use this instead!
@@ -1,35 +1,40 @@
-use core::{arch::asm, mem};
+use core::{arch::asm, mem, ops::Range};
 
+/// A frame in the frame pointer chain. `s0` points just above the saved registers, the return
+/// address at `s0 - 8` and the caller's saved `s0` below it.
 pub struct StackTrace {
+    /// Address of the saved frame pointer
     pub fp: usize,
+    /// Address of the return address
     pub pc_ptr: *const usize,
 }
 
 impl StackTrace {
+    /// The frame of the function this is inlined into
     #[inline(always)]
     pub unsafe fn start() -> Option<Self> {
-        unsafe {
-            let fp: usize;
-            asm!("mv {}, fp", out(reg) fp);
+        let fp: usize;
+        unsafe { asm!("mv {}, s0", out(reg) fp) };
+        Self::from_fp(fp)
+    }
 
-            let pc_ptr = fp.checked_sub(mem::size_of::<usize>())?;
-            let fp = pc_ptr.checked_sub(mem::size_of::<usize>())?;
-            Some(StackTrace {
-                fp,
-                pc_ptr: pc_ptr as *const usize,
-            })
-        }
+    /// The frame whose frame pointer register held `fp`
+    pub fn from_fp(fp: usize) -> Option<Self> {
+        let pc_ptr = fp.checked_sub(mem::size_of::<usize>())?;
+        let fp = pc_ptr.checked_sub(mem::size_of::<usize>())?;
+        Some(StackTrace {
+            fp,
+            pc_ptr: pc_ptr as *const usize,
+        })
     }
 
+    /// The caller's frame. `self.fp` must be readable.
     pub unsafe fn next(self) -> Option<Self> {
-        unsafe {
-            let fp = *(self.fp as *const usize);
-            let pc_ptr = fp.checked_sub(mem::size_of::<usize>())?;
-            let fp = pc_ptr.checked_sub(mem::size_of::<usize>())?;
-            Some(StackTrace {
-                fp: fp,
-                pc_ptr: pc_ptr as *const usize,
-            })
-        }
+        Self::from_fp(unsafe { *(self.fp as *const usize) })
     }
 }
+
+/// The stacks of this CPU other than the kernel stacks of contexts, which are not tracked here
+pub fn cpu_stacks() -> impl Iterator<Item = Range<usize>> {
+    core::iter::empty()
+}
@@ -21,4 +21,41 @@ pub fn console_getchar() -> isize {
 
 pub fn set_timer(time: u64) {
     sbi_rt::set_timer(time);
-}
\ No newline at end of file
+}
+
+/// SSTATUS.SUM, which lets S-mode access user pages. Only set within the usercopy functions, and
+/// cleared on every trap entry.
+pub const SSTATUS_SUM: usize = 1 << 18;
+
+/// See documentation in `src/syscall/usercopy.rs`.
+///
+/// The trap handler sets SUM again when returning into the copy loop, and a fault in it returns
+/// through `usercopy_trampoline` with the bytes left in a2.
+#[unsafe(naked)]
+pub unsafe extern "C" fn arch_copy_to_user(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    core::arch::naked_asm!(
+        "
+    .global __usercopy_start
+    __usercopy_start:
+        li t0, {sum}
+        csrs sstatus, t0
+        beqz a2, 2f
+    1:
+        lbu t1, 0(a1)
+        sb t1, 0(a0)
+        addi a0, a0, 1
+        addi a1, a1, 1
+        addi a2, a2, -1
+        bnez a2, 1b
+    2:
+    .global __usercopy_end
+    __usercopy_end:
+        li t0, {sum}
+        csrc sstatus, t0
+        mv a0, a2
+        ret
+    ",
+        sum = const SSTATUS_SUM,
+    );
+}
+pub use arch_copy_to_user as arch_copy_from_user;
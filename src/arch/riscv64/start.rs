@@ -129,6 +129,9 @@ unsafe extern "C" fn start(args_ptr: *const KernelArgs) -> ! {
             crate::log::init();
 
             crate::dtb::init(dtb_data);
+            if let Some(dtb) = &dtb {
+                crate::topology::devicetree::init(dtb);
+            }
 
             // Initialize devices
             device::init();
@@ -3,27 +3,120 @@ use core::{
     sync::atomic::{AtomicUsize, Ordering},
 };
 
+use crate::{context, percpu::PercpuBlock, scheduler, sync::CleanLockToken};
+
 /// The frequency of the `mtime` counter in Hz.
 static MTIME_FREQ_HZ: AtomicUsize = AtomicUsize::new(0);
 
+/// Period of the timer interrupt while neither the scheduler nor a sleeper has a deadline.
+const IDLE_TICK_NS: u64 = 1_000_000;
+
 /// Initializes the timekeeping system.
 pub fn init(freq_hz: usize) {
     MTIME_FREQ_HZ.store(freq_hz, Ordering::Relaxed);
 }
 
-/// Returns the monotonic time in nanoseconds.
-pub fn monotonic_absolute() -> u128 {
+fn rdtime() -> u64 {
+    let counter: usize;
+    unsafe {
+        asm!(
+        "rdtime t0",
+        lateout("t0") counter
+        );
+    };
+    counter as u64
+}
+
+/// Converts `mtime` ticks to nanoseconds.
+pub fn ticks_to_ns(ticks: u64) -> u64 {
     let freq_hz = MTIME_FREQ_HZ.load(Ordering::Relaxed);
     if freq_hz > 0 {
-        let counter: usize;
-        unsafe {
-            asm!(
-            "rdtime t0",
-            lateout("t0") counter
-            );
-        };
-        counter as u128 * 1_000_000_000u128 / freq_hz as u128
+        (ticks as u128 * 1_000_000_000u128 / freq_hz as u128) as u64
     } else {
         0
     }
 }
+
+/// Converts nanoseconds to `mtime` ticks, rounding up so that a timer never fires early.
+pub fn ns_to_ticks(ns: u64) -> u64 {
+    let freq_hz = MTIME_FREQ_HZ.load(Ordering::Relaxed) as u128;
+    (ns as u128 * freq_hz).div_ceil(1_000_000_000u128) as u64
+}
+
+/// Returns the monotonic time in nanoseconds.
+pub fn monotonic_absolute() -> u128 {
+    ticks_to_ns(rdtime()) as u128
+}
+
+/// Asks for a timer interrupt on this hart no later than `deadline`, in nanoseconds.
+pub fn set_wake_deadline(deadline: u64) {
+    let wake_deadline = &PercpuBlock::current().misc_arch_info.wake_deadline;
+    let old = wake_deadline.get();
+    if old == 0 || deadline < old {
+        wake_deadline.set(deadline);
+    }
+    rearm();
+}
+
+/// Programs the SBI timer for the next event on this hart.
+///
+/// That is the earlier of the scheduler's time slice deadline and any wake deadline, or one
+/// idle tick from now if there is neither. The lockup detector needs a timer interrupt at least
+/// once per check, so the timer is never armed further out than that while it is enabled.
+pub fn rearm() {
+    let now = monotonic_absolute() as u64;
+    let scheduler = scheduler::scheduler();
+    let slice = scheduler
+        .get_next_timer(&scheduler.lock().run_queue)
+        .filter(|&deadline| deadline > now);
+    let wake = Some(PercpuBlock::current().misc_arch_info.wake_deadline.get())
+        .filter(|&deadline| deadline != 0);
+
+    let deadline = match (slice, wake) {
+        (Some(slice), Some(wake)) => slice.min(wake),
+        (Some(deadline), None) | (None, Some(deadline)) => deadline,
+        (None, None) => now + IDLE_TICK_NS,
+    };
+    let deadline = if crate::watchdog::enabled() {
+        deadline.min(now + crate::watchdog::CHECK_INTERVAL_NS)
+    } else {
+        deadline
+    };
+    // An expired wake deadline makes the timer fire right away
+    sbi_rt::set_timer(ns_to_ticks(deadline));
+}
+
+/// Handles the supervisor timer interrupt.
+///
+/// Fires expired timeouts, and preempts the current context when its time slice is over or a
+/// more urgent context became runnable, before arming the timer again.
+pub fn timer_interrupt(token: &mut CleanLockToken) {
+    let now = monotonic_absolute() as u64;
+
+    let wake_deadline = &PercpuBlock::current().misc_arch_info.wake_deadline;
+    if wake_deadline.get() <= now {
+        wake_deadline.set(0);
+    }
+    crate::watchdog::tick();
+
+    context::timeout::trigger(token);
+    crate::log::wake_kmsg_readers(token);
+    crate::deferred::tick(token);
+
+    let preempt = {
+        let scheduler = scheduler::scheduler();
+        let state = scheduler.lock_irq();
+        let slice_expired = scheduler
+            .get_next_timer(&state.run_queue)
+            .is_some_and(|deadline| deadline <= now);
+        crate::preempt::preempt_enabled()
+            && (slice_expired || scheduler.should_preempt(&state.run_queue, token))
+    };
+    if preempt {
+        unsafe {
+            context::switch(token);
+        }
+    }
+
+    rearm();
+}
@@ -287,6 +287,7 @@ macro_rules! interrupt_stack {
         #[unsafe(naked)]
         pub unsafe extern "C" fn $name() {
             unsafe extern "fastcall" fn inner($stack: &mut $crate::arch::x86::interrupt::InterruptStack) {
+                let _locks = $crate::sync::InterruptedLocks::set_aside();
                 #[allow(unused_unsafe)]
                 unsafe {
                     $code
@@ -336,6 +337,7 @@ macro_rules! interrupt {
         #[unsafe(naked)]
         pub unsafe extern "C" fn $name() {
             unsafe extern "C" fn inner() {
+                let _locks = $crate::sync::InterruptedLocks::set_aside();
                 $code
             }
 
@@ -376,6 +378,7 @@ macro_rules! interrupt_error {
         #[unsafe(naked)]
         pub unsafe extern "C" fn $name() {
             unsafe extern "C" fn inner($stack: &mut $crate::arch::x86::interrupt::handler::InterruptErrorStack) {
+                let _locks = $crate::sync::InterruptedLocks::set_aside();
                 let $error_code: usize = $stack.code;
                 $code
             }
@@ -432,7 +435,8 @@ unsafe extern "C" fn usercopy_trampoline() {
         pop esi
         pop edi
 
-        mov eax, 1
+        # ECX still holds the number of bytes REP MOVSB did not copy
+        mov eax, ecx
         ret
     "
     );
@@ -442,6 +446,9 @@ impl ArchIntCtx for InterruptStack {
     fn ip(&self) -> usize {
         self.iret.eip
     }
+    fn sp(&self) -> usize {
+        self.stack_pointer()
+    }
     fn recover_and_efault(&mut self) {
         // Unlike on x86_64, Protected Mode interrupts will not save/restore esp and ss unless
         // privilege rings changed, which they won't here as we are catching a kernel-induced page
@@ -15,7 +15,7 @@ pub mod flags {
 
 /// See documentation in `src/syscall/usercopy.rs`.
 #[unsafe(naked)]
-pub unsafe extern "C" fn arch_copy_to_user(dst: usize, src: usize, len: usize) -> u8 {
+pub unsafe extern "C" fn arch_copy_to_user(dst: *mut u8, src: *const u8, len: usize) -> usize {
     core::arch::naked_asm!(
         "
     .global __usercopy_start
@@ -31,7 +31,7 @@ pub unsafe extern "C" fn arch_copy_to_user(dst: usize, src: usize, len: usize) -
         pop esi
         pop edi
 
-        xor eax, eax
+        mov eax, ecx
         ret
     .global __usercopy_end
     __usercopy_end:
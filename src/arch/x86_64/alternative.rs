@@ -40,6 +40,7 @@ pub unsafe fn apply_alternatives() {
     }
 }
 
+/// Size of the kfx area saved on context switch, as detected at boot
 pub fn kfx_size() -> usize {
-    512
+    super::features::kfx_size()
 }
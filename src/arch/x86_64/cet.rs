@@ -0,0 +1,291 @@
+//! # Control-flow Enforcement Technology
+//!
+//! Supervisor shadow stacks keep a second copy of every return address the kernel pushes, on
+//! pages ordinary stores cannot write, and fault with #CP when a `ret` disagrees with it. IBT is
+//! only reported, as the kernel is not built with ENDBR64 landing pads.
+//!
+//! Every kernel stack has a shadow stack in the slot of the shadow stack region matching its own
+//! slot in the kstack region, and every CPU has one for its boot stack and one for its IST stack
+//! in the upper half of the region. Each has a supervisor token at its top, where the CPU
+//! switches to on entering the kernel from userspace: interrupts load it from `IA32_PL0_SSP`,
+//! which the context switch keeps pointing at the token of the context it switches to, and
+//! syscalls with `setssbsy`. A context switch moves between the shadow stacks of the two contexts
+//! with `rstorssp` and `saveprevssp`, which leaves a restore token on the stack switched away
+//! from to come back to it with.
+//!
+//! Shadow stacks are enabled last in the start code of each CPU, by [`enter`], since the shadow
+//! stack a CPU switches to holds none of the calls made before, so none of them may return.
+
+use alloc::boxed::Box;
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use x86::{
+    controlregs::{cr4, cr4_write, Cr4},
+    msr,
+};
+
+use super::{
+    consts::{KERNEL_SHADOW_STACK_OFFSET, KERNEL_SHADOW_STACK_SIZE},
+    features::{self, FeatureFlags},
+};
+use crate::{
+    cpu_set::LogicalCpuId,
+    memory::{allocate_p2frame, deallocate_p2frame, Enomem, Frame, KernelMapper},
+    paging::{entry::EntryFlags, PageFlags, RmmA, RmmArch, VirtualAddress, PAGE_SIZE},
+    startup::params,
+};
+
+// IA32_S_CET: MSR enabling CET features
+const IA32_S_CET: u32 = 0x6A2;
+// IA32_PL0_SSP: MSR holding the Shadow Stack Pointer for Ring 0
+const IA32_PL0_SSP: u32 = 0x6A4;
+// IA32_INTERRUPT_SSP_TABLE_ADDR: MSR holding the address of the shadow stack of each IST stack
+const IA32_INTERRUPT_SSP_TABLE_ADDR: u32 = 0x6A8;
+
+// S_CET Bits
+const S_CET_SH_STK_EN: u64 = 1 << 0; // Supervisor shadow stacks
+const S_CET_WR_SHSTK_EN: u64 = 1 << 1; // WRSS in supervisor mode
+
+// CR4 Bit for Control-flow Enforcement
+const CR4_CET_ENABLE: Cr4 = Cr4::from_bits_truncate(1 << 23);
+
+/// Supervisor token bit marking its shadow stack as in use
+const TOKEN_BUSY: u64 = 1;
+/// Restore token bit marking it as made in 64-bit mode
+const TOKEN_64BIT: u64 = 1;
+
+/// Shadow stacks are allocated as naturally aligned blocks of this order
+pub const SHADOW_STACK_ORDER: u32 = 1;
+/// Size of a shadow stack
+pub const SHADOW_STACK_SIZE: usize = PAGE_SIZE << SHADOW_STACK_ORDER;
+/// Each slot is a guard page followed by a shadow stack
+pub const SLOT_SIZE: usize = PAGE_SIZE + SHADOW_STACK_SIZE;
+/// The first slot of the per-CPU stacks, each CPU having two
+const CPU_SLOTS: usize = KERNEL_SHADOW_STACK_SIZE / 2 / SLOT_SIZE;
+/// Number of slots in the region
+const SLOT_COUNT: usize = KERNEL_SHADOW_STACK_SIZE / SLOT_SIZE;
+
+/// Whether shadow stacks are in use, decided once by [`init_bsp`] before any context exists
+pub(crate) static ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[inline]
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Lowest address of the shadow stack in `slot`
+pub const fn slot_bottom(slot: usize) -> usize {
+    KERNEL_SHADOW_STACK_OFFSET
+        .saturating_add(slot.saturating_mul(SLOT_SIZE))
+        .saturating_add(PAGE_SIZE)
+}
+
+/// Number of slots below the per-CPU ones, one for each kernel stack slot
+pub const fn kstack_slots() -> usize {
+    CPU_SLOTS
+}
+
+/// The top of the shadow stack containing `addr`, if it is in the region
+fn stack_top(addr: usize) -> Option<usize> {
+    let slot = addr.checked_sub(KERNEL_SHADOW_STACK_OFFSET)? / SLOT_SIZE;
+    (slot < SLOT_COUNT).then(|| slot_bottom(slot).saturating_add(SHADOW_STACK_SIZE))
+}
+
+/// A shadow stack, mapped read-only and dirty as the CPU wants it
+pub struct ShadowStack {
+    /// naturally aligned, of order [`SHADOW_STACK_ORDER`]
+    base: Frame,
+    /// Lowest address of the stack
+    bottom: usize,
+}
+
+impl ShadowStack {
+    /// Allocate a shadow stack and map it at `bottom`
+    pub fn new(bottom: usize) -> Result<Self, Enomem> {
+        let base = allocate_p2frame(SHADOW_STACK_ORDER).ok_or(Enomem)?;
+        let stack = Self { base, bottom };
+
+        // Present, read-only and dirty is the shadow stack encoding
+        let flags = PageFlags::new().custom_flag(EntryFlags::DIRTY.bits(), true);
+        let mut mapper_lock = KernelMapper::lock();
+        let mapper = mapper_lock
+            .get_mut()
+            .expect("KernelMapper mapper locked re-entrant in ShadowStack::new");
+        for (i, page) in stack.pages().enumerate() {
+            let phys = base.base().add(i.saturating_mul(PAGE_SIZE));
+            match unsafe { mapper.map_phys(VirtualAddress::new(page), phys, flags) } {
+                Some(flush) => flush.flush(),
+                None => {
+                    // Nothing else has seen the pages yet, so no other CPU can have them cached
+                    for mapped in stack.pages().take(i) {
+                        let virt = VirtualAddress::new(mapped);
+                        if let Some((_, _, flush)) = unsafe { mapper.unmap_phys(virt, false) } {
+                            flush.flush();
+                        }
+                    }
+                    drop(mapper_lock);
+                    unsafe { deallocate_p2frame(base, SHADOW_STACK_ORDER) };
+                    return Err(Enomem);
+                }
+            }
+        }
+        Ok(stack)
+    }
+
+    /// Virtual address of each page of the stack
+    pub fn pages(&self) -> impl Iterator<Item = usize> + Clone + use<> {
+        let bottom = self.bottom;
+        (0..SHADOW_STACK_SIZE)
+            .step_by(PAGE_SIZE)
+            .map(move |offset| bottom.saturating_add(offset))
+    }
+
+    /// Address of the supervisor token, at the top of the stack
+    pub fn token(&self) -> usize {
+        self.bottom
+            .saturating_add(SHADOW_STACK_SIZE)
+            .saturating_sub(size_of::<u64>())
+    }
+
+    /// Store `value` at `addr` through the physmap, as the stack itself is read-only
+    fn store(&self, addr: usize, value: u64) {
+        let offset = addr.saturating_sub(self.bottom);
+        debug_assert!(offset < SHADOW_STACK_SIZE);
+        let virt = RmmA::phys_to_virt(self.base.base())
+            .data()
+            .saturating_add(offset);
+        unsafe { (virt as *mut u64).write(value) };
+    }
+
+    /// Lay out the stack of a context that has not run yet, which returns to `returns` in turn,
+    /// the last one first. Returns the address of the restore token to switch to it with.
+    pub fn prepare(&self, returns: &[usize]) -> usize {
+        // The context starts out in the kernel, as if it had come in through the token
+        let token = self.token();
+        self.store(token, token as u64 | TOKEN_BUSY);
+
+        let mut ssp = token;
+        for &ret in returns {
+            ssp = ssp.saturating_sub(size_of::<u64>());
+            self.store(ssp, ret as u64);
+        }
+
+        // rstorssp continues right above the restore token
+        let restore = ssp.saturating_sub(size_of::<u64>());
+        self.store(restore, ssp as u64 | TOKEN_64BIT);
+        restore
+    }
+
+    /// Free the frames of the stack, once it has been unmapped everywhere
+    pub unsafe fn free(self) {
+        unsafe { deallocate_p2frame(self.base, SHADOW_STACK_ORDER) }
+    }
+}
+
+/// Decide whether to use shadow stacks. Runs on the BSP before any AP starts, once the boot
+/// parameters are parsed, as `nocet` turns them off.
+pub fn init_bsp() {
+    let flags = features::get().flags;
+    if !flags.contains(FeatureFlags::CET_SS) {
+        info!("CET: shadow stacks not supported, skipping");
+        return;
+    }
+    if params::param_bool("nocet") == Some(true) {
+        info!("CET: disabled by nocet");
+        return;
+    }
+    ENABLED.store(true, Ordering::Relaxed);
+    info!(
+        "CET: supervisor shadow stacks enabled{}",
+        if flags.contains(FeatureFlags::CET_IBT) {
+            ", IBT available but not enabled"
+        } else {
+            ""
+        }
+    );
+}
+
+/// Enable shadow stacks on this CPU if [`init_bsp`] chose to, then run `entry`
+///
+/// The CPU switches onto a fresh shadow stack here, so this has to be the last thing its start
+/// code does, and `entry` must never return.
+pub unsafe fn enter(cpu: LogicalCpuId, entry: impl FnOnce() -> !) -> ! {
+    if enabled() {
+        if unsafe { enable(cpu) }.is_err() {
+            // Contexts get shadow stacks of their own and switch between them on every CPU
+            panic!("CET: failed to set up the shadow stacks of CPU {}", cpu);
+        }
+        // Switch onto the boot shadow stack, marking its token busy. Nothing called so far may
+        // return from here on, which is why this is not in enable.
+        unsafe { asm!("setssbsy") };
+    }
+    entry()
+}
+
+unsafe fn enable(cpu: LogicalCpuId) -> Result<(), Enomem> {
+    let slot = CPU_SLOTS.saturating_add((cpu.get() as usize).saturating_mul(2));
+    let boot = ShadowStack::new(slot_bottom(slot))?;
+    let ist = ShadowStack::new(slot_bottom(slot.saturating_add(1)))?;
+
+    // Both are entered through their free token, the boot stack by setssbsy below and the IST
+    // stack by every interrupt using the backup IST stack
+    boot.store(boot.token(), boot.token() as u64);
+    ist.store(ist.token(), ist.token() as u64);
+    let mut table = Box::new([0_u64; 8]);
+    if let Some(entry) = table.get_mut(usize::from(crate::idt::BACKUP_IST)) {
+        *entry = ist.token() as u64;
+    }
+
+    unsafe {
+        msr::wrmsr(
+            IA32_INTERRUPT_SSP_TABLE_ADDR,
+            Box::leak(table).as_ptr() as u64,
+        );
+        msr::wrmsr(IA32_PL0_SSP, boot.token() as u64);
+
+        cr4_write(cr4() | CR4_CET_ENABLE);
+        // Writes are only needed to move the return address of an interrupted usercopy
+        let s_cet = msr::rdmsr(IA32_S_CET) | S_CET_SH_STK_EN | S_CET_WR_SHSTK_EN;
+        msr::wrmsr(IA32_S_CET, s_cet);
+    }
+    Ok(())
+}
+
+/// Point `IA32_PL0_SSP` at the token of `stack`, which is where the CPU enters the kernel from
+/// userspace
+pub unsafe fn set_kernel_entry(stack: &ShadowStack) {
+    unsafe { msr::wrmsr(IA32_PL0_SSP, stack.token() as u64) }
+}
+
+/// Make the interrupt that stopped the kernel at `old` return to `new` instead
+///
+/// IRET checks the address it returns to against the one the CPU saved on the shadow stack, so
+/// that copy has to be moved as well. The CPU saved the SSP it interrupted, the return address
+/// and CS, with the SSP lowest and pointing right above the frame.
+pub fn redirect_interrupt_return(old: usize, new: usize) {
+    if !enabled() {
+        return;
+    }
+    let mut ssp: usize;
+    unsafe { asm!("rdsspq {}", out(reg) ssp, options(nomem, nostack)) };
+    let Some(top) = stack_top(ssp) else {
+        return;
+    };
+
+    let frame_size = 3 * size_of::<u64>();
+    let mut frame = ssp;
+    while frame.saturating_add(frame_size) <= top {
+        let words = frame as *const u64;
+        let (saved_ssp, lip) = unsafe { (words.read(), words.add(1).read()) };
+        if saved_ssp == frame.saturating_add(frame_size) as u64 && lip == old as u64 {
+            let lip_addr = frame.saturating_add(size_of::<u64>());
+            unsafe {
+                asm!("wrssq [{}], {}", in(reg) lip_addr, in(reg) new as u64, options(nostack))
+            };
+            return;
+        }
+        frame = frame.saturating_add(size_of::<u64>());
+    }
+}
@@ -23,6 +23,18 @@ pub const KERNEL_HEAP_PML4: usize = (KERNEL_HEAP_OFFSET & PML4_MASK) / PML4_SIZE
 /// Size of kernel heap
 pub const KERNEL_HEAP_SIZE: usize = 1 * 1024 * 1024; // 1 MB
 
+/// Offset of kernel stacks, each mapped with an unmapped guard page below it. The region is in
+/// the kernel's own PML4, above where the heap grows, so that every address space shares it.
+pub const KERNEL_KSTACK_OFFSET: usize = KERNEL_HEAP_OFFSET + PML4_SIZE / 2;
+/// Size of the kernel stack region
+pub const KERNEL_KSTACK_SIZE: usize = PML4_SIZE / 4;
+
+/// Offset of supervisor shadow stacks, right above the kernel stacks and laid out the same way,
+/// followed by those of each CPU
+pub const KERNEL_SHADOW_STACK_OFFSET: usize = KERNEL_KSTACK_OFFSET + KERNEL_KSTACK_SIZE;
+/// Size of the shadow stack region
+pub const KERNEL_SHADOW_STACK_SIZE: usize = PML4_SIZE / 4;
+
 /// Offset of physmap
 // This needs to match RMM's PHYS_OFFSET
 pub const PHYS_OFFSET: usize = 0xFFFF_8000_0000_0000;
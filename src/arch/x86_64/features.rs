@@ -0,0 +1,166 @@
+//! # CPU Feature Detection
+//!
+//! Detects the CPU features the kernel cares about once on the BSP, and programs CR4 and XCR0
+//! identically on every CPU. Context switching sizes and saves the extended state according to
+//! what is recorded here, and the IOMMU driver checks it for AMD-Vi support, so nothing else
+//! should touch XCR0.
+
+use raw_cpuid::CpuId;
+use spin::Once;
+use x86::controlregs::{self, Cr4, Xcr0};
+
+use crate::context::arch::KFX_ALIGN;
+
+/// Size of the legacy FXSAVE area, used when XSAVE is unavailable
+const FXSAVE_SIZE: usize = 512;
+
+bitflags! {
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct FeatureFlags: u64 {
+        const AVX       = 1 << 0;
+        const AVX2      = 1 << 1;
+        const AVX512F   = 1 << 2; // Foundation
+        const AVX512VL  = 1 << 3; // Vector Length Extensions
+        const AVX512BW  = 1 << 4; // Byte and Word Instructions
+        const IOMMU     = 1 << 5; // AMD-Vi
+        const XSAVE     = 1 << 6;
+        const XSAVEOPT  = 1 << 7;
+        const XSAVEC    = 1 << 8; // Compacted XSAVE
+        const XGETBV    = 1 << 9;
+        const FSGSBASE  = 1 << 10;
+        const CET_SS    = 1 << 11; // Shadow stacks
+        const CET_IBT   = 1 << 12; // Indirect branch tracking
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CpuFeatures {
+    pub flags: FeatureFlags,
+    /// XCR0 value written on every CPU, empty without XSAVE
+    pub xcr0: Xcr0,
+    /// Size of the area XSAVE writes for the state enabled in `xcr0`, or of the FXSAVE area
+    pub xsave_size: usize,
+}
+
+impl CpuFeatures {
+    /// Legacy FXSAVE only, assumed until detection has run
+    const LEGACY: Self = Self {
+        flags: FeatureFlags::empty(),
+        xcr0: Xcr0::empty(),
+        xsave_size: FXSAVE_SIZE,
+    };
+
+    fn detect() -> Self {
+        let cpuid = CpuId::new();
+        let mut flags = FeatureFlags::empty();
+
+        if let Some(features) = cpuid.get_feature_info() {
+            flags.set(FeatureFlags::AVX, features.has_avx());
+            flags.set(FeatureFlags::XSAVE, features.has_xsave());
+        }
+        if let Some(features) = cpuid.get_extended_feature_info() {
+            flags.set(FeatureFlags::AVX2, features.has_avx2());
+            flags.set(FeatureFlags::AVX512F, features.has_avx512f());
+            flags.set(FeatureFlags::AVX512VL, features.has_avx512vl());
+            flags.set(FeatureFlags::AVX512BW, features.has_avx512bw());
+            flags.set(FeatureFlags::FSGSBASE, features.has_fsgsbase());
+        }
+        // CPUID.(EAX=7,ECX=0):ECX[7] and EDX[20], which raw_cpuid does not decode
+        let leaf7 = unsafe { core::arch::x86_64::__cpuid_count(7, 0) };
+        flags.set(FeatureFlags::CET_SS, leaf7.ecx & (1 << 7) != 0);
+        flags.set(FeatureFlags::CET_IBT, leaf7.edx & (1 << 20) != 0);
+        if let Some(svm) = cpuid.get_svm_info() {
+            flags.set(FeatureFlags::IOMMU, svm.has_npt());
+        }
+
+        // Partial AVX-512 support is not worth the state size, so use all of it or none
+        if !flags.contains(FeatureFlags::AVX512F | FeatureFlags::AVX512VL | FeatureFlags::AVX512BW)
+        {
+            flags.remove(FeatureFlags::AVX512F | FeatureFlags::AVX512VL | FeatureFlags::AVX512BW);
+        }
+
+        let mut xcr0 = Xcr0::empty();
+        if flags.contains(FeatureFlags::XSAVE) {
+            flags |= FeatureFlags::XGETBV;
+            if let Some(state) = cpuid.get_extended_state_info() {
+                flags.set(FeatureFlags::XSAVEOPT, state.has_xsaveopt());
+                flags.set(FeatureFlags::XSAVEC, state.has_xsavec());
+            }
+
+            xcr0 = Xcr0::XCR0_FPU_MMX_STATE | Xcr0::XCR0_SSE_STATE;
+            if flags.contains(FeatureFlags::AVX) {
+                xcr0 |= Xcr0::XCR0_AVX_STATE;
+            }
+            if flags.contains(FeatureFlags::AVX512F) {
+                xcr0 |= Xcr0::XCR0_AVX512_OPMASK_STATE
+                    | Xcr0::XCR0_AVX512_ZMM_HI256_STATE
+                    | Xcr0::XCR0_AVX512_HI16_ZMM_STATE;
+            }
+        }
+
+        Self {
+            flags,
+            xcr0,
+            xsave_size: FXSAVE_SIZE,
+        }
+    }
+
+    /// Program this CPU's CR4 and XCR0 for the detected features
+    unsafe fn enable(&self) {
+        unsafe {
+            let mut cr4 = controlregs::cr4();
+            if self.flags.contains(FeatureFlags::XSAVE) {
+                cr4 |= Cr4::CR4_ENABLE_OS_XSAVE;
+            }
+            if self.flags.contains(FeatureFlags::FSGSBASE) {
+                cr4 |= Cr4::CR4_ENABLE_FSGSBASE;
+            }
+            controlregs::cr4_write(cr4);
+
+            if self.flags.contains(FeatureFlags::XSAVE) {
+                controlregs::xcr0_write(self.xcr0);
+            }
+        }
+    }
+}
+
+static CPU_FEATURES: Once<CpuFeatures> = Once::new();
+
+/// Detect features and enable them on the BSP, before any context is created
+pub unsafe fn init_bsp() {
+    let features = CPU_FEATURES.call_once(|| {
+        let mut features = CpuFeatures::detect();
+        unsafe { features.enable() };
+
+        // Only valid once XCR0 is set, as it covers the enabled state components
+        if features.flags.contains(FeatureFlags::XSAVE)
+            && let Some(state) = CpuId::new().get_extended_state_info()
+        {
+            features.xsave_size =
+                (state.xsave_area_size_enabled_features() as usize).max(FXSAVE_SIZE);
+        }
+        features
+    });
+
+    info!(
+        "CPU features: {:?}, XCR0 {:#x}, extended state {} bytes",
+        features.flags,
+        features.xcr0.bits(),
+        features.xsave_size
+    );
+}
+
+/// Enable the features detected by the BSP on an AP
+pub unsafe fn init_ap() {
+    unsafe { get().enable() };
+}
+
+/// Features detected at boot, or legacy FXSAVE only if detection has not run yet
+pub fn get() -> &'static CpuFeatures {
+    CPU_FEATURES.get().unwrap_or(&CpuFeatures::LEGACY)
+}
+
+/// Size of the per-context extended state area, rounded up to `KFX_ALIGN`
+pub fn kfx_size() -> usize {
+    get().xsave_size.next_multiple_of(KFX_ALIGN)
+}
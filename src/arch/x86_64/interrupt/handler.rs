@@ -35,20 +35,22 @@ pub struct InterruptStack {
 
 impl InterruptStack {
     pub fn dump(&self) {
-        crate::println!("Interrupt Stack Dump:");
-        crate::println!(
+        let _ = self.dump_to(&mut crate::log::Writer::new());
+    }
+    /// Write what [`Self::dump`] prints to `out`
+    pub fn dump_to(&self, out: &mut impl core::fmt::Write) -> core::fmt::Result {
+        writeln!(out, "Interrupt Stack Dump:")?;
+        writeln!(
+            out,
             "RIP: {:016x} CS: {:016x} RFLAGS: {:016x}",
-            self.rip,
-            self.cs,
-            self.rflags
-        );
-        crate::println!("RSP: {:016x} SS: {:016x}", self.rsp, self.ss);
-        crate::println!(
+            self.rip, self.cs, self.rflags
+        )?;
+        writeln!(out, "RSP: {:016x} SS: {:016x}", self.rsp, self.ss)?;
+        writeln!(
+            out,
             "RAX: {:016x} RBX: {:016x} RCX: {:016x}",
-            self.rax,
-            self.rbx,
-            self.rcx
-        );
+            self.rax, self.rbx, self.rcx
+        )
     }
     pub fn init(&mut self) {
         *self = Self::default();
@@ -133,12 +135,29 @@ impl crate::memory::ArchIntCtx for InterruptStack {
     fn ip(&self) -> usize {
         self.rip as usize
     }
+    fn sp(&self) -> usize {
+        self.stack_pointer()
+    }
     fn recover_and_efault(&mut self) {
-        // Placeholder for recovery
-        self.rax = usize::MAX as u64;
+        // The fault came from the REP MOVSB in arch_copy_to_user, which takes no stack, so
+        // returning through the trampoline finishes the copy function with the count in RCX.
+        let trampoline = usercopy_trampoline as usize;
+        crate::arch::cet::redirect_interrupt_return(self.rip as usize, trampoline);
+        self.rip = trampoline as u64;
     }
 }
 
+#[unsafe(naked)]
+unsafe extern "C" fn usercopy_trampoline() {
+    core::arch::naked_asm!(
+        "
+        # RCX still holds the number of bytes REP MOVSB did not copy
+        mov rax, rcx
+        ret
+    "
+    );
+}
+
 // Wrapper for syscall handling
 #[unsafe(no_mangle)]
 pub extern "C" fn syscall_handler(stack: &mut InterruptStack) {
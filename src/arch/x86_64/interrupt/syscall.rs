@@ -1,5 +1,6 @@
 use crate::{
     arch::{gdt, interrupt::InterruptStack},
+    context::upcall,
     pop_preserved, pop_scratch, ptrace, ptrace_event, push_preserved, push_scratch,
     sync::CleanLockToken,
     syscall::flag::{PTRACE_FLAG_IGNORE, PTRACE_STOP_POST_SYSCALL, PTRACE_STOP_PRE_SYSCALL},
@@ -68,9 +69,12 @@ pub unsafe fn init() {
     }
 }
 
+/// Returns whether every register must be restored on the way back to usermode, rather than
+/// letting sysretq clobber rcx and r11
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn syscall_instruction(stack: *mut InterruptStack) {
+pub unsafe extern "C" fn syscall_instruction(stack: *mut InterruptStack) -> bool {
     let _guard = ptrace::set_process_regs(&mut *stack);
+    let mut full_restore = false;
 
     let mut token = CleanLockToken::new();
     let allowed = ptrace::breakpoint_callback(PTRACE_STOP_PRE_SYSCALL, None, &mut token)
@@ -100,9 +104,17 @@ pub unsafe extern "C" fn syscall_instruction(stack: *mut InterruptStack) {
 
         // Save the return value
         current_stack.rax = ret as u64;
+
+        // A finished upcall resumes the code it interrupted instead
+        if rax as usize == crate::syscall::SYS_UPCALL_RETURN {
+            full_restore = upcall::apply_return(current_stack, &mut token);
+        }
     }
 
     ptrace::breakpoint_callback(PTRACE_STOP_POST_SYSCALL, None, &mut token);
+
+    upcall::deliver(&mut *stack, &mut token);
+    full_restore
 }
 
 #[unsafe(naked)]
@@ -130,6 +142,12 @@ pub unsafe extern "C" fn syscall_instruction_entry() {
     "push QWORD PTR {cs_sel};",   // Push fake CS (resembling iret stack frame)
     "push rcx;",                  // Push userspace return pointer
 
+    // Enter the shadow stack of the kernel stack, whose token IA32_PL0_SSP points to
+    "cmp BYTE PTR [rip + {cet_enabled}], 0;",
+    "je 4f;",
+    "setssbsy;",
+    "4:",
+
     // Push context registers
     push_scratch!(),
     push_preserved!(),
@@ -140,10 +158,15 @@ pub unsafe extern "C" fn syscall_instruction_entry() {
     // Call inner funtion
     "mov rdi, rsp;",
     "call syscall_instruction;",
+    // Keep whether to restore all registers across unmap, r12 is popped from the stack anyway
+    "movzx r12d, al;",
 
     // TODO: Unmap PTI
     "call unmap;",
 
+    "test r12d, r12d;",
+    "jnz 3f;",
+
     "
     .globl enter_usermode
     enter_usermode:
@@ -204,6 +227,13 @@ pub unsafe extern "C" fn syscall_instruction_entry() {
     // 63:48 (0x8000_DEAD_BEEF_XXXX => 0xFFFF_8000_DEAD_BEEF).
     "sar rcx, 16;",
 
+    // Leave the shadow stack, as sysretq does not, for the next entry to use it again
+    "cmp BYTE PTR [rip + {cet_enabled}], 0;",
+    "je 5f;",
+    "rdsspq r11;",
+    "clrssbsy [r11];",
+    "5:",
+
     "add rsp, 8;",              // Pop fake userspace CS
     "pop r11;",                 // Pop rflags
     "pop rsp;",                 // Restore userspace stack pointer
@@ -216,12 +246,24 @@ pub unsafe extern "C" fn syscall_instruction_entry() {
     xor rcx, rcx
     xor r11, r11
     iretq
-    "),
+    ",
+
+    // Full restore, for a finished upcall which needs rcx and r11 as they were interrupted:
+    "
+    .p2align 4
+    3:
+    ",
+    pop_preserved!(),
+    pop_scratch!(),
+    "swapgs;",
+    "iretq;",
+    ),
 
     sp = const(offset_of!(gdt::ProcessorControlRegion, user_rsp_tmp)),
     ksp = const(offset_of!(gdt::ProcessorControlRegion, tss) + offset_of!(TaskStateSegment, rsp)),
     ss_sel = const(SegmentSelector::new(gdt::GDT_USER_DATA as u16, x86::Ring::Ring3).bits()),
     cs_sel = const(SegmentSelector::new(gdt::GDT_USER_CODE as u16, x86::Ring::Ring3).bits()),
+    cet_enabled = sym crate::arch::cet::ENABLED,
     );
 }
 unsafe extern "C" {
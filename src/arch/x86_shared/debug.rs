@@ -42,6 +42,18 @@ impl<'a> Writer<'a> {
         }
     }
 
+    /// Creates a new `Writer` if none of the ports is locked.
+    pub fn try_new() -> Option<Writer<'a>> {
+        Some(Writer {
+            lpss: LPSS.try_lock()?,
+            #[cfg(feature = "qemu_debug")]
+            qemu: QEMU.try_lock()?,
+            serial: COM1.try_lock()?,
+            #[cfg(feature = "system76_ec_debug")]
+            system76_ec: SYSTEM76_EC.try_lock()?,
+        })
+    }
+
     /// Writes to the serial port.
     pub fn write(&mut self, buf: &[u8]) {
         self.lpss.write(buf);
@@ -0,0 +1,829 @@
+//! AMD IOMMU (AMD-Vi) Driver
+//!
+//! This module handles the initialization of the AMD I/O Memory Management Unit to ensure
+//! DMA isolation and safety. Support is taken from the boot-time CPU feature detection in
+//! `arch::x86_64::features`, and the registers are found through the ACPI IVRS table.
+//!
+//! Every device has an entry in the device table, indexed by its PCI requester ID. At init all
+//! devices are put in the passthrough domain, whose DMA is not translated, so that drivers keep
+//! working as they did without an IOMMU. A driver that wants its device isolated creates a
+//! domain with [`create_domain`], maps its DMA buffers into it with [`map`] and moves the device
+//! there with [`attach`]. DMA to anything not mapped is then blocked, and reported in the event
+//! log read by [`poll_events`].
+//!
+//! Changes to the device table and to the I/O page tables are followed by an invalidation
+//! command and a completion wait, so they are in effect once the call returns.
+
+use alloc::collections::BTreeMap;
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use spin::{Mutex, Once};
+
+use crate::{
+    arch::features::{self, FeatureFlags},
+    memory::{
+        allocate_p2frame, deallocate_frame,
+        iomap::{CacheMode, IoMapping},
+        Frame, PhysicalAddress, PAGE_SIZE,
+    },
+    paging::{RmmA, RmmArch},
+    syscall::error::{Error, Result, EBUSY, EEXIST, EINVAL, EIO, ENODEV, ENOMEM, ENOSPC},
+};
+
+// Global IOMMU instance.
+pub static IOMMU: Once<AmdIommu> = Once::new();
+
+// AMD IOMMU MMIO Offsets
+const IOMMU_DEV_TABLE_BASE: usize = 0x0000;
+const IOMMU_CMD_BUF_BASE: usize = 0x0008;
+const IOMMU_EVENT_LOG_BASE: usize = 0x0010;
+const IOMMU_CONTROL: usize = 0x0018;
+const IOMMU_CMD_BUF_HEAD: usize = 0x2000;
+const IOMMU_CMD_BUF_TAIL: usize = 0x2008;
+const IOMMU_EVENT_LOG_HEAD: usize = 0x2010;
+const IOMMU_EVENT_LOG_TAIL: usize = 0x2018;
+const IOMMU_STATUS: usize = 0x2020;
+/// Size of the MMIO register block
+const IOMMU_MMIO_SIZE: usize = 0x4000;
+
+// IOMMU Control Register Flags
+const CONTROL_IOMMU_EN: u64 = 1 << 0;
+const CONTROL_HT_TUN_EN: u64 = 1 << 1;
+const CONTROL_EVENT_LOG_EN: u64 = 1 << 2;
+const CONTROL_CMD_BUF_EN: u64 = 1 << 12;
+
+// IOMMU Status Register Flags
+const STATUS_EVENT_OVERFLOW: u64 = 1 << 0;
+
+/// Device IDs are 16-bit PCI requester IDs, each with a 32-byte device table entry, so the
+/// device table takes 2 MiB
+const DEVICE_COUNT: usize = 1 << 16;
+const DEV_TABLE_ORDER: u32 = 9;
+/// Size field of the device table base register, the size in pages minus one
+const DEV_TABLE_SIZE: u64 = (1 << DEV_TABLE_ORDER) - 1;
+
+/// The command buffer and the event log each fill a page, with 256 entries of 16 bytes
+const RING_ENTRY_SIZE: usize = 16;
+const RING_SIZE: usize = PAGE_SIZE;
+/// Length field of the command buffer and event log base registers, log2 of the entry count
+const RING_LEN: u64 = 8 << 56;
+
+/// Bits of a physical address in device table and page table entries
+const ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+// Device table entry flags, in the first quadword
+const DTE_V: u64 = 1 << 0;
+const DTE_TV: u64 = 1 << 1;
+const DTE_MODE_SHIFT: u64 = 9;
+const DTE_IR: u64 = 1 << 61;
+const DTE_IW: u64 = 1 << 62;
+
+/// I/O page tables have four levels like x86_64 paging, covering 48 bits of IOVA
+const PAGE_TABLE_LEVELS: u64 = 4;
+const IOVA_LIMIT: usize = 1 << 48;
+const TABLE_ENTRIES: usize = 512;
+
+// I/O page table entry flags
+const PTE_PR: u64 = 1 << 0;
+const PTE_NEXT_LEVEL_SHIFT: u64 = 9;
+const PTE_IR: u64 = 1 << 61;
+const PTE_IW: u64 = 1 << 62;
+
+// Command opcodes
+const CMD_COMPLETION_WAIT: u32 = 0x01;
+const CMD_INVALIDATE_DEVTAB_ENTRY: u32 = 0x02;
+const CMD_INVALIDATE_IOMMU_PAGES: u32 = 0x03;
+/// Address that, with the size bit set, invalidates every page of a domain
+const INVALIDATE_ALL_ADDRESS: u64 = 0x7FFF_FFFF_FFFF_F000;
+
+/// Spins to wait for the IOMMU to take a command or complete a wait before giving up
+const COMMAND_TIMEOUT_SPINS: usize = 10_000_000;
+
+// Event codes
+const EVENT_ILLEGAL_DEV_TABLE_ENTRY: u8 = 0x1;
+const EVENT_IO_PAGE_FAULT: u8 = 0x2;
+const EVENT_DEV_TAB_HARDWARE_ERROR: u8 = 0x3;
+const EVENT_PAGE_TAB_HARDWARE_ERROR: u8 = 0x4;
+const EVENT_ILLEGAL_COMMAND_ERROR: u8 = 0x5;
+const EVENT_COMMAND_HARDWARE_ERROR: u8 = 0x6;
+const EVENT_IOTLB_INV_TIMEOUT: u8 = 0x7;
+const EVENT_INVALID_DEVICE_REQUEST: u8 = 0x8;
+
+// IO_PAGE_FAULT event flags
+const FAULT_PR: u16 = 1 << 4;
+const FAULT_RW: u16 = 1 << 5;
+
+bitflags! {
+    /// Accesses a device may make through a mapping
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct DmaFlags: u32 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+    }
+}
+
+/// An I/O address space that devices are attached to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DomainId(pub u16);
+
+impl DomainId {
+    /// The domain every device starts in, whose DMA is not translated
+    pub const PASSTHROUGH: Self = Self(0);
+}
+
+/// The device ID of a PCI function, which indexes the device table
+pub fn device_id(bus: u8, device: u8, function: u8) -> u16 {
+    (u16::from(bus) << 8) | (u16::from(device & 0x1F) << 3) | u16::from(function & 0x7)
+}
+
+/// Builder for a device table entry
+///
+/// A new entry is valid and blocks all DMA of its device. Translating with a page table
+/// and allowing accesses opens it up, allowing accesses without a page table lets DMA through
+/// untranslated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceTableEntry {
+    qwords: [u64; 4],
+}
+
+impl Default for DeviceTableEntry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeviceTableEntry {
+    pub const fn new() -> Self {
+        Self {
+            qwords: [DTE_V | DTE_TV, 0, 0, 0],
+        }
+    }
+
+    /// Translate DMA with the I/O page table of `levels` levels at `root`
+    pub fn page_table(mut self, root: PhysicalAddress, levels: u64) -> Self {
+        let [first, ..] = &mut self.qwords;
+        *first &= !(ADDR_MASK | (0x7 << DTE_MODE_SHIFT));
+        *first |= (root.data() as u64 & ADDR_MASK) | ((levels & 0x7) << DTE_MODE_SHIFT);
+        self
+    }
+
+    /// Allow the accesses in `flags`, as far as the page table allows them
+    pub fn permissions(mut self, flags: DmaFlags) -> Self {
+        let [first, ..] = &mut self.qwords;
+        *first &= !(DTE_IR | DTE_IW);
+        if flags.contains(DmaFlags::READ) {
+            *first |= DTE_IR;
+        }
+        if flags.contains(DmaFlags::WRITE) {
+            *first |= DTE_IW;
+        }
+        self
+    }
+
+    /// Tag the IOMMU's cached translations for the device with `domain`
+    pub fn domain(mut self, domain: DomainId) -> Self {
+        let [_, second, ..] = &mut self.qwords;
+        *second = (*second & !0xFFFF) | u64::from(domain.0);
+        self
+    }
+
+    pub fn build(self) -> [u64; 4] {
+        self.qwords
+    }
+}
+
+/// A page table entry pointing at `phys`, a table of level `next_level` or a page if 0
+fn pte(phys: PhysicalAddress, next_level: u64, flags: DmaFlags) -> u64 {
+    let mut entry =
+        PTE_PR | (phys.data() as u64 & ADDR_MASK) | (next_level << PTE_NEXT_LEVEL_SHIFT);
+    if flags.contains(DmaFlags::READ) {
+        entry |= PTE_IR;
+    }
+    if flags.contains(DmaFlags::WRITE) {
+        entry |= PTE_IW;
+    }
+    entry
+}
+
+/// Index of the entry for `iova` in a page table of `level`, 1 being the last
+fn table_index(iova: usize, level: u64) -> usize {
+    let shift = 9usize
+        .saturating_mul(level.saturating_sub(1) as usize)
+        .saturating_add(12);
+    (iova >> shift) % TABLE_ENTRIES
+}
+
+fn completion_wait_cmd(store: PhysicalAddress, value: u64) -> [u32; 4] {
+    let store = store.data() as u64;
+    [
+        (store as u32 & !0x7) | 1,
+        ((store >> 32) as u32 & 0xF_FFFF) | (CMD_COMPLETION_WAIT << 28),
+        value as u32,
+        (value >> 32) as u32,
+    ]
+}
+
+fn invalidate_devtab_entry_cmd(device: u16) -> [u32; 4] {
+    [u32::from(device), CMD_INVALIDATE_DEVTAB_ENTRY << 28, 0, 0]
+}
+
+/// Invalidate the page at `iova` in `domain`, or every page of it if `None`
+fn invalidate_pages_cmd(domain: DomainId, iova: Option<usize>) -> [u32; 4] {
+    // The size bit extends the invalidation to the range encoded in the address, the PDE bit
+    // to the cached directory entries
+    let (address, flags) = match iova {
+        Some(iova) => (iova as u64 & !0xFFF, 0),
+        None => (INVALIDATE_ALL_ADDRESS, 0b11),
+    };
+    [
+        0,
+        u32::from(domain.0) | (CMD_INVALIDATE_IOMMU_PAGES << 28),
+        address as u32 | flags,
+        (address >> 32) as u32,
+    ]
+}
+
+/// An entry of the event log
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Event {
+    code: u8,
+    device: u16,
+    domain: u16,
+    flags: u16,
+    address: u64,
+}
+
+impl Event {
+    fn decode(raw: [u32; 4]) -> Self {
+        let [first, second, low, high] = raw;
+        Event {
+            code: (second >> 28) as u8,
+            device: first as u16,
+            domain: second as u16,
+            flags: ((second >> 16) & 0xFFF) as u16,
+            address: (u64::from(high) << 32) | u64::from(low),
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.code {
+            EVENT_ILLEGAL_DEV_TABLE_ENTRY => "illegal device table entry",
+            EVENT_IO_PAGE_FAULT => "I/O page fault",
+            EVENT_DEV_TAB_HARDWARE_ERROR => "device table hardware error",
+            EVENT_PAGE_TAB_HARDWARE_ERROR => "page table hardware error",
+            EVENT_ILLEGAL_COMMAND_ERROR => "illegal command",
+            EVENT_COMMAND_HARDWARE_ERROR => "command hardware error",
+            EVENT_IOTLB_INV_TIMEOUT => "IOTLB invalidation timeout",
+            EVENT_INVALID_DEVICE_REQUEST => "invalid device request",
+            _ => "unknown event",
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} from device {:02x}:{:02x}.{} at {:#x}",
+            self.reason(),
+            self.device >> 8,
+            (self.device >> 3) & 0x1F,
+            self.device & 0x7,
+            self.address
+        )?;
+        if self.code == EVENT_IO_PAGE_FAULT {
+            write!(
+                f,
+                " in domain {}, {} {}",
+                self.domain,
+                if self.flags & FAULT_RW != 0 {
+                    "write"
+                } else {
+                    "read"
+                },
+                if self.flags & FAULT_PR != 0 {
+                    "not permitted"
+                } else {
+                    "to unmapped page"
+                }
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Allocate `2^order` zeroed frames
+fn zeroed_frames(order: u32) -> Option<Frame> {
+    let size = PAGE_SIZE.checked_shl(order)?;
+    let frame = allocate_p2frame(order)?;
+    unsafe {
+        (RmmA::phys_to_virt(frame.base()).data() as *mut u8).write_bytes(0, size);
+    }
+    Some(frame)
+}
+
+/// The entries of the I/O page table at `table`
+fn table_entries(table: PhysicalAddress) -> &'static [AtomicU64; TABLE_ENTRIES] {
+    unsafe { &*(RmmA::phys_to_virt(table).data() as *const [AtomicU64; TABLE_ENTRIES]) }
+}
+
+/// Free the I/O page table of `level` at `table`, and the tables below it
+fn free_table(table: PhysicalAddress, level: u64) {
+    if level > 1 {
+        for entry in table_entries(table) {
+            let entry = entry.load(Ordering::Relaxed);
+            if entry & PTE_PR != 0 {
+                free_table(
+                    PhysicalAddress::new((entry & ADDR_MASK) as usize),
+                    level.saturating_sub(1),
+                );
+            }
+        }
+    }
+    unsafe { deallocate_frame(Frame::containing(table)) };
+}
+
+struct Domain {
+    root: Frame,
+    /// Number of devices attached
+    devices: usize,
+}
+
+impl Domain {
+    /// The last level entry for `iova`, allocating the tables on the way if `alloc` is set
+    fn leaf_entry(&self, iova: usize, alloc: bool) -> Result<Option<&'static AtomicU64>> {
+        let mut table = self.root.base();
+        for level in (2..=PAGE_TABLE_LEVELS).rev() {
+            let entry = table_entries(table)
+                .get(table_index(iova, level))
+                .ok_or(Error::new(EINVAL))?;
+            let value = entry.load(Ordering::Relaxed);
+            table = if value & PTE_PR != 0 {
+                PhysicalAddress::new((value & ADDR_MASK) as usize)
+            } else if alloc {
+                let frame = zeroed_frames(0).ok_or(Error::new(ENOMEM))?;
+                // Directory entries allow everything, the leaves decide
+                let next_level = level.saturating_sub(1);
+                entry.store(
+                    pte(frame.base(), next_level, DmaFlags::all()),
+                    Ordering::Relaxed,
+                );
+                frame.base()
+            } else {
+                return Ok(None);
+            };
+        }
+        Ok(table_entries(table).get(table_index(iova, 1)))
+    }
+}
+
+struct State {
+    /// Byte offset in the command buffer of the next command
+    cmd_tail: usize,
+    /// Value stored by the next completion wait
+    next_completion: u64,
+    domains: BTreeMap<u16, Domain>,
+}
+
+pub struct AmdIommu {
+    mmio: IoMapping,
+    device_table: Frame,
+    command_buffer: Frame,
+    event_log: Frame,
+    /// Where completion waits store their value
+    completion: Frame,
+    state: Mutex<State>,
+    /// Held while reading the event log
+    events: Mutex<()>,
+}
+
+impl AmdIommu {
+    /// Initialize the IOMMU if detected.
+    pub unsafe fn init() {
+        if !features::get().flags.contains(FeatureFlags::IOMMU) {
+            println!("AMD-Vi: Not detected or unsupported.");
+            return;
+        }
+
+        // The MMIO base varies between platforms, only the IVRS table knows where it is
+        let Some(mmio_base) = crate::acpi::ivrs::get_iommu_base() else {
+            println!("AMD-Vi: ACPI IVRS table not found, leaving the IOMMU disabled.");
+            return;
+        };
+
+        // Map MMIO region
+        let mmio = match IoMapping::new(
+            PhysicalAddress::new(mmio_base),
+            IOMMU_MMIO_SIZE,
+            CacheMode::Uncached,
+        ) {
+            Ok(mmio) => mmio,
+            Err(err) => {
+                println!("AMD-Vi: Failed to map MMIO at {:#x}: {:?}", mmio_base, err);
+                return;
+            }
+        };
+
+        let (Some(device_table), Some(command_buffer), Some(event_log), Some(completion)) = (
+            zeroed_frames(DEV_TABLE_ORDER),
+            zeroed_frames(0),
+            zeroed_frames(0),
+            zeroed_frames(0),
+        ) else {
+            println!("AMD-Vi: Out of memory for the IOMMU tables, leaving it disabled.");
+            return;
+        };
+
+        let iommu = AmdIommu {
+            mmio,
+            device_table,
+            command_buffer,
+            event_log,
+            completion,
+            state: Mutex::new(State {
+                cmd_tail: 0,
+                next_completion: 1,
+                domains: BTreeMap::new(),
+            }),
+            events: Mutex::new(()),
+        };
+
+        // Nothing is cached before translation is enabled, so no invalidation is needed
+        let passthrough = DeviceTableEntry::new()
+            .permissions(DmaFlags::all())
+            .domain(DomainId::PASSTHROUGH);
+        for device in 0..DEVICE_COUNT {
+            iommu.write_dte(device as u16, passthrough);
+        }
+
+        unsafe { iommu.setup_hardware() };
+
+        IOMMU.call_once(|| iommu);
+        println!("AMD-Vi: Initialized, all devices in the passthrough domain.");
+    }
+
+    unsafe fn write_reg(&self, offset: usize, value: u64) {
+        unsafe {
+            let ptr = self.mmio.virt().data().saturating_add(offset) as *mut u64;
+            ptr.write_volatile(value);
+        }
+    }
+
+    unsafe fn read_reg(&self, offset: usize) -> u64 {
+        unsafe {
+            let ptr = self.mmio.virt().data().saturating_add(offset) as *const u64;
+            ptr.read_volatile()
+        }
+    }
+
+    unsafe fn setup_hardware(&self) {
+        unsafe {
+            // 1. Configure Device Table Base
+            let dev_phys = self.device_table.base().data() as u64;
+            self.write_reg(IOMMU_DEV_TABLE_BASE, dev_phys | DEV_TABLE_SIZE);
+
+            // 2. Configure Command Buffer Base
+            let cmd_phys = self.command_buffer.base().data() as u64;
+            self.write_reg(IOMMU_CMD_BUF_BASE, cmd_phys | RING_LEN);
+            self.write_reg(IOMMU_CMD_BUF_HEAD, 0);
+            self.write_reg(IOMMU_CMD_BUF_TAIL, 0);
+
+            // 3. Configure Event Log Base
+            let evt_phys = self.event_log.base().data() as u64;
+            self.write_reg(IOMMU_EVENT_LOG_BASE, evt_phys | RING_LEN);
+            self.write_reg(IOMMU_EVENT_LOG_HEAD, 0);
+            self.write_reg(IOMMU_EVENT_LOG_TAIL, 0);
+
+            // 4. Enable Translation and internal buffers
+            let mut ctrl = self.read_reg(IOMMU_CONTROL);
+            ctrl |=
+                CONTROL_IOMMU_EN | CONTROL_CMD_BUF_EN | CONTROL_EVENT_LOG_EN | CONTROL_HT_TUN_EN;
+            self.write_reg(IOMMU_CONTROL, ctrl);
+        }
+    }
+
+    fn dte(&self, device: u16) -> &[AtomicU64; 4] {
+        let table = RmmA::phys_to_virt(self.device_table.base()).data() as *const [AtomicU64; 4];
+        unsafe { &*table.add(usize::from(device)) }
+    }
+
+    fn write_dte(&self, device: u16, entry: DeviceTableEntry) {
+        let [first, rest @ ..] = entry.build();
+        let [dte_first, dte_rest @ ..] = self.dte(device);
+        // The first quadword holds the valid bits, so it goes last
+        for (dte, value) in dte_rest.iter().zip(rest) {
+            dte.store(value, Ordering::Relaxed);
+        }
+        dte_first.store(first, Ordering::Release);
+    }
+
+    fn dte_domain(&self, device: u16) -> DomainId {
+        let [_, second, ..] = self.dte(device);
+        DomainId(second.load(Ordering::Relaxed) as u16)
+    }
+
+    /// Queue `command`, waiting for room in the command buffer
+    fn submit(&self, state: &mut State, command: [u32; 4]) -> Result<()> {
+        let next_tail = state.cmd_tail.saturating_add(RING_ENTRY_SIZE) % RING_SIZE;
+        let mut spins = 0;
+        while unsafe { self.read_reg(IOMMU_CMD_BUF_HEAD) } as usize % RING_SIZE == next_tail {
+            spins = spins.saturating_add(1);
+            if spins > COMMAND_TIMEOUT_SPINS {
+                warn!("AMD-Vi: command buffer stuck full");
+                return Err(Error::new(EIO));
+            }
+            core::hint::spin_loop();
+        }
+
+        let slot = RmmA::phys_to_virt(self.command_buffer.base()).data() as *mut [u32; 4];
+        unsafe {
+            slot.add(state.cmd_tail / RING_ENTRY_SIZE)
+                .write_volatile(command);
+            self.write_reg(IOMMU_CMD_BUF_TAIL, next_tail as u64);
+        }
+        state.cmd_tail = next_tail;
+        Ok(())
+    }
+
+    /// Wait until the IOMMU has executed every command queued before
+    fn completion_wait(&self, state: &mut State) -> Result<()> {
+        let value = state.next_completion;
+        state.next_completion = value.wrapping_add(1);
+        self.submit(state, completion_wait_cmd(self.completion.base(), value))?;
+
+        let store = RmmA::phys_to_virt(self.completion.base()).data() as *const u64;
+        let mut spins = 0;
+        while unsafe { store.read_volatile() } != value {
+            spins = spins.saturating_add(1);
+            if spins > COMMAND_TIMEOUT_SPINS {
+                warn!("AMD-Vi: completion wait timed out");
+                return Err(Error::new(EIO));
+            }
+            core::hint::spin_loop();
+        }
+        Ok(())
+    }
+
+    /// Invalidate the cached translations of the `pages` pages at `iova` in `domain`
+    fn invalidate(
+        &self,
+        state: &mut State,
+        domain: DomainId,
+        iova: usize,
+        pages: usize,
+    ) -> Result<()> {
+        let command = if pages == 1 {
+            invalidate_pages_cmd(domain, Some(iova))
+        } else {
+            invalidate_pages_cmd(domain, None)
+        };
+        self.submit(state, command)?;
+        self.completion_wait(state)
+    }
+
+    /// Print the events logged since the last call
+    fn poll_events(&self) {
+        let Some(_guard) = self.events.try_lock() else {
+            return;
+        };
+        let log = RmmA::phys_to_virt(self.event_log.base()).data() as *const [u32; 4];
+        unsafe {
+            let mut head = self.read_reg(IOMMU_EVENT_LOG_HEAD) as usize % RING_SIZE;
+            let tail = self.read_reg(IOMMU_EVENT_LOG_TAIL) as usize % RING_SIZE;
+            while head != tail {
+                let event = Event::decode(log.add(head / RING_ENTRY_SIZE).read_volatile());
+                warn!("AMD-Vi: {}", event);
+                head = head.saturating_add(RING_ENTRY_SIZE) % RING_SIZE;
+            }
+            self.write_reg(IOMMU_EVENT_LOG_HEAD, head as u64);
+
+            // An overflow stops the event log until it is restarted
+            if self.read_reg(IOMMU_STATUS) & STATUS_EVENT_OVERFLOW != 0 {
+                warn!("AMD-Vi: event log overflowed, events were lost");
+                let ctrl = self.read_reg(IOMMU_CONTROL);
+                self.write_reg(IOMMU_CONTROL, ctrl & !CONTROL_EVENT_LOG_EN);
+                self.write_reg(IOMMU_STATUS, STATUS_EVENT_OVERFLOW);
+                self.write_reg(IOMMU_CONTROL, ctrl | CONTROL_EVENT_LOG_EN);
+            }
+        }
+    }
+}
+
+fn iommu() -> Result<&'static AmdIommu> {
+    IOMMU.get().ok_or(Error::new(ENODEV))
+}
+
+/// Create an empty domain, which blocks all DMA of the devices attached to it until buffers are
+/// mapped into it
+pub fn create_domain() -> Result<DomainId> {
+    let iommu = iommu()?;
+    let root = zeroed_frames(0).ok_or(Error::new(ENOMEM))?;
+    let mut state = iommu.state.lock();
+    let Some(id) = (1..=u16::MAX).find(|id| !state.domains.contains_key(id)) else {
+        drop(state);
+        unsafe { deallocate_frame(root) };
+        return Err(Error::new(ENOSPC));
+    };
+    state.domains.insert(id, Domain { root, devices: 0 });
+    Ok(DomainId(id))
+}
+
+/// Free a domain and its page tables. Fails with `EBUSY` while devices are attached to it.
+pub fn destroy_domain(domain: DomainId) -> Result<()> {
+    let iommu = iommu()?;
+    let mut state = iommu.state.lock();
+    match state.domains.get(&domain.0) {
+        Some(entry) if entry.devices > 0 => return Err(Error::new(EBUSY)),
+        Some(_) => {}
+        None => return Err(Error::new(EINVAL)),
+    }
+    if let Some(entry) = state.domains.remove(&domain.0) {
+        free_table(entry.root.base(), PAGE_TABLE_LEVELS);
+    }
+    Ok(())
+}
+
+/// Move `device` to `domain`, or back to the passthrough domain
+pub fn attach(device: u16, domain: DomainId) -> Result<()> {
+    let iommu = iommu()?;
+    let mut state = iommu.state.lock();
+    let entry = if domain == DomainId::PASSTHROUGH {
+        DeviceTableEntry::new()
+    } else {
+        let root = state.domains.get(&domain.0).ok_or(Error::new(EINVAL))?.root;
+        DeviceTableEntry::new().page_table(root.base(), PAGE_TABLE_LEVELS)
+    };
+
+    let old = iommu.dte_domain(device);
+    iommu.write_dte(device, entry.permissions(DmaFlags::all()).domain(domain));
+    if let Some(old) = state.domains.get_mut(&old.0) {
+        old.devices = old.devices.saturating_sub(1);
+    }
+    if let Some(new) = state.domains.get_mut(&domain.0) {
+        new.devices = new.devices.saturating_add(1);
+    }
+
+    iommu.submit(&mut state, invalidate_devtab_entry_cmd(device))?;
+    iommu.completion_wait(&mut state)
+}
+
+/// Map `len` bytes of physical memory at `phys` at `iova` in `domain`, for the accesses in
+/// `flags`. All of it must be page aligned and not mapped yet.
+pub fn map(
+    domain: DomainId,
+    iova: usize,
+    phys: PhysicalAddress,
+    len: usize,
+    flags: DmaFlags,
+) -> Result<()> {
+    let end = iova.checked_add(len).ok_or(Error::new(EINVAL))?;
+    if len == 0
+        || end > IOVA_LIMIT
+        || !iova.is_multiple_of(PAGE_SIZE)
+        || !phys.data().is_multiple_of(PAGE_SIZE)
+        || !len.is_multiple_of(PAGE_SIZE)
+    {
+        return Err(Error::new(EINVAL));
+    }
+    let iommu = iommu()?;
+    let mut state = iommu.state.lock();
+    let entry = state.domains.get(&domain.0).ok_or(Error::new(EINVAL))?;
+
+    let mut result = Ok(());
+    let mut mapped = 0;
+    while mapped < len {
+        let page = entry.leaf_entry(iova.saturating_add(mapped), true);
+        let page = match page {
+            Ok(Some(page)) if page.load(Ordering::Relaxed) & PTE_PR == 0 => Ok(page),
+            Ok(_) => Err(Error::new(EEXIST)),
+            Err(err) => Err(err),
+        };
+        match page {
+            Ok(page) => {
+                let phys = PhysicalAddress::new(phys.data().saturating_add(mapped));
+                page.store(pte(phys, 0, flags), Ordering::Relaxed);
+                mapped = mapped.saturating_add(PAGE_SIZE);
+            }
+            Err(err) => {
+                result = Err(err);
+                break;
+            }
+        }
+    }
+
+    // Roll back the part that was mapped if the rest could not be
+    if result.is_err() {
+        for offset in (0..mapped).step_by(PAGE_SIZE) {
+            if let Ok(Some(page)) = entry.leaf_entry(iova.saturating_add(offset), false) {
+                page.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // The IOMMU may have cached the entries as not present
+    iommu.invalidate(&mut state, domain, iova, len / PAGE_SIZE)?;
+    result
+}
+
+/// Unmap `len` bytes at `iova` from `domain`. Pages that are not mapped are skipped.
+pub fn unmap(domain: DomainId, iova: usize, len: usize) -> Result<()> {
+    let end = iova.checked_add(len).ok_or(Error::new(EINVAL))?;
+    if len == 0
+        || end > IOVA_LIMIT
+        || !iova.is_multiple_of(PAGE_SIZE)
+        || !len.is_multiple_of(PAGE_SIZE)
+    {
+        return Err(Error::new(EINVAL));
+    }
+    let iommu = iommu()?;
+    let mut state = iommu.state.lock();
+    let entry = state.domains.get(&domain.0).ok_or(Error::new(EINVAL))?;
+    for offset in (0..len).step_by(PAGE_SIZE) {
+        if let Some(page) = entry.leaf_entry(iova.saturating_add(offset), false)? {
+            page.store(0, Ordering::Relaxed);
+        }
+    }
+    iommu.invalidate(&mut state, domain, iova, len / PAGE_SIZE)
+}
+
+/// Print the DMA faults and errors the IOMMU logged since the last call, if there is one
+pub fn poll_events() {
+    if let Some(iommu) = IOMMU.get() {
+        iommu.poll_events();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_iommu_device_table_entry() {
+        let blocked = DeviceTableEntry::new().build();
+        assert_eq!(blocked, [DTE_V | DTE_TV, 0, 0, 0]);
+
+        let passthrough = DeviceTableEntry::new()
+            .permissions(DmaFlags::all())
+            .domain(DomainId::PASSTHROUGH)
+            .build();
+        assert_eq!(passthrough[0], DTE_V | DTE_TV | DTE_IR | DTE_IW);
+
+        let translated = DeviceTableEntry::new()
+            .page_table(PhysicalAddress::new(0x1234_5000), 4)
+            .permissions(DmaFlags::READ)
+            .domain(DomainId(7))
+            .build();
+        assert_eq!(
+            translated[0],
+            DTE_V | DTE_TV | 0x1234_5000 | (4 << 9) | DTE_IR
+        );
+        assert_eq!(translated[1], 7);
+    }
+
+    #[test]
+    fn test_iommu_page_tables() {
+        let iova = 0x0000_7F12_3456_7000;
+        assert_eq!(table_index(iova, 1), (iova >> 12) & 0x1FF);
+        assert_eq!(table_index(iova, 4), (iova >> 39) & 0x1FF);
+
+        let leaf = pte(PhysicalAddress::new(0xABC_D000), 0, DmaFlags::WRITE);
+        assert_eq!(leaf, PTE_PR | 0xABC_D000 | PTE_IW);
+        let directory = pte(PhysicalAddress::new(0x1000), 3, DmaFlags::all());
+        assert_eq!(directory, PTE_PR | 0x1000 | (3 << 9) | PTE_IR | PTE_IW);
+        assert_eq!(device_id(0x3, 0x1F, 0x7), 0x03FF);
+    }
+
+    #[test]
+    fn test_iommu_commands() {
+        let wait = completion_wait_cmd(PhysicalAddress::new(0x12_3456_7008), 5);
+        assert_eq!(wait, [0x3456_7009, 0x1000_0012, 5, 0]);
+        assert_eq!(
+            invalidate_devtab_entry_cmd(0x0310),
+            [0x310, 0x2000_0000, 0, 0]
+        );
+        assert_eq!(
+            invalidate_pages_cmd(DomainId(3), Some(0x1_2345_6789)),
+            [0, 0x3000_0003, 0x2345_6000, 0x1]
+        );
+        assert_eq!(
+            invalidate_pages_cmd(DomainId(3), None),
+            [0, 0x3000_0003, 0xFFFF_F003, 0x7FFF_FFFF]
+        );
+    }
+
+    #[test]
+    fn test_iommu_event_decode() {
+        // Write to an unmapped page from 00:14.0 in domain 2
+        let event = Event::decode([0x00A0, 0x2020_0002, 0xDEAD_0000, 0x1]);
+        assert_eq!(event.code, EVENT_IO_PAGE_FAULT);
+        assert_eq!(event.device, 0x00A0);
+        assert_eq!(event.domain, 2);
+        assert_eq!(event.address, 0x1_DEAD_0000);
+        assert_eq!(
+            format!("{}", event),
+            "I/O page fault from device 00:14.0 at 0x1dead0000 in domain 2, write to unmapped page"
+        );
+    }
+}
@@ -7,6 +7,8 @@ pub mod cpu;
 #[cfg(feature = "acpi")]
 pub mod hpet;
 pub mod ioapic;
+#[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+pub mod iommu;
 pub mod local_apic;
 pub mod pic;
 pub mod pit;
@@ -30,6 +32,11 @@ pub unsafe fn init() {
 pub unsafe fn init_after_acpi() {
     // this will disable the IOAPIC if needed.
     //ioapic::init(mapper);
+
+    #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+    unsafe {
+        iommu::AmdIommu::init();
+    }
 }
 
 #[cfg(feature = "acpi")]
@@ -94,6 +101,8 @@ pub struct ArchPercpuMisc {
     pub apic_id_opt: Cell<Option<local_apic::ApicId>>,
     #[cfg(feature = "x86_kvm_pv")]
     pub tsc_info: tsc::TscPercpu,
+    /// Bottom and top of the stack this CPU started on
+    pub boot_stack: Cell<(usize, usize)>,
 }
 
 impl ArchPercpuMisc {
@@ -102,6 +111,7 @@ impl ArchPercpuMisc {
             apic_id_opt: Cell::new(None),
             #[cfg(feature = "x86_kvm_pv")]
             tsc_info: tsc::TscPercpu::default(),
+            boot_stack: Cell::new((0, 0)),
         }
     }
 }
@@ -65,6 +65,7 @@ pub fn tsc_calibrate() -> u64 {
 
     // 3. Store result
     TSC_FREQUENCY.store(hz, Ordering::SeqCst);
+    crate::time::publish_counter_frequency(hz);
 
     println!("TSC: Calibrated frequency: {} Hz", hz);
     hz
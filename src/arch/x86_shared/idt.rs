@@ -55,8 +55,11 @@ impl Idt {
         let byte_index = index / 32;
         let bit = index % 32;
 
-        self.reservations[usize::from(byte_index)]
-            .fetch_or(u32::from(reserved) << bit, Ordering::AcqRel);
+        if reserved {
+            self.reservations[usize::from(byte_index)].fetch_or(1 << bit, Ordering::AcqRel);
+        } else {
+            self.reservations[usize::from(byte_index)].fetch_and(!(1 << bit), Ordering::AcqRel);
+        }
     }
 
     #[inline]
@@ -70,7 +73,7 @@ impl Idt {
 }
 
 // Allocate 64 KiB of stack space for the backup stack.
-const BACKUP_STACK_SIZE: usize = PAGE_SIZE << 4;
+pub(crate) const BACKUP_STACK_SIZE: usize = PAGE_SIZE << 4;
 
 static INIT_BSP_IDT: SyncUnsafeCell<Idt> = SyncUnsafeCell::new(Idt::new());
 
@@ -179,7 +182,7 @@ pub fn allocate_and_init_idt(cpu_id: LogicalCpuId) -> *mut Idt {
     *idt
 }
 
-const BACKUP_IST: u8 = 1;
+pub(crate) const BACKUP_IST: u8 = 1;
 
 /// Initializes an IDT for any type of processor.
 fn init_generic(cpu_id: LogicalCpuId, idt: &mut Idt, backup_stack_end: usize) {
@@ -32,6 +32,13 @@ interrupt_stack!(debug, @paranoid, |stack| {
     stack.set_singlestep(false);
 
     let mut token = unsafe { CleanLockToken::new() };
+    #[cfg(feature = "debugger")]
+    if stack.cs & 3 == 3
+        && crate::debugger::gdb::trap(crate::debugger::gdb::Trap::Step, &mut token)
+    {
+        // The remote debugger sets singlestep again if it wants another step
+        return;
+    }
     if ptrace::breakpoint_callback(PTRACE_STOP_SINGLESTEP, None, &mut token).is_some() {
         handled = true;
     } else {
@@ -50,6 +57,12 @@ interrupt_stack!(debug, @paranoid, |stack| {
 });
 
 interrupt_stack!(non_maskable, @paranoid, |stack| {
+    #[cfg(target_arch = "x86_64")]
+    if crate::watchdog::take_nmi_request() {
+        crate::watchdog::dump_stalled(stack);
+        return;
+    }
+
     #[cfg(feature = "profiling")]
     unsafe { crate::profiling::nmi_handler(stack) };
 
@@ -82,6 +95,12 @@ interrupt_stack!(breakpoint, |stack| {
     }
 
     let mut token = unsafe { CleanLockToken::new() };
+    #[cfg(feature = "debugger")]
+    if stack.cs & 3 == 3
+        && crate::debugger::gdb::trap(crate::debugger::gdb::Trap::Breakpoint, &mut token)
+    {
+        return;
+    }
     if ptrace::breakpoint_callback(PTRACE_STOP_BREAKPOINT, None, &mut token).is_none() {
         println!("Breakpoint trap");
         stack.dump();
@@ -131,6 +150,10 @@ interrupt_stack!(device_not_available, |stack| {
 interrupt_error!(double_fault, |stack, _code| {
     println!("Double fault");
     stack.trace();
+    // A page fault that could not be pushed on an overflowed kernel stack. This runs on the
+    // backup IST stack, so it is still safe to report.
+    #[cfg(target_arch = "x86_64")]
+    crate::context::kstack::check_overflow(unsafe { x86::controlregs::cr2() });
     unsafe {
         loop {
             interrupt::disable();
@@ -3,8 +3,12 @@ use crate::{
     sync::CleanLockToken,
 };
 
-interrupt!(wakeup, || {
+// Also sent to deliver an upcall posted to a context running on this CPU, which needs the frame
+crate::interrupt_stack!(wakeup, |stack| {
     unsafe { the_local_apic().eoi() };
+
+    let mut token = unsafe { CleanLockToken::new() };
+    context::upcall::deliver(stack, &mut token);
 });
 
 interrupt!(tlb, || {
@@ -22,8 +26,10 @@ interrupt!(switch, || {
 
 interrupt!(pit, || {
     unsafe { the_local_apic().eoi() };
+    crate::watchdog::tick();
 
     // Switch after a sufficient amount of time since the last switch.
     let mut token = unsafe { CleanLockToken::new() };
+    crate::deferred::tick(&mut token);
     let _ = context::switch(&mut token);
 });
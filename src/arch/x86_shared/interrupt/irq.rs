@@ -11,7 +11,10 @@ use crate::{
     interrupt,
     ipi::{ipi, IpiKind, IpiTarget},
     percpu::PercpuBlock,
-    scheme::{irq::irq_trigger, serio::serio_input},
+    scheme::{
+        irq::irq_trigger,
+        serio::{serio_irq_input, PORT_AUX, PORT_KEYBOARD},
+    },
     sync::CleanLockToken,
     time,
 };
@@ -170,12 +173,14 @@ unsafe fn ioapic_unmask(irq: usize) {
     }
 }
 
-crate::interrupt_stack!(pit_stack, |_stack| {
+crate::interrupt_stack!(pit_stack, |stack| {
     // Saves CPU time by not sending IRQ event irq_trigger(0);
 
     {
         *time::OFFSET.lock() += pit::RATE;
     }
+    time::publish_tick();
+    crate::watchdog::tick();
 
     unsafe { eoi(0) };
 
@@ -186,9 +191,13 @@ crate::interrupt_stack!(pit_stack, |_stack| {
 
     // Any better way of doing this?
     timeout::trigger(&mut token);
+    crate::log::wake_kmsg_readers(&mut token);
+    crate::deferred::tick(&mut token);
 
     // Reschedule after timer interrupt
     let _ = context::switch(&mut token);
+
+    context::upcall::deliver(stack, &mut token);
 });
 
 crate::interrupt!(keyboard, || {
@@ -197,8 +206,7 @@ crate::interrupt!(keyboard, || {
 
     unsafe { eoi(1) };
 
-    let mut token = unsafe { CleanLockToken::new() };
-    serio_input(0, data, &mut token);
+    serio_irq_input(PORT_KEYBOARD, data);
 });
 
 crate::interrupt!(cascade, || {
@@ -278,8 +286,7 @@ crate::interrupt!(mouse, || {
 
     unsafe { eoi(12) };
 
-    let mut token = unsafe { CleanLockToken::new() };
-    serio_input(1, data, &mut token);
+    serio_irq_input(PORT_AUX, data);
 });
 
 crate::interrupt!(fpu, || {
@@ -317,7 +324,9 @@ crate::interrupt!(lapic_timer, || {
 #[cfg(feature = "profiling")]
 crate::interrupt!(aux_timer, || {
     unsafe { lapic_eoi() };
-    crate::ipi::ipi(IpiKind::Profile, IpiTarget::Other);
+    if crate::profiling::sample_due() {
+        crate::ipi::ipi(IpiKind::Profile, IpiTarget::Other);
+    }
 });
 
 crate::interrupt!(lapic_error, || {
@@ -15,6 +15,17 @@ pub unsafe fn disable() {
     }
 }
 
+/// Whether interrupts are enabled on this CPU
+#[inline(always)]
+pub fn are_enabled() -> bool {
+    let flags: usize;
+    unsafe {
+        core::arch::asm!("pushf", "pop {}", out(reg) flags, options(nomem, preserves_flags));
+    }
+    // IF
+    flags & (1 << 9) != 0
+}
+
 /// Set interrupts and halt
 /// This will atomically wait for the next interrupt
 /// Performing enable followed by halt is not guaranteed to be atomic, use this instead!
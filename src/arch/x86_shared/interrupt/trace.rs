@@ -1,11 +1,18 @@
-use core::mem;
+use core::{mem, ops::Range};
 
+use crate::percpu::PercpuBlock;
+
+/// A frame in the frame pointer chain. `rbp` (`ebp` on x86) points at the caller's saved frame
+/// pointer, with the return address into the caller right above it.
 pub struct StackTrace {
+    /// Address of the saved frame pointer
     pub fp: usize,
+    /// Address of the return address
     pub pc_ptr: *const usize,
 }
 
 impl StackTrace {
+    /// The frame of the function this is inlined into
     #[inline(always)]
     pub unsafe fn start() -> Option<Self> {
         unsafe {
@@ -14,22 +21,43 @@ impl StackTrace {
             core::arch::asm!("mov {}, ebp", out(reg) fp);
             #[cfg(target_arch = "x86_64")]
             core::arch::asm!("mov {}, rbp", out(reg) fp);
-            let pc_ptr = fp.checked_add(mem::size_of::<usize>())?;
-            Some(Self {
-                fp,
-                pc_ptr: pc_ptr as *const usize,
-            })
+            Self::from_fp(fp)
         }
     }
 
+    /// The frame whose frame pointer register held `fp`
+    pub fn from_fp(fp: usize) -> Option<Self> {
+        let pc_ptr = fp.checked_add(mem::size_of::<usize>())?;
+        Some(Self {
+            fp,
+            pc_ptr: pc_ptr as *const usize,
+        })
+    }
+
+    /// The caller's frame. `self.fp` must be readable.
     pub unsafe fn next(self) -> Option<Self> {
-        unsafe {
-            let fp = *(self.fp as *const usize);
-            let pc_ptr = fp.checked_add(mem::size_of::<usize>())?;
-            Some(Self {
-                fp,
-                pc_ptr: pc_ptr as *const usize,
-            })
-        }
+        Self::from_fp(unsafe { *(self.fp as *const usize) })
     }
 }
+
+/// The stacks of this CPU other than the kernel stacks of contexts: the one it started on and, on
+/// x86_64, the IST stack of double faults, NMIs and machine checks
+pub fn cpu_stacks() -> impl Iterator<Item = Range<usize>> {
+    let (bottom, top) = PercpuBlock::current().misc_arch_info.boot_stack.get();
+    let boot = (bottom < top).then_some(bottom..top);
+
+    #[cfg(target_arch = "x86_64")]
+    let backup = {
+        use crate::idt::{BACKUP_IST, BACKUP_STACK_SIZE};
+
+        let ist = crate::gdt::pcr().tss.ist;
+        let top = ist
+            .get(usize::from(BACKUP_IST - 1))
+            .map(|&top| top as usize);
+        top.and_then(|top| Some(top.checked_sub(BACKUP_STACK_SIZE)?..top))
+    };
+    #[cfg(target_arch = "x86")]
+    let backup = None;
+
+    boot.into_iter().chain(backup)
+}
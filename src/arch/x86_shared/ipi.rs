@@ -47,6 +47,22 @@ pub fn ipi(kind: IpiKind, target: IpiTarget) {
     unsafe { the_local_apic().set_icr(icr) };
 }
 
+/// Sends a non-maskable interrupt to a single CPU, which it takes even with interrupts disabled.
+#[inline(always)]
+pub fn ipi_nmi(target: &crate::percpu::PercpuBlock) {
+    use crate::device::local_apic::the_local_apic;
+
+    if cfg!(not(feature = "multi_core")) {
+        return;
+    }
+
+    if let Some(apic_id) = target.misc_arch_info.apic_id_opt.get() {
+        unsafe {
+            the_local_apic().ipi_nmi(apic_id);
+        }
+    }
+}
+
 /// Sends an IPI to a single CPU.
 #[inline(always)]
 pub fn ipi_single(kind: IpiKind, target: &crate::percpu::PercpuBlock) {
@@ -44,6 +44,7 @@ macro_rules! interrupt {
             }
 
             pub unsafe extern "C" fn inner() {
+                let _locks = $crate::sync::InterruptedLocks::set_aside();
                 $code
             }
         }
@@ -76,6 +77,7 @@ macro_rules! interrupt_stack {
             }
 
             pub unsafe extern "C" fn inner($stack: &mut $crate::arch::x86_64::interrupt::handler::InterruptStack) {
+                let _locks = $crate::sync::InterruptedLocks::set_aside();
                 $code
             }
         }
@@ -104,6 +106,7 @@ macro_rules! interrupt_stack {
             }
 
             pub unsafe extern "C" fn inner($stack: &mut $crate::arch::x86_64::interrupt::handler::InterruptStack) {
+                let _locks = $crate::sync::InterruptedLocks::set_aside();
                 $code
             }
         }
@@ -138,6 +141,7 @@ macro_rules! interrupt_error {
             }
 
             pub unsafe extern "C" fn inner($stack: &mut $crate::arch::x86_64::interrupt::handler::InterruptStack, $code: usize) {
+                let _locks = $crate::sync::InterruptedLocks::set_aside();
                 $code_block
             }
         }
@@ -6,4 +6,149 @@ use rmm::{Arch as RmmArchTrait, PageTable, TableKind};
 
 pub unsafe fn page_table_allocator() -> Option<Frame> {
     crate::memory::allocate_frame()
-}
\ No newline at end of file
+}
+
+/// Number of base pages covered by one huge page
+pub const HUGE_PAGE_COUNT: usize = RmmA::PAGE_ENTRIES;
+/// Size of a huge page, mapped directly by a level 1 (page directory) entry
+pub const HUGE_PAGE_SIZE: usize = PAGE_SIZE * HUGE_PAGE_COUNT;
+
+/// Walk down to the level 1 table covering `virt`, allocating missing tables if `create` is set.
+unsafe fn huge_parent(
+    table: PageTable<RmmA>,
+    virt: VirtualAddress,
+    create: bool,
+) -> Option<PageTable<RmmA>> {
+    let mut table = table;
+    while table.level() > 1 {
+        let i = table.index_of(virt)?;
+        table = match unsafe { table.next(i) } {
+            Some(next) => next,
+            None if create => {
+                let frame = crate::memory::allocate_p2frame_complex(
+                    0,
+                    crate::memory::AllocationFlags::ZEROED,
+                    None,
+                    0,
+                    None,
+                )?
+                .0;
+                let mut flags = RmmA::ENTRY_FLAG_READWRITE | RmmA::ENTRY_FLAG_DEFAULT_TABLE;
+                if virt.kind() == TableKind::User {
+                    flags |= RmmA::ENTRY_FLAG_USER;
+                }
+                unsafe {
+                    table.set_entry(i, rmm::PageEntry::new(frame.base().data(), flags));
+                    table.next(i)?
+                }
+            }
+            None => return None,
+        };
+    }
+    Some(table)
+}
+
+/// Map the huge page at `virt` to the huge frame at `phys`.
+///
+/// Fails if a table of small pages is already present at `virt`.
+pub unsafe fn map_huge(
+    table: PageTable<RmmA>,
+    virt: VirtualAddress,
+    phys: PhysicalAddress,
+    flags: PageFlags<RmmA>,
+) -> Option<rmm::PageFlush<RmmA>> {
+    let mut parent = unsafe { huge_parent(table, virt, true)? };
+    let i = parent.index_of(virt)?;
+    if unsafe { parent.entry(i) }.is_some_and(|entry| entry.present()) {
+        return None;
+    }
+    let flags = flags.custom_flag(super::entry::EntryFlags::HUGE_PAGE.bits(), true);
+    unsafe {
+        parent.set_entry(i, rmm::PageEntry::new(phys.data(), flags.data()));
+    }
+    Some(rmm::PageFlush::new(virt))
+}
+
+/// Remove the table of small pages at `virt` if none of its entries is present, so that a huge
+/// page can be mapped there, returning the frame of the table.
+///
+/// The table may be cached until `virt` is invalidated on every CPU using `table`, so the frame
+/// must not be freed before.
+pub unsafe fn unmap_empty_table(
+    table: PageTable<RmmA>,
+    virt: VirtualAddress,
+) -> Option<(Frame, rmm::PageFlush<RmmA>)> {
+    let mut parent = unsafe { huge_parent(table, virt, false)? };
+    let i = parent.index_of(virt)?;
+    let entry = unsafe { parent.entry(i)? };
+    if !entry.present()
+        || entry
+            .flags()
+            .has_flag(super::entry::EntryFlags::HUGE_PAGE.bits())
+    {
+        return None;
+    }
+    let frame = Frame::containing(entry.address().ok()?);
+    let small = unsafe { parent.next(i)? };
+    if (0..RmmA::PAGE_ENTRIES).any(|j| unsafe { small.entry(j) }.is_some_and(|e| e.present())) {
+        return None;
+    }
+    unsafe {
+        parent.set_entry(i, rmm::PageEntry::new(0, 0));
+    }
+    Some((frame, rmm::PageFlush::new(virt)))
+}
+
+/// Change the flags of the huge page mapped at `virt`, returning the huge frame backing it.
+pub unsafe fn remap_huge(
+    table: PageTable<RmmA>,
+    virt: VirtualAddress,
+    flags: PageFlags<RmmA>,
+) -> Option<(PhysicalAddress, rmm::PageFlush<RmmA>)> {
+    let (phys, _, flush) = unsafe { unmap_huge(table, virt)? };
+    flush.ignore();
+    let flush = unsafe { map_huge(table, virt, phys, flags)? };
+    Some((phys, flush))
+}
+
+/// Look up the huge page mapped at `virt`, returning the huge frame and its flags.
+pub unsafe fn translate_huge(
+    table: PageTable<RmmA>,
+    virt: VirtualAddress,
+) -> Option<(PhysicalAddress, PageFlags<RmmA>)> {
+    let parent = unsafe { huge_parent(table, virt, false)? };
+    let entry = unsafe { parent.entry(parent.index_of(virt)?)? };
+    if !entry.present()
+        || !entry
+            .flags()
+            .has_flag(super::entry::EntryFlags::HUGE_PAGE.bits())
+    {
+        return None;
+    }
+    Some((entry.address().ok()?, entry.flags()))
+}
+
+/// Remove the huge page mapped at `virt`, returning the huge frame and its flags.
+pub unsafe fn unmap_huge(
+    table: PageTable<RmmA>,
+    virt: VirtualAddress,
+) -> Option<(PhysicalAddress, PageFlags<RmmA>, rmm::PageFlush<RmmA>)> {
+    let mut parent = unsafe { huge_parent(table, virt, false)? };
+    let i = parent.index_of(virt)?;
+    let entry = unsafe { parent.entry(i)? };
+    if !entry.present()
+        || !entry
+            .flags()
+            .has_flag(super::entry::EntryFlags::HUGE_PAGE.bits())
+    {
+        return None;
+    }
+    unsafe {
+        parent.set_entry(i, rmm::PageEntry::new(0, 0));
+    }
+    Some((
+        entry.address().ok()?,
+        entry.flags(),
+        rmm::PageFlush::new(virt),
+    ))
+}
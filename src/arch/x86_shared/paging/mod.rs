@@ -5,7 +5,13 @@ use core::fmt::Debug;
 
 use x86::msr;
 
-use crate::memory::Frame;
+use arrayvec::ArrayVec;
+
+use crate::{
+    cpu_set::{LogicalCpuId, LogicalCpuSet, MAX_CPU_COUNT},
+    memory::Frame,
+    percpu::{self, TlbTicket, TLB_SHOOTDOWN_MAX_PAGES},
+};
 
 pub use super::CurrentRmmArch as RmmA;
 pub use rmm::{Arch as RmmArch, PageFlags, PageFlush, PhysicalAddress, TableKind, VirtualAddress};
@@ -16,6 +22,8 @@ pub mod entry {
     bitflags! {
         pub struct EntryFlags: usize {
             const NO_CACHE =        1 << 4;
+            /// Together with a read-only mapping, marks a shadow stack page
+            const DIRTY =           1 << 6;
             const HUGE_PAGE =       1 << 7;
             const GLOBAL =          1 << 8;
             const DEV_MEM =         0;
@@ -149,14 +157,104 @@ pub fn round_up_pages(number: usize) -> usize {
     number.next_multiple_of(PAGE_SIZE)
 }
 
+/// What happened to a page queued on [`TlbShootdownActions`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlbAction {
+    /// Mapped where nothing was, which no TLB caches
+    NewMapping,
+    ChangeProtection,
+    Free,
+}
+
+/// Invalidations of user pages of one address space, batched until [`Self::flush`].
+///
+/// Only the CPUs the address space is active on are involved. If that is just the current CPU,
+/// the pages are invalidated locally without any IPI. Otherwise every other CPU gets a single
+/// shootdown carrying the whole batch, or asking for a full flush once more than
+/// [`TLB_SHOOTDOWN_MAX_PAGES`] pages are queued, and the flush waits until all have handled it.
+/// Whatever is still queued when dropped is flushed then.
 #[derive(Debug)]
-pub struct TlbShootdownActions;
+pub struct TlbShootdownActions {
+    /// The `used_by` of the address space, which does not change while it is locked for writing
+    cpus: LogicalCpuSet,
+    pages: ArrayVec<usize, TLB_SHOOTDOWN_MAX_PAGES>,
+    /// Too many pages were queued for `pages`, or one at an unknown address
+    full: bool,
+}
 
 impl TlbShootdownActions {
-    pub const NEW_MAPPING: Self = Self;
-    pub fn new() -> Self {
-        Self
+    pub const NEW_MAPPING: TlbAction = TlbAction::NewMapping;
+    pub const CHANGE_PROTECTION: TlbAction = TlbAction::ChangeProtection;
+    pub const FREE: TlbAction = TlbAction::Free;
+
+    /// Batch invalidations for an address space active on `cpus`
+    pub fn new(cpus: LogicalCpuSet) -> Self {
+        Self {
+            cpus,
+            pages: ArrayVec::new(),
+            full: false,
+        }
+    }
+
+    pub fn queue(&mut self, _frame: Frame, page: Option<Page>, action: TlbAction) {
+        if action == TlbAction::NewMapping || self.full {
+            return;
+        }
+        let pushed = page.map(|page| self.pages.try_push(page.start_address().data()));
+        if !matches!(pushed, Some(Ok(()))) {
+            self.full = true;
+            self.pages.clear();
+        }
+    }
+
+    /// Invalidate everything queued so far on every CPU the address space is active on
+    pub fn flush(&mut self) {
+        if !self.full && self.pages.is_empty() {
+            return;
+        }
+        let pages = (!self.full).then_some(self.pages.as_slice());
+        let current = crate::cpu_id();
+
+        // Other CPUs invalidate while this one does
+        let mut tickets = ArrayVec::<TlbTicket, MAX_CPU_COUNT>::new();
+        for id in 0..crate::cpu_count() {
+            let cpu = LogicalCpuId::new(id);
+            if cpu == current || !self.cpus.contains(cpu) {
+                continue;
+            }
+            if let Some(ticket) = percpu::request_tlb_shootdown(cpu, pages)
+                && let Err(full) = tickets.try_push(ticket)
+            {
+                percpu::wait_tlb_shootdown(full.element());
+            }
+        }
+        let local = self.cpus.contains(current);
+        if local {
+            unsafe {
+                match pages {
+                    Some(pages) => {
+                        for &page in pages {
+                            RmmA::invalidate(VirtualAddress::new(page));
+                        }
+                    }
+                    None => RmmA::invalidate_all(),
+                }
+            }
+        }
+        if local || !tickets.is_empty() {
+            percpu::count_tlb_flush(pages.map(<[usize]>::len));
+        }
+        for ticket in tickets {
+            percpu::wait_tlb_shootdown(ticket);
+        }
+
+        self.pages.clear();
+        self.full = false;
+    }
+}
+
+impl Drop for TlbShootdownActions {
+    fn drop(&mut self) {
+        self.flush();
     }
-    pub fn flush(&mut self) {}
-    pub fn queue(&mut self, _frame: Frame, _page: Option<Page>, _action: TlbShootdownActions) {}
 }
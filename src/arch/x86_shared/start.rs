@@ -111,6 +111,11 @@ unsafe extern "C" fn start(args_ptr: *const KernelArgs, stack_end: usize) -> ! {
 
             // Set up GDT
             gdt::init_bsp(stack_end);
+            let stack = STACK.get().addr();
+            crate::percpu::PercpuBlock::current()
+                .misc_arch_info
+                .boot_stack
+                .set((stack, stack.saturating_add(size_of_val(&STACK))));
 
             // Set up IDT
             idt::init_bsp();
@@ -126,6 +131,8 @@ unsafe extern "C" fn start(args_ptr: *const KernelArgs, stack_end: usize) -> ! {
 
             #[cfg(target_arch = "x86_64")]
             crate::alternative::early_init(true);
+            #[cfg(target_arch = "x86_64")]
+            crate::arch::features::init_bsp();
 
             // Set up syscall instruction
             interrupt::syscall::init();
@@ -142,9 +149,18 @@ unsafe extern "C" fn start(args_ptr: *const KernelArgs, stack_end: usize) -> ! {
             // Activate memory logging
             crate::log::init();
 
+            // Parse the boot parameters for the CPU features they turn off, kmain finds them done
+            crate::startup::params::init(args.env());
+
+            // Decide on shadow stacks before the first kernel stack is allocated
+            #[cfg(target_arch = "x86_64")]
+            crate::arch::cet::init_bsp();
+
             // Initialize miscellaneous processor features
             #[cfg(target_arch = "x86_64")]
             crate::misc::init(LogicalCpuId::BSP);
+            #[cfg(target_arch = "x86_64")]
+            crate::topology::init_cpu(LogicalCpuId::BSP);
 
             // Initialize devices
             device::init();
@@ -164,12 +180,16 @@ unsafe extern "C" fn start(args_ptr: *const KernelArgs, stack_end: usize) -> ! {
             args.bootstrap()
         };
 
+        #[cfg(target_arch = "x86_64")]
+        crate::arch::cet::enter(LogicalCpuId::BSP, move || crate::kmain(bootstrap));
+        #[cfg(target_arch = "x86")]
         crate::kmain(bootstrap);
     }
 }
 
 /// Arguments for starting an application processor.
 pub struct KernelArgsAp {
+    pub stack_start: *mut u8,
     pub stack_end: *mut u8,
     pub cpu_id: LogicalCpuId,
     pub pcr_ptr: *mut gdt::ProcessorControlRegion,
@@ -221,6 +241,10 @@ unsafe extern "C" fn start_ap(args_ptr: *const KernelArgsAp) -> ! {
 
             // Set up GDT
             gdt::install_pcr(args.pcr_ptr);
+            crate::percpu::PercpuBlock::current()
+                .misc_arch_info
+                .boot_stack
+                .set((args.stack_start.addr(), args.stack_end.addr()));
 
             // Set up IDT
             idt::install_idt(args.idt_ptr);
@@ -232,6 +256,8 @@ unsafe extern "C" fn start_ap(args_ptr: *const KernelArgsAp) -> ! {
 
             #[cfg(target_arch = "x86_64")]
             crate::alternative::early_init(false);
+            #[cfg(target_arch = "x86_64")]
+            crate::arch::features::init_ap();
 
             // Set up syscall instruction
             interrupt::syscall::init();
@@ -239,6 +265,8 @@ unsafe extern "C" fn start_ap(args_ptr: *const KernelArgsAp) -> ! {
             // Initialize miscellaneous processor features
             #[cfg(target_arch = "x86_64")]
             crate::misc::init(args.cpu_id);
+            #[cfg(target_arch = "x86_64")]
+            crate::topology::init_cpu(args.cpu_id);
 
             // Initialize devices (for AP)
             device::init_ap();
@@ -252,6 +280,9 @@ unsafe extern "C" fn start_ap(args_ptr: *const KernelArgsAp) -> ! {
             hint::spin_loop();
         }
 
+        #[cfg(target_arch = "x86_64")]
+        crate::arch::cet::enter(cpu_id, move || crate::kmain_ap(cpu_id));
+        #[cfg(target_arch = "x86")]
         crate::kmain_ap(cpu_id);
     }
 }
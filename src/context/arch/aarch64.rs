@@ -1,6 +1,6 @@
 use crate::{
     arch::{device::cpu::registers::control_regs, interrupt::InterruptStack, paging::PageMapper},
-    context::{context::Kstack, memory::Table},
+    context::{kstack::Kstack, memory::Table},
     percpu::PercpuBlock,
     syscall::FloatRegisters,
 };
@@ -0,0 +1,337 @@
+use crate::{context::kstack::Kstack, percpu::PercpuBlock};
+use core::{
+    mem::{offset_of, size_of},
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+use spin::Once;
+
+/// This must be used by the kernel to ensure that context switches are done atomically
+/// Compare and exchange this to true when beginning a context switch on any CPU
+/// The `Context::switch_to` function will set it back to false, allowing other CPU's to switch
+/// This must be done, as no locks can be held on the stack during switch
+pub static CONTEXT_SWITCH_LOCK: AtomicBool = AtomicBool::new(false);
+
+pub const KFX_ALIGN: usize = 16;
+
+/// VFP support detected at boot, see [`vfp_init`]
+static VFP: AtomicU8 = AtomicU8::new(VFP_NONE);
+const VFP_NONE: u8 = 0;
+/// VFPv3-D16, only d0-d15 exist
+const VFP_D16: u8 = 1;
+/// VFPv3-D32 or NEON, d0-d31 exist
+const VFP_D32: u8 = 2;
+
+/// FPEXC.EN, enables the VFP and NEON register file
+const FPEXC_EN: u32 = 1 << 30;
+
+/// Layout of the kfx area on ARMv7
+#[repr(C)]
+pub struct VfpState {
+    d: [u64; 32],
+    fpscr: u32,
+    fpexc: u32,
+}
+
+pub const KFX_SIZE: usize = size_of::<VfpState>();
+
+#[derive(Clone, Debug, Default)]
+pub struct Context {
+    pub(crate) tpidrurw: usize, /* User read/write thread ID register                  */
+    pub(crate) tpidruro: usize, /* Pointer to the context, user read-only thread ID    */
+    fx_loadable: bool,
+    sp: usize,  /* Stack Pointer (r13)                                  */
+    lr: usize,  /* Link Register (r14)                                  */
+    r11: usize, /* Frame pointer, callee saved Register                 */
+    r10: usize, /* Callee saved Register                                */
+    r9: usize,  /* Callee saved Register                                */
+    r8: usize,  /* Callee saved Register                                */
+    r7: usize,  /* Callee saved Register                                */
+    r6: usize,  /* Callee saved Register                                */
+    r5: usize,  /* Callee saved Register                                */
+    r4: usize,  /* Callee saved Register, entry point of a new context  */
+}
+
+impl Context {
+    pub fn new() -> Context {
+        Context::default()
+    }
+
+    fn set_stack(&mut self, address: usize) {
+        self.sp = address;
+    }
+
+    fn set_r4(&mut self, r4: usize) {
+        self.r4 = r4;
+    }
+
+    fn set_lr(&mut self, address: usize) {
+        self.lr = address;
+    }
+
+    fn set_context_handle(&mut self) {
+        let address = self as *const _ as usize;
+        self.tpidruro = address;
+    }
+
+    pub(crate) fn setup_initial_call(
+        &mut self,
+        stack: &Kstack,
+        func: extern "C" fn(),
+        userspace_allowed: bool,
+    ) {
+        // There is no usermode entry on ARMv7 yet, so every context starts as a kernel thread
+        assert!(!userspace_allowed, "ARMv7 does not support userspace yet");
+
+        self.set_lr(kthread_entry as usize);
+        self.set_r4(func as usize);
+        self.set_context_handle();
+
+        self.set_stack(stack.initial_top() as usize);
+    }
+
+    #[allow(unused)]
+    pub fn dump(&self) {
+        println!("tpidrurw: 0x{:08x}", self.tpidrurw);
+        println!("tpidruro: 0x{:08x}", self.tpidruro);
+        println!("sp: 0x{:08x}", self.sp);
+        println!("lr: 0x{:08x}", self.lr);
+        println!("r11: 0x{:08x}", self.r11);
+        println!("r10: 0x{:08x}", self.r10);
+        println!("r9: 0x{:08x}", self.r9);
+        println!("r8: 0x{:08x}", self.r8);
+        println!("r7: 0x{:08x}", self.r7);
+        println!("r6: 0x{:08x}", self.r6);
+        println!("r5: 0x{:08x}", self.r5);
+        println!("r4: 0x{:08x}", self.r4);
+    }
+}
+
+impl super::Context {
+    pub fn current_syscall(&self) -> Option<[usize; 6]> {
+        None
+    }
+
+    pub fn set_userspace_io_allowed(&mut self, _allowed: bool) {}
+}
+
+pub static EMPTY_CR3: Once<rmm::PhysicalAddress> = Once::new();
+
+// SAFETY: EMPTY_CR3 must be initialized.
+pub unsafe fn empty_cr3() -> rmm::PhysicalAddress {
+    unsafe {
+        debug_assert!(EMPTY_CR3.poll().is_some());
+        *EMPTY_CR3.get_unchecked()
+    }
+}
+
+/// Detect the VFP on this CPU and enable it
+///
+/// Grants full access to coprocessors 10 and 11 in CPACR. If the bits do not stick, there is
+/// no VFP, and contexts switch without touching the floating point state.
+pub unsafe fn vfp_init() {
+    unsafe {
+        let cpacr: u32;
+        core::arch::asm!(
+            "mrc p15, 0, {0}, c1, c0, 2",
+            "orr {0}, {0}, #(0xF << 20)",
+            "mcr p15, 0, {0}, c1, c0, 2",
+            "isb",
+            "mrc p15, 0, {0}, c1, c0, 2",
+            out(reg) cpacr,
+        );
+        if cpacr & (0xF << 20) != (0xF << 20) {
+            VFP.store(VFP_NONE, Ordering::Relaxed);
+            return;
+        }
+
+        let mvfr0: u32;
+        core::arch::asm!(
+            ".fpu vfpv3",
+            "vmrs {0}, fpexc",
+            "orr {0}, {0}, #(1 << 30)",
+            "vmsr fpexc, {0}",
+            "vmrs {0}, mvfr0",
+            out(reg) mvfr0,
+        );
+        // MVFR0.A_SIMD_registers, 2 means 32 double precision registers
+        let kind = if mvfr0 & 0xF == 2 { VFP_D32 } else { VFP_D16 };
+        VFP.store(kind, Ordering::Relaxed);
+    }
+}
+
+unsafe fn fp_save(state: *mut VfpState, kind: u8) {
+    unsafe {
+        let fpexc: u32;
+        core::arch::asm!(
+            ".fpu neon",
+            "vmrs {fpexc}, fpexc",
+            "orr {tmp}, {fpexc}, #(1 << 30)",
+            "vmsr fpexc, {tmp}",
+            "vstmia {d}, {{d0-d15}}",
+            "cmp {kind}, #{d32}",
+            "addeq {tmp}, {d}, #(8 * 16)",
+            "vstmiaeq {tmp}, {{d16-d31}}",
+            "vmrs {tmp}, fpscr",
+            "str {tmp}, [{d}, #{off_fpscr}]",
+            fpexc = out(reg) fpexc,
+            tmp = out(reg) _,
+            d = in(reg) state,
+            kind = in(reg) kind as u32,
+            d32 = const VFP_D32,
+            off_fpscr = const offset_of!(VfpState, fpscr),
+        );
+        (*state).fpexc = fpexc;
+    }
+}
+
+unsafe fn fp_load(state: *const VfpState, kind: u8) {
+    unsafe {
+        core::arch::asm!(
+            ".fpu neon",
+            "vmrs {tmp}, fpexc",
+            "orr {tmp}, {tmp}, #(1 << 30)",
+            "vmsr fpexc, {tmp}",
+            "vldmia {d}, {{d0-d15}}",
+            "cmp {kind}, #{d32}",
+            "addeq {tmp}, {d}, #(8 * 16)",
+            "vldmiaeq {tmp}, {{d16-d31}}",
+            "ldr {tmp}, [{d}, #{off_fpscr}]",
+            "vmsr fpscr, {tmp}",
+            "ldr {tmp}, [{d}, #{off_fpexc}]",
+            "vmsr fpexc, {tmp}",
+            tmp = out(reg) _,
+            d = in(reg) state,
+            kind = in(reg) kind as u32,
+            d32 = const VFP_D32,
+            off_fpscr = const offset_of!(VfpState, fpscr),
+            off_fpexc = const offset_of!(VfpState, fpexc),
+        );
+    }
+}
+
+pub unsafe fn switch_to(prev: &mut super::Context, next: &mut super::Context) {
+    unsafe {
+        let vfp = VFP.load(Ordering::Relaxed);
+        if vfp != VFP_NONE {
+            fp_save(prev.kfx.as_mut_ptr() as *mut VfpState, vfp);
+
+            prev.arch.fx_loadable = true;
+
+            if next.arch.fx_loadable {
+                fp_load(next.kfx.as_ptr() as *const VfpState, vfp);
+            } else {
+                // A fresh context starts with the VFP enabled and the default FPSCR
+                core::arch::asm!(
+                    ".fpu vfpv3",
+                    "vmsr fpexc, {en}",
+                    "vmsr fpscr, {zero}",
+                    en = in(reg) FPEXC_EN,
+                    zero = in(reg) 0_u32,
+                );
+            }
+        }
+
+        PercpuBlock::current()
+            .new_addrsp_tmp
+            .set(next.addr_space.clone());
+
+        switch_to_inner(&mut prev.arch, &mut next.arch)
+    }
+}
+
+/// Switch to the first context on this CPU
+///
+/// The boot context is never resumed, so its registers go to a save area that is then dropped.
+pub unsafe fn switch_to_first(next: &mut super::Context) {
+    unsafe {
+        let vfp = VFP.load(Ordering::Relaxed);
+        if vfp != VFP_NONE && next.arch.fx_loadable {
+            fp_load(next.kfx.as_ptr() as *const VfpState, vfp);
+        }
+
+        PercpuBlock::current()
+            .new_addrsp_tmp
+            .set(next.addr_space.clone());
+
+        let mut boot = Context::new();
+        switch_to_inner(&mut boot, &mut next.arch)
+    }
+}
+
+#[unsafe(naked)]
+unsafe extern "C" fn switch_to_inner(_prev: &mut Context, _next: &mut Context) {
+    core::arch::naked_asm!(
+        "
+        str r4, [r0, #{off_r4}]
+        ldr r4, [r1, #{off_r4}]
+
+        str r5, [r0, #{off_r5}]
+        ldr r5, [r1, #{off_r5}]
+
+        str r6, [r0, #{off_r6}]
+        ldr r6, [r1, #{off_r6}]
+
+        str r7, [r0, #{off_r7}]
+        ldr r7, [r1, #{off_r7}]
+
+        str r8, [r0, #{off_r8}]
+        ldr r8, [r1, #{off_r8}]
+
+        str r9, [r0, #{off_r9}]
+        ldr r9, [r1, #{off_r9}]
+
+        str r10, [r0, #{off_r10}]
+        ldr r10, [r1, #{off_r10}]
+
+        str r11, [r0, #{off_r11}]
+        ldr r11, [r1, #{off_r11}]
+
+        str lr, [r0, #{off_lr}]
+        ldr lr, [r1, #{off_lr}]
+
+        mrc p15, 0, r2, c13, c0, 2
+        str r2, [r0, #{off_tpidrurw}]
+        ldr r2, [r1, #{off_tpidrurw}]
+        mcr p15, 0, r2, c13, c0, 2
+
+        mrc p15, 0, r2, c13, c0, 3
+        str r2, [r0, #{off_tpidruro}]
+        ldr r2, [r1, #{off_tpidruro}]
+        mcr p15, 0, r2, c13, c0, 3
+
+        mov r2, sp
+        str r2, [r0, #{off_sp}]
+        ldr r2, [r1, #{off_sp}]
+        mov sp, r2
+
+        b {switch_hook}
+        ",
+        off_r4 = const(offset_of!(Context, r4)),
+        off_r5 = const(offset_of!(Context, r5)),
+        off_r6 = const(offset_of!(Context, r6)),
+        off_r7 = const(offset_of!(Context, r7)),
+        off_r8 = const(offset_of!(Context, r8)),
+        off_r9 = const(offset_of!(Context, r9)),
+        off_r10 = const(offset_of!(Context, r10)),
+        off_r11 = const(offset_of!(Context, r11)),
+        off_lr = const(offset_of!(Context, lr)),
+        off_tpidrurw = const(offset_of!(Context, tpidrurw)),
+        off_tpidruro = const(offset_of!(Context, tpidruro)),
+        off_sp = const(offset_of!(Context, sp)),
+
+        switch_hook = sym crate::context::switch_finish_hook,
+    );
+}
+
+/// First code run by a new context, once `switch_finish_hook` returns into it
+///
+/// `setup_initial_call` leaves the entry point in r4. Kernel threads must not return.
+#[unsafe(naked)]
+unsafe extern "C" fn kthread_entry() -> ! {
+    core::arch::naked_asm!(
+        "
+        blx r4
+        udf #0
+        "
+    );
+}
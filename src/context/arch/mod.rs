@@ -8,6 +8,11 @@ pub mod aarch64;
 #[cfg(target_arch = "aarch64")]
 pub use self::aarch64::*;
 
+#[cfg(target_arch = "arm")]
+pub mod armv7;
+#[cfg(target_arch = "arm")]
+pub use self::armv7::*;
+
 #[cfg(target_arch = "riscv64")]
 pub mod riscv64;
 #[cfg(target_arch = "riscv64")]
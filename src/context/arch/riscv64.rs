@@ -3,7 +3,7 @@ use crate::{
         interrupt::InterruptStack,
         paging::{PageMapper, ENTRY_COUNT},
     },
-    context::{context::Kstack, memory::Table},
+    context::{kstack::Kstack, memory::Table},
     memory::{KernelMapper, RmmA},
     percpu::PercpuBlock,
     syscall::FloatRegisters,
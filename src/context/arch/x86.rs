@@ -8,7 +8,7 @@ use crate::{
 
 use crate::{
     arch::{interrupt::InterruptStack, paging::PageMapper},
-    context::{context::Kstack, memory::Table},
+    context::{kstack::Kstack, memory::Table},
     memory::RmmA,
 };
 use core::mem::offset_of;
@@ -26,6 +26,9 @@ const ST_RESERVED: u128 = 0xFFFF_FFFF_FFFF_0000_0000_0000_0000_0000;
 
 pub const KFX_ALIGN: usize = 16;
 
+/// MXCSR_MASK to assume when the processor leaves it zero in the FXSAVE area
+const DEFAULT_MXCSR_MASK: u32 = 0xFFBF;
+
 #[derive(Clone, Debug)]
 #[repr(C)]
 pub struct Context {
@@ -129,6 +132,14 @@ impl super::Context {
             }
             new.st_space = new_st;
 
+            // Setting a reserved MXCSR bit makes fxrstor raise #GP
+            let mxcsr_mask = match old.mxcsr_mask {
+                0 => DEFAULT_MXCSR_MASK,
+                mask => mask,
+            };
+            new.mxcsr &= mxcsr_mask;
+            new.mxcsr_mask = old.mxcsr_mask;
+
             // Make sure we don't use `old` from now on
         }
 
@@ -12,10 +12,12 @@ use core::{
 
 use crate::{
     arch::{
+        cet,
+        features::{self, FeatureFlags},
         interrupt::InterruptStack,
         paging::{PageMapper, ENTRY_COUNT},
     },
-    context::{context::Kstack, memory::Table},
+    context::{kstack::Kstack, memory::Table},
     memory::RmmA,
     syscall::FloatRegisters,
 };
@@ -28,23 +30,6 @@ use x86::{
     msr,
 };
 
-bitflags! {
-    #[derive(Clone, Copy)]
-    pub struct FeatureFlags: u64 {
-        const XSAVE = 1 << 0;
-        const XSAVEOPT = 1 << 1;
-        const FSGSBASE = 1 << 2;
-    }
-}
-
-impl Default for FeatureFlags {
-    fn default() -> Self {
-        Self::empty()
-    }
-}
-
-pub static CPU_FEATURES: Once<FeatureFlags> = Once::new();
-
 /// Global lock to ensure atomic context switches.
 pub static CONTEXT_SWITCH_LOCK: AtomicBool = AtomicBool::new(false);
 
@@ -52,6 +37,9 @@ pub static CONTEXT_SWITCH_LOCK: AtomicBool = AtomicBool::new(false);
 // AVX-512 requires 64-byte alignment for the XSAVE area.
 pub const KFX_ALIGN: usize = 64;
 
+/// MXCSR_MASK to assume when the processor leaves it zero in the FXSAVE area
+const DEFAULT_MXCSR_MASK: u32 = 0xFFBF;
+
 #[derive(Clone, Debug)]
 #[repr(C)]
 pub struct Context {
@@ -75,6 +63,8 @@ pub struct Context {
     pub(crate) fsbase: usize,
     /// GSBASE
     pub(crate) gsbase: usize,
+    /// Restore token to switch back to the shadow stack with, if shadow stacks are enabled
+    ssp: usize,
     userspace_io_allowed: bool,
 }
 
@@ -91,6 +81,7 @@ impl Context {
             rsp: 0,
             fsbase: 0,
             gsbase: 0,
+            ssp: 0,
             userspace_io_allowed: false,
         }
     }
@@ -126,6 +117,16 @@ impl Context {
         }
 
         self.set_stack(stack_top as usize);
+
+        // The shadow stack has to hold the same return addresses
+        if let Some(shadow) = stack.shadow_stack() {
+            let enter_usermode = crate::interrupt::syscall::enter_usermode as usize;
+            self.ssp = if userspace_allowed {
+                shadow.prepare(&[enter_usermode, func as usize])
+            } else {
+                shadow.prepare(&[func as usize])
+            };
+        }
     }
 }
 
@@ -135,11 +136,35 @@ impl crate::context::Context {
     }
 
     pub fn set_fx_regs(&mut self, mut new: FloatRegisters) {
+        {
+            let old = unsafe { &*(self.kfx.as_ptr().cast::<FloatRegisters>()) };
+            // Setting a reserved MXCSR bit makes xrstor raise #GP
+            let mxcsr_mask = match old.mxcsr_mask {
+                0 => DEFAULT_MXCSR_MASK,
+                mask => mask,
+            };
+            new.mxcsr &= mxcsr_mask;
+            new.mxcsr_mask = old.mxcsr_mask;
+        }
+
         unsafe {
             self.kfx.as_mut_ptr().cast::<FloatRegisters>().write(new);
         }
     }
 
+    /// Write the FPU state of the current context back to `kfx` if it is live in the registers,
+    /// and set `CR0.TS` so that its next use reloads it from there. Must only be called on the
+    /// current context.
+    pub unsafe fn flush_fx(&mut self) {
+        let cr0 = controlregs::cr0();
+        if !cr0.contains(Cr0::CR0_TASK_SWITCHED) {
+            unsafe {
+                save_fx(self.kfx.as_mut_ptr());
+                controlregs::cr0_write(cr0 | Cr0::CR0_TASK_SWITCHED);
+            }
+        }
+    }
+
     pub fn set_userspace_io_allowed(&mut self, allowed: bool) {
         self.arch.userspace_io_allowed = allowed;
         if self.is_current_context() {
@@ -236,11 +261,13 @@ pub unsafe fn switch_to(prev: &mut crate::context::Context, next: &mut crate::co
 
         if let Some(ref stack) = next.kstack {
             crate::gdt::set_tss_stack(0, stack.initial_top() as usize);
+            if let Some(shadow) = stack.shadow_stack() {
+                cet::set_kernel_entry(shadow);
+            }
         }
         crate::gdt::set_userspace_io_allowed(next.arch.userspace_io_allowed, pcr);
 
-        let features = CPU_FEATURES.get().map(|f| *f).unwrap_or_default();
-        let use_xsave = features.contains(FeatureFlags::XSAVE);
+        let features = features::get().flags;
 
         // --- Phase 2.4: Lazy Switching Core Logic ---
         let mut cr0 = controlregs::cr0();
@@ -248,33 +275,7 @@ pub unsafe fn switch_to(prev: &mut crate::context::Context, next: &mut crate::co
         // If TS is not set, the current FPU state belongs to 'prev' and is modified. We must save it.
         // Optimization: This avoids saving if the previous process never touched AVX.
         if !cr0.contains(Cr0::CR0_TASK_SWITCHED) {
-            if use_xsave {
-                // Phase 2.3: Core XSAVE Routine
-                // Optimized XSAVE (XSAVEOPT) saves only modified state if hardware supports it.
-                if features.contains(FeatureFlags::XSAVEOPT) {
-                    core::arch::asm!(
-                        "mov eax, 0xffffffff",
-                        "mov edx, eax",
-                        "xsaveopt64 [{prev_fx}]",
-                        out("eax") _, out("edx") _,
-                        prev_fx = in(reg) prev.kfx.as_mut_ptr(),
-                    );
-                } else {
-                    core::arch::asm!(
-                        "mov eax, 0xffffffff",
-                        "mov edx, eax",
-                        "xsave64 [{prev_fx}]",
-                        out("eax") _, out("edx") _,
-                        prev_fx = in(reg) prev.kfx.as_mut_ptr(),
-                    );
-                }
-            } else {
-                // Legacy FXSAVE for older CPUs
-                core::arch::asm!(
-                    "fxsave64 [{prev_fx}]",
-                    prev_fx = in(reg) prev.kfx.as_mut_ptr(),
-                );
-            }
+            save_fx(prev.kfx.as_mut_ptr());
         }
 
         // Set TS bit. This "arms" the trap.
@@ -361,6 +362,16 @@ unsafe extern "sysv64" fn switch_to_inner(_prev: &mut Context, _next: &mut Conte
         pop QWORD PTR [rdi + {off_rflags}]
         push QWORD PTR [rsi + {off_rflags}]
         popfq
+
+        // Switch shadow stacks, leaving a restore token for prev on its own
+        cmp BYTE PTR [rip + {cet_enabled}], 0
+        je 2f
+        rdsspq rax
+        rstorssp [rsi + {off_ssp}]
+        saveprevssp
+        sub rax, 8
+        mov [rdi + {off_ssp}], rax
+    2:
         jmp {switch_hook}
         "),
         off_rflags = const(offset_of!(Cx, rflags)),
@@ -371,6 +382,8 @@ unsafe extern "sysv64" fn switch_to_inner(_prev: &mut Context, _next: &mut Conte
         off_r15 = const(offset_of!(Cx, r15)),
         off_rbp = const(offset_of!(Cx, rbp)),
         off_rsp = const(offset_of!(Cx, rsp)),
+        off_ssp = const(offset_of!(Cx, ssp)),
+        cet_enabled = sym crate::arch::cet::ENABLED,
         switch_hook = sym crate::context::switch_finish_hook,
     );
 }
@@ -393,6 +406,40 @@ pub fn setup_new_utable() -> Result<Table> {
     })
 }
 
+/// Save the FPU/AVX registers to `kfx`, with XSAVE where the processor has it
+unsafe fn save_fx(kfx: *mut u8) {
+    let features = features::get().flags;
+    unsafe {
+        if features.contains(FeatureFlags::XSAVE) {
+            // Phase 2.3: Core XSAVE Routine
+            // Optimized XSAVE (XSAVEOPT) saves only modified state if hardware supports it.
+            if features.contains(FeatureFlags::XSAVEOPT) {
+                core::arch::asm!(
+                    "mov eax, 0xffffffff",
+                    "mov edx, eax",
+                    "xsaveopt64 [{kfx}]",
+                    out("eax") _, out("edx") _,
+                    kfx = in(reg) kfx,
+                );
+            } else {
+                core::arch::asm!(
+                    "mov eax, 0xffffffff",
+                    "mov edx, eax",
+                    "xsave64 [{kfx}]",
+                    out("eax") _, out("edx") _,
+                    kfx = in(reg) kfx,
+                );
+            }
+        } else {
+            // Legacy FXSAVE for older CPUs
+            core::arch::asm!(
+                "fxsave64 [{kfx}]",
+                kfx = in(reg) kfx,
+            );
+        }
+    }
+}
+
 /// Phase 2.4: Device Not Available (#NM) Fault Hook
 ///
 /// This function is intended to be called by the #NM exception handler (Trap 7).
@@ -408,7 +455,7 @@ pub unsafe extern "C" fn device_not_available_handler(current_kfx: *mut u8) {
     }
 
     // 2. Restore state
-    let features = CPU_FEATURES.get().map(|f| *f).unwrap_or_default();
+    let features = features::get().flags;
 
     if features.contains(FeatureFlags::XSAVE) {
         unsafe {
@@ -1,33 +1,37 @@
 use alloc::{collections::BTreeSet, sync::Arc, vec::Vec};
-use arrayvec::ArrayString;
 use core::{
     mem::{self, size_of},
     num::NonZeroUsize,
     sync::atomic::{AtomicU32, AtomicUsize, Ordering},
 };
 use spin::RwLock;
-use syscall::{SigProcControl, Sigcontrol, UPPER_FDTBL_TAG};
+use syscall::{flag::O_CREAT, SigProcControl, Sigcontrol, UPPER_FDTBL_TAG};
 
 use crate::{
     arch::{interrupt::InterruptStack, paging::PAGE_SIZE},
     common::aligned_box::AlignedBox,
-    context::{self, arch, file::FileDescriptor},
+    context::{self, arch, file::FileDescriptor, kstack::Kstack, name::ContextName, slab::Slab},
     cpu_set::{LogicalCpuId, LogicalCpuSet},
     cpu_stats,
     ipi::{ipi, IpiKind, IpiTarget},
-    memory::{allocate_p2frame, deallocate_p2frame, Enomem, Frame, RaiiFrame},
+    memory::{Frame, RaiiFrame},
     paging::{RmmA, RmmArch},
     percpu::PercpuBlock,
-    scheduler,
+    scheduler::{self, SchedPolicy},
     scheme::{CallerCtx, FileHandle, SchemeId, SchemeNamespace},
     sync::{CleanLockToken, Priority},
+    syscall::personality::PersonalityState,
 };
 
-use crate::syscall::error::{Error, Result, EAGAIN, EBADF, EEXIST, EINVAL, EMFILE, ENOMEM, ESRCH};
+use crate::syscall::error::{
+    Error, Result, EAGAIN, EBADF, EEXIST, EINVAL, EMFILE, ENFILE, ENOMEM, EPERM, ESRCH,
+};
 
 use super::{
     empty_cr3,
-    memory::{AddrSpaceWrapper, GrantFileRef},
+    memory::{
+        AddrSpaceWrapper, GrantFileRef, LockLimit, DEFAULT_MEMLOCK_LIMIT, DEFAULT_STACK_LIMIT,
+    },
 };
 
 /// The status of a context - used for scheduling
@@ -45,6 +49,11 @@ pub enum Status {
     HardBlocked {
         reason: HardBlockedReason,
     },
+    /// Exited, but kept in the context list with its wait status until its parent reaps it
+    Zombie {
+        excp: Option<syscall::Exception>,
+        status: usize,
+    },
     Dead {
         excp: Option<syscall::Exception>,
     },
@@ -57,6 +66,9 @@ impl Status {
     pub fn is_soft_blocked(&self) -> bool {
         matches!(self, Self::Blocked)
     }
+    pub fn has_exited(&self) -> bool {
+        matches!(self, Self::Zombie { .. } | Self::Dead { .. })
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -70,8 +82,6 @@ pub enum HardBlockedReason {
     NotYetStarted,
 }
 
-const CONTEXT_NAME_CAPAC: usize = 32;
-
 #[derive(Debug)]
 pub enum SyscallFrame {
     Free(RaiiFrame),
@@ -105,6 +115,8 @@ pub struct Context {
     pub switch_time: u128,
     /// Amount of CPU time used
     pub cpu_time: u128,
+    /// Number of times this context has been switched to
+    pub switch_count: u64,
     /// Scheduler CPU affinity. If set, [`cpu_id`] can except [`None`] never be anything else than
     /// this value.
     pub sched_affinity: LogicalCpuSet,
@@ -134,18 +146,24 @@ pub struct Context {
     /// not yet had its address space changed. Note that these are only for user mappings; kernel
     /// mappings are universal and independent on address spaces or contexts.
     pub addr_space: Option<Arc<AddrSpaceWrapper>>,
-    /// The name of the context
-    pub name: ArrayString<CONTEXT_NAME_CAPAC>,
+    /// The name of the context, also listed in [`context::names`] to be read without locking
+    /// the context
+    pub name: Arc<ContextName>,
     /// The open files in the scheme
     pub files: Arc<RwLock<FdTbl>>,
     /// All contexts except kmain will primarily live in userspace, and enter the kernel only when
     /// interrupts or syscalls occur. This flag is set for all contexts but kmain.
     pub userspace: bool,
     pub being_sigkilled: bool,
+    /// Set by an `interrupt` on `proc:<pid>/ctl`, makes the current or next blocking wait return
+    /// EINTR
+    pub interrupt_pending: bool,
     pub fmap_ret: Option<Frame>,
 
     // TODO: id can reappear after wraparound?
     pub owner_proc_id: Option<NonZeroUsize>,
+    /// Id of the context that created this one, which may collect its exit status with waitpid
+    pub parent: Option<usize>,
 
     // TODO: Temporary replacement for existing kernel logic, replace with capabilities!
     pub ens: SchemeNamespace,
@@ -160,17 +178,47 @@ pub struct Context {
     /// Virtual deadline for the scheduler
     pub virtual_deadline: u64,
 
+    /// Time this context last blocked, for the scheduler to tell how long it slept once woken
+    pub last_blocked_at: Option<u128>,
+
+    /// Set by a wakeup boost, shortens the next time slice to the minimum
+    pub wakeup_boost: bool,
+
     /// Last CPU this context ran on, for cache locality
     pub last_cpu_id: Option<LogicalCpuId>,
 
     /// True if this is a hard real-time task
     pub is_realtime: bool,
 
+    /// Scheduling policy, as set by `sched_setscheduler`
+    pub sched_policy: SchedPolicy,
+
+    /// Budget of a context with the deadline policy
+    pub sched_deadline: Option<scheduler::DeadlineState>,
+
     /// Memory lock status (MCL_CURRENT, MCL_FUTURE flags)
     pub mlock: u32,
 
     /// Count of memory-locked pages for this context
     pub memory_locked_count: usize,
+
+    /// Bytes the user of this context may lock with mlock, like `RLIMIT_MEMLOCK`
+    pub memlock_limit: usize,
+
+    /// Size in bytes a user stack may grow to by faulting on its guard page
+    pub stack_limit: usize,
+
+    /// Upcall handler registered with `SYS_UPCALL_REGISTER`, and its queued payloads
+    pub upcall: Option<context::upcall::UpcallState>,
+
+    /// Limits on the number of files this context may have open
+    pub nofile: FileLimit,
+
+    /// Permission bits cleared from the mode of files this context creates
+    pub umask: Umask,
+
+    /// The ABI this context's syscalls follow, see [`crate::syscall::personality`]
+    pub personality: PersonalityState,
 }
 
 #[derive(Debug)]
@@ -207,6 +255,7 @@ impl Context {
             cpu_id: None,
             switch_time: 0,
             cpu_time: 0,
+            switch_count: 0,
             sched_affinity: LogicalCpuSet::all(),
             inside_syscall: false,
             syscall_head: SyscallFrame::Free(RaiiFrame::allocate()?),
@@ -218,12 +267,14 @@ impl Context {
             )?,
             kstack: None,
             addr_space: None,
-            name: ArrayString::new(),
+            name: Arc::new(ContextName::new()),
             files: Arc::new(RwLock::new(FdTbl::new())),
             userspace: false,
             fmap_ret: None,
             being_sigkilled: false,
+            interrupt_pending: false,
             owner_proc_id,
+            parent: None,
 
             ens: 0.into(),
             euid: 0,
@@ -233,10 +284,24 @@ impl Context {
 
             priority: priority_tracker,
             virtual_deadline: 0,
+            last_blocked_at: None,
+            wakeup_boost: false,
             last_cpu_id: None,
             is_realtime,
+            sched_policy: if is_realtime {
+                SchedPolicy::Fifo
+            } else {
+                SchedPolicy::Normal
+            },
+            sched_deadline: None,
             mlock: 0,
             memory_locked_count: 0,
+            memlock_limit: DEFAULT_MEMLOCK_LIMIT,
+            stack_limit: DEFAULT_STACK_LIMIT,
+            upcall: None,
+            nofile: FileLimit::DEFAULT,
+            umask: Umask::DEFAULT,
+            personality: PersonalityState::default(),
 
             #[cfg(feature = "syscall_debug")]
             syscall_debug_info: crate::syscall::debug::SyscallDebugInfo::default(),
@@ -249,6 +314,12 @@ impl Context {
         self.id
     }
 
+    /// Rename the context, truncating the name to `CONTEXT_NAME_CAPAC` bytes. Only needs the
+    /// context locked for reading.
+    pub fn set_name(&self, name: &str) {
+        self.name.set(name);
+    }
+
     /// Set whether this context is a hard real-time task.
     pub fn set_realtime(&mut self, is_rt: bool) {
         self.is_realtime = is_rt;
@@ -259,6 +330,7 @@ impl Context {
         if self.status.is_runnable() {
             self.status = Status::Blocked;
             self.status_reason = reason;
+            self.last_blocked_at = Some(crate::time::monotonic());
             scheduler::remove_context(&self.id());
             true
         } else {
@@ -269,6 +341,7 @@ impl Context {
     pub fn hard_block(&mut self, reason: HardBlockedReason) -> bool {
         if self.status.is_runnable() {
             self.status = Status::HardBlocked { reason };
+            self.last_blocked_at = Some(crate::time::monotonic());
             scheduler::remove_context(&self.id());
             true
         } else {
@@ -277,6 +350,8 @@ impl Context {
     }
 
     /// Unblock context, and return true if it was blocked before being marked runnable
+    ///
+    /// The context then still has to be queued, see [`scheduler::unblock`].
     pub fn unblock(&mut self) -> bool {
         if self.unblock_no_ipi() {
             // TODO: Only send IPI if currently running?
@@ -298,10 +373,8 @@ impl Context {
         if self.status.is_soft_blocked() {
             self.status = Status::Runnable;
             self.status_reason = "";
-            // Re-add to scheduler if it was removed
-            // This requires a ContextRef, which we don't have here.
-            // The `spawn` function already adds it, and `block` removes it.
-            // So, this is implicitly handled by the `spawn` and `block` calls.
+            // `block` took it off the run queue, the caller queues it again with
+            // `scheduler::wake` once the context is unlocked
             true
         } else {
             false
@@ -309,31 +382,35 @@ impl Context {
     }
 
     /// Add a file to the lowest available slot.
-    /// Return the file descriptor number or None if no slot was found
-    pub fn add_file(&self, file: FileDescriptor) -> Option<FileHandle> {
+    /// Return the file descriptor number, or EMFILE or ENFILE if past the limits on open files
+    pub fn add_file(&self, file: FileDescriptor) -> Result<FileHandle> {
         self.add_file_min(file, 0)
     }
 
     /// Add a file to the lowest available slot greater than or equal to min.
-    /// Return the file descriptor number or None if no slot was found
-    pub fn add_file_min(&self, file: FileDescriptor, min: usize) -> Option<FileHandle> {
-        self.files.write().add_file_min(file, min)
+    /// Return the file descriptor number, or EMFILE or ENFILE if past the limits on open files
+    pub fn add_file_min(&self, file: FileDescriptor, min: usize) -> Result<FileHandle> {
+        self.files.write().add_file_min(file, min, self.nofile.soft)
     }
 
     /// Bulk-add multiple files to the POSIX file table
     pub fn bulk_add_files_posix(
         &self,
         files_to_add: Vec<FileDescriptor>,
-    ) -> Option<Vec<FileHandle>> {
-        self.files.write().bulk_add_files_posix(files_to_add)
+    ) -> Result<Vec<FileHandle>> {
+        self.files
+            .write()
+            .bulk_add_files_posix(files_to_add, self.nofile.soft)
     }
 
     /// Bulk-insert multiple files into to the upper file table contiguously
     pub fn bulk_insert_files_upper(
         &self,
         files_to_insert: Vec<FileDescriptor>,
-    ) -> Option<Vec<FileHandle>> {
-        self.files.write().bulk_insert_files_upper(files_to_insert)
+    ) -> Result<Vec<FileHandle>> {
+        self.files
+            .write()
+            .bulk_insert_files_upper(files_to_insert, self.nofile.soft)
     }
 
     /// Bulk-insert multiple files into to the upper file table manually
@@ -342,9 +419,11 @@ impl Context {
         files_to_insert: Vec<FileDescriptor>,
         handles: &[FileHandle],
     ) -> Result<()> {
-        self.files
-            .write()
-            .bulk_insert_files_upper_manual(files_to_insert, handles)
+        self.files.write().bulk_insert_files_upper_manual(
+            files_to_insert,
+            handles,
+            self.nofile.soft,
+        )
     }
 
     /// Get a file
@@ -358,13 +437,13 @@ impl Context {
     }
 
     /// Insert a file with a specific handle number. This is used by dup2
-    /// Return the file descriptor number or None if the slot was not empty, or i was invalid
-    pub fn insert_file(&self, i: FileHandle, file: FileDescriptor) -> Option<FileHandle> {
-        self.files.write().insert_file(i, file)
+    /// Return the file descriptor number, or EMFILE if the slot was not empty, i was invalid or
+    /// the limit on open files is reached, or ENFILE past the kernel-wide limit
+    pub fn insert_file(&self, i: FileHandle, file: FileDescriptor) -> Result<FileHandle> {
+        self.files.write().insert_file(i, file, self.nofile.soft)
     }
 
     /// Remove a file
-    // TODO: adjust files vector to smaller size if possible
     pub fn remove_file(&self, i: FileHandle) -> Option<FileDescriptor> {
         self.files.write().remove_file(i)
     }
@@ -378,6 +457,14 @@ impl Context {
         self.running && self.cpu_id == Some(crate::cpu_id())
     }
 
+    /// The user charged for memory this context locks, and their limit. Root is not limited.
+    pub fn lock_limit(&self) -> LockLimit {
+        LockLimit {
+            uid: self.euid,
+            bytes: (self.euid != 0).then_some(self.memlock_limit),
+        }
+    }
+
     pub fn addr_space(&self) -> Result<&Arc<AddrSpaceWrapper>> {
         self.addr_space.as_ref().ok_or(Error::new(ESRCH))
     }
@@ -484,8 +571,37 @@ impl Context {
             uid: self.euid,
             gid: self.egid,
             pid: self.pid,
+            ns: self.ens,
+            mode: 0,
         }
     }
+    /// The caller of an open with `flags`, and the flags with the umask applied to their mode
+    pub fn open_caller_ctx(&self, flags: usize) -> (CallerCtx, usize) {
+        let flags = self.umask.apply(flags);
+        let mut caller = self.caller_ctx();
+        if flags & O_CREAT == O_CREAT {
+            caller.mode = (flags & Umask::MODE) as u16;
+        }
+        (caller, flags)
+    }
+    /// The settings a new process created by this context starts out with
+    pub fn inherited(&self) -> Inherited {
+        Inherited {
+            parent: self.id,
+            nofile: self.nofile,
+            memlock_limit: self.memlock_limit,
+            umask: self.umask,
+            personality: self.personality,
+        }
+    }
+    /// Take over the settings of the context creating this one as a new process
+    pub fn inherit(&mut self, from: Inherited) {
+        self.parent = Some(from.parent);
+        self.nofile = from.nofile;
+        self.memlock_limit = from.memlock_limit;
+        self.umask = from.umask;
+        self.personality = from.personality;
+    }
     pub fn has_capability(&self, cap: Capabilities) -> bool {
         self.capabilities.contains(cap)
     }
@@ -595,56 +711,151 @@ impl Drop for BorrowedHtBuf {
     }
 }
 
-pub struct Kstack {
-    /// naturally aligned, order 4
-    base: Frame,
+/// Files open in all file tables together
+static OPEN_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of files open in all file tables together
+pub fn open_files() -> usize {
+    OPEN_FILES.load(Ordering::Relaxed)
 }
-impl Kstack {
-    pub fn new() -> Result<Self, Enomem> {
-        Ok(Self {
-            base: allocate_p2frame(4).ok_or(Enomem)?,
+
+/// Count `count` more files as open, or fail with ENFILE if that would be more than
+/// [`super::MAX_OPEN_FILES`]
+fn charge_open_files(count: usize) -> Result<()> {
+    OPEN_FILES
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |open| {
+            open.checked_add(count)
+                .filter(|&open| open <= super::MAX_OPEN_FILES)
         })
-    }
-    pub fn initial_top(&self) -> *mut u8 {
-        unsafe { (RmmA::phys_to_virt(self.base.base()).data() as *mut u8).add(PAGE_SIZE << 4) }
-    }
-    pub fn len(&self) -> usize {
-        PAGE_SIZE << 4
-    }
+        .map(|_| ())
+        .map_err(|_| Error::new(ENFILE))
 }
 
-impl Drop for Kstack {
-    fn drop(&mut self) {
-        unsafe { deallocate_p2frame(self.base, 4) }
+fn release_open_files(count: usize) {
+    OPEN_FILES.fetch_sub(count, Ordering::Relaxed);
+}
+
+/// Limits on the number of files a context may have open, like `RLIMIT_NOFILE`. The soft limit
+/// is enforced, and may be raised up to the hard limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileLimit {
+    pub soft: usize,
+    pub hard: usize,
+}
+
+impl FileLimit {
+    pub const DEFAULT: Self = Self {
+        soft: super::CONTEXT_DEFAULT_FILES,
+        hard: super::CONTEXT_MAX_FILES,
+    };
+
+    /// Change the limits to `new` on behalf of a caller with effective uid `euid`. Only root may
+    /// raise the hard limit, and neither may exceed [`super::CONTEXT_MAX_FILES`].
+    pub fn set(&mut self, new: FileLimit, euid: u32) -> Result<()> {
+        if new.soft > new.hard || new.hard > super::CONTEXT_MAX_FILES {
+            return Err(Error::new(EINVAL));
+        }
+        if new.hard > self.hard && euid != 0 {
+            return Err(Error::new(EPERM));
+        }
+        *self = new;
+        Ok(())
     }
 }
-impl core::fmt::Debug for Kstack {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "[kstack at {:?}]", self.base)
+
+/// Settings a new process copies from the context creating it, see [`Context::inherited`]
+#[derive(Clone, Copy, Debug)]
+pub struct Inherited {
+    parent: usize,
+    nofile: FileLimit,
+    memlock_limit: usize,
+    umask: Umask,
+    personality: PersonalityState,
+}
+
+/// Permission bits taken away from the mode of files a context creates, like the POSIX umask.
+/// Applied by the kernel before an open reaches the scheme, so schemes get the final mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Umask(u16);
+
+impl Umask {
+    pub const DEFAULT: Self = Self(0o022);
+    /// The bits a umask can clear
+    const PERMISSIONS: usize = 0o777;
+    /// The mode in open flags
+    const MODE: usize = 0o7777;
+
+    pub fn bits(self) -> u16 {
+        self.0
+    }
+
+    /// Change the mask to the permission bits of `mask`, and return the previous mask
+    pub fn replace(&mut self, mask: usize) -> Umask {
+        mem::replace(self, Self((mask & Self::PERMISSIONS) as u16))
+    }
+
+    /// Clear the masked bits from the mode in open `flags`, if the open may create a file
+    pub fn apply(self, flags: usize) -> usize {
+        if flags & O_CREAT == O_CREAT {
+            flags & !usize::from(self.0)
+        } else {
+            flags
+        }
     }
 }
 
-#[derive(Clone, Debug, Default)]
+/// The file table of a context. Every file in it is counted against the kernel-wide limit on
+/// open files until it is removed or the table is dropped.
+///
+/// Descriptors without [`UPPER_FDTBL_TAG`] are allocated POSIX style, at the lowest free number,
+/// and the tagged ones in blocks. Both halves are a [`Slab`], so that allocating, looking up and
+/// freeing a descriptor do not depend on how many are open.
+#[derive(Debug, Default)]
 pub struct FdTbl {
-    pub posix_fdtbl: Vec<Option<FileDescriptor>>,
-    pub upper_fdtbl: Vec<Option<FileDescriptor>>,
-    active_count: usize,
+    posix_fdtbl: Slab<FileDescriptor>,
+    upper_fdtbl: Slab<FileDescriptor>,
 }
 
 impl FdTbl {
     pub fn new() -> Self {
         Self {
-            posix_fdtbl: Vec::new(),
-            upper_fdtbl: Vec::new(),
-            active_count: 0,
+            posix_fdtbl: Slab::new(),
+            upper_fdtbl: Slab::new(),
+        }
+    }
+
+    /// Duplicate the table for another context, counting its files against the kernel-wide limit
+    /// again
+    pub fn try_clone(&self) -> Result<Self> {
+        charge_open_files(self.open_count())?;
+        Ok(Self {
+            posix_fdtbl: self.posix_fdtbl.clone(),
+            upper_fdtbl: self.upper_fdtbl.clone(),
+        })
+    }
+
+    /// Number of files in the table
+    pub fn open_count(&self) -> usize {
+        self.posix_fdtbl
+            .len()
+            .saturating_add(self.upper_fdtbl.len())
+    }
+
+    /// Count `count` more files as open, failing with EMFILE if the table would hold more than
+    /// `limit` and with ENFILE past the kernel-wide limit. The caller must add them to the table,
+    /// or release them again if it cannot.
+    fn reserve(&self, count: usize, limit: usize) -> Result<()> {
+        if self.open_count().saturating_add(count) > limit.min(super::CONTEXT_MAX_FILES) {
+            return Err(Error::new(EMFILE));
         }
+        charge_open_files(count)
     }
 
     fn strip_tags(index: usize) -> usize {
         index & !UPPER_FDTBL_TAG
     }
 
-    fn select_fdtbl(&self, index: usize) -> (&Vec<Option<FileDescriptor>>, usize) {
+    fn select_fdtbl(&self, index: usize) -> (&Slab<FileDescriptor>, usize) {
         if index & UPPER_FDTBL_TAG == 0 {
             (&self.posix_fdtbl, index)
         } else {
@@ -652,7 +863,7 @@ impl FdTbl {
         }
     }
 
-    fn select_fdtbl_mut(&mut self, index: usize) -> (&mut Vec<Option<FileDescriptor>>, usize) {
+    fn select_fdtbl_mut(&mut self, index: usize) -> (&mut Slab<FileDescriptor>, usize) {
         if index & UPPER_FDTBL_TAG == 0 {
             (&mut self.posix_fdtbl, index)
         } else {
@@ -664,13 +875,17 @@ impl FdTbl {
         let mut checked_handles = BTreeSet::new();
         for i in handles {
             let index = i.get();
+            // Only the upper table is placed manually
+            if index & UPPER_FDTBL_TAG == 0 {
+                return Err(Error::new(EBADF));
+            }
             if Self::strip_tags(index) >= super::CONTEXT_MAX_FILES {
                 return Err(Error::new(EMFILE));
             }
             if !checked_handles.insert(index) {
                 return Err(Error::new(EBADF)); // Duplicate handle
             }
-            if !matches!(self.get(index), Some(Some(_))) {
+            if self.get(index).is_none() {
                 return Err(Error::new(EBADF));
             }
         }
@@ -688,7 +903,7 @@ impl FdTbl {
             if !checked_slots.insert(index) {
                 return Err(Error::new(EINVAL)); // Duplicate slots
             }
-            if matches!(self.get(index), Some(Some(_))) {
+            if self.get(index).is_some() {
                 return Err(Error::new(EEXIST));
             }
         }
@@ -696,119 +911,104 @@ impl FdTbl {
         Ok(())
     }
 
-    pub fn add_file_min(&mut self, file: FileDescriptor, min: usize) -> Option<FileHandle> {
-        if self.active_count >= super::CONTEXT_MAX_FILES {
-            return None;
-        }
+    pub fn add_file_min(
+        &mut self,
+        file: FileDescriptor,
+        min: usize,
+        limit: usize,
+    ) -> Result<FileHandle> {
+        self.reserve(1, limit)?;
 
         let tag = min & UPPER_FDTBL_TAG;
-
         let (fdtbl, min) = self.select_fdtbl_mut(min);
 
-        // Find the first empty slot in the posix_fdtbl starting from `min`.
-        if let Some((pos, slot)) = fdtbl
-            .iter_mut()
-            .enumerate()
-            .skip(min)
-            .find(|(_, slot)| slot.is_none())
-        {
-            *slot = Some(file);
-            self.active_count += 1;
-            return Some(FileHandle::from(pos | tag));
+        // The first empty slot starting from `min`
+        let Some(index) = fdtbl.first_free(min) else {
+            release_open_files(1);
+            return Err(Error::new(EMFILE));
         };
-
-        let len = fdtbl.len();
-
-        // If no empty slot was found, we need to allocate a new slot.
-        if len >= min {
-            fdtbl.push(Some(file));
-            self.active_count += 1;
-            Some(FileHandle::from(len | tag))
-        } else {
-            self.insert_file(FileHandle::from(min | tag), file)
+        if fdtbl.insert(index, file).is_err() {
+            release_open_files(1);
+            return Err(Error::new(EMFILE));
         }
+        Ok(FileHandle::from(index | tag))
     }
 
     fn bulk_add_files_posix(
         &mut self,
         files_to_add: Vec<FileDescriptor>,
-    ) -> Option<Vec<FileHandle>> {
+        limit: usize,
+    ) -> Result<Vec<FileHandle>> {
         let count = files_to_add.len();
         if count == 0 {
-            return Some(Vec::new());
-        }
-        if self.active_count + count > super::CONTEXT_MAX_FILES {
-            return None;
-        }
-
-        let handles = self.find_free_posix_slots(count);
-        let max_index = handles[count - 1].get();
-        if self.posix_fdtbl.len() <= max_index {
-            // Resize the posix_fdtbl to accommodate the new files.
-            self.posix_fdtbl.resize(max_index + 1, None);
+            return Ok(Vec::new());
         }
+        self.reserve(count, limit)?;
 
+        let Some(handles) = self.find_free_posix_slots(count) else {
+            release_open_files(count);
+            return Err(Error::new(EMFILE));
+        };
         for (&handle, file) in handles.iter().zip(files_to_add) {
-            let index = handle.get();
-            self.posix_fdtbl[index] = Some(file);
+            // The slots were just found to be free
+            let _ = self.posix_fdtbl.insert(handle.get(), file);
         }
 
-        self.active_count += count;
-        Some(handles)
+        Ok(handles)
     }
 
-    fn insert_file(&mut self, i: FileHandle, file: FileDescriptor) -> Option<FileHandle> {
-        if self.active_count >= super::CONTEXT_MAX_FILES {
-            return None;
-        }
+    fn insert_file(
+        &mut self,
+        i: FileHandle,
+        file: FileDescriptor,
+        limit: usize,
+    ) -> Result<FileHandle> {
         let index = i.get();
-        let (fdtbl, real_index) = self.select_fdtbl_mut(index);
-
-        if real_index >= super::CONTEXT_MAX_FILES {
-            return None;
-        }
-
-        if real_index >= fdtbl.len() {
-            fdtbl.resize_with(real_index + 1, || None);
+        if Self::strip_tags(index) >= super::CONTEXT_MAX_FILES {
+            return Err(Error::new(EMFILE));
         }
+        self.reserve(1, limit)?;
 
-        if let Some(slot @ None) = fdtbl.get_mut(real_index) {
-            *slot = Some(file);
-            self.active_count += 1;
-            Some(i)
+        let (fdtbl, real_index) = self.select_fdtbl_mut(index);
+        if fdtbl.insert(real_index, file).is_ok() {
+            Ok(i)
         } else {
-            None
+            release_open_files(1);
+            Err(Error::new(EMFILE))
         }
     }
 
     fn bulk_insert_files_upper(
         &mut self,
         files_to_insert: Vec<FileDescriptor>,
-    ) -> Option<Vec<FileHandle>> {
+        limit: usize,
+    ) -> Result<Vec<FileHandle>> {
         let count = files_to_insert.len();
         if count == 0 {
-            return Some(Vec::new());
-        }
-        if self.active_count + count > super::CONTEXT_MAX_FILES {
-            return None;
+            return Ok(Vec::new());
         }
+        self.reserve(count, limit)?;
 
-        let index = Self::strip_tags(self.find_free_upper_block(count).get());
+        let Some(index) = self.upper_fdtbl.first_free_run(count) else {
+            release_open_files(count);
+            return Err(Error::new(EMFILE));
+        };
         let mut handles = Vec::with_capacity(count);
         for (i, file) in files_to_insert.into_iter().enumerate() {
-            let current_index = index + i;
-            self.upper_fdtbl[current_index] = Some(file);
+            let current_index = index.saturating_add(i);
+            // The whole run was just found to be free
+            let _ = self.upper_fdtbl.insert(current_index, file);
             handles.push(FileHandle::from(current_index | UPPER_FDTBL_TAG));
         }
 
-        self.active_count += count;
-        Some(handles)
+        Ok(handles)
     }
 
     fn bulk_insert_files_upper_manual(
         &mut self,
         files_to_insert: Vec<FileDescriptor>,
         handles: &[FileHandle],
+        limit: usize,
     ) -> Result<()> {
         if handles.len() != files_to_insert.len() {
             return Err(Error::new(EINVAL));
@@ -817,52 +1017,41 @@ impl FdTbl {
         if count == 0 {
             return Ok(());
         }
-        if self.active_count + count > super::CONTEXT_MAX_FILES {
-            return Err(Error::new(EMFILE));
-        }
         self.validate_free_slots(handles)?;
+        self.reserve(count, limit)?;
 
-        let max_index = handles
-            .iter()
-            .map(|h: &FileHandle| Self::strip_tags(h.get()))
-            .max()
-            .unwrap_or(0);
-        if self.upper_fdtbl.len() <= max_index {
-            self.upper_fdtbl.resize_with(max_index + 1, || None);
-        }
         for (file, &handle) in files_to_insert.into_iter().zip(handles) {
             let index = Self::strip_tags(handle.get());
-            self.upper_fdtbl[index] = Some(file);
+            if self.upper_fdtbl.insert(index, file).is_err() {
+                // Every slot was checked to be free above
+                release_open_files(1);
+            }
         }
 
-        self.active_count += count;
         Ok(())
     }
 
-    pub fn get(&self, index: usize) -> Option<&Option<FileDescriptor>> {
+    pub fn get(&self, index: usize) -> Option<&FileDescriptor> {
         let (fdtbl, real_index) = self.select_fdtbl(index);
 
         fdtbl.get(real_index)
     }
 
-    pub fn get_mut(&mut self, index: usize) -> Option<&mut Option<FileDescriptor>> {
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut FileDescriptor> {
         let (fdtbl, real_index) = self.select_fdtbl_mut(index);
 
         fdtbl.get_mut(real_index)
     }
 
     pub fn get_file(&self, i: FileHandle) -> Option<FileDescriptor> {
-        self.get(i.get()).cloned().flatten()
+        self.get(i.get()).cloned()
     }
 
     fn bulk_get_files(&self, handles: &[FileHandle]) -> Result<Vec<FileDescriptor>> {
         // Validate that all handles are valid before proceeding to avoid partial results.
         self.validate_handles(handles)?;
 
-        let files = handles
-            .iter()
-            .map(|&i| self.get_file(i).expect("File should exist"))
-            .collect();
+        let files = handles.iter().filter_map(|&i| self.get_file(i)).collect();
 
         Ok(files)
     }
@@ -875,7 +1064,6 @@ impl FdTbl {
         scheme_number: usize,
     ) -> Result<FileDescriptor> {
         self.iter()
-            .flatten()
             .find(|&context_fd| {
                 let desc = context_fd.description.read();
                 desc.scheme == scheme_id && desc.number == scheme_number
@@ -888,9 +1076,9 @@ impl FdTbl {
         let index = i.get();
         let (fdtbl, real_index) = self.select_fdtbl_mut(index);
 
-        let removed_file_opt = fdtbl.get_mut(real_index).and_then(|opt| opt.take());
+        let removed_file_opt = fdtbl.remove(real_index);
         if removed_file_opt.is_some() {
-            self.active_count -= 1;
+            release_open_files(1);
         }
 
         removed_file_opt
@@ -902,89 +1090,234 @@ impl FdTbl {
 
         let files = handles
             .iter()
-            .map(|&i| self.remove_file(i).expect("File should exist"))
+            .filter_map(|&i| self.remove_file(i))
             .collect();
 
         Ok(files)
     }
 
-    fn find_free_posix_slots(&self, count: usize) -> Vec<FileHandle> {
+    /// The `count` lowest free POSIX descriptors, or `None` if there are not that many
+    fn find_free_posix_slots(&self, count: usize) -> Option<Vec<FileHandle>> {
         let mut free_slots = Vec::with_capacity(count);
-
-        for (i, slot) in self.posix_fdtbl.iter().enumerate() {
-            if slot.is_none() {
-                free_slots.push(FileHandle::from(i));
-                if free_slots.len() == count {
-                    return free_slots;
-                }
-            }
-        }
-
-        let mut current_len = self.posix_fdtbl.len();
+        let mut next = 0;
         while free_slots.len() < count {
-            free_slots.push(FileHandle::from(current_len));
-            current_len += 1;
+            let index = self.posix_fdtbl.first_free(next)?;
+            free_slots.push(FileHandle::from(index));
+            next = index.saturating_add(1);
         }
-        free_slots
+        Some(free_slots)
     }
 
-    fn find_free_upper_block(&mut self, len: usize) -> FileHandle {
-        let mut start = 0;
-        let mut count = 0;
-
-        for (i, file_opt) in self.upper_fdtbl.iter().enumerate() {
-            if file_opt.is_none() {
-                if count == 0 {
-                    start = i;
-                }
-                count += 1;
-                if count == len {
-                    break;
-                }
-            } else {
-                count = 0;
-            }
-        }
-
-        if count < len {
-            if count == 0 {
-                start = self.upper_fdtbl.len();
-            }
-            let needed = len - count;
-            self.upper_fdtbl
-                .resize(self.upper_fdtbl.len() + needed, None);
+    pub fn force_close_all(&mut self, token: &mut CleanLockToken) {
+        release_open_files(self.open_count());
+        let upper = self.upper_fdtbl.drain();
+        for file in self.posix_fdtbl.drain().chain(upper) {
+            let _ = file.close(token);
         }
+    }
 
-        FileHandle::from(start | UPPER_FDTBL_TAG)
+    /// Remove the descriptors marked close-on-exec, for the caller to close once it holds no
+    /// locks. Other descriptors of the same descriptions are left alone.
+    pub fn take_cloexec(&mut self) -> Vec<FileDescriptor> {
+        let mut files = self.posix_fdtbl.remove_where(|file| file.cloexec);
+        files.append(&mut self.upper_fdtbl.remove_where(|file| file.cloexec));
+        release_open_files(files.len());
+        files
     }
+}
 
-    pub fn force_close_all(&mut self, token: &mut CleanLockToken) {
-        for file_opt in self.iter_mut() {
-            if let Some(file) = file_opt.take() {
-                let _ = file.close(token);
-            }
-        }
-        self.active_count = 0;
+impl Drop for FdTbl {
+    fn drop(&mut self) {
+        release_open_files(self.open_count());
     }
 }
 
 impl FdTbl {
-    pub fn enumerate(&self) -> impl Iterator<Item = (usize, &Option<FileDescriptor>)> {
-        self.posix_fdtbl.iter().enumerate().chain(
+    /// The open descriptors with their numbers, the POSIX ones first
+    pub fn enumerate(&self) -> impl Iterator<Item = (usize, &FileDescriptor)> {
+        self.posix_fdtbl.iter().chain(
             self.upper_fdtbl
                 .iter()
-                .enumerate()
                 .map(|(i, fd)| (i | UPPER_FDTBL_TAG, fd)),
         )
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = &Option<FileDescriptor>> {
-        self.posix_fdtbl.iter().chain(self.upper_fdtbl.iter())
+    pub fn iter(&self) -> impl Iterator<Item = &FileDescriptor> {
+        self.enumerate().map(|(_, fd)| fd)
     }
+}
 
-    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Option<FileDescriptor>> {
-        self.posix_fdtbl
-            .iter_mut()
-            .chain(self.upper_fdtbl.iter_mut())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_limit_set() {
+        let mut limit = FileLimit::DEFAULT;
+        let lowered = FileLimit {
+            soft: 64,
+            hard: 128,
+        };
+        assert!(limit.set(lowered, 1000).is_ok());
+        assert_eq!(limit, lowered);
+
+        // Only root may raise the hard limit again
+        let raised = FileLimit {
+            soft: 128,
+            hard: 256,
+        };
+        assert_eq!(limit.set(raised, 1000), Err(Error::new(EPERM)));
+        assert!(limit.set(raised, 0).is_ok());
+
+        let inverted = FileLimit { soft: 256, hard: 1 };
+        assert_eq!(limit.set(inverted, 0), Err(Error::new(EINVAL)));
+        let too_high = FileLimit {
+            soft: 1,
+            hard: context::CONTEXT_MAX_FILES + 1,
+        };
+        assert_eq!(limit.set(too_high, 0), Err(Error::new(EINVAL)));
+    }
+
+    #[test]
+    fn test_umask() {
+        let mut umask = Umask::DEFAULT;
+        assert_eq!(umask.replace(0o077), Umask(0o022));
+        // Only permission bits are kept
+        assert_eq!(umask.replace(0o4027), Umask(0o077));
+        assert_eq!(umask.bits(), 0o027);
+
+        let mode = 0o4777;
+        assert_eq!(umask.apply(O_CREAT | mode), O_CREAT | 0o4750);
+        // Opens that cannot create are left alone
+        assert_eq!(umask.apply(mode), mode);
+    }
+
+    #[test]
+    fn test_umask_inherited() {
+        let mut parent = Context::new(None).unwrap();
+        parent.umask.replace(0o077);
+        let mut child = Context::new(None).unwrap();
+        assert_eq!(child.umask, Umask::DEFAULT);
+
+        child.inherit(parent.inherited());
+        assert_eq!(child.umask, Umask(0o077));
+        assert_eq!(child.parent, Some(parent.id()));
+
+        // The child has its own copy
+        child.umask.replace(0);
+        assert_eq!(parent.umask, Umask(0o077));
+    }
+
+    #[test]
+    fn test_fdtbl_take_cloexec() {
+        let description = Arc::new(RwLock::new(context::file::FileDescription {
+            offset: 0,
+            scheme: SchemeId::from(1),
+            number: 0,
+            flags: 0,
+            internal_flags: context::file::InternalFlags::empty(),
+        }));
+        let file = |cloexec| FileDescriptor {
+            description: Arc::clone(&description),
+            cloexec,
+        };
+
+        let mut files = FdTbl::new();
+        let limit = FileLimit::DEFAULT.soft;
+        let kept = files.add_file_min(file(false), 0, limit).unwrap();
+        let closed = files.add_file_min(file(true), 0, limit).unwrap();
+        let upper = files
+            .bulk_insert_files_upper(vec![file(true)], limit)
+            .unwrap();
+
+        let taken = files.take_cloexec();
+        assert_eq!(taken.len(), 2);
+        assert!(taken.iter().all(|file| file.cloexec));
+        assert!(files.get_file(kept).is_some());
+        assert!(files.get_file(closed).is_none());
+        assert!(files.get_file(upper[0]).is_none());
+        assert_eq!(files.open_count(), 1);
+    }
+
+    #[test]
+    fn test_fdtbl_numbering() {
+        let description = Arc::new(RwLock::new(context::file::FileDescription {
+            offset: 0,
+            scheme: SchemeId::from(1),
+            number: 0,
+            flags: 0,
+            internal_flags: context::file::InternalFlags::empty(),
+        }));
+        let file = || FileDescriptor {
+            description: Arc::clone(&description),
+            cloexec: false,
+        };
+        let limit = FileLimit::DEFAULT.soft;
+        let mut files = FdTbl::new();
+
+        for fd in 0..3 {
+            assert_eq!(files.add_file_min(file(), 0, limit).unwrap().get(), fd);
+        }
+        // dup2 past the end leaves a gap, which is filled first
+        let high = FileHandle::from(100);
+        assert_eq!(files.insert_file(high, file(), limit), Ok(high));
+        assert_eq!(
+            files.insert_file(high, file(), limit),
+            Err(Error::new(EMFILE))
+        );
+        assert_eq!(files.add_file_min(file(), 0, limit).unwrap().get(), 3);
+        assert_eq!(files.add_file_min(file(), 100, limit).unwrap().get(), 101);
+        assert_eq!(files.add_file_min(file(), 200, limit).unwrap().get(), 200);
+
+        assert!(files.remove_file(FileHandle::from(1)).is_some());
+        let bulk = files
+            .bulk_add_files_posix(vec![file(), file()], limit)
+            .unwrap();
+        assert_eq!(bulk, [FileHandle::from(1), FileHandle::from(4)]);
+
+        // Upper descriptors are allocated in contiguous blocks
+        let upper = files
+            .bulk_insert_files_upper(vec![file(), file()], limit)
+            .unwrap();
+        assert_eq!(
+            upper,
+            [
+                FileHandle::from(UPPER_FDTBL_TAG),
+                FileHandle::from(1 | UPPER_FDTBL_TAG)
+            ]
+        );
+        assert!(files.remove_file(upper[0]).is_some());
+        let block = files
+            .bulk_insert_files_upper(vec![file(), file()], limit)
+            .unwrap();
+        assert_eq!(block[0], FileHandle::from(2 | UPPER_FDTBL_TAG));
+        // Descriptors placed manually must name the upper table
+        assert_eq!(
+            files.bulk_insert_files_upper_manual(vec![file()], &[FileHandle::from(5)], limit),
+            Err(Error::new(EBADF))
+        );
+
+        let numbers: Vec<usize> = files.enumerate().map(|(fd, _)| fd).collect();
+        assert_eq!(
+            numbers,
+            [
+                0,
+                1,
+                2,
+                3,
+                4,
+                100,
+                101,
+                200,
+                1 | UPPER_FDTBL_TAG,
+                2 | UPPER_FDTBL_TAG,
+                3 | UPPER_FDTBL_TAG
+            ]
+        );
+        assert_eq!(files.open_count(), numbers.len());
+        assert_eq!(
+            files.insert_file(FileHandle::from(context::CONTEXT_MAX_FILES), file(), limit),
+            Err(Error::new(EMFILE))
+        );
     }
 }
@@ -4,7 +4,7 @@ use crate::{
     event,
     scheme::{self, KernelScheme, SchemeId},
     sync::CleanLockToken,
-    syscall::error::{Error, Result, EBADF},
+    syscall::error::Result,
 };
 use alloc::sync::Arc;
 use spin::RwLock;
@@ -74,11 +74,10 @@ impl FileDescription {
     pub fn try_close(self, token: &mut CleanLockToken) -> Result<()> {
         event::unregister_file(self.scheme, self.number);
 
-        let schemes_guard = scheme::schemes(&token.token());
-        let scheme = schemes_guard
-            .get(self.scheme)
-            .ok_or(Error::new(EBADF))?
-            .clone();
+        // A provider that went away took its handles with it
+        let Some(scheme) = scheme::scheme(self.scheme) else {
+            return Ok(());
+        };
 
         scheme.close(self.number, token)
     }
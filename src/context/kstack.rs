@@ -0,0 +1,374 @@
+//! # Kernel stacks
+//!
+//! Every context that has run in the kernel has a [`Kstack`] of [`KSTACK_SIZE`] bytes. Where the
+//! architecture reserves a kstack region (x86_64 and aarch64), the stack is mapped into a slot of
+//! its own there, with an unmapped guard page below it, rather than used through the physmap.
+//! Overflowing the stack then faults on the guard page instead of overwriting whatever frame
+//! happens to be below it.
+//!
+//! On x86_64 that fault cannot be delivered on the overflowed stack, which turns it into a double
+//! fault. The double fault handler runs on its own IST stack and calls [`check_overflow`], which
+//! panics naming the context that owns the stack rather than letting the CPU triple fault.
+//!
+//! With CET enabled, each kstack on x86_64 also owns the supervisor shadow stack in the matching
+//! slot of the shadow stack region, see [`crate::arch::cet`].
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use alloc::vec::Vec;
+use core::ops::Range;
+
+#[cfg(target_arch = "x86_64")]
+use crate::arch::cet::{self, ShadowStack};
+use crate::{
+    arch::paging::PAGE_SIZE,
+    memory::{allocate_p2frame, deallocate_p2frame, Enomem, Frame},
+};
+
+/// Kernel stacks are allocated as naturally aligned blocks of this order
+pub const KSTACK_ORDER: u32 = 4;
+/// Size of a kernel stack
+pub const KSTACK_SIZE: usize = PAGE_SIZE << KSTACK_ORDER;
+
+pub struct Kstack {
+    /// naturally aligned, order 4
+    base: Frame,
+    /// Lowest address of the stack
+    bottom: usize,
+    /// Slot of the stack in the kstack region, or `None` if it is used through the physmap
+    slot: Option<usize>,
+    /// Shadow stack of the same slot, if shadow stacks are enabled
+    #[cfg(target_arch = "x86_64")]
+    shadow: Option<ShadowStack>,
+}
+
+impl Kstack {
+    pub fn new() -> Result<Self, Enomem> {
+        let base = allocate_p2frame(KSTACK_ORDER).ok_or(Enomem)?;
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        let (bottom, slot) = match region::map(base) {
+            Ok(slot) => (region::stack_bottom(slot), Some(slot)),
+            Err(err) => {
+                unsafe { deallocate_p2frame(base, KSTACK_ORDER) };
+                return Err(err);
+            }
+        };
+        #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+        let (bottom, slot) = {
+            use crate::paging::{RmmA, RmmArch};
+            (RmmA::phys_to_virt(base.base()).data(), None)
+        };
+        #[cfg(target_arch = "x86_64")]
+        let shadow = match slot.filter(|_| cet::enabled()) {
+            Some(slot) => match ShadowStack::new(cet::slot_bottom(slot)) {
+                Ok(shadow) => Some(shadow),
+                Err(err) => {
+                    region::unmap(slot, &[]);
+                    unsafe { deallocate_p2frame(base, KSTACK_ORDER) };
+                    return Err(err);
+                }
+            },
+            None => None,
+        };
+        Ok(Self {
+            base,
+            bottom,
+            slot,
+            #[cfg(target_arch = "x86_64")]
+            shadow,
+        })
+    }
+    pub fn initial_top(&self) -> *mut u8 {
+        unsafe { (self.bottom as *mut u8).add(KSTACK_SIZE) }
+    }
+    pub fn len(&self) -> usize {
+        KSTACK_SIZE
+    }
+    /// The addresses of the stack
+    pub fn range(&self) -> Range<usize> {
+        self.bottom..self.bottom.saturating_add(KSTACK_SIZE)
+    }
+    /// The unmapped page below the stack, if it has one
+    pub fn guard(&self) -> Option<Range<usize>> {
+        let guard = self.bottom.checked_sub(PAGE_SIZE)?;
+        self.slot.map(|_| guard..self.bottom)
+    }
+    /// The shadow stack used along with this stack, if shadow stacks are enabled
+    #[cfg(target_arch = "x86_64")]
+    pub fn shadow_stack(&self) -> Option<&ShadowStack> {
+        self.shadow.as_ref()
+    }
+}
+
+impl Drop for Kstack {
+    fn drop(&mut self) {
+        #[cfg(target_arch = "x86_64")]
+        let shadow = self.shadow.take();
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        if let Some(slot) = self.slot {
+            #[cfg(target_arch = "x86_64")]
+            let shadow_pages = shadow
+                .iter()
+                .flat_map(ShadowStack::pages)
+                .collect::<Vec<_>>();
+            #[cfg(target_arch = "aarch64")]
+            let shadow_pages = Vec::new();
+            region::unmap(slot, &shadow_pages);
+        }
+        #[cfg(target_arch = "x86_64")]
+        if let Some(shadow) = shadow {
+            unsafe { shadow.free() };
+        }
+        unsafe { deallocate_p2frame(self.base, KSTACK_ORDER) }
+    }
+}
+impl core::fmt::Debug for Kstack {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "[kstack at {:?}, {:#x}]", self.base, self.bottom)
+    }
+}
+
+/// Panic if `addr` is in the guard page of a kernel stack, naming the context that overflowed it.
+/// Called by the fault handlers with a faulting kernel address, before they give up on it.
+pub fn check_overflow(addr: usize) {
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    if let Some(slot) = region::guard_slot(addr) {
+        // This could deadlock like the panic handler does, if the overflow happened with a
+        // context locked, but we are going to halt anyways
+        if let Some(contexts) = super::contexts().try_read() {
+            let mut token = unsafe { crate::sync::CleanLockToken::new() };
+            for context_lock in contexts.values() {
+                let context = context_lock.read(token.token());
+                if context
+                    .kstack
+                    .as_ref()
+                    .and_then(Kstack::guard)
+                    .is_some_and(|guard| guard.contains(&addr))
+                {
+                    panic!(
+                        "kernel stack overflow: {:#x} is in the guard page of the kernel stack of {} (debug ID {})",
+                        addr, context.name, context.debug_id
+                    );
+                }
+            }
+        }
+        panic!(
+            "kernel stack overflow: {:#x} is in the guard page of kernel stack slot {}",
+            addr, slot
+        );
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    let _ = addr;
+}
+
+/// The kernel stack containing `addr`, if it is in a slot of the kstack region that is handed out
+/// and mapped. As this is meant for diagnostics that must not wait, it also returns `None` while
+/// slots are being handed out or returned.
+pub fn stack_containing(addr: usize) -> Option<Range<usize>> {
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    if let Some(slot) = region::stack_slot(addr).filter(|&slot| region::is_mapped(slot)) {
+        let bottom = region::stack_bottom(slot);
+        return Some(bottom..bottom.saturating_add(KSTACK_SIZE));
+    }
+    let _ = addr;
+    None
+}
+
+/// Slots handed out in the kstack region
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+mod region {
+    use alloc::vec::Vec;
+    use spin::Mutex;
+
+    use super::{Enomem, Frame, KSTACK_SIZE};
+    use crate::{
+        arch::{
+            consts::{KERNEL_KSTACK_OFFSET, KERNEL_KSTACK_SIZE},
+            paging::PAGE_SIZE,
+        },
+        memory::{KernelMapper, PhysicalAddress},
+        paging::{PageFlags, VirtualAddress},
+    };
+
+    /// Each slot is a guard page followed by a stack
+    pub(super) const SLOT_SIZE: usize = PAGE_SIZE + KSTACK_SIZE;
+    pub(super) const SLOT_COUNT: usize = KERNEL_KSTACK_SIZE / SLOT_SIZE;
+
+    struct Slots {
+        /// Lowest slot never handed out
+        next: usize,
+        /// Slots handed out and returned since
+        free: Vec<usize>,
+        /// Slots handed out whose stack pages are not all mapped, while being mapped or unmapped
+        unmapped: Vec<usize>,
+    }
+
+    static SLOTS: Mutex<Slots> = Mutex::new(Slots {
+        next: 0,
+        free: Vec::new(),
+        unmapped: Vec::new(),
+    });
+
+    pub(super) fn stack_bottom(slot: usize) -> usize {
+        KERNEL_KSTACK_OFFSET
+            .saturating_add(slot.saturating_mul(SLOT_SIZE))
+            .saturating_add(PAGE_SIZE)
+    }
+
+    /// The slot whose guard page contains `addr`, if any
+    pub(super) fn guard_slot(addr: usize) -> Option<usize> {
+        let offset = addr.checked_sub(KERNEL_KSTACK_OFFSET)?;
+        let slot = offset / SLOT_SIZE;
+        (slot < SLOT_COUNT && offset % SLOT_SIZE < PAGE_SIZE).then_some(slot)
+    }
+
+    /// The slot whose stack contains `addr`, if any
+    pub(super) fn stack_slot(addr: usize) -> Option<usize> {
+        let offset = addr.checked_sub(KERNEL_KSTACK_OFFSET)?;
+        let slot = offset / SLOT_SIZE;
+        (slot < SLOT_COUNT && offset % SLOT_SIZE >= PAGE_SIZE).then_some(slot)
+    }
+
+    fn alloc_slot() -> Result<usize, Enomem> {
+        let mut slots = SLOTS.lock();
+        let slot = match slots.free.pop() {
+            Some(slot) => slot,
+            None if slots.next < SLOT_COUNT => {
+                let slot = slots.next;
+                slots.next = slot.saturating_add(1);
+                slot
+            }
+            None => return Err(Enomem),
+        };
+        slots.unmapped.push(slot);
+        Ok(slot)
+    }
+
+    fn free_slot(slot: usize) {
+        // Pushing may allocate, which must not happen with the mapper locked
+        let mut slots = SLOTS.lock();
+        slots.unmapped.retain(|&unmapped| unmapped != slot);
+        slots.free.push(slot);
+    }
+
+    /// Whether the stack pages of `slot` are mapped, or `false` if the slots are locked
+    pub(super) fn is_mapped(slot: usize) -> bool {
+        SLOTS.try_lock().is_some_and(|slots| {
+            slot < slots.next && !slots.free.contains(&slot) && !slots.unmapped.contains(&slot)
+        })
+    }
+
+    /// Map the stack pages of a new slot to the frames at `base`, leaving its guard page unmapped
+    pub(super) fn map(base: Frame) -> Result<usize, Enomem> {
+        let slot = alloc_slot()?;
+        let bottom = stack_bottom(slot);
+        let pages = (0..KSTACK_SIZE).step_by(PAGE_SIZE);
+
+        let mut mapper_lock = KernelMapper::lock();
+        let mapper = mapper_lock
+            .get_mut()
+            .expect("KernelMapper mapper locked re-entrant in Kstack::new");
+        for offset in pages.clone() {
+            let mapped = unsafe {
+                mapper.map_phys(
+                    VirtualAddress::new(bottom.saturating_add(offset)),
+                    PhysicalAddress::new(base.base().data().saturating_add(offset)),
+                    PageFlags::new().write(true),
+                )
+            };
+            match mapped {
+                Some(flush) => flush.flush(),
+                None => {
+                    // Nothing else has seen the pages yet, so no other CPU can have them cached
+                    for mapped in pages.take_while(|&mapped| mapped < offset) {
+                        let virt = VirtualAddress::new(bottom.saturating_add(mapped));
+                        if let Some((_, _, flush)) = unsafe { mapper.unmap_phys(virt, false) } {
+                            flush.flush();
+                        }
+                    }
+                    drop(mapper_lock);
+                    free_slot(slot);
+                    return Err(Enomem);
+                }
+            }
+        }
+        drop(mapper_lock);
+        SLOTS.lock().unmapped.retain(|&unmapped| unmapped != slot);
+        Ok(slot)
+    }
+
+    /// Unmap the stack pages of `slot` and `extra` kernel pages going away with it, and return
+    /// the slot once no CPU can have any of them cached
+    pub(super) fn unmap(slot: usize, extra: &[usize]) {
+        let bottom = stack_bottom(slot);
+        let pages = (0..KSTACK_SIZE)
+            .step_by(PAGE_SIZE)
+            .map(|offset| bottom.saturating_add(offset))
+            .chain(extra.iter().copied())
+            .collect::<Vec<_>>();
+        SLOTS.lock().unmapped.push(slot);
+
+        let mut mapper_lock = KernelMapper::lock();
+        let mapper = mapper_lock
+            .get_mut()
+            .expect("KernelMapper mapper locked re-entrant in Kstack::drop");
+        for &page in &pages {
+            if let Some((_, _, flush)) =
+                unsafe { mapper.unmap_phys(VirtualAddress::new(page), false) }
+            {
+                flush.flush();
+            }
+        }
+        drop(mapper_lock);
+
+        let current = crate::cpu_id();
+        for id in 0..crate::cpu_count() {
+            let cpu = crate::cpu_set::LogicalCpuId::new(id);
+            if cpu != current
+                && let Some(ticket) = crate::percpu::request_tlb_shootdown(cpu, Some(&pages))
+            {
+                crate::percpu::wait_tlb_shootdown(ticket);
+            }
+        }
+        free_slot(slot);
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::{region::*, *};
+    use crate::arch::consts::{KERNEL_HEAP_OFFSET, KERNEL_KSTACK_OFFSET, KERNEL_OFFSET};
+
+    #[test]
+    fn test_kstack_guard_slot() {
+        let second = stack_bottom(1);
+        assert_eq!(second, KERNEL_KSTACK_OFFSET + SLOT_SIZE + PAGE_SIZE);
+        assert_eq!(guard_slot(second - 1), Some(1));
+        assert_eq!(guard_slot(second - PAGE_SIZE), Some(1));
+        // The stack itself, and the top of the stack below the guard
+        assert_eq!(guard_slot(second), None);
+        assert_eq!(guard_slot(second - PAGE_SIZE - 8), None);
+        assert_eq!(guard_slot(KERNEL_KSTACK_OFFSET), Some(0));
+        assert_eq!(guard_slot(KERNEL_KSTACK_OFFSET - 8), None);
+        assert_eq!(guard_slot(stack_bottom(SLOT_COUNT) - PAGE_SIZE), None);
+
+        assert_eq!(stack_slot(second), Some(1));
+        assert_eq!(stack_slot(second - 1), None);
+        assert_eq!(stack_slot(second + KSTACK_SIZE), None);
+        // No slot has been handed out, so none of them holds a stack
+        assert!(!is_mapped(1));
+        assert_eq!(stack_containing(second + 8), None);
+    }
+
+    #[test]
+    fn test_kstack_region_layout() {
+        // Above the heap and below the kernel image, in a PML4 every address space shares
+        assert!(KERNEL_KSTACK_OFFSET > KERNEL_HEAP_OFFSET);
+        assert!(stack_bottom(SLOT_COUNT) <= KERNEL_OFFSET);
+        assert_eq!(
+            KERNEL_KSTACK_OFFSET & crate::arch::consts::PML4_MASK,
+            KERNEL_OFFSET & crate::arch::consts::PML4_MASK
+        );
+        // Every kstack slot has a shadow stack slot, below those of the CPUs
+        assert!(SLOT_COUNT <= crate::arch::cet::kstack_slots());
+    }
+}
@@ -0,0 +1,127 @@
+//! # Kernel Threads
+//!
+//! Contexts that run kernel code for their whole life, such as housekeeping loops and scheme
+//! daemons living in the kernel. They are spawned through [`spawn`], which registers them so
+//! that sys:context can tell them apart and so that they can be asked to stop.
+//!
+//! A kernel thread has no parent, so when it exits it would be reaped right away. As long as its
+//! [`Handle`] may still join it, it is kept as a zombie instead, and [`Handle::join`] reaps it.
+//! A thread nobody is going to join should be detached with [`Handle::detach`].
+
+use alloc::{collections::BTreeMap, sync::Arc};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::RwLock;
+
+use crate::{
+    context::{self, reap, ContextRef, SpawnOptions},
+    cpu_set::LogicalCpuSet,
+    percpu::PercpuBlock,
+    sync::{CleanLockToken, Priority},
+    syscall::error::Result,
+};
+
+/// Every kernel thread that has not been reaped yet, by context ID
+static KTHREADS: RwLock<BTreeMap<usize, Arc<Kthread>>> = RwLock::new(BTreeMap::new());
+
+struct Kthread {
+    entry: fn(),
+    /// Set by [`Handle::stop`], polled by the thread through [`should_stop`]
+    stop: AtomicBool,
+    /// Cleared once nothing is going to join the thread, so that it is reaped as it exits
+    joinable: AtomicBool,
+}
+
+/// Owner of a kernel thread, which can ask it to stop and wait for it to exit
+pub struct Handle {
+    context: ContextRef,
+    kthread: Arc<Kthread>,
+}
+
+/// Spawn a kernel thread named `name` that runs `entry` on the CPUs in `affinity`. The thread
+/// exits once `entry` returns.
+pub fn spawn(
+    name: &str,
+    affinity: LogicalCpuSet,
+    priority: Priority,
+    entry: fn(),
+    token: &mut CleanLockToken,
+) -> Result<Handle> {
+    let options = SpawnOptions {
+        affinity,
+        priority,
+        name,
+        ..SpawnOptions::default()
+    };
+    let context = context::spawn_with(options, trampoline, token)?;
+    let kthread = Arc::new(Kthread {
+        entry,
+        stop: AtomicBool::new(false),
+        joinable: AtomicBool::new(true),
+    });
+
+    // Spawned contexts are not runnable yet, so the trampoline always finds its entry
+    {
+        let mut context = context.write(token.token());
+        KTHREADS.write().insert(context.id(), Arc::clone(&kthread));
+        context.status = context::Status::Runnable;
+    }
+
+    Ok(Handle { context, kthread })
+}
+
+fn trampoline() {
+    let entry = current().map(|kthread| kthread.entry);
+    if let Some(entry) = entry {
+        entry();
+    }
+    let mut token = unsafe { CleanLockToken::new() };
+    crate::syscall::process::exit_this_context(0, None, &mut token);
+}
+
+fn current() -> Option<Arc<Kthread>> {
+    let id = PercpuBlock::current().context_id.get();
+    KTHREADS.read().get(&id).cloned()
+}
+
+/// Whether the current kernel thread has been asked to stop. Threads that run until stopped
+/// should poll this and return once it is set.
+pub fn should_stop() -> bool {
+    current().is_some_and(|kthread| kthread.stop.load(Ordering::Acquire))
+}
+
+/// Whether context `id` is a kernel thread
+pub fn is_kthread(id: usize) -> bool {
+    KTHREADS.read().contains_key(&id)
+}
+
+/// Whether context `id` is a kernel thread that must stay a zombie when it exits, for its
+/// handle to reap
+pub(super) fn is_joinable(id: usize) -> bool {
+    KTHREADS
+        .read()
+        .get(&id)
+        .is_some_and(|kthread| kthread.joinable.load(Ordering::Acquire))
+}
+
+/// Forget a reaped context, if it was a kernel thread
+pub(super) fn unregister(id: usize) {
+    KTHREADS.write().remove(&id);
+}
+
+impl Handle {
+    /// Ask the thread to stop, see [`should_stop`]
+    pub fn stop(&self) {
+        self.kthread.stop.store(true, Ordering::Release);
+    }
+
+    /// Wait for the thread to exit and reap it. Fails with `EINTR` if the wait is interrupted,
+    /// in which case it can be joined again.
+    pub fn join(&self, token: &mut CleanLockToken) -> Result<()> {
+        reap::join_kthread(&self.context, token)
+    }
+
+    /// Let the thread be reaped as soon as it exits, without anything waiting for it
+    pub fn detach(self, token: &mut CleanLockToken) {
+        reap::detach_kthread(&self.context, &self.kthread.joinable, token);
+    }
+}
@@ -1,20 +1,34 @@
 use crate::{
-    context::{context::Context, ContextLock, ContextRef},
+    context::{
+        context::Context,
+        name::{ContextName, CONTEXT_NAME_CAPAC},
+        ContextLock, ContextRef,
+    },
+    cpu_set::LogicalCpuSet,
     percpu::PercpuBlock,
-    scheduler,
+    scheduler::{self, SchedPolicy},
     scheme::SchemeNamespace,
-    sync::CleanLockToken,
+    sync::{CleanLockToken, Priority},
     syscall::error::Result,
 };
 use alloc::{collections::BTreeMap, sync::Arc};
+use arrayvec::ArrayString;
+use core::num::NonZeroUsize;
 use spin::RwLock;
 
 /// The maximum number of files that can be open in a context
 pub const CONTEXT_MAX_FILES: usize = 65536;
+/// The soft limit on open files a context starts out with
+pub const CONTEXT_DEFAULT_FILES: usize = 1024;
+/// The maximum number of files that can be open in all file tables together
+pub const MAX_OPEN_FILES: usize = 262144;
 
 /// Contexts list
 pub static CONTEXTS: RwLock<BTreeMap<usize, Arc<ContextLock>>> = RwLock::new(BTreeMap::new());
 
+/// The name of every context in [`CONTEXTS`], shared with the context itself
+static NAMES: RwLock<BTreeMap<usize, Arc<ContextName>>> = RwLock::new(BTreeMap::new());
+
 pub fn init() {
     // Initialize contexts if needed
 }
@@ -23,6 +37,18 @@ pub fn contexts() -> &'static RwLock<BTreeMap<usize, Arc<ContextLock>>> {
     &CONTEXTS
 }
 
+pub fn names() -> &'static RwLock<BTreeMap<usize, Arc<ContextName>>> {
+    &NAMES
+}
+
+/// The name of the context with ID `id`, read without locking the context
+///
+/// Returns None if there is no such context, or if the list is being changed, as this is meant
+/// for diagnostics that must not wait on a CPU that might be stuck.
+pub fn name_of(id: usize) -> Option<ArrayString<CONTEXT_NAME_CAPAC>> {
+    NAMES.try_read()?.get(&id).map(|name| name.get())
+}
+
 pub fn current() -> Arc<ContextLock> {
     let context_id = PercpuBlock::current().context_id.get();
     let contexts = contexts().read();
@@ -33,24 +59,80 @@ pub fn current() -> Arc<ContextLock> {
     )
 }
 
+/// How a new context is set up before it is first enqueued, see [`spawn_with`]
+pub struct SpawnOptions<'a> {
+    pub userspace: bool,
+    pub owner_proc_id: Option<NonZeroUsize>,
+    /// CPUs the context may run on, which also decides the run queue it starts on
+    pub affinity: LogicalCpuSet,
+    /// Whether the context is a hard real-time task
+    pub realtime: bool,
+    pub priority: Priority,
+    /// Truncated to the context name capacity
+    pub name: &'a str,
+}
+
+impl Default for SpawnOptions<'_> {
+    fn default() -> Self {
+        Self {
+            userspace: false,
+            owner_proc_id: None,
+            affinity: LogicalCpuSet::all(),
+            realtime: false,
+            priority: Priority::Normal,
+            name: "",
+        }
+    }
+}
+
 /// Spawn a new context
 pub fn spawn(
     userspace: bool,
-    owner_proc_id: Option<core::num::NonZeroUsize>,
+    owner_proc_id: Option<NonZeroUsize>,
     call: fn(),
     token: &mut CleanLockToken,
 ) -> Result<ContextRef> {
-    let context_ref = Arc::new(ContextLock::new(Context::new(owner_proc_id)?));
-    let context_id = {
+    spawn_with(
+        SpawnOptions {
+            userspace,
+            owner_proc_id,
+            ..SpawnOptions::default()
+        },
+        call,
+        token,
+    )
+}
+
+/// Spawn a new context with its affinity, scheduling class and name set up front, so that it
+/// starts out on a CPU it is allowed to run on
+pub fn spawn_with(
+    options: SpawnOptions,
+    call: fn(),
+    token: &mut CleanLockToken,
+) -> Result<ContextRef> {
+    let context_ref = Arc::new(ContextLock::new(Context::new(options.owner_proc_id)?));
+    let (context_id, name) = {
         let mut context = context_ref.write(token.token());
-        context.userspace = userspace;
+        context.userspace = options.userspace;
+        context.sched_affinity = options.affinity;
+        context.priority.set_base_priority(options.priority);
+        context.set_realtime(options.realtime);
+        context.sched_policy = if options.realtime {
+            SchedPolicy::Fifo
+        } else {
+            SchedPolicy::Normal
+        };
+
+        context.set_name(options.name);
+
         context.set_entry_point(unsafe { core::mem::transmute(call) })?;
-        context.id()
+        (context.id(), Arc::clone(&context.name))
     };
 
     {
         let mut contexts = contexts().write();
         contexts.insert(context_id, Arc::clone(&context_ref));
+        NAMES.write().insert(context_id, name);
     }
 
     scheduler::add_context(context_ref.clone(), token);
@@ -1,25 +1,50 @@
 //! # Virtual Memory Management for Contexts
 
-use alloc::{sync::Arc, vec::Vec};
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::num::NonZeroUsize;
 use spin::RwLock;
 
 use crate::{
-    context::file::FileDescription,
-    memory::{self, Enomem, Frame, RaiiFrame},
-    arch::paging::{Page, PageFlags, RmmA, VirtualAddress, PAGE_SIZE},
+    context::{file::FileDescription, ContextLock},
+    memory::{self, AllocationFlags, Enomem, Frame, RaiiFrame, RefCount},
+    arch::paging::{Page, PageFlags, RmmA, RmmArch, VirtualAddress, PAGE_SIZE},
+    paging::mapper::{self, HUGE_PAGE_COUNT, HUGE_PAGE_SIZE},
     sync::CleanLockToken,
     syscall::{
         self,
         error::{Error, Result as SysResult},
-        flag::MapFlags,
+        flag::{MapFlags, MunmapFlags},
+        MAP_HUGE,
     },
 };
 use alloc::collections::BTreeMap;
 
+/// Buddy allocator order of a huge page
+const HUGE_PAGE_ORDER: u32 = HUGE_PAGE_COUNT.trailing_zeros();
+
+/// Largest size a user stack may grow to by faulting on its guard page, unless changed per context
+pub const DEFAULT_STACK_LIMIT: usize = 8 * 1024 * 1024;
+
+/// Bytes a user other than root may lock with mlock, unless changed per context
+pub const DEFAULT_MEMLOCK_LIMIT: usize = 64 * 1024;
+
+/// Pages locked by mlock in all address spaces, by the user charged for them
+static LOCKED_PAGES: spin::Mutex<LockedPages> = spin::Mutex::new(LockedPages(BTreeMap::new()));
+
+/// How far below the stack pointer a fault on a guard page still grows the stack
+///
+/// Anything further down is a stray access rather than a push or stack frame being set up.
+pub const STACK_GROWTH_WINDOW: usize = 64 * 1024;
+
+/// Longest label a grant can carry, in bytes
+pub const GRANT_NAME_MAX: usize = 32;
+
 #[derive(Debug)]
 pub enum PfError {
     Oom,
     Segv,
+    /// The stack ran into its guard page and could not grow any further
+    StackOverflow,
     RecursionLimitExceeded,
     NonfatalInternalError,
 }
@@ -37,14 +62,45 @@ pub enum AccessMode {
     InstrFetch,
 }
 
+/// Translate the protection bits of `MapFlags` into user page table flags
+///
+/// Pages cannot be writable or executable without being readable, so only PROT_NONE takes away
+/// read access, by making the pages inaccessible from userspace altogether.
+pub fn page_flags(map_flags: MapFlags) -> PageFlags<RmmA> {
+    let prot = MapFlags::PROT_READ | MapFlags::PROT_WRITE | MapFlags::PROT_EXEC;
+    PageFlags::new()
+        .user(map_flags.intersects(prot))
+        .write(map_flags.contains(MapFlags::PROT_WRITE))
+        .execute(map_flags.contains(MapFlags::PROT_EXEC))
+}
+
 #[derive(Debug)]
 pub struct Grant {
     start: Page,
     end: Page,
     flags: PageFlags<RmmA>,
+    /// Flags the grant was created with, bounding what mprotect may later add back
+    max_flags: PageFlags<RmmA>,
+    /// Frame backing the first page of the grant, if it was faulted in eagerly
     phys: Option<RaiiFrame>,
     pub provider: Provider,
     pub locked: bool, // Added field for memory locking
+    /// Mapped with huge pages, each backed by an order 9 allocation
+    huge: bool,
+    stack: StackRole,
+    /// Label shown in grant listings, set by the kernel for mappings it creates or by userspace
+    name: Option<Box<str>>,
+}
+
+/// Part a grant plays in a user stack
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StackRole {
+    None,
+    /// Committed stack pages, grown downwards over the guard page below them
+    Stack,
+    /// Page kept unmapped below a stack, so an overflow faults instead of running into whatever
+    /// is mapped next
+    Guard,
 }
 
 impl Grant {
@@ -53,23 +109,115 @@ impl Grant {
             start,
             end,
             flags,
+            max_flags: flags,
             phys: None,
             provider: Provider::Allocated { flags },
             locked: false,
+            huge: false,
+            stack: StackRole::None,
+            name: None,
+        }
+    }
+
+    /// The same grant labelled `name` in grant listings
+    pub fn named(mut self, name: &str) -> Self {
+        debug_assert!(name.len() <= GRANT_NAME_MAX);
+        self.name = Some(name.into());
+        self
+    }
+
+    /// The guard page below a user stack, never mapped
+    fn guard(page: Page) -> Self {
+        let mut grant = Grant::new(page, page.next(), page_flags(MapFlags::empty()));
+        grant.stack = StackRole::Guard;
+        grant
+    }
+
+    /// Split off the pages from `at` onwards into a new grant, leaving [start, at) in `self`.
+    ///
+    /// `at` must lie strictly inside the grant.
+    fn split_off(&mut self, at: Page) -> Grant {
+        assert!(self.start < at && at < self.end);
+        let offset = at.offset_from(self.start);
+        let tail = Grant {
+            start: at,
+            end: self.end,
+            flags: self.flags,
+            max_flags: self.max_flags,
+            // Backs the first page, so it always stays with the head
+            phys: None,
+            provider: self.provider.split_at(offset),
+            locked: self.locked,
+            huge: self.huge,
+            stack: self.stack,
+            name: self.name.clone(),
+        };
+        self.end = at;
+        tail
+    }
+
+    /// The same grant in a forked address space, backed by `provider`
+    ///
+    /// mlock is not inherited, and the frame held in `phys` stays with the original grant.
+    fn fork_with(&self, provider: Provider) -> Grant {
+        Grant {
+            start: self.start,
+            end: self.end,
+            flags: self.flags,
+            max_flags: self.max_flags,
+            phys: None,
+            provider,
+            locked: false,
+            huge: self.huge,
+            stack: self.stack,
+            name: self.name.clone(),
         }
     }
 
+    /// Returns true if the grant maps memory owned by someone else
+    fn is_borrowed(&self) -> bool {
+        matches!(
+            self.provider,
+            Provider::PhysBorrowed { .. }
+                | Provider::FmapBorrowed { .. }
+                | Provider::IpcBuffer { .. }
+        )
+    }
+
+    pub fn is_huge(&self) -> bool {
+        self.huge
+    }
+
+    /// Whether mlock would newly lock this grant. Guard pages are never mapped, so they are
+    /// skipped.
+    fn lockable(&self) -> bool {
+        !self.locked && self.stack != StackRole::Guard
+    }
+
+    pub fn stack_role(&self) -> StackRole {
+        self.stack
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
     pub fn phys(&self) -> Option<Frame> {
         self.phys.as_ref().map(|f| f.get())
     }
     pub fn grant_flags(&self) -> MapFlags {
         // TODO: reconstruct MapFlags from PageFlags
         let mut flags = MapFlags::empty();
+        if !self.flags.has_flag(RmmA::ENTRY_FLAG_USER) {
+            return flags;
+        }
         if self.flags.has_write() {
             flags |= MapFlags::PROT_WRITE;
         }
-        // if self.flags.has_execute() { flags |= MapFlags::PROT_EXEC; } // PageFlags might not have has_execute depending on arch
-        flags |= MapFlags::PROT_READ; // Always readable?
+        if self.flags.has_execute() {
+            flags |= MapFlags::PROT_EXEC;
+        }
+        flags |= MapFlags::PROT_READ; // Always readable unless PROT_NONE
         flags
     }
     pub fn file_ref(&self) -> Option<&GrantFileRef> {
@@ -79,6 +227,15 @@ impl Grant {
         }
     }
 
+    /// The notification owed to the provider of a file-backed grant once it is unmapped
+    pub fn unmap_result(&self, flags: MunmapFlags) -> Option<UnmapResult> {
+        Some(UnmapResult {
+            file_ref: self.file_ref()?.clone(),
+            size: self.page_count() * PAGE_SIZE,
+            flags,
+        })
+    }
+
     pub fn start_address(&self) -> VirtualAddress {
         self.start.start_address()
     }
@@ -92,6 +249,76 @@ impl Grant {
         Err(Error::new(crate::syscall::error::ENOMEM))
     }
 
+    /// Back `span` with zeroed huge pages, each mapped by a single level 1 entry.
+    ///
+    /// `span` must be aligned to and sized in whole huge pages. Empty tables of small pages left
+    /// in `span` by earlier mappings are freed first. There is no fallback to small pages: if no
+    /// free 2 MiB block is left, whatever was mapped is undone and ENOMEM returned.
+    pub fn zeroed_huge(
+        span: PageSpan,
+        flags: PageFlags<RmmA>,
+        table: &mut UTableWrapper,
+        flusher: &mut TlbShootdownActions,
+    ) -> SysResult<Self> {
+        debug_assert!(span.base.start_address().data() % HUGE_PAGE_SIZE == 0);
+        debug_assert!(span.count % HUGE_PAGE_COUNT == 0);
+
+        let mut tables = Vec::new();
+        for page in (0..span.count)
+            .step_by(HUGE_PAGE_COUNT)
+            .map(|i| span.base.next_by(i))
+        {
+            let Some((frame, flush)) =
+                (unsafe { mapper::unmap_empty_table(table.table(), page.start_address()) })
+            else {
+                continue;
+            };
+            flush.ignore();
+            flusher.queue(frame, Some(page), TlbShootdownActions::FREE);
+            tables.push(frame);
+        }
+        if !tables.is_empty() {
+            flusher.flush();
+            for frame in tables {
+                unsafe { memory::deallocate_frame(frame) };
+            }
+        }
+
+        for offset in (0..span.count).step_by(HUGE_PAGE_COUNT) {
+            let page = span.base.next_by(offset);
+            let mapped = memory::allocate_p2frame_complex(
+                HUGE_PAGE_ORDER,
+                AllocationFlags::ZEROED,
+                None,
+                HUGE_PAGE_ORDER,
+                None,
+            )
+            .and_then(|(frame, _)| {
+                let flush = unsafe {
+                    mapper::map_huge(table.table(), page.start_address(), frame.base(), flags)
+                };
+                if flush.is_none() {
+                    unsafe { memory::deallocate_p2frame(frame, HUGE_PAGE_ORDER) };
+                }
+                Some((frame, flush?))
+            });
+            let Some((frame, flush)) = mapped else {
+                let frames = unmap_huge_pages(table, PageSpan::new(span.base, offset), flusher);
+                flusher.flush();
+                for frame in frames {
+                    unsafe { memory::deallocate_p2frame(frame, HUGE_PAGE_ORDER) };
+                }
+                return Err(Error::new(syscall::error::ENOMEM));
+            };
+            flush.ignore();
+            flusher.queue(frame, Some(page), TlbShootdownActions::NEW_MAPPING);
+        }
+
+        let mut grant = Grant::new(span.base, span.base.next_by(span.count), flags);
+        grant.huge = true;
+        Ok(grant)
+    }
+
     pub fn zeroed(
         _span: PageSpan,
         _flags: PageFlags<RmmA>,
@@ -189,14 +416,64 @@ impl Grant {
     }
 }
 
+/// Fix up the page tables after a user page fault on `faulting_page`, if it was legitimate.
+///
+/// `stack_pointer` is the user stack pointer at the time of the fault, if it came from
+/// userspace. A fault on a stack's guard page close enough below it grows the stack, up to the
+/// context's stack limit.
 pub fn try_correcting_page_tables(
     faulting_page: Page,
-    _access: AccessMode,
+    access: AccessMode,
+    stack_pointer: Option<usize>,
     token: &mut CleanLockToken,
 ) -> Result<(), PfError> {
     let current_context_ref = crate::context::current();
     let current_context_guard = current_context_ref.read(token.token());
 
+    if let Some(addr_space) = current_context_guard.addr_space.as_ref() {
+        let mut inner = addr_space.inner.write();
+        let role = inner
+            .grants_in(faulting_page, faulting_page.next())
+            .next()
+            .map(|grant| (grant.is_huge(), grant.stack_role()));
+        let present = inner
+            .table
+            .utable
+            .translate(faulting_page.start_address())
+            .is_some();
+        match role {
+            // Huge grants are populated eagerly and never shared copy-on-write, so a fault inside
+            // one is a genuine access violation. Carrying on would map a small page into it.
+            Some((true, _)) => return Err(PfError::Segv),
+            Some((false, StackRole::Guard)) => {
+                let address = faulting_page.start_address().data();
+                let near_stack_pointer = stack_pointer
+                    .is_some_and(|sp| address.saturating_add(STACK_GROWTH_WINDOW) >= sp);
+                if !near_stack_pointer {
+                    return Err(PfError::Segv);
+                }
+                let grown = inner.grow_stack(faulting_page, current_context_guard.stack_limit);
+                if let Err(PfError::StackOverflow) = grown {
+                    info!(
+                        "STACK OVERFLOW, PID {}, NAME {}, fault at {:#x}, stack pointer {:#x}, \
+                         limit {} KiB",
+                        current_context_guard.pid,
+                        current_context_guard.name,
+                        address,
+                        stack_pointer.unwrap_or(0),
+                        current_context_guard.stack_limit / 1024
+                    );
+                }
+                return grown;
+            }
+            // A write to a present page can only be legitimate if it is shared copy-on-write
+            Some((false, _)) if present && access == AccessMode::Write => {
+                return inner.break_cow(faulting_page);
+            }
+            _ => (),
+        }
+    }
+
     if current_context_guard.memory_locked_count > 0 {
         if let Some(addr_space) = current_context_guard.addr_space.as_ref() {
             let mut inner = addr_space.inner.write();
@@ -227,8 +504,117 @@ pub fn try_correcting_page_tables(
     Err(PfError::Segv)
 }
 
+/// Unmap the huge pages in `span`, returning the frames that backed them
+fn unmap_huge_pages(
+    table: &mut UTableWrapper,
+    span: PageSpan,
+    flusher: &mut TlbShootdownActions,
+) -> Vec<Frame> {
+    let mut frames = Vec::new();
+    for page in (0..span.count)
+        .step_by(HUGE_PAGE_COUNT)
+        .map(|i| span.base.next_by(i))
+    {
+        let Some((phys, _, flush)) =
+            (unsafe { mapper::unmap_huge(table.table(), page.start_address()) })
+        else {
+            continue;
+        };
+        flush.ignore();
+        let frame = Frame::containing(phys);
+        flusher.queue(frame, Some(page), TlbShootdownActions::FREE);
+        frames.push(frame);
+    }
+    frames
+}
+
+/// Returns true if `page` maps a frame whose users are counted, rather than device memory
+fn is_counted(table: &Table, page: Page) -> bool {
+    table
+        .utable
+        .0
+        .translate(page.start_address())
+        .and_then(|(phys, _)| memory::get_page_info(Frame::containing(phys)))
+        .is_some_and(|info| info.refcount().is_some())
+}
+
+/// Copy `size` bytes from the frames starting at `src` to those starting at `dst`
+unsafe fn copy_frames(src: Frame, dst: Frame, size: usize) {
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            RmmA::phys_to_virt(src.base()).data() as *const u8,
+            RmmA::phys_to_virt(dst.base()).data() as *mut u8,
+            size,
+        );
+    }
+}
+
 // --- Added missing types ---
 
+/// The user charged for locking pages, and how much memory they may have locked
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LockLimit {
+    pub uid: u32,
+    /// Bytes the user may have locked across all address spaces, `None` if unlimited
+    pub bytes: Option<usize>,
+}
+
+/// Pages of an address space locked by mlock, and the user they are charged to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct LockCharge {
+    uid: u32,
+    pages: usize,
+}
+
+/// Total pages charged to each user with a nonzero charge
+struct LockedPages(BTreeMap<u32, usize>);
+
+impl LockedPages {
+    /// Replace the charge `old` with one of `pages` to `limit.uid`, leaving `old` in place if
+    /// that takes the user past their limit. That fails with EPERM if the user may not lock
+    /// anything, and with ENOMEM otherwise, as mlock does.
+    fn recharge(
+        &mut self,
+        old: &mut Option<LockCharge>,
+        limit: LockLimit,
+        pages: usize,
+    ) -> SysResult<()> {
+        let uid = limit.uid;
+        let total = self.0.get(&uid).copied().unwrap_or(0);
+        let refund = old.filter(|old| old.uid == uid).map_or(0, |old| old.pages);
+        let new_total = total.saturating_sub(refund).saturating_add(pages);
+        // Giving back pages is always allowed, even past a limit lowered since they were locked
+        if pages > refund {
+            match limit.bytes {
+                Some(0) => return Err(Error::new(syscall::error::EPERM)),
+                Some(bytes) if new_total.saturating_mul(PAGE_SIZE) > bytes => {
+                    return Err(Error::new(syscall::error::ENOMEM));
+                }
+                _ => (),
+            }
+        }
+
+        self.uncharge(old);
+        if pages > 0 {
+            let total = self.0.entry(uid).or_insert(0);
+            *total = total.saturating_add(pages);
+            *old = Some(LockCharge { uid, pages });
+        }
+        Ok(())
+    }
+
+    /// Give back the charge `old`
+    fn uncharge(&mut self, old: &mut Option<LockCharge>) {
+        if let Some(old) = old.take() {
+            let total = self.0.entry(old.uid).or_insert(0);
+            *total = total.saturating_sub(old.pages);
+            if *total == 0 {
+                self.0.remove(&old.uid);
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct AddrSpaceWrapper {
     pub inner: RwLock<AddrSpaceInner>,
@@ -240,9 +626,18 @@ pub type AddrSpace = AddrSpaceWrapper;
 pub struct AddrSpaceInner {
     pub used_by: crate::cpu_set::LogicalCpuSet,
     pub table: TableWrapper,
-    pub tlb_ack: core::sync::atomic::AtomicUsize,
     pub grants: BTreeMap<Page, Grant>,
     pub mmap_min: usize,
+    /// Locked pages as charged to their user, see [`LockedPages`]
+    lock_charge: Option<LockCharge>,
+    /// Set by mlockall with MCL_FUTURE, so that new mappings are locked as well
+    lock_future: Option<LockLimit>,
+}
+
+impl Drop for AddrSpaceInner {
+    fn drop(&mut self) {
+        LOCKED_PAGES.lock().uncharge(&mut self.lock_charge);
+    }
 }
 
 #[derive(Debug)]
@@ -285,19 +680,34 @@ impl AddrSpaceWrapper {
                         .ok_or(Error::new(crate::syscall::error::ENOMEM))?
                     }),
                 },
-                tlb_ack: core::sync::atomic::AtomicUsize::new(0),
                 grants: BTreeMap::new(),
                 mmap_min: PAGE_SIZE,
+                lock_charge: None,
+                lock_future: None,
             }),
         }))
     }
 
+    // The holder of the lock may be flushing the TLB and waiting for this CPU to acknowledge, so
+    // shootdowns are handled while spinning.
     pub fn acquire_read(&self) -> spin::RwLockReadGuard<AddrSpaceInner> {
-        self.inner.read()
+        loop {
+            if let Some(guard) = self.inner.try_read() {
+                return guard;
+            }
+            crate::percpu::PercpuBlock::current().maybe_handle_tlb_shootdown();
+            core::hint::spin_loop();
+        }
     }
 
     pub fn acquire_write(&self) -> spin::RwLockWriteGuard<AddrSpaceInner> {
-        self.inner.write()
+        loop {
+            if let Some(guard) = self.inner.try_write() {
+                return guard;
+            }
+            crate::percpu::PercpuBlock::current().maybe_handle_tlb_shootdown();
+            core::hint::spin_loop();
+        }
     }
 
     pub fn current(token: &mut CleanLockToken) -> SysResult<Arc<Self>> {
@@ -308,12 +718,68 @@ impl AddrSpaceWrapper {
             .ok_or(Error::new(crate::syscall::error::ESRCH))
     }
 
+    /// Create a copy of this address space for a forked child
+    ///
+    /// Private memory is shared copy-on-write, mapped read-only on both sides until a write
+    /// faults in a copy of its own, see `try_correcting_page_tables`. Borrowed device memory is
+    /// shared as is and borrowed RAM takes a reference per page, while huge pages and file
+    /// mappings are copied up front. Both address spaces
+    /// stay locked throughout, so nobody sees either one half cloned, nor its usage torn.
     pub fn try_clone(&self) -> SysResult<Arc<Self>> {
-        Self::new()
+        let new = Self::new()?;
+        {
+            let mut parent = self.acquire_write();
+            let mut child = new.acquire_write();
+            child.mmap_min = parent.mmap_min;
+
+            if let Err(err) = parent.fork_into(&mut child) {
+                // Dropping the child's references leaves the parent's pages exclusive again, so
+                // they simply become writable on the next write fault
+                let spans: Vec<PageSpan> = child
+                    .grants
+                    .values()
+                    .map(|grant| PageSpan::new(grant.start, grant.page_count()))
+                    .collect();
+                for span in spans {
+                    let _ = child.munmap(span, false);
+                }
+                return Err(err);
+            }
+        }
+        Ok(new)
     }
 
-    pub fn munmap(&self, _span: PageSpan, _unpin: bool) -> SysResult<Vec<Grant>> {
-        Ok(Vec::new())
+    pub fn munmap(&self, span: PageSpan, unpin: bool) -> SysResult<Vec<Grant>> {
+        self.acquire_write().munmap(span, unpin)
+    }
+
+    /// Unmap the pages in `span` as [`AddrSpaceInner::munmap_counted`] does, and take the locked
+    /// pages among them off the count of `owner`, the context the address space belongs to
+    pub fn munmap_for(
+        &self,
+        span: PageSpan,
+        unpin: bool,
+        owner: &ContextLock,
+        token: &mut CleanLockToken,
+    ) -> SysResult<Vec<Grant>> {
+        let (removed, unlocked_pages) = self.acquire_write().munmap_counted(span, unpin)?;
+        if unlocked_pages > 0 {
+            let mut owner = owner.write(token.token());
+            owner.memory_locked_count = owner.memory_locked_count.saturating_sub(unlocked_pages);
+        }
+        Ok(removed)
+    }
+
+    /// Notifications for the providers of every file-backed grant, as this address space is
+    /// going away with its last user
+    ///
+    /// No flags are set, so providers know they may skip writing back what the process left.
+    pub fn teardown_notifications(&self) -> Vec<UnmapResult> {
+        self.acquire_read()
+            .grants
+            .values()
+            .filter_map(|grant| grant.unmap_result(MunmapFlags::empty()))
+            .collect()
     }
 }
 
@@ -325,32 +791,868 @@ impl AddrSpaceInner {
     ) -> SysResult<RaiiFrame> {
         Err(Error::new(crate::syscall::error::ENOMEM))
     }
-    pub fn mprotect(&mut self, _base: Page, _count: usize, _flags: MapFlags) -> SysResult<()> {
-        Err(Error::new(crate::syscall::error::ENOSYS))
+
+    /// Clone every grant into the empty address space `child`, see `AddrSpaceWrapper::try_clone`
+    ///
+    /// On failure, `child` keeps whatever was cloned so far, which the caller has to unmap.
+    fn fork_into(&mut self, child: &mut AddrSpaceInner) -> SysResult<()> {
+        let mut flusher = TlbShootdownActions::new(self.used_by);
+        let result = self.fork_grants_into(child, &mut flusher);
+        // The parent must stop writing to pages it now shares before the child can run
+        flusher.flush();
+        result
     }
-    pub fn munmap(&mut self, _span: PageSpan, _unpin: bool) -> SysResult<Vec<Grant>> {
-        Ok(Vec::new())
+
+    fn fork_grants_into(
+        &mut self,
+        child: &mut AddrSpaceInner,
+        flusher: &mut TlbShootdownActions,
+    ) -> SysResult<()> {
+        let Self { grants, table, .. } = self;
+        for grant in grants.values() {
+            let pages = (0..grant.page_count()).map(|i| grant.start.next_by(i));
+            match grant.provider {
+                Provider::Allocated { flags } if grant.huge => {
+                    child
+                        .grants
+                        .insert(grant.start, grant.fork_with(Provider::Allocated { flags }));
+                    for page in pages.step_by(HUGE_PAGE_COUNT) {
+                        let Some((phys, page_flags)) = (unsafe {
+                            mapper::translate_huge(table.utable.table(), page.start_address())
+                        }) else {
+                            continue;
+                        };
+                        child.map_huge_copy(page, Frame::containing(phys), page_flags)?;
+                    }
+                }
+                Provider::Allocated { flags } => {
+                    child
+                        .grants
+                        .insert(grant.start, grant.fork_with(Provider::Allocated { flags }));
+                    for page in pages {
+                        let Some((phys, page_flags)) =
+                            table.utable.0.translate(page.start_address())
+                        else {
+                            continue;
+                        };
+                        let frame = Frame::containing(phys);
+                        let info = memory::get_page_info(frame);
+
+                        // The grant releases its own frame when dropped, so the child gets a copy
+                        let Some(info) = info.filter(|_| Some(frame) != grant.phys()) else {
+                            child.map_copy(page, frame, page_flags)?;
+                            continue;
+                        };
+                        if let Some(RefCount::Shared(_)) = info.refcount() {
+                            info.add_ref(memory::RefKind::Shared)
+                                .map_err(|_| Error::new(syscall::error::ENOMEM))?;
+                            child.map_frame(page, frame, page_flags).inspect_err(|_| {
+                                let _ = info.remove_ref();
+                            })?;
+                            continue;
+                        }
+
+                        info.add_ref(memory::RefKind::Cow)
+                            .map_err(|_| Error::new(syscall::error::ENOMEM))?;
+                        let read_only = page_flags.write(false);
+                        child.map_frame(page, frame, read_only).inspect_err(|_| {
+                            let _ = info.remove_ref();
+                        })?;
+                        if page_flags.has_write() {
+                            if let Some(flush) =
+                                unsafe { table.utable.0.remap(page.start_address(), read_only) }
+                            {
+                                flush.ignore();
+                                flusher.queue(
+                                    frame,
+                                    Some(page),
+                                    TlbShootdownActions::CHANGE_PROTECTION,
+                                );
+                            }
+                        }
+                    }
+                }
+                // Nobody counts mappings of device memory, so it is shared as is
+                Provider::PhysBorrowed { .. }
+                    if !pages.clone().any(|page| is_counted(table, page)) =>
+                {
+                    let provider = grant.provider.split_at(0);
+                    child.grants.insert(grant.start, grant.fork_with(provider));
+                    for page in pages {
+                        let Some((phys, page_flags)) =
+                            table.utable.0.translate(page.start_address())
+                        else {
+                            continue;
+                        };
+                        child.map_frame(page, Frame::containing(phys), page_flags)?;
+                    }
+                }
+                // Borrowed RAM may be freed by its owner while the child still maps it, so the
+                // child holds a reference of its own, which its allocated grant drops on munmap
+                Provider::PhysBorrowed { .. } => {
+                    let provider = Provider::Allocated { flags: grant.flags };
+                    child.grants.insert(grant.start, grant.fork_with(provider));
+                    for page in pages {
+                        let Some((phys, page_flags)) =
+                            table.utable.0.translate(page.start_address())
+                        else {
+                            continue;
+                        };
+                        let frame = Frame::containing(phys);
+                        let shared = memory::get_page_info(frame)
+                            .filter(|info| info.refcount().is_some())
+                            .filter(|info| info.add_ref(memory::RefKind::Shared).is_ok());
+                        let Some(info) = shared else {
+                            child.map_copy(page, frame, page_flags)?;
+                            continue;
+                        };
+                        child.map_frame(page, frame, page_flags).inspect_err(|_| {
+                            let _ = info.remove_ref();
+                        })?;
+                    }
+                }
+                Provider::IpcBuffer { ref buffer } => {
+                    let provider = Provider::IpcBuffer {
+                        buffer: buffer.clone(),
+                    };
+                    child.grants.insert(grant.start, grant.fork_with(provider));
+                    for page in pages {
+                        let Some((phys, page_flags)) =
+                            table.utable.0.translate(page.start_address())
+                        else {
+                            continue;
+                        };
+                        child.map_frame(page, Frame::containing(phys), page_flags)?;
+                    }
+                }
+                // The provider only knows about the parent's mapping, so the child gets a private
+                // copy rather than a mapping it would have to be told about
+                Provider::FmapBorrowed { .. } => {
+                    let provider = Provider::Allocated { flags: grant.flags };
+                    child.grants.insert(grant.start, grant.fork_with(provider));
+                    for page in pages {
+                        let Some((phys, page_flags)) =
+                            table.utable.0.translate(page.start_address())
+                        else {
+                            continue;
+                        };
+                        child.map_copy(page, Frame::containing(phys), page_flags)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Map `frame` at `page` of an address space that is not in use yet, so nothing to flush
+    fn map_frame(&mut self, page: Page, frame: Frame, flags: PageFlags<RmmA>) -> SysResult<()> {
+        let flush = unsafe {
+            self.table
+                .utable
+                .0
+                .map_phys(page.start_address(), frame.base(), flags)
+        }
+        .ok_or(Error::new(syscall::error::ENOMEM))?;
+        flush.ignore();
+        Ok(())
     }
 
+    /// Map a private copy of `frame` at `page` of an address space that is not in use yet
+    fn map_copy(&mut self, page: Page, frame: Frame, flags: PageFlags<RmmA>) -> SysResult<()> {
+        let copy =
+            memory::init_frame(RefCount::One).map_err(|_| Error::new(syscall::error::ENOMEM))?;
+        unsafe { copy_frames(frame, copy, PAGE_SIZE) };
+        self.map_frame(page, copy, flags)
+            .inspect_err(|_| unsafe { memory::deallocate_frame(copy) })
+    }
+
+    /// Map a private copy of the huge frame `frame` at `page` of an address space that is not in
+    /// use yet
+    fn map_huge_copy(&mut self, page: Page, frame: Frame, flags: PageFlags<RmmA>) -> SysResult<()> {
+        let (copy, _) = memory::allocate_p2frame_complex(
+            HUGE_PAGE_ORDER,
+            AllocationFlags::NONE,
+            None,
+            HUGE_PAGE_ORDER,
+            None,
+        )
+        .ok_or(Error::new(syscall::error::ENOMEM))?;
+        unsafe { copy_frames(frame, copy, HUGE_PAGE_SIZE) };
+        let flush = unsafe {
+            mapper::map_huge(
+                self.table.utable.table(),
+                page.start_address(),
+                copy.base(),
+                flags,
+            )
+        };
+        let Some(flush) = flush else {
+            unsafe { memory::deallocate_p2frame(copy, HUGE_PAGE_ORDER) };
+            return Err(Error::new(syscall::error::ENOMEM));
+        };
+        flush.ignore();
+        Ok(())
+    }
+
+    /// Give the present, read-only `page` a frame of its own after a write to it faulted.
+    ///
+    /// Only private memory shared copy-on-write by a fork is mapped like that in a writable
+    /// grant. Whoever is last to write to a shared frame takes it over without copying.
+    fn break_cow(&mut self, page: Page) -> Result<(), PfError> {
+        let Some(grant) = self.grants_in(page, page.next()).next() else {
+            return Err(PfError::Segv);
+        };
+        if !matches!(grant.provider, Provider::Allocated { .. }) || !grant.flags.has_write() {
+            return Err(PfError::Segv);
+        }
+        let flags = grant.flags;
+
+        let (phys, mapped_flags) = self
+            .table
+            .utable
+            .0
+            .translate(page.start_address())
+            .ok_or(PfError::Segv)?;
+        if mapped_flags.has_write() {
+            // Another CPU's write got here first
+            return Ok(());
+        }
+        let old = Frame::containing(phys);
+        let info = memory::get_page_info(old).ok_or(PfError::Segv)?;
+
+        let mut flusher = TlbShootdownActions::new(self.used_by);
+        match info.refcount() {
+            Some(RefCount::One) => {
+                let flush = unsafe { self.table.utable.0.remap(page.start_address(), flags) }
+                    .ok_or(PfError::NonfatalInternalError)?;
+                flush.ignore();
+                flusher.queue(old, Some(page), TlbShootdownActions::CHANGE_PROTECTION);
+                flusher.flush();
+            }
+            Some(RefCount::Cow(_)) => {
+                self.copy_shared(page, old, flags)?;
+            }
+            _ => return Err(PfError::Segv),
+        }
+        Ok(())
+    }
+
+    /// Map a private copy of `old`, which `page` shares copy-on-write, at `page` with `flags`
+    fn copy_shared(
+        &mut self,
+        page: Page,
+        old: Frame,
+        flags: PageFlags<RmmA>,
+    ) -> Result<Frame, PfError> {
+        let info = memory::get_page_info(old).ok_or(PfError::Segv)?;
+        let new = memory::init_frame(RefCount::One)?;
+        unsafe { copy_frames(old, new, PAGE_SIZE) };
+
+        let mut flusher = TlbShootdownActions::new(self.used_by);
+        let (_, _, flush) = unsafe { self.table.utable.0.unmap_phys(page.start_address(), false) }
+            .expect("page was translated above");
+        flush.ignore();
+        flusher.queue(old, Some(page), TlbShootdownActions::FREE);
+        let flush = unsafe {
+            self.table
+                .utable
+                .0
+                .map_phys(page.start_address(), new.base(), flags)
+        }
+        .expect("parent tables are kept when unmapping");
+        flush.ignore();
+        flusher.queue(new, Some(page), TlbShootdownActions::NEW_MAPPING);
+        flusher.flush();
+
+        if info.remove_ref().is_none() {
+            unsafe { memory::deallocate_frame(old) };
+        }
+        Ok(new)
+    }
+
+    /// Exchange the frame mapped at `page` for `frame`, returning the frame it replaced.
+    ///
+    /// Only a present, writable page of private memory that nobody else references can be
+    /// exchanged, so the change is invisible outside this address space. Otherwise `frame` is
+    /// handed back untouched.
+    pub fn swap_frame(&mut self, page: Page, frame: RaiiFrame) -> Result<RaiiFrame, RaiiFrame> {
+        let Some(grant) = self.grants_in(page, page.next()).next() else {
+            return Err(frame);
+        };
+        let owned = matches!(grant.provider, Provider::Allocated { .. }) && !grant.huge;
+        if !owned || !grant.flags.has_write() {
+            return Err(frame);
+        }
+        let (held, flags) = (grant.phys(), grant.flags);
+
+        let Some(phys) = self.table.utable.translate(page.start_address()) else {
+            return Err(frame);
+        };
+        let old = Frame::containing(phys);
+        let exclusive =
+            memory::get_page_info(old).and_then(|info| info.refcount()) == Some(RefCount::One);
+        // The grant releases its own frame when dropped, so that one has to stay
+        if !exclusive || Some(old) == held {
+            return Err(frame);
+        }
+
+        let mut flusher = TlbShootdownActions::new(self.used_by);
+        let (_, _, flush) = unsafe { self.table.utable.0.unmap_phys(page.start_address(), false) }
+            .expect("page was translated above");
+        flush.ignore();
+        flusher.queue(old, Some(page), TlbShootdownActions::FREE);
+
+        // The mapping takes over the reference held by `frame`
+        let new = frame.take();
+        let flush = unsafe {
+            self.table
+                .utable
+                .0
+                .map_phys(page.start_address(), new.base(), flags)
+        }
+        .expect("parent tables are kept when unmapping");
+        flush.ignore();
+        flusher.queue(new, Some(page), TlbShootdownActions::NEW_MAPPING);
+        flusher.flush();
+
+        Ok(unsafe { RaiiFrame::new_unchecked(old) })
+    }
+
+    /// Label the `count` pages starting at `base` with `name` in grant listings, or remove their
+    /// label if it is `None`.
+    ///
+    /// The whole range must already be mapped, otherwise ENOMEM is returned and nothing is
+    /// changed. Names longer than [`GRANT_NAME_MAX`] or with characters other than printable
+    /// ASCII fail with EINVAL. Grants straddling either end of the range are split first, as for
+    /// mprotect.
+    pub fn set_name(&mut self, base: Page, count: usize, name: Option<&str>) -> SysResult<()> {
+        let valid = |name: &str| {
+            name.len() <= GRANT_NAME_MAX && name.bytes().all(|b| b == b' ' || b.is_ascii_graphic())
+        };
+        if name.is_some_and(|name| !valid(name)) {
+            return Err(Error::new(syscall::error::EINVAL));
+        }
+        let end = base.next_by(count);
+        self.check_huge_boundary(base)?;
+        self.check_huge_boundary(end)?;
+
+        let mut cursor = base;
+        for grant in self.grants_in(base, end) {
+            if grant.start > cursor {
+                return Err(Error::new(syscall::error::ENOMEM));
+            }
+            cursor = grant.end;
+        }
+        if count == 0 || cursor < end {
+            return Err(Error::new(syscall::error::ENOMEM));
+        }
+
+        self.split_grant_at(base);
+        self.split_grant_at(end);
+        for (_, grant) in self.grants.range_mut(base..end) {
+            grant.name = name.map(Box::from);
+        }
+        Ok(())
+    }
+
+    /// Change the protection of the `count` pages starting at `base`.
+    ///
+    /// The whole range must already be mapped, otherwise ENOMEM is returned and nothing is
+    /// changed. Grants straddling either end of the range are split first, which fails with
+    /// EINVAL if that would split a huge page.
+    pub fn mprotect(&mut self, base: Page, count: usize, flags: MapFlags) -> SysResult<()> {
+        let end = base.next_by(count);
+        let new_flags = page_flags(flags);
+        self.check_huge_boundary(base)?;
+        self.check_huge_boundary(end)?;
+
+        // Validate everything up front so that failures leave the address space untouched
+        let mut cursor = base;
+        for grant in self.grants_in(base, end) {
+            if grant.start > cursor {
+                return Err(Error::new(syscall::error::ENOMEM));
+            }
+            if new_flags.has_write() && !grant.max_flags.has_write() && grant.is_borrowed() {
+                return Err(Error::new(syscall::error::EACCES));
+            }
+            if new_flags.has_execute() && !grant.max_flags.has_execute() {
+                return Err(Error::new(syscall::error::EACCES));
+            }
+            cursor = grant.end;
+        }
+        if count == 0 || cursor < end {
+            return Err(Error::new(syscall::error::ENOMEM));
+        }
+
+        self.split_grant_at(base);
+        self.split_grant_at(end);
+
+        let mut flusher = TlbShootdownActions::new(self.used_by);
+        for grant in self.grants.range_mut(base..end).map(|(_, grant)| grant) {
+            grant.flags = new_flags;
+
+            if grant.huge {
+                for page in (0..grant.page_count())
+                    .step_by(HUGE_PAGE_COUNT)
+                    .map(|i| grant.start.next_by(i))
+                {
+                    let remapped = unsafe {
+                        mapper::remap_huge(
+                            self.table.utable.table(),
+                            page.start_address(),
+                            new_flags,
+                        )
+                    };
+                    if let Some((phys, flush)) = remapped {
+                        flush.ignore();
+                        flusher.queue(
+                            Frame::containing(phys),
+                            Some(page),
+                            TlbShootdownActions::CHANGE_PROTECTION,
+                        );
+                    }
+                }
+                continue;
+            }
+
+            for page in (0..grant.page_count()).map(|i| grant.start.next_by(i)) {
+                let Some((phys, _)) = self.table.utable.0.translate(page.start_address()) else {
+                    continue;
+                };
+                // Pages shared copy-on-write stay read-only until a write fault copies them
+                let cow = memory::get_page_info(Frame::containing(phys))
+                    .and_then(|info| info.refcount())
+                    .is_some_and(|refcount| matches!(refcount, RefCount::Cow(_)));
+                let mapped_flags = if cow {
+                    new_flags.write(false)
+                } else {
+                    new_flags
+                };
+                let remapped = unsafe {
+                    self.table
+                        .utable
+                        .0
+                        .remap(page.start_address(), mapped_flags)
+                };
+                if let Some(flush) = remapped {
+                    flush.ignore();
+                    flusher.queue(
+                        Frame::containing(phys),
+                        Some(page),
+                        TlbShootdownActions::CHANGE_PROTECTION,
+                    );
+                }
+            }
+        }
+        flusher.flush();
+
+        Ok(())
+    }
+    /// Unmap the pages in `span`, returning the grants that were removed.
+    ///
+    /// Grants straddling either end of the span are split so that only the requested pages go
+    /// away, which fails with EINVAL if that would split a huge page. Unmapping a range without
+    /// any grants is not an error.
+    pub fn munmap(&mut self, span: PageSpan, unpin: bool) -> SysResult<Vec<Grant>> {
+        self.munmap_counted(span, unpin).map(|(removed, _)| removed)
+    }
+
+    /// Unmap the pages in `span` as [`Self::munmap`] does, also returning how many of them were
+    /// locked
+    ///
+    /// The caller takes them off the `memory_locked_count` of the context owning the address
+    /// space, once this is no longer locked.
+    pub fn munmap_counted(
+        &mut self,
+        span: PageSpan,
+        _unpin: bool,
+    ) -> SysResult<(Vec<Grant>, usize)> {
+        let end = span.base.next_by(span.count);
+        if self.grants_in(span.base, end).next().is_none() {
+            return Ok((Vec::new(), 0));
+        }
+        self.check_huge_boundary(span.base)?;
+        self.check_huge_boundary(end)?;
+
+        self.split_grant_at(span.base);
+        self.split_grant_at(end);
+
+        let keys: Vec<Page> = self
+            .grants
+            .range(span.base..end)
+            .map(|(key, _)| *key)
+            .collect();
+
+        let mut flusher = TlbShootdownActions::new(self.used_by);
+        let mut to_free = Vec::new();
+        let mut to_free_huge = Vec::new();
+        let mut unlocked_pages = 0;
+        let mut removed = Vec::with_capacity(keys.len());
+
+        for key in keys {
+            let grant = self.grants.remove(&key).expect("key was just collected");
+            // The frame in `phys` is released when the returned grant is dropped
+            let held = grant.phys();
+
+            if grant.huge {
+                let span = PageSpan::new(grant.start, grant.page_count());
+                to_free_huge.extend(unmap_huge_pages(&mut self.table.utable, span, &mut flusher));
+            } else {
+                for page in (0..grant.page_count()).map(|i| grant.start.next_by(i)) {
+                    let unmapped =
+                        unsafe { self.table.utable.0.unmap_phys(page.start_address(), true) };
+                    let Some((phys, _, flush)) = unmapped else {
+                        continue;
+                    };
+                    flush.ignore();
+
+                    let frame = Frame::containing(phys);
+                    flusher.queue(frame, Some(page), TlbShootdownActions::FREE);
+                    if matches!(grant.provider, Provider::Allocated { .. }) && Some(frame) != held {
+                        to_free.push(frame);
+                    }
+                }
+            }
+
+            if grant.locked {
+                unlocked_pages += grant.page_count();
+            }
+            removed.push(grant);
+        }
+
+        // Other CPUs must stop using the old translations before the frames can be reused
+        flusher.flush();
+        for frame in to_free {
+            if let Some(info) = memory::get_page_info(frame) {
+                if info.remove_ref().is_none() {
+                    unsafe { memory::deallocate_frame(frame) };
+                }
+            }
+        }
+        for frame in to_free_huge {
+            unsafe { memory::deallocate_p2frame(frame, HUGE_PAGE_ORDER) };
+        }
+
+        if unlocked_pages > 0 {
+            self.sync_locked();
+        }
+
+        Ok((removed, unlocked_pages))
+    }
+
+    /// Map `count` pages using the grant built by `func`, returning the first page.
+    ///
+    /// The pages are placed at `base` if given, otherwise anywhere above `mmap_min`. MAP_HUGE
+    /// is rejected here, as only anonymous memory can be huge; see `mmap_huge`. Providers of
+    /// file-backed grants MAP_FIXED replaces are added to `notify`, for the caller to tell once
+    /// the address space is unlocked.
     pub fn mmap(
         &mut self,
-        _base: Option<Page>,
-        _count: core::num::NonZeroUsize,
-        _flags: MapFlags,
-        _vec: &mut Vec<GrantFileRef>,
-        _func: impl FnOnce(
+        base: Option<Page>,
+        count: NonZeroUsize,
+        flags: MapFlags,
+        notify: &mut Vec<UnmapResult>,
+        func: impl FnOnce(
             Page,
             crate::paging::PageFlags<RmmA>,
             &mut crate::memory::KernelMapper,
             &mut TlbShootdownActions,
         ) -> SysResult<Grant>,
-    ) -> SysResult<Grant> {
-        Err(Error::new(crate::syscall::error::ENOMEM))
+    ) -> SysResult<Page> {
+        if flags.contains(MAP_HUGE) {
+            return Err(Error::new(syscall::error::EINVAL));
+        }
+        let span = self.place(base, count.get(), flags, 1, notify)?;
+
+        let mut kernel_mapper = crate::memory::KernelMapper::lock();
+        let mut flusher = TlbShootdownActions::new(self.used_by);
+        let grant = func(
+            span.base,
+            page_flags(flags),
+            &mut kernel_mapper,
+            &mut flusher,
+        )?;
+        // Other CPUs may be spinning on the kernel mapper while this one waits for them
+        drop(kernel_mapper);
+        flusher.flush();
+
+        self.grants.insert(grant.start, grant);
+        self.lock_if_future(span)?;
+        Ok(span.base)
+    }
+
+    /// Map `count` pages of zeroed, private memory backed by huge pages, returning the first page.
+    ///
+    /// `base` and `count` must be multiples of the huge page size, otherwise EINVAL is returned.
+    /// `notify` is filled in as by `mmap`.
+    pub fn mmap_huge(
+        &mut self,
+        base: Option<Page>,
+        count: NonZeroUsize,
+        flags: MapFlags,
+        notify: &mut Vec<UnmapResult>,
+    ) -> SysResult<Page> {
+        let misaligned = base.is_some_and(|base| base.start_address().data() % HUGE_PAGE_SIZE != 0);
+        if misaligned || count.get() % HUGE_PAGE_COUNT != 0 {
+            return Err(Error::new(syscall::error::EINVAL));
+        }
+        if flags.contains(MapFlags::MAP_SHARED) {
+            return Err(Error::new(syscall::error::EOPNOTSUPP));
+        }
+        let span = self.place(base, count.get(), flags, HUGE_PAGE_COUNT, notify)?;
+
+        let mut flusher = TlbShootdownActions::new(self.used_by);
+        let grant = Grant::zeroed_huge(
+            span,
+            page_flags(flags),
+            &mut self.table.utable,
+            &mut flusher,
+        )?;
+        flusher.flush();
+
+        self.grants.insert(grant.start, grant);
+        self.lock_if_future(span)?;
+        Ok(span.base)
+    }
+
+    /// Map `frames` at consecutive pages with `page_flags`, returning the first page.
+    ///
+    /// This is for memory owned by an object outside the address space, such as an anonymous
+    /// memory scheme object, which keeps its own reference to the frames. Every page takes a
+    /// shared reference, which munmap drops again. `page_flags` is usually
+    /// [`page_flags`]`(flags)`, with whatever cache attributes the memory needs added. `notify`
+    /// is filled in as by `mmap`.
+    pub fn mmap_shared_frames(
+        &mut self,
+        base: Option<Page>,
+        frames: &[Frame],
+        flags: MapFlags,
+        page_flags: PageFlags<RmmA>,
+        notify: &mut Vec<UnmapResult>,
+    ) -> SysResult<Page> {
+        if flags.contains(MAP_HUGE) {
+            return Err(Error::new(syscall::error::EINVAL));
+        }
+        let span = self.place(base, frames.len(), flags, 1, notify)?;
+        self.grants.insert(
+            span.base,
+            Grant::new(span.base, span.base.next_by(span.count), page_flags),
+        );
+
+        let mut flusher = TlbShootdownActions::new(self.used_by);
+        for (i, &frame) in frames.iter().enumerate() {
+            let page = span.base.next_by(i);
+            let info = memory::get_page_info(frame)
+                .filter(|info| info.add_ref(memory::RefKind::Shared).is_ok());
+            let mapped = info.and_then(|info| {
+                let flush = unsafe {
+                    self.table
+                        .utable
+                        .0
+                        .map_phys(page.start_address(), frame.base(), page_flags)
+                };
+                if flush.is_none() {
+                    info.remove_ref();
+                }
+                flush
+            });
+            let Some(flush) = mapped else {
+                // Whatever was mapped so far is released along with the grant
+                flusher.flush();
+                self.munmap(span, false)?;
+                return Err(Error::new(syscall::error::ENOMEM));
+            };
+            flush.ignore();
+            flusher.queue(frame, Some(page), TlbShootdownActions::NEW_MAPPING);
+        }
+        flusher.flush();
+
+        self.lock_if_future(span)?;
+        Ok(span.base)
+    }
+
+    /// Whether any of the `count` pages starting at `base` are locked by mlock
+    pub fn is_locked(&self, base: Page, count: usize) -> bool {
+        self.grants_in(base, base.next_by(count))
+            .any(|grant| grant.locked)
+    }
+
+    /// Pages of grants backed by memory allocated for this address space
+    pub fn allocated_pages(&self) -> usize {
+        self.grants
+            .values()
+            .filter(|grant| matches!(grant.provider, Provider::Allocated { .. }))
+            .map(Grant::page_count)
+            .sum()
+    }
+
+    /// Map a user stack of `count` zeroed pages ending just below `top`, returning its first page.
+    ///
+    /// The page below the stack is reserved as its guard page. Faults on it grow the stack
+    /// downwards, see `try_correcting_page_tables`.
+    pub fn mmap_stack(
+        &mut self,
+        top: Page,
+        count: NonZeroUsize,
+        flags: MapFlags,
+    ) -> SysResult<Page> {
+        let guard = top
+            .start_address()
+            .data()
+            .checked_sub((count.get() + 1) * PAGE_SIZE)
+            .filter(|&address| address >= self.mmap_min)
+            .map(|address| Page::containing_address(VirtualAddress::new(address)))
+            .ok_or(Error::new(syscall::error::ENOMEM))?;
+        if self.grants_in(guard, top).next().is_some() {
+            return Err(Error::new(syscall::error::EEXIST));
+        }
+
+        let base = guard.next();
+        let flags = page_flags(flags);
+        let mut flusher = TlbShootdownActions::new(self.used_by);
+        let mapped = self.map_zeroed_pages(PageSpan::new(base, count.get()), flags, &mut flusher);
+        flusher.flush();
+        mapped?;
+
+        let mut stack = Grant::new(base, top, flags).named("stack");
+        stack.stack = StackRole::Stack;
+        self.grants.insert(base, stack);
+        self.grants.insert(guard, Grant::guard(guard));
+        self.lock_if_future(PageSpan::new(guard, top.offset_from(guard)))?;
+        Ok(base)
+    }
+
+    /// Extend the stack above the guard page `guard` over it, reserving the page below as the
+    /// new guard.
+    ///
+    /// Fails with `StackOverflow` if the stack would grow beyond `limit` bytes, or if the page
+    /// below is taken so the stack could not keep a guard.
+    fn grow_stack(&mut self, guard: Page, limit: usize) -> Result<(), PfError> {
+        let stack_start = guard.next();
+        let (flags, top) = match self.grants.get(&stack_start) {
+            Some(stack) if stack.stack == StackRole::Stack => (stack.flags, stack.end),
+            _ => return Err(PfError::Segv),
+        };
+        if top.offset_from(guard) * PAGE_SIZE > limit {
+            return Err(PfError::StackOverflow);
+        }
+        let new_guard = guard
+            .start_address()
+            .data()
+            .checked_sub(PAGE_SIZE)
+            .filter(|&address| address >= self.mmap_min)
+            .map(|address| Page::containing_address(VirtualAddress::new(address)))
+            .ok_or(PfError::StackOverflow)?;
+        if self.grants_in(new_guard, guard).next().is_some() {
+            return Err(PfError::StackOverflow);
+        }
+
+        let mut flusher = TlbShootdownActions::new(self.used_by);
+        let mapped = self.map_zeroed_pages(PageSpan::new(guard, 1), flags, &mut flusher);
+        flusher.flush();
+        mapped.map_err(|_| PfError::Oom)?;
+
+        self.grants.remove(&guard);
+        let mut stack = self
+            .grants
+            .remove(&stack_start)
+            .expect("stack grant was just found");
+        stack.start = guard;
+        self.grants.insert(guard, stack);
+        self.grants.insert(new_guard, Grant::guard(new_guard));
+        // A locked stack stays locked as it grows
+        self.sync_locked();
+        Ok(())
+    }
+
+    /// Back every page in `span` with a zeroed frame of its own.
+    ///
+    /// If a frame cannot be allocated or mapped, the pages mapped so far are unmapped and freed
+    /// again before ENOMEM is returned.
+    fn map_zeroed_pages(
+        &mut self,
+        span: PageSpan,
+        flags: PageFlags<RmmA>,
+        flusher: &mut TlbShootdownActions,
+    ) -> SysResult<()> {
+        for (i, page) in (0..span.count).map(|i| (i, span.base.next_by(i))) {
+            let mapped = memory::init_frame(RefCount::One).ok().and_then(|frame| {
+                unsafe {
+                    (RmmA::phys_to_virt(frame.base()).data() as *mut u8).write_bytes(0, PAGE_SIZE);
+                }
+                let flush = unsafe {
+                    self.table
+                        .utable
+                        .0
+                        .map_phys(page.start_address(), frame.base(), flags)
+                };
+                if flush.is_none() {
+                    unsafe { memory::deallocate_frame(frame) };
+                }
+                Some((frame, flush?))
+            });
+            let Some((frame, flush)) = mapped else {
+                let mut frames = Vec::with_capacity(i);
+                for page in (0..i).map(|i| span.base.next_by(i)) {
+                    let unmapped =
+                        unsafe { self.table.utable.0.unmap_phys(page.start_address(), true) };
+                    if let Some((phys, _, flush)) = unmapped {
+                        flush.ignore();
+                        let frame = Frame::containing(phys);
+                        flusher.queue(frame, Some(page), TlbShootdownActions::FREE);
+                        frames.push(frame);
+                    }
+                }
+                flusher.flush();
+                for frame in frames {
+                    unsafe { memory::deallocate_frame(frame) };
+                }
+                return Err(Error::new(syscall::error::ENOMEM));
+            };
+            flush.ignore();
+            flusher.queue(frame, Some(page), TlbShootdownActions::NEW_MAPPING);
+        }
+        Ok(())
+    }
+
+    /// Pick where a new mapping of `count` pages goes, clearing the way for MAP_FIXED.
+    ///
+    /// A `base` that is not fixed is only a hint, and the mapping moves elsewhere if it is
+    /// already taken. The providers of file-backed grants MAP_FIXED unmaps are added to `notify`.
+    fn place(
+        &mut self,
+        base: Option<Page>,
+        count: usize,
+        flags: MapFlags,
+        align: usize,
+        notify: &mut Vec<UnmapResult>,
+    ) -> SysResult<PageSpan> {
+        if let Some(base) = base {
+            let span = PageSpan::new(base, count);
+            let occupied = self.grants_in(base, base.next_by(count)).next().is_some();
+            if !occupied {
+                return Ok(span);
+            }
+            if flags.contains(MapFlags::MAP_FIXED_NOREPLACE) {
+                return Err(Error::new(syscall::error::EEXIST));
+            }
+            if flags.contains(MapFlags::MAP_FIXED) {
+                let removed = self.munmap(span, false)?;
+                notify.extend(
+                    removed
+                        .iter()
+                        .filter_map(|grant| grant.unmap_result(MunmapFlags::NEEDS_SYNC)),
+                );
+                return Ok(span);
+            }
+        }
+        self.find_free_span_aligned(self.mmap_min, count, align)
+            .ok_or(Error::new(syscall::error::ENOMEM))
     }
 
     pub fn mmap_anywhere(
         &mut self,
-        count: core::num::NonZeroUsize,
+        count: NonZeroUsize,
         flags: MapFlags,
         func: impl FnOnce(
             Page,
@@ -358,12 +1660,64 @@ impl AddrSpaceInner {
             &mut crate::memory::KernelMapper,
             &mut TlbShootdownActions,
         ) -> SysResult<Grant>,
-    ) -> SysResult<Grant> {
+    ) -> SysResult<Page> {
         self.mmap(None, count, flags, &mut Vec::new(), func)
     }
 
+    /// Iterate over the grants overlapping [start, end), in address order
+    fn grants_in(&self, start: Page, end: Page) -> impl Iterator<Item = &Grant> + '_ {
+        // The grant containing `start` is keyed below it, so begin with its predecessor
+        let first = self
+            .grants
+            .range(..=start)
+            .next_back()
+            .map_or(start, |(key, _)| *key);
+        self.grants
+            .range(first..end)
+            .map(|(_, grant)| grant)
+            .filter(move |grant| grant.end > start)
+    }
+
+    /// Fail with EINVAL if `at` lies inside a huge page, as huge pages cannot be split
+    fn check_huge_boundary(&self, at: Page) -> SysResult<()> {
+        let splits_huge = at.start_address().data() % HUGE_PAGE_SIZE != 0
+            && self.grants_in(at, at.next()).any(Grant::is_huge);
+        if splits_huge {
+            return Err(Error::new(syscall::error::EINVAL));
+        }
+        Ok(())
+    }
+
+    /// Split the grant containing `at`, if any, so that a grant boundary falls on `at`
+    fn split_grant_at(&mut self, at: Page) {
+        let Some((_, grant)) = self.grants.range_mut(..at).next_back() else {
+            return;
+        };
+        if grant.end > at {
+            let tail = grant.split_off(at);
+            self.grants.insert(at, tail);
+        }
+    }
+
     pub fn find_free_span(&self, min_address: usize, page_count: usize) -> Option<PageSpan> {
-        let mut start = Page::containing_address(VirtualAddress::new(min_address));
+        self.find_free_span_aligned(min_address, page_count, 1)
+    }
+
+    /// Like `find_free_span`, but only returns spans starting on a multiple of `align` pages
+    pub fn find_free_span_aligned(
+        &self,
+        min_address: usize,
+        page_count: usize,
+        align: usize,
+    ) -> Option<PageSpan> {
+        let align_up = |page: Page| {
+            let address = page
+                .start_address()
+                .data()
+                .next_multiple_of(align * PAGE_SIZE);
+            Page::containing_address(VirtualAddress::new(address))
+        };
+        let mut start = align_up(Page::containing_address(VirtualAddress::new(min_address)));
 
         for grant in self.grants.values() {
             let grant_start = grant.start;
@@ -378,7 +1732,7 @@ impl AddrSpaceInner {
             }
 
             if grant_end.start_address().data() > start.start_address().data() {
-                start = grant_end;
+                start = align_up(grant_end);
             }
         }
 
@@ -403,73 +1757,325 @@ impl AddrSpaceInner {
         _dst: Option<Page>,
         _count: usize,
         _flags: MapFlags,
-        _vec: &mut Vec<GrantFileRef>,
-    ) -> SysResult<Grant> {
+        _vec: &mut Vec<UnmapResult>,
+    ) -> SysResult<Page> {
         Err(Error::new(crate::syscall::error::ENOMEM))
     }
 
 }
 
+/// Locking memory with mlock and mlockall.
+///
+/// A locked grant has all its pages present, and keeps them until it is unlocked or unmapped.
+/// Every locked page is charged to the user who locked it, against the limit they had then.
+/// Splitting a grant locks both halves, so the charge only changes as pages are locked, unlocked
+/// or unmapped.
 impl AddrSpaceInner {
-    pub fn mlock(&mut self, start: VirtualAddress, size: usize) -> SysResult<()> {
-        let current_context_ref = crate::context::current();
-        let mut token = unsafe { CleanLockToken::new() };
-        let mut current_context =
-            current_context_ref.write(token.token());
+    /// Lock the `count` pages starting at `base`, failing with ENOMEM if not all of them are
+    /// mapped or if locking them takes the user past their limit.
+    pub fn mlock(&mut self, base: Page, count: usize, limit: LockLimit) -> SysResult<()> {
+        let end = base.next_by(count);
+        self.check_huge_boundary(base)?;
+        self.check_huge_boundary(end)?;
+
+        let mut cursor = base;
+        let mut newly_locked = 0_usize;
+        for grant in self.grants_in(base, end) {
+            if grant.start > cursor {
+                return Err(Error::new(syscall::error::ENOMEM));
+            }
+            if grant.lockable() {
+                let overlap = grant.end.min(end).offset_from(grant.start.max(base));
+                newly_locked = newly_locked.saturating_add(overlap);
+            }
+            cursor = grant.end;
+        }
+        if cursor < end {
+            return Err(Error::new(syscall::error::ENOMEM));
+        }
+        self.charge_locked(limit, newly_locked)?;
+
+        self.split_grant_at(base);
+        self.split_grant_at(end);
+        let locked = self.lock_range(base, end);
+        // Only what was locked before running out of memory stays charged
+        self.sync_locked();
+        locked
+    }
 
-        let mut kernel_mapper = crate::memory::KernelMapper::lock();
-        let mapper = kernel_mapper
-            .get_mut()
-            .expect("failed to lock kernel mapper");
-        let mut flusher = TlbShootdownActions::new();
-
-        let start_page = Page::containing_address(start);
-        let end_page = Page::containing_address(VirtualAddress::new(start.data() + size - 1));
-
-        for page in Page::range_inclusive(start_page, end_page) {
-            if let Some(grant) = self.grants.get_mut(&page) {
-                if !grant.locked {
-                    // Ensure the page is present in physical memory
-                    if grant.phys.is_none() {
-                        let frame =
-                            memory::allocate_frame().ok_or(Error::new(syscall::error::ENOMEM))?;
-                        grant.set_phys(frame);
-                        unsafe {
-                            mapper
-                                .map_phys(page.start_address(), frame.base(), grant.flags)
-                                .ok_or(Error::new(syscall::error::ENOMEM))?
-                                .flush();
-                        }
-                    }
-                    grant.locked = true;
-                    current_context.memory_locked_count += 1;
+    /// Unlock the `count` pages starting at `base`, skipping those that are not mapped
+    pub fn munlock(&mut self, base: Page, count: usize) -> SysResult<()> {
+        let end = base.next_by(count);
+        self.check_huge_boundary(base)?;
+        self.check_huge_boundary(end)?;
+
+        self.split_grant_at(base);
+        self.split_grant_at(end);
+        for (_, grant) in self.grants.range_mut(base..end) {
+            grant.locked = false;
+        }
+        self.sync_locked();
+        Ok(())
+    }
+
+    /// Lock every page mapped now if `flags` has MCL_CURRENT, and every page mapped from now on
+    /// if it has MCL_FUTURE, as mlockall does.
+    pub fn mlockall(&mut self, flags: memory::MlockFlags, limit: LockLimit) -> SysResult<()> {
+        if flags.is_empty() {
+            return Err(Error::new(syscall::error::EINVAL));
+        }
+        if flags.contains(memory::MlockFlags::MCL_CURRENT) {
+            let newly_locked = self
+                .grants
+                .values()
+                .filter(|grant| grant.lockable())
+                .map(Grant::page_count)
+                .fold(0_usize, usize::saturating_add);
+            self.charge_locked(limit, newly_locked)?;
+
+            let keys: Vec<Page> = self.grants.keys().copied().collect();
+            let mut locked = Ok(());
+            for key in keys {
+                locked = self.lock_grant(key);
+                if locked.is_err() {
+                    break;
                 }
-            } else {
-                return Err(Error::new(syscall::error::ENOMEM));
             }
+            self.sync_locked();
+            locked?;
         }
+        self.lock_future = flags
+            .contains(memory::MlockFlags::MCL_FUTURE)
+            .then_some(limit);
         Ok(())
     }
 
-    pub fn munlock(&mut self, start: VirtualAddress, size: usize) -> SysResult<()> {
-        let current_context_ref = crate::context::current();
-        let mut token = unsafe { CleanLockToken::new() };
-        let mut current_context =
-            current_context_ref.write(token.token());
+    /// Unlock every page, and stop locking new mappings
+    pub fn munlockall(&mut self) {
+        self.lock_future = None;
+        for grant in self.grants.values_mut() {
+            grant.locked = false;
+        }
+        self.sync_locked();
+    }
 
-        let start_page = Page::containing_address(start);
-        let end_page = Page::containing_address(VirtualAddress::new(start.data() + size - 1));
+    /// Number of pages locked by mlock
+    pub fn locked_pages(&self) -> usize {
+        self.grants
+            .values()
+            .filter(|grant| grant.locked)
+            .map(Grant::page_count)
+            .fold(0, usize::saturating_add)
+    }
 
-        for page in Page::range_inclusive(start_page, end_page) {
-            if let Some(grant) = self.grants.get_mut(&page) {
-                if grant.locked {
-                    grant.locked = false;
-                    current_context.memory_locked_count -= 1;
+    /// Charge `limit.uid` for `newly_locked` pages on top of those locked already
+    fn charge_locked(&mut self, limit: LockLimit, newly_locked: usize) -> SysResult<()> {
+        let pages = self.locked_pages().saturating_add(newly_locked);
+        LOCKED_PAGES
+            .lock()
+            .recharge(&mut self.lock_charge, limit, pages)
+    }
+
+    /// Bring the charge in line with the pages actually locked, once some were unlocked or
+    /// unmapped, or did not get locked after all
+    fn sync_locked(&mut self) {
+        let pages = self.locked_pages();
+        let Some(charge) = self.lock_charge.filter(|charge| charge.pages != pages) else {
+            return;
+        };
+        let limit = LockLimit {
+            uid: charge.uid,
+            bytes: None,
+        };
+        let _ = LOCKED_PAGES
+            .lock()
+            .recharge(&mut self.lock_charge, limit, pages);
+    }
+
+    /// Lock the grants in [base, end), which must start and end at grant boundaries
+    fn lock_range(&mut self, base: Page, end: Page) -> SysResult<()> {
+        let keys: Vec<Page> = self.grants.range(base..end).map(|(key, _)| *key).collect();
+        for key in keys {
+            self.lock_grant(key)?;
+        }
+        Ok(())
+    }
+
+    /// Lock the grant starting at `key`, first backing any of its pages that are not present
+    /// yet if it is private memory
+    fn lock_grant(&mut self, key: Page) -> SysResult<()> {
+        let Some(grant) = self.grants.get(&key).filter(|grant| grant.lockable()) else {
+            return Ok(());
+        };
+        if matches!(grant.provider, Provider::Allocated { .. }) && !grant.huge {
+            let flags = grant.flags;
+            let pages: Vec<Page> = (0..grant.page_count())
+                .map(|i| grant.start.next_by(i))
+                .filter(|page| self.table.utable.translate(page.start_address()).is_none())
+                .collect();
+
+            let mut flusher = TlbShootdownActions::new(self.used_by);
+            let mapped = pages.into_iter().try_for_each(|page| {
+                self.map_zeroed_pages(PageSpan::new(page, 1), flags, &mut flusher)
+            });
+            flusher.flush();
+            mapped?;
+        }
+        if let Some(grant) = self.grants.get_mut(&key) {
+            grant.locked = true;
+        }
+        Ok(())
+    }
+
+    /// Lock the new mapping `span` if mlockall asked for future mappings to be locked, unmapping
+    /// it again if that fails
+    fn lock_if_future(&mut self, span: PageSpan) -> SysResult<()> {
+        let Some(limit) = self.lock_future else {
+            return Ok(());
+        };
+        let end = span.base.next_by(span.count);
+        let newly_locked = self
+            .grants_in(span.base, end)
+            .filter(|grant| grant.lockable())
+            .map(Grant::page_count)
+            .fold(0, usize::saturating_add);
+        let locked = self
+            .charge_locked(limit, newly_locked)
+            .and_then(|()| self.lock_range(span.base, end));
+        if locked.is_err() {
+            // Only memory allocated here is locked eagerly, so nobody needs to hear of this unmap
+            self.munmap(span, false)?;
+        }
+        locked
+    }
+}
+
+/// Access to the memory of an address space from outside it, for `proc:<pid>/mem`.
+///
+/// Memory is read and written through the physmap, one grant per call, so that a range running
+/// into a hole or a page that is not present yet stops short there. Pages are never faulted in, an
+/// address that cannot be accessed at all fails with EFAULT instead.
+impl AddrSpaceInner {
+    /// The grant containing `page`, if it is mapped, along with the kernel address of the page
+    fn mem_page(&self, page: Page) -> Option<(&Grant, Frame, usize)> {
+        let grant = self.grants_in(page, page.next()).next()?;
+        let phys = if grant.huge {
+            let (huge, _) =
+                unsafe { mapper::translate_huge(self.table.utable.table(), page.start_address())? };
+            let offset = page.start_address().data() % HUGE_PAGE_SIZE;
+            crate::paging::PhysicalAddress::new(huge.data().saturating_add(offset))
+        } else {
+            self.table.utable.translate(page.start_address())?
+        };
+        Some((
+            grant,
+            Frame::containing(phys),
+            RmmA::phys_to_virt(phys).data(),
+        ))
+    }
+
+    /// Copy the memory at `addr` into `buf`, returning how much was copied
+    pub fn read_mem(&self, addr: usize, buf: &mut [u8]) -> SysResult<usize> {
+        let first = Page::containing_address(VirtualAddress::new(addr));
+        let grant_end = match self.mem_page(first) {
+            Some((grant, _, _)) => grant.end,
+            None => return Err(Error::new(syscall::error::EFAULT)),
+        };
+        let len = mem_len(addr, buf.len(), grant_end);
+        Ok(copy_pages(addr, len, |page, in_page, range| {
+            let Some((_, _, virt)) = self.mem_page(page) else {
+                return false;
+            };
+            let Some(dst) = buf.get_mut(range) else {
+                return false;
+            };
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    (virt as *const u8).add(in_page),
+                    dst.as_mut_ptr(),
+                    dst.len(),
+                );
+            }
+            true
+        }))
+    }
+
+    /// Copy `buf` into the memory at `addr`, returning how much was copied.
+    ///
+    /// Read-only grants fail with EACCES unless `force` is set, and only private memory can be
+    /// forced. A page shared copy-on-write gets a copy of its own before it is written, so the
+    /// write is never seen outside this address space.
+    pub fn write_mem(&mut self, addr: usize, buf: &[u8], force: bool) -> SysResult<usize> {
+        let first = Page::containing_address(VirtualAddress::new(addr));
+        let (grant_end, flags, private) = match self.mem_page(first) {
+            Some((grant, _, _)) => (
+                grant.end,
+                grant.flags,
+                matches!(grant.provider, Provider::Allocated { .. }),
+            ),
+            None => return Err(Error::new(syscall::error::EFAULT)),
+        };
+        if !flags.has_write() && !(force && private) {
+            return Err(Error::new(syscall::error::EACCES));
+        }
+        let len = mem_len(addr, buf.len(), grant_end);
+        Ok(copy_pages(addr, len, |page, in_page, range| {
+            let Some((_, frame, mut virt)) = self.mem_page(page) else {
+                return false;
+            };
+            let shared = memory::get_page_info(frame)
+                .and_then(|info| info.refcount())
+                .is_some_and(|refcount| matches!(refcount, RefCount::Cow(_)));
+            if private && shared {
+                // A writable grant gets its write access back as on a fault, a read-only one
+                // keeps its flags
+                match self.copy_shared(page, frame, flags) {
+                    Ok(new) => virt = RmmA::phys_to_virt(new.base()).data(),
+                    Err(_) => return false,
                 }
             }
+            let Some(src) = buf.get(range) else {
+                return false;
+            };
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    src.as_ptr(),
+                    (virt as *mut u8).add(in_page),
+                    src.len(),
+                );
+            }
+            true
+        }))
+    }
+}
+
+/// How much of `len` bytes at `addr` lies below `grant_end`
+fn mem_len(addr: usize, len: usize, grant_end: Page) -> usize {
+    len.min(grant_end.start_address().data().saturating_sub(addr))
+}
+
+/// Walk `len` bytes at `addr` a page at a time, calling `copy` with each page, the offset into it
+/// and the range of the buffer it covers, until `copy` returns false. Returns the number of bytes
+/// walked.
+fn copy_pages(
+    addr: usize,
+    len: usize,
+    mut copy: impl FnMut(Page, usize, core::ops::Range<usize>) -> bool,
+) -> usize {
+    let mut done = 0;
+    while done < len {
+        let at = addr.saturating_add(done);
+        let in_page = at % PAGE_SIZE;
+        let chunk = PAGE_SIZE
+            .saturating_sub(in_page)
+            .min(len.saturating_sub(done));
+        let page = Page::containing_address(VirtualAddress::new(at));
+        if !copy(page, in_page, done..done.saturating_add(chunk)) {
+            break;
         }
-        Ok(())
+        done = done.saturating_add(chunk);
     }
+    done
 }
 
 #[derive(Debug, Clone)]
@@ -478,9 +2084,31 @@ pub struct GrantFileRef {
     pub description: Arc<RwLock<FileDescription>>,
 }
 
-impl GrantFileRef {
-    pub fn unmap(&self, _token: &mut CleanLockToken) -> SysResult<()> {
-        Ok(())
+/// A file-backed range that has been unmapped, and whose provider is yet to be told
+///
+/// `flags` has NEEDS_SYNC for an explicit munmap, where the provider should write back dirty
+/// pages, and is empty when the whole address space is torn down at exit.
+#[derive(Debug)]
+#[must_use = "the provider must be notified of the unmap"]
+pub struct UnmapResult {
+    pub file_ref: GrantFileRef,
+    pub size: usize,
+    pub flags: MunmapFlags,
+}
+
+impl UnmapResult {
+    /// Tell the provider, waiting for it to acknowledge
+    ///
+    /// The caller releases the frames whatever this returns, so a provider that fails, dies, or
+    /// is interrupted while waiting cannot keep them mapped.
+    pub fn unmap(self, token: &mut CleanLockToken) -> SysResult<()> {
+        let (scheme_id, number) = {
+            let desc = self.file_ref.description.read();
+            (desc.scheme, desc.number)
+        };
+        let scheme = crate::scheme::scheme(scheme_id).ok_or(Error::new(syscall::error::ENODEV))?;
+
+        scheme.kfunmap(number, self.file_ref.base_offset, self.size, self.flags, token)
     }
 }
 
@@ -514,8 +2142,30 @@ pub use crate::arch::paging::TlbShootdownActions;
 pub enum Provider {
     Allocated { flags: PageFlags<RmmA> },
     PhysBorrowed { base: Frame },
-    External { address: usize, size: usize },
     FmapBorrowed { file_ref: GrantFileRef },
+    IpcBuffer { buffer: crate::ipc::BufferRef },
+}
+
+impl Provider {
+    /// The provider of the part of a grant starting `page_offset` pages in
+    fn split_at(&self, page_offset: usize) -> Provider {
+        let byte_offset = page_offset * PAGE_SIZE;
+        match self {
+            Provider::Allocated { flags } => Provider::Allocated { flags: *flags },
+            Provider::PhysBorrowed { base } => Provider::PhysBorrowed {
+                base: Frame::containing(base.base().add(byte_offset)),
+            },
+            Provider::FmapBorrowed { file_ref } => Provider::FmapBorrowed {
+                file_ref: GrantFileRef {
+                    base_offset: file_ref.base_offset + byte_offset,
+                    description: Arc::clone(&file_ref.description),
+                },
+            },
+            Provider::IpcBuffer { buffer } => Provider::IpcBuffer {
+                buffer: buffer.clone(),
+            },
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -534,4 +2184,246 @@ pub enum MmapMode {
 
 pub const DANGLING: usize = 0;
 
-pub fn handle_notify_files(_files: Vec<GrantFileRef>, _token: &mut CleanLockToken) {}
+pub fn handle_notify_files(files: Vec<UnmapResult>, token: &mut CleanLockToken) {
+    for file in files {
+        let _ = file.unmap(token);
+    }
+}
+
+#[cfg(feature = "selftest")]
+pub mod selftests {
+    use alloc::format;
+
+    use super::*;
+    use crate::selftest::{check_eq, SelftestResult};
+
+    /// Where the tests start reading in the writable page
+    const SKIP: usize = 16;
+    /// Size of the memory the tests map, a writable page followed by a read-only one
+    const MAPPED: usize = 2 * PAGE_SIZE;
+
+    /// The bytes at `offsets` of the memory the tests map, each different from those around it
+    fn pattern(offsets: core::ops::Range<usize>) -> Vec<u8> {
+        offsets.map(|offset| (offset % 251) as u8).collect()
+    }
+
+    /// Reads and writes of another address space through `read_mem` and `write_mem`, on a
+    /// writable page holding a known pattern followed by a read-only page, with a hole above
+    pub fn mem_pattern_round_trip(_token: &mut CleanLockToken) -> SelftestResult {
+        let addr_space =
+            AddrSpaceWrapper::new().map_err(|err| format!("no address space: {}", err))?;
+        let mut frames = Vec::new();
+        for offset in [0, PAGE_SIZE] {
+            let frame =
+                memory::init_frame(RefCount::One).map_err(|err| format!("no frame: {:?}", err))?;
+            let bytes = pattern(offset..offset.saturating_add(PAGE_SIZE));
+            let virt = RmmA::phys_to_virt(frame.base()).data() as *mut u8;
+            unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), virt, PAGE_SIZE) };
+            frames.push(frame);
+        }
+
+        let result = check_mem(&addr_space, &frames);
+
+        // Unmapping drops the references the mappings took, leaving the ones taken above
+        let spans = addr_space
+            .acquire_read()
+            .grants
+            .values()
+            .map(|grant| PageSpan::new(grant.start, grant.page_count()))
+            .collect::<Vec<_>>();
+        for span in spans {
+            let _ = addr_space.munmap(span, false);
+        }
+        for frame in frames {
+            if memory::get_page_info(frame).is_some_and(|info| info.remove_ref().is_none()) {
+                unsafe { memory::deallocate_frame(frame) };
+            }
+        }
+        result
+    }
+
+    fn check_mem(addr_space: &AddrSpaceWrapper, frames: &[Frame]) -> SelftestResult {
+        let (writable, read_only) = frames.split_at(1);
+        let mut space = addr_space.acquire_write();
+        let base = space
+            .mmap_shared_frames(
+                None,
+                writable,
+                MapFlags::PROT_READ | MapFlags::PROT_WRITE,
+                page_flags(MapFlags::PROT_READ | MapFlags::PROT_WRITE),
+                &mut Vec::new(),
+            )
+            .map_err(|err| format!("failed to map the writable page: {}", err))?;
+        space
+            .mmap_shared_frames(
+                Some(base.next()),
+                read_only,
+                MapFlags::PROT_READ | MapFlags::MAP_FIXED_NOREPLACE,
+                page_flags(MapFlags::PROT_READ),
+                &mut Vec::new(),
+            )
+            .map_err(|err| format!("failed to map the read-only page: {}", err))?;
+        let addr = base.start_address().data();
+        let read_only_addr = base.next().start_address().data();
+        let hole = base.next_by(2).start_address().data();
+        let at = |offset: usize| addr.saturating_add(offset);
+
+        // A read stops at the end of the grant it starts in
+        let mut buf = vec![0_u8; MAPPED];
+        check_eq!(space.read_mem(at(SKIP), &mut buf), Ok(PAGE_SIZE - SKIP));
+        check_eq!(
+            buf.get(..PAGE_SIZE - SKIP),
+            Some(pattern(SKIP..PAGE_SIZE).as_slice())
+        );
+        check_eq!(space.read_mem(read_only_addr, &mut buf), Ok(PAGE_SIZE));
+        check_eq!(
+            buf.get(..PAGE_SIZE),
+            Some(pattern(PAGE_SIZE..MAPPED).as_slice())
+        );
+
+        // The hole above is not zero-filled
+        check_eq!(
+            space.read_mem(hole, &mut buf),
+            Err(Error::new(syscall::error::EFAULT))
+        );
+
+        // Writes reach the page, and read-only memory is only written when forced
+        let mut expected = pattern(6..14);
+        expected.splice(2..6, [0xA5; 4]);
+        check_eq!(space.write_mem(at(8), &[0xA5; 4], false), Ok(4));
+        check_eq!(space.read_mem(at(6), &mut buf), Ok(PAGE_SIZE - 6));
+        check_eq!(buf.get(..8), Some(expected.as_slice()));
+
+        check_eq!(
+            space.write_mem(read_only_addr, &[0x5A; 4], false),
+            Err(Error::new(syscall::error::EACCES))
+        );
+        check_eq!(space.read_mem(read_only_addr, &mut buf), Ok(PAGE_SIZE));
+        check_eq!(
+            buf.get(..PAGE_SIZE),
+            Some(pattern(PAGE_SIZE..MAPPED).as_slice())
+        );
+
+        let mut expected = pattern(PAGE_SIZE..PAGE_SIZE + 8);
+        expected.splice(..4, [0x5A; 4]);
+        check_eq!(space.write_mem(read_only_addr, &[0x5A; 4], true), Ok(4));
+        check_eq!(space.read_mem(read_only_addr, &mut buf), Ok(PAGE_SIZE));
+        check_eq!(buf.get(..8), Some(expected.as_slice()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Byte at `addr` in the test pattern
+    fn pattern(addr: usize) -> u8 {
+        (addr as u8) ^ ((addr >> 12) as u8)
+    }
+
+    /// Read `len` bytes at `addr` from a target with the pattern in every page of `present`
+    fn read(present: &[usize], addr: usize, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        let done = copy_pages(addr, len, |page, in_page, range| {
+            let base = page.start_address().data();
+            if !present.contains(&base) {
+                return false;
+            }
+            for (i, byte) in buf[range].iter_mut().enumerate() {
+                *byte = pattern(base + in_page + i);
+            }
+            true
+        });
+        buf.truncate(done);
+        buf
+    }
+
+    #[test]
+    fn test_mem_copy_pages() {
+        let present = [0x10000, 0x11000, 0x13000];
+        let data = read(&present, 0x10F00, 0x800);
+        assert_eq!(data.len(), 0x800);
+        assert!(data
+            .iter()
+            .enumerate()
+            .all(|(i, &byte)| byte == pattern(0x10F00 + i)));
+
+        // Stops short at the page that is not present
+        assert_eq!(read(&present, 0x11FF0, 0x100).len(), 0x10);
+        assert!(read(&present, 0x12000, 0x100).is_empty());
+        assert!(read(&present, 0x13000, 0).is_empty());
+    }
+
+    #[test]
+    fn test_mem_len_stops_at_grant_end() {
+        let end = Page::containing_address(VirtualAddress::new(0x13000));
+        assert_eq!(mem_len(0x12010, 0x2000, end), 0xFF0);
+        assert_eq!(mem_len(0x12010, 0x10, end), 0x10);
+        assert_eq!(mem_len(0x13000, 0x10, end), 0);
+    }
+
+    #[test]
+    fn test_prot_none_is_not_user_accessible() {
+        let user = |flags| page_flags(flags).has_flag(RmmA::ENTRY_FLAG_USER);
+        assert!(!user(MapFlags::empty()));
+        assert!(!user(MapFlags::MAP_PRIVATE));
+        assert!(user(MapFlags::PROT_READ));
+        assert!(user(MapFlags::PROT_WRITE));
+        assert!(user(MapFlags::PROT_EXEC));
+    }
+
+    #[test]
+    fn test_locked_pages_recharge() {
+        let mut locked = LockedPages(BTreeMap::new());
+        let user = LockLimit {
+            uid: 1000,
+            bytes: Some(DEFAULT_MEMLOCK_LIMIT),
+        };
+        let limit_pages = DEFAULT_MEMLOCK_LIMIT / PAGE_SIZE;
+        let mut first = None;
+        let mut second = None;
+
+        assert!(locked.recharge(&mut first, user, limit_pages - 1).is_ok());
+        // The limit is per user, across address spaces
+        assert_eq!(
+            locked.recharge(&mut second, user, 2),
+            Err(Error::new(syscall::error::ENOMEM))
+        );
+        assert_eq!(second, None);
+        assert!(locked.recharge(&mut second, user, 1).is_ok());
+        assert_eq!(locked.0.get(&1000), Some(&limit_pages));
+
+        // Giving back pages works even past a limit lowered meanwhile
+        let lowered = LockLimit {
+            uid: 1000,
+            bytes: Some(PAGE_SIZE),
+        };
+        assert!(locked.recharge(&mut first, lowered, 2).is_ok());
+        assert_eq!(locked.0.get(&1000), Some(&3));
+
+        // A limit of zero forbids locking at all
+        let forbidden = LockLimit {
+            uid: 1001,
+            bytes: Some(0),
+        };
+        let mut third = None;
+        assert_eq!(
+            locked.recharge(&mut third, forbidden, 1),
+            Err(Error::new(syscall::error::EPERM))
+        );
+
+        // Root is not limited, and taking over a charge moves it to the new user
+        let root = LockLimit {
+            uid: 0,
+            bytes: None,
+        };
+        assert!(locked.recharge(&mut first, root, 1000).is_ok());
+        assert_eq!(locked.0.get(&1000), Some(&1));
+        assert_eq!(locked.0.get(&0), Some(&1000));
+
+        locked.uncharge(&mut first);
+        locked.uncharge(&mut second);
+        assert!(locked.0.is_empty());
+    }
+}
@@ -2,16 +2,23 @@
 
 pub mod arch;
 pub mod file;
+pub mod kstack;
+pub mod kthread;
 pub mod list;
 pub mod memory;
+pub mod name;
 pub mod reap;
+pub mod slab;
 pub mod switch;
 
 #[allow(clippy::module_inception)]
 pub mod context;
 pub use context::*;
 
-pub use self::list::{contexts, current, init, CONTEXT_MAX_FILES};
+pub use self::list::{
+    contexts, current, init, name_of, names, spawn_with, SpawnOptions, CONTEXT_DEFAULT_FILES,
+    CONTEXT_MAX_FILES, MAX_OPEN_FILES,
+};
 
 // Type aliases
 pub type ContextLock = crate::sync::RwLock<crate::sync::L2, Context>;
@@ -27,3 +34,5 @@ pub fn empty_cr3() -> crate::paging::PhysicalAddress {
 
 pub use switch::switch;
 pub mod timeout;
+pub mod trace;
+pub mod upcall;
@@ -0,0 +1,143 @@
+//! # Context names
+//!
+//! A context name is read far more often than it changes: by listings walking every context and
+//! by diagnostics printed whatever state the context is in. It is kept as atomic bytes behind a
+//! sequence count, shared between the context and the list of names next to the context list,
+//! so that [`super::name_of`] can read it without locking the context. Readers copy the bytes and
+//! retry if a writer changed them meanwhile.
+
+use arrayvec::ArrayString;
+use core::{
+    fmt, str,
+    sync::atomic::{fence, AtomicU8, AtomicUsize, Ordering},
+};
+
+/// Longest name a context can have, in bytes
+pub const CONTEXT_NAME_CAPAC: usize = 32;
+
+pub struct ContextName {
+    /// Odd while a writer is changing the name
+    seq: AtomicUsize,
+    len: AtomicU8,
+    bytes: [AtomicU8; CONTEXT_NAME_CAPAC],
+}
+
+/// The longest prefix of `name` that fits, cut at a character boundary
+fn truncate(name: &str) -> &str {
+    let len = (0..=name.len().min(CONTEXT_NAME_CAPAC))
+        .rev()
+        .find(|&len| name.is_char_boundary(len))
+        .unwrap_or(0);
+    name.get(..len).unwrap_or("")
+}
+
+impl ContextName {
+    pub const fn new() -> Self {
+        Self {
+            seq: AtomicUsize::new(0),
+            len: AtomicU8::new(0),
+            bytes: [const { AtomicU8::new(0) }; CONTEXT_NAME_CAPAC],
+        }
+    }
+
+    /// Change the name, truncated to [`CONTEXT_NAME_CAPAC`] bytes
+    pub fn set(&self, name: &str) {
+        let name = truncate(name);
+
+        // Writers exclude each other by making the count odd
+        let mut seq = self.seq.load(Ordering::Relaxed);
+        loop {
+            if seq % 2 == 0 {
+                match self.seq.compare_exchange_weak(
+                    seq,
+                    seq.wrapping_add(1),
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break,
+                    Err(current) => seq = current,
+                }
+            } else {
+                core::hint::spin_loop();
+                seq = self.seq.load(Ordering::Relaxed);
+            }
+        }
+        // A reader that sees any of the new bytes sees the odd count as well
+        fence(Ordering::Release);
+
+        for (byte, &new) in self.bytes.iter().zip(name.as_bytes()) {
+            byte.store(new, Ordering::Relaxed);
+        }
+        self.len.store(name.len() as u8, Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(2), Ordering::Release);
+    }
+
+    /// A copy of the name, never torn by a concurrent [`set`](Self::set)
+    pub fn get(&self) -> ArrayString<CONTEXT_NAME_CAPAC> {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq % 2 == 0 {
+                let mut bytes = [0_u8; CONTEXT_NAME_CAPAC];
+                for (copy, byte) in bytes.iter_mut().zip(&self.bytes) {
+                    *copy = byte.load(Ordering::Relaxed);
+                }
+                let len = usize::from(self.len.load(Ordering::Relaxed));
+                fence(Ordering::Acquire);
+
+                if self.seq.load(Ordering::Relaxed) == seq {
+                    // Only whole names are stored, so an untorn copy is valid UTF-8
+                    let name = bytes.get(..len).and_then(|name| str::from_utf8(name).ok());
+                    return name
+                        .and_then(|name| ArrayString::from(name).ok())
+                        .unwrap_or_default();
+                }
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl Default for ContextName {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for ContextName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.get(), f)
+    }
+}
+
+impl fmt::Debug for ContextName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.get(), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context_name_set_get() {
+        let name = ContextName::new();
+        assert_eq!(name.get().as_str(), "");
+        name.set("[bootstrap]");
+        assert_eq!(name.get().as_str(), "[bootstrap]");
+        // A shorter name replaces the longer one entirely
+        name.set("init");
+        assert_eq!(name.get().as_str(), "init");
+        assert_eq!(format!("{}", name), "init");
+    }
+
+    #[test]
+    fn test_context_name_truncates() {
+        let name = ContextName::new();
+        name.set(&"x".repeat(40));
+        assert_eq!(name.get().len(), CONTEXT_NAME_CAPAC);
+        // Cut before a character that would straddle the end
+        name.set(&format!("{}é", "x".repeat(CONTEXT_NAME_CAPAC - 1)));
+        assert_eq!(name.get().as_str(), "x".repeat(CONTEXT_NAME_CAPAC - 1));
+    }
+}
@@ -1,6 +1,33 @@
 //! # Context Reaping
+//!
+//! A context that exits while its parent is around stays in the context list as a zombie, which
+//! holds nothing but its wait status, until the parent collects it with waitpid. Children of an
+//! exiting context are handed to the bootstrap context, so that zombies cannot pile up forever.
+//! Kernel threads have no parent, and are kept as zombies for their handle to join instead.
 
-use crate::context::{Context, Status};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+
+use crate::{
+    context::{self, kthread, Context, ContextRef, Status},
+    sync::{CleanLockToken, WaitCondition},
+    syscall::{
+        error::{Error, Result, ECHILD, EINTR},
+        flag::{SIGKILL, SIGSEGV},
+    },
+};
+
+/// Return immediately from waitpid if no child has exited
+pub const WNOHANG: usize = 1;
+
+/// Waiters in waitpid, woken to rescan their children when one exits
+static CHILD_EXITED: WaitCondition = WaitCondition::new();
+/// Held while a context exits and while a waiter looks for zombies, so that an exit cannot slip
+/// in between the scan and the wait
+static EXIT_LOCK: Mutex<()> = Mutex::new(());
+/// Id of the bootstrap context, which adopts orphans, or 0 before it has started
+static INIT_ID: AtomicUsize = AtomicUsize::new(0);
 
 pub fn reap_grants() {
     // Placeholder: This is where we would lock global context list
@@ -9,4 +36,264 @@ pub fn reap_grants() {
 
 pub fn cleanup_context(_context: &mut Context) {
     // Dropping context fields handles cleanup
-}
\ No newline at end of file
+}
+
+/// Make the current context the one that adopts orphaned children
+pub fn set_init(id: usize) {
+    INIT_ID.store(id, Ordering::Relaxed);
+}
+
+/// Wait status as POSIX encodes it, with the exit code in bits 8 to 15, or the signal that
+/// terminated the context in the low bits
+fn wait_status(code: u8, signal: Option<u8>) -> usize {
+    match signal {
+        Some(signal) => usize::from(signal & 0x7f),
+        None => usize::from(code) << 8,
+    }
+}
+
+/// Remove an exited context from the context list, after which only handles keep it alive
+fn reap(context_ref: &ContextRef, token: &mut CleanLockToken) {
+    let id = {
+        let mut context = context_ref.write(token.token());
+        if let Status::Zombie { excp, .. } = &mut context.status {
+            let excp = excp.take();
+            context.status = Status::Dead { excp };
+        }
+        context.parent = None;
+        context.id()
+    };
+    let _ = context::contexts().write().remove(&id);
+    let _ = context::names().write().remove(&id);
+    kthread::unregister(id);
+}
+
+/// Mark the current context as exited with `code`, which its wait status reports unless a signal
+/// ended it. It becomes a zombie if its parent can still collect it, and is reaped right away
+/// otherwise. Its own children are handed to the bootstrap context.
+pub fn exit_current(code: u8, excp: Option<syscall::Exception>, token: &mut CleanLockToken) {
+    let context_ref = context::current();
+    let exit_lock = EXIT_LOCK.lock();
+
+    let id = context_ref.read(token.token()).id();
+    let init = INIT_ID.load(Ordering::Relaxed);
+    let adopter = (init != 0 && init != id).then_some(init);
+
+    // As in sys:context, the list is not held locked while contexts are locked
+    let context_refs = context::contexts()
+        .read()
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+    let mut orphaned_zombies = Vec::new();
+    let mut adopted_zombie = false;
+    for child_ref in context_refs.iter() {
+        let mut child = child_ref.write(token.token());
+        if child.parent != Some(id) {
+            continue;
+        }
+        child.parent = adopter;
+        if matches!(child.status, Status::Zombie { .. }) {
+            if adopter.is_some() {
+                adopted_zombie = true;
+            } else {
+                orphaned_zombies.push(child_ref);
+            }
+        }
+    }
+    for zombie in orphaned_zombies {
+        reap(zombie, token);
+    }
+    // Kernel threads have no parent, but wait for their handle to reap them unless detached
+    let joinable = kthread::is_joinable(id);
+
+    let zombie = {
+        let mut context = context_ref.write(token.token());
+        let signal = if excp.is_some() {
+            Some(SIGSEGV as u8)
+        } else if context.being_sigkilled {
+            Some(SIGKILL as u8)
+        } else {
+            None
+        };
+        // A parent is in the list for as long as it has not exited, as exiting clears this field
+        let zombie = context.parent.is_some() || joinable;
+        context.status = if zombie {
+            Status::Zombie {
+                excp,
+                status: wait_status(code, signal),
+            }
+        } else {
+            Status::Dead { excp }
+        };
+        zombie
+    };
+    if !zombie {
+        reap(&context_ref, token);
+    }
+    drop(exit_lock);
+
+    if zombie || adopted_zombie {
+        CHILD_EXITED.notify(token);
+    }
+}
+
+/// Wait for a child of the current context to exit and reap it. A `pid` of 0 or -1 picks any
+/// child, and anything else the child with that id.
+///
+/// Returns the id and wait status of the child, or `None` if [`WNOHANG`] is set and no child has
+/// exited yet. Fails with `ECHILD` if there is no such child, and `EINTR` if interrupted.
+pub fn waitpid(
+    pid: usize,
+    options: usize,
+    token: &mut CleanLockToken,
+) -> Result<Option<(usize, usize)>> {
+    let current = context::current();
+    let id = current.read(token.token()).id();
+    let any = pid == 0 || pid as isize == -1;
+
+    loop {
+        let exit_lock = EXIT_LOCK.lock();
+        let context_refs = context::contexts()
+            .read()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut has_child = false;
+        for child_ref in context_refs.iter() {
+            let child = child_ref.read(token.token());
+            if child.parent != Some(id) || !(any || child.id() == pid) {
+                continue;
+            }
+            has_child = true;
+            if let Status::Zombie { status, .. } = child.status {
+                let child_id = child.id();
+                drop(child);
+                reap(child_ref, token);
+                return Ok(Some((child_id, status)));
+            }
+        }
+
+        if !has_child {
+            return Err(Error::new(ECHILD));
+        }
+        if options & WNOHANG == WNOHANG {
+            return Ok(None);
+        }
+        if !CHILD_EXITED.wait(exit_lock, "waitpid", token) {
+            return Err(Error::new(EINTR));
+        }
+        // Woken by any exit, so the children are scanned again
+    }
+}
+
+/// Wait for the kernel thread `context_ref` to exit and reap it, see [`kthread::Handle::join`]
+pub(super) fn join_kthread(context_ref: &ContextRef, token: &mut CleanLockToken) -> Result<()> {
+    loop {
+        let exit_lock = EXIT_LOCK.lock();
+        let status = context_ref.read(token.token()).status.clone();
+        match status {
+            Status::Zombie { .. } => {
+                reap(context_ref, token);
+                return Ok(());
+            }
+            // Already reaped by an earlier join
+            Status::Dead { .. } => return Ok(()),
+            // Exits of kernel threads that are waited for notify like those of children
+            _ => {
+                if !CHILD_EXITED.wait(exit_lock, "kthread join", token) {
+                    return Err(Error::new(EINTR));
+                }
+            }
+        }
+    }
+}
+
+/// Stop keeping the kernel thread `context_ref` around for a join, reaping it now if it has
+/// already exited
+pub(super) fn detach_kthread(
+    context_ref: &ContextRef,
+    joinable: &AtomicBool,
+    token: &mut CleanLockToken,
+) {
+    let _exit_lock = EXIT_LOCK.lock();
+    joinable.store(false, Ordering::Release);
+    if matches!(
+        context_ref.read(token.token()).status,
+        Status::Zombie { .. }
+    ) {
+        reap(context_ref, token);
+    }
+}
+
+#[cfg(feature = "selftest")]
+pub mod selftests {
+    use alloc::format;
+
+    use super::*;
+    use crate::{
+        arch::interrupt,
+        context::SpawnOptions,
+        cpu_set::{LogicalCpuId, LogicalCpuSet},
+        selftest::{check_eq, SelftestResult},
+    };
+
+    const EXIT_CODE: u8 = 42;
+
+    fn exit_with_code() {
+        let mut token = unsafe { CleanLockToken::new() };
+        crate::syscall::process::exit_this_context(EXIT_CODE, None, &mut token);
+    }
+
+    /// A child of the current context exits with a code, which waitpid reports in its status
+    pub fn waitpid_reports_exit_code(token: &mut CleanLockToken) -> SelftestResult {
+        const TIMEOUT_NS: u128 = 10_000_000_000;
+
+        let parent = context::current().read(token.token()).id();
+        let mut bsp = LogicalCpuSet::new();
+        bsp.add(LogicalCpuId::BSP);
+        let options = SpawnOptions {
+            affinity: bsp,
+            name: "[selftest_exit]",
+            ..SpawnOptions::default()
+        };
+        let child_ref = context::spawn_with(options, exit_with_code, token)
+            .map_err(|err| format!("failed to spawn the child: {}", err))?;
+        let child = {
+            let mut child = child_ref.write(token.token());
+            child.parent = Some(parent);
+            child.status = Status::Runnable;
+            child.id()
+        };
+        drop(child_ref);
+
+        // As in selftest::switch_until, the child only runs once this context switches away
+        let deadline = crate::time::monotonic().saturating_add(TIMEOUT_NS);
+        let reaped = loop {
+            match waitpid(child, WNOHANG, token) {
+                Ok(None) if crate::time::monotonic() <= deadline => unsafe {
+                    interrupt::disable();
+                    let _ = context::switch(token);
+                    interrupt::enable_and_nop();
+                },
+                reaped => break reaped,
+            }
+        };
+        check_eq!(reaped, Ok(Some((child, wait_status(EXIT_CODE, None)))));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wait_status_encodes_code_or_signal() {
+        assert_eq!(wait_status(0, None), 0);
+        assert_eq!(wait_status(3, None), 0x300);
+        assert_eq!(wait_status(0, Some(9)), 9);
+        assert_eq!(wait_status(5, Some(11)), 11);
+    }
+}
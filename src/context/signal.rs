@@ -12,7 +12,7 @@ pub fn signal_handler(token: &mut CleanLockToken) {
     if being_sigkilled {
         drop(context_guard);
         drop(context_lock);
-        crate::syscall::process::exit_this_context(None, token);
+        crate::syscall::process::exit_this_context(0, None, token);
     }
 
     /*let thumbs_down = ptrace::breakpoint_callback(
@@ -87,7 +87,7 @@ pub fn excp_handler(excp: syscall::Exception) {
         drop(context);
         // TODO: Allow exceptions to be caught by tracer etc, without necessarily exiting the
         // context (closing files, dropping AddrSpace, etc)
-        crate::syscall::process::exit_this_context(Some(excp), &mut token);
+        crate::syscall::process::exit_this_context(0, Some(excp), &mut token);
     };
     // TODO
     /*
@@ -0,0 +1,424 @@
+//! # File Table Slab
+//!
+//! Storage for the two halves of a context's file table. Slots are kept in chunks of 64, which
+//! are allocated when one of their slots is first filled and freed again once they are empty, so
+//! a table holding a few descriptors at high numbers stays small.
+//!
+//! Occupancy is tracked by a two-level bitmap: every chunk has a word with a bit per slot, and a
+//! summary has a bit per chunk that is set while the chunk is full. The 16 summary words cover
+//! [`CONTEXT_MAX_FILES`], so finding the lowest free slot looks at the word of the chunk it
+//! starts in, the summary, and the word of the chunk the summary points to, whatever the number
+//! of open files.
+
+use alloc::{boxed::Box, vec::Vec};
+
+use super::CONTEXT_MAX_FILES;
+
+/// Slots per chunk, one bitmap word
+const CHUNK_SLOTS: usize = u64::BITS as usize;
+/// Chunks needed to hold [`CONTEXT_MAX_FILES`] slots
+const MAX_CHUNKS: usize = CONTEXT_MAX_FILES.div_ceil(CHUNK_SLOTS);
+/// Summary words, one bit per chunk
+const SUMMARY_WORDS: usize = MAX_CHUNKS.div_ceil(u64::BITS as usize);
+
+/// The word with only `bit` set, which must be less than 64
+fn bit_mask(bit: usize) -> u64 {
+    1_u64.wrapping_shl(bit as u32)
+}
+
+/// Mask of the bits below `bit`, which must be less than 64
+fn bits_below(bit: usize) -> u64 {
+    bit_mask(bit).wrapping_sub(1)
+}
+
+#[derive(Clone, Debug)]
+struct Chunk<T> {
+    /// A set bit for every slot that holds a value
+    used: u64,
+    slots: [Option<T>; CHUNK_SLOTS],
+}
+
+impl<T> Chunk<T> {
+    fn new() -> Box<Self> {
+        Box::new(Self {
+            used: 0,
+            slots: [const { None }; CHUNK_SLOTS],
+        })
+    }
+}
+
+/// Values at indices below [`CONTEXT_MAX_FILES`], allocating the lowest free index in constant
+/// time
+#[derive(Clone, Debug)]
+pub struct Slab<T> {
+    /// Chunks by index, `None` where all slots are free. Has no trailing `None`.
+    chunks: Vec<Option<Box<Chunk<T>>>>,
+    /// A set bit for every full chunk
+    full: [u64; SUMMARY_WORDS],
+    count: usize,
+}
+
+impl<T> Default for Slab<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Slab<T> {
+    pub const fn new() -> Self {
+        Self {
+            chunks: Vec::new(),
+            full: [0; SUMMARY_WORDS],
+            count: 0,
+        }
+    }
+
+    /// Number of values held
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    fn used_bits(&self, chunk: usize) -> u64 {
+        match self.chunks.get(chunk) {
+            Some(Some(chunk)) => chunk.used,
+            _ => 0,
+        }
+    }
+
+    fn set_full(&mut self, chunk: usize, full: bool) {
+        let bit = bit_mask(chunk % u64::BITS as usize);
+        if let Some(word) = self.full.get_mut(chunk / u64::BITS as usize) {
+            if full {
+                *word |= bit;
+            } else {
+                *word &= !bit;
+            }
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&T> {
+        let chunk = self.chunks.get(index / CHUNK_SLOTS)?.as_ref()?;
+        chunk.slots.get(index % CHUNK_SLOTS)?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        let chunk = self.chunks.get_mut(index / CHUNK_SLOTS)?.as_mut()?;
+        chunk.slots.get_mut(index % CHUNK_SLOTS)?.as_mut()
+    }
+
+    pub fn contains(&self, index: usize) -> bool {
+        self.used_bits(index / CHUNK_SLOTS) & bit_mask(index % CHUNK_SLOTS) != 0
+    }
+
+    /// The lowest free index greater than or equal to `min`, or `None` if all of them up to
+    /// [`CONTEXT_MAX_FILES`] are taken
+    pub fn first_free(&self, min: usize) -> Option<usize> {
+        if min >= CONTEXT_MAX_FILES {
+            return None;
+        }
+        let chunk = min / CHUNK_SLOTS;
+        // Slots below `min` count as taken
+        let used = self.used_bits(chunk) | bits_below(min % CHUNK_SLOTS);
+        if used != u64::MAX {
+            return Self::free_in(chunk, used);
+        }
+
+        // Otherwise the first chunk after it that is not full
+        let next = chunk.saturating_add(1);
+        let mut word = next / u64::BITS as usize;
+        let mut skip = bits_below(next % u64::BITS as usize);
+        while let Some(&full) = self.full.get(word) {
+            let full = full | skip;
+            if full != u64::MAX {
+                let chunk = word
+                    .saturating_mul(u64::BITS as usize)
+                    .saturating_add((!full).trailing_zeros() as usize);
+                return Self::free_in(chunk, self.used_bits(chunk));
+            }
+            skip = 0;
+            word = word.saturating_add(1);
+        }
+        None
+    }
+
+    /// The lowest free slot of `chunk`, given its used bits with at least one clear
+    fn free_in(chunk: usize, used: u64) -> Option<usize> {
+        let index = chunk
+            .saturating_mul(CHUNK_SLOTS)
+            .saturating_add((!used).trailing_zeros() as usize);
+        (index < CONTEXT_MAX_FILES).then_some(index)
+    }
+
+    /// The lowest taken index greater than or equal to `min`, or [`CONTEXT_MAX_FILES`] if there
+    /// is none
+    fn first_used(&self, min: usize) -> usize {
+        let mut chunk = min / CHUNK_SLOTS;
+        let mut skip = bits_below(min % CHUNK_SLOTS);
+        while chunk < self.chunks.len() {
+            let used = self.used_bits(chunk) & !skip;
+            if used != 0 {
+                return chunk
+                    .saturating_mul(CHUNK_SLOTS)
+                    .saturating_add(used.trailing_zeros() as usize);
+            }
+            skip = 0;
+            chunk = chunk.saturating_add(1);
+        }
+        CONTEXT_MAX_FILES
+    }
+
+    /// The lowest index starting `len` free slots in a row, or `None` if there is no such run
+    /// below [`CONTEXT_MAX_FILES`]
+    pub fn first_free_run(&self, len: usize) -> Option<usize> {
+        let mut start = self.first_free(0)?;
+        loop {
+            let end = self.first_used(start);
+            if end.saturating_sub(start) >= len {
+                return Some(start);
+            }
+            start = self.first_free(end)?;
+        }
+    }
+
+    /// Put `value` at `index`, or give it back if the index is taken or out of range
+    pub fn insert(&mut self, index: usize, value: T) -> Result<(), T> {
+        if index >= CONTEXT_MAX_FILES || self.contains(index) {
+            return Err(value);
+        }
+        let chunk_index = index / CHUNK_SLOTS;
+        if self.chunks.len() <= chunk_index {
+            self.chunks
+                .resize_with(chunk_index.saturating_add(1), || None);
+        }
+        let Some(entry) = self.chunks.get_mut(chunk_index) else {
+            return Err(value);
+        };
+        let chunk = entry.get_or_insert_with(Chunk::new);
+        let bit = index % CHUNK_SLOTS;
+        let Some(slot) = chunk.slots.get_mut(bit) else {
+            return Err(value);
+        };
+        *slot = Some(value);
+        chunk.used |= bit_mask(bit);
+        let full = chunk.used == u64::MAX;
+
+        self.count = self.count.saturating_add(1);
+        if full {
+            self.set_full(chunk_index, true);
+        }
+        Ok(())
+    }
+
+    /// Take the value at `index`, freeing its chunk if it was the last one there
+    pub fn remove(&mut self, index: usize) -> Option<T> {
+        let chunk_index = index / CHUNK_SLOTS;
+        let entry = self.chunks.get_mut(chunk_index)?;
+        let chunk = entry.as_mut()?;
+        let bit = index % CHUNK_SLOTS;
+        let value = chunk.slots.get_mut(bit)?.take()?;
+        chunk.used &= !bit_mask(bit);
+        if chunk.used == 0 {
+            *entry = None;
+            while let Some(None) = self.chunks.last() {
+                self.chunks.pop();
+            }
+        }
+
+        self.count = self.count.saturating_sub(1);
+        self.set_full(chunk_index, false);
+        Some(value)
+    }
+
+    /// Take the values for which `predicate` holds, in order of their index
+    pub fn remove_where(&mut self, mut predicate: impl FnMut(&T) -> bool) -> Vec<T> {
+        let indices: Vec<usize> = self
+            .iter()
+            .filter(|(_, value)| predicate(value))
+            .map(|(index, _)| index)
+            .collect();
+        indices
+            .into_iter()
+            .filter_map(|index| self.remove(index))
+            .collect()
+    }
+
+    /// Take every value, in order of their index
+    pub fn drain(&mut self) -> impl Iterator<Item = T> {
+        self.full = [0; SUMMARY_WORDS];
+        self.count = 0;
+        core::mem::take(&mut self.chunks)
+            .into_iter()
+            .flatten()
+            .flat_map(|chunk| chunk.slots.into_iter().flatten())
+    }
+
+    /// The values held with their indices, in order of their index
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &T)> {
+        self.chunks
+            .iter()
+            .enumerate()
+            .filter_map(|(index, chunk)| Some((index, chunk.as_ref()?)))
+            .flat_map(|(index, chunk)| {
+                let base = index.saturating_mul(CHUNK_SLOTS);
+                chunk
+                    .slots
+                    .iter()
+                    .enumerate()
+                    .filter_map(move |(bit, slot)| Some((base.saturating_add(bit), slot.as_ref()?)))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_free_skips_full_chunks() {
+        let mut slab = Slab::new();
+        for i in 0..200 {
+            assert_eq!(slab.first_free(0), Some(i));
+            slab.insert(i, i).unwrap();
+        }
+        assert_eq!(slab.len(), 200);
+        assert_eq!(slab.first_free(0), Some(200));
+        assert_eq!(slab.first_free(10), Some(200));
+        assert_eq!(slab.first_free(300), Some(300));
+
+        assert_eq!(slab.remove(70), Some(70));
+        assert_eq!(slab.first_free(0), Some(70));
+        assert_eq!(slab.first_free(71), Some(200));
+        assert_eq!(slab.remove(70), None);
+
+        assert_eq!(slab.insert(70, 70), Ok(()));
+        assert_eq!(slab.insert(70, 0), Err(0));
+        assert_eq!(slab.get(70), Some(&70));
+    }
+
+    #[test]
+    fn test_full_slab() {
+        let mut slab = Slab::new();
+        for i in 0..CONTEXT_MAX_FILES {
+            slab.insert(i, ()).unwrap();
+        }
+        assert_eq!(slab.first_free(0), None);
+        assert_eq!(slab.insert(CONTEXT_MAX_FILES, ()), Err(()));
+
+        slab.remove(CONTEXT_MAX_FILES - 1).unwrap();
+        assert_eq!(slab.first_free(0), Some(CONTEXT_MAX_FILES - 1));
+        assert_eq!(slab.first_free(CONTEXT_MAX_FILES), None);
+    }
+
+    #[test]
+    fn test_sparse_chunks_are_freed() {
+        let mut slab = Slab::new();
+        slab.insert(5, 'a').unwrap();
+        slab.insert(CONTEXT_MAX_FILES - 1, 'b').unwrap();
+        assert_eq!(slab.chunks.len(), MAX_CHUNKS);
+        assert_eq!(slab.chunks.iter().flatten().count(), 2);
+        assert_eq!(
+            slab.iter().collect::<Vec<_>>(),
+            [(5, &'a'), (CONTEXT_MAX_FILES - 1, &'b')]
+        );
+
+        assert_eq!(slab.remove(CONTEXT_MAX_FILES - 1), Some('b'));
+        assert_eq!(slab.chunks.len(), 1);
+        assert_eq!(slab.remove(5), Some('a'));
+        assert!(slab.chunks.is_empty());
+        assert!(slab.is_empty());
+    }
+
+    #[test]
+    fn test_first_free_run() {
+        let mut slab = Slab::new();
+        for i in [0, 1, 3, 6, 64] {
+            slab.insert(i, ()).unwrap();
+        }
+        assert_eq!(slab.first_free_run(1), Some(2));
+        assert_eq!(slab.first_free_run(2), Some(4));
+        assert_eq!(slab.first_free_run(57), Some(7));
+        assert_eq!(slab.first_free_run(58), Some(65));
+        assert_eq!(slab.first_free_run(CONTEXT_MAX_FILES - 65), Some(65));
+        assert_eq!(slab.first_free_run(CONTEXT_MAX_FILES - 64), None);
+    }
+
+    #[test]
+    fn test_remove_where_and_drain() {
+        let mut slab = Slab::new();
+        for i in 0..130 {
+            slab.insert(i, i).unwrap();
+        }
+        let odd = slab.remove_where(|value| value % 2 == 1);
+        assert_eq!(odd.len(), 65);
+        assert!(odd.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(slab.len(), 65);
+        assert_eq!(slab.first_free(0), Some(1));
+
+        assert_eq!(slab.drain().count(), 65);
+        assert!(slab.is_empty());
+        assert_eq!(slab.first_free(0), Some(0));
+    }
+
+    /// The lowest free slot found by scanning, as the file table did before
+    fn linear_add(table: &mut Vec<Option<usize>>, value: usize) -> usize {
+        match table.iter().position(Option::is_none) {
+            Some(index) => {
+                table[index] = Some(value);
+                index
+            }
+            None => {
+                table.push(Some(value));
+                table.len() - 1
+            }
+        }
+    }
+
+    /// Close and reopen the lowest descriptor with 1, 1k and 64k files open, against the linear
+    /// scan. Run with `cargo test --release -- --ignored --nocapture bench_open_close`.
+    #[test]
+    #[ignore]
+    fn bench_open_close() {
+        const ROUNDS: usize = 10_000;
+        use std::{println, time::Instant};
+
+        for open in [1, 1_000, CONTEXT_MAX_FILES] {
+            let mut linear = Vec::new();
+            for i in 0..open {
+                linear_add(&mut linear, i);
+            }
+            let start = Instant::now();
+            for i in 0..ROUNDS {
+                linear[0] = None;
+                assert_eq!(linear_add(&mut linear, i), 0);
+                // Reopening the highest descriptor has to scan the whole table
+                linear[open - 1] = None;
+                assert_eq!(linear_add(&mut linear, i), open - 1);
+            }
+            let linear_time = start.elapsed();
+
+            let mut slab = Slab::new();
+            for i in 0..open {
+                slab.insert(slab.first_free(0).unwrap(), i).unwrap();
+            }
+            let start = Instant::now();
+            for i in 0..ROUNDS {
+                slab.remove(0).unwrap();
+                assert_eq!(slab.first_free(0), Some(0));
+                slab.insert(0, i).unwrap();
+                slab.remove(open - 1).unwrap();
+                assert_eq!(slab.first_free(0), Some(open - 1));
+                slab.insert(open - 1, i).unwrap();
+            }
+            let slab_time = start.elapsed();
+
+            println!(
+                "{} open, {} rounds: linear scan {:?}, slab {:?}",
+                open, ROUNDS, linear_time, slab_time
+            );
+        }
+    }
+}
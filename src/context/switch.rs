@@ -1,7 +1,7 @@
 //! # Context Switching
 
 use crate::{
-    context::{contexts, Context},
+    context::{contexts, timeout, trace, Context},
     percpu::PercpuBlock,
     scheduler,
     sync::CleanLockToken,
@@ -26,6 +26,10 @@ pub unsafe fn switch(token: &mut CleanLockToken) -> SwitchResult {
 
     let next_context_ref_opt = scheduler::schedule_next(token);
 
+    // Follow the time slice deadline the scheduler just picked
+    #[cfg(target_arch = "riscv64")]
+    crate::arch::time::rearm();
+
     if let Some(next_context_ref) = next_context_ref_opt {
         let next_context_id = next_context_ref.read(token.token()).id();
 
@@ -61,47 +65,43 @@ pub unsafe fn switch(token: &mut CleanLockToken) -> SwitchResult {
         if let Some(prev_lock) = prev_context_lock {
             // SAFETY: We need two write locks. Since we are in context switch, the hierarchy is respected
             // implicitly by the fact that we are switching from prev to next.
-            let mut token2 = unsafe { CleanLockToken::new() };
+            let mut token2 = unsafe { CleanLockToken::new_nested() };
             let mut prev_guard = prev_lock.write(token2.token());
+            trace::record_switch(Some(&prev_guard), next_context_id);
 
             PercpuBlock::current().context_id.set(next_context_id);
 
+            // The next context starts out with no locks, or resumes here with those of its own
+            // switch, which are released once it returns
+            #[cfg(debug_assertions)]
+            let held_locks = PercpuBlock::current().held_locks.replace(0);
+
             crate::arch::switch_to(&mut *prev_guard, &mut *next_guard);
+
+            #[cfg(debug_assertions)]
+            PercpuBlock::current().held_locks.set(held_locks);
         } else {
             // This case handles the initial switch from an idle state or kmain
             // where there isn't a "previous" user context to save.
+            trace::record_switch(None, next_context_id);
             PercpuBlock::current().context_id.set(next_context_id);
+            #[cfg(debug_assertions)]
+            PercpuBlock::current().held_locks.set(0);
             unsafe { crate::arch::switch_to_first(&mut *next_guard) };
         }
 
         SwitchResult::Switched
     } else {
-        // All contexts are idle. Program the timer for the earliest wake time among all contexts.
-        let mut earliest_wake: Option<u128> = None;
-        let contexts_guard = contexts().read();
-        for (_id, context_lock) in contexts_guard.iter() {
-            // Cannot use token again while contexts_guard is alive?
-            // Yes, ContextLock.read(token) needs mutable borrow of token.
-            // contexts_guard holds mutable borrow of token? No, read() takes &mut Token?
-            // RwLock::read takes &mut Token.
-            // So we cannot use token again.
-            // We need a new token for inner loop or iterate keys and re-acquire?
-            // Re-acquiring contexts lock for each item is slow.
-            // But we can unsafe create token for inner lock since we respect ordering (Contexts -> Context).
-            let mut token2 = unsafe { CleanLockToken::new() };
-            let context = context_lock.read(token2.token());
-            if let Some(wake_time) = context.wake {
-                if earliest_wake.is_none() || wake_time < earliest_wake.unwrap() {
-                    earliest_wake = Some(wake_time);
-                }
-            }
-        }
-
-        if let Some(wake_time) = earliest_wake {
-            time::set_next_timer_event(wake_time as u64);
+        // All contexts are idle. Sleepers register their wake time as a timeout and throttled
+        // deadline contexts wait for their next period, so the earliest of these is the next
+        // time anything can become runnable.
+        let replenish = scheduler::scheduler().lock().run_queue.next_replenish();
+        let timeout = timeout::next_deadline(token).map(|deadline| deadline as u64);
+        if let Some(deadline) = timeout.into_iter().chain(replenish).min() {
+            time::set_next_timer_event(deadline);
         } else {
-            // If no contexts are set to wake up, set a default idle timeout
-            time::set_next_timer_event(time::monotonic() as u64 + 1_000_000_000); // 1 second
+            // If no contexts are set to wake up, set a default idle timeout of 1 second
+            time::set_next_timer_event(time::monotonic() as u64 + 1_000_000_000);
         }
 
         SwitchResult::AllContextsIdle
@@ -1,8 +1,23 @@
-use alloc::collections::VecDeque;
+//! # Timeouts
+//!
+//! Pending timeouts are kept in one min-heap of deadlines per clock, so registering costs
+//! O(log n) and [`trigger`] only looks at the timeouts that are due. Cancelling drops the entry
+//! from `timeouts` and leaves its heap slot behind, to be skipped when it reaches the top.
+//! Periodic timeouts keep their id and are pushed back with their next deadline as they fire.
+//!
+//! The queue is global rather than per-CPU: [`trigger`] is run from a single CPU's timer
+//! interrupt, and waiters are cancelled from whichever CPU they resume on.
+
+use alloc::{
+    collections::{BTreeMap, BinaryHeap},
+    sync::Weak,
+};
+use core::cmp::Reverse;
 use spin::Once;
 
 use crate::{
-    event,
+    context::ContextLock,
+    event, scheduler,
     scheme::SchemeId,
     sync::{CleanLockToken, LockToken, OrderedMutex, OrderedMutexGuard, L0, L1},
     syscall::{
@@ -12,21 +27,162 @@ use crate::{
     time,
 };
 
+#[derive(Clone, Debug)]
+enum Target {
+    /// Trigger a read event on a scheme handle
+    Event {
+        scheme_id: SchemeId,
+        event_id: usize,
+    },
+    /// Unblock a context sleeping with a deadline
+    Wakeup(Weak<ContextLock>),
+}
+
 #[derive(Debug)]
 struct Timeout {
-    pub scheme_id: SchemeId,
-    pub event_id: usize,
+    pub target: Target,
     pub clock: usize,
     pub time: u128,
+    /// Period after which a timeout fires again, or 0 if it fires once
+    pub interval: u128,
+}
+
+/// Min-heap of `(deadline, id)`, possibly holding ids of cancelled timeouts
+type Deadlines = BinaryHeap<Reverse<(u128, u64)>>;
+
+#[derive(Debug, Default)]
+struct Registry {
+    next_id: u64,
+    monotonic: Deadlines,
+    realtime: Deadlines,
+    /// Pending timeouts by id
+    timeouts: BTreeMap<u64, Timeout>,
+    /// Id of the pending wakeup of each context, keyed by its address
+    wakeups: BTreeMap<usize, u64>,
 }
 
-type Registry = VecDeque<Timeout>;
+fn context_key(context: &Weak<ContextLock>) -> usize {
+    context.as_ptr() as usize
+}
+
+impl Registry {
+    fn insert(&mut self, timeout: Timeout) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let deadlines = match timeout.clock {
+            CLOCK_MONOTONIC => &mut self.monotonic,
+            CLOCK_REALTIME => &mut self.realtime,
+            clock => {
+                // Fires on the next trigger
+                println!("timeout::register: unknown clock {}", clock);
+                self.monotonic.push(Reverse((0, id)));
+                self.timeouts.insert(id, timeout);
+                return id;
+            }
+        };
+        deadlines.push(Reverse((timeout.time, id)));
+
+        if let Target::Wakeup(ref context) = timeout.target {
+            // A context sleeps once at a time, so a newer wakeup replaces any stale one
+            if let Some(old) = self.wakeups.insert(context_key(context), id) {
+                self.timeouts.remove(&old);
+            }
+        }
+        self.timeouts.insert(id, timeout);
+        self.compact();
+        id
+    }
+
+    fn cancel(&mut self, id: u64) {
+        if let Some(timeout) = self.timeouts.remove(&id) {
+            if let Target::Wakeup(ref context) = timeout.target {
+                self.wakeups.remove(&context_key(context));
+            }
+            self.compact();
+        }
+    }
+
+    fn cancel_wakeup(&mut self, context: &Weak<ContextLock>) {
+        if let Some(id) = self.wakeups.remove(&context_key(context)) {
+            self.timeouts.remove(&id);
+            self.compact();
+        }
+    }
+
+    /// Rebuild the heaps once cancelled entries outnumber the pending ones, so that waiters
+    /// woken early cannot grow them without bound.
+    fn compact(&mut self) {
+        let stale = self.monotonic.len() + self.realtime.len() - self.timeouts.len();
+        if stale <= self.timeouts.len().max(64) {
+            return;
+        }
+        let timeouts = &self.timeouts;
+        self.monotonic
+            .retain(|Reverse((_, id))| timeouts.contains_key(id));
+        self.realtime
+            .retain(|Reverse((_, id))| timeouts.contains_key(id));
+    }
+
+    /// Drop cancelled entries from the top of `deadlines` and return the earliest pending one
+    fn peek(deadlines: &mut Deadlines, timeouts: &BTreeMap<u64, Timeout>) -> Option<(u128, u64)> {
+        while let Some(&Reverse((time, id))) = deadlines.peek() {
+            if timeouts.contains_key(&id) {
+                return Some((time, id));
+            }
+            deadlines.pop();
+        }
+        None
+    }
+
+    /// Remove and return one timeout that is due at the given clock readings
+    fn pop_due(&mut self, mono: u128, real: u128) -> Option<Timeout> {
+        for (deadlines, now) in [(&mut self.monotonic, mono), (&mut self.realtime, real)] {
+            match Self::peek(deadlines, &self.timeouts) {
+                Some((time, id)) if time <= now => {
+                    deadlines.pop();
+                    let periodic = self.timeouts.get_mut(&id).filter(|t| t.interval != 0);
+                    if let Some(timeout) = periodic {
+                        // Skip the periods missed since, which fire as one
+                        let periods = (now - time) / timeout.interval + 1;
+                        timeout.time = time + periods * timeout.interval;
+                        deadlines.push(Reverse((timeout.time, id)));
+                        return Some(Timeout {
+                            target: timeout.target.clone(),
+                            clock: timeout.clock,
+                            time,
+                            interval: timeout.interval,
+                        });
+                    }
+                    let timeout = self.timeouts.remove(&id)?;
+                    if let Target::Wakeup(ref context) = timeout.target {
+                        self.wakeups.remove(&context_key(context));
+                    }
+                    return Some(timeout);
+                }
+                _ => (),
+            }
+        }
+        None
+    }
+
+    /// Earliest pending deadline, with realtime deadlines converted to the monotonic clock
+    fn next_deadline(&mut self, mono: u128, real: u128) -> Option<u128> {
+        let mono_next = Self::peek(&mut self.monotonic, &self.timeouts).map(|(time, _)| time);
+        let real_next = Self::peek(&mut self.realtime, &self.timeouts)
+            .map(|(time, _)| (time + mono).saturating_sub(real));
+        match (mono_next, real_next) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
 
 static REGISTRY: Once<OrderedMutex<L1, Registry>> = Once::new();
 
 /// Initialize registry, called if needed
 fn init_registry() -> OrderedMutex<L1, Registry> {
-    OrderedMutex::new(Registry::new())
+    OrderedMutex::new(Registry::default())
 }
 
 /// Get the global timeouts list
@@ -42,54 +198,294 @@ pub fn register(
     token: &mut CleanLockToken,
 ) {
     let mut registry = registry(token.token());
-    registry.push_back(Timeout {
-        scheme_id,
-        event_id,
+    registry.insert(Timeout {
+        target: Target::Event {
+            scheme_id,
+            event_id,
+        },
         clock,
         time: (time.tv_sec as u128 * time::NANOS_PER_SEC) + (time.tv_nsec as u128),
+        interval: 0,
+    });
+}
+
+/// Trigger a read event on a scheme handle once `clock` reaches `time`, and every `interval`
+/// nanoseconds after that, returning the id to [`cancel`] it with.
+///
+/// An `interval` of 0 fires once.
+pub fn register_periodic(
+    scheme_id: SchemeId,
+    event_id: usize,
+    clock: usize,
+    time: u128,
+    interval: u128,
+    token: &mut CleanLockToken,
+) -> u64 {
+    let mut registry = registry(token.token());
+    registry.insert(Timeout {
+        target: Target::Event {
+            scheme_id,
+            event_id,
+        },
+        clock,
+        time,
+        interval,
+    })
+}
+
+/// Remove the timeout with the given id, if it has not fired for the last time yet
+pub fn cancel(id: u64, token: &mut CleanLockToken) {
+    registry(token.token()).cancel(id);
+}
+
+/// Unblock `context` once `clock` reaches `time`, in nanoseconds.
+///
+/// The waiter must call [`cancel_wakeup`] after resuming, so that a stale timeout cannot cut a
+/// later, unrelated sleep short.
+pub fn register_wakeup(
+    context: Weak<ContextLock>,
+    clock: usize,
+    time: u128,
+    token: &mut CleanLockToken,
+) {
+    let mut registry = registry(token.token());
+    registry.insert(Timeout {
+        target: Target::Wakeup(context),
+        clock,
+        time,
+        interval: 0,
     });
 }
 
+/// Remove any pending wakeup registered for `context`
+pub fn cancel_wakeup(context: &Weak<ContextLock>, token: &mut CleanLockToken) {
+    registry(token.token()).cancel_wakeup(context);
+}
+
+/// Earliest pending timeout on the monotonic clock, in nanoseconds, if any
+pub fn next_deadline(token: &mut CleanLockToken) -> Option<u128> {
+    let mono = time::monotonic();
+    let real = time::realtime();
+    registry(token.token()).next_deadline(mono, real)
+}
+
 pub fn trigger(token: &mut CleanLockToken) {
     let mono = time::monotonic();
     let real = time::realtime();
 
-    let mut i = 0;
     loop {
         // Acquire registry lock for this iteration and possibly remove a timeout
-        let timeout_opt = {
-            let mut registry = registry(token.token());
-            if i < registry.len() {
-                let trigger = match registry[i].clock {
-                    CLOCK_MONOTONIC => {
-                        let time = registry[i].time;
-                        mono >= time
-                    }
-                    CLOCK_REALTIME => {
-                        let time = registry[i].time;
-                        real >= time
-                    }
-                    clock => {
-                        println!("timeout::trigger: unknown clock {}", clock);
-                        true
+        let timeout_opt = registry(token.token()).pop_due(mono, real);
+        match timeout_opt {
+            // Registry lock is dropped, safe to use token again
+            Some(timeout) => match timeout.target {
+                Target::Event {
+                    scheme_id,
+                    event_id,
+                } => event::trigger(scheme_id, event_id, EVENT_READ, token),
+                Target::Wakeup(context) => {
+                    if let Some(context) = context.upgrade() {
+                        scheduler::unblock(&context, token);
                     }
-                };
-                if trigger {
-                    Some(registry.remove(i).unwrap())
-                } else {
-                    i += 1;
-                    None
                 }
+            },
+            None => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{collections::VecDeque, vec::Vec};
+
+    fn event(id: usize, clock: usize, time: u128) -> Timeout {
+        Timeout {
+            target: Target::Event {
+                scheme_id: SchemeId::new(0),
+                event_id: id,
+            },
+            clock,
+            time,
+            interval: 0,
+        }
+    }
+
+    fn event_id(timeout: Timeout) -> usize {
+        match timeout.target {
+            Target::Event { event_id, .. } => event_id,
+            Target::Wakeup(_) => panic!("expected an event timeout"),
+        }
+    }
+
+    fn drain(registry: &mut Registry, mono: u128, real: u128) -> Vec<usize> {
+        core::iter::from_fn(|| registry.pop_due(mono, real))
+            .map(event_id)
+            .collect()
+    }
+
+    #[test]
+    fn test_pop_due_in_deadline_order() {
+        let mut registry = Registry::default();
+        registry.insert(event(3, CLOCK_MONOTONIC, 30));
+        registry.insert(event(1, CLOCK_MONOTONIC, 10));
+        registry.insert(event(2, CLOCK_MONOTONIC, 20));
+
+        assert_eq!(drain(&mut registry, 5, 0), []);
+        assert_eq!(drain(&mut registry, 20, 0), [1, 2]);
+        assert_eq!(drain(&mut registry, 100, 0), [3]);
+        assert!(registry.timeouts.is_empty());
+    }
+
+    #[test]
+    fn test_realtime_uses_own_clock() {
+        let mut registry = Registry::default();
+        registry.insert(event(1, CLOCK_REALTIME, 1_000));
+        registry.insert(event(2, CLOCK_MONOTONIC, 50));
+
+        // Realtime runs 900ns ahead of monotonic
+        assert_eq!(registry.next_deadline(0, 900), Some(50));
+        assert_eq!(drain(&mut registry, 60, 960), [2]);
+        assert_eq!(registry.next_deadline(60, 960), Some(100));
+        assert_eq!(drain(&mut registry, 100, 1_000), [1]);
+        assert_eq!(registry.next_deadline(100, 1_000), None);
+    }
+
+    #[test]
+    fn test_cancel_wakeup() {
+        let mut registry = Registry::default();
+        let context = Weak::new();
+        registry.insert(Timeout {
+            target: Target::Wakeup(context.clone()),
+            clock: CLOCK_MONOTONIC,
+            time: 10,
+            interval: 0,
+        });
+        registry.insert(event(1, CLOCK_MONOTONIC, 20));
+
+        registry.cancel_wakeup(&context);
+        assert_eq!(registry.next_deadline(0, 0), Some(20));
+        assert_eq!(drain(&mut registry, 100, 0), [1]);
+        assert!(registry.wakeups.is_empty());
+    }
+
+    #[test]
+    fn test_wakeup_replaces_stale_one() {
+        let mut registry = Registry::default();
+        let context = Weak::new();
+        for time in [10, 50] {
+            registry.insert(Timeout {
+                target: Target::Wakeup(context.clone()),
+                clock: CLOCK_MONOTONIC,
+                time,
+                interval: 0,
+            });
+        }
+
+        assert_eq!(registry.timeouts.len(), 1);
+        assert_eq!(registry.next_deadline(0, 0), Some(50));
+    }
+
+    #[test]
+    fn test_periodic_refires_until_cancelled() {
+        let mut registry = Registry::default();
+        let id = registry.insert(Timeout {
+            interval: 10,
+            ..event(1, CLOCK_MONOTONIC, 100)
+        });
+
+        assert_eq!(drain(&mut registry, 100, 0), [1]);
+        assert_eq!(registry.next_deadline(100, 0), Some(110));
+        // Periods missed in between fire once
+        assert_eq!(drain(&mut registry, 135, 0), [1]);
+        assert_eq!(registry.next_deadline(135, 0), Some(140));
+
+        registry.cancel(id);
+        assert_eq!(drain(&mut registry, 1_000, 0), []);
+        assert_eq!(registry.next_deadline(1_000, 0), None);
+    }
+
+    #[test]
+    fn test_compact_drops_cancelled() {
+        let mut registry = Registry::default();
+        let context = Weak::new();
+        for time in 0..1_000 {
+            registry.insert(Timeout {
+                target: Target::Wakeup(context.clone()),
+                clock: CLOCK_MONOTONIC,
+                time,
+                interval: 0,
+            });
+            registry.cancel_wakeup(&context);
+        }
+
+        assert!(registry.timeouts.is_empty());
+        assert!(registry.monotonic.len() <= 64);
+    }
+
+    /// The linear scan the registry replaced, kept as a baseline for the benchmark
+    fn linear_trigger(registry: &mut VecDeque<Timeout>, mono: u128) -> usize {
+        let mut fired = 0;
+        let mut i = 0;
+        while i < registry.len() {
+            if mono >= registry[i].time {
+                registry.remove(i);
+                fired += 1;
             } else {
-                None
-            }
-        };
-        match timeout_opt {
-            Some(timeout) => {
-                // Registry lock is dropped, safe to use token again
-                event::trigger(timeout.scheme_id, timeout.event_id, EVENT_READ, token);
+                i += 1;
             }
-            None => break,
         }
+        fired
+    }
+
+    /// Compare against the linear scan with 10k sleepers, one tick per 10 deadlines and every
+    /// other sleeper woken early. Run with `cargo test -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_10k_sleepers() {
+        const SLEEPERS: usize = 10_000;
+        const TICK: u128 = 10;
+        use std::{println, time::Instant};
+
+        // Deadlines spread over 0..SLEEPERS in a scrambled order
+        let deadline = |i: usize| (i as u128 * 7919) % SLEEPERS as u128;
+
+        let start = Instant::now();
+        let mut linear = VecDeque::new();
+        for i in 0..SLEEPERS {
+            linear.push_back(event(i, CLOCK_MONOTONIC, deadline(i)));
+        }
+        for i in (0..SLEEPERS).step_by(2) {
+            linear.retain(|timeout: &Timeout| {
+                !matches!(timeout.target, Target::Event { event_id, .. } if event_id == i)
+            });
+        }
+        let mut fired = 0;
+        for tick in 0..=SLEEPERS as u128 / TICK {
+            fired += linear_trigger(&mut linear, tick * TICK);
+        }
+        assert_eq!(fired, SLEEPERS / 2);
+        let linear_time = start.elapsed();
+
+        let start = Instant::now();
+        let mut registry = Registry::default();
+        let mut ids = Vec::with_capacity(SLEEPERS);
+        for i in 0..SLEEPERS {
+            ids.push(registry.insert(event(i, CLOCK_MONOTONIC, deadline(i))));
+        }
+        for id in ids.into_iter().step_by(2) {
+            registry.timeouts.remove(&id);
+        }
+        let mut fired = 0;
+        for tick in 0..=SLEEPERS as u128 / TICK {
+            fired += drain(&mut registry, tick * TICK, 0).len();
+        }
+        assert_eq!(fired, SLEEPERS / 2);
+        let heap_time = start.elapsed();
+
+        println!(
+            "{} sleepers: linear scan {:?}, heap {:?}",
+            SLEEPERS, linear_time, heap_time
+        );
     }
 }
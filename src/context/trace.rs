@@ -0,0 +1,320 @@
+//! # Context Switch Tracing
+//!
+//! While enabled, every context switch appends a [`TraceRecord`] to a ring owned by the CPU it
+//! happens on, and sys:trace drains the rings. Only the CPU itself writes to its ring, and the
+//! write never waits for the reader: a full ring overwrites its oldest records, which the reader
+//! counts as dropped.
+//!
+//! ## Format
+//!
+//! A sys:trace stream starts with a 16 byte header:
+//!
+//! | Offset | Size | Field                                   |
+//! |--------|------|-----------------------------------------|
+//! | 0      | 4    | magic, `KTRC`                           |
+//! | 4      | 2    | format version, currently 1             |
+//! | 6      | 2    | record size in bytes, currently 32      |
+//! | 8      | 8    | ring capacity in records, per CPU       |
+//!
+//! followed by fixed size records, each CPU's in the order they were written:
+//!
+//! | Offset | Size | Field                                                       |
+//! |--------|------|-------------------------------------------------------------|
+//! | 0      | 8    | monotonic time of the switch in nanoseconds                 |
+//! | 8      | 8    | ID of the context switched away from, 0 for the kmain loop  |
+//! | 16     | 8    | ID of the context switched to                               |
+//! | 24     | 4    | logical CPU ID                                              |
+//! | 28     | 1    | effective priority of the context switched away from        |
+//! | 29     | 1    | reason, one of the `REASON_*` constants                     |
+//! | 30     | 2    | reserved, 0                                                 |
+//!
+//! A record with reason [`REASON_DROPPED`] reports records of its CPU lost to overwriting since
+//! the last one, with the count in place of the context switched away from. All fields are in
+//! native byte order.
+
+use alloc::boxed::Box;
+use core::{
+    cell::UnsafeCell,
+    ptr,
+    sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+
+use crate::{
+    context::{Context, Status},
+    cpu_set::{LogicalCpuId, MAX_CPU_COUNT},
+    percpu, time,
+};
+
+/// Number of records each CPU's ring holds before the oldest are overwritten
+pub const TRACE_CAPACITY: usize = 1024;
+
+/// The context switched away from was still runnable
+pub const REASON_PREEMPT: u8 = 0;
+/// The context switched away from blocked
+pub const REASON_BLOCK: u8 = 1;
+/// The context switched away from exited
+pub const REASON_EXIT: u8 = 2;
+/// The CPU left the kmain loop for its first context
+pub const REASON_IDLE: u8 = 3;
+/// Not a switch, but a count of overwritten records
+pub const REASON_DROPPED: u8 = 0xFF;
+
+/// A fixed size record in the sys:trace stream, see the module documentation
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub time: u64,
+    pub from: u64,
+    pub to: u64,
+    pub cpu: u32,
+    pub from_prio: u8,
+    pub reason: u8,
+    pub _rsvd: u16,
+}
+
+struct Slot {
+    /// One more than the index of the record in the slot, or `usize::MAX` while it is written
+    stamp: AtomicUsize,
+    record: UnsafeCell<TraceRecord>,
+}
+
+/// A ring of switch records with a single writer, its CPU, and a single reader
+pub struct TraceRing {
+    /// Number of records ever written
+    tail: AtomicUsize,
+    /// Number of records read or skipped by the reader
+    head: AtomicUsize,
+    /// Records overwritten before the reader got to them, since it last collected this count
+    dropped: AtomicU64,
+    slots: [Slot; TRACE_CAPACITY],
+}
+
+unsafe impl Sync for TraceRing {}
+
+impl TraceRing {
+    fn new() -> Box<Self> {
+        // All-zero slots hold no records, as no stamp is 0
+        unsafe { Box::new_zeroed().assume_init() }
+    }
+
+    /// Append a record, overwriting the oldest if the ring is full. This never waits.
+    ///
+    /// Must only be called by the ring's own CPU.
+    fn push(&self, record: TraceRecord) {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let slot = &self.slots[tail % TRACE_CAPACITY];
+        slot.stamp.store(usize::MAX, Ordering::Relaxed);
+        fence(Ordering::Release);
+        unsafe { ptr::write_volatile(slot.record.get(), record) };
+        slot.stamp.store(tail.wrapping_add(1), Ordering::Release);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+    }
+
+    /// Take the oldest record that has not been overwritten.
+    ///
+    /// # Safety
+    ///
+    /// There must be no other reader at the same time.
+    unsafe fn pop(&self) -> Option<TraceRecord> {
+        loop {
+            let tail = self.tail.load(Ordering::Acquire);
+            let mut head = self.head.load(Ordering::Relaxed);
+            if tail.wrapping_sub(head) > TRACE_CAPACITY {
+                let skipped = tail.wrapping_sub(TRACE_CAPACITY).wrapping_sub(head);
+                self.dropped.fetch_add(skipped as u64, Ordering::Relaxed);
+                head = tail.wrapping_sub(TRACE_CAPACITY);
+            }
+            if head == tail {
+                self.head.store(head, Ordering::Relaxed);
+                return None;
+            }
+            self.head.store(head.wrapping_add(1), Ordering::Relaxed);
+
+            // The writer may lap the reader at any point, in which case the stamp changes
+            let slot = &self.slots[head % TRACE_CAPACITY];
+            let stamp = slot.stamp.load(Ordering::Acquire);
+            let record = unsafe { ptr::read_volatile(slot.record.get()) };
+            fence(Ordering::Acquire);
+            if stamp == head.wrapping_add(1) && slot.stamp.load(Ordering::Relaxed) == stamp {
+                return Some(record);
+            }
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Take the number of records overwritten since the last call
+    fn take_dropped(&self) -> u64 {
+        self.dropped.swap(0, Ordering::Relaxed)
+    }
+
+    /// Discard every record written so far.
+    ///
+    /// # Safety
+    ///
+    /// There must be no other reader at the same time.
+    unsafe fn clear(&self) {
+        self.head
+            .store(self.tail.load(Ordering::Acquire), Ordering::Relaxed);
+        self.dropped.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Each CPU's ring, allocated the first time tracing starts
+static RINGS: [AtomicPtr<TraceRing>; MAX_CPU_COUNT] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPU_COUNT];
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Serializes readers of the rings, each of which allows only one at a time, and their allocation
+static DRAIN_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+
+fn ring(cpu: usize) -> Option<&'static TraceRing> {
+    unsafe { RINGS.get(cpu)?.load(Ordering::Acquire).as_ref() }
+}
+
+/// Start recording switches, allocating the rings of CPUs that do not have one yet
+pub fn start() {
+    // Also keeps concurrent starts from both allocating a ring
+    let _guard = DRAIN_LOCK.lock();
+    for (cpu, ring) in RINGS.iter().enumerate() {
+        let up = percpu::get_percpu_block(LogicalCpuId::new(cpu as u32)).is_some();
+        if up && ring.load(Ordering::Acquire).is_null() {
+            ring.store(Box::into_raw(TraceRing::new()), Ordering::Release);
+        }
+    }
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Stop recording switches, keeping the records not read yet
+pub fn stop() {
+    ENABLED.store(false, Ordering::Release);
+}
+
+/// Discard all records not read yet, and the counts of dropped ones
+pub fn clear() {
+    let _guard = DRAIN_LOCK.lock();
+    for cpu in 0..MAX_CPU_COUNT {
+        if let Some(ring) = ring(cpu) {
+            // DRAIN_LOCK is held, so this is the only reader
+            unsafe { ring.clear() };
+        }
+    }
+}
+
+/// Record a switch on the current CPU from context `from`, or from the kmain loop if there is
+/// none, to the context with ID `to`
+pub fn record_switch(from: Option<&Context>, to: usize) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let cpu = crate::cpu_id().get();
+    let Some(ring) = ring(cpu as usize) else {
+        return;
+    };
+    let (from, from_prio, reason) = match from {
+        Some(context) => (
+            context.id(),
+            context.priority.effective_priority(),
+            match context.status {
+                Status::Runnable => REASON_PREEMPT,
+                Status::Blocked | Status::HardBlocked { .. } => REASON_BLOCK,
+                Status::Zombie { .. } | Status::Dead { .. } => REASON_EXIT,
+            },
+        ),
+        None => (0, 0, REASON_IDLE),
+    };
+    ring.push(TraceRecord {
+        time: time::monotonic() as u64,
+        from: from as u64,
+        to: to as u64,
+        cpu,
+        from_prio,
+        reason,
+        _rsvd: 0,
+    });
+}
+
+/// Call `emit` with the records of every CPU, oldest first per CPU, until it returns false. Each
+/// CPU that lost records since the last drain first gets a [`REASON_DROPPED`] record.
+pub fn drain(mut emit: impl FnMut(TraceRecord) -> bool) {
+    let _guard = DRAIN_LOCK.lock();
+    for cpu in 0..MAX_CPU_COUNT {
+        let Some(ring) = ring(cpu) else {
+            continue;
+        };
+        // DRAIN_LOCK is held, so this is the only reader
+        while let Some(record) = unsafe { ring.pop() } {
+            let dropped = ring.take_dropped();
+            if dropped > 0 {
+                let report = TraceRecord {
+                    from: dropped,
+                    cpu: cpu as u32,
+                    reason: REASON_DROPPED,
+                    ..TraceRecord::default()
+                };
+                if !emit(report) {
+                    ring.dropped.fetch_add(dropped, Ordering::Relaxed);
+                    return;
+                }
+            }
+            if !emit(record) {
+                // Left for the next drain, which counts it as dropped if it is overwritten first
+                ring.head.fetch_sub(1, Ordering::Relaxed);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn switch(to: u64) -> TraceRecord {
+        TraceRecord {
+            to,
+            ..TraceRecord::default()
+        }
+    }
+
+    #[test]
+    fn test_trace_ring_order() {
+        let ring = TraceRing::new();
+        ring.push(switch(1));
+        ring.push(switch(2));
+        unsafe {
+            assert_eq!(ring.pop(), Some(switch(1)));
+            assert_eq!(ring.pop(), Some(switch(2)));
+            assert_eq!(ring.pop(), None);
+        }
+        assert_eq!(ring.take_dropped(), 0);
+    }
+
+    #[test]
+    fn test_trace_ring_overwrites_oldest() {
+        let ring = TraceRing::new();
+        for to in 0..TRACE_CAPACITY as u64 + 3 {
+            ring.push(switch(to));
+        }
+
+        // The newest records are kept, and the three overwritten ones are counted
+        assert_eq!(unsafe { ring.pop() }, Some(switch(3)));
+        assert_eq!(ring.take_dropped(), 3);
+        assert_eq!(ring.take_dropped(), 0);
+        ring.push(switch(100));
+        assert_eq!(ring.take_dropped(), 0);
+    }
+
+    #[test]
+    fn test_trace_ring_clear() {
+        let ring = TraceRing::new();
+        for to in 0..TRACE_CAPACITY as u64 * 2 {
+            ring.push(switch(to));
+        }
+        unsafe {
+            ring.clear();
+            assert_eq!(ring.pop(), None);
+        }
+        assert_eq!(ring.take_dropped(), 0);
+        ring.push(switch(7));
+        assert_eq!(unsafe { ring.pop() }, Some(switch(7)));
+    }
+}
@@ -0,0 +1,319 @@
+//! # Upcalls
+//!
+//! A lighter way than signals to deliver events to a context. The context registers an entry
+//! point and a small stack of its own, and any context allowed to may post it a 64-bit payload.
+//! The next time the target returns to usermode, the kernel saves the registers it was about to
+//! return with on the upcall stack and enters the handler with the payload instead. The handler
+//! resumes the interrupted code with `SYS_UPCALL_RETURN`, which restores those registers exactly,
+//! so there is no sigreturn trampoline or signal mask to maintain.
+//!
+//! Only one upcall runs at a time. Payloads posted while one is running, or before the target next
+//! returns to usermode, are queued up to [`UPCALL_QUEUE_MAX`] deep and delivered one after the
+//! other. Posting does not interrupt a blocking syscall, the upcall is delivered once it returns.
+//!
+//! ## Handler ABI (x86_64)
+//!
+//! The handler is entered with the payload in `rdi` and a pointer to the saved [`IntRegisters`] in
+//! `rsi`, with `rsp + 8` 16-byte aligned as if it had been called. It must not return, but end
+//! with `SYS_UPCALL_RETURN`, and may change the saved registers before that to resume elsewhere.
+//!
+//! The FPU and vector registers are saved in the kernel rather than on the upcall stack, so the
+//! handler may use them freely and they are restored along with the saved registers.
+//!
+//! Upcalls are delivered after the post-syscall ptrace stop, so a tracer sees the result of the
+//! interrupted syscall, and registers it changes there are the ones saved for the handler.
+
+use alloc::collections::VecDeque;
+use core::{
+    mem::size_of,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    arch::interrupt::InterruptStack,
+    context::{self, ContextRef},
+    ipi::{ipi_single, IpiKind},
+    percpu,
+    sync::CleanLockToken,
+    syscall::{
+        error::{Error, Result, EAGAIN, EBUSY, EINVAL, ENOSYS},
+        usercopy::UserSlice,
+    },
+};
+
+#[cfg(target_arch = "x86_64")]
+use crate::syscall::{error::EFAULT, IntRegisters};
+#[cfg(target_arch = "x86_64")]
+use alloc::{boxed::Box, vec};
+
+/// Number of payloads a context may have waiting before posting fails with EAGAIN
+pub const UPCALL_QUEUE_MAX: usize = 32;
+/// Smallest upcall stack that may be registered
+pub const UPCALL_STACK_MIN: usize = 1024;
+
+/// Payloads queued on any context, so that returning to usermode can skip the context lock when
+/// there are none
+static PENDING_UPCALLS: AtomicUsize = AtomicUsize::new(0);
+
+/// The upcall handler of a context
+#[derive(Debug)]
+pub struct UpcallState {
+    entry: usize,
+    stack_top: usize,
+    /// Address of the registers saved for the upcall running right now, if any
+    active_frame: Option<usize>,
+    /// Payloads waiting to be delivered, allocated up front so that posting never allocates
+    pending: VecDeque<u64>,
+    /// Registers read back by `SYS_UPCALL_RETURN`, for the syscall return path to resume with
+    #[cfg(target_arch = "x86_64")]
+    restored: Option<IntRegisters>,
+    /// FPU state of the interrupted code while an upcall runs, the size of `Context::kfx`
+    #[cfg(target_arch = "x86_64")]
+    saved_fx: Box<[u8]>,
+}
+
+impl Drop for UpcallState {
+    fn drop(&mut self) {
+        PENDING_UPCALLS.fetch_sub(self.pending.len(), Ordering::Relaxed);
+    }
+}
+
+/// Register the upcall handler of the current context, or unregister it if `entry` is 0.
+/// Payloads already queued are kept when a handler is replaced.
+pub fn register(
+    entry: usize,
+    stack_base: usize,
+    stack_size: usize,
+    token: &mut CleanLockToken,
+) -> Result<()> {
+    if !cfg!(target_arch = "x86_64") {
+        return Err(Error::new(ENOSYS));
+    }
+    if entry != 0 {
+        if entry >= crate::USER_END_OFFSET || stack_size < UPCALL_STACK_MIN {
+            return Err(Error::new(EINVAL));
+        }
+        UserSlice::rw(stack_base, stack_size)?;
+    }
+    let stack_top = stack_base.saturating_add(stack_size);
+    let pending = VecDeque::with_capacity(UPCALL_QUEUE_MAX);
+    #[cfg(target_arch = "x86_64")]
+    let saved_fx = vec![0; crate::arch::alternative::kfx_size()].into_boxed_slice();
+
+    let current = context::current();
+    let mut context = current.write(token.token());
+    if context
+        .upcall
+        .as_ref()
+        .is_some_and(|state| state.active_frame.is_some())
+    {
+        return Err(Error::new(EBUSY));
+    }
+    if entry == 0 {
+        context.upcall = None;
+    } else if let Some(state) = context.upcall.as_mut() {
+        state.entry = entry;
+        state.stack_top = stack_top;
+    } else {
+        context.upcall = Some(UpcallState {
+            entry,
+            stack_top,
+            active_frame: None,
+            pending,
+            #[cfg(target_arch = "x86_64")]
+            restored: None,
+            #[cfg(target_arch = "x86_64")]
+            saved_fx,
+        });
+    }
+    Ok(())
+}
+
+/// Queue `payload` for the upcall handler of `target`, kicking its CPU if it is running elsewhere
+/// so that the upcall is delivered without waiting for the next timer tick
+pub fn post(target: &ContextRef, payload: u64, token: &mut CleanLockToken) -> Result<()> {
+    let cpu = {
+        let mut context = target.write(token.token());
+        let state = context.upcall.as_mut().ok_or(Error::new(EINVAL))?;
+        if state.pending.len() >= UPCALL_QUEUE_MAX {
+            return Err(Error::new(EAGAIN));
+        }
+        state.pending.push_back(payload);
+        PENDING_UPCALLS.fetch_add(1, Ordering::Relaxed);
+        context.cpu_id.filter(|_| context.running)
+    };
+
+    if let Some(cpu) = cpu.filter(|&cpu| cpu != crate::cpu_id())
+        && let Some(block) = percpu::get_percpu_block(cpu)
+    {
+        ipi_single(IpiKind::Wakeup, block);
+    }
+    Ok(())
+}
+
+/// Where the registers are saved on an upcall stack ending at `stack_top`
+#[cfg(target_arch = "x86_64")]
+fn frame_address(stack_top: usize) -> usize {
+    stack_top.saturating_sub(size_of::<IntRegisters>()) & !15
+}
+
+/// The flags of `rflags` userspace may choose when resuming, with interrupts enabled
+#[cfg(target_arch = "x86_64")]
+fn user_rflags(rflags: u64) -> u64 {
+    use x86::bits64::rflags::RFlags;
+
+    let user = RFlags::FLAGS_CF
+        | RFlags::FLAGS_PF
+        | RFlags::FLAGS_AF
+        | RFlags::FLAGS_ZF
+        | RFlags::FLAGS_SF
+        | RFlags::FLAGS_TF
+        | RFlags::FLAGS_DF
+        | RFlags::FLAGS_OF
+        | RFlags::FLAGS_AC;
+    (rflags & user.bits()) | (RFlags::FLAGS_IF | RFlags::FLAGS_A1).bits()
+}
+
+/// Enter the upcall handler of the current context instead of returning to `stack`, if it has
+/// a payload waiting and is not already in an upcall. Must be called last before returning to
+/// usermode.
+pub fn deliver(stack: &mut InterruptStack, token: &mut CleanLockToken) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        use x86::bits64::rflags::RFlags;
+
+        if stack.cs & 3 != 3 || PENDING_UPCALLS.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+
+        let current = context::current();
+        let (entry, frame, payload) = {
+            let mut context = current.write(token.token());
+            let context = &mut *context;
+            let Some(state) = context.upcall.as_mut() else {
+                return;
+            };
+            if state.active_frame.is_some() {
+                return;
+            }
+            let Some(payload) = state.pending.pop_front() else {
+                return;
+            };
+            PENDING_UPCALLS.fetch_sub(1, Ordering::Relaxed);
+            let frame = frame_address(state.stack_top);
+            state.active_frame = Some(frame);
+            let entry = state.entry;
+
+            // The handler starts with the FPU state as it is, and the interrupted code gets it back
+            unsafe { context.flush_fx() };
+            if let Some(state) = context.upcall.as_mut() {
+                state.saved_fx.copy_from_slice(&context.kfx);
+            }
+            (entry, frame, payload)
+        };
+
+        let mut saved = IntRegisters::default();
+        stack.save_to(&mut saved);
+        let bytes = unsafe {
+            core::slice::from_raw_parts((&raw const saved).cast::<u8>(), size_of::<IntRegisters>())
+        };
+        let copied = UserSlice::wo(frame, bytes.len()).and_then(|slice| slice.copy_exactly(bytes));
+        if let Err(err) = copied {
+            // The stack is gone, and so is any hope of delivering on it
+            warn!(
+                "upcall: unregistering handler with unusable stack at {:#x}: {}",
+                frame, err
+            );
+            current.write(token.token()).upcall = None;
+            return;
+        }
+
+        stack.rip = entry as u64;
+        stack.rsp = frame.saturating_sub(8) as u64;
+        stack.rdi = payload;
+        stack.rsi = frame as u64;
+        stack.rflags &= !(RFlags::FLAGS_DF | RFlags::FLAGS_AC).bits();
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = (stack, token);
+}
+
+/// Finish the running upcall, reading back the registers saved for it to resume with once the
+/// syscall returns
+#[cfg(target_arch = "x86_64")]
+pub fn upcall_return(token: &mut CleanLockToken) -> Result<()> {
+    let current = context::current();
+    let frame = current
+        .read(token.token())
+        .upcall
+        .as_ref()
+        .and_then(|state| state.active_frame)
+        .ok_or(Error::new(EINVAL))?;
+
+    let regs =
+        unsafe { UserSlice::ro(frame, size_of::<IntRegisters>())?.read_exact::<IntRegisters>()? };
+    let (rip, rsp) = (regs.rip, regs.rsp);
+    // Both are loaded with iretq, which faults in the kernel if they are not canonical
+    if rip >= crate::USER_END_OFFSET || rsp >= crate::USER_END_OFFSET {
+        return Err(Error::new(EFAULT));
+    }
+
+    let mut context = current.write(token.token());
+    let state = context.upcall.as_mut().ok_or(Error::new(EINVAL))?;
+    state.active_frame = None;
+    state.restored = Some(regs);
+    Ok(())
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub fn upcall_return(_token: &mut CleanLockToken) -> Result<()> {
+    Err(Error::new(ENOSYS))
+}
+
+/// Load the registers read back by a successful `SYS_UPCALL_RETURN` into `stack`. Returns whether
+/// there were any, in which case every register must be restored, including those sysretq
+/// clobbers.
+#[cfg(target_arch = "x86_64")]
+pub fn apply_return(stack: &mut InterruptStack, token: &mut CleanLockToken) -> bool {
+    let current = context::current();
+    let regs = {
+        let mut context = current.write(token.token());
+        let context = &mut *context;
+        let Some(regs) = context
+            .upcall
+            .as_mut()
+            .and_then(|state| state.restored.take())
+        else {
+            return false;
+        };
+        // Drop whatever the handler left in the FPU registers, the next use reloads `kfx`
+        unsafe { context.flush_fx() };
+        if let Some(state) = context.upcall.as_ref() {
+            context.kfx.copy_from_slice(&state.saved_fx);
+        }
+        regs
+    };
+    stack.load_from(&regs);
+    stack.rflags = user_rflags(stack.rflags);
+    true
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upcall_frame_alignment() {
+        let frame = frame_address(0x7000_0000);
+        assert_eq!(frame % 16, 0);
+        assert!(frame + size_of::<IntRegisters>() <= 0x7000_0000);
+        assert_eq!(frame_address(0x7000_0008), frame);
+    }
+
+    #[test]
+    fn test_upcall_rflags_sanitized() {
+        // IOPL, NT and VM are dropped, IF and the reserved bit are always set
+        assert_eq!(user_rflags(0x3_3000), 0x202);
+        assert_eq!(user_rflags(0x4_0DD5), 0x4_0FD7);
+    }
+}
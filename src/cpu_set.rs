@@ -7,6 +7,8 @@ pub const CPU_COUNT: usize = 128;
 type Word = u64;
 const WORD_BITS: usize = mem::size_of::<Word>() * 8;
 const WORD_COUNT: usize = (CPU_COUNT + WORD_BITS - 1) / WORD_BITS;
+// Masks are written as a single hexadecimal number
+const _: () = assert!(CPU_COUNT <= u128::BITS as usize);
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
 pub struct LogicalCpuId(pub u32);
@@ -38,6 +40,12 @@ impl core::fmt::Display for CpuSet {
     }
 }
 
+impl core::fmt::LowerHex for CpuSet {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::LowerHex::fmt(&self.bits(), f)
+    }
+}
+
 impl CpuSet {
     pub const fn new() -> Self {
         Self {
@@ -112,6 +120,32 @@ impl CpuSet {
     pub fn to_raw(&self) -> RawMask {
         self.mask
     }
+
+    /// Parse a mask written as a hexadecimal number, with CPU 0 as the lowest bit
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        let hex = hex.strip_prefix("0x").unwrap_or(hex);
+        let bits = u128::from_str_radix(hex, 16).ok()?;
+        let mut set = Self::new();
+        for (word, shift) in set.mask.iter_mut().zip((0..u128::BITS).step_by(WORD_BITS)) {
+            *word = (bits >> shift) as Word;
+        }
+        Some(set)
+    }
+
+    fn bits(&self) -> u128 {
+        self.mask
+            .iter()
+            .zip((0..u128::BITS).step_by(WORD_BITS))
+            .fold(0, |bits, (&word, shift)| bits | (u128::from(word) << shift))
+    }
+
+    /// Whether every CPU in this set is in `other` as well
+    pub fn is_subset(&self, other: &Self) -> bool {
+        self.mask
+            .iter()
+            .zip(&other.mask)
+            .all(|(word, other)| word & !other == 0)
+    }
 }
 
 impl Default for CpuSet {
@@ -127,3 +161,28 @@ pub type RawMask = [u64; WORD_COUNT];
 pub fn mask_as_bytes(mask: &RawMask) -> &[u8] {
     unsafe { core::slice::from_raw_parts(mask.as_ptr() as *const u8, mem::size_of::<RawMask>()) }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cpu_set_hex() {
+        let set = CpuSet::from_hex("0x80000000000000005").unwrap();
+        assert!(set.contains(LogicalCpuId::new(0)));
+        assert!(!set.contains(LogicalCpuId::new(1)));
+        assert!(set.contains(LogicalCpuId::new(2)));
+        assert!(set.contains(LogicalCpuId::new(67)));
+        assert_eq!(format!("{:x}", set), "80000000000000005");
+        assert_eq!(CpuSet::from_hex("f"), CpuSet::from_hex("0xF"));
+        assert_eq!(CpuSet::from_hex(""), None);
+        assert_eq!(CpuSet::from_hex("xyz"), None);
+        // One digit too many for 128 CPUs
+        assert_eq!(CpuSet::from_hex(&"1".repeat(33)), None);
+
+        let mut all = CpuSet::new();
+        (0..4).for_each(|id| all.add(LogicalCpuId::new(id)));
+        assert!(CpuSet::from_hex("5").unwrap().is_subset(&all));
+        assert!(!CpuSet::from_hex("10").unwrap().is_subset(&all));
+    }
+}
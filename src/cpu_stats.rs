@@ -6,8 +6,11 @@ use crate::cpu_set::LogicalCpuId;
 // Note: Using AtomicUsize rather than AtomicU64 as 32bit x86 doesn't support the latter
 /// The number of times (overall) where a CPU switched from one context to another.
 static CONTEXT_SWITCH_COUNT: AtomicUsize = AtomicUsize::new(0);
+/// Number of interrupt numbers tracked individually.
+pub const IRQ_VECTOR_COUNT: usize = 256;
 /// Number of times each Interrupt happened.
-static IRQ_COUNT: [AtomicUsize; 256] = [const { AtomicUsize::new(0) }; 256];
+static IRQ_COUNT: [AtomicUsize; IRQ_VECTOR_COUNT] =
+    [const { AtomicUsize::new(0) }; IRQ_VECTOR_COUNT];
 /// Number of contexts that were created.
 static CONTEXTS_COUNT: AtomicUsize = AtomicUsize::new(0);
 
@@ -25,7 +28,7 @@ pub enum CpuState {
 }
 
 /// Statistics for the CPUs.
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct CpuStats {
     /// Number of ticks spent on userspace contexts
     user: AtomicU64,
@@ -37,6 +40,8 @@ pub struct CpuStats {
     idle: AtomicU64,
     /// Number of times the CPU handled an interrupt
     irq: AtomicU64,
+    /// Number of times the CPU handled each interrupt
+    irq_vectors: [AtomicU64; IRQ_VECTOR_COUNT],
     /// Current state of the CPU
     state: AtomicU8,
 }
@@ -50,6 +55,7 @@ impl CpuStats {
             kernel: AtomicU64::new(0),
             idle: AtomicU64::new(0),
             irq: AtomicU64::new(0),
+            irq_vectors: [const { AtomicU64::new(0) }; IRQ_VECTOR_COUNT],
             state: AtomicU8::new(0),
         }
     }
@@ -105,8 +111,14 @@ impl CpuStats {
     #[inline]
     pub fn add_irq(&self, irq: u8) {
         IRQ_COUNT[irq as usize].fetch_add(1, Ordering::Relaxed);
+        self.irq_vectors[irq as usize].fetch_add(1, Ordering::Relaxed);
         self.irq.fetch_add(1, Ordering::Relaxed);
     }
+
+    /// Get the number of times this CPU handled each interrupt.
+    pub fn irq_vector_counts(&self) -> [u64; IRQ_VECTOR_COUNT] {
+        core::array::from_fn(|irq| self.irq_vectors[irq].load(Ordering::Relaxed))
+    }
 }
 
 impl CpuStatsData {
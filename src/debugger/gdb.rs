@@ -0,0 +1,819 @@
+//! GDB Remote Serial Protocol stub for user contexts
+//!
+//! Opening `debug:gdb/<pid>` attaches to the context and stops it. The handle carries the
+//! protocol byte stream: whatever relays it to a host-side gdb writes packets in and reads the
+//! replies back. Supported are `?`, `qSupported`, `g`/`G`, `m`/`M`, `c`/`s`, `Z0`/`z0`, `D` and
+//! `k`, as well as the interrupt byte, with acknowledgements per the protocol. Anything else gets
+//! the empty reply meaning unsupported.
+//!
+//! Software breakpoints and single steps trap into [`trap`], which parks the target until gdb
+//! resumes it. Closing the handle detaches, removing the breakpoints and resuming the target.
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::fmt::Write;
+
+use crate::{
+    arch::interrupt::InterruptStack,
+    context::{self, context::HardBlockedReason, memory::AddrSpaceWrapper, ContextLock, Status},
+    event,
+    memory::{ArchIntCtx, Frame},
+    paging::{Page, RmmA, RmmArch, VirtualAddress, PAGE_SIZE},
+    scheduler,
+    scheme::GlobalSchemes,
+    sync::{CleanLockToken, Mutex, RwLock, WaitCondition, L1},
+    syscall::{
+        error::{Error, Result, EAGAIN, EBADF, EBUSY, EFAULT, EINTR, ESRCH},
+        flag::{EventFlags, EVENT_READ},
+        usercopy::{UserSliceRo, UserSliceWo},
+    },
+};
+
+/// Largest packet accepted, advertised in the `qSupported` reply
+const PACKET_SIZE: usize = 0x1000;
+
+/// Signal reported for breakpoints and single steps
+const SIGTRAP: u8 = 5;
+/// Signal reported when gdb interrupted the target
+const SIGINT: u8 = 2;
+
+/// Byte gdb sends out of band to interrupt a running target
+const INTERRUPT: u8 = 0x03;
+
+/// Instruction a software breakpoint replaces the original code with
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const BREAKPOINT_INSN: &[u8] = &[0xCC];
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+const BREAKPOINT_INSN: &[u8] = &[];
+
+/// Reason the target trapped into the stub
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trap {
+    /// A breakpoint instruction, with the instruction pointer already moved back onto it
+    Breakpoint,
+    /// A single step finished
+    Step,
+}
+
+/// Every attached stub, by handle ID
+static SESSIONS: RwLock<L1, BTreeMap<usize, Arc<Session>>> = RwLock::new(BTreeMap::new());
+
+/// Software breakpoints inserted by every stub, by address space and address
+///
+/// Threads share their address space, so stubs attached to several of them insert into the same
+/// code. The original code is put back once the last stub that inserted a breakpoint removes it.
+static BREAKPOINTS: Mutex<BTreeMap<(usize, usize), Breakpoint>> = Mutex::new(BTreeMap::new());
+
+struct Breakpoint {
+    /// Address space the breakpoint is in, as an address space freed since could have been
+    /// allocated at the same address
+    addr_space: Weak<AddrSpaceWrapper>,
+    /// Original bytes under the breakpoint instruction
+    original: Vec<u8>,
+    /// Handle IDs of the stubs that inserted it
+    sessions: BTreeSet<usize>,
+}
+
+/// Key of the breakpoint at `address` of `addr_space` in [`BREAKPOINTS`]
+fn breakpoint_key(addr_space: &Arc<AddrSpaceWrapper>, address: usize) -> (usize, usize) {
+    (Arc::as_ptr(addr_space) as usize, address)
+}
+
+struct Session {
+    /// Handle ID, for event notifications
+    id: usize,
+    pid: usize,
+    context: Weak<ContextLock>,
+    state: Mutex<State>,
+    /// Readers waiting for output
+    output_ready: WaitCondition,
+}
+
+/// Outcome of a command
+enum Reply {
+    Packet(Vec<u8>),
+    /// The target runs again, and the reply is the stop reply once it stops
+    Resumed,
+}
+
+struct State {
+    /// Bytes written that do not form a whole packet yet
+    input: Vec<u8>,
+    /// Bytes not read yet
+    output: VecDeque<u8>,
+    /// Stop reply for the current stop, or None while the target runs
+    stop: Option<Vec<u8>>,
+}
+
+impl State {
+    /// Queue `data` as a packet, with its checksum
+    fn send_packet(&mut self, data: &[u8]) {
+        self.output.push_back(b'$');
+        self.output.extend(data);
+        let mut trailer = Vec::new();
+        let _ = write!(VecWriter(&mut trailer), "#{:02x}", checksum(data));
+        self.output.extend(trailer);
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// Stop reply for `signal`, marking software breakpoints so gdb knows the instruction pointer
+/// already points at them
+fn stop_reply(signal: u8, swbreak: bool) -> Vec<u8> {
+    let mut reply = Vec::new();
+    let _ = write!(VecWriter(&mut reply), "T{signal:02x}");
+    if swbreak {
+        reply.extend(b"swbreak:;");
+    }
+    reply
+}
+
+struct VecWriter<'a>(&'a mut Vec<u8>);
+
+impl Write for VecWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.0.extend(s.as_bytes());
+        Ok(())
+    }
+}
+
+fn push_hex(out: &mut Vec<u8>, bytes: &[u8]) {
+    for byte in bytes {
+        let _ = write!(VecWriter(out), "{byte:02x}");
+    }
+}
+
+fn parse_hex_bytes(hex: &[u8]) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| {
+            let pair = core::str::from_utf8(pair).ok()?;
+            u8::from_str_radix(pair, 16).ok()
+        })
+        .collect()
+}
+
+fn parse_hex(hex: &[u8]) -> Option<usize> {
+    usize::from_str_radix(core::str::from_utf8(hex).ok()?, 16).ok()
+}
+
+/// Parse `addr,len` as in `m` and `Z0`
+fn parse_addr_len(args: &[u8]) -> Option<(usize, usize)> {
+    let mut parts = args.splitn(2, |&b| b == b',');
+    Some((parse_hex(parts.next()?)?, parse_hex(parts.next()?)?))
+}
+
+fn is_stopped(status: &Status) -> bool {
+    matches!(
+        status,
+        Status::HardBlocked {
+            reason: HardBlockedReason::Stopped
+        }
+    )
+}
+
+/// Attach to `pid`, stopping it, and register the stub under handle `id`
+pub fn attach(id: usize, pid: usize, token: &mut CleanLockToken) -> Result<()> {
+    let context_lock = context::contexts()
+        .read()
+        .get(&pid)
+        .cloned()
+        .ok_or(Error::new(ESRCH))?;
+    if Arc::ptr_eq(&context_lock, &context::current()) {
+        return Err(Error::new(EBUSY));
+    }
+    {
+        let mut sessions = SESSIONS.write(token.token());
+        if sessions.values().any(|session| session.pid == pid) {
+            return Err(Error::new(EBUSY));
+        }
+        {
+            let mut context = context_lock.write(token.token());
+            if !context.userspace {
+                return Err(Error::new(EBUSY));
+            }
+            match context.status {
+                // A waiting context resumes as runnable, which its wait loop copes with
+                Status::Runnable | Status::Blocked => (),
+                _ => return Err(Error::new(EBUSY)),
+            }
+            context.status = Status::HardBlocked {
+                reason: HardBlockedReason::Stopped,
+            };
+        }
+        sessions.insert(
+            id,
+            Arc::new(Session {
+                id,
+                pid,
+                context: Arc::downgrade(&context_lock),
+                state: Mutex::new(State {
+                    input: Vec::new(),
+                    output: VecDeque::new(),
+                    stop: Some(stop_reply(SIGTRAP, false)),
+                }),
+                output_ready: WaitCondition::new(),
+            }),
+        );
+    }
+    wait_until_off_cpu(&context_lock, token);
+    Ok(())
+}
+
+/// Wait until a stopped context is no longer running, so its saved registers are final
+fn wait_until_off_cpu(context_lock: &Arc<ContextLock>, token: &mut CleanLockToken) {
+    while context_lock.read(token.token()).running {
+        unsafe { context::switch(token) };
+    }
+}
+
+fn session(id: usize, token: &mut CleanLockToken) -> Result<Arc<Session>> {
+    SESSIONS
+        .read(token.token())
+        .get(&id)
+        .cloned()
+        .ok_or(Error::new(EBADF))
+}
+
+/// Detach the stub of handle `id`, removing its breakpoints and resuming the target
+pub fn detach(id: usize, token: &mut CleanLockToken) -> Result<()> {
+    let session = SESSIONS
+        .write(token.token())
+        .remove(&id)
+        .ok_or(Error::new(EBADF))?;
+    session.detach(token);
+    Ok(())
+}
+
+/// Read replies from the stub of handle `id`, waiting for some unless `nonblock` is set
+pub fn read(
+    id: usize,
+    buf: UserSliceWo,
+    nonblock: bool,
+    token: &mut CleanLockToken,
+) -> Result<usize> {
+    let session = session(id, token)?;
+    loop {
+        let mut state = session.state.lock(token);
+        if !state.output.is_empty() {
+            let (front, _) = state.output.as_slices();
+            let count = buf.copy_common_bytes_from_slice(front)?;
+            state.output.drain(..count);
+            return Ok(count);
+        }
+        if nonblock {
+            return Err(Error::new(EAGAIN));
+        }
+        if !state.wait(&session.output_ready, "gdb::read") {
+            return Err(Error::new(EINTR));
+        }
+    }
+}
+
+/// Feed bytes from gdb to the stub of handle `id`
+pub fn write(id: usize, buf: UserSliceRo, token: &mut CleanLockToken) -> Result<usize> {
+    let session = session(id, token)?;
+    let mut tmp = [0_u8; 512];
+    for chunk in buf.in_variable_chunks(tmp.len()) {
+        let count = chunk.copy_common_bytes_to_slice(&mut tmp)?;
+        session
+            .state
+            .lock(token)
+            .input
+            .extend_from_slice(&tmp[..count]);
+    }
+    session.process_input(token);
+    Ok(buf.len())
+}
+
+/// Event flags of the stub of handle `id`
+pub fn events(id: usize, token: &mut CleanLockToken) -> Result<EventFlags> {
+    let session = session(id, token)?;
+    let ready = !session.state.lock(token).output.is_empty();
+    Ok(if ready {
+        EVENT_READ
+    } else {
+        EventFlags::empty()
+    })
+}
+
+/// Park the current context for an attached stub after it trapped, returning false if no stub
+/// is attached to it
+///
+/// Must only be called for traps taken in user mode, without any locks held.
+pub fn trap(kind: Trap, token: &mut CleanLockToken) -> bool {
+    let current = context::current();
+    let (pid, ip, addr_space) = {
+        let context = current.read(token.token());
+        (
+            context.pid,
+            context.regs().map(ArchIntCtx::ip),
+            context.addr_space().ok().cloned(),
+        )
+    };
+    let Some(session) = SESSIONS
+        .read(token.token())
+        .values()
+        .find(|session| session.pid == pid)
+        .cloned()
+    else {
+        return false;
+    };
+
+    let swbreak = kind == Trap::Breakpoint
+        && ip
+            .zip(addr_space)
+            .is_some_and(|(ip, addr_space)| has_breakpoint(&addr_space, ip, token));
+    {
+        let mut state = session.state.lock(token);
+        let reply = stop_reply(SIGTRAP, swbreak);
+        state.send_packet(&reply);
+        state.stop = Some(reply);
+        current.write(state.token().token()).status = Status::HardBlocked {
+            reason: HardBlockedReason::Stopped,
+        };
+    }
+    session.notify(token);
+
+    // The stub makes it runnable again on continue, step, detach or kill
+    while is_stopped(&current.read(token.token()).status) {
+        unsafe { context::switch(token) };
+    }
+    if current.read(token.token()).being_sigkilled {
+        drop(current);
+        crate::syscall::process::exit_this_context(0, None, token);
+    }
+    true
+}
+
+impl Session {
+    /// Wake readers and notify event listeners of new output
+    fn notify(&self, token: &mut CleanLockToken) {
+        self.output_ready.notify(token);
+        event::trigger(GlobalSchemes::Debug.scheme_id(), self.id, EVENT_READ, token);
+    }
+
+    /// Handle every whole packet received so far
+    fn process_input(&self, token: &mut CleanLockToken) {
+        loop {
+            let packet = {
+                let mut state = self.state.lock(token);
+                let Some(start) = state
+                    .input
+                    .iter()
+                    .position(|&b| b == b'$' || b == INTERRUPT)
+                else {
+                    // Acknowledgements and noise between packets
+                    state.input.clear();
+                    return;
+                };
+                if state.input[start] == INTERRUPT {
+                    state.input.drain(..=start);
+                    None
+                } else {
+                    let Some(end) = state.input[start..].iter().position(|&b| b == b'#') else {
+                        if state.input.len() - start > PACKET_SIZE + 4 {
+                            state.input.clear();
+                        }
+                        return;
+                    };
+                    let end = start + end;
+                    if state.input.len() < end + 3 {
+                        return;
+                    }
+                    let data = state.input[start + 1..end].to_vec();
+                    let valid = parse_hex(&state.input[end + 1..end + 3])
+                        .is_some_and(|sum| sum == usize::from(checksum(&data)));
+                    state.input.drain(..end + 3);
+                    state.output.push_back(if valid { b'+' } else { b'-' });
+                    if !valid {
+                        drop(state);
+                        self.notify(token);
+                        continue;
+                    }
+                    Some(data)
+                }
+            };
+
+            match packet {
+                None => self.interrupt(token),
+                Some(data) => {
+                    if let Some(reply) = self.handle_packet(&data, token) {
+                        self.state.lock(token).send_packet(&reply);
+                    }
+                }
+            }
+            self.notify(token);
+        }
+    }
+
+    /// Stop the running target on gdb's request
+    fn interrupt(&self, token: &mut CleanLockToken) {
+        let Some(context_lock) = self.context.upgrade() else {
+            return;
+        };
+        if self.state.lock(token).stop.is_some() {
+            return;
+        }
+        {
+            let mut context = context_lock.write(token.token());
+            if !matches!(context.status, Status::Runnable | Status::Blocked) {
+                return;
+            }
+            context.status = Status::HardBlocked {
+                reason: HardBlockedReason::Stopped,
+            };
+        }
+        wait_until_off_cpu(&context_lock, token);
+
+        let mut state = self.state.lock(token);
+        let reply = stop_reply(SIGINT, false);
+        state.send_packet(&reply);
+        state.stop = Some(reply);
+    }
+
+    /// Handle one packet, returning the reply to send, if any yet
+    ///
+    /// Malformed packets and requests that cannot be served get an error reply.
+    fn handle_packet(&self, data: &[u8], token: &mut CleanLockToken) -> Option<Vec<u8>> {
+        match self.handle_command(data, token) {
+            Some(Reply::Packet(reply)) => Some(reply),
+            Some(Reply::Resumed) => None,
+            None => Some(b"E01".to_vec()),
+        }
+    }
+
+    fn handle_command(&self, data: &[u8], token: &mut CleanLockToken) -> Option<Reply> {
+        let (&command, args) = data.split_first()?;
+        let ok = || Some(Reply::Packet(b"OK".to_vec()));
+
+        match command {
+            b'q' if args.starts_with(b"Supported") => {
+                let mut reply = Vec::new();
+                let _ = write!(VecWriter(&mut reply), "PacketSize={PACKET_SIZE:x};swbreak+");
+                return Some(Reply::Packet(reply));
+            }
+            b'?' => {
+                let stop = self.state.lock(token).stop.clone();
+                return Some(Reply::Packet(stop.unwrap_or_default()));
+            }
+            b'g' | b'G' | b'm' | b'M' | b'Z' | b'z' | b'c' | b's' | b'D' | b'k' => (),
+            _ => return Some(Reply::Packet(Vec::new())),
+        }
+        // Everything else needs the target stopped and alive
+        if self.state.lock(token).stop.is_none() {
+            return None;
+        }
+        let context_lock = self.context.upgrade()?;
+
+        match command {
+            b'g' => {
+                let context = context_lock.read(token.token());
+                let mut reply = Vec::new();
+                read_registers(context.regs()?, &mut reply).then_some(Reply::Packet(reply))
+            }
+            b'G' => {
+                let bytes = parse_hex_bytes(args)?;
+                let mut context = context_lock.write(token.token());
+                write_registers(context.regs_mut()?, &bytes)
+                    .then_some(Reply::Packet(b"OK".to_vec()))
+            }
+            b'm' => {
+                let (address, len) = parse_addr_len(args)?;
+                let addr_space = context_lock.read(token.token()).addr_space().ok()?.clone();
+                let mut bytes = alloc::vec![0; len.min(PACKET_SIZE / 2)];
+                read_memory(&addr_space, address, &mut bytes).ok()?;
+                let mut reply = Vec::new();
+                push_hex(&mut reply, &bytes);
+                Some(Reply::Packet(reply))
+            }
+            b'M' => {
+                let mut parts = args.splitn(2, |&b| b == b':');
+                let (address, len) = parse_addr_len(parts.next()?)?;
+                let bytes = parse_hex_bytes(parts.next()?)?;
+                if bytes.len() != len {
+                    return None;
+                }
+                let addr_space = context_lock.read(token.token()).addr_space().ok()?.clone();
+                write_memory(&addr_space, address, &bytes).ok()?;
+                ok()
+            }
+            b'Z' | b'z' => {
+                // Only software breakpoints are supported
+                let Some(args) = args.strip_prefix(b"0,") else {
+                    return Some(Reply::Packet(Vec::new()));
+                };
+                if BREAKPOINT_INSN.is_empty() {
+                    return Some(Reply::Packet(Vec::new()));
+                }
+                let (address, _kind) = parse_addr_len(args)?;
+                let addr_space = context_lock.read(token.token()).addr_space().ok()?.clone();
+                if command == b'Z' {
+                    insert_breakpoint(self.id, &addr_space, address, token).ok()?;
+                } else {
+                    remove_breakpoint(self.id, &addr_space, address, token).ok()?;
+                }
+                ok()
+            }
+            b'c' | b's' => {
+                if !args.is_empty() {
+                    let address = parse_hex(args)?;
+                    let mut context = context_lock.write(token.token());
+                    context.regs_mut()?.set_instr_pointer(address);
+                }
+                self.resume(&context_lock, command == b's', token);
+                Some(Reply::Resumed)
+            }
+            b'D' => {
+                SESSIONS.write(token.token()).remove(&self.id);
+                self.detach(token);
+                ok()
+            }
+            b'k' => {
+                SESSIONS.write(token.token()).remove(&self.id);
+                // A target stopped in a trap exits as soon as it resumes, and anything else at
+                // its next syscall boundary
+                context_lock.write(token.token()).being_sigkilled = true;
+                self.detach(token);
+                Some(Reply::Resumed)
+            }
+            _ => unreachable!("filtered above"),
+        }
+    }
+
+    /// Let the stopped target run again, for a single instruction if `step` is set
+    fn resume(&self, context_lock: &Arc<ContextLock>, step: bool, token: &mut CleanLockToken) {
+        self.state.lock(token).stop = None;
+        let mut context = context_lock.write(token.token());
+        if let Some(regs) = context.regs_mut() {
+            regs.set_singlestep(step);
+        }
+        let stopped = is_stopped(&context.status);
+        if stopped {
+            context.status = Status::Runnable;
+        }
+        drop(context);
+        if stopped {
+            scheduler::wake(Arc::clone(context_lock), token);
+        }
+    }
+
+    /// Remove every breakpoint and resume the target if it is stopped
+    fn detach(&self, token: &mut CleanLockToken) {
+        let Some(context_lock) = self.context.upgrade() else {
+            return;
+        };
+        let addr_space = context_lock.read(token.token()).addr_space().cloned();
+        if let Ok(addr_space) = addr_space {
+            let addresses: Vec<usize> = BREAKPOINTS
+                .lock(token)
+                .iter()
+                .filter(|((key, _), breakpoint)| {
+                    *key == Arc::as_ptr(&addr_space) as usize
+                        && breakpoint.sessions.contains(&self.id)
+                })
+                .map(|(&(_, address), _)| address)
+                .collect();
+            for address in addresses {
+                let _ = remove_breakpoint(self.id, &addr_space, address, token);
+            }
+        }
+        self.resume(&context_lock, false, token);
+    }
+}
+
+/// Whether a stub inserted a breakpoint at `address` of `addr_space`
+fn has_breakpoint(
+    addr_space: &Arc<AddrSpaceWrapper>,
+    address: usize,
+    token: &mut CleanLockToken,
+) -> bool {
+    BREAKPOINTS
+        .lock(token)
+        .get(&breakpoint_key(addr_space, address))
+        .is_some_and(|breakpoint| breakpoint.addr_space.strong_count() > 0)
+}
+
+/// Replace the code at `address` with a breakpoint instruction for the stub of handle `id`,
+/// remembering the original unless another stub already inserted it
+fn insert_breakpoint(
+    id: usize,
+    addr_space: &Arc<AddrSpaceWrapper>,
+    address: usize,
+    token: &mut CleanLockToken,
+) -> Result<()> {
+    let mut breakpoints = BREAKPOINTS.lock(token);
+    let key = breakpoint_key(addr_space, address);
+    if let Some(breakpoint) = breakpoints.get_mut(&key)
+        && breakpoint.addr_space.strong_count() > 0
+    {
+        breakpoint.sessions.insert(id);
+        return Ok(());
+    }
+    let mut original = alloc::vec![0; BREAKPOINT_INSN.len()];
+    read_memory(addr_space, address, &mut original)?;
+    write_memory(addr_space, address, BREAKPOINT_INSN)?;
+    breakpoints.insert(
+        key,
+        Breakpoint {
+            addr_space: Arc::downgrade(addr_space),
+            original,
+            sessions: BTreeSet::from([id]),
+        },
+    );
+    Ok(())
+}
+
+/// Drop the stub of handle `id` from the breakpoint at `address`, putting back the code it
+/// replaced if no other stub inserted it
+fn remove_breakpoint(
+    id: usize,
+    addr_space: &Arc<AddrSpaceWrapper>,
+    address: usize,
+    token: &mut CleanLockToken,
+) -> Result<()> {
+    let mut breakpoints = BREAKPOINTS.lock(token);
+    let key = breakpoint_key(addr_space, address);
+    let Some(breakpoint) = breakpoints.get_mut(&key) else {
+        return Ok(());
+    };
+    breakpoint.sessions.remove(&id);
+    if !breakpoint.sessions.is_empty() {
+        return Ok(());
+    }
+    let Some(breakpoint) = breakpoints.remove(&key) else {
+        return Ok(());
+    };
+    if breakpoint.addr_space.strong_count() == 0 {
+        return Ok(());
+    }
+    write_memory(addr_space, address, &breakpoint.original)
+}
+
+/// Call `f` with the kernel address of every page-sized piece of the user range `[address,
+/// address + len)`
+///
+/// Goes through the page tables rather than touching user memory, so an unmapped address fails
+/// with EFAULT instead of faulting.
+fn for_each_piece(
+    addr_space: &AddrSpaceWrapper,
+    address: usize,
+    len: usize,
+    mut f: impl FnMut(usize, *mut u8, usize) -> Result<()>,
+) -> Result<()> {
+    let end = address.checked_add(len).ok_or(Error::new(EFAULT))?;
+    if end > crate::USER_END_OFFSET {
+        return Err(Error::new(EFAULT));
+    }
+    let inner = addr_space.acquire_read();
+    let mut cursor = address;
+    while cursor < end {
+        let page = Page::containing_address(VirtualAddress::new(cursor));
+        let offset = cursor - page.start_address().data();
+        let count = (PAGE_SIZE - offset).min(end - cursor);
+
+        inner
+            .grants
+            .range(..=page)
+            .next_back()
+            .filter(|(_, grant)| {
+                cursor < grant.start_address().data() + grant.page_count() * PAGE_SIZE
+            })
+            .ok_or(Error::new(EFAULT))?;
+        let phys = inner
+            .table
+            .utable
+            .0
+            .translate(page.start_address())
+            .ok_or(Error::new(EFAULT))?;
+        let frame = Frame::containing(phys);
+        let ptr = unsafe { (RmmA::phys_to_virt(frame.base()).data() as *mut u8).add(offset) };
+        f(cursor - address, ptr, count)?;
+        cursor += count;
+    }
+    Ok(())
+}
+
+/// Copy the user memory at `address` of `addr_space` into `buf`
+fn read_memory(addr_space: &AddrSpaceWrapper, address: usize, buf: &mut [u8]) -> Result<()> {
+    for_each_piece(addr_space, address, buf.len(), |done, src, count| {
+        unsafe { core::ptr::copy_nonoverlapping(src, buf[done..].as_mut_ptr(), count) };
+        Ok(())
+    })
+}
+
+/// Copy `buf` into the user memory at `address` of `addr_space`, whatever its protection
+///
+/// As for a tracer writing proc:<pid>/mem, only private memory can be written, and pages shared
+/// copy-on-write, as after a fork, get a copy of their own first.
+fn write_memory(addr_space: &AddrSpaceWrapper, address: usize, buf: &[u8]) -> Result<()> {
+    let written = addr_space.acquire_write().write_mem(address, buf, true)?;
+    if written < buf.len() {
+        return Err(Error::new(EFAULT));
+    }
+    Ok(())
+}
+
+/// Append the registers in the order of gdb's amd64 `g` packet: the 64-bit general purpose
+/// registers and rip, then eflags and the segment registers at 32 bits each
+#[cfg(target_arch = "x86_64")]
+fn read_registers(regs: &InterruptStack, out: &mut Vec<u8>) -> bool {
+    let wide = [
+        regs.rax, regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rbp, regs.rsp, regs.r8,
+        regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15, regs.rip,
+    ];
+    for value in wide {
+        push_hex(out, &value.to_le_bytes());
+    }
+    // eflags, cs, ss, ds, es, fs, gs
+    let narrow = [regs.rflags, regs.cs, regs.ss, 0, 0, 0, 0];
+    for value in narrow {
+        push_hex(out, &(value as u32).to_le_bytes());
+    }
+    true
+}
+
+/// Load registers from a `G` packet laid out as for [`read_registers`]
+///
+/// Segment registers are ignored, and only the arithmetic, direction and trap flags of eflags
+/// can be changed.
+#[cfg(target_arch = "x86_64")]
+fn write_registers(regs: &mut InterruptStack, bytes: &[u8]) -> bool {
+    const USER_FLAGS: u64 = 0xDD5;
+
+    if bytes.len() < 17 * 8 + 4 {
+        return false;
+    }
+    let mut wide = bytes
+        .chunks_exact(8)
+        .take(17)
+        .map(|chunk| u64::from_le_bytes(chunk.try_into().expect("chunks are 8 bytes")));
+    for reg in [
+        &mut regs.rax,
+        &mut regs.rbx,
+        &mut regs.rcx,
+        &mut regs.rdx,
+        &mut regs.rsi,
+        &mut regs.rdi,
+        &mut regs.rbp,
+        &mut regs.rsp,
+        &mut regs.r8,
+        &mut regs.r9,
+        &mut regs.r10,
+        &mut regs.r11,
+        &mut regs.r12,
+        &mut regs.r13,
+        &mut regs.r14,
+        &mut regs.r15,
+        &mut regs.rip,
+    ] {
+        *reg = wide.next().expect("length checked above");
+    }
+    let eflags = u32::from_le_bytes(bytes[136..140].try_into().expect("length checked above"));
+    regs.rflags = (regs.rflags & !USER_FLAGS) | (u64::from(eflags) & USER_FLAGS);
+    true
+}
+
+// TODO: Register layouts of the remaining architectures
+#[cfg(not(target_arch = "x86_64"))]
+fn read_registers(_regs: &InterruptStack, _out: &mut Vec<u8>) -> bool {
+    false
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn write_registers(_regs: &mut InterruptStack, _bytes: &[u8]) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(checksum(b"OK"), 0x9a);
+        assert_eq!(checksum(b""), 0);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_addr_len(b"4010a0,10"), Some((0x4010a0, 0x10)));
+        assert_eq!(parse_addr_len(b"4010a0"), None);
+        assert_eq!(parse_hex_bytes(b"cc0f"), Some(alloc::vec![0xcc, 0x0f]));
+        assert_eq!(parse_hex_bytes(b"ccf"), None);
+    }
+
+    #[test]
+    fn test_stop_reply() {
+        assert_eq!(stop_reply(SIGTRAP, false), b"T05");
+        assert_eq!(stop_reply(SIGTRAP, true), b"T05swbreak:;");
+    }
+}
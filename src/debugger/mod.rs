@@ -1,3 +1,5 @@
+pub mod gdb;
+
 use crate::{
     context::{context::SyscallFrame, Context, ContextLock},
     memory::{get_page_info, the_zeroed_frame, Frame, RefCount},
@@ -89,31 +91,34 @@ pub unsafe fn debugger(target_id: Option<*const ContextLock>, token: &mut CleanL
 
                     #[cfg(target_arch = "aarch64")]
                     println!(
-                        "    virt 0x{:016x}:0x{:016x} size 0x{:08x} {:?}",
+                        "    virt 0x{:016x}:0x{:016x} size 0x{:08x} {:?} {}",
                         base.start_address().data(),
                         base.next_by(info.page_count() - 1).start_address().data() + 0xFFF,
                         size,
                         info.provider,
+                        info.name().unwrap_or(""),
                     );
 
                     // FIXME riscv64 implementation
 
                     #[cfg(target_arch = "x86")]
                     println!(
-                        "    virt 0x{:08x}:0x{:08x} size 0x{:08x} {:?}",
+                        "    virt 0x{:08x}:0x{:08x} size 0x{:08x} {:?} {}",
                         base.start_address().data(),
                         base.next_by(info.page_count()).start_address().data() + 0xFFF,
                         size,
                         info.provider,
+                        info.name().unwrap_or(""),
                     );
 
                     #[cfg(target_arch = "x86_64")]
                     println!(
-                        "    virt 0x{:016x}:0x{:016x} size 0x{:08x} {:?}",
+                        "    virt 0x{:016x}:0x{:016x} size 0x{:08x} {:?} {}",
                         base.start_address().data(),
                         base.start_address().data() + size - 1,
                         size,
                         info.provider,
+                        info.name().unwrap_or(""),
                     );
                 }
             }
@@ -288,8 +293,8 @@ unsafe fn check_page_table_consistency(
                     let p = matches!(
                         grant.provider,
                         Provider::PhysBorrowed { .. }
-                            | Provider::External { .. }
                             | Provider::FmapBorrowed { .. }
+                            | Provider::IpcBuffer { .. }
                     );
                     let frame = Frame::containing(physaddr);
                     if new_as {
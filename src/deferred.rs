@@ -0,0 +1,309 @@
+//! # Deferred Work
+//!
+//! Interrupt handlers run with interrupts disabled, so work done in them adds to the interrupt
+//! latency of their CPU. Handlers instead [`defer`] whatever does not have to happen right away,
+//! which queues a function and an argument on the CPU's [`DeferQueue`], and the work runs with
+//! interrupts enabled later, on the same CPU:
+//!
+//! - before a syscall returns to userspace,
+//! - when the CPU goes idle,
+//! - or by the CPU's `[deferred/N]` kernel thread, which the timer tick wakes once the backlog
+//!   reaches [`WAKE_THRESHOLD`] or work has been waiting since the previous tick.
+//!
+//! Queueing takes no locks, so that even the NMI handler can defer. A full queue drops the work
+//! and counts it, both counts being shown in sys:irq_stats.
+
+use alloc::format;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::{
+    arch::interrupt,
+    context::kthread,
+    cpu_set::LogicalCpuSet,
+    percpu::PercpuBlock,
+    sync::{CleanLockToken, Priority, WaitCondition},
+};
+
+/// Work to run later, called with the argument it was deferred with. It runs in whichever
+/// context drains the queue, so it must not block.
+pub type Work = fn(usize, &mut CleanLockToken);
+
+/// Work each CPU can have queued before more is dropped
+pub const DEFER_CAPACITY: usize = 256;
+/// Backlog at which the timer tick wakes the CPU's worker thread
+pub const WAKE_THRESHOLD: usize = 64;
+
+#[derive(Debug)]
+struct Slot {
+    /// The [`Work`] as an address, 0 until the slot is filled
+    work: AtomicUsize,
+    arg: AtomicUsize,
+}
+
+/// The deferred work of one CPU
+#[derive(Debug)]
+pub struct DeferQueue {
+    slots: [Slot; DEFER_CAPACITY],
+    /// Index of the next slot to run, only advanced by whoever holds `running`
+    head: AtomicUsize,
+    /// Index of the next slot to fill
+    tail: AtomicUsize,
+    /// `tail` at the previous timer tick, to find work that waited a whole tick
+    tail_at_tick: AtomicUsize,
+    /// Held while draining, so that only one context runs the queue at a time
+    running: AtomicBool,
+    dropped: AtomicU64,
+    ran: AtomicU64,
+    /// The worker thread waits here, with `worker_lock` held until it is registered
+    worker: WaitCondition,
+    worker_lock: spin::Mutex<()>,
+}
+
+impl DeferQueue {
+    pub const fn new() -> Self {
+        Self {
+            slots: [const {
+                Slot {
+                    work: AtomicUsize::new(0),
+                    arg: AtomicUsize::new(0),
+                }
+            }; DEFER_CAPACITY],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            tail_at_tick: AtomicUsize::new(0),
+            running: AtomicBool::new(false),
+            dropped: AtomicU64::new(0),
+            ran: AtomicU64::new(0),
+            worker: WaitCondition::new(),
+            worker_lock: spin::Mutex::new(()),
+        }
+    }
+
+    /// Work queued and not run yet
+    pub fn backlog(&self) -> usize {
+        self.tail
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+
+    /// Work dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Work run so far
+    pub fn ran(&self) -> u64 {
+        self.ran.load(Ordering::Relaxed)
+    }
+
+    /// Queue `work`, or count it as dropped if the queue is full. May interrupt itself, as an
+    /// NMI arriving while an interrupt handler queues work would.
+    fn push(&self, work: Work, arg: usize) -> bool {
+        let mut tail = self.tail.load(Ordering::Relaxed);
+        loop {
+            if tail.wrapping_sub(self.head.load(Ordering::Acquire)) >= DEFER_CAPACITY {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+            match self.tail.compare_exchange_weak(
+                tail,
+                tail.wrapping_add(1),
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current) => tail = current,
+            }
+        }
+        // The capacity check saw `head` past the previous use of the slot, so it is empty
+        let Some(slot) = self.slots.get(tail % DEFER_CAPACITY) else {
+            return false;
+        };
+        slot.arg.store(arg, Ordering::Relaxed);
+        slot.work.store(work as usize, Ordering::Release);
+        true
+    }
+
+    /// Take the oldest work, unless its slot is still being filled
+    fn pop(&self) -> Option<(Work, usize)> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let slot = self.slots.get(head % DEFER_CAPACITY)?;
+        let work = slot.work.load(Ordering::Acquire);
+        if work == 0 {
+            return None;
+        }
+        let arg = slot.arg.load(Ordering::Relaxed);
+        slot.work.store(0, Ordering::Relaxed);
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        // Only addresses of `Work` functions are ever stored
+        Some((unsafe { core::mem::transmute::<usize, Work>(work) }, arg))
+    }
+
+    /// Run the queued work, unless another context on this CPU already is. Work queued while
+    /// running is run as well.
+    fn run(&self, token: &mut CleanLockToken) {
+        if self.running.swap(true, Ordering::Acquire) {
+            return;
+        }
+        while let Some((work, arg)) = self.pop() {
+            work(arg, token);
+            self.ran.fetch_add(1, Ordering::Relaxed);
+        }
+        self.running.store(false, Ordering::Release);
+    }
+}
+
+impl Default for DeferQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Run `work(arg)` later on this CPU, with interrupts enabled. Safe to call from any interrupt
+/// handler, including the NMI handler. Returns false if the queue was full and the work dropped.
+pub fn defer(work: Work, arg: usize) -> bool {
+    PercpuBlock::current().deferred.push(work, arg)
+}
+
+/// Run this CPU's deferred work. Enables interrupts while doing so if they are disabled, so it
+/// must only be called where an interrupt could be taken, such as on the way back to userspace.
+pub fn run_pending(token: &mut CleanLockToken) {
+    let queue = &PercpuBlock::current().deferred;
+    if queue.backlog() == 0 {
+        return;
+    }
+    let enabled = interrupt::are_enabled();
+    if !enabled {
+        unsafe { interrupt::enable_and_nop() };
+    }
+    // A context preempted while running still holds the queue of the CPU it started on
+    queue.run(token);
+    if !enabled {
+        unsafe { interrupt::disable() };
+    }
+}
+
+/// Wake this CPU's worker if its backlog reached [`WAKE_THRESHOLD`], or if work queued before
+/// the previous tick is still waiting. Called from the timer interrupt.
+pub fn tick(token: &mut CleanLockToken) {
+    let queue = &PercpuBlock::current().deferred;
+    let tail = queue.tail.load(Ordering::Acquire);
+    let previous = queue.tail_at_tick.swap(tail, Ordering::Relaxed);
+    let backlog = queue.backlog();
+    if backlog == 0 {
+        return;
+    }
+    // Whether `head` is still behind the tail of the previous tick
+    let stale = previous.wrapping_sub(queue.head.load(Ordering::Acquire)) as isize > 0;
+    if backlog < WAKE_THRESHOLD && !stale {
+        return;
+    }
+    // The worker holds the lock only while about to wait, and retries before doing so
+    if let Some(guard) = queue.worker_lock.try_lock() {
+        drop(guard);
+        queue.worker.notify(token);
+    }
+}
+
+/// Spawn the worker thread of this CPU
+pub fn spawn_worker(token: &mut CleanLockToken) {
+    let cpu = crate::cpu_id();
+    let mut affinity = LogicalCpuSet::new();
+    affinity.add(cpu);
+    match kthread::spawn(
+        &format!("[deferred/{}]", cpu),
+        affinity,
+        Priority::High,
+        worker,
+        token,
+    ) {
+        Ok(handle) => handle.detach(token),
+        Err(err) => warn!(
+            "Failed to spawn the deferred work thread of CPU {}: {}",
+            cpu, err
+        ),
+    }
+}
+
+fn worker() {
+    let mut token = unsafe { CleanLockToken::new() };
+    // The thread only runs on its own CPU
+    let queue = &PercpuBlock::current().deferred;
+    while !kthread::should_stop() {
+        queue.run(&mut token);
+
+        let guard = queue.worker_lock.lock();
+        if queue.backlog() != 0 {
+            continue;
+        }
+        queue.worker.wait(guard, "deferred::worker", &mut token);
+    }
+}
+
+#[cfg(feature = "selftest")]
+pub mod selftests {
+    use super::*;
+    use crate::selftest::{check, check_eq, SelftestResult};
+
+    fn nothing(_arg: usize, _token: &mut CleanLockToken) {}
+
+    /// Indices that wrap around `usize` keep the queue's order and capacity
+    pub fn ring_index_wraparound(_token: &mut CleanLockToken) -> SelftestResult {
+        let queue = DeferQueue::new();
+        let start = usize::MAX - DEFER_CAPACITY / 2;
+        queue.head.store(start, Ordering::Relaxed);
+        queue.tail.store(start, Ordering::Relaxed);
+
+        for arg in 0..DEFER_CAPACITY {
+            check!(queue.push(nothing, arg));
+        }
+        check!(!queue.push(nothing, DEFER_CAPACITY));
+        check_eq!(queue.backlog(), DEFER_CAPACITY);
+        check_eq!(queue.dropped(), 1);
+
+        for arg in 0..DEFER_CAPACITY {
+            check_eq!(queue.pop().map(|(_, popped)| popped), Some(arg));
+        }
+        check!(queue.pop().is_none());
+        check_eq!(queue.backlog(), 0);
+        check!(queue.tail.load(Ordering::Relaxed) < start);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static LAST: AtomicUsize = AtomicUsize::new(0);
+
+    fn record(arg: usize, _token: &mut CleanLockToken) {
+        LAST.store(arg, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn test_defer_queue_order_and_overflow() {
+        let queue = DeferQueue::new();
+        for arg in 0..DEFER_CAPACITY {
+            assert!(queue.push(record, arg));
+        }
+        assert!(!queue.push(record, DEFER_CAPACITY));
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(queue.backlog(), DEFER_CAPACITY);
+
+        for arg in 0..DEFER_CAPACITY {
+            let (work, popped) = queue.pop().unwrap();
+            assert_eq!(work as usize, record as Work as usize);
+            assert_eq!(popped, arg);
+        }
+        assert!(queue.pop().is_none());
+
+        // Freed slots are reused
+        assert!(queue.push(record, 7));
+        assert_eq!(queue.backlog(), 1);
+        assert_eq!(queue.pop().map(|(_, arg)| arg), Some(7));
+    }
+}
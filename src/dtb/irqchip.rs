@@ -11,6 +11,14 @@ pub trait InterruptHandler {
     fn irq_handler(&mut self, irq: u32, token: &mut CleanLockToken);
 }
 
+/// CPUs a software-generated interrupt is routed to
+#[derive(Debug, Copy, Clone)]
+pub enum SgiTarget {
+    Cpu(LogicalCpuId),
+    /// Every CPU but the sender
+    Others,
+}
+
 #[derive(Debug, Copy, Clone)]
 #[allow(dead_code)]
 pub enum IrqCell {
@@ -34,9 +42,11 @@ pub trait InterruptController: InterruptHandler {
     fn irq_disable(&mut self, irq_num: u32);
     fn irq_xlate(&self, irq_data: IrqCell) -> Result<usize>;
     fn irq_to_virq(&self, hwirq: u32) -> Option<usize>;
-    fn send_sgi(&mut self, irq: u32, target_cpu: u32) {
+    fn send_sgi(&mut self, _intid: u8, _target: SgiTarget) {
         // Default implementation does nothing
     }
+    /// Set up the interface of the current CPU, on each CPU as it comes up
+    fn init_cpu(&mut self) {}
 }
 
 pub struct IrqConnection {
@@ -330,10 +340,17 @@ impl IrqChipCore {
         }
     }
 
-    pub fn send_sgi(&mut self, irq: u32, target_cpu: u32) {
+    pub fn send_sgi(&mut self, intid: u8, target: SgiTarget) {
         // Send SGI via the first interrupt controller (usually the GIC)
         if let Some(chip) = self.irq_chip_list.chips.first_mut() {
-            chip.ic.send_sgi(irq, target_cpu);
+            chip.ic.send_sgi(intid, target);
+        }
+    }
+
+    /// Bring up the current CPU's interface to every interrupt controller
+    pub fn init_cpu(&mut self) {
+        for chip in &mut self.irq_chip_list.chips {
+            chip.ic.init_cpu();
         }
     }
 
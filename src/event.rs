@@ -1,22 +1,51 @@
-use alloc::{collections::VecDeque, sync::Arc};
-use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    mem,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 use hashbrown::{HashMap, HashSet};
-use spin::Once;
+use spin::{Mutex, Once};
 
 use crate::{
     context,
     scheme::{self, GlobalSchemes, KernelScheme, SchemeId},
     sync::{
-        CleanLockToken, LockToken, RwLock, RwLockReadGuard, RwLockWriteGuard, OptimizedWaitQueue, L0, L1,
+        CleanLockToken, LockToken, RwLock, RwLockReadGuard, RwLockWriteGuard, WaitCondition, L0, L1,
     },
     syscall::{
         data::Event,
-        error::{Error, Result, EBADF},
+        error::{Error, Result, EAGAIN, EBADF, EBUSY, EINTR, EINVAL, EIO, EPERM},
         flag::EventFlags,
         usercopy::UserSliceWo,
     },
 };
 
+/// Only notify when readiness is newly reported, not again until the event has been read.
+pub const EVENT_EDGE: EventFlags = EventFlags::from_bits_retain(1 << 30);
+/// Disarm the registration after its first notification, until it is registered again.
+pub const EVENT_ONESHOT: EventFlags = EventFlags::from_bits_retain(1 << 31);
+/// Registration flags that select a mode rather than a kind of readiness
+const EVENT_MODES: EventFlags = EVENT_EDGE.union(EVENT_ONESHOT);
+/// Read from a queue that had to drop events because it was full, in place of them. The event
+/// names no file, so the reader should check all its files for readiness again.
+pub const EVENT_OVERFLOW: EventFlags = EventFlags::from_bits_retain(1 << 29);
+
+/// Pending events a new queue holds before it overflows
+pub const DEFAULT_QUEUE_EVENTS: usize = 4096;
+/// Most pending events users other than root can let a queue hold through F_SETEVENTQ_SZ
+const MAX_QUEUE_EVENTS: usize = 65536;
+/// Memory for pending events a user other than root can hold across all their queues
+const EVENT_USER_QUOTA: usize = 16 * 1024 * 1024;
+/// Memory charged for each pending event, which is kept both in the order and in the map
+const PENDING_EVENT_SIZE: usize = 2 * mem::size_of::<Event>();
+
+/// Memory for pending events charged to each user, see [`EVENT_USER_QUOTA`]
+static EVENT_CHARGES: Mutex<EventCharges> = Mutex::new(EventCharges(BTreeMap::new()));
+
 /// A unique identifier for an event queue.
 int_like!(EventQueueId, AtomicEventQueueId, usize, AtomicUsize);
 
@@ -24,28 +53,92 @@ int_like!(EventQueueId, AtomicEventQueueId, usize, AtomicUsize);
 pub struct EventQueue {
     /// The unique identifier of the event queue.
     id: EventQueueId,
-    /// The wait queue for events.
-    queue: OptimizedWaitQueue<Event>,
+    /// Events not read yet
+    pending: Mutex<PendingEvents>,
+    /// Readers waiting for events
+    condition: WaitCondition,
 }
 
 impl EventQueue {
-    /// Creates a new event queue.
-    pub fn new(id: EventQueueId) -> EventQueue {
+    /// Creates a new event queue, charging its pending events to `uid`.
+    pub fn new(id: EventQueueId, uid: u32) -> EventQueue {
         EventQueue {
             id,
-            queue: OptimizedWaitQueue::new(),
+            pending: Mutex::new(PendingEvents::new(uid)),
+            condition: WaitCondition::new(),
         }
     }
 
     /// Returns true if the event queue is currently empty.
     pub fn is_currently_empty(&self) -> bool {
-        self.queue.is_currently_empty()
+        self.pending.lock().is_empty()
+    }
+
+    /// The most events the queue holds before it overflows
+    pub fn capacity(&self) -> usize {
+        self.pending.lock().capacity
+    }
+
+    /// Let the queue hold `capacity` events, on behalf of user `uid`. Returns the new capacity.
+    pub fn set_capacity(&self, capacity: usize, uid: u32) -> Result<usize> {
+        if capacity == 0 {
+            return Err(Error::new(EINVAL));
+        }
+        if capacity > MAX_QUEUE_EVENTS && uid != 0 {
+            return Err(Error::new(EPERM));
+        }
+        let mut pending = self.pending.lock();
+        if capacity < pending.len() {
+            return Err(Error::new(EBUSY));
+        }
+        pending.capacity = capacity;
+        Ok(capacity)
     }
 
-    /// Reads an event from the event queue.
+    /// Reads events from the event queue, blocking for the first one if `block` is set.
     pub fn read(&self, buf: UserSliceWo, block: bool, token: &mut CleanLockToken) -> Result<usize> {
-        self.queue
-            .receive_into_user(buf, block, "EventQueue::read", token)
+        let mut total = 0;
+
+        for chunk in buf.in_exact_chunks(mem::size_of::<Event>()) {
+            let event = match self.receive(block && total == 0, token) {
+                Ok(event) => event,
+                Err(Error { errno: EAGAIN }) => break,
+                Err(err) => return Err(err),
+            };
+            if event.flags.contains(EVENT_OVERFLOW) {
+                consume_overflow(self.id);
+            } else {
+                consume(self.id, &event);
+            }
+            chunk.copy_exactly(&event)?;
+            total += mem::size_of::<Event>();
+        }
+
+        Ok(total)
+    }
+
+    /// Takes the next event, blocking for it if `block` is set.
+    fn receive(&self, block: bool, token: &mut CleanLockToken) -> Result<Event> {
+        loop {
+            let mut pending = self.pending.lock();
+            if let Some(event) = pending.pop(&mut EVENT_CHARGES.lock()) {
+                return Ok(event);
+            }
+            if !block {
+                return Err(Error::new(EAGAIN));
+            }
+            if !self.condition.wait(pending, "EventQueue::read", token) {
+                return Err(Error::new(EINTR));
+            }
+        }
+    }
+
+    /// Adds an event for the readers, returning false if it was dropped as the queue is full.
+    fn send(&self, event: Event, token: &mut CleanLockToken) -> bool {
+        let sent = self.pending.lock().push(event, &mut EVENT_CHARGES.lock());
+        // Even a dropped event leaves the overflow to be read
+        self.condition.notify(token);
+        sent
     }
 
     /// Writes an event to the event queue.
@@ -56,10 +149,7 @@ impl EventQueue {
                 let context = context_ref.read(token.token());
 
                 let files = context.files.read();
-                match files.get(event.id).ok_or(Error::new(EBADF))? {
-                    Some(file) => file.clone(),
-                    None => return Err(Error::new(EBADF)),
-                }
+                files.get(event.id).ok_or(Error::new(EBADF))?.clone()
             };
 
             let (scheme, number) = {
@@ -87,6 +177,116 @@ impl EventQueue {
     }
 }
 
+impl Drop for EventQueue {
+    fn drop(&mut self) {
+        self.pending.get_mut().clear(&mut EVENT_CHARGES.lock());
+    }
+}
+
+/// The events of a queue that have not been read yet, at most one for each file
+struct PendingEvents {
+    /// The id and data of files with an event, in the order their events arrived
+    order: VecDeque<(usize, usize)>,
+    /// The readiness reported for each file in `order`
+    flags: HashMap<(usize, usize), EventFlags>,
+    /// Most events held at once
+    capacity: usize,
+    /// Whether events were dropped since the last read of [`EVENT_OVERFLOW`]
+    overflowed: bool,
+    /// User the pending events are charged to
+    uid: u32,
+}
+
+impl PendingEvents {
+    fn new(uid: u32) -> Self {
+        Self {
+            order: VecDeque::new(),
+            flags: HashMap::new(),
+            capacity: DEFAULT_QUEUE_EVENTS,
+            overflowed: false,
+            uid,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.order.is_empty() && !self.overflowed
+    }
+
+    /// Add `event`, merged into the pending event for the same file if there is one. Returns
+    /// false and records the overflow if it was dropped, as the queue is full or the user is
+    /// over their quota.
+    fn push(&mut self, event: Event, charges: &mut EventCharges) -> bool {
+        let key = (event.id, event.data);
+        if let Some(flags) = self.flags.get_mut(&key) {
+            *flags |= event.flags;
+            return true;
+        }
+        if self.len() >= self.capacity || !charges.charge(self.uid, PENDING_EVENT_SIZE) {
+            self.overflowed = true;
+            return false;
+        }
+        self.order.push_back(key);
+        self.flags.insert(key, event.flags);
+        true
+    }
+
+    /// Take the next event, the overflow first as it makes the others moot
+    fn pop(&mut self, charges: &mut EventCharges) -> Option<Event> {
+        if mem::take(&mut self.overflowed) {
+            return Some(Event {
+                id: usize::MAX,
+                flags: EVENT_OVERFLOW,
+                data: 0,
+            });
+        }
+        let (id, data) = self.order.pop_front()?;
+        let flags = self
+            .flags
+            .remove(&(id, data))
+            .unwrap_or(EventFlags::empty());
+        charges.uncharge(self.uid, PENDING_EVENT_SIZE);
+        Some(Event { id, flags, data })
+    }
+
+    /// Drop all events, once the queue is gone
+    fn clear(&mut self, charges: &mut EventCharges) {
+        charges.uncharge(self.uid, self.len().saturating_mul(PENDING_EVENT_SIZE));
+        self.order.clear();
+        self.flags.clear();
+        self.overflowed = false;
+    }
+}
+
+/// Memory for pending events charged to each user with a nonzero charge
+struct EventCharges(BTreeMap<u32, usize>);
+
+impl EventCharges {
+    /// Charge `bytes` to `uid`, returning false if that takes a user other than root past
+    /// [`EVENT_USER_QUOTA`]
+    fn charge(&mut self, uid: u32, bytes: usize) -> bool {
+        let total = self.0.get(&uid).copied().unwrap_or(0).saturating_add(bytes);
+        if uid != 0 && total > EVENT_USER_QUOTA {
+            return false;
+        }
+        self.0.insert(uid, total);
+        true
+    }
+
+    /// Give back `bytes` charged to `uid`
+    fn uncharge(&mut self, uid: u32, bytes: usize) {
+        if let Some(total) = self.0.get_mut(&uid) {
+            *total = total.saturating_sub(bytes);
+            if *total == 0 {
+                self.0.remove(&uid);
+            }
+        }
+    }
+}
+
 pub type EventQueueList = HashMap<EventQueueId, Arc<EventQueue>>;
 
 // Next queue id
@@ -128,13 +328,108 @@ pub struct QueueKey {
     pub data: usize,
 }
 
-type Registry = HashMap<RegKey, HashMap<QueueKey, EventFlags>>;
+/// The interest of one event queue in one file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Registration {
+    flags: EventFlags,
+    /// Edge mode: readiness delivered to the queue that has not been read from it yet
+    unconsumed: EventFlags,
+    /// Oneshot mode: cleared by the first delivery
+    armed: bool,
+}
+
+impl Registration {
+    pub fn new(flags: EventFlags) -> Self {
+        Self {
+            flags,
+            unconsumed: EventFlags::empty(),
+            armed: true,
+        }
+    }
+
+    /// The readiness this registration currently wants to hear about
+    pub fn interest(&self) -> EventFlags {
+        if self.armed {
+            self.flags.difference(EVENT_MODES)
+        } else {
+            EventFlags::empty()
+        }
+    }
+
+    /// Returns the part of a `flags` trigger to deliver, updating the edge and oneshot state as
+    /// though it was delivered.
+    fn deliver(&mut self, flags: EventFlags) -> EventFlags {
+        let mut common = flags & self.interest();
+        if self.flags.contains(EVENT_EDGE) {
+            common.remove(self.unconsumed);
+            self.unconsumed |= common;
+        }
+        if self.flags.contains(EVENT_ONESHOT) && !common.is_empty() {
+            self.armed = false;
+        }
+        common
+    }
+
+    /// Note that an event carrying `flags` has been read from the queue
+    fn consume(&mut self, flags: EventFlags) {
+        self.unconsumed.remove(flags);
+    }
+}
+
+/// Registrations of all files, by file and by queue
+#[derive(Default)]
+pub struct Registry {
+    /// The registrations of each file, by the queue and file id they were made with
+    files: HashMap<RegKey, HashMap<QueueKey, Registration>>,
+    /// The files each queue has registrations for, so that a queue finds its own without a scan
+    queues: HashMap<EventQueueId, HashSet<RegKey>>,
+}
+
+impl Registry {
+    /// Forget that `queue_id` has a registration for `reg_key`, if it has no other one left
+    fn unindex(&mut self, reg_key: &RegKey, queue_id: EventQueueId) {
+        let still_registered = self
+            .files
+            .get(reg_key)
+            .is_some_and(|queue_list| queue_list.keys().any(|key| key.queue == queue_id));
+        if still_registered {
+            return;
+        }
+        if let Some(reg_keys) = self.queues.get_mut(&queue_id) {
+            reg_keys.remove(reg_key);
+            if reg_keys.is_empty() {
+                self.queues.remove(&queue_id);
+            }
+        }
+    }
+
+    /// Call `f` on the registrations `queue_id` made with any file
+    fn for_queue(
+        &mut self,
+        queue_id: EventQueueId,
+        mut f: impl FnMut(&QueueKey, &mut Registration),
+    ) {
+        let Some(reg_keys) = self.queues.get(&queue_id) else {
+            return;
+        };
+        for reg_key in reg_keys {
+            let Some(queue_list) = self.files.get_mut(reg_key) else {
+                continue;
+            };
+            for (queue_key, registration) in queue_list.iter_mut() {
+                if queue_key.queue == queue_id {
+                    f(queue_key, registration);
+                }
+            }
+        }
+    }
+}
 
 static REGISTRY: Once<spin::RwLock<Registry>> = Once::new();
 
 /// Initialize registry, called if needed
 fn init_registry() -> spin::RwLock<Registry> {
-    spin::RwLock::new(Registry::new())
+    spin::RwLock::new(Registry::default())
 }
 
 /// Get the global schemes list, const
@@ -149,13 +444,23 @@ pub fn registry_mut() -> spin::RwLockWriteGuard<'static, Registry> {
 
 pub fn register(reg_key: RegKey, queue_key: QueueKey, flags: EventFlags) {
     let mut registry = registry_mut();
+    let queue_id = queue_key.queue;
 
-    let entry = registry.entry(reg_key).or_default();
-
-    if flags.is_empty() {
-        entry.remove(&queue_key);
+    if flags.difference(EVENT_MODES).is_empty() {
+        if let Some(queue_list) = registry.files.get_mut(&reg_key) {
+            queue_list.remove(&queue_key);
+            if queue_list.is_empty() {
+                registry.files.remove(&reg_key);
+            }
+        }
+        registry.unindex(&reg_key, queue_id);
     } else {
-        entry.insert(queue_key, flags);
+        registry
+            .files
+            .entry(reg_key.clone())
+            .or_default()
+            .insert(queue_key, Registration::new(flags));
+        registry.queues.entry(queue_id).or_default().insert(reg_key);
     }
 }
 
@@ -165,34 +470,60 @@ pub fn sync(reg_key: RegKey, token: &mut CleanLockToken) -> Result<EventFlags> {
     {
         let registry = registry();
 
-        if let Some(queue_list) = registry.get(&reg_key) {
-            for (_queue_key, &queue_flags) in queue_list.iter() {
-                flags |= queue_flags;
+        if let Some(queue_list) = registry.files.get(&reg_key) {
+            for registration in queue_list.values() {
+                flags |= registration.interest();
             }
         }
     }
 
-    let scheme = scheme::schemes(&token.token())
-        .get(reg_key.scheme)
-        .ok_or(Error::new(EBADF))?
-        .clone();
+    let scheme = scheme::scheme(reg_key.scheme).ok_or(Error::new(EIO))?;
 
     scheme.fevent(reg_key.number, flags, token)
 }
 
 pub fn unregister_file(scheme: SchemeId, number: usize) {
     let mut registry = registry_mut();
+    let reg_key = RegKey { scheme, number };
 
-    registry.remove(&RegKey { scheme, number });
+    if let Some(queue_list) = registry.files.remove(&reg_key) {
+        for queue_key in queue_list.keys() {
+            registry.unindex(&reg_key, queue_key.queue);
+        }
+    }
+}
+
+/// Lets edge triggered registrations report `event` again, now that it was read from `queue_id`.
+fn consume(queue_id: EventQueueId, event: &Event) {
+    registry_mut().for_queue(queue_id, |queue_key, registration| {
+        if queue_key.id == event.id && queue_key.data == event.data {
+            registration.consume(event.flags);
+        }
+    });
+}
+
+/// Lets edge triggered registrations of `queue_id` report again after [`EVENT_OVERFLOW`] was
+/// read from it, as the events they are waiting to be read from may have been dropped.
+fn consume_overflow(queue_id: EventQueueId) {
+    registry_mut().for_queue(queue_id, |_, registration| {
+        registration.consume(EventFlags::all());
+    });
 }
 
 /// Unregisters all events for a given queue.
 pub fn unregister_queue(queue_id: EventQueueId) {
     let mut registry = registry_mut();
-    registry.retain(|_reg_key, queue_list| {
-        queue_list.retain(|key, _flags| key.queue != queue_id);
-        !queue_list.is_empty()
-    });
+    let Some(reg_keys) = registry.queues.remove(&queue_id) else {
+        return;
+    };
+    for reg_key in reg_keys {
+        if let Some(queue_list) = registry.files.get_mut(&reg_key) {
+            queue_list.retain(|key, _| key.queue != queue_id);
+            if queue_list.is_empty() {
+                registry.files.remove(&reg_key);
+            }
+        }
+    }
 }
 
 /// Triggers an event.
@@ -203,28 +534,37 @@ fn trigger_inner(
     todo: &mut VecDeque<EventQueueId>,
     token: &mut CleanLockToken,
 ) {
-    let registry = registry();
-    if let Some(queue_list) = registry.get(&RegKey { scheme, number }) {
-        for (queue_key, &queue_flags) in queue_list.iter() {
-            let common_flags = flags & queue_flags;
-            if !common_flags.is_empty() {
-                let queue_opt = {
-                    let queues = queues(token.token());
-                    queues.get(&queue_key.queue).cloned()
-                };
-                if let Some(queue) = queue_opt {
-                    queue.queue.send(
-                        Event {
-                            id: queue_key.id,
-                            flags: common_flags,
-                            data: queue_key.data,
-                        },
-                        token,
-                    );
-                    if !todo.contains(&queue_key.queue) {
-                        todo.push_back(queue_key.queue);
-                    }
-                }
+    // Edge and oneshot state changes on delivery, so decide everything up front
+    let deliveries = {
+        let mut registry = registry_mut();
+        let Some(queue_list) = registry.files.get_mut(&RegKey { scheme, number }) else {
+            return;
+        };
+        queue_list
+            .iter_mut()
+            .filter_map(|(queue_key, registration)| {
+                let common_flags = registration.deliver(flags);
+                (!common_flags.is_empty()).then(|| (queue_key.clone(), common_flags))
+            })
+            .collect::<Vec<_>>()
+    };
+
+    for (queue_key, common_flags) in deliveries {
+        let queue_opt = {
+            let queues = queues(token.token());
+            queues.get(&queue_key.queue).cloned()
+        };
+        if let Some(queue) = queue_opt {
+            queue.send(
+                Event {
+                    id: queue_key.id,
+                    flags: common_flags,
+                    data: queue_key.data,
+                },
+                token,
+            );
+            if !todo.contains(&queue_key.queue) {
+                todo.push_back(queue_key.queue);
             }
         }
     }
@@ -246,3 +586,334 @@ pub fn trigger(scheme: SchemeId, number: usize, flags: EventFlags, token: &mut C
         );
     }
 }
+
+#[cfg(feature = "selftest")]
+pub mod selftests {
+    use alloc::format;
+
+    use super::*;
+    use crate::{
+        context::{kthread, ContextRef},
+        cpu_set::{LogicalCpuId, LogicalCpuSet},
+        scheduler,
+        scheme::{
+            pipe::{self, PipeScheme},
+            CallerCtx, KernelScheme, SchemeNamespace,
+        },
+        selftest::{self, check, check_eq, SelftestResult},
+        sync::Priority,
+        syscall::{flag::O_NONBLOCK, usercopy::UserSlice},
+        time,
+    };
+
+    /// Where the events read from the queue go in the user page, after the pipe data
+    const EVENTS_OFFSET: usize = 64;
+    /// Most events read from the queue at once
+    const EVENTS_MAX: usize = 4;
+    /// How long the reader is given to block in the queue before the pipe is written
+    const BLOCK_NS: u128 = 5_000_000;
+
+    /// The queue the reader blocks in, while the test runs
+    static WAITING_QUEUE: Mutex<Option<Arc<EventQueue>>> = Mutex::new(None);
+    /// The reader blocked in the queue, until it returns
+    static READER: Mutex<Option<ContextRef>> = Mutex::new(None);
+    /// What the blocked reader received, once it is woken
+    static RECEIVED: Mutex<Option<Result<Event>>> = Mutex::new(None);
+
+    type PipeTest =
+        fn(&Arc<EventQueue>, usize, usize, usize, &mut CleanLockToken) -> SelftestResult;
+
+    /// Edge and oneshot registrations on the read end of a real pipe, written and read through
+    /// the pipe scheme and read back from a real queue: two writes without a read in between
+    /// make one edge event, and a oneshot registration stays quiet after its first event was read
+    pub fn pipe_edge_and_oneshot(token: &mut CleanLockToken) -> SelftestResult {
+        selftest::with_user_page(|page, token| with_pipe(check_modes, page, token), token)
+    }
+
+    /// A reader blocked in a queue is woken by a write to the pipe it waits on, and receives the
+    /// pipe's read event
+    pub fn pipe_write_wakes_blocked_reader(token: &mut CleanLockToken) -> SelftestResult {
+        selftest::with_user_page(|page, token| with_pipe(check_wakeup, page, token), token)
+    }
+
+    /// Run `test` on a new pipe and event queue, given the queue, the read and write ends, and
+    /// the user page
+    fn with_pipe(test: PipeTest, page: usize, token: &mut CleanLockToken) -> SelftestResult {
+        let ctx = CallerCtx {
+            uid: 0,
+            gid: 0,
+            pid: 0,
+            ns: SchemeNamespace::from(0),
+            mode: 0,
+        };
+        let (reader, writer) =
+            pipe::pipe(&ctx, token).map_err(|err| format!("failed to create a pipe: {}", err))?;
+        let queue_id = next_queue_id();
+        let queue = Arc::new(EventQueue::new(queue_id, 0));
+        queues_mut(token.token()).insert(queue_id, Arc::clone(&queue));
+
+        let result = test(&queue, reader, writer, page, token);
+
+        unregister_queue(queue_id);
+        queues_mut(token.token()).remove(&queue_id);
+        let _ = PipeScheme.close(reader, token);
+        let _ = PipeScheme.close(writer, token);
+        result
+    }
+
+    fn check_modes(
+        queue: &Arc<EventQueue>,
+        reader: usize,
+        writer: usize,
+        page: usize,
+        token: &mut CleanLockToken,
+    ) -> SelftestResult {
+        let reg_key = RegKey {
+            scheme: GlobalSchemes::Pipe.scheme_id(),
+            number: reader,
+        };
+        let queue_key = QueueKey {
+            queue: queue.id,
+            id: 1,
+            data: 0,
+        };
+        let write = |token: &mut CleanLockToken| {
+            PipeScheme.kwrite(writer, UserSlice::ro(page, 1)?, 0, 0, token)
+        };
+        let drain = |token: &mut CleanLockToken| {
+            PipeScheme.kread(reader, UserSlice::wo(page, 2)?, O_NONBLOCK as u32, 0, token)
+        };
+        let read_events = |token: &mut CleanLockToken| {
+            let len = EVENTS_MAX * mem::size_of::<Event>();
+            let buf = UserSlice::wo(page.saturating_add(EVENTS_OFFSET), len)?;
+            Ok::<_, Error>(queue.read(buf, false, token)? / mem::size_of::<Event>())
+        };
+        let read = EventFlags::EVENT_READ;
+
+        register(reg_key.clone(), queue_key.clone(), read | EVENT_EDGE);
+        check_eq!(sync(reg_key.clone(), token), Ok(EventFlags::empty()));
+        check_eq!(write(token), Ok(1));
+        check_eq!(write(token), Ok(1));
+        check_eq!(read_events(token), Ok(1));
+        // Once the event was read, data arriving in the emptied pipe is reported again
+        check_eq!(drain(token), Ok(2));
+        check_eq!(write(token), Ok(1));
+        check_eq!(read_events(token), Ok(1));
+        check_eq!(drain(token), Ok(1));
+
+        register(reg_key.clone(), queue_key, read | EVENT_ONESHOT);
+        check_eq!(sync(reg_key, token), Ok(EventFlags::empty()));
+        check_eq!(write(token), Ok(1));
+        check_eq!(read_events(token), Ok(1));
+        check_eq!(drain(token), Ok(1));
+        check_eq!(write(token), Ok(1));
+        check_eq!(read_events(token), Ok(0));
+        Ok(())
+    }
+
+    fn blocked_reader() {
+        let mut token = unsafe { CleanLockToken::new() };
+        *READER.lock() = Some(context::current());
+        let queue = WAITING_QUEUE.lock().clone();
+        let received = match queue {
+            Some(queue) => queue.receive(true, &mut token),
+            None => Err(Error::new(EBADF)),
+        };
+        READER.lock().take();
+        *RECEIVED.lock() = Some(received);
+    }
+
+    fn check_wakeup(
+        queue: &Arc<EventQueue>,
+        reader: usize,
+        writer: usize,
+        page: usize,
+        token: &mut CleanLockToken,
+    ) -> SelftestResult {
+        *WAITING_QUEUE.lock() = Some(Arc::clone(queue));
+        *READER.lock() = None;
+        *RECEIVED.lock() = None;
+
+        let result = wake_reader(queue, reader, writer, page, token);
+
+        // A reader left blocked by a failed check is interrupted rather than left behind
+        let blocked = READER.lock().take();
+        if let Some(blocked) = blocked {
+            blocked.write(token.token()).being_sigkilled = true;
+            scheduler::unblock(&blocked, token);
+            selftest::switch_until(|| RECEIVED.lock().is_some(), token);
+        }
+        *WAITING_QUEUE.lock() = None;
+        result
+    }
+
+    fn wake_reader(
+        queue: &EventQueue,
+        reader: usize,
+        writer: usize,
+        page: usize,
+        token: &mut CleanLockToken,
+    ) -> SelftestResult {
+        let reg_key = RegKey {
+            scheme: GlobalSchemes::Pipe.scheme_id(),
+            number: reader,
+        };
+        let queue_key = QueueKey {
+            queue: queue.id,
+            id: 1,
+            data: 0,
+        };
+        register(reg_key.clone(), queue_key, EventFlags::EVENT_READ);
+        check_eq!(sync(reg_key, token), Ok(EventFlags::empty()));
+
+        let mut bsp = LogicalCpuSet::new();
+        bsp.add(LogicalCpuId::BSP);
+        kthread::spawn(
+            "[selftest_event_reader]",
+            bsp,
+            Priority::Normal,
+            blocked_reader,
+            token,
+        )
+        .map_err(|err| format!("failed to spawn the reader: {}", err))?
+        .detach(token);
+
+        let hold = time::monotonic().saturating_add(BLOCK_NS);
+        selftest::switch_until(|| time::monotonic() >= hold, token);
+        check!(READER.lock().is_some());
+
+        check_eq!(
+            PipeScheme.kwrite(writer, UserSlice::ro(page, 1)?, 0, 0, token),
+            Ok(1)
+        );
+        check!(selftest::switch_until(|| RECEIVED.lock().is_some(), token));
+        let received = RECEIVED.lock().take();
+        let Some(Ok(event)) = received else {
+            return Err(format!("the reader got {:?} instead of an event", received));
+        };
+        check_eq!(event.id, 1);
+        check!(event.flags.contains(EventFlags::EVENT_READ));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A pipe reports EVENT_READ to its reader on every write that leaves data behind
+    const READ: EventFlags = EventFlags::EVENT_READ;
+
+    #[test]
+    fn level_registration_fires_on_every_write() {
+        let mut registration = Registration::new(READ);
+        assert_eq!(registration.deliver(READ), READ);
+        assert_eq!(registration.deliver(READ), READ);
+    }
+
+    #[test]
+    fn edge_registration_fires_once_until_read() {
+        let mut registration = Registration::new(READ | EVENT_EDGE);
+
+        // Two writes without reading in between: one event
+        assert_eq!(registration.deliver(READ), READ);
+        assert!(registration.deliver(READ).is_empty());
+
+        // Once the event has been read, the next write is reported again
+        registration.consume(READ);
+        assert_eq!(registration.deliver(READ), READ);
+    }
+
+    #[test]
+    fn oneshot_registration_stays_disarmed_after_read() {
+        let mut registration = Registration::new(READ | EVENT_ONESHOT);
+
+        assert_eq!(registration.deliver(READ), READ);
+        registration.consume(READ);
+        assert!(registration.deliver(READ).is_empty());
+        assert!(registration.interest().is_empty());
+
+        // Registering again with fevent re-arms it
+        let mut registration = Registration::new(READ | EVENT_ONESHOT);
+        assert_eq!(registration.deliver(READ), READ);
+    }
+
+    fn event(id: usize, flags: EventFlags) -> Event {
+        Event { id, flags, data: 0 }
+    }
+
+    #[test]
+    fn pending_events_for_the_same_file_are_merged() {
+        let mut charges = EventCharges(BTreeMap::new());
+        let mut pending = PendingEvents::new(1000);
+
+        assert!(pending.push(event(3, READ), &mut charges));
+        assert!(pending.push(event(4, READ), &mut charges));
+        assert!(pending.push(event(3, EventFlags::EVENT_WRITE), &mut charges));
+        assert_eq!(pending.len(), 2);
+
+        let first = pending.pop(&mut charges).unwrap();
+        assert_eq!((first.id, first.flags), (3, READ | EventFlags::EVENT_WRITE));
+        assert_eq!(pending.pop(&mut charges).unwrap().id, 4);
+        assert!(pending.pop(&mut charges).is_none());
+        assert!(charges.0.is_empty());
+    }
+
+    #[test]
+    fn flooded_queue_stays_bounded_and_reports_overflow() {
+        let mut charges = EventCharges(BTreeMap::new());
+        let mut pending = PendingEvents::new(1000);
+
+        // A producer triggering far more files than fit while the reader is blocked
+        for round in 0..4 {
+            for id in 0..2 * DEFAULT_QUEUE_EVENTS {
+                let sent = pending.push(event(id, READ), &mut charges);
+                assert_eq!(sent, id < DEFAULT_QUEUE_EVENTS, "round {} id {}", round, id);
+            }
+        }
+        assert_eq!(pending.len(), DEFAULT_QUEUE_EVENTS);
+        assert_eq!(
+            charges.0.get(&1000),
+            Some(&(DEFAULT_QUEUE_EVENTS * PENDING_EVENT_SIZE))
+        );
+
+        // The reader hears of the overflow first, then of the events that were kept
+        assert_eq!(pending.pop(&mut charges).unwrap().flags, EVENT_OVERFLOW);
+        for id in 0..DEFAULT_QUEUE_EVENTS {
+            assert_eq!(pending.pop(&mut charges).unwrap().id, id);
+        }
+        assert!(pending.pop(&mut charges).is_none());
+        assert!(pending.is_empty());
+        assert!(charges.0.is_empty());
+    }
+
+    #[test]
+    fn pending_events_count_against_the_user_quota() {
+        let mut charges = EventCharges(BTreeMap::new());
+        assert!(charges.charge(1000, EVENT_USER_QUOTA - PENDING_EVENT_SIZE));
+        let mut pending = PendingEvents::new(1000);
+
+        assert!(pending.push(event(1, READ), &mut charges));
+        assert!(!pending.push(event(2, READ), &mut charges));
+        // Merging into a pending event costs nothing
+        assert!(pending.push(event(1, EventFlags::EVENT_WRITE), &mut charges));
+
+        // Root is not limited
+        let mut root = PendingEvents::new(0);
+        assert!(root.push(event(1, READ), &mut charges));
+
+        pending.clear(&mut charges);
+        root.clear(&mut charges);
+        assert_eq!(
+            charges.0.get(&1000),
+            Some(&(EVENT_USER_QUOTA - PENDING_EVENT_SIZE))
+        );
+        assert_eq!(charges.0.get(&0), None);
+    }
+
+    #[test]
+    fn mode_bits_are_not_readiness() {
+        let registration = Registration::new(EventFlags::EVENT_WRITE | EVENT_EDGE | EVENT_ONESHOT);
+        assert_eq!(registration.interest(), EventFlags::EVENT_WRITE);
+    }
+}
@@ -12,16 +12,22 @@ use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
 use core::{
     mem::MaybeUninit,
     ptr::NonNull,
-    sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
 };
-use spin::RwLock;
+use spin::{Once, RwLock};
 
 use crate::{
     context::{self, ContextRef},
-    memory::{allocate_frame, deallocate_frame, Frame, RmmA, RmmArch, PAGE_SIZE},
-    sync::{CleanLockToken, IpcCriticalGuard, LockFreeQueue, Priority, PriorityTracker},
+    memory::{
+        allocate_frame, allocate_reserved_frame, deallocate_frame, Frame, PhysicalAddress, RmmA,
+        RmmArch, PAGE_SIZE,
+    },
+    scheduler,
+    sync::{
+        CleanLockToken, IpcCriticalGuard, LockFreeQueue, Priority, PriorityTracker, WaitCondition,
+    },
     syscall::{
-        error::{Error, Result, EBADF, EINVAL, ENOMEM, EPERM, ETIMEDOUT},
+        error::{Error, Result, EBADF, EINTR, EINVAL, ENOMEM, EPERM, ETIMEDOUT},
         flag::MapFlags,
     },
     time::monotonic,
@@ -42,6 +48,19 @@ pub const INLINE_MSG_MAX: usize = 64;
 /// Maximum number of shared buffers in the pool
 pub const SHARED_BUFFER_POOL_SIZE: usize = 256;
 
+/// Number of shared buffers the pool starts out with, and never shrinks below
+pub const SHARED_BUFFER_POOL_INITIAL: usize = 16;
+
+/// Time a free shared buffer beyond the initial ones is kept before its frame is freed
+/// (nanoseconds)
+pub const SHARED_BUFFER_IDLE_NS: u64 = 10_000_000_000; // 10 seconds
+
+/// Minimum time between two scans for idle shared buffers (nanoseconds)
+const SHARED_BUFFER_SHRINK_PERIOD_NS: u64 = 1_000_000_000;
+
+/// Number of segments the shared buffer pool can grow to, each as large as all before it
+const SHARED_BUFFER_SEGMENTS: usize = 24;
+
 /// Size of each shared buffer (one page)
 pub const SHARED_BUFFER_SIZE: usize = PAGE_SIZE;
 
@@ -54,7 +73,7 @@ pub const DEFAULT_IPC_TIMEOUT_NS: u64 = 5_000_000_000; // 5 seconds
 
 /// A zero-copy message that can be transferred between processes
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub struct ZeroCopyMessage {
     /// Message header with metadata
     pub header: MessageHeader,
@@ -149,31 +168,102 @@ pub struct SharedBufferRef {
 
 /// A pre-allocated buffer for zero-copy transfers
 pub struct SharedBuffer {
-    /// Physical frame backing this buffer
-    frame: Frame,
+    /// Base address of the physical frame backing this buffer, or [`Self::NO_FRAME`] once the
+    /// pool freed it
+    frame: AtomicUsize,
+    /// Time the buffer was last released, or added to the pool
+    last_used: AtomicU64,
     /// Reference count for concurrent access
     ref_count: AtomicU32,
     /// Owner context (0 = available)
     owner: AtomicUsize,
     /// Lock state for exclusive access
     locked: AtomicU32,
+    /// Contexts the owner has shared this buffer with
+    peers: spin::Mutex<Vec<usize>>,
 }
 
 impl SharedBuffer {
+    /// Never a frame base address, as those are page aligned
+    const NO_FRAME: usize = usize::MAX;
+
     /// Create a new shared buffer backed by a physical frame
     fn new(frame: Frame) -> Self {
+        Self::with_frame(frame.base().data())
+    }
+
+    /// Create a buffer slot the pool has no frame for yet
+    fn without_frame() -> Self {
+        Self::with_frame(Self::NO_FRAME)
+    }
+
+    fn with_frame(frame: usize) -> Self {
         SharedBuffer {
-            frame,
+            frame: AtomicUsize::new(frame),
+            last_used: AtomicU64::new(0),
             ref_count: AtomicU32::new(0),
             owner: AtomicUsize::new(0),
             locked: AtomicU32::new(0),
+            peers: spin::Mutex::new(Vec::new()),
         }
     }
 
+    /// Context that allocated this buffer, or 0 if it is free
+    #[inline]
+    pub fn owner(&self) -> usize {
+        self.owner.load(Ordering::Acquire)
+    }
+
+    /// Allow `peer` to access this buffer, which only its owner may do
+    pub fn share(&self, caller: usize, peer: usize) -> Result<()> {
+        if caller == 0 || self.owner() != caller {
+            return Err(Error::new(EPERM));
+        }
+        let mut peers = self.peers.lock();
+        if !peers.contains(&peer) {
+            peers.push(peer);
+        }
+        Ok(())
+    }
+
+    /// Returns true if `id` owns this buffer or the owner shared it with `id`
+    pub fn may_access(&self, id: usize) -> bool {
+        id != 0 && (self.owner() == id || self.peers.lock().contains(&id))
+    }
+
+    /// Physical frame backing this buffer
+    #[inline]
+    pub fn frame(&self) -> Frame {
+        Frame::containing(PhysicalAddress::new(self.frame.load(Ordering::Acquire)))
+    }
+
+    /// Returns true if the buffer is backed by a frame
+    #[inline]
+    fn has_frame(&self) -> bool {
+        self.frame.load(Ordering::Acquire) != Self::NO_FRAME
+    }
+
+    /// Back a free buffer by `frame`, as of `now`
+    fn set_frame(&self, frame: Frame, now: u64) {
+        self.last_used.store(now, Ordering::Relaxed);
+        self.frame.store(frame.base().data(), Ordering::Release);
+    }
+
+    /// Take the frame of a free buffer, which the caller must deallocate
+    fn take_frame(&self) -> Option<Frame> {
+        let base = self.frame.swap(Self::NO_FRAME, Ordering::AcqRel);
+        (base != Self::NO_FRAME).then(|| Frame::containing(PhysicalAddress::new(base)))
+    }
+
+    /// Returns true if the buffer has been free since before `cutoff`
+    fn idle_since(&self, cutoff: u64) -> bool {
+        self.owner() == 0 && self.last_used.load(Ordering::Relaxed) < cutoff
+    }
+
     /// Get the virtual address of this buffer's data
     #[inline]
     pub fn as_ptr(&self) -> *mut u8 {
-        unsafe { RmmA::phys_to_virt(self.frame.base()).data() as *mut u8 }
+        unsafe { RmmA::phys_to_virt(self.frame().base()).data() as *mut u8 }
     }
 
     /// Get a slice view of the buffer
@@ -188,15 +278,22 @@ impl SharedBuffer {
         unsafe { core::slice::from_raw_parts_mut(self.as_ptr(), SHARED_BUFFER_SIZE) }
     }
 
-    /// Acquire the buffer for exclusive use
+    /// Acquire the buffer for exclusive use, holding the first reference
     fn acquire(&self, owner_id: usize) -> bool {
-        self.owner
+        if self
+            .owner
             .compare_exchange(0, owner_id, Ordering::AcqRel, Ordering::Relaxed)
-            .is_ok()
+            .is_err()
+        {
+            return false;
+        }
+        self.ref_count.store(1, Ordering::Release);
+        true
     }
 
     /// Release the buffer
     fn release(&self) {
+        self.peers.lock().clear();
         self.owner.store(0, Ordering::Release);
         self.ref_count.store(0, Ordering::Release);
         self.locked.store(0, Ordering::Release);
@@ -207,6 +304,15 @@ impl SharedBuffer {
         self.ref_count.fetch_add(1, Ordering::AcqRel);
     }
 
+    /// Increment reference count, unless the buffer has already been released
+    fn try_add_ref(&self) -> bool {
+        self.ref_count
+            .fetch_update(Ordering::AcqRel, Ordering::Relaxed, |count| {
+                (count != 0).then(|| count + 1)
+            })
+            .is_ok()
+    }
+
     /// Decrement reference count
     fn remove_ref(&self) -> bool {
         self.ref_count.fetch_sub(1, Ordering::AcqRel) == 1
@@ -218,10 +324,9 @@ impl SharedBuffer {
         context: &ContextRef,
         virt: VirtualAddress,
         flags: MapFlags,
+        token: &mut CleanLockToken,
     ) -> Result<()> {
-        let mut token = unsafe { CleanLockToken::new() };
-        let ctx = context.read(token.token());
-        let addr_space = ctx.addr_space()?;
+        let addr_space = Arc::clone(context.read(token.token()).addr_space()?);
 
         // Convert MapFlags to PageFlags
         // TODO: Move this conversion to a common helper
@@ -246,7 +351,7 @@ impl SharedBuffer {
                 .table
                 .utable
                 .0
-                .map_phys(virt, self.frame.base(), page_flags)
+                .map_phys(virt, self.frame().base(), page_flags)
                 .ok_or(Error::new(ENOMEM))?
                 .flush();
         }
@@ -256,9 +361,24 @@ impl SharedBuffer {
 }
 
 /// Pool of shared buffers for zero-copy transfers
+///
+/// The pool starts with `initial` buffers and grows on demand, adding a segment as large as all
+/// buffers so far whenever [`Self::allocate`] finds no free one, until it holds `max`. Buffer IDs
+/// are stable: segments are never removed, [`Self::shrink`] only frees the frames of buffers
+/// beyond the first segment that have been idle, and [`Self::allocate`] backs them again.
 pub struct SharedBufferPool {
-    /// Pre-allocated buffers
-    buffers: Vec<SharedBuffer>,
+    /// Buffer segments, published once each and never removed
+    segments: [Once<Box<[SharedBuffer]>>; SHARED_BUFFER_SEGMENTS],
+    /// Number of published segments
+    segment_count: AtomicUsize,
+    /// Number of buffers in the first segment
+    initial: usize,
+    /// Maximum number of buffers over all segments
+    max: usize,
+    /// Set while a caller of [`Self::allocate`] adds a segment
+    growing: AtomicBool,
+    /// Time of the last [`Self::shrink`] scan
+    last_shrink: AtomicU64,
     /// Free list implemented as a stack (indices of available buffers)
     free_stack: LockFreeQueue<u32>,
     /// Statistics
@@ -272,88 +392,289 @@ pub struct BufferPoolStats {
     pub deallocations: AtomicU64,
     pub allocation_failures: AtomicU64,
     pub high_watermark: AtomicU32,
+    /// Number of buffers over all segments, with or without a frame
+    pub capacity: AtomicU32,
+    /// Number of buffers currently backed by a frame
+    pub frames: AtomicU32,
+    /// Number of segments added after the first
+    pub grows: AtomicU64,
+    /// Number of frames freed from idle buffers
+    pub shrinks: AtomicU64,
+}
+
+/// Owner the shrinker holds a buffer under while freeing its frame, never a context ID
+const SHRINK_OWNER: usize = usize::MAX;
+
+/// Segment and index within it of buffer `id`, in a pool whose first segment has `initial` buffers
+///
+/// Segment `n > 0` starts at `initial << (n - 1)` and is as large as all segments before it.
+fn segment_of(initial: usize, id: usize) -> (usize, usize) {
+    if id < initial {
+        return (0, id);
+    }
+    let segment = (id / initial).ilog2() as usize + 1;
+    (segment, id - (initial << (segment - 1)))
 }
 
 impl SharedBufferPool {
-    /// Create a new buffer pool with the specified capacity
-    pub fn new(capacity: usize) -> Result<Self> {
-        let mut buffers = Vec::with_capacity(capacity);
+    /// Create a buffer pool of `initial` buffers that may grow to `max`
+    pub fn new(initial: usize, max: usize) -> Result<Self> {
+        if initial == 0 || max < initial || max > u32::MAX as usize {
+            return Err(Error::new(EINVAL));
+        }
+
+        let mut buffers = Vec::with_capacity(initial);
         let free_stack = LockFreeQueue::new();
 
-        for i in 0..capacity {
-            let frame = allocate_frame().ok_or(Error::new(ENOMEM))?;
+        for i in 0..initial {
+            let Some(frame) = allocate_reserved_frame() else {
+                for buffer in &buffers {
+                    unsafe { deallocate_frame(buffer.frame()) };
+                }
+                return Err(Error::new(ENOMEM));
+            };
             buffers.push(SharedBuffer::new(frame));
             free_stack.enqueue(i as u32);
         }
 
+        let segments = [const { Once::new() }; SHARED_BUFFER_SEGMENTS];
+        segments[0].call_once(|| buffers.into_boxed_slice());
+
+        let stats = BufferPoolStats::default();
+        stats.capacity.store(initial as u32, Ordering::Relaxed);
+        stats.frames.store(initial as u32, Ordering::Relaxed);
+
         Ok(SharedBufferPool {
-            buffers,
+            segments,
+            segment_count: AtomicUsize::new(1),
+            initial,
+            max,
+            growing: AtomicBool::new(false),
+            last_shrink: AtomicU64::new(0),
             free_stack,
-            stats: BufferPoolStats::default(),
+            stats,
         })
     }
 
-    /// Allocate a buffer from the pool
-    pub fn allocate(&self, owner_id: usize) -> Option<u32> {
-        // Try to get a buffer from the free stack
-        let buffer_id = self.free_stack.dequeue()?;
+    /// Number of buffers the pool has room for without growing
+    pub fn capacity(&self) -> usize {
+        self.stats.capacity.load(Ordering::Acquire) as usize
+    }
 
-        // Try to acquire the buffer
-        if self.buffers[buffer_id as usize].acquire(owner_id) {
-            self.stats.allocations.fetch_add(1, Ordering::Relaxed);
-
-            // Update high watermark
-            let current = self.stats.allocations.load(Ordering::Relaxed)
-                - self.stats.deallocations.load(Ordering::Relaxed);
-            let mut hwm = self.stats.high_watermark.load(Ordering::Relaxed);
-            while current as u32 > hwm {
-                match self.stats.high_watermark.compare_exchange_weak(
-                    hwm,
-                    current as u32,
-                    Ordering::Relaxed,
-                    Ordering::Relaxed,
-                ) {
-                    Ok(_) => break,
-                    Err(new_hwm) => hwm = new_hwm,
+    /// Statistics of the pool
+    pub fn stats(&self) -> &BufferPoolStats {
+        &self.stats
+    }
+
+    /// Add the next segment, unless the pool is at its maximum or another caller is growing it
+    ///
+    /// Frames come from the regular allocator without touching the reserve, and a segment is
+    /// only published if at least one of its buffers could be backed. Buffers left without a
+    /// frame are backed by [`Self::allocate`] once they are handed out.
+    fn grow(&self) {
+        if self
+            .growing
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return;
+        }
+
+        let segment = self.segment_count.load(Ordering::Acquire);
+        let start = self.capacity();
+        let len = start.min(self.max - start);
+        if segment < SHARED_BUFFER_SEGMENTS && len > 0 {
+            let now = monotonic() as u64;
+            let mut buffers = Vec::new();
+            if buffers.try_reserve_exact(len).is_ok() {
+                let mut frames = 0;
+                buffers.extend((0..len).map(|_| match allocate_frame() {
+                    Some(frame) => {
+                        frames += 1;
+                        let buffer = SharedBuffer::without_frame();
+                        buffer.set_frame(frame, now);
+                        buffer
+                    }
+                    None => SharedBuffer::without_frame(),
+                }));
+
+                if frames > 0 {
+                    self.segments[segment].call_once(|| buffers.into_boxed_slice());
+                    self.segment_count.store(segment + 1, Ordering::Release);
+                    self.stats.frames.fetch_add(frames, Ordering::Relaxed);
+                    self.stats
+                        .capacity
+                        .store((start + len) as u32, Ordering::Release);
+                    self.stats.grows.fetch_add(1, Ordering::Relaxed);
+                    for id in start..start + len {
+                        self.free_stack.enqueue(id as u32);
+                    }
                 }
             }
+        }
 
-            Some(buffer_id)
-        } else {
+        self.growing.store(false, Ordering::Release);
+    }
+
+    /// Allocate a buffer from the pool
+    ///
+    /// Returns `None` if every buffer is in use and the pool cannot grow, or if no frame is left
+    /// to back the buffer with.
+    pub fn allocate(&self, owner_id: usize) -> Option<u32> {
+        // Try to get a buffer from the free stack, growing the pool if there is none
+        let Some(buffer_id) = self.free_stack.dequeue().or_else(|| {
+            self.grow();
+            self.free_stack.dequeue()
+        }) else {
+            self.stats
+                .allocation_failures
+                .fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        let buffer = self.get(buffer_id)?;
+
+        // Try to acquire the buffer
+        if !buffer.acquire(owner_id) {
             // Put buffer back on free stack
             self.free_stack.enqueue(buffer_id);
             self.stats
                 .allocation_failures
                 .fetch_add(1, Ordering::Relaxed);
-            None
+            return None;
         }
+
+        // Back it again if the pool freed its frame while it was idle
+        if !buffer.has_frame() {
+            let Some(frame) = allocate_frame() else {
+                buffer.release();
+                self.free_stack.enqueue(buffer_id);
+                self.stats
+                    .allocation_failures
+                    .fetch_add(1, Ordering::Relaxed);
+                return None;
+            };
+            buffer.set_frame(frame, monotonic() as u64);
+            self.stats.frames.fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.stats.allocations.fetch_add(1, Ordering::Relaxed);
+
+        // Update high watermark
+        let current = self.stats.allocations.load(Ordering::Relaxed)
+            - self.stats.deallocations.load(Ordering::Relaxed);
+        let mut hwm = self.stats.high_watermark.load(Ordering::Relaxed);
+        while current as u32 > hwm {
+            match self.stats.high_watermark.compare_exchange_weak(
+                hwm,
+                current as u32,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(new_hwm) => hwm = new_hwm,
+            }
+        }
+
+        Some(buffer_id)
     }
 
     /// Release a buffer back to the pool
     pub fn release(&self, buffer_id: u32) {
-        if (buffer_id as usize) < self.buffers.len() {
-            self.buffers[buffer_id as usize].release();
+        if let Some(buffer) = self.get(buffer_id) {
+            buffer
+                .last_used
+                .store(monotonic() as u64, Ordering::Relaxed);
+            buffer.release();
             self.free_stack.enqueue(buffer_id);
             self.stats.deallocations.fetch_add(1, Ordering::Relaxed);
         }
     }
 
+    /// Free the frames of buffers beyond the first segment that have been free for longer than
+    /// [`SHARED_BUFFER_IDLE_NS`] as of `now`
+    ///
+    /// Scans at most once every [`SHARED_BUFFER_SHRINK_PERIOD_NS`], so it can be called from a
+    /// polling loop.
+    pub fn shrink(&self, now: u64) {
+        let last = self.last_shrink.load(Ordering::Relaxed);
+        if now.saturating_sub(last) < SHARED_BUFFER_SHRINK_PERIOD_NS
+            || self
+                .last_shrink
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            return;
+        }
+
+        let cutoff = now.saturating_sub(SHARED_BUFFER_IDLE_NS);
+        let segments = self.segment_count.load(Ordering::Acquire);
+        for segment in self.segments[1..segments].iter().filter_map(Once::get) {
+            for buffer in segment.iter() {
+                if !buffer.has_frame() || !buffer.idle_since(cutoff) {
+                    continue;
+                }
+                // Hold the buffer so allocate cannot hand it out while its frame goes away
+                if !buffer.acquire(SHRINK_OWNER) {
+                    continue;
+                }
+                if let Some(frame) = buffer.take_frame() {
+                    unsafe { deallocate_frame(frame) };
+                    self.stats.frames.fetch_sub(1, Ordering::Relaxed);
+                    self.stats.shrinks.fetch_add(1, Ordering::Relaxed);
+                }
+                buffer.release();
+            }
+        }
+    }
+
+    /// Take another reference to an allocated buffer on behalf of `accessor`
+    ///
+    /// Fails with EPERM unless `accessor` owns the buffer or the owner shared it with them.
+    pub fn retain(&self, buffer_id: u32, accessor: usize) -> Result<&SharedBuffer> {
+        let buffer = self.get(buffer_id).ok_or(Error::new(EBADF))?;
+        if !buffer.may_access(accessor) {
+            return Err(Error::new(EPERM));
+        }
+        if !buffer.try_add_ref() {
+            return Err(Error::new(EBADF));
+        }
+        Ok(buffer)
+    }
+
+    /// Drop a reference from [`Self::allocate`] or [`Self::retain`]
+    ///
+    /// The buffer goes back to the pool once its last reference is dropped.
+    pub fn put(&self, buffer_id: u32) {
+        let Some(buffer) = self.get(buffer_id) else {
+            return;
+        };
+        if buffer.remove_ref() {
+            self.release(buffer_id);
+        }
+    }
+
     /// Get a reference to a buffer by ID
     pub fn get(&self, buffer_id: u32) -> Option<&SharedBuffer> {
-        self.buffers.get(buffer_id as usize)
+        let (segment, index) = segment_of(self.initial, buffer_id as usize);
+        self.segments.get(segment)?.get()?.get(index)
     }
 
     /// Get a mutable reference to a buffer by ID
     pub fn get_mut(&mut self, buffer_id: u32) -> Option<&mut SharedBuffer> {
-        self.buffers.get_mut(buffer_id as usize)
+        let (segment, index) = segment_of(self.initial, buffer_id as usize);
+        self.segments.get_mut(segment)?.get_mut()?.get_mut(index)
     }
 }
 
 impl Drop for SharedBufferPool {
     fn drop(&mut self) {
-        for buffer in &self.buffers {
-            unsafe {
-                deallocate_frame(buffer.frame);
+        for segment in self.segments.iter().filter_map(Once::get) {
+            for buffer in segment.iter() {
+                if let Some(frame) = buffer.take_frame() {
+                    unsafe {
+                        deallocate_frame(frame);
+                    }
+                }
             }
         }
     }
@@ -384,8 +705,8 @@ pub struct IpcChannel {
     state: AtomicU32,
     /// Send queue (messages waiting to be received)
     send_queue: LockFreeQueue<ZeroCopyMessage>,
-    /// Receive queue (for replies)
-    recv_queue: LockFreeQueue<ZeroCopyMessage>,
+    /// Callers blocked in [`Self::call`], by the sequence number of their message
+    replies: spin::Mutex<BTreeMap<u64, Arc<ReplyWaiter>>>,
     /// Context waiting on this channel
     waiting_context: AtomicUsize,
     /// Priority tracker for priority inheritance
@@ -396,6 +717,12 @@ pub struct IpcChannel {
     stats: ChannelStats,
 }
 
+/// A caller waiting for the reply to its message, see [`IpcChannel::call`]
+struct ReplyWaiter {
+    reply: spin::Mutex<Option<ZeroCopyMessage>>,
+    condition: WaitCondition,
+}
+
 /// Channel statistics for monitoring
 #[derive(Debug, Default)]
 pub struct ChannelStats {
@@ -412,7 +739,7 @@ impl IpcChannel {
             id,
             state: AtomicU32::new(ChannelState::Ready as u32),
             send_queue: LockFreeQueue::new(),
-            recv_queue: LockFreeQueue::new(),
+            replies: spin::Mutex::new(BTreeMap::new()),
             waiting_context: AtomicUsize::new(0),
             priority: PriorityTracker::new(Priority::Normal),
             seq_counter: AtomicU64::new(0),
@@ -437,7 +764,17 @@ impl IpcChannel {
     }
 
     /// Send a message through the channel (non-blocking)
-    pub fn send(&self, mut msg: ZeroCopyMessage) -> Result<u64> {
+    pub fn send(&self, msg: ZeroCopyMessage, token: &mut CleanLockToken) -> Result<u64> {
+        self.send_inner(msg, None, token)
+    }
+
+    /// Send `msg`, registering `waiter` for its reply before the receiver can see it
+    fn send_inner(
+        &self,
+        mut msg: ZeroCopyMessage,
+        waiter: Option<&Arc<ReplyWaiter>>,
+        token: &mut CleanLockToken,
+    ) -> Result<u64> {
         if self.state() == ChannelState::Closed {
             return Err(Error::new(EBADF));
         }
@@ -445,6 +782,9 @@ impl IpcChannel {
         // Assign sequence number
         let seq = self.seq_counter.fetch_add(1, Ordering::Relaxed);
         msg.header.seq = seq;
+        if let Some(waiter) = waiter {
+            self.replies.lock().insert(seq, Arc::clone(waiter));
+        }
         msg.header.timestamp = monotonic() as u64;
 
         // Priority boost for high-priority messages
@@ -466,7 +806,7 @@ impl IpcChannel {
         // Wake up waiting receiver if any
         let waiter = self.waiting_context.load(Ordering::Acquire);
         if waiter != 0 {
-            self.wake_waiter(waiter);
+            self.wake_waiter(waiter, token);
         }
 
         if msg.header.flags.contains(MessageFlags::HIGH_PRIORITY) {
@@ -552,7 +892,10 @@ impl IpcChannel {
     }
 
     /// Send a reply message
-    pub fn reply(&self, reply: ZeroCopyMessage) -> Result<()> {
+    ///
+    /// Replies keep the header of the message they answer, which picks the caller to wake. A
+    /// reply whose caller is no longer waiting, having been interrupted, is dropped.
+    pub fn reply(&self, reply: ZeroCopyMessage, token: &mut CleanLockToken) -> Result<()> {
         if self.state() == ChannelState::Closed {
             return Err(Error::new(EBADF));
         }
@@ -561,57 +904,71 @@ impl IpcChannel {
         msg.header.flags |= MessageFlags::IS_REPLY;
         msg.header.timestamp = monotonic() as u64;
 
-        self.recv_queue.enqueue(msg);
+        let Some(waiter) = self.replies.lock().remove(&msg.header.seq) else {
+            return Ok(());
+        };
+        *waiter.reply.lock() = Some(msg);
+        waiter.condition.notify(token);
         Ok(())
     }
 
-    /// Wait for a reply to a sent message
-    pub fn wait_reply(
+    /// Send `msg` and block until the receiver replies to it, see [`Self::reply`]
+    ///
+    /// There is no timeout, as the receiver may take as long as the call it serves. Fails with
+    /// EINTR if a signal arrives first, and with EBADF if the channel is closed.
+    pub fn call(
         &self,
-        _seq: u64,
+        msg: ZeroCopyMessage,
         token: &mut CleanLockToken,
-        timeout_ns: u64,
     ) -> Result<ZeroCopyMessage> {
-        let deadline = monotonic() as u64 + timeout_ns;
-
-        loop {
-            // Check for matching reply
-            if let Some(msg) = self.recv_queue.dequeue() {
-                return Ok(msg);
-            }
-
-            // Check timeout
-            if monotonic() as u64 > deadline {
-                return Err(Error::new(ETIMEDOUT));
+        let waiter = Arc::new(ReplyWaiter {
+            reply: spin::Mutex::new(None),
+            condition: WaitCondition::new(),
+        });
+        let seq = self.send_inner(msg, Some(&waiter), token)?;
+
+        let result = loop {
+            let mut reply = waiter.reply.lock();
+            if let Some(reply) = reply.take() {
+                break Ok(reply);
             }
-
-            // Yield to other contexts
-            unsafe { context::switch(token) };
-
             if self.state() == ChannelState::Closed {
-                return Err(Error::new(EBADF));
+                break Err(Error::new(EBADF));
             }
-        }
+            if !waiter.condition.wait(reply, "IpcChannel::call", token) {
+                break Err(Error::new(EINTR));
+            }
+        };
+        // A reply that comes later has nobody to go to
+        self.replies.lock().remove(&seq);
+        result
     }
 
     /// Close the channel
-    pub fn close(&self) {
+    pub fn close(&self, token: &mut CleanLockToken) {
         self.state
             .store(ChannelState::Closed as u32, Ordering::Release);
 
         // Wake up any waiting context
         let waiter = self.waiting_context.swap(0, Ordering::AcqRel);
         if waiter != 0 {
-            self.wake_waiter(waiter);
+            self.wake_waiter(waiter, token);
+        }
+
+        // Callers see the channel closed once woken
+        let callers: Vec<_> = self.replies.lock().values().cloned().collect();
+        for caller in callers {
+            // Once its lock is free, a caller that missed the close is waiting to be woken
+            drop(caller.reply.lock());
+            caller.condition.notify(token);
         }
     }
 
     /// Wake up a waiting context
-    fn wake_waiter(&self, context_id: usize) {
-        let mut token = unsafe { CleanLockToken::new() };
-        let contexts = context::contexts().read();
-        if let Some(context_ref) = contexts.get(&context_id) {
-            context_ref.write(token.token()).unblock();
+    fn wake_waiter(&self, context_id: usize, token: &mut CleanLockToken) {
+        let context_ref = context::contexts().read().get(&context_id).cloned();
+        if let Some(context_ref) = context_ref {
+            scheduler::unblock(&context_ref, token);
         }
     }
 
@@ -698,8 +1055,11 @@ impl IpcRegistry {
     }
 
     /// Initialize the buffer pool (must be called after memory is initialized)
-    pub fn init_buffer_pool(&mut self) -> Result<()> {
-        self.buffer_pool = Some(SharedBufferPool::new(SHARED_BUFFER_POOL_SIZE)?);
+    fn init_buffer_pool(&mut self) -> Result<()> {
+        self.buffer_pool = Some(SharedBufferPool::new(
+            SHARED_BUFFER_POOL_INITIAL,
+            SHARED_BUFFER_POOL_SIZE,
+        )?);
         Ok(())
     }
 
@@ -722,9 +1082,9 @@ impl IpcRegistry {
     }
 
     /// Close and remove a channel
-    pub fn close_channel(&self, id: u64) -> Result<()> {
+    pub fn close_channel(&self, id: u64, token: &mut CleanLockToken) -> Result<()> {
         let channel = self.channels.write().remove(&id).ok_or(Error::new(EBADF))?;
-        channel.close();
+        channel.close(token);
         Ok(())
     }
 
@@ -745,6 +1105,11 @@ impl IpcRegistry {
         self.buffer_pool.as_ref()?.get(buffer_id)
     }
 
+    /// Get the shared buffer pool, if it has been initialized
+    pub fn buffer_pool(&self) -> Option<&SharedBufferPool> {
+        self.buffer_pool.as_ref()
+    }
+
     /// Map a shared buffer into a context
     pub fn map_buffer(
         &self,
@@ -752,24 +1117,90 @@ impl IpcRegistry {
         context: &ContextRef,
         virt: VirtualAddress,
         flags: MapFlags,
+        token: &mut CleanLockToken,
     ) -> Result<()> {
         let pool = self.buffer_pool.as_ref().ok_or(Error::new(EINVAL))?;
         let buffer = pool.get(buffer_id).ok_or(Error::new(EBADF))?;
-        buffer.map_to_context(context, virt, flags)
+        buffer.map_to_context(context, virt, flags, token)
     }
 }
 
-/// Global IPC registry instance
-pub static mut IPC_REGISTRY: IpcRegistry = IpcRegistry::new();
+/// Global IPC registry instance, see [`init`]
+static IPC_REGISTRY: Once<IpcRegistry> = Once::new();
+
+/// Create the global IPC registry and its shared buffer pool
+///
+/// Must run once during kmain, after the frame allocator is up and before the IPC schemes are
+/// registered or any AP can reach IPC code. Only `channels` and the buffers' own atomics change
+/// afterwards, so the registry is shared without further locking.
+pub fn init() {
+    IPC_REGISTRY.call_once(|| {
+        let mut registry = IpcRegistry::new();
+        if let Err(err) = registry.init_buffer_pool() {
+            warn!("failed to allocate IPC shared buffer pool: {:?}", err);
+        }
+        registry
+    });
+}
 
 /// Get the global IPC registry
+///
+/// Panics if called before [`init`].
 pub fn registry() -> &'static IpcRegistry {
-    unsafe { &IPC_REGISTRY }
+    IPC_REGISTRY
+        .get()
+        .expect("IPC registry used before ipc::init")
+}
+
+/// Free the frames of shared buffers that have been idle for a while, see
+/// [`SharedBufferPool::shrink`]
+pub fn reap_buffers() {
+    if let Some(pool) = IPC_REGISTRY.get().and_then(IpcRegistry::buffer_pool) {
+        pool.shrink(monotonic() as u64);
+    }
+}
+
+/// A reference to a shared buffer, dropped back to the pool with the value
+///
+/// Grants mapping a buffer hold one each, so a fork, a partial munmap or an address space going
+/// away all keep the count right without the pool having to track mappings.
+#[derive(Debug)]
+pub struct BufferRef {
+    buffer_id: u32,
+}
+
+impl BufferRef {
+    /// Take over a reference from [`SharedBufferPool::allocate`] or [`SharedBufferPool::retain`]
+    pub fn new(buffer_id: u32) -> Self {
+        Self { buffer_id }
+    }
+
+    #[inline]
+    pub fn buffer_id(&self) -> u32 {
+        self.buffer_id
+    }
 }
 
-/// Get the global IPC registry mutably (for initialization)
-pub fn registry_mut() -> &'static mut IpcRegistry {
-    unsafe { &mut IPC_REGISTRY }
+impl Clone for BufferRef {
+    fn clone(&self) -> Self {
+        // The reference held by self keeps the buffer from being released meanwhile
+        if let Some(buffer) = IPC_REGISTRY
+            .get()
+            .and_then(IpcRegistry::buffer_pool)
+            .and_then(|pool| pool.get(self.buffer_id))
+        {
+            buffer.add_ref();
+        }
+        Self::new(self.buffer_id)
+    }
+}
+
+impl Drop for BufferRef {
+    fn drop(&mut self) {
+        if let Some(pool) = IPC_REGISTRY.get().and_then(IpcRegistry::buffer_pool) {
+            pool.put(self.buffer_id);
+        }
+    }
 }
 
 // =============================================================================
@@ -960,7 +1391,20 @@ pub mod rcu {
     }
 }
 
-/// Dynamic priority boosting for IPC lock holders\n///\n/// When a high-priority thread is waiting on a lock held by a lower-priority\n/// thread, boost the holder's priority to prevent indefinite blocking.\npub fn priority_boost(holder: &crate::context::ContextRef, waiter_priority: u8) {\n    let mut token = unsafe { crate::sync::CleanLockToken::new() };\n    let holder_ctx = holder.read(token.token());\n    \n    // Only boost if waiter has higher priority (lower number)\n    let current = holder_ctx.priority.effective_priority();\n    if waiter_priority < current {\n        // Boost via IPC critical section mechanism\n        holder_ctx.priority.enter_ipc_critical();\n    }\n}
+/// Dynamic priority boosting for IPC lock holders
+///
+/// When a high-priority thread is waiting on a lock held by a lower-priority
+/// thread, boost the holder's priority to prevent indefinite blocking.
+pub fn priority_boost(holder: &ContextRef, waiter_priority: u8, token: &mut CleanLockToken) {
+    let holder_ctx = holder.read(token.token());
+
+    // Only boost if waiter has higher priority (lower number)
+    let current = holder_ctx.priority.effective_priority();
+    if waiter_priority < current {
+        // Boost via IPC critical section mechanism
+        holder_ctx.priority.enter_ipc_critical();
+    }
+}
 
 /// Full preemption check point
 /// Call this at safe preemption points to allow RT threads to preempt
@@ -970,17 +1414,14 @@ pub fn preempt_check(token: &mut CleanLockToken) {
     let ctx = current.read(token.token());
 
     // Check if there's a higher priority thread waiting
-    if let Some(scheduler_ref) = crate::percpu::PercpuBlock::current()
-        .scheduler
+    let waiting = crate::scheduler::scheduler()
+        .lock()
         .run_queue
         .rt_queue
-        .front()
-    {
-        let waiting_priority = scheduler_ref
-            .context
-            .read(token.token())
-            .priority
-            .effective_priority();
+        .first()
+        .map(|entry| entry.context.clone());
+    if let Some(waiting) = waiting {
+        let waiting_priority = waiting.read(token.token()).priority.effective_priority();
         let current_priority = ctx.priority.effective_priority();
 
         // Lower number = higher priority
@@ -1027,9 +1468,56 @@ mod tests {
 
     #[test]
     fn test_channel_state() {
+        let mut token = unsafe { CleanLockToken::new() };
         let channel = IpcChannel::new(1);
         assert_eq!(channel.state(), ChannelState::Ready);
-        channel.close();
+        channel.close(&mut token);
         assert_eq!(channel.state(), ChannelState::Closed);
     }
+
+    fn test_buffer() -> SharedBuffer {
+        SharedBuffer::new(Frame::containing(crate::memory::PhysicalAddress::new(
+            0x1000,
+        )))
+    }
+
+    #[test]
+    fn test_buffer_sharing() {
+        let buffer = test_buffer();
+        assert!(buffer.acquire(7));
+        assert!(buffer.may_access(7));
+        assert!(!buffer.may_access(8));
+
+        assert_eq!(buffer.share(8, 9).unwrap_err().errno, EPERM);
+        buffer.share(7, 8).unwrap();
+        assert!(buffer.may_access(8));
+
+        buffer.release();
+        assert!(!buffer.may_access(7));
+        assert!(!buffer.may_access(8));
+    }
+
+    #[test]
+    fn test_segment_of() {
+        assert_eq!(segment_of(16, 0), (0, 0));
+        assert_eq!(segment_of(16, 15), (0, 15));
+        assert_eq!(segment_of(16, 16), (1, 0));
+        assert_eq!(segment_of(16, 31), (1, 15));
+        assert_eq!(segment_of(16, 32), (2, 0));
+        assert_eq!(segment_of(16, 63), (2, 31));
+        assert_eq!(segment_of(16, 64), (3, 0));
+        assert_eq!(segment_of(3, 5), (1, 2));
+        assert_eq!(segment_of(3, 6), (2, 0));
+    }
+
+    #[test]
+    fn test_buffer_refcount() {
+        let buffer = test_buffer();
+        assert!(!buffer.try_add_ref());
+
+        assert!(buffer.acquire(7));
+        assert!(buffer.try_add_ref());
+        assert!(!buffer.remove_ref());
+        assert!(buffer.remove_ref());
+    }
 }
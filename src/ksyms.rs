@@ -0,0 +1,178 @@
+//! # Kernel Symbols
+//!
+//! A table of the kernel's functions, embedded with the `ksyms` feature so that backtraces and
+//! profiler samples can be resolved without the build artifacts. It is read through sys:ksyms and
+//! by the panic handler.
+//!
+//! Addresses are only known once the kernel is linked, so the kernel is linked twice, as
+//! `make KSYMS=1` does. build.rs turns the `nm -n -S --defined-only` listing of the first link,
+//! passed as `KSYMS_FILE`, into the table placed in the `.ksyms` section at the end of .rodata.
+//! Code only finds the table through the linker symbols around that section, so embedding it
+//! moves no function between the two links.
+//!
+//! Names are kept mangled, and demangled when printed.
+
+use core::{slice, str};
+
+/// The table generated by build.rs, see `ksyms_table` there for its layout
+#[used]
+#[unsafe(link_section = ".ksyms")]
+static TABLE: [u8; include_bytes!(concat!(env!("OUT_DIR"), "/ksyms.bin")).len()] =
+    *include_bytes!(concat!(env!("OUT_DIR"), "/ksyms.bin"));
+
+const MAGIC: &[u8; 4] = b"KSYM";
+const HEADER_SIZE: usize = 8;
+const ENTRY_SIZE: usize = 16;
+
+/// A function in the symbol table
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Symbol<'a> {
+    pub addr: usize,
+    pub size: usize,
+    /// The mangled name
+    pub name: &'a str,
+}
+
+#[derive(Clone, Copy)]
+struct Table<'a> {
+    entries: &'a [u8],
+    names: &'a [u8],
+    count: usize,
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(at..at.checked_add(4)?)?.try_into().ok()?,
+    ))
+}
+
+impl<'a> Table<'a> {
+    fn parse(bytes: &'a [u8]) -> Option<Self> {
+        let (header, rest) = bytes.split_at_checked(HEADER_SIZE)?;
+        if header.get(..MAGIC.len())? != MAGIC {
+            return None;
+        }
+        let count = usize::try_from(read_u32(header, 4)?).ok()?;
+        let (entries, names) = rest.split_at_checked(count.checked_mul(ENTRY_SIZE)?)?;
+        Some(Self {
+            entries,
+            names,
+            count,
+        })
+    }
+
+    fn entry(&self, index: usize) -> Option<&'a [u8]> {
+        let start = index.checked_mul(ENTRY_SIZE)?;
+        self.entries.get(start..start.checked_add(ENTRY_SIZE)?)
+    }
+
+    fn addr(&self, index: usize) -> Option<usize> {
+        let addr = u64::from_le_bytes(self.entry(index)?.get(..8)?.try_into().ok()?);
+        usize::try_from(addr).ok()
+    }
+
+    fn name_offset(&self, index: usize) -> Option<usize> {
+        usize::try_from(read_u32(self.entry(index)?, 12)?).ok()
+    }
+
+    fn get(&self, index: usize) -> Option<Symbol<'a>> {
+        let entry = self.entry(index)?;
+        let start = self.name_offset(index)?;
+        // Names are stored in the order of the entries
+        let end = match index.checked_add(1).filter(|&next| next < self.count) {
+            Some(next) => self.name_offset(next)?,
+            None => self.names.len(),
+        };
+        Some(Symbol {
+            addr: self.addr(index)?,
+            size: usize::try_from(read_u32(entry, 8)?).ok()?,
+            name: str::from_utf8(self.names.get(start..end)?).ok()?,
+        })
+    }
+
+    /// The function containing `addr` and the offset of `addr` into it
+    fn symbolize(&self, addr: usize) -> Option<(Symbol<'a>, usize)> {
+        // Find the first symbol past addr, the one before it is the only candidate
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let mid = low.midpoint(high);
+            if self.addr(mid)? <= addr {
+                low = mid.saturating_add(1);
+            } else {
+                high = mid;
+            }
+        }
+        let symbol = self.get(low.checked_sub(1)?)?;
+        let offset = addr.checked_sub(symbol.addr)?;
+        (offset < symbol.size).then_some((symbol, offset))
+    }
+}
+
+fn table() -> Option<Table<'static>> {
+    let start = crate::kernel_executable_offsets::__ksyms_start();
+    let end = crate::kernel_executable_offsets::__ksyms_end();
+    let bytes = unsafe { slice::from_raw_parts(start as *const u8, end.checked_sub(start)?) };
+    Table::parse(bytes)
+}
+
+/// The symbol at `index`, in order of address
+pub fn symbol(index: usize) -> Option<Symbol<'static>> {
+    table()?.get(index)
+}
+
+/// The mangled name of the function containing `addr`, and the offset of `addr` into it. Does
+/// not allocate or lock, so that the panic handler can use it.
+pub fn symbolize(addr: usize) -> Option<(&'static str, usize)> {
+    let (symbol, offset) = table()?.symbolize(addr)?;
+    Some((symbol.name, offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn build(symbols: &[(u64, u32, &str)]) -> Vec<u8> {
+        let mut table = Vec::from(*MAGIC);
+        table.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+        let mut names = Vec::new();
+        for &(addr, size, name) in symbols {
+            table.extend_from_slice(&addr.to_le_bytes());
+            table.extend_from_slice(&size.to_le_bytes());
+            table.extend_from_slice(&(names.len() as u32).to_le_bytes());
+            names.extend_from_slice(name.as_bytes());
+        }
+        table.extend_from_slice(&names);
+        table
+    }
+
+    #[test]
+    fn test_ksyms_symbolize() {
+        let bytes = build(&[
+            (0x1000, 0x10, "first"),
+            (0x1020, 0x20, "second"),
+            (0x1040, 8, "last"),
+        ]);
+        let table = Table::parse(&bytes).unwrap();
+
+        assert_eq!(table.symbolize(0xFFF), None);
+        assert_eq!(table.symbolize(0x1000).unwrap().0.name, "first");
+        assert_eq!(table.symbolize(0x100F).unwrap().1, 0xF);
+        // Between two functions
+        assert_eq!(table.symbolize(0x1010), None);
+        assert_eq!(table.symbolize(0x1033).unwrap().0.name, "second");
+        assert_eq!(table.symbolize(0x1047).unwrap().0.name, "last");
+        assert_eq!(table.symbolize(0x1048), None);
+        assert_eq!(table.get(3), None);
+    }
+
+    #[test]
+    fn test_ksyms_empty_and_malformed() {
+        let empty = build(&[]);
+        assert_eq!(Table::parse(&empty).unwrap().symbolize(0x1000), None);
+        assert!(Table::parse(b"KSYM").is_none());
+        assert!(Table::parse(b"NOPE\0\0\0\0").is_none());
+        // Claims more entries than it has
+        assert!(Table::parse(b"KSYM\x01\0\0\0").is_none());
+    }
+}
@@ -1,23 +1,113 @@
-use alloc::collections::VecDeque;
-use core::fmt;
+use alloc::{collections::VecDeque, vec::Vec};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
 use spin::{Mutex, MutexGuard};
 
-use crate::devices::graphical_debug::{DebugDisplay, DEBUG_DISPLAY};
+use crate::{
+    devices::graphical_debug::{DebugDisplay, DEBUG_DISPLAY},
+    sync::{CleanLockToken, WaitCondition},
+};
 
 /// The global logger.
 pub static LOG: Mutex<Option<Log>> = Mutex::new(None);
 
+/// Readers of `sys:kmsg` waiting for new records.
+pub static KMSG_WAIT: WaitCondition = WaitCondition::new();
+
+/// Set when a record was added since readers were last woken.
+static KMSG_UNREAD: AtomicBool = AtomicBool::new(false);
+
+/// The size of the kernel message ring buffer.
+const KMSG_SIZE: usize = 128 * 1024;
+
+/// The longest line kept as a single record. Longer lines are split.
+const KMSG_LINE_MAX: usize = 1024;
+
+/// The size of the header in front of each record: a timestamp and the text length.
+const KMSG_HEADER_SIZE: usize = 10;
+
+/// The size of the scrollback of everything written to `debug:`.
+pub const SCROLLBACK_SIZE: usize = 256 * 1024;
+
+/// Wakes the readers of `sys:kmsg` if new records were added.
+///
+/// Log lines are written in any context, including with the scheduler locks held, so readers
+/// are not woken by the writer itself but by the timer tick calling this.
+pub fn wake_kmsg_readers(token: &mut CleanLockToken) {
+    if KMSG_UNREAD.swap(false, Ordering::AcqRel) {
+        KMSG_WAIT.notify(token);
+    }
+}
+
 /// Initializes the global logger.
 pub fn init() {
     *LOG.lock() = Some(Log::new(1024 * 1024));
 }
 
+/// The severity of a log message, from most to least severe.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    /// Parses a level name, as given by the `loglevel` boot parameter.
+    pub fn from_name(name: &str) -> Option<Level> {
+        match name {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+}
+
+/// The least severe level that is printed. Debug messages are printed by default only on the
+/// architectures that are still being brought up.
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(
+    if cfg!(any(target_arch = "aarch64", target_arch = "riscv64")) {
+        Level::Debug as u8
+    } else {
+        Level::Info as u8
+    },
+);
+
+/// Whether messages of the given level are printed.
+#[inline]
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Applies the `loglevel` boot parameter, if it was given.
+pub fn init_level() {
+    let Some(name) = crate::startup::params::param_str("loglevel") else {
+        return;
+    };
+    match Level::from_name(name) {
+        Some(level) => MAX_LEVEL.store(level as u8, Ordering::Relaxed),
+        None => warn!("Unknown log level {:?}", name),
+    }
+}
+
 /// A circular buffer for storing log messages.
 pub struct Log {
     /// The circular buffer.
     data: VecDeque<u8>,
     /// The maximum size of the buffer.
     size: usize,
+    /// The log split into records, for `sys:kmsg`.
+    pub kmsg: Kmsg,
+    /// Everything written to `debug:`, for `debug:scrollback`.
+    pub scrollback: Scrollback,
 }
 
 impl Log {
@@ -26,6 +116,8 @@ impl Log {
         Log {
             data: VecDeque::with_capacity(size),
             size,
+            kmsg: Kmsg::new(KMSG_SIZE),
+            scrollback: Scrollback::new(SCROLLBACK_SIZE),
         }
     }
 
@@ -42,6 +134,213 @@ impl Log {
             }
             self.data.push_back(b);
         }
+        self.kmsg.write(buf, || crate::time::monotonic() as u64);
+    }
+}
+
+/// A record read from the kernel message buffer.
+#[derive(Debug, PartialEq)]
+pub struct KmsgRecord {
+    /// The sequence number of the record.
+    pub seq: u64,
+    /// The monotonic time at which the line was started, in nanoseconds.
+    pub time: u64,
+    /// The line, without its newline.
+    pub text: Vec<u8>,
+}
+
+/// A ring buffer of log lines, each numbered in sequence and timestamped.
+///
+/// The buffer has a fixed size and never allocates, so the oldest records are overwritten when
+/// it is full.
+pub struct Kmsg {
+    /// The records, each a header followed by the text.
+    data: VecDeque<u8>,
+    /// The maximum size of the buffer.
+    size: usize,
+    /// Where each record in the buffer starts, by sequence number from `first_seq`, counted in
+    /// bytes ever written to the buffer so that dropping records does not move the others.
+    offsets: VecDeque<u64>,
+    /// The number of bytes dropped from the front of the buffer.
+    dropped: u64,
+    /// The sequence number of the oldest record in the buffer.
+    first_seq: u64,
+    /// The sequence number the next record will get.
+    next_seq: u64,
+    /// The line being written, committed once its newline arrives.
+    line: [u8; KMSG_LINE_MAX],
+    /// The length of the line being written.
+    line_len: usize,
+    /// The time at which the line being written was started.
+    line_time: u64,
+}
+
+impl Kmsg {
+    /// Creates a new `Kmsg` with the given size.
+    pub fn new(size: usize) -> Kmsg {
+        Kmsg {
+            data: VecDeque::with_capacity(size),
+            size,
+            // Records are at least a header long, so this is as many as the buffer can hold
+            offsets: VecDeque::with_capacity(size / KMSG_HEADER_SIZE + 1),
+            dropped: 0,
+            first_seq: 0,
+            next_seq: 0,
+            line: [0; KMSG_LINE_MAX],
+            line_len: 0,
+            line_time: 0,
+        }
+    }
+
+    /// Writes to the buffer, adding a record for every completed line.
+    ///
+    /// `now` is only called when a line is started.
+    pub fn write(&mut self, buf: &[u8], mut now: impl FnMut() -> u64) {
+        for &b in buf {
+            if b == b'\n' {
+                self.commit();
+                continue;
+            }
+            if self.line_len == 0 {
+                self.line_time = now();
+            }
+            self.line[self.line_len] = b;
+            self.line_len += 1;
+            if self.line_len == KMSG_LINE_MAX {
+                self.commit();
+            }
+        }
+    }
+
+    /// Adds the line being written as a record, dropping the oldest records to make room.
+    fn commit(&mut self) {
+        let record_len = KMSG_HEADER_SIZE + self.line_len;
+        while !self.data.is_empty() && self.data.len() + record_len > self.size {
+            let len = KMSG_HEADER_SIZE + self.text_len(0);
+            let _ = self.data.drain(..len);
+            self.offsets.pop_front();
+            self.dropped += len as u64;
+            self.first_seq += 1;
+        }
+
+        self.offsets
+            .push_back(self.dropped + self.data.len() as u64);
+        self.data.extend(self.line_time.to_le_bytes());
+        self.data.extend((self.line_len as u16).to_le_bytes());
+        self.data.extend(&self.line[..self.line_len]);
+        self.line_len = 0;
+        self.next_seq += 1;
+
+        KMSG_UNREAD.store(true, Ordering::Release);
+    }
+
+    /// The length of the text of the record starting at `pos`.
+    fn text_len(&self, pos: usize) -> usize {
+        u16::from_le_bytes([self.data[pos + 8], self.data[pos + 9]]).into()
+    }
+
+    /// The sequence number of the oldest record in the buffer.
+    pub fn first_seq(&self) -> u64 {
+        self.first_seq
+    }
+
+    /// Reads the record numbered `seq`, or the oldest one left if it was overwritten.
+    ///
+    /// Returns `None` if there is no such record yet.
+    pub fn read(&self, seq: u64) -> Option<KmsgRecord> {
+        if seq >= self.next_seq {
+            return None;
+        }
+        let seq = seq.max(self.first_seq);
+        let offset = self.offsets[(seq - self.first_seq) as usize];
+        let pos = (offset - self.dropped) as usize;
+
+        let mut time = [0; 8];
+        for (i, b) in time.iter_mut().enumerate() {
+            *b = self.data[pos + i];
+        }
+        let text_start = pos + KMSG_HEADER_SIZE;
+        let text = self
+            .data
+            .range(text_start..text_start + self.text_len(pos))
+            .copied()
+            .collect();
+
+        Some(KmsgRecord {
+            seq,
+            time: u64::from_le_bytes(time),
+            text,
+        })
+    }
+}
+
+/// A ring buffer of everything written through the debug scheme.
+///
+/// Bytes are addressed by their position in everything ever written rather than in the buffer,
+/// so that a reader can tell how much was overwritten before it got to it.
+pub struct Scrollback {
+    /// The most recently written bytes.
+    data: VecDeque<u8>,
+    /// The maximum size of the buffer.
+    size: usize,
+    /// The number of bytes ever written.
+    written: u64,
+}
+
+impl Scrollback {
+    /// Creates a new `Scrollback` with the given size.
+    pub fn new(size: usize) -> Scrollback {
+        Scrollback {
+            data: VecDeque::with_capacity(size),
+            size,
+            written: 0,
+        }
+    }
+
+    /// Appends to the buffer, dropping the oldest bytes to make room.
+    pub fn write(&mut self, buf: &[u8]) {
+        // Only the end of a write larger than the whole buffer would be kept anyway
+        let kept = buf
+            .get(buf.len().saturating_sub(self.size)..)
+            .unwrap_or(buf);
+        let excess = self
+            .data
+            .len()
+            .saturating_add(kept.len())
+            .saturating_sub(self.size);
+        let _ = self.data.drain(..excess);
+        self.data.extend(kept);
+        self.written = self.written.saturating_add(buf.len() as u64);
+    }
+
+    /// The number of bytes in the buffer.
+    pub fn buffered(&self) -> usize {
+        self.data.len()
+    }
+
+    /// The position of the oldest byte in the buffer.
+    pub fn start(&self) -> u64 {
+        self.written.saturating_sub(self.data.len() as u64)
+    }
+
+    /// Copies the bytes from position `pos` on into `buf`, starting with the oldest byte in the
+    /// buffer if `pos` was overwritten.
+    ///
+    /// Returns the number of bytes copied and the number of bytes skipped because they were
+    /// overwritten.
+    pub fn read(&self, pos: u64, buf: &mut [u8]) -> (usize, u64) {
+        let start = self.start();
+        let lost = start.saturating_sub(pos);
+        let offset = usize::try_from(pos.saturating_sub(start)).unwrap_or(usize::MAX);
+        let mut count = 0_usize;
+        for (dst, &src) in buf
+            .iter_mut()
+            .zip(self.data.range(offset.min(self.data.len())..))
+        {
+            *dst = src;
+            count = count.saturating_add(1);
+        }
+        (count, lost)
     }
 }
 
@@ -68,6 +367,16 @@ impl<'a> Writer<'a> {
         }
     }
 
+    /// Creates a `Writer` if none of its locks is held, for code that may have interrupted the
+    /// holder on its own CPU.
+    pub fn try_new() -> Option<Writer<'a>> {
+        Some(Writer {
+            log: LOG.try_lock()?,
+            display: DEBUG_DISPLAY.try_lock()?,
+            arch: crate::arch::debug::Writer::try_new()?,
+        })
+    }
+
     /// Writes to the log.
     pub fn write(&mut self, buf: &[u8], preserve: bool) {
         if preserve {
@@ -82,6 +391,17 @@ impl<'a> Writer<'a> {
 
         self.arch.write(buf);
     }
+
+    /// Writes to the log what was written to `debug:`, keeping it in the scrollback as well.
+    ///
+    /// The scrollback is appended to under the same lock as the rest of the output, so that it
+    /// interleaves writes exactly as the console shows them.
+    pub fn write_debug(&mut self, buf: &[u8], preserve: bool) {
+        if let Some(ref mut log) = *self.log {
+            log.scrollback.write(buf);
+        }
+        self.write(buf, preserve);
+    }
 }
 
 impl fmt::Write for Writer<'_> {
@@ -90,3 +410,104 @@ impl fmt::Write for Writer<'_> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(seq: u64, time: u64, text: &[u8]) -> Option<KmsgRecord> {
+        Some(KmsgRecord {
+            seq,
+            time,
+            text: text.to_vec(),
+        })
+    }
+
+    #[test]
+    fn level_names() {
+        assert_eq!(Level::from_name("warn"), Some(Level::Warn));
+        assert_eq!(Level::from_name("trace"), Some(Level::Trace));
+        assert_eq!(Level::from_name("WARN"), None);
+        assert!(Level::Error < Level::Debug);
+    }
+
+    #[test]
+    fn lines_become_numbered_records() {
+        let mut kmsg = Kmsg::new(1024);
+        let mut clock = 0;
+        let mut now = || {
+            clock += 1;
+            clock
+        };
+        kmsg.write(b"first", &mut now);
+        assert_eq!(kmsg.read(0), None);
+
+        kmsg.write(b" line\nsecond\n", &mut now);
+        assert_eq!(kmsg.read(0), record(0, 1, b"first line"));
+        assert_eq!(kmsg.read(1), record(1, 2, b"second"));
+        assert_eq!(kmsg.read(2), None);
+        assert_eq!(kmsg.first_seq(), 0);
+    }
+
+    #[test]
+    fn lagging_reader_gets_oldest_record() {
+        // Room for two records with five bytes of text each
+        let mut kmsg = Kmsg::new(2 * (KMSG_HEADER_SIZE + 5));
+        kmsg.write(b"zero!\none!!\ntwo!!\nthree\n", || 7);
+
+        assert_eq!(kmsg.read(0), record(2, 7, b"two!!"));
+        assert_eq!(kmsg.read(3), record(3, 7, b"three"));
+        assert_eq!(kmsg.read(4), None);
+        assert_eq!(kmsg.first_seq(), 2);
+    }
+
+    #[test]
+    fn records_are_found_after_wrapping() {
+        // Records of different lengths, so that each one moves the next by a different amount
+        let mut kmsg = Kmsg::new(3 * (KMSG_HEADER_SIZE + 3));
+        for i in 0..10_u8 {
+            let line = [b'a' + i, b'a' + i, b'\n'];
+            let line = if i % 2 == 0 { &line[1..] } else { &line[..] };
+            kmsg.write(line, || u64::from(i));
+        }
+
+        assert_eq!(kmsg.first_seq(), 7);
+        assert_eq!(kmsg.read(7), record(7, 7, b"hh"));
+        assert_eq!(kmsg.read(8), record(8, 8, b"i"));
+        assert_eq!(kmsg.read(9), record(9, 9, b"jj"));
+    }
+
+    #[test]
+    fn long_lines_are_split() {
+        let mut kmsg = Kmsg::new(4 * KMSG_LINE_MAX);
+        let line = [b'x'; KMSG_LINE_MAX + 1];
+        kmsg.write(&line, || 0);
+        kmsg.write(b"\n", || 0);
+
+        assert_eq!(kmsg.read(0).map(|r| r.text.len()), Some(KMSG_LINE_MAX));
+        assert_eq!(kmsg.read(1), record(1, 0, b"x"));
+    }
+
+    #[test]
+    fn scrollback_wraps_and_counts_lost_bytes() {
+        let mut scrollback = Scrollback::new(8);
+        let mut buf = [0; 16];
+        scrollback.write(b"hello");
+        assert_eq!(scrollback.read(0, &mut buf), (5, 0));
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(scrollback.read(3, &mut buf), (2, 0));
+        assert_eq!(&buf[..2], b"lo");
+
+        scrollback.write(b" world");
+        assert_eq!(scrollback.buffered(), 8);
+        assert_eq!(scrollback.start(), 3);
+        assert_eq!(scrollback.read(1, &mut buf), (8, 2));
+        assert_eq!(&buf[..8], b"lo world");
+        assert_eq!(scrollback.read(11, &mut buf), (0, 0));
+
+        // A write larger than the buffer keeps only its end
+        scrollback.write(b"0123456789");
+        assert_eq!(scrollback.read(0, &mut buf), (8, 13));
+        assert_eq!(&buf[..8], b"23456789");
+    }
+}
@@ -20,7 +20,9 @@ macro_rules! println {
 #[macro_export]
 macro_rules! error {
     ($($arg:tt)*) => {
-        println!("{}:ERROR -- {}", core::module_path!(), format_args!($($arg)*));
+        if $crate::log::enabled($crate::log::Level::Error) {
+            println!("{}:ERROR -- {}", core::module_path!(), format_args!($($arg)*));
+        }
     };
 }
 
@@ -28,7 +30,9 @@ macro_rules! error {
 #[macro_export]
 macro_rules! warn {
     ($($arg:tt)*) => {
-        println!("{}:WARN -- {}", core::module_path!(), format_args!($($arg)*));
+        if $crate::log::enabled($crate::log::Level::Warn) {
+            println!("{}:WARN -- {}", core::module_path!(), format_args!($($arg)*));
+        }
     };
 }
 
@@ -36,7 +40,9 @@ macro_rules! warn {
 #[macro_export]
 macro_rules! info {
     ($($arg:tt)*) => {
-        println!("{}:INFO -- {}", core::module_path!(), format_args!($($arg)*));
+        if $crate::log::enabled($crate::log::Level::Info) {
+            println!("{}:INFO -- {}", core::module_path!(), format_args!($($arg)*));
+        }
     };
 }
 
@@ -44,7 +50,7 @@ macro_rules! info {
 #[macro_export]
 macro_rules! debug {
     ($($arg:tt)*) => {
-        if cfg!(any(target_arch = "aarch64", target_arch = "riscv64")) {
+        if $crate::log::enabled($crate::log::Level::Debug) {
             println!("{}:DEBUG -- {}", core::module_path!(), format_args!($($arg)*));
         }
     };
@@ -54,8 +60,25 @@ macro_rules! debug {
 #[macro_export]
 macro_rules! trace {
     ($($arg:tt)*) => {
-        if false {
+        if $crate::log::enabled($crate::log::Level::Trace) {
             println!("{}:TRACE -- {}", core::module_path!(), format_args!($($arg)*));
         }
     };
 }
+
+/// Evaluates a scheme call, recording its latency when the `scheme_metrics` feature is enabled.
+///
+/// Without the feature this is just `$call`.
+#[macro_export]
+macro_rules! scheme_timed {
+    ($scheme_id:expr, $op:ident, $call:expr) => {{
+        #[cfg(not(feature = "scheme_metrics"))]
+        let _ = $scheme_id;
+        #[cfg(feature = "scheme_metrics")]
+        let start = $crate::time::monotonic();
+        let result = $call;
+        #[cfg(feature = "scheme_metrics")]
+        $crate::scheme::metrics::record($scheme_id, $crate::scheme::metrics::Op::$op, start);
+        result
+    }};
+}
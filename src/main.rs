@@ -55,6 +55,7 @@ mod cpu_set;
 mod cpu_stats;
 #[cfg(feature = "debugger")]
 mod debugger;
+mod deferred;
 mod devices;
 #[cfg(feature = "dtb")]
 mod dtb;
@@ -63,6 +64,8 @@ mod event;
 mod externs;
 mod gdt;
 mod ipc;
+#[cfg(feature = "ksyms")]
+mod ksyms;
 mod log;
 mod memory;
 mod misc;
@@ -72,16 +75,23 @@ mod profiling;
 mod ptrace;
 mod scheduler;
 mod scheme;
+#[cfg(feature = "selftest")]
+mod selftest;
 mod startup;
 #[macro_use]
 mod stubs;
 use sync::CleanLockToken;
 mod sync;
 mod syscall;
-#[cfg(feature = "stress_test")]
+#[cfg(any(
+    feature = "stress_test",
+    feature = "kcall_test",
+    feature = "usercopy_test"
+))]
 mod tests;
 mod time;
 mod topology;
+mod watchdog;
 
 #[cfg_attr(not(test), global_allocator)]
 static ALLOCATOR: allocator::Allocator = allocator::Allocator;
@@ -116,9 +126,12 @@ struct Bootstrap {
 
 static BOOTSTRAP: spin::Once<Bootstrap> = spin::Once::new();
 
-extern "C" fn kmain_reaper() {
-    loop {
+fn kmain_reaper() {
+    while !context::kthread::should_stop() {
         context::reap::reap_grants();
+        ipc::reap_buffers();
+        #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+        arch::device::iommu::poll_events();
         core::hint::spin_loop();
     }
 }
@@ -126,35 +139,60 @@ extern "C" fn kmain_reaper() {
 fn kmain(bootstrap: Bootstrap) -> ! {
     let mut token = unsafe { CleanLockToken::new() };
     context::init();
+    ipc::init();
+    time::init_time_page();
     scheme::init_schemes();
 
     info!("BSP: {} CPUs", cpu_count());
     debug!("Env: {:?}", ::core::str::from_utf8(bootstrap.env));
 
     BOOTSTRAP.call_once(|| bootstrap);
+    startup::params::init(init_env());
+    log::init_level();
     profiling::ready_for_profiling();
+    profiling::start_from_params();
+    scheduler::init_from_params();
+    watchdog::init_from_params();
+
+    #[cfg(feature = "selftest")]
+    selftest::run_from_params(&mut token);
 
     #[cfg(feature = "stress_test")]
     tests::stress_test::start_stress_test();
 
-    let owner = None;
-    match context::spawn(false, owner.clone(), || kmain_reaper(), &mut token) {
-        Ok(context_lock) => {
-            let mut context = context_lock.write(token.token());
-            context.status = context::Status::Runnable;
-            context.name.clear();
-            context.name.push_str("[kmain_reaper]");
-        }
+    #[cfg(feature = "kcall_test")]
+    tests::kcall_test::start_kcall_test();
+
+    #[cfg(feature = "usercopy_test")]
+    tests::usercopy_test::start_usercopy_test();
+
+    // Reaping is housekeeping, keep it on the BSP so it never migrates onto CPUs running RT work
+    let mut housekeeping = cpu_set::LogicalCpuSet::new();
+    housekeeping.add(cpu_set::LogicalCpuId::BSP);
+    match context::kthread::spawn(
+        "[kmain_reaper]",
+        housekeeping,
+        sync::Priority::Low,
+        kmain_reaper,
+        &mut token,
+    ) {
+        Ok(reaper) => reaper.detach(&mut token),
         Err(err) => {
             panic!("failed to spawn kmain_reaper: {:?}", err);
         }
     }
-    match context::spawn(true, owner, || userspace_init(), &mut token) {
+    deferred::spawn_worker(&mut token);
+    scheme::gal::spawn_worker(&mut token);
+
+    // The bootstrap context becomes userspace, so it is not a kernel thread
+    let init = context::SpawnOptions {
+        userspace: true,
+        name: "[bootstrap]",
+        ..context::SpawnOptions::default()
+    };
+    match context::spawn_with(init, || userspace_init(), &mut token) {
         Ok(context_lock) => {
-            let mut context = context_lock.write(token.token());
-            context.status = context::Status::Runnable;
-            context.name.clear();
-            context.name.push_str("[bootstrap]");
+            context_lock.write(token.token()).status = context::Status::Runnable;
         }
         Err(err) => {
             panic!("failed to spawn userspace_init: {:?}", err);
@@ -181,14 +219,23 @@ fn kmain_ap(cpu_id: crate::cpu_set::LogicalCpuId) -> ! {
         }
     }
 
+    // The interrupt controller keeps per-CPU state for IPIs and the local timer
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        device::init_ap();
+    }
+
     context::init();
     info!("AP {}", cpu_id);
     profiling::ready_for_profiling();
+    deferred::spawn_worker(&mut token);
     run_userspace(&mut token)
 }
 
 fn run_userspace(token: &mut CleanLockToken) -> ! {
     loop {
+        // Interrupts are enabled here, so run what interrupt handlers deferred
+        deferred::run_pending(token);
         unsafe {
             interrupt::disable();
             match context::switch(token) {
@@ -229,4 +276,7 @@ mod kernel_executable_offsets {
 
     #[cfg(target_arch = "x86_64")]
     linker_offsets!(__altrelocs_start, __altrelocs_end);
+
+    #[cfg(feature = "ksyms")]
+    linker_offsets!(__ksyms_start, __ksyms_end);
 }
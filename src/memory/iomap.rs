@@ -0,0 +1,292 @@
+//! # I/O Mappings
+//!
+//! Kernel mappings of device memory and firmware tables, at their place in the physmap. An
+//! [`IoMapping`] maps its range with the requested caching when created and unmaps it when
+//! dropped. Pages shared by several mappings, such as firmware tables packed into one page, are
+//! counted and only unmapped once the last of them is dropped, and must agree on their flags.
+//!
+//! Mapping normal memory with other caching than the physmap's aliases it with conflicting
+//! attributes, which is almost always a driver bug, so ranges overlapping RAM are refused unless
+//! [`IoMapFlags::FORCE`] is passed.
+
+use alloc::collections::BTreeMap;
+use core::slice;
+use spin::Mutex;
+
+use crate::{
+    context::memory::TlbShootdownActions,
+    cpu_set::LogicalCpuSet,
+    memory::{areas, Frame, KernelMapper, PhysicalAddress, RmmA, RmmArch, PAGE_SIZE},
+    paging::{entry::EntryFlags, Page, PageFlags, VirtualAddress},
+    syscall::error::{Error, Result, EBUSY, EINVAL, ENOMEM},
+};
+
+/// How the CPU caches accesses to a mapping
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Cached like normal memory, for firmware tables
+    WriteBack,
+    /// Every access goes to the device, in order, for MMIO registers
+    Uncached,
+    /// Writes may be buffered and combined, for framebuffers. Falls back to [`Self::Uncached`]
+    /// where there is no PAT.
+    WriteCombining,
+}
+
+impl CacheMode {
+    fn page_flags(self, writable: bool) -> PageFlags<RmmA> {
+        let flags = PageFlags::new().write(writable);
+        match self {
+            CacheMode::WriteBack => flags,
+            // PAT entry 4 is set up as write-combining
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            CacheMode::WriteCombining => flags.custom_flag(EntryFlags::HUGE_PAGE.bits(), true),
+            _ => flags.custom_flag(EntryFlags::NO_CACHE.bits(), true),
+        }
+    }
+}
+
+bitflags! {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct IoMapFlags: u32 {
+        const NONE = 0;
+        /// Map the range even if it overlaps RAM
+        const FORCE = 1 << 0;
+        /// Map the range read-only
+        const READ_ONLY = 1 << 1;
+    }
+}
+
+/// A page of the physmap mapped through [`IoMapping`]
+struct MappedPage {
+    /// Number of mappings using the page
+    count: usize,
+    flags: PageFlags<RmmA>,
+    /// Whether the page was mapped for [`IoMapping`], and is unmapped once the last user is dropped
+    owned: bool,
+    /// Flags of the mapping the page already had, restored once the last user is dropped
+    prev: Option<PageFlags<RmmA>>,
+}
+
+/// Every page mapped through [`IoMapping`], by physical address
+static PAGES: Mutex<BTreeMap<usize, MappedPage>> = Mutex::new(BTreeMap::new());
+
+/// A range of physical memory mapped into the kernel, unmapped when dropped
+#[derive(Debug)]
+pub struct IoMapping {
+    phys: PhysicalAddress,
+    len: usize,
+}
+
+/// Whether `start..end` overlaps any of `areas`
+fn overlaps(areas: &[rmm::MemoryArea], start: usize, end: usize) -> bool {
+    areas.iter().any(|area| {
+        let area_end = area.base.data().saturating_add(area.size);
+        area.base.data() < end && start < area_end
+    })
+}
+
+/// Physical addresses of the pages covering `len` bytes at `phys`
+fn page_range(phys: PhysicalAddress, len: usize) -> impl Iterator<Item = usize> {
+    let start = crate::paging::round_down_pages(phys.data());
+    let end = crate::paging::round_up_pages(phys.data().saturating_add(len));
+    (start..end).step_by(PAGE_SIZE)
+}
+
+impl IoMapping {
+    /// Map `len` bytes of physical memory at `phys` with caching `mode`
+    pub fn new(phys: PhysicalAddress, len: usize, mode: CacheMode) -> Result<Self> {
+        Self::with_flags(phys, len, mode, IoMapFlags::NONE)
+    }
+
+    /// Like [`Self::new`], with `flags` changing how the range is checked and mapped
+    pub fn with_flags(
+        phys: PhysicalAddress,
+        len: usize,
+        mode: CacheMode,
+        flags: IoMapFlags,
+    ) -> Result<Self> {
+        let end = phys.data().checked_add(len).ok_or(Error::new(EINVAL))?;
+        if len == 0 {
+            return Err(Error::new(EINVAL));
+        }
+        if !flags.contains(IoMapFlags::FORCE) && overlaps(areas(), phys.data(), end) {
+            warn!(
+                "iomap: refusing to map RAM {:#x}:{:#x} as {:?}",
+                phys.data(),
+                end,
+                mode
+            );
+            return Err(Error::new(EINVAL));
+        }
+
+        let page_flags = mode.page_flags(!flags.contains(IoMapFlags::READ_ONLY));
+        let mut table = PAGES.lock();
+
+        // Check for conflicts first, so that nothing needs to be undone
+        for base in page_range(phys, len) {
+            if let Some(page) = table.get(&base)
+                && page.flags.data() != page_flags.data()
+            {
+                warn!(
+                    "iomap: {:#x} is already mapped with other flags than {:?}",
+                    base, mode
+                );
+                return Err(Error::new(EBUSY));
+            }
+        }
+
+        // Inserting may allocate, which must not happen with the mapper locked
+        let mapping = IoMapping { phys, len };
+        for base in mapping.pages() {
+            table
+                .entry(base)
+                .and_modify(|page| page.count = page.count.saturating_add(1))
+                .or_insert(MappedPage {
+                    count: 1,
+                    flags: page_flags,
+                    owned: false,
+                    prev: None,
+                });
+        }
+
+        // The physmap is used on every CPU, any of which may have the old flags cached
+        let mut flusher = TlbShootdownActions::new(LogicalCpuSet::all());
+        let mut mapper_lock = KernelMapper::lock();
+        let mapper = mapper_lock
+            .get_mut()
+            .expect("KernelMapper mapper locked re-entrant in IoMapping::new");
+        for base in mapping.pages() {
+            let Some(page) = table.get_mut(&base).filter(|page| page.count == 1) else {
+                continue;
+            };
+            let phys = PhysicalAddress::new(base);
+            let virt = RmmA::phys_to_virt(phys);
+            let (flush, action) = match mapper.translate(virt) {
+                // Mapped by someone else, only the flags are changed for as long as it is used
+                Some((_, prev)) => {
+                    page.prev = Some(prev);
+                    if prev.data() == page_flags.data() {
+                        continue;
+                    }
+                    let flush = unsafe { mapper.remap(virt, page_flags) };
+                    (flush, TlbShootdownActions::CHANGE_PROTECTION)
+                }
+                None => {
+                    let mapped = unsafe { mapper.map_linearly(phys, page_flags) };
+                    page.owned = mapped.is_some();
+                    let flush = mapped.map(|(_, flush)| flush);
+                    (flush, TlbShootdownActions::NEW_MAPPING)
+                }
+            };
+            match flush {
+                Some(flush) => {
+                    flush.ignore();
+                    let page = Page::containing_address(virt);
+                    flusher.queue(Frame::containing(phys), Some(page), action);
+                }
+                None => {
+                    drop(mapper_lock);
+                    flusher.flush();
+                    mapping.release(&mut table);
+                    core::mem::forget(mapping);
+                    return Err(Error::new(ENOMEM));
+                }
+            }
+        }
+        drop(mapper_lock);
+        flusher.flush();
+
+        Ok(mapping)
+    }
+
+    /// Physical addresses of the pages covering the mapping
+    fn pages(&self) -> impl Iterator<Item = usize> + use<> {
+        page_range(self.phys, self.len)
+    }
+
+    /// Drop this mapping's use of its pages, unmapping those no other mapping uses
+    fn release(&self, table: &mut BTreeMap<usize, MappedPage>) {
+        let mut flusher = TlbShootdownActions::new(LogicalCpuSet::all());
+        let mut mapper_lock = KernelMapper::lock();
+        let mapper = mapper_lock
+            .get_mut()
+            .expect("KernelMapper mapper locked re-entrant in IoMapping::drop");
+        for base in self.pages() {
+            let Some(page) = table.get_mut(&base) else {
+                continue;
+            };
+            page.count = page.count.saturating_sub(1);
+            if page.count > 0 {
+                continue;
+            }
+            let phys = PhysicalAddress::new(base);
+            let virt = RmmA::phys_to_virt(phys);
+            let flush = match page.prev {
+                _ if page.owned => unsafe { mapper.unmap_phys(virt, false) }
+                    .map(|(_, _, flush)| (flush, TlbShootdownActions::FREE)),
+                Some(prev) if prev.data() != page.flags.data() => {
+                    unsafe { mapper.remap(virt, prev) }
+                        .map(|flush| (flush, TlbShootdownActions::CHANGE_PROTECTION))
+                }
+                _ => None,
+            };
+            if let Some((flush, action)) = flush {
+                flush.ignore();
+                let page = Page::containing_address(virt);
+                flusher.queue(Frame::containing(phys), Some(page), action);
+            }
+            table.remove(&base);
+        }
+        drop(mapper_lock);
+        flusher.flush();
+    }
+
+    /// The virtual address of the start of the mapping
+    pub fn virt(&self) -> VirtualAddress {
+        RmmA::phys_to_virt(self.phys)
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.virt().data() as *mut u8
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.as_ptr(), self.len) }
+    }
+
+    /// Keep the range mapped for the rest of the kernel's life
+    pub fn leak(self) -> &'static [u8] {
+        let slice = unsafe { slice::from_raw_parts(self.as_ptr(), self.len) };
+        core::mem::forget(self);
+        slice
+    }
+}
+
+impl Drop for IoMapping {
+    fn drop(&mut self) {
+        self.release(&mut PAGES.lock());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn area(base: usize, size: usize) -> rmm::MemoryArea {
+        rmm::MemoryArea {
+            base: PhysicalAddress::new(base),
+            size,
+        }
+    }
+
+    #[test]
+    fn test_iomap_ram_overlap() {
+        let areas = [area(0x1000, 0x9000), area(0x10_0000, 0x10_0000)];
+        assert!(!overlaps(&areas, 0, 0x1000));
+        assert!(overlaps(&areas, 0, 0x1001));
+        assert!(!overlaps(&areas, 0xA000, 0x10_0000));
+        assert!(overlaps(&areas, 0x1F_F000, 0x20_1000));
+        assert!(!overlaps(&areas, 0x20_0000, 0x20_1000));
+    }
+}
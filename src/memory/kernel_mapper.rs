@@ -42,6 +42,32 @@ impl KernelMapper {
             }
         }
 
+        Self::locked(mapper)
+    }
+
+    /// Lock the mapper like [`Self::lock`], but return `None` instead of waiting if another
+    /// hardware thread holds it.
+    pub fn try_lock() -> Option<Self> {
+        let current_processor = crate::cpu_id();
+        match LOCK_OWNER.compare_exchange(
+            NO_PROCESSOR,
+            current_processor.get(),
+            Ordering::Acquire,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => (),
+            // already owned by this hardware thread
+            Err(id) if id == current_processor.get() => (),
+            Err(_) => return None,
+        }
+
+        let mapper =
+            unsafe { PageMapper::current(TableKind::Kernel, crate::memory::TheFrameAllocator) };
+        Some(Self::locked(mapper))
+    }
+
+    /// Count another guard of the lock this hardware thread now owns
+    fn locked(mapper: crate::paging::PageMapper) -> Self {
         let prev_count = LOCK_COUNT.fetch_add(1, Ordering::Relaxed);
         atomic::compiler_fence(Ordering::Acquire);
 
@@ -1,13 +1,16 @@
 //! # Memory management
 //! Includes the physical memory allocator (buddy system).
 
+pub mod iomap;
 mod kernel_mapper;
+pub mod pressure;
 
+use alloc::{sync::Arc, vec::Vec};
 use core::{
     cell::SyncUnsafeCell,
     mem,
     num::NonZeroUsize,
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
 };
 
 pub use kernel_mapper::KernelMapper;
@@ -21,8 +24,10 @@ use crate::{
     },
     kernel_executable_offsets::{__usercopy_end, __usercopy_start},
     paging::{entry::EntryFlags, Page, PageFlags},
+    percpu::PercpuBlock,
     sync::CleanLockToken,
     syscall::error::{Error, EFAULT, EINVAL, EIO, ENOMEM, EOVERFLOW},
+    topology::{NumaNodeId, CPU_TOPOLOGY},
 };
 use rmm::{BumpAllocator, FrameAllocator, FrameCount, FrameUsage, TableKind, VirtualAddress};
 
@@ -48,6 +53,8 @@ bitflags! {
     pub struct AllocationFlags: u32 {
         const NONE = 0;
         const ZEROED = 1 << 0;
+        /// May take the frames held back for kernel allocations that must not fail
+        const RESERVE = 1 << 1;
     }
 }
 
@@ -61,87 +68,101 @@ pub fn free_frames() -> usize {
 }
 
 pub fn used_frames() -> usize {
-    FREELIST.lock().used_frames + BUMP_FRAMES.load(Ordering::Relaxed)
+    USED_FRAMES.load(Ordering::Relaxed) + BUMP_FRAMES.load(Ordering::Relaxed)
 }
 
 pub fn total_frames() -> usize {
     sections().iter().map(|section| section.frames.len()).sum()
 }
 
+/// A snapshot of the buddy allocator, for diagnosing fragmentation.
+#[derive(Debug)]
+pub struct BuddyStats {
+    /// Number of free blocks on the freelist of each order
+    pub free_blocks: [usize; ORDER_COUNT as usize],
+    /// Frames handed out by the buddy allocator
+    pub used_frames: usize,
+    /// Frames consumed by the bump allocator during early boot
+    pub bump_frames: usize,
+    /// Memory of each NUMA node, by node index
+    pub nodes: [NodeStats; MAX_NUMA_NODES],
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NodeStats {
+    /// Frames of the sections local to the node
+    pub total_frames: usize,
+    /// Frames on the node's freelists
+    pub free_frames: usize,
+}
+
+/// Count the free blocks of every order, and the free frames of every node.
+///
+/// Only the counts are gathered while each freelist is locked; callers format the result
+/// afterwards.
+pub fn buddy_stats() -> BuddyStats {
+    let mut free_blocks = [0; ORDER_COUNT as usize];
+    let mut nodes = [NodeStats::default(); MAX_NUMA_NODES];
+
+    for section in sections() {
+        nodes[section.node()].total_frames += section.frames.len();
+    }
+    for (freelist, stats) in FREELISTS.iter().zip(nodes.iter_mut()) {
+        let freelist = freelist.lock();
+        for (order, head) in freelist.for_orders.iter().enumerate() {
+            let mut next = *head;
+            while let Some(frame) = next {
+                free_blocks[order] += 1;
+                stats.free_frames += 1 << order;
+                next = get_free_alloc_page_info(frame).next().frame();
+            }
+        }
+    }
+
+    BuddyStats {
+        free_blocks,
+        used_frames: USED_FRAMES.load(Ordering::Relaxed),
+        bump_frames: BUMP_FRAMES.load(Ordering::Relaxed),
+        nodes,
+    }
+}
+
 pub fn allocate_p2frame(order: u32) -> Option<Frame> {
-    allocate_p2frame_complex(order, AllocationFlags::NONE, None, order).map(|(f, _)| f)
+    allocate_p2frame_complex(order, AllocationFlags::NONE, None, order, None).map(|(f, _)| f)
 }
 
 pub fn allocate_frame() -> Option<Frame> {
     allocate_p2frame(0)
 }
 
+/// Allocate a frame for the kernel itself, dipping into the reserve if memory is short
+pub fn allocate_reserved_frame() -> Option<Frame> {
+    allocate_p2frame_complex(0, AllocationFlags::RESERVE, None, 0, None).map(|(f, _)| f)
+}
+
+/// Allocate a block of `2^min_order` frames from `node`, or else from the nearest node that has
+/// one free. Without a node, the current CPU's node is preferred.
 pub fn allocate_p2frame_complex(
     _req_order: u32,
     flags: AllocationFlags,
     _strategy: Option<PlacementStrategy>,
     min_order: u32,
+    node: Option<NumaNodeId>,
 ) -> Option<(Frame, usize)> {
-    let mut freelist = FREELIST.lock();
-
-    let (frame_order, frame) = freelist
-        .for_orders
-        .iter()
-        .enumerate()
-        .skip(min_order as usize)
-        .find_map(|(i, f)| f.map(|f| (i as u32, f)))?;
-
-    let info = get_page_info(frame)
-        .unwrap_or_else(|| panic!("no page info for allocated frame {frame:?}"));
-
-    let next_free = match info.transition_to_used() {
-        Ok(next) => next,
-        Err(_) => panic!("freelist frame {frame:?} was not in Free state!"),
-    };
-
-    debug_assert_eq!(
-        next_free.order(),
-        frame_order,
-        "{frame:?}->next {next_free:?}.order != {frame_order}"
-    );
-
-    if let Some(next) = next_free.frame() {
-        let f = get_free_alloc_page_info(next);
-        f.set_prev(P2Frame::new(None, frame_order));
-    }
-
-    if let Some(entry) = freelist.for_orders.get_mut(frame_order as usize) {
-        *entry = next_free.frame();
-    }
-
-    for order in (min_order..frame_order).rev() {
-        let order_page_count = 1usize
-            .checked_shl(order)
-            .ok_or(Error::new(EOVERFLOW))
-            .ok()?;
-        let hi = frame.try_next_by(order_page_count).ok()?;
-
-        let hi_info = get_page_info(hi).expect("sub-p2frame of split p2flame lacked PageInfo");
-
-        let free_info = hi_info.transition_to_free(order);
-        free_info.set_next(P2Frame::new(None, order));
-        free_info.set_prev(P2Frame::new(None, order));
-
-        if let Some(entry) = freelist.for_orders.get_mut(order as usize) {
-            *entry = Some(hi);
-        }
-    }
-
-    if let Some(added) = 1usize.checked_shl(min_order) {
-        freelist.used_frames = freelist
-            .used_frames
-            .checked_add(added)
-            .expect("Used frames overflow");
-    } else {
+    let use_reserve = flags.contains(AllocationFlags::RESERVE);
+    if !pressure::allocation_allowed(free_frames(), 1 << min_order, use_reserve) {
+        pressure::update(free_frames());
         return None;
     }
 
-    drop(freelist);
+    let preferred = node.map_or_else(local_node, node_index);
+    let fallback = unsafe { &(*NODE_ORDER.get())[preferred] };
+    let frame = fallback
+        .iter()
+        .find_map(|&node| FREELISTS[usize::from(node)].lock().take(min_order))?;
+
+    USED_FRAMES.fetch_add(1 << min_order, Ordering::Relaxed);
+    pressure::update(free_frames());
 
     unsafe {
         if flags.contains(AllocationFlags::ZEROED) {
@@ -157,7 +178,8 @@ pub fn allocate_p2frame_complex(
 }
 
 pub unsafe fn deallocate_p2frame(orig_frame: Frame, order: u32) {
-    let mut freelist = FREELIST.lock();
+    let node = frame_node(orig_frame);
+    let mut freelist = FREELISTS[node].lock();
 
     let initial_info = get_page_info(orig_frame)
         .unwrap_or_else(|| panic!("missing PageInfo for {orig_frame:?} being freed"));
@@ -166,77 +188,65 @@ pub unsafe fn deallocate_p2frame(orig_frame: Frame, order: u32) {
         panic!("Attempted to free frame {orig_frame:?} which is not in Used state");
     }
 
-    initial_info.refcount.store(0, Ordering::Relaxed);
-
-    let mut largest_order = order;
     let mut current = orig_frame;
+    let mut current_order = order;
 
-    for merge_order in order..MAX_ORDER {
-        let size_at_order = match PAGE_SIZE.checked_shl(merge_order) {
-            Some(s) => s,
-            None => break,
-        };
-
-        let sibling_addr = current.base().data() ^ size_at_order;
-        let sibling = Frame::containing(PhysicalAddress::new(sibling_addr));
-
-        let Some(_cur_info) = get_page_info(current) else {
-            unreachable!("attempting to free non-allocator-owned page");
+    while current_order < MAX_ORDER {
+        let Some(size_at_order) = PAGE_SIZE.checked_shl(current_order) else {
+            break;
         };
+        let sibling =
+            Frame::containing(PhysicalAddress::new(current.base().data() ^ size_at_order));
 
         let Some(sib_info) = get_page_info(sibling) else {
             break;
         };
-
-        let sib_free_info = match sib_info.as_free() {
-            Some(info) => info,
-            None => break,
+        // The sibling's free blocks would be on the list of another node
+        if frame_node(sibling) != node {
+            break;
+        }
+        let Some(sib_free_info) = sib_info.as_free() else {
+            break;
         };
-
-        if sib_free_info.next().order() < merge_order {
+        // Only a whole free block of the same order can be merged. A free sibling of lower order
+        // is just part of the buddy, the rest of which is still in use.
+        if sib_free_info.next().order() != current_order {
             break;
         }
 
-        if let Some(sib_prev) = sib_free_info.prev().frame() {
-            get_free_alloc_page_info(sib_prev).set_next(sib_free_info.next());
-        } else {
-            if let Some(entry) = freelist.for_orders.get_mut(merge_order as usize) {
-                *entry = sib_free_info.next().frame();
-            }
-        }
-        if let Some(sib_next) = sib_free_info.next().frame() {
-            get_free_alloc_page_info(sib_next).set_prev(sib_free_info.prev());
-        }
+        freelist.unlink(sibling, current_order);
 
-        let mask = !(PAGE_SIZE.checked_shl(merge_order).unwrap_or(0));
-        let current_data = current.base().data() & mask;
-        current = Frame::containing(PhysicalAddress::new(current_data));
+        // The upper half is no longer the head of a block, and must not be mistaken for one by
+        // later merges.
+        let (lower, upper) = if sibling < current {
+            (sibling, current)
+        } else {
+            (current, sibling)
+        };
+        get_page_info(upper)
+            .expect("merged buddy lacked PageInfo")
+            .mark_not_head();
 
-        largest_order = merge_order + 1;
+        current = lower;
+        current_order += 1;
     }
 
-    get_page_info(current)
-        .expect("freeing frame without PageInfo")
-        .transition_to_free(largest_order);
-
-    let new_head = current;
-
-    if let Some(entry) = freelist.for_orders.get_mut(largest_order as usize) {
-        if let Some(old_head) = entry.replace(new_head) {
-            let old_head_info = get_free_alloc_page_info(old_head);
-            let new_head_info = get_free_alloc_page_info(new_head);
+    freelist.push(current, current_order);
+    drop(freelist);
 
-            new_head_info.set_next(P2Frame::new(Some(old_head), largest_order));
-            new_head_info.set_prev(P2Frame::new(None, largest_order));
-            old_head_info.set_prev(P2Frame::new(Some(new_head), largest_order));
-        }
-    }
+    let old = USED_FRAMES.fetch_sub(1 << order, Ordering::Relaxed);
+    assert!(old >= 1 << order, "Free list underflow");
+    pressure::update(free_frames());
+}
 
-    if let Some(sub) = 1usize.checked_shl(order) {
-        freelist.used_frames = freelist
-            .used_frames
-            .checked_sub(sub)
-            .expect("Free list underflow");
+/// Check that every block on the freelists is free, aligned to its order, has a consistent
+/// order and back link, and is on the list of that order.
+///
+/// Panics on the first inconsistency found.
+#[cfg(any(debug_assertions, feature = "selftest"))]
+pub fn check_freelist_integrity() {
+    for (node, freelist) in FREELISTS.iter().enumerate() {
+        freelist.lock().check_integrity(node);
     }
 }
 
@@ -274,7 +284,7 @@ pub unsafe fn map_device_memory(addr: PhysicalAddress, len: usize) -> VirtualAdd
     }
 }
 
-const ORDER_COUNT: u32 = 11;
+pub const ORDER_COUNT: u32 = 11;
 const MAX_ORDER: u32 = ORDER_COUNT - 1;
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
@@ -435,19 +445,197 @@ struct AllocatorData {
     abs_off: usize,
 }
 
+/// Free blocks of one NUMA node
 #[derive(Debug)]
 struct FreeList {
     for_orders: [Option<Frame>; ORDER_COUNT as usize],
-    used_frames: usize,
 }
-static FREELIST: Mutex<FreeList> = Mutex::new(FreeList {
-    for_orders: [None; ORDER_COUNT as usize],
-    used_frames: 0,
-});
+
+impl FreeList {
+    const fn new() -> Self {
+        Self {
+            for_orders: [None; ORDER_COUNT as usize],
+        }
+    }
+
+    /// Take the smallest free block of at least `min_order`, and put back all of it beyond
+    /// `2^min_order` frames
+    fn take(&mut self, min_order: u32) -> Option<Frame> {
+        let (frame_order, frame) = self
+            .for_orders
+            .iter()
+            .enumerate()
+            .skip(min_order as usize)
+            .find_map(|(i, f)| f.map(|f| (i as u32, f)))?;
+
+        let info = get_page_info(frame)
+            .unwrap_or_else(|| panic!("no page info for allocated frame {frame:?}"));
+
+        let next_free = match info.transition_to_used() {
+            Ok(next) => next,
+            Err(_) => panic!("freelist frame {frame:?} was not in Free state!"),
+        };
+
+        debug_assert_eq!(
+            next_free.order(),
+            frame_order,
+            "{frame:?}->next {next_free:?}.order != {frame_order}"
+        );
+
+        if let Some(next) = next_free.frame() {
+            let f = get_free_alloc_page_info(next);
+            f.set_prev(P2Frame::new(None, frame_order));
+        }
+
+        if let Some(entry) = self.for_orders.get_mut(frame_order as usize) {
+            *entry = next_free.frame();
+        }
+
+        for order in (min_order..frame_order).rev() {
+            let order_page_count = 1usize
+                .checked_shl(order)
+                .ok_or(Error::new(EOVERFLOW))
+                .ok()?;
+            let hi = frame.try_next_by(order_page_count).ok()?;
+
+            let hi_info = get_page_info(hi).expect("sub-p2frame of split p2flame lacked PageInfo");
+
+            let free_info = hi_info.transition_to_free(order);
+            free_info.set_next(P2Frame::new(None, order));
+            free_info.set_prev(P2Frame::new(None, order));
+
+            if let Some(entry) = self.for_orders.get_mut(order as usize) {
+                *entry = Some(hi);
+            }
+        }
+
+        Some(frame)
+    }
+
+    /// Remove a free block from the middle or head of the list of its order
+    fn unlink(&mut self, frame: Frame, order: u32) {
+        let info = get_free_alloc_page_info(frame);
+        let (prev, next) = (info.prev(), info.next());
+
+        match prev.frame() {
+            Some(prev_frame) => get_free_alloc_page_info(prev_frame).set_next(next),
+            None => {
+                let head = &mut self.for_orders[order as usize];
+                assert_eq!(
+                    *head,
+                    Some(frame),
+                    "{frame:?} had no prev but is not the order {order} head"
+                );
+                *head = next.frame();
+            }
+        }
+        if let Some(next_frame) = next.frame() {
+            get_free_alloc_page_info(next_frame).set_prev(prev);
+        }
+    }
+
+    /// Mark a block as free and make it the head of the list of its order
+    fn push(&mut self, frame: Frame, order: u32) {
+        let info = get_page_info(frame)
+            .expect("freeing frame without PageInfo")
+            .transition_to_free(order);
+
+        let old_head = self.for_orders[order as usize].replace(frame);
+        info.set_next(P2Frame::new(old_head, order));
+        info.set_prev(P2Frame::new(None, order));
+
+        if let Some(old_head) = old_head {
+            get_free_alloc_page_info(old_head).set_prev(P2Frame::new(Some(frame), order));
+        }
+    }
+
+    #[cfg(any(debug_assertions, feature = "selftest"))]
+    fn check_integrity(&self, node: usize) {
+        for (order, head) in self.for_orders.iter().enumerate() {
+            let order = order as u32;
+            let mut prev = None;
+            let mut next = *head;
+
+            while let Some(frame) = next {
+                let info = get_free_alloc_page_info(frame);
+                assert!(
+                    frame.is_aligned_to_order(order),
+                    "{frame:?} on the order {order} list is misaligned"
+                );
+                assert_eq!(info.prev().frame(), prev, "{frame:?} has a stale prev link");
+                assert_eq!(info.prev().order(), order, "{frame:?} prev order mismatch");
+                assert_eq!(info.next().order(), order, "{frame:?} next order mismatch");
+                assert_eq!(
+                    frame_node(frame),
+                    node,
+                    "{frame:?} is on another node's list"
+                );
+
+                prev = Some(frame);
+                next = info.next().frame();
+            }
+        }
+    }
+}
+
+/// Most NUMA nodes the buddy allocator keeps apart, with any further nodes sharing the last
+pub const MAX_NUMA_NODES: usize = 8;
+
+static FREELISTS: [Mutex<FreeList>; MAX_NUMA_NODES] =
+    [const { Mutex::new(FreeList::new()) }; MAX_NUMA_NODES];
+
+/// Frames handed out by the buddy allocator, on all nodes
+static USED_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+/// For each node index, all node indices in the order their freelists are tried
+static NODE_ORDER: SyncUnsafeCell<[[u8; MAX_NUMA_NODES]; MAX_NUMA_NODES]> =
+    SyncUnsafeCell::new(default_node_order());
+
+/// The node itself, then the others by index
+const fn default_node_order() -> [[u8; MAX_NUMA_NODES]; MAX_NUMA_NODES] {
+    let mut order = [[0; MAX_NUMA_NODES]; MAX_NUMA_NODES];
+    let mut from = 0;
+    while from < MAX_NUMA_NODES {
+        order[from][0] = from as u8;
+        let (mut to, mut i) = (0, 1);
+        while to < MAX_NUMA_NODES {
+            if to != from {
+                order[from][i] = to as u8;
+                i += 1;
+            }
+            to += 1;
+        }
+        from += 1;
+    }
+    order
+}
+
+fn node_index(node: NumaNodeId) -> usize {
+    (node.0 as usize).min(MAX_NUMA_NODES - 1)
+}
+
+#[cfg(not(test))]
+fn local_node() -> usize {
+    node_index(crate::topology::node_of(crate::cpu_id()))
+}
+
+/// There is no current CPU in tests
+#[cfg(test)]
+fn local_node() -> usize {
+    0
+}
 
 pub struct Section {
     base: Frame,
     frames: &'static [PageInfo],
+    /// Index of the node whose freelists hold the free blocks of this section
+    node: AtomicU8,
+}
+
+impl Section {
+    fn node(&self) -> usize {
+        self.node.load(Ordering::Relaxed).into()
+    }
 }
 
 pub const MAX_SECTION_SIZE_BITS: u32 = 27;
@@ -461,9 +649,57 @@ fn init_sections(allocator: BumpAllocator<RmmA>) {
     BUMP_FRAMES.store(bump_used, Ordering::Relaxed);
 }
 
+/// Tag every section with the node its memory is local to, move free blocks to the freelists of
+/// their node, and order the fallback nodes of each node by distance.
+///
+/// Sections are set up from the boot memory map before the firmware tables that describe NUMA
+/// affinity are read, so until this runs all memory is on node 0. Must run before other CPUs
+/// allocate frames.
+#[cold]
+pub fn assign_nodes() {
+    for section in sections() {
+        let node = CPU_TOPOLOGY
+            .node_of_address(section.base.base().data())
+            .unwrap_or_default();
+        section
+            .node
+            .store(node_index(node) as u8, Ordering::Relaxed);
+    }
+
+    for (node, freelist) in FREELISTS.iter().enumerate() {
+        let mut freelist = freelist.lock();
+        for order in 0..ORDER_COUNT {
+            let mut next = freelist.for_orders[order as usize];
+            while let Some(frame) = next {
+                next = get_free_alloc_page_info(frame).next().frame();
+                let owner = frame_node(frame);
+                if owner == node {
+                    continue;
+                }
+                freelist.unlink(frame, order);
+                // Pushing expects a block that is not free yet
+                get_page_info(frame)
+                    .expect("free block lacked PageInfo")
+                    .mark_not_head();
+                FREELISTS[owner].lock().push(frame, order);
+            }
+        }
+    }
+
+    let node_order = unsafe { &mut *NODE_ORDER.get() };
+    for (from, row) in node_order.iter_mut().enumerate() {
+        let from_node = NumaNodeId(from as u32);
+        row.sort_unstable_by_key(|&to| {
+            let distance = CPU_TOPOLOGY.distance(from_node, NumaNodeId(to.into()));
+            (usize::from(to) != from, distance, to)
+        });
+    }
+}
+
 #[cold]
 pub fn init_mm(allocator: BumpAllocator<RmmA>) {
     init_sections(allocator);
+    pressure::init(total_frames());
 
     unsafe {
         let the_frame = allocate_frame().expect("failed to allocate static zeroed frame");
@@ -512,6 +748,13 @@ impl PageInfo {
         Ok(next)
     }
 
+    /// Mark a frame that was merged into a larger free block, so that it does not look like the
+    /// head of a free block itself.
+    fn mark_not_head(&self) {
+        self.refcount.store(RC_USED_NOT_FREE, Ordering::Relaxed);
+        self.next.store(0, Ordering::Relaxed);
+    }
+
     fn transition_to_free(&self, order: u32) -> PageInfoFree<'_> {
         debug_assert_eq!(self.state(), PageInfoState::Used);
         self.refcount.store(order as usize, Ordering::Relaxed);
@@ -626,15 +869,22 @@ impl RefCount {
 fn sections() -> &'static [Section] {
     unsafe { ALLOCATOR_DATA.sections }
 }
-pub fn get_page_info(frame: Frame) -> Option<&'static PageInfo> {
+fn section_of(frame: Frame) -> Option<&'static Section> {
     let sections = sections();
     let idx_res = sections.binary_search_by_key(&frame, |section| section.base);
     if idx_res == Err(0) {
         return None;
     }
-    let section = &sections[idx_res.unwrap_or_else(|e| e - 1)];
+    Some(&sections[idx_res.unwrap_or_else(|e| e - 1)])
+}
+pub fn get_page_info(frame: Frame) -> Option<&'static PageInfo> {
+    let section = section_of(frame)?;
     section.frames.get(frame.offset_from(section.base))
 }
+/// Index of the node whose freelists `frame` belongs on
+fn frame_node(frame: Frame) -> usize {
+    section_of(frame).map_or(0, Section::node)
+}
 
 #[track_caller]
 fn get_free_alloc_page_info(frame: Frame) -> PageInfoFree<'static> {
@@ -659,6 +909,7 @@ bitflags! {
 
 pub trait ArchIntCtx {
     fn ip(&self) -> usize;
+    fn sp(&self) -> usize;
     fn recover_and_efault(&mut self);
 }
 
@@ -693,10 +944,34 @@ pub fn page_fault_handler(
 
     if address_is_user && (caused_by_user || is_usercopy) {
         let mut token = unsafe { CleanLockToken::new() };
-        match context::memory::try_correcting_page_tables(faulting_page, mode, &mut token) {
+        // Only the user's own accesses may grow its stack, not the kernel copying on its behalf
+        let stack_pointer = caused_by_user.then(|| stack.sp());
+        match context::memory::try_correcting_page_tables(
+            faulting_page,
+            mode,
+            stack_pointer,
+            &mut token,
+        ) {
             Ok(()) => return Ok(()),
-            Err(PfError::Oom) => return Err(Error::new(ENOMEM)),
+            // Fail the copy with ENOMEM rather than killing on behalf of a syscall
+            Err(PfError::Oom) if is_usercopy && caused_by_kernel => {
+                crate::scheme::sys::notify_memory_pressure(&mut token);
+                PercpuBlock::current().usercopy_enomem.set(true);
+            }
+            Err(PfError::Oom) => {
+                crate::scheme::sys::notify_memory_pressure(&mut token);
+                if pressure::oom_kill(&mut token) {
+                    // Let the victim run to its exit, then fault again
+                    unsafe { context::switch(&mut token) };
+                    return Ok(());
+                }
+                return Err(Error::new(ENOMEM));
+            }
+            // A bad user address in a usercopy fails the copy below rather than the kernel
+            Err(PfError::Segv | PfError::StackOverflow | PfError::RecursionLimitExceeded)
+                if is_usercopy && caused_by_kernel => {}
             Err(PfError::Segv) => return Err(Error::new(EFAULT)),
+            Err(PfError::StackOverflow) => return Err(Error::new(EFAULT)),
             Err(PfError::RecursionLimitExceeded) => return Err(Error::new(EFAULT)),
             Err(PfError::NonfatalInternalError) => return Err(Error::new(EIO)),
         }
@@ -707,6 +982,10 @@ pub fn page_fault_handler(
         return Ok(());
     }
 
+    if caused_by_kernel && !address_is_user {
+        context::kstack::check_overflow(faulting_address.data());
+    }
+
     Err(Error::new(EFAULT))
 }
 
@@ -739,6 +1018,29 @@ pub fn init_frame(init_rc: RefCount) -> Result<Frame, PfError> {
     Ok(new_frame)
 }
 
+/// Allocate a zeroed block of `2^order` physically contiguous frames, each referenced once like a
+/// frame from [`init_frame`]. The frames can be shared like any other, and each one goes back to
+/// the allocator on its own once its last reference is dropped.
+pub fn init_p2frame(order: u32) -> Result<Vec<RaiiFrame>, Enomem> {
+    let count = 1_usize.checked_shl(order).ok_or(Enomem)?;
+    let mut frames = Vec::new();
+    frames.try_reserve_exact(count).map_err(|_| Enomem)?;
+    let (base, _) = allocate_p2frame_complex(order, AllocationFlags::ZEROED, None, order, None)
+        .ok_or(Enomem)?;
+
+    // Every frame of an allocated block is in use, not only its head
+    for frame in (0..count).filter_map(|i| base.try_next_by(i).ok()) {
+        let page_info = get_page_info(frame)
+            .unwrap_or_else(|| panic!("all allocated frames need a page info, {frame:?} didn't"));
+        debug_assert_eq!(page_info.state(), PageInfoState::Used);
+        page_info
+            .refcount
+            .store(RefCount::One.to_raw(), Ordering::Relaxed);
+        frames.push(RaiiFrame { inner: frame });
+    }
+    Ok(frames)
+}
+
 #[derive(Debug)]
 pub struct TheFrameAllocator;
 
@@ -768,37 +1070,251 @@ bitflags! {
     }
 }
 
-pub fn mlockall(flags: MlockFlags) -> Result<(), Error> {
-    let mut token = unsafe { CleanLockToken::new() };
-    let context = context::current();
-    let mut context = context.write(token.token());
-
-    if flags.contains(MlockFlags::MCL_CURRENT) {
-        let addr_space = context.addr_space().map_err(|_| Error::new(ENOMEM))?;
-        let _addr_space = addr_space.acquire_write();
+/// Lock the address space of the current context as `flags` asks, see
+/// [`AddrSpaceInner::mlockall`](crate::context::memory::AddrSpaceInner::mlockall)
+pub fn mlockall(flags: MlockFlags, token: &mut CleanLockToken) -> Result<(), Error> {
+    let context_ref = context::current();
+    let (addr_space, limit) = {
+        let context = context_ref.read(token.token());
+        (Arc::clone(context.addr_space()?), context.lock_limit())
+    };
 
-        /*
-        for (page, frame) in addr_space.table.utable.iter() {
-            // Lock the page, preventing it from being swapped out.
-            // This is a simplified representation. A real implementation would
-            // involve more complex interactions with the memory manager.
-            // For now, we just set a flag on the page info.
-            if let Some(page_info) = get_page_info(frame.frame()) {
-                // A real implementation would do more here.
-            }
-        }
-        */
-    }
+    let (locked, locked_pages) = {
+        let mut addr_space = addr_space.acquire_write();
+        let locked = addr_space.mlockall(flags, limit);
+        (locked, addr_space.locked_pages())
+    };
 
-    context.mlock |= flags.bits();
+    // Some pages may have been locked before running out of memory
+    let mut context = context_ref.write(token.token());
+    context.memory_locked_count = locked_pages;
+    locked?;
+    context.mlock = flags.bits();
     Ok(())
 }
 
-pub fn munlockall() -> Result<(), Error> {
-    let mut token = unsafe { CleanLockToken::new() };
-    let context = context::current();
-    let mut context = context.write(token.token());
+/// Unlock the whole address space of the current context
+pub fn munlockall(token: &mut CleanLockToken) -> Result<(), Error> {
+    let context_ref = context::current();
+    let addr_space = Arc::clone(context_ref.read(token.token()).addr_space()?);
+    addr_space.acquire_write().munlockall();
 
+    let mut context = context_ref.write(token.token());
     context.mlock = 0;
+    context.memory_locked_count = 0;
     Ok(())
 }
+
+#[cfg(feature = "selftest")]
+pub mod selftests {
+    use super::*;
+    use crate::selftest::{check, check_eq, SelftestResult};
+
+    pub fn refcount_from_raw(_token: &mut CleanLockToken) -> SelftestResult {
+        let count = |n| NonZeroUsize::new(n).ok_or("zero count");
+        for refcount in [
+            RefCount::One,
+            RefCount::Cow(count(2)?),
+            RefCount::Shared(count(3)?),
+            RefCount::Cow(count(RC_MAX)?),
+        ] {
+            check_eq!(RefCount::from_raw(refcount.to_raw()), Some(refcount));
+        }
+        // Free frames and used frames without references have no count
+        check_eq!(RefCount::from_raw(0), None);
+        check_eq!(RefCount::from_raw(3), None);
+        check_eq!(RefCount::from_raw(RC_USED_NOT_FREE), None);
+        // A single reference is never shared
+        check_eq!(
+            RefCount::from_raw(1 | RC_SHARED_NOT_COW | RC_USED_NOT_FREE),
+            Some(RefCount::One)
+        );
+        Ok(())
+    }
+
+    pub fn p2frame_encoding(_token: &mut CleanLockToken) -> SelftestResult {
+        let frame = Frame::containing(PhysicalAddress::new(0x1234_5000));
+        for order in [0, 1, MAX_ORDER] {
+            check_eq!(P2Frame::new(Some(frame), order).get(), (Some(frame), order));
+            check_eq!(P2Frame::new(None, order).get(), (None, order));
+        }
+        // The used bit shared with the refcount is not part of the frame
+        let tagged = P2Frame(P2Frame::new(Some(frame), 3).0 | RC_USED_NOT_FREE);
+        check_eq!(tagged.frame(), Some(frame));
+        check_eq!(tagged.order(), 3);
+        Ok(())
+    }
+
+    pub fn page_info_transitions(_token: &mut CleanLockToken) -> SelftestResult {
+        let info = PageInfo {
+            refcount: AtomicUsize::new(RC_USED_NOT_FREE),
+            next: AtomicUsize::new(0),
+        };
+        check_eq!(info.state(), PageInfoState::Used);
+        check_eq!(info.refcount(), None);
+
+        let free = info.transition_to_free(4);
+        check_eq!(free.next().get(), (None, 4));
+        check_eq!(free.prev().get(), (None, 4));
+        check_eq!(info.state(), PageInfoState::Free);
+        check!(info.as_free().is_some());
+
+        check_eq!(info.transition_to_used().map(P2Frame::order), Ok(4));
+        check_eq!(info.state(), PageInfoState::Used);
+        check!(info.transition_to_used().is_err());
+
+        info.refcount
+            .store(RefCount::One.to_raw(), Ordering::Relaxed);
+        check_eq!(info.add_ref(RefKind::Cow), Ok(()));
+        check_eq!(
+            info.refcount(),
+            Some(RefCount::Cow(NonZeroUsize::MIN.saturating_add(1)))
+        );
+        check_eq!(info.add_ref(RefKind::Shared), Err(AddRefError::CowToShared));
+        check_eq!(info.remove_ref(), Some(RefCount::One));
+        check_eq!(info.remove_ref(), None);
+        check_eq!(info.state(), PageInfoState::Used);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{boxed::Box, vec::Vec};
+
+    const TEST_FRAMES: usize = 64;
+
+    /// The allocator state is global, so tests that install sections must not overlap
+    static SERIAL: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    /// Install a section of `TEST_FRAMES` allocated frames on each node index in `nodes`, all
+    /// owned by the caller, and return the base of each
+    fn setup_sections(nodes: &[u8]) -> Vec<Frame> {
+        let sections: Vec<Section> = nodes
+            .iter()
+            .enumerate()
+            .map(|(i, &node)| {
+                let frames: Vec<PageInfo> = (0..TEST_FRAMES)
+                    .map(|_| PageInfo {
+                        refcount: AtomicUsize::new(RC_USED_NOT_FREE),
+                        next: AtomicUsize::new(0),
+                    })
+                    .collect();
+                Section {
+                    base: Frame::containing(PhysicalAddress::new(0x100_0000 * (i + 1))),
+                    frames: Box::leak(frames.into_boxed_slice()),
+                    node: AtomicU8::new(node),
+                }
+            })
+            .collect();
+        let bases = sections.iter().map(|section| section.base).collect();
+        unsafe {
+            ALLOCATOR_DATA.sections = Box::leak(sections.into_boxed_slice());
+        }
+        for freelist in &FREELISTS {
+            *freelist.lock() = FreeList::new();
+        }
+        USED_FRAMES.store(TEST_FRAMES * nodes.len(), Ordering::Relaxed);
+        bases
+    }
+
+    fn free_section(base: Frame) {
+        for i in 0..TEST_FRAMES {
+            unsafe { deallocate_frame(base.try_next_by(i).unwrap()) };
+        }
+    }
+
+    #[test]
+    fn test_buddy_merge_keeps_freelists_consistent() {
+        let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        let base = setup_sections(&[0])[0];
+
+        // Free every other frame first so that merges happen out of order
+        for i in (0..TEST_FRAMES)
+            .step_by(2)
+            .chain((1..TEST_FRAMES).step_by(2))
+        {
+            unsafe { deallocate_frame(base.try_next_by(i).unwrap()) };
+            check_freelist_integrity();
+        }
+        assert_eq!(buddy_stats().free_blocks[6], 1);
+
+        // Heavy order-2/order-3 churn used to leave stale heads on the lower order lists
+        for _ in 0..16 {
+            let a = allocate_p2frame(2).expect("order 2");
+            let b = allocate_p2frame(3).expect("order 3");
+            let c = allocate_p2frame(2).expect("order 2");
+            check_freelist_integrity();
+            unsafe {
+                deallocate_p2frame(b, 3);
+                check_freelist_integrity();
+                deallocate_p2frame(a, 2);
+                check_freelist_integrity();
+                deallocate_p2frame(c, 2);
+                check_freelist_integrity();
+            }
+        }
+        assert_eq!(buddy_stats().free_blocks[6], 1);
+        assert_eq!(USED_FRAMES.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_allocation_prefers_node_then_falls_back() {
+        let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        let bases = setup_sections(&[0, 1]);
+        for &base in &bases {
+            free_section(base);
+        }
+        check_freelist_integrity();
+
+        let stats = buddy_stats();
+        for node in 0..2 {
+            assert_eq!(stats.nodes[node].total_frames, TEST_FRAMES);
+            assert_eq!(stats.nodes[node].free_frames, TEST_FRAMES);
+        }
+        assert_eq!(stats.nodes[2].total_frames, 0);
+
+        let allocate = |node| {
+            allocate_p2frame_complex(0, AllocationFlags::NONE, None, 0, Some(NumaNodeId(node)))
+                .expect("out of frames")
+                .0
+        };
+        let local: Vec<Frame> = (0..TEST_FRAMES).map(|_| allocate(1)).collect();
+        assert!(local.iter().all(|&frame| frame_node(frame) == 1));
+        assert_eq!(buddy_stats().nodes[1].free_frames, 0);
+
+        // Node 1 is exhausted, so the next frame comes from node 0
+        let remote = allocate(1);
+        assert_eq!(frame_node(remote), 0);
+
+        for frame in local.into_iter().chain([remote]) {
+            unsafe { deallocate_frame(frame) };
+        }
+        check_freelist_integrity();
+        let stats = buddy_stats();
+        assert_eq!(stats.nodes[0].free_frames, TEST_FRAMES);
+        assert_eq!(stats.nodes[1].free_frames, TEST_FRAMES);
+        assert_eq!(stats.used_frames, 0);
+    }
+
+    #[test]
+    fn test_pressure_follows_watermark() {
+        let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        let base = setup_sections(&[0])[0];
+        free_section(base);
+        let _ = pressure::take_change();
+
+        // A watermark above all of memory puts any allocation under pressure
+        pressure::set_low_watermark(TEST_FRAMES + 1);
+        let frame = allocate_frame().expect("out of frames");
+        assert!(pressure::under_pressure());
+        assert!(pressure::take_change());
+        assert!(!pressure::take_change());
+
+        pressure::set_low_watermark(0);
+        unsafe { deallocate_frame(frame) };
+        assert!(!pressure::under_pressure());
+        assert!(pressure::take_change());
+    }
+}
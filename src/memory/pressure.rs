@@ -0,0 +1,206 @@
+//! # Memory pressure
+//!
+//! Every allocation and free compares the free frames to a low watermark. Falling below it puts
+//! the system under pressure, which sys:memory_pressure reports and announces through fevent so
+//! that userspace can give memory back before it runs out. A few frames are held back for kernel
+//! allocations flagged [`AllocationFlags::RESERVE`](super::AllocationFlags::RESERVE), and when a
+//! user page fault still finds no frame, the largest non-realtime process is killed.
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+
+use crate::{
+    context::{self, memory::AddrSpaceWrapper},
+    scheduler::{self, SchedPolicy},
+    sync::CleanLockToken,
+};
+
+/// Frames held back for kernel-critical allocations, unless memory is too small to spare them
+const RESERVE_FRAMES: usize = 256;
+
+/// Free frames below which the system is under pressure
+static LOW_WATERMARK: AtomicUsize = AtomicUsize::new(0);
+/// Free frames only allocations flagged `RESERVE` may take
+static RESERVED: AtomicUsize = AtomicUsize::new(0);
+static UNDER_PRESSURE: AtomicBool = AtomicBool::new(false);
+/// Set when pressure starts or ends, until readers of sys:memory_pressure are told
+static CHANGED: AtomicBool = AtomicBool::new(false);
+/// Number of times the system has come under pressure
+static EPISODES: AtomicU64 = AtomicU64::new(0);
+/// Address space of the last process killed for memory, while it is still being torn down
+static VICTIM: Mutex<Option<Weak<AddrSpaceWrapper>>> = Mutex::new(None);
+
+/// Set aside the reserve and the default watermark for `total` frames of memory
+#[cold]
+pub fn init(total: usize) {
+    let reserved = RESERVE_FRAMES.min(total / 16);
+    RESERVED.store(reserved, Ordering::Relaxed);
+    LOW_WATERMARK.store(reserved + total / 32, Ordering::Relaxed);
+}
+
+pub fn low_watermark() -> usize {
+    LOW_WATERMARK.load(Ordering::Relaxed)
+}
+
+/// Change the watermark, which takes effect at the next allocation or free
+pub fn set_low_watermark(frames: usize) {
+    LOW_WATERMARK.store(frames, Ordering::Relaxed);
+}
+
+pub fn reserved_frames() -> usize {
+    RESERVED.load(Ordering::Relaxed)
+}
+
+pub fn under_pressure() -> bool {
+    UNDER_PRESSURE.load(Ordering::Relaxed)
+}
+
+pub fn episodes() -> u64 {
+    EPISODES.load(Ordering::Relaxed)
+}
+
+/// Whether `count` frames may be taken with `free` left, the last `reserved` of which are only for
+/// allocations that may use the reserve
+fn may_allocate(free: usize, count: usize, reserved: usize, use_reserve: bool) -> bool {
+    let floor = if use_reserve { 0 } else { reserved };
+    free.checked_sub(count).is_some_and(|left| left >= floor)
+}
+
+pub(super) fn allocation_allowed(free: usize, count: usize, use_reserve: bool) -> bool {
+    may_allocate(free, count, reserved_frames(), use_reserve)
+}
+
+/// Compare the free frames to the watermark, after they changed
+pub(super) fn update(free: usize) {
+    let low = free < low_watermark();
+    if UNDER_PRESSURE.load(Ordering::Relaxed) == low
+        || UNDER_PRESSURE.swap(low, Ordering::AcqRel) == low
+    {
+        return;
+    }
+    if low {
+        EPISODES.fetch_add(1, Ordering::Relaxed);
+    }
+    CHANGED.store(true, Ordering::Release);
+}
+
+/// Whether pressure started or ended since the last call. The allocator cannot trigger events
+/// itself, as it runs with arbitrary locks held, so this is polled where a token is at hand.
+pub fn take_change() -> bool {
+    CHANGED.load(Ordering::Relaxed) && CHANGED.swap(false, Ordering::Acquire)
+}
+
+/// Index of the candidate with the most allocated pages, among those that are not realtime
+fn select_victim(candidates: &[(bool, usize)]) -> Option<usize> {
+    candidates
+        .iter()
+        .enumerate()
+        .filter(|&(_, &(realtime, pages))| !realtime && pages > 0)
+        .max_by_key(|&(_, &(_, pages))| pages)
+        .map(|(index, _)| index)
+}
+
+/// Free memory for a user page fault that found no frame, by killing the non-realtime process
+/// with the most allocated memory. Nothing more is killed while the previous victim still holds
+/// its address space.
+///
+/// Returns whether the fault is worth retrying, which it is unless the faulting process is the
+/// victim or there is nothing to kill.
+pub fn oom_kill(token: &mut CleanLockToken) -> bool {
+    let current = context::current();
+    let current_addr_space = current.read(token.token()).addr_space().ok().cloned();
+
+    let mut victim = VICTIM.lock();
+    if let Some(previous) = victim.as_ref().and_then(Weak::upgrade) {
+        return !current_addr_space.is_some_and(|own| Arc::ptr_eq(&own, &previous));
+    }
+
+    // As in sys:context, the list is not held locked while address spaces are walked
+    let context_refs = context::contexts()
+        .read()
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+    let mut address_spaces = Vec::new();
+    let mut candidates = Vec::new();
+    for context_ref in context_refs.iter() {
+        let context = context_ref.read(token.token());
+        if !context.userspace || context.being_sigkilled {
+            continue;
+        }
+        let Ok(addr_space) = context.addr_space() else {
+            continue;
+        };
+        let realtime =
+            context.sched_policy.is_realtime() || context.sched_policy == SchedPolicy::Deadline;
+        let addr_space = Arc::clone(addr_space);
+        drop(context);
+
+        let pages = addr_space.acquire_read().allocated_pages();
+        candidates.push((realtime, pages));
+        address_spaces.push(addr_space);
+    }
+
+    let Some(index) = select_victim(&candidates) else {
+        warn!("out of memory, with no process to kill");
+        return false;
+    };
+    let target = &address_spaces[index];
+
+    // Every thread sharing the address space has to go for its memory to be freed
+    let mut logged = false;
+    for context_ref in context_refs.iter() {
+        let mut context = context_ref.write(token.token());
+        let shares = context
+            .addr_space()
+            .is_ok_and(|addr_space| Arc::ptr_eq(addr_space, target));
+        if !shares {
+            continue;
+        }
+        if !logged {
+            logged = true;
+            warn!(
+                "out of memory: killing pid {} ({}) with {} allocated pages",
+                context.pid, context.name, candidates[index].1
+            );
+        }
+        // Exits at its next syscall boundary or return to userspace, as with proc:<pid>/ctl kill
+        context.being_sigkilled = true;
+        context.interrupt_pending = true;
+        if context.unblock() {
+            drop(context);
+            scheduler::wake(Arc::clone(context_ref), token);
+        }
+    }
+    *victim = Some(Arc::downgrade(target));
+
+    !current_addr_space.is_some_and(|own| Arc::ptr_eq(&own, target))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserve_is_only_for_flagged_allocations() {
+        assert!(may_allocate(300, 1, 256, false));
+        assert!(!may_allocate(256, 1, 256, false));
+        assert!(may_allocate(256, 1, 256, true));
+        assert!(!may_allocate(0, 1, 256, true));
+        assert!(!may_allocate(260, 8, 256, false));
+    }
+
+    #[test]
+    fn victim_is_largest_non_realtime() {
+        assert_eq!(
+            select_victim(&[(false, 10), (true, 500), (false, 40)]),
+            Some(2)
+        );
+        assert_eq!(select_victim(&[(true, 500), (false, 0)]), None);
+        assert_eq!(select_victim(&[]), None);
+    }
+}
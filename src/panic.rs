@@ -1,6 +1,6 @@
 //! Intrinsics for panic handling
 
-use core::{mem, panic::PanicInfo, slice};
+use core::{mem, ops::Range, panic::PanicInfo, slice};
 
 #[cfg(target_pointer_width = "32")]
 use object::elf::FileHeader32 as FileHeader;
@@ -8,17 +8,26 @@ use object::elf::FileHeader32 as FileHeader;
 use object::elf::FileHeader64 as FileHeader;
 use object::{
     elf,
-    read::elf::{FileHeader as _, Sym as _},
+    read::elf::{FileHeader as _, Sym as _, SymbolTable},
     NativeEndian,
 };
-use rmm::VirtualAddress;
 use rustc_demangle::demangle;
 
 use crate::{
-    arch::{consts::USER_END_OFFSET, interrupt::trace::StackTrace},
-    context, cpu_id,
+    arch::{
+        consts::USER_END_OFFSET,
+        interrupt::trace::{self, StackTrace},
+    },
+    context::{
+        self,
+        kstack::{self, Kstack, KSTACK_SIZE},
+    },
+    cpu_id,
     interrupt::{self, InterruptStack},
+    kernel_executable_offsets,
     memory::KernelMapper,
+    paging::VirtualAddress,
+    percpu::PercpuBlock,
     sync::CleanLockToken,
     syscall::{self, usercopy::UserSliceRo},
 };
@@ -70,82 +79,202 @@ fn panic_handler_inner(info: &PanicInfo) -> ! {
     }
 }
 
-/// Prints a stack trace.
-#[inline(never)]
-pub unsafe fn stack_trace() {
-    unsafe {
-        let mapper = KernelMapper::lock();
-
-        let kernel_ptr = crate::KERNEL_OFFSET as *const u8;
-        let elf_header: &FileHeader<NativeEndian> = object::pod::from_bytes(slice::from_raw_parts(
-            kernel_ptr,
-            size_of::<FileHeader<NativeEndian>>(),
-        ))
-        .unwrap()
-        .0;
-
-        // This assumes that the linker places .shstrtab as last section. If it
-        // isn't, that just causes a recursive panic, not UB.
-        let kernel_size = elf_header.e_shoff(NativeEndian) as usize
-            + usize::from(elf_header.e_shnum(NativeEndian))
-                * usize::from(elf_header.e_shentsize(NativeEndian));
-        let kernel_slice = slice::from_raw_parts(kernel_ptr, kernel_size);
-
-        let symbols = elf_header
-            .sections(NativeEndian, kernel_slice)
-            .unwrap()
-            .symbols(NativeEndian, kernel_slice, elf::SHT_SYMTAB)
-            .unwrap();
-
-        let mut frame = StackTrace::start();
-
-        //Maximum 64 frames
-        for _ in 0..64 {
-            let Some(frame_) = frame else {
-                break;
-            };
-            let fp_virt = VirtualAddress::new(frame_.fp);
-            let pc_virt = VirtualAddress::new(frame_.pc_ptr as usize);
-            if !(fp_virt.data() >= USER_END_OFFSET
-                && pc_virt.data() >= USER_END_OFFSET
-                && (fp_virt.data() as *const usize).is_aligned()
-                && (pc_virt.data() as *const usize).is_aligned()
-                && mapper.translate(fp_virt).is_some()
-                && mapper.translate(pc_virt).is_some())
-            {
-                println!("  {:>016x}: GUARD PAGE", frame_.fp);
-                break;
-            }
+/// Most return addresses printed by [`stack_trace`]
+const MAX_FRAMES: usize = 32;
 
-            let pc = *frame_.pc_ptr;
-            if pc == 0 {
-                println!(" {:>016x}: EMPTY RETURN", frame_.fp);
-                break;
-            }
+/// Why [`walk_frames`] stopped
+#[derive(Debug, PartialEq)]
+enum WalkEnd {
+    /// The outermost frame was reached
+    Complete,
+    /// The frame at this address is not within the stack
+    OffStack(usize),
+    /// This return address is not in the kernel's text
+    NotText(usize),
+    /// The next frame pointer is not above the current one
+    Backwards(usize),
+    /// The word at this address could not be found to be mapped
+    Unmapped(usize),
+    /// [`MAX_FRAMES`] frames were printed
+    Limit,
+}
 
-            println!("  FP {:>016x}: PC {:>016x}", frame_.fp, pc);
-
-            for sym in symbols.iter() {
-                if sym.st_type() != elf::STT_FUNC {
-                    continue;
-                }
-                let sym_addr = sym.st_value.get(NativeEndian) as usize;
-                if !(pc >= sym_addr && pc < sym_addr + sym.st_size.get(NativeEndian) as usize) {
-                    continue;
-                }
-
-                println!("    {:>016X}+{:>04X}", sym_addr, pc - sym_addr);
-
-                if let Some(sym_name) = sym
-                    .name(NativeEndian, symbols.strings())
-                    .ok()
-                    .and_then(|name| core::str::from_utf8(name).ok())
-                {
-                    println!("    {:#}", demangle(sym_name));
-                }
-            }
-            frame = frame_.next();
+/// Whether the word at `addr` lies within `stack`
+fn holds_word(stack: &Range<usize>, addr: usize) -> bool {
+    addr % mem::size_of::<usize>() == 0
+        && addr >= stack.start
+        && addr
+            .checked_add(mem::size_of::<usize>())
+            .is_some_and(|end| end <= stack.end)
+}
+
+/// Follow the frame pointer chain from `frame`, calling `found` with the frame pointer and return
+/// address of each frame.
+///
+/// Nothing is read before it is checked: both slots of a frame must lie within `stack` and be
+/// `readable`, each frame must be above the one before, and return addresses must point into
+/// `text`. The first frame that fails a check ends the walk, so a corrupted stack cannot fault the
+/// walker.
+///
+/// # Safety
+///
+/// Every word of `stack` that `readable` accepts must be mapped.
+unsafe fn walk_frames(
+    mut frame: StackTrace,
+    stack: &Range<usize>,
+    readable: impl Fn(usize) -> bool,
+    text: &Range<usize>,
+    mut found: impl FnMut(usize, usize),
+) -> WalkEnd {
+    for _ in 0..MAX_FRAMES {
+        if !holds_word(stack, frame.fp) || !holds_word(stack, frame.pc_ptr as usize) {
+            return WalkEnd::OffStack(frame.fp);
+        }
+        if let Some(addr) = [frame.fp, frame.pc_ptr as usize]
+            .into_iter()
+            .find(|&addr| !readable(addr))
+        {
+            return WalkEnd::Unmapped(addr);
+        }
+        let pc = unsafe { frame.pc_ptr.read() };
+        if pc == 0 {
+            return WalkEnd::Complete;
         }
+        if !text.contains(&pc) {
+            return WalkEnd::NotText(pc);
+        }
+        found(frame.fp, pc);
+
+        let fp = frame.fp;
+        frame = match unsafe { frame.next() } {
+            Some(next) if next.fp > fp => next,
+            Some(next) if next.fp != 0 => return WalkEnd::Backwards(next.fp),
+            _ => return WalkEnd::Complete,
+        };
+    }
+    WalkEnd::Limit
+}
+
+/// The stack containing `fp`, if it is one known to be mapped: a handed out slot of the kstack
+/// region, the kernel stack of the context running on this CPU, or a stack of this CPU itself
+fn known_stack(fp: usize) -> Option<Range<usize>> {
+    kstack::stack_containing(fp)
+        .into_iter()
+        .chain(current_kstack())
+        .chain(trace::cpu_stacks())
+        .find(|stack| stack.contains(&fp))
+}
+
+/// The kernel stack of the context running on this CPU. Only the context itself replaces its
+/// kstack, so the context is not locked, as it may be what was locked when the kernel panicked.
+fn current_kstack() -> Option<Range<usize>> {
+    let id = PercpuBlock::current().context_id.get();
+    let contexts = context::contexts().try_read()?;
+    let context = unsafe { &*contexts.get(&id)?.data_ptr() };
+    context.kstack.as_ref().map(Kstack::range)
+}
+
+/// Where the stack containing `fp` would be if it is not a [`known_stack`]. Kernel stacks outside
+/// the kstack region are naturally aligned blocks in the physmap, so it is taken to be the block
+/// of [`KSTACK_SIZE`] containing `fp`. Nothing says it is mapped.
+fn guessed_stack(fp: usize) -> Option<Range<usize>> {
+    if fp < USER_END_OFFSET {
+        return None;
+    }
+    let bottom = fp.wrapping_sub(fp % KSTACK_SIZE);
+    Some(bottom..bottom.checked_add(KSTACK_SIZE)?)
+}
+
+/// Whether the kernel page tables map `addr`. As the walk must not wait on a CPU that might be
+/// stuck, this is `false` while another CPU has the kernel mapper locked.
+fn is_mapped(addr: usize) -> bool {
+    KernelMapper::try_lock()
+        .is_some_and(|mapper| mapper.translate(VirtualAddress::new(addr)).is_some())
+}
+
+/// The symbol table of the kernel image, if its ELF headers can be parsed
+unsafe fn kernel_symbols() -> Option<SymbolTable<'static, FileHeader<NativeEndian>>> {
+    let kernel_ptr = crate::KERNEL_OFFSET as *const u8;
+    let header_bytes =
+        unsafe { slice::from_raw_parts(kernel_ptr, size_of::<FileHeader<NativeEndian>>()) };
+    let elf_header: &FileHeader<NativeEndian> = object::pod::from_bytes(header_bytes).ok()?.0;
+
+    // This assumes that the linker places .shstrtab as last section
+    let kernel_size = usize::from(elf_header.e_shnum(NativeEndian))
+        .checked_mul(usize::from(elf_header.e_shentsize(NativeEndian)))?
+        .checked_add(usize::try_from(elf_header.e_shoff(NativeEndian)).ok()?)?;
+    let kernel_slice = unsafe { slice::from_raw_parts(kernel_ptr, kernel_size) };
+
+    elf_header
+        .sections(NativeEndian, kernel_slice)
+        .ok()?
+        .symbols(NativeEndian, kernel_slice, elf::SHT_SYMTAB)
+        .ok()
+}
+
+/// Print the function containing `pc`, from the ksyms table if the kernel has one and otherwise
+/// from the symbol table of the kernel image
+fn print_symbol(pc: usize, symbols: Option<&SymbolTable<'static, FileHeader<NativeEndian>>>) {
+    #[cfg(feature = "ksyms")]
+    if let Some((sym_name, offset)) = crate::ksyms::symbolize(pc) {
+        println!("    {:#}+{:#x}", demangle(sym_name), offset);
+        return;
+    }
+
+    let Some(symbols) = symbols else {
+        return;
+    };
+    for sym in symbols.iter() {
+        if sym.st_type() != elf::STT_FUNC {
+            continue;
+        }
+        let sym_addr = sym.st_value.get(NativeEndian) as usize;
+        let sym_size = sym.st_size.get(NativeEndian) as usize;
+        let Some(offset) = pc.checked_sub(sym_addr).filter(|&offset| offset < sym_size) else {
+            continue;
+        };
+
+        println!("    {:>016X}+{:>04X}", sym_addr, offset);
+
+        if let Some(sym_name) = sym
+            .name(NativeEndian, symbols.strings())
+            .ok()
+            .and_then(|name| core::str::from_utf8(name).ok())
+        {
+            println!("    {:#}", demangle(sym_name));
+        }
+    }
+}
+
+/// Prints a stack trace of up to [`MAX_FRAMES`] return addresses, by following the frame
+/// pointer chain. See [`walk_frames`] for what ends it early.
+#[inline(never)]
+pub unsafe fn stack_trace() {
+    let Some(frame) = (unsafe { StackTrace::start() }) else {
+        return;
+    };
+    let known = known_stack(frame.fp);
+    let Some(stack) = known.clone().or_else(|| guessed_stack(frame.fp)) else {
+        println!("  FP {:>016x}: NOT ON A KERNEL STACK", frame.fp);
+        return;
+    };
+    let readable = |addr| known.is_some() || is_mapped(addr);
+    let text = kernel_executable_offsets::__text_start()..kernel_executable_offsets::__text_end();
+    let symbols = unsafe { kernel_symbols() };
+
+    let end = unsafe {
+        walk_frames(frame, &stack, readable, &text, |fp, pc| {
+            println!("  FP {:>016x}: PC {:>016x}", fp, pc);
+            print_symbol(pc, symbols.as_ref());
+        })
+    };
+    match end {
+        WalkEnd::Complete => (),
+        WalkEnd::OffStack(fp) => println!("  FP {:>016x}: OUTSIDE THE STACK", fp),
+        WalkEnd::NotText(pc) => println!("  PC {:>016x}: OUTSIDE KERNEL TEXT", pc),
+        WalkEnd::Backwards(fp) => println!("  FP {:>016x}: BELOW THE PREVIOUS FRAME", fp),
+        WalkEnd::Unmapped(addr) => println!("  {:>016x}: NOT MAPPED", addr),
+        WalkEnd::Limit => println!("  <more than {} frames>", MAX_FRAMES),
     }
 }
 
@@ -197,3 +326,108 @@ pub unsafe fn user_stack_trace(stack: &InterruptStack) {
         fp = next_fp;
     }
 }
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    const TEXT: Range<usize> = 0x1000..0x2000;
+
+    /// A stack with a frame at every fourth word, each calling the one above it
+    fn fake_stack(frames: usize) -> Vec<usize> {
+        let mut stack = vec![0_usize; 4 * frames + 4];
+        let base = stack.as_ptr() as usize;
+        for frame in 0..frames {
+            let next = 4 * (frame + 1);
+            stack[4 * frame] = if frame + 1 < frames {
+                base + 8 * next
+            } else {
+                0
+            };
+            stack[4 * frame + 1] = TEXT.start + 0x10 * (frame + 1);
+        }
+        stack
+    }
+
+    fn walk(stack: &[usize]) -> (Vec<usize>, WalkEnd) {
+        walk_readable(stack, |_| true)
+    }
+
+    fn walk_readable(stack: &[usize], readable: impl Fn(usize) -> bool) -> (Vec<usize>, WalkEnd) {
+        let start = stack.as_ptr() as usize;
+        let bounds = start..start + 8 * stack.len();
+        let mut pcs = Vec::new();
+        let end = unsafe {
+            walk_frames(
+                StackTrace::from_fp(start).unwrap(),
+                &bounds,
+                readable,
+                &TEXT,
+                |_, pc| pcs.push(pc),
+            )
+        };
+        (pcs, end)
+    }
+
+    #[test]
+    fn chain_is_followed_to_the_outermost_frame() {
+        let stack = fake_stack(3);
+        assert_eq!(
+            walk(&stack),
+            (vec![0x1010, 0x1020, 0x1030], WalkEnd::Complete)
+        );
+    }
+
+    #[test]
+    fn zero_return_address_ends_the_walk() {
+        let mut stack = fake_stack(3);
+        stack[5] = 0;
+        assert_eq!(walk(&stack), (vec![0x1010], WalkEnd::Complete));
+    }
+
+    #[test]
+    fn invalid_frames_end_the_walk() {
+        let mut stack = fake_stack(3);
+        let end = stack.as_ptr() as usize + 8 * stack.len();
+        stack[4] = end + 64;
+        assert_eq!(
+            walk(&stack),
+            (vec![0x1010, 0x1020], WalkEnd::OffStack(end + 64))
+        );
+
+        let mut stack = fake_stack(3);
+        let start = stack.as_ptr() as usize;
+        stack[4] = start;
+        assert_eq!(
+            walk(&stack),
+            (vec![0x1010, 0x1020], WalkEnd::Backwards(start))
+        );
+
+        let mut stack = fake_stack(3);
+        stack[5] = 0x5000;
+        assert_eq!(walk(&stack), (vec![0x1010], WalkEnd::NotText(0x5000)));
+    }
+
+    #[test]
+    fn unreadable_frame_ends_the_walk() {
+        let stack = fake_stack(3);
+        let third = stack.as_ptr() as usize + 8 * 8;
+        assert_eq!(
+            walk_readable(&stack, |addr| addr < third),
+            (vec![0x1010, 0x1020], WalkEnd::Unmapped(third))
+        );
+        let return_address = third + 8;
+        assert_eq!(
+            walk_readable(&stack, |addr| addr != return_address),
+            (vec![0x1010, 0x1020], WalkEnd::Unmapped(return_address))
+        );
+    }
+
+    #[test]
+    fn walk_stops_after_max_frames() {
+        let stack = fake_stack(MAX_FRAMES + 8);
+        let (pcs, end) = walk(&stack);
+        assert_eq!(pcs.len(), MAX_FRAMES);
+        assert_eq!(end, WalkEnd::Limit);
+    }
+}
@@ -4,20 +4,24 @@ use alloc::{
 };
 use core::{
     cell::{Cell, RefCell},
-    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering},
 };
 
 use rmm::Arch;
 use syscall::PtraceFlags;
 
 use crate::{
+    allocator::stats::HeapCounters,
     arch::device::ArchPercpuMisc,
     context::{empty_cr3, memory::AddrSpaceWrapper, switch::ContextSwitchPercpu},
     cpu_set::{LogicalCpuId, MAX_CPU_COUNT},
-    cpu_stats::{CpuStats, CpuStatsData},
+    cpu_stats::{CpuStats, CpuStatsData, IRQ_VECTOR_COUNT},
+    deferred::DeferQueue,
+    paging::VirtualAddress,
     ptrace::Session,
     scheduler::Scheduler,
     syscall::debug::SyscallDebugInfo,
+    watchdog::Heartbeat,
 };
 
 /// The percpu block, that stored all percpu variables.
@@ -32,13 +36,23 @@ pub struct PercpuBlock {
 
     pub current_addrsp: RefCell<Option<Arc<AddrSpaceWrapper>>>,
     pub new_addrsp_tmp: Cell<Option<Arc<AddrSpaceWrapper>>>,
+    /// Set while a shootdown sent to this CPU is not handled yet, see [`request_tlb_shootdown`]
     pub wants_tlb_shootdown: AtomicBool,
+    /// Number of pages in `tlb_pages` to invalidate, or `TLB_FLUSH_ALL`, 0 once handled
+    tlb_request: AtomicUsize,
+    tlb_pages: [AtomicUsize; TLB_SHOOTDOWN_MAX_PAGES],
+    /// Number of shootdowns this CPU has handled
+    tlb_ack: AtomicUsize,
 
     pub profiling: Option<&'static crate::profiling::RingBuffer>,
 
     pub ptrace_flags: Cell<PtraceFlags>,
     pub ptrace_session: RefCell<Option<Weak<Session>>>,
     pub inside_syscall: Cell<bool>,
+    /// Ordered locks held, counted in debug builds to check clean lock tokens
+    pub held_locks: Cell<usize>,
+    /// Set when a usercopy fault ran out of memory, so the copy fails with ENOMEM
+    pub usercopy_enomem: Cell<bool>,
 
     pub syscall_debug_info: Cell<SyscallDebugInfo>,
 
@@ -47,6 +61,15 @@ pub struct PercpuBlock {
     pub stats: CpuStats,
 
     pub scheduler: Scheduler,
+
+    /// Heartbeat checked by the lockup detector
+    pub watchdog: Heartbeat,
+
+    /// Kernel heap allocations made by this CPU
+    pub heap: HeapCounters,
+
+    /// Work interrupt handlers deferred to run with interrupts enabled
+    pub deferred: DeferQueue,
 }
 
 static ALL_PERCPU_BLOCKS: [AtomicPtr<PercpuBlock>; MAX_CPU_COUNT as usize] =
@@ -57,6 +80,12 @@ pub unsafe fn init_tlb_shootdown(id: LogicalCpuId, block: *mut PercpuBlock) {
     ALL_PERCPU_BLOCKS[id.get() as usize].store(block, Ordering::Release)
 }
 
+/// Get the per-CPU block of a CPU, if it has come up
+pub fn get_percpu_block(id: LogicalCpuId) -> Option<&'static PercpuBlock> {
+    let block = ALL_PERCPU_BLOCKS.get(id.get() as usize)?;
+    unsafe { block.load(Ordering::Acquire).as_ref() }
+}
+
 pub fn get_all_stats() -> Vec<(LogicalCpuId, CpuStatsData)> {
     let mut res = ALL_PERCPU_BLOCKS
         .iter()
@@ -70,65 +99,163 @@ pub fn get_all_stats() -> Vec<(LogicalCpuId, CpuStatsData)> {
     res
 }
 
+/// Get the per-interrupt counts of every CPU that has come up, ordered by CPU id.
+pub fn get_all_irq_counts() -> Vec<(LogicalCpuId, [u64; IRQ_VECTOR_COUNT])> {
+    let mut res = ALL_PERCPU_BLOCKS
+        .iter()
+        .filter_map(|block| unsafe { block.load(Ordering::Relaxed).as_ref() })
+        .map(|block| (block.cpu_id, block.stats.irq_vector_counts()))
+        .collect::<Vec<_>>();
+    res.sort_unstable_by_key(|(id, _counts)| id.get());
+    res
+}
+
 // PercpuBlock::current() is implemented somewhere in the arch-specific modules
 
+/// Most pages a single shootdown invalidates one by one, above which the whole TLB is flushed
+pub const TLB_SHOOTDOWN_MAX_PAGES: usize = 32;
+/// Value of `tlb_request` asking for the whole TLB to be flushed
+const TLB_FLUSH_ALL: usize = usize::MAX;
+/// Longest pause in spins between checks for a shootdown acknowledgement
+const TLB_ACK_BACKOFF_MAX: u32 = 1024;
+
+/// Shootdown IPIs sent
+static TLB_SHOOTDOWNS: AtomicU64 = AtomicU64::new(0);
+/// Pages invalidated one by one, counted once per flush however many CPUs it reached
+static TLB_PAGES_FLUSHED: AtomicU64 = AtomicU64::new(0);
+/// Flushes of the whole TLB, counted the same way
+static TLB_FULL_FLUSHES: AtomicU64 = AtomicU64::new(0);
+
+/// TLB shootdown counters, see [`tlb_stats`]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TlbStats {
+    pub shootdowns: u64,
+    pub pages_flushed: u64,
+    pub full_flushes: u64,
+}
+
+pub fn tlb_stats() -> TlbStats {
+    TlbStats {
+        shootdowns: TLB_SHOOTDOWNS.load(Ordering::Relaxed),
+        pages_flushed: TLB_PAGES_FLUSHED.load(Ordering::Relaxed),
+        full_flushes: TLB_FULL_FLUSHES.load(Ordering::Relaxed),
+    }
+}
+
+/// Count a flush of `pages`, or of the whole TLB if `None`
+pub fn count_tlb_flush(pages: Option<usize>) {
+    match pages {
+        Some(pages) => TLB_PAGES_FLUSHED.fetch_add(pages as u64, Ordering::Relaxed),
+        None => TLB_FULL_FLUSHES.fetch_add(1, Ordering::Relaxed),
+    };
+}
+
 /// Shoots down the TLB on a specific CPU or all CPUs.
 ///
 /// # Arguments
 ///
 /// * `target` - The CPU to shoot down the TLB on. If `None`, the TLB will be shot down on all CPUs.
 pub fn shootdown_tlb_ipi(target: Option<LogicalCpuId>) {
+    if let Some(target) = target {
+        request_tlb_shootdown(target, None);
+    } else {
+        for id in 0..crate::cpu_count() {
+            shootdown_tlb_ipi(Some(LogicalCpuId::new(id)));
+        }
+    }
+}
+
+/// Ask `target` to invalidate the pages at the addresses in `pages`, or its whole TLB if `None`
+/// or if there are more than [`TLB_SHOOTDOWN_MAX_PAGES`]. Returns a ticket for
+/// [`wait_tlb_shootdown`], or `None` if no IPI was sent.
+pub fn request_tlb_shootdown(target: LogicalCpuId, pages: Option<&[usize]>) -> Option<TlbTicket> {
     if cfg!(not(feature = "multi_core")) {
-        return;
+        return None;
     }
 
-    if let Some(target) = target {
-        let my_percpublock = PercpuBlock::current();
-        assert_ne!(target, my_percpublock.cpu_id);
-
-        let Some(percpublock) = (unsafe {
-            ALL_PERCPU_BLOCKS[target.get() as usize]
-                .load(Ordering::Acquire)
-                .as_ref()
-        }) else {
-            warn!("Trying to TLB shootdown a CPU that doesn't exist or isn't initialized.");
-            return;
-        };
-        #[expect(clippy::bool_comparison)]
-        while percpublock
-            .wants_tlb_shootdown
-            .swap(true, Ordering::Release)
-            == true
-        {
-            // Load is faster than CAS or on x86, LOCK BTS
-            while percpublock.wants_tlb_shootdown.load(Ordering::Relaxed) == true {
-                my_percpublock.maybe_handle_tlb_shootdown();
-                core::hint::spin_loop();
+    let my_percpublock = PercpuBlock::current();
+    assert_ne!(target, my_percpublock.cpu_id);
+
+    let Some(percpublock) = get_percpu_block(target) else {
+        warn!("Trying to TLB shootdown a CPU that doesn't exist or isn't initialized.");
+        return None;
+    };
+
+    // Only one shootdown is in flight per CPU. Whoever waits for the slot handles its own
+    // shootdowns meanwhile, so that two CPUs shooting down each other cannot deadlock.
+    while percpublock
+        .wants_tlb_shootdown
+        .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        my_percpublock.maybe_handle_tlb_shootdown();
+        core::hint::spin_loop();
+    }
+
+    // The slot is ours until the target acknowledges, so the next acknowledgement is for this
+    let acked = percpublock.tlb_ack.load(Ordering::Acquire);
+    let request = match pages {
+        Some(pages) if pages.len() <= TLB_SHOOTDOWN_MAX_PAGES => {
+            for (slot, &page) in percpublock.tlb_pages.iter().zip(pages) {
+                slot.store(page, Ordering::Relaxed);
             }
+            pages.len()
         }
+        _ => TLB_FLUSH_ALL,
+    };
+    percpublock.tlb_request.store(request, Ordering::Release);
 
-        crate::ipi::ipi_single(crate::ipi::IpiKind::Tlb, percpublock);
-    } else {
-        for id in 0..crate::cpu_count() {
-            shootdown_tlb_ipi(Some(LogicalCpuId::new(id)));
+    crate::ipi::ipi_single(crate::ipi::IpiKind::Tlb, percpublock);
+    TLB_SHOOTDOWNS.fetch_add(1, Ordering::Relaxed);
+
+    Some(TlbTicket {
+        target: percpublock,
+        acked,
+    })
+}
+
+/// A shootdown sent by [`request_tlb_shootdown`] that may not be handled yet
+pub struct TlbTicket {
+    target: &'static PercpuBlock,
+    acked: usize,
+}
+
+/// Wait until the target of `ticket` has invalidated its TLB, backing off exponentially between
+/// checks and handling shootdowns sent to this CPU meanwhile
+pub fn wait_tlb_shootdown(ticket: TlbTicket) {
+    let my_percpublock = PercpuBlock::current();
+    let mut backoff = 1;
+    while ticket.target.tlb_ack.load(Ordering::Acquire) == ticket.acked {
+        my_percpublock.maybe_handle_tlb_shootdown();
+        for _ in 0..backoff {
+            core::hint::spin_loop();
         }
+        backoff = backoff.saturating_mul(2).min(TLB_ACK_BACKOFF_MAX);
     }
 }
+
 impl PercpuBlock {
     /// Handles a TLB shootdown IPI.
     pub fn maybe_handle_tlb_shootdown(&self) {
-        #[expect(clippy::bool_comparison)]
-        if self.wants_tlb_shootdown.swap(false, Ordering::Relaxed) == false {
+        let request = self.tlb_request.swap(0, Ordering::Acquire);
+        if request == 0 {
             return;
         }
 
         unsafe {
-            crate::paging::RmmA::invalidate_all();
+            match self.tlb_pages.get(..request) {
+                Some(pages) => {
+                    for page in pages {
+                        let address = VirtualAddress::new(page.load(Ordering::Relaxed));
+                        crate::paging::RmmA::invalidate(address);
+                    }
+                }
+                None => crate::paging::RmmA::invalidate_all(),
+            }
         }
 
-        if let Some(addrsp) = &*self.current_addrsp.borrow() {
-            addrsp.acquire_read().tlb_ack.fetch_add(1, Ordering::Release);
-        }
+        self.tlb_ack.fetch_add(1, Ordering::Release);
+        self.wants_tlb_shootdown.store(false, Ordering::Release);
     }
 }
 /// The arch-specific hook for switching address spaces.
@@ -183,9 +310,14 @@ impl PercpuBlock {
             current_addrsp: RefCell::new(None),
             new_addrsp_tmp: Cell::new(None),
             wants_tlb_shootdown: AtomicBool::new(false),
+            tlb_request: AtomicUsize::new(0),
+            tlb_pages: [const { AtomicUsize::new(0) }; TLB_SHOOTDOWN_MAX_PAGES],
+            tlb_ack: AtomicUsize::new(0),
             ptrace_flags: Cell::new(PtraceFlags::empty()),
             ptrace_session: RefCell::new(None),
             inside_syscall: Cell::new(false),
+            held_locks: Cell::new(0),
+            usercopy_enomem: Cell::new(false),
 
             syscall_debug_info: Cell::new(SyscallDebugInfo::default()),
 
@@ -196,6 +328,10 @@ impl PercpuBlock {
             stats: CpuStats::default(),
 
             scheduler: Scheduler::new(),
+
+            watchdog: Heartbeat::default(),
+            heap: HeapCounters::new(),
+            deferred: DeferQueue::new(),
         }
     }
 }
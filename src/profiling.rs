@@ -3,7 +3,7 @@ use core::sync::atomic::AtomicU32;
 use core::{
     cell::UnsafeCell,
     mem::size_of,
-    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering},
 };
 
 use alloc::boxed::Box;
@@ -40,6 +40,8 @@ pub struct RingBuffer {
     /// The number of NMIs that have occurred in user space.
     #[cfg_attr(not(feature = "profiling"), expect(dead_code))]
     pub(crate) nmi_ucount: AtomicUsize,
+    /// One record per kernel sample, read through sys:profile.
+    pub(crate) samples: SampleRing,
 }
 
 impl RingBuffer {
@@ -105,9 +107,85 @@ impl RingBuffer {
             buf: Box::leak(unsafe { Box::new_zeroed().assume_init() }),
             nmi_kcount: AtomicUsize::new(0),
             nmi_ucount: AtomicUsize::new(0),
+            samples: SampleRing::new(),
         }))
     }
 }
+
+/// Number of records each CPU's sample ring holds before new samples are dropped.
+pub const SAMPLE_CAPACITY: usize = 4096;
+
+/// Record kind of a sample.
+pub const RECORD_SAMPLE: u32 = 0;
+/// Record kind reporting samples lost because the ring was full, counted in `context`.
+pub const RECORD_DROPPED: u32 = 1;
+
+/// A fixed size record in the sys:profile stream.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProfileRecord {
+    pub kind: u32,
+    pub cpu: u32,
+    /// Timestamp counter when the sample was taken.
+    pub time: u64,
+    /// Interrupted kernel instruction pointer.
+    pub ip: u64,
+    /// ID of the context that was running.
+    pub context: u64,
+}
+
+/// A single producer, single consumer ring of samples, filled from the profiling NMI.
+pub struct SampleRing {
+    /// Index of the next record to read.
+    head: AtomicUsize,
+    /// Index of the next record to write.
+    tail: AtomicUsize,
+    buf: &'static [UnsafeCell<ProfileRecord>; SAMPLE_CAPACITY],
+    /// Samples lost since the reader last collected this count.
+    dropped: AtomicU64,
+}
+
+impl SampleRing {
+    fn new() -> Self {
+        Self {
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            buf: Box::leak(unsafe { Box::new_zeroed().assume_init() }),
+            dropped: AtomicU64::new(0),
+        }
+    }
+    /// Append a record, counting it as dropped if the ring is full.
+    ///
+    /// Must only be called by the ring's own CPU.
+    #[cfg_attr(not(any(test, feature = "profiling")), expect(dead_code))]
+    fn push(&self, record: ProfileRecord) {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) >= SAMPLE_CAPACITY {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        unsafe { self.buf[tail % SAMPLE_CAPACITY].get().write(record) };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+    }
+    /// Take the oldest record.
+    ///
+    /// # Safety
+    ///
+    /// There must be no other reader at the same time.
+    pub unsafe fn pop(&self) -> Option<ProfileRecord> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let record = unsafe { self.buf[head % SAMPLE_CAPACITY].get().read() };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(record)
+    }
+    /// Take the number of samples dropped since the last call.
+    pub fn take_dropped(&self) -> u64 {
+        self.dropped.swap(0, Ordering::Relaxed)
+    }
+}
 /// A null pointer to a `RingBuffer`.
 const NULL: AtomicPtr<RingBuffer> = AtomicPtr::new(core::ptr::null_mut());
 /// An array of atomic pointers to the `RingBuffer`s for each CPU.
@@ -118,6 +196,67 @@ pub const PROFILE_TOGGLEABLE: bool = true;
 /// A flag indicating whether profiling is currently enabled.
 pub static IS_PROFILING: AtomicBool = AtomicBool::new(false);
 
+/// Highest sampling rate accepted by `start`.
+///
+/// The profiler CPU's timer still bounds the rate, samples are never taken more often than it
+/// fires.
+pub const MAX_SAMPLE_HZ: u64 = 100_000;
+
+/// Nanoseconds between samples, or 0 to sample on every profiler timer tick.
+static SAMPLE_INTERVAL: AtomicU64 = AtomicU64::new(0);
+/// Monotonic time at which the next sample is due.
+static NEXT_SAMPLE: AtomicU64 = AtomicU64::new(0);
+
+/// Start sampling `hz` times a second.
+pub fn start(hz: u64) -> Result<()> {
+    if cfg!(not(feature = "profiling")) {
+        return Err(Error::new(ENODEV));
+    }
+    if hz == 0 || hz > MAX_SAMPLE_HZ {
+        return Err(Error::new(EINVAL));
+    }
+    SAMPLE_INTERVAL.store(1_000_000_000 / hz, Ordering::Relaxed);
+    NEXT_SAMPLE.store(0, Ordering::Relaxed);
+    info!("Enabling profiling at {} Hz", hz);
+    IS_PROFILING.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Start sampling at boot if the `profile_hz` boot parameter asks for it.
+pub fn start_from_params() {
+    let Some(hz) = crate::startup::params::param_u64("profile_hz") else {
+        return;
+    };
+    if let Err(err) = start(hz) {
+        warn!("Boot parameter profile_hz={} ignored: {:?}", hz, err);
+    }
+}
+
+/// Stop sampling.
+pub fn stop() {
+    info!("Disabling profiling");
+    IS_PROFILING.store(false, Ordering::SeqCst);
+}
+
+/// Whether the profiler timer should ask the other CPUs for a sample now.
+///
+/// Only called from the profiler CPU's timer interrupt, so the next deadline has a single writer.
+pub fn sample_due() -> bool {
+    if !IS_PROFILING.load(Ordering::Relaxed) {
+        return false;
+    }
+    let interval = SAMPLE_INTERVAL.load(Ordering::Relaxed);
+    if interval == 0 {
+        return true;
+    }
+    let now = crate::time::monotonic() as u64;
+    if now < NEXT_SAMPLE.load(Ordering::Relaxed) {
+        return false;
+    }
+    NEXT_SAMPLE.store(now + interval, Ordering::Relaxed);
+    true
+}
+
 /// Handles a serial command.
 ///
 /// This function is used to toggle profiling at runtime.
@@ -193,6 +332,15 @@ pub unsafe fn nmi_handler(stack: &InterruptStack) {
     buf[0] = stack.iret.rip & !(1 << 63);
     buf[1] = unsafe { x86::time::rdtsc() } as usize;
 
+    let percpu = crate::percpu::PercpuBlock::current();
+    profiling.samples.push(ProfileRecord {
+        kind: RECORD_SAMPLE,
+        cpu: percpu.cpu_id.get(),
+        time: buf[1] as u64,
+        ip: buf[0] as u64,
+        context: percpu.context_id.get() as u64,
+    });
+
     let mut bp = stack.preserved.rbp;
 
     let mut len = 2;
@@ -285,3 +433,44 @@ pub fn maybe_setup_timer(idt: &mut Idt, cpu_id: LogicalCpuId) {
     idt.entries[32].set_func(aux_timer);
     idt.set_reserved_mut(32, true);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(ip: u64) -> ProfileRecord {
+        ProfileRecord {
+            kind: RECORD_SAMPLE,
+            ip,
+            ..ProfileRecord::default()
+        }
+    }
+
+    #[test]
+    fn test_sample_ring_order() {
+        let ring = SampleRing::new();
+        ring.push(sample(1));
+        ring.push(sample(2));
+        unsafe {
+            assert_eq!(ring.pop(), Some(sample(1)));
+            assert_eq!(ring.pop(), Some(sample(2)));
+            assert_eq!(ring.pop(), None);
+        }
+        assert_eq!(ring.take_dropped(), 0);
+    }
+
+    #[test]
+    fn test_sample_ring_overrun() {
+        let ring = SampleRing::new();
+        for ip in 0..SAMPLE_CAPACITY as u64 + 3 {
+            ring.push(sample(ip));
+        }
+        assert_eq!(ring.take_dropped(), 3);
+        assert_eq!(ring.take_dropped(), 0);
+
+        // The oldest records are kept, and space freed by reading is reused
+        assert_eq!(unsafe { ring.pop() }, Some(sample(0)));
+        ring.push(sample(100));
+        assert_eq!(ring.take_dropped(), 0);
+    }
+}
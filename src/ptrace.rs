@@ -3,16 +3,20 @@
 //! of the scheme.
 
 use crate::{
+    context::{context::HardBlockedReason, Context, Status},
     event,
     percpu::PercpuBlock,
     ptrace_event,
     scheme::GlobalSchemes,
-    sync::{CleanLockToken, WaitCondition},
+    sync::{CleanLockToken, RwLock, WaitCondition, L1},
     syscall::{data::PtraceEvent, error::*, flag::*},
 };
 
 use crate::sync::Mutex;
-use alloc::{collections::VecDeque, sync::Arc};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+};
 use core::cmp;
 
 //  ____                _
@@ -84,6 +88,8 @@ pub struct Session {
     pub tracee: WaitCondition,
     /// The wait condition for the tracer.
     pub tracer: WaitCondition,
+    /// The process that attached the session.
+    pub tracer_pid: usize,
 }
 impl Session {
     /// Returns the current ptrace session.
@@ -95,7 +101,7 @@ impl Session {
             .upgrade()
     }
     /// Creates a new ptrace session.
-    pub fn new(file_id: usize) -> Arc<Session> {
+    pub fn new(file_id: usize, tracer_pid: usize) -> Arc<Session> {
         Arc::new(Session {
             data: Mutex::new(SessionData {
                 breakpoint: None,
@@ -104,10 +110,53 @@ impl Session {
             }),
             tracee: WaitCondition::new(),
             tracer: WaitCondition::new(),
+            tracer_pid,
         })
     }
 }
 
+/// The session attached to each tracee, by context ID
+static SESSIONS: RwLock<L1, BTreeMap<usize, Arc<Session>>> = RwLock::new(BTreeMap::new());
+
+/// Attach a new session to the context `tracee`, for the process `tracer_pid` tracing it through
+/// the proc: handle `file_id`. Fails with EBUSY if the context is traced already.
+pub fn attach(
+    tracee: usize,
+    tracer_pid: usize,
+    file_id: usize,
+    token: &mut CleanLockToken,
+) -> Result<Arc<Session>> {
+    let mut sessions = SESSIONS.write(token.token());
+    if sessions.contains_key(&tracee) {
+        return Err(Error::new(EBUSY));
+    }
+    let session = Session::new(file_id, tracer_pid);
+    sessions.insert(tracee, Arc::clone(&session));
+    Ok(session)
+}
+
+/// Detach `session` from the context `tracee` and close it
+pub fn detach(tracee: usize, session: &Arc<Session>, token: &mut CleanLockToken) {
+    {
+        let mut sessions = SESSIONS.write(token.token());
+        if sessions
+            .get(&tracee)
+            .is_some_and(|attached| Arc::ptr_eq(attached, session))
+        {
+            sessions.remove(&tracee);
+        }
+    }
+    close_session(session, token);
+}
+
+/// Returns true if the process `pid` has a session attached to the context `tracee`
+pub fn is_tracer(tracee: usize, pid: usize, token: &mut CleanLockToken) -> bool {
+    SESSIONS
+        .read(token.token())
+        .get(&tracee)
+        .is_some_and(|session| session.tracer_pid == pid)
+}
+
 /// Remove the session from the list of open sessions and notify any
 /// waiting processes
 pub fn close_session(session: &Session, token: &mut CleanLockToken) {
@@ -125,7 +174,7 @@ pub fn close_tracee(session: &Session, token: &mut CleanLockToken) {
     session.tracer.notify(token);
 
     let file_id = {
-        let data = session.data.lock();
+        let data = session.data.lock(token);
         data.file_id
     };
     proc_trigger_event(file_id, EVENT_READ, token);
@@ -141,7 +190,7 @@ fn proc_trigger_event(file_id: usize, flags: EventFlags, token: &mut CleanLockTo
 /// event was sent.
 pub fn send_event(event: PtraceEvent, token: &mut CleanLockToken) -> Option<()> {
     let session = Session::current()?;
-    let mut data = session.data.lock();
+    let mut data = session.data.lock(token);
     let breakpoint = data.breakpoint.as_ref()?;
 
     if event.cause & breakpoint.flags != event.cause {
@@ -170,6 +219,9 @@ pub fn send_event(event: PtraceEvent, token: &mut CleanLockToken) -> Option<()>
 // |____/|_|  \___|\__,_|_|\_\ .__/ \___/|_|_| |_|\__|___/
 //                           |_|
 
+/// The reason a tracee waiting in `breakpoint_callback` is blocked with
+const BREAKPOINT_REASON: &str = "ptrace::breakpoint_callback";
+
 #[derive(Debug, Clone, Copy)]
 pub(crate) struct Breakpoint {
     reached: bool,
@@ -185,7 +237,7 @@ pub fn wait(session: Arc<Session>, token: &mut CleanLockToken) -> Result<()> {
     loop {
         // Lock the data, to make sure we're reading the final value before going
         // to sleep.
-        let data = session.data.lock();
+        let data = session.data.lock(token);
 
         // Wake up if a breakpoint is already reached or there's an unread event
         if data.breakpoint.as_ref().map(|b| b.reached).unwrap_or(false) || !data.events.is_empty() {
@@ -194,7 +246,7 @@ pub fn wait(session: Arc<Session>, token: &mut CleanLockToken) -> Result<()> {
 
         // Go to sleep, and drop the lock on our data, which will allow other the
         // tracer to wake us up.
-        if session.tracer.wait(data, "ptrace::wait", token) {
+        if data.wait(&session.tracer, "ptrace::wait") {
             // We successfully waited, wake up!
             break;
         }
@@ -224,7 +276,7 @@ pub fn breakpoint_callback(
 
         let session = percpu.ptrace_session.borrow().as_ref()?.upgrade()?;
 
-        let mut data = session.data.lock();
+        let mut data = session.data.lock(token);
         let breakpoint = data.breakpoint?; // only go to sleep if there's a breakpoint
 
         // In case no tracer is waiting, make sure the next one gets the memo
@@ -238,21 +290,18 @@ pub fn breakpoint_callback(
         let file_id = data.file_id;
 
         // Wake up sleeping tracer
-        session.tracer.notify(token);
+        session.tracer.notify(data.token());
 
         if trigger {
             drop(data);
             proc_trigger_event(file_id, EVENT_READ, token);
-            data = session.data.lock();
+            data = session.data.lock(token);
         }
 
-        if session
-            .tracee
-            .wait(data, "ptrace::breakpoint_callback", token)
-        {
+        if data.wait(&session.tracee, BREAKPOINT_REASON) {
             // We successfully waited, wake up!
             // We need to re-check breakpoint because we might have dropped lock
-            let data = session.data.lock();
+            let data = session.data.lock(token);
             if let Some(bp) = data.breakpoint {
                 break Some(bp.flags);
             }
@@ -268,7 +317,7 @@ pub fn next_breakpoint(token: &mut CleanLockToken) -> Option<PtraceFlags> {
     //  TODO: Make this function arch-independent (probably requires moving
     //  some stuff from the syscall handler)
     let session = Session::current()?;
-    let data = session.data.lock();
+    let data = session.data.lock(token);
     let breakpoint = data.breakpoint?;
 
     Some(breakpoint.flags)
@@ -280,3 +329,26 @@ pub fn set_process_regs(
 ) -> Option<crate::arch::interrupt::InterruptStack> {
     None
 }
+
+//  ____            _     _
+// |  _ \ ___  __ _(_)___| |_ ___ _ __ ___
+// | |_) / _ \/ _` | / __| __/ _ \ '__/ __|
+// |  _ <  __/ (_| | \__ \ ||  __/ |  \__ \
+// |_| \_\___|\__, |_|___/\__\___|_|  |___/
+//            |___/
+
+/// Returns true if the tracee is in ptrace-stop, that is stopped by a signal or waiting at a
+/// breakpoint, and no longer running on any CPU. Only then do its saved registers hold the
+/// values it will resume with.
+pub fn is_stopped(context: &Context) -> bool {
+    if context.running {
+        return false;
+    }
+    match context.status {
+        Status::HardBlocked {
+            reason: HardBlockedReason::Stopped,
+        } => true,
+        Status::Blocked => context.status_reason == BREAKPOINT_REASON,
+        _ => false,
+    }
+}
@@ -6,6 +6,8 @@
 //!
 //! - **Fixed-priority preemptive scheduling** for real-time (RT) tasks
 //! - **Virtual Deadline (MuQSS-style)** for fair scheduling of non-RT tasks
+//! - **Wakeup boost** so that non-RT tasks woken after sleeping run soon, for interactivity
+//! - **Deadline class (SCHED_DEADLINE-style)** with runtime reservations per period
 //! - **Per-CPU run queues** with work stealing for cache locality
 //! - **Tickless operation** with dynamic timer programming
 //! - **Priority inheritance** support for avoiding priority inversion
@@ -13,22 +15,27 @@
 //!
 //! ## Design
 //!
-//! The scheduler uses separate queues for RT and non-RT tasks:
+//! The scheduler uses separate queues for deadline, RT and non-RT tasks:
+//! - Deadline tasks: Absolute deadline-ordered queue (EDF), preempt both other classes
 //! - RT tasks: Priority-ordered queue, always preempt non-RT
 //! - Non-RT tasks: Virtual deadline-ordered queue (MuQSS algorithm)
 //!
 //! Virtual deadlines are calculated as: `vd = vd + (time_slice / (weight + 1))`
 //! where weight is derived from priority (lower priority = higher weight).
 
-use alloc::collections::VecDeque;
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+};
 use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 
 use crate::{
     context::{ContextRef, Status},
-    cpu_set::LogicalCpuId,
-    ipi::{ipi, IpiKind, IpiTarget},
-    percpu::PercpuBlock,
-    sync::{CleanLockToken, Priority},
+    cpu_set::{LogicalCpuId, LogicalCpuSet, MAX_CPU_COUNT},
+    ipi::{ipi, ipi_single, IpiKind, IpiTarget},
+    percpu::{self, PercpuBlock},
+    sync::{CleanLockToken, IrqMutex, IrqMutexGuard, Priority},
+    syscall::error::{Error, Result, EBUSY, EINVAL},
     time::monotonic,
 };
 
@@ -62,6 +69,19 @@ const IMBALANCE_PCT: usize = 25;
 /// Tasks exceeding this are considered latency violations.
 pub const LATE_DEADLINE_NS: u64 = 100_000; // 100µs
 
+/// Time a non-RT task must have been blocked for to get a wakeup boost.
+const WAKEUP_BOOST_SLEEP_NS: u64 = 5_000_000; // 5ms
+
+/// How far past the earliest queued virtual deadline a boosted task is placed.
+const WAKEUP_BOOST_OFFSET_NS: u64 = MIN_TIME_SLICE_NS;
+
+/// Whether tasks woken after sleeping are boosted, cleared by the `nowakeboost` boot parameter
+static WAKEUP_BOOST: AtomicBool = AtomicBool::new(true);
+
+/// Range of nice values of non-RT tasks, which offset their base priority from `Priority::Normal`
+pub const NICE_MIN: i32 = -20;
+pub const NICE_MAX: i32 = 19;
+
 /// Scheduling policies
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u8)]
@@ -78,10 +98,31 @@ pub enum SchedPolicy {
     Idle = 5,
     /// Interactive (gaming/workstation, lowest latency)
     Interactive = 6,
-    /// Deadline scheduling (for future EDF implementation)
+    /// Deadline scheduling (EDF with a runtime budget per period)
     Deadline = 7,
 }
 
+impl SchedPolicy {
+    /// Decode a policy number as passed by userspace
+    pub fn from_raw(raw: usize) -> Option<Self> {
+        Some(match raw {
+            0 => SchedPolicy::Normal,
+            1 => SchedPolicy::Fifo,
+            2 => SchedPolicy::RoundRobin,
+            3 => SchedPolicy::Batch,
+            5 => SchedPolicy::Idle,
+            6 => SchedPolicy::Interactive,
+            7 => SchedPolicy::Deadline,
+            _ => return None,
+        })
+    }
+
+    /// Returns true if contexts with this policy belong in the RT queue
+    pub fn is_realtime(self) -> bool {
+        matches!(self, SchedPolicy::Fifo | SchedPolicy::RoundRobin)
+    }
+}
+
 // =============================================================================
 // Scheduler Statistics
 // =============================================================================
@@ -105,6 +146,8 @@ pub struct SchedulerStats {
     pub migrations: AtomicU64,
     /// Number of preemptions
     pub preemptions: AtomicU64,
+    /// Number of wakeups boosted for interactivity
+    pub interactive_boosts: AtomicU64,
 }
 
 impl SchedulerStats {
@@ -118,6 +161,7 @@ impl SchedulerStats {
             balance_ops: AtomicU64::new(0),
             migrations: AtomicU64::new(0),
             preemptions: AtomicU64::new(0),
+            interactive_boosts: AtomicU64::new(0),
         }
     }
 
@@ -192,23 +236,333 @@ impl RunQueueEntry {
     }
 }
 
+// =============================================================================
+// Deadline Class
+// =============================================================================
+
+/// Default cap on the utilization of all deadline contexts, in percent of every CPU
+pub const DEFAULT_DEADLINE_CAP_PCT: u32 = 75;
+
+/// Utilization is counted in millionths of one CPU
+const UTILIZATION_SCALE: u64 = 1_000_000;
+
+/// Cap on the utilization of all deadline contexts, in percent of every CPU
+static DEADLINE_CAP_PCT: AtomicU32 = AtomicU32::new(DEFAULT_DEADLINE_CAP_PCT);
+
+/// Utilization reserved by the admitted deadline contexts
+static DEADLINE_UTILIZATION: AtomicU64 = AtomicU64::new(0);
+
+/// Parameters of a deadline context, as passed to `sched_setscheduler`, in nanoseconds
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct DeadlineParams {
+    /// Time the context may run every period
+    pub runtime: u64,
+    /// Time from the start of a period by which the runtime must have been given
+    pub deadline: u64,
+    pub period: u64,
+}
+
+impl DeadlineParams {
+    /// Whether `0 < runtime <= deadline <= period`
+    pub fn is_valid(&self) -> bool {
+        self.runtime > 0 && self.runtime <= self.deadline && self.deadline <= self.period
+    }
+
+    /// Share of a CPU reserved, rounded up
+    fn utilization(&self) -> u64 {
+        (u128::from(self.runtime) * u128::from(UTILIZATION_SCALE))
+            .div_ceil(u128::from(self.period.max(1))) as u64
+    }
+}
+
+/// Budget of a deadline context in its current period
+///
+/// This follows the constant bandwidth server: a context may run for `runtime` before its
+/// absolute deadline, after which it is throttled until the next period starts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeadlineState {
+    pub params: DeadlineParams,
+    /// Runtime left in this period
+    pub budget: u64,
+    /// Absolute deadline of this period
+    pub deadline: u64,
+    pub period_start: u64,
+    /// Out of budget, and not queued until the next period
+    pub throttled: bool,
+}
+
+impl DeadlineState {
+    pub fn new(params: DeadlineParams, now: u64) -> Self {
+        let mut state = DeadlineState {
+            params,
+            budget: 0,
+            deadline: 0,
+            period_start: 0,
+            throttled: false,
+        };
+        state.start_period(now);
+        state
+    }
+
+    fn start_period(&mut self, start: u64) {
+        self.period_start = start;
+        self.budget = self.params.runtime;
+        self.deadline = start.saturating_add(self.params.deadline);
+        self.throttled = false;
+    }
+
+    /// When the budget is replenished next
+    pub fn next_period(&self) -> u64 {
+        self.period_start.saturating_add(self.params.period)
+    }
+
+    /// Debit `spent` from the budget, throttling the context once it is used up
+    fn charge(&mut self, spent: u64) {
+        self.budget = self.budget.saturating_sub(spent);
+        if self.budget == 0 {
+            self.throttled = true;
+        }
+    }
+
+    /// Bring the budget up to date before the context is queued
+    ///
+    /// A throttled context gets its runtime back once its period is over. A new period also
+    /// starts right away if the deadline has passed, or if running the rest of the budget before
+    /// the deadline would take more than the reserved share of the CPU.
+    fn refresh(&mut self, now: u64) {
+        if self.throttled && now >= self.next_period() {
+            self.start_period(now);
+        }
+        if !self.throttled {
+            let left = u128::from(self.deadline.saturating_sub(now));
+            let overrun = u128::from(self.budget) * u128::from(self.params.deadline)
+                > left * u128::from(self.params.runtime);
+            if self.deadline <= now || overrun {
+                self.start_period(now);
+            }
+        }
+    }
+}
+
+/// New total utilization after replacing a reservation of `old` with `new`, if it stays within
+/// `capacity`
+fn admit(total: u64, old: u64, new: u64, capacity: u64) -> Option<u64> {
+    let total = total.saturating_sub(old).checked_add(new)?;
+    (total <= capacity).then_some(total)
+}
+
+/// Reserve the utilization of `new` for a context, in place of `old` if it was already a
+/// deadline context
+///
+/// Fails with `EBUSY` if all deadline contexts together would use more than the cap.
+pub fn admit_deadline(old: Option<&DeadlineParams>, new: &DeadlineParams) -> Result<()> {
+    let old = old.map_or(0, DeadlineParams::utilization);
+    let new = new.utilization();
+    let capacity = u64::from(DEADLINE_CAP_PCT.load(Ordering::Relaxed))
+        * (UTILIZATION_SCALE / 100)
+        * u64::from(crate::cpu_count());
+    DEADLINE_UTILIZATION
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |total| {
+            admit(total, old, new, capacity)
+        })
+        .map(|_| ())
+        .map_err(|_| Error::new(EBUSY))
+}
+
+/// Give back the utilization reserved for a deadline context
+pub fn release_deadline(params: &DeadlineParams) {
+    let utilization = params.utilization();
+    let _ = DEADLINE_UTILIZATION.fetch_update(Ordering::AcqRel, Ordering::Acquire, |total| {
+        Some(total.saturating_sub(utilization))
+    });
+}
+
+/// Set the deadline utilization cap from a percentage written to sys:deadline_cap
+///
+/// Contexts that were already admitted keep their reservation.
+pub fn sys_set_deadline_cap(buf: &[u8], _token: &mut CleanLockToken) -> Result<usize> {
+    let percent = core::str::from_utf8(buf.trim_ascii())
+        .ok()
+        .and_then(|text| text.parse::<u32>().ok())
+        .filter(|percent| (1..=100).contains(percent))
+        .ok_or(Error::new(EINVAL))?;
+    DEADLINE_CAP_PCT.store(percent, Ordering::Relaxed);
+    Ok(buf.len())
+}
+
+// =============================================================================
+// Queue Structures
+// =============================================================================
+
+/// RT entries in FIFO buckets per priority, lower values first
+///
+/// A bitmap of the non-empty buckets finds the highest priority in constant time, so adding and
+/// popping no longer scan the whole queue. Removal by ID only scans its own bucket.
+pub struct RtQueue<T> {
+    buckets: [VecDeque<(usize, T)>; RT_PRIORITY_LEVELS],
+    /// Bit `n` is set if bucket `n` is non-empty
+    nonempty: u128,
+    /// Bucket of every queued ID
+    priorities: BTreeMap<usize, u8>,
+}
+
+const _: () = assert!(RT_PRIORITY_LEVELS <= u128::BITS as usize);
+
+impl<T> RtQueue<T> {
+    pub const fn new() -> Self {
+        RtQueue {
+            buckets: [const { VecDeque::new() }; RT_PRIORITY_LEVELS],
+            nonempty: 0,
+            priorities: BTreeMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.priorities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nonempty == 0
+    }
+
+    /// Queue `value` behind the entries of the same priority, returning true if it is now first
+    ///
+    /// Priorities past the last RT level share the last bucket.
+    pub fn insert(&mut self, id: usize, priority: u8, value: T) -> bool {
+        let level = usize::from(priority).min(RT_PRIORITY_LEVELS - 1);
+        let first = self.first_level().is_none_or(|first| level < first);
+        self.buckets[level].push_back((id, value));
+        self.nonempty |= 1u128 << level;
+        self.priorities.insert(id, level as u8);
+        first
+    }
+
+    fn first_level(&self) -> Option<usize> {
+        (self.nonempty != 0).then(|| self.nonempty.trailing_zeros() as usize)
+    }
+
+    /// The entry that runs next
+    pub fn first(&self) -> Option<&T> {
+        let (_, value) = self.buckets[self.first_level()?].front()?;
+        Some(value)
+    }
+
+    pub fn pop_first(&mut self) -> Option<T> {
+        let level = self.first_level()?;
+        let (id, value) = self.buckets[level].pop_front()?;
+        self.priorities.remove(&id);
+        self.update_level(level);
+        Some(value)
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<T> {
+        let level = usize::from(self.priorities.remove(&id)?);
+        let bucket = &mut self.buckets[level];
+        let pos = bucket.iter().position(|(entry, _)| *entry == id)?;
+        let (_, value) = bucket.remove(pos)?;
+        self.update_level(level);
+        Some(value)
+    }
+
+    fn update_level(&mut self, level: usize) {
+        if self.buckets[level].is_empty() {
+            self.nonempty &= !(1u128 << level);
+        }
+    }
+}
+
+/// Entries ordered by virtual or absolute deadline, earliest first
+///
+/// Adding, popping either end and removal by ID all take O(log n). Entries with equal deadlines
+/// run in the order they were added.
+pub struct DeadlineQueue<T> {
+    entries: BTreeMap<(u64, u64), (usize, T)>,
+    /// Key in `entries` of every queued ID
+    keys: BTreeMap<usize, (u64, u64)>,
+    /// Breaks ties between equal deadlines
+    next_seq: u64,
+}
+
+impl<T> DeadlineQueue<T> {
+    pub const fn new() -> Self {
+        DeadlineQueue {
+            entries: BTreeMap::new(),
+            keys: BTreeMap::new(),
+            next_seq: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Queue `value` by `vdeadline`, returning true if it is now first
+    pub fn insert(&mut self, id: usize, vdeadline: u64, value: T) -> bool {
+        let first = self
+            .entries
+            .first_key_value()
+            .is_none_or(|(&(first, _), _)| vdeadline < first);
+        let key = (vdeadline, self.next_seq);
+        self.next_seq += 1;
+        self.entries.insert(key, (id, value));
+        self.keys.insert(id, key);
+        first
+    }
+
+    /// The entry with the earliest deadline, and that deadline
+    pub fn first(&self) -> Option<(u64, &T)> {
+        let (&(vdeadline, _), (_, value)) = self.entries.first_key_value()?;
+        Some((vdeadline, value))
+    }
+
+    pub fn pop_first(&mut self) -> Option<T> {
+        let (_, (id, value)) = self.entries.pop_first()?;
+        self.keys.remove(&id);
+        Some(value)
+    }
+
+    /// Pop the entry with the latest deadline, which has the least to lose from moving
+    pub fn pop_last(&mut self) -> Option<T> {
+        let (_, (id, value)) = self.entries.pop_last()?;
+        self.keys.remove(&id);
+        Some(value)
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<T> {
+        let key = self.keys.remove(&id)?;
+        let (_, value) = self.entries.remove(&key)?;
+        Some(value)
+    }
+}
+
 // =============================================================================
 // Run Queue
 // =============================================================================
 
 /// A single run queue for a CPU.
 ///
-/// Uses separate queues for RT and non-RT tasks for predictable scheduling.
+/// Uses separate queues for deadline, RT and non-RT tasks for predictable scheduling.
 pub struct RunQueue {
+    /// Deadline tasks, ordered by absolute deadline.
+    pub dl_queue: DeadlineQueue<RunQueueEntry>,
+
+    /// Deadline tasks out of budget, ordered by the start of their next period.
+    /// These are not runnable and not counted in `task_count`.
+    throttled: DeadlineQueue<ContextRef>,
+
     /// Real-time tasks, ordered by priority (lower value = higher priority).
-    /// Uses a simple deque since RT task count is typically small.
-    pub rt_queue: VecDeque<RunQueueEntry>,
+    pub rt_queue: RtQueue<RunQueueEntry>,
 
     /// Non-real-time tasks, ordered by virtual deadline.
     /// This implements the MuQSS virtual deadline algorithm.
-    pub non_rt_queue: VecDeque<RunQueueEntry>,
+    pub non_rt_queue: DeadlineQueue<RunQueueEntry>,
 
-    /// Total number of tasks in both queues
+    /// Total number of tasks in the runnable queues
     task_count: AtomicUsize,
 
     /// Total load weight (for balancing)
@@ -221,8 +575,10 @@ pub struct RunQueue {
 impl RunQueue {
     pub const fn new() -> Self {
         RunQueue {
-            rt_queue: VecDeque::new(),
-            non_rt_queue: VecDeque::new(),
+            dl_queue: DeadlineQueue::new(),
+            throttled: DeadlineQueue::new(),
+            rt_queue: RtQueue::new(),
+            non_rt_queue: DeadlineQueue::new(),
             task_count: AtomicUsize::new(0),
             load_weight: AtomicU64::new(0),
             needs_preempt: AtomicBool::new(false),
@@ -231,47 +587,47 @@ impl RunQueue {
 
     /// Adds a context to the appropriate run queue.
     ///
+    /// Deadline tasks are inserted sorted by absolute deadline, or set aside until their next
+    /// period if they are out of budget.
     /// RT tasks are inserted sorted by priority.
     /// Non-RT tasks are inserted sorted by virtual deadline (earliest first).
     pub fn add(&mut self, context_ref: ContextRef, token: &mut CleanLockToken) {
-        let (is_realtime, id, vdeadline, priority) = {
-            let context = context_ref.read(token.token());
+        let (is_realtime, id, vdeadline, priority, deadline) = {
+            let mut context = context_ref.write(token.token());
+            let deadline = context.sched_deadline.as_mut().map(|state| {
+                state.refresh(monotonic() as u64);
+                *state
+            });
             (
                 context.is_realtime,
                 context.id(),
                 context.virtual_deadline,
                 context.priority.effective_priority(),
+                deadline,
             )
         };
 
-        let entry = RunQueueEntry::new(id, context_ref, vdeadline, priority);
+        // A context is only ever queued once
+        self.remove(id);
 
-        if is_realtime {
-            // Insert RT task sorted by priority (lower value = higher priority)
-            let insert_pos = self
-                .rt_queue
-                .iter()
-                .position(|e| priority < e.priority)
-                .unwrap_or(self.rt_queue.len());
-            self.rt_queue.insert(insert_pos, entry);
+        if let Some(state) = deadline
+            && state.throttled
+        {
+            self.throttled.insert(id, state.next_period(), context_ref);
+            return;
+        }
+        let entry = RunQueueEntry::new(id, context_ref, vdeadline, priority);
 
-            // Mark preemption needed if this is highest priority
-            if insert_pos == 0 {
-                self.needs_preempt.store(true, Ordering::Release);
-            }
+        let first = if let Some(state) = deadline {
+            self.dl_queue.insert(id, state.deadline, entry)
+        } else if is_realtime {
+            self.rt_queue.insert(id, priority, entry)
         } else {
-            // Insert non-RT task sorted by virtual deadline (earliest first)
-            let insert_pos = self
-                .non_rt_queue
-                .iter()
-                .position(|e| vdeadline < e.vdeadline)
-                .unwrap_or(self.non_rt_queue.len());
-            self.non_rt_queue.insert(insert_pos, entry);
-
-            // Mark preemption needed if this task has the earliest deadline
-            if insert_pos == 0 {
-                self.needs_preempt.store(true, Ordering::Release);
-            }
+            self.non_rt_queue.insert(id, vdeadline, entry)
+        };
+        // Mark preemption needed if this task now runs next in its class
+        if first {
+            self.needs_preempt.store(true, Ordering::Release);
         }
 
         // Update counts and load
@@ -282,12 +638,21 @@ impl RunQueue {
 
     /// Removes and returns the next context to run.
     ///
-    /// RT tasks always have priority over non-RT tasks.
+    /// Deadline tasks always have priority over RT tasks, which have priority over non-RT tasks.
     pub fn next(&mut self) -> Option<ContextRef> {
         self.needs_preempt.store(false, Ordering::Relaxed);
 
-        // RT tasks first
-        if let Some(mut entry) = self.rt_queue.pop_front() {
+        // Deadline tasks first (earliest absolute deadline first)
+        if let Some(mut entry) = self.dl_queue.pop_first() {
+            self.task_count.fetch_sub(1, Ordering::Relaxed);
+            let weight = Self::priority_to_weight(entry.priority);
+            self.load_weight.fetch_sub(weight, Ordering::Relaxed);
+            entry.run_count += 1;
+            return Some(entry.context);
+        }
+
+        // Then RT tasks
+        if let Some(mut entry) = self.rt_queue.pop_first() {
             self.task_count.fetch_sub(1, Ordering::Relaxed);
             let weight = Self::priority_to_weight(entry.priority);
             self.load_weight.fetch_sub(weight, Ordering::Relaxed);
@@ -296,7 +661,7 @@ impl RunQueue {
         }
 
         // Then non-RT tasks (earliest virtual deadline first)
-        if let Some(mut entry) = self.non_rt_queue.pop_front() {
+        if let Some(mut entry) = self.non_rt_queue.pop_first() {
             self.task_count.fetch_sub(1, Ordering::Relaxed);
             let weight = Self::priority_to_weight(entry.priority);
             self.load_weight.fetch_sub(weight, Ordering::Relaxed);
@@ -309,16 +674,18 @@ impl RunQueue {
 
     /// Peek at the next context without removing it
     pub fn peek(&self) -> Option<&ContextRef> {
-        if let Some(entry) = self.rt_queue.front() {
+        if let Some((_, entry)) = self.dl_queue.first() {
+            Some(&entry.context)
+        } else if let Some(entry) = self.rt_queue.first() {
             Some(&entry.context)
         } else {
-            self.non_rt_queue.front().map(|e| &e.context)
+            self.non_rt_queue.first().map(|(_, e)| &e.context)
         }
     }
 
     /// Check if there's a higher priority RT task waiting
     pub fn has_higher_priority(&self, current_priority: u8) -> bool {
-        if let Some(front) = self.rt_queue.front() {
+        if let Some(front) = self.rt_queue.first() {
             front.priority < current_priority
         } else {
             false
@@ -327,25 +694,36 @@ impl RunQueue {
 
     /// Removes a specific context from the run queue.
     pub fn remove(&mut self, context_id: usize) -> Option<ContextRef> {
-        // Check RT queue first
-        if let Some(pos) = self.rt_queue.iter().position(|e| e.id == context_id) {
-            let entry = self.rt_queue.remove(pos)?;
-            self.task_count.fetch_sub(1, Ordering::Relaxed);
-            let weight = Self::priority_to_weight(entry.priority);
-            self.load_weight.fetch_sub(weight, Ordering::Relaxed);
-            return Some(entry.context);
+        if let Some(context_ref) = self.throttled.remove(context_id) {
+            return Some(context_ref);
         }
+        let entry = self
+            .dl_queue
+            .remove(context_id)
+            .or_else(|| self.rt_queue.remove(context_id))
+            .or_else(|| self.non_rt_queue.remove(context_id))?;
+        self.task_count.fetch_sub(1, Ordering::Relaxed);
+        let weight = Self::priority_to_weight(entry.priority);
+        self.load_weight.fetch_sub(weight, Ordering::Relaxed);
+        Some(entry.context)
+    }
 
-        // Then non-RT queue
-        if let Some(pos) = self.non_rt_queue.iter().position(|e| e.id == context_id) {
-            let entry = self.non_rt_queue.remove(pos)?;
-            self.task_count.fetch_sub(1, Ordering::Relaxed);
-            let weight = Self::priority_to_weight(entry.priority);
-            self.load_weight.fetch_sub(weight, Ordering::Relaxed);
-            return Some(entry.context);
+    /// Queue the throttled deadline tasks whose next period has started
+    pub fn replenish(&mut self, now: u64, token: &mut CleanLockToken) {
+        while self
+            .throttled
+            .first()
+            .is_some_and(|(start, _)| start <= now)
+        {
+            if let Some(context_ref) = self.throttled.pop_first() {
+                self.add(context_ref, token);
+            }
         }
+    }
 
-        None
+    /// Start of the earliest period a throttled deadline task is waiting for
+    pub fn next_replenish(&self) -> Option<u64> {
+        self.throttled.first().map(|(start, _)| start)
     }
 
     /// Check if the queue is empty
@@ -372,7 +750,7 @@ impl RunQueue {
     pub fn steal(&mut self) -> Option<RunQueueEntry> {
         // Only steal from the back of non-RT queue to minimize disruption
         if self.non_rt_queue.len() > 1 {
-            let entry = self.non_rt_queue.pop_back()?;
+            let entry = self.non_rt_queue.pop_last()?;
             self.task_count.fetch_sub(1, Ordering::Relaxed);
             let weight = Self::priority_to_weight(entry.priority);
             self.load_weight.fetch_sub(weight, Ordering::Relaxed);
@@ -403,12 +781,12 @@ impl RunQueue {
 // =============================================================================
 
 /// Per-CPU scheduler state implementing MuQSS-style virtual deadline scheduling.
+///
+/// The run queue and the running context are used by both threads and interrupt handlers of
+/// their CPU, so they are only reached through [`Scheduler::lock`], which keeps interrupts
+/// disabled while they are. The rest are atomics that can be read from anywhere.
 pub struct Scheduler {
-    /// The run queue for this CPU
-    pub run_queue: RunQueue,
-
-    /// The currently running context
-    pub current_context: Option<ContextRef>,
+    state: IrqMutex<SchedulerState>,
 
     /// Virtual deadline of current context (for quick comparison)
     pub current_virtual_deadline: AtomicU64,
@@ -416,6 +794,9 @@ pub struct Scheduler {
     /// Current context priority (for preemption checks)
     pub current_priority: AtomicU32,
 
+    /// Absolute deadline of current context, `u64::MAX` unless it is a deadline task
+    pub current_deadline: AtomicU64,
+
     /// Time of last load balance check
     pub last_balance_time: AtomicU64,
 
@@ -427,43 +808,86 @@ pub struct Scheduler {
 
     /// Next timer event (for tickless)
     pub next_timer_event: AtomicU64,
+
+    /// Contexts added by other CPUs, moved into the run queue on the next `schedule`
+    pub incoming: IrqMutex<VecDeque<ContextRef>>,
+}
+
+/// The part of a [`Scheduler`] behind its lock
+pub struct SchedulerState {
+    /// The run queue for this CPU
+    pub run_queue: RunQueue,
+
+    /// The currently running context
+    pub current_context: Option<ContextRef>,
 }
 
 impl Scheduler {
     pub const fn new() -> Self {
         Scheduler {
-            run_queue: RunQueue::new(),
-            current_context: None,
+            state: IrqMutex::new(SchedulerState {
+                run_queue: RunQueue::new(),
+                current_context: None,
+            }),
             current_virtual_deadline: AtomicU64::new(0),
             current_priority: AtomicU32::new(Priority::Low as u32),
+            current_deadline: AtomicU64::new(u64::MAX),
             last_balance_time: AtomicU64::new(0),
             stats: SchedulerStats::new(),
             tickless: AtomicBool::new(true),
             next_timer_event: AtomicU64::new(0),
+            incoming: IrqMutex::new(VecDeque::new()),
         }
     }
 
+    /// Lock the run queue and running context, disabling interrupts until the guard is dropped
+    pub fn lock(&self) -> IrqMutexGuard<'_, SchedulerState> {
+        self.state.lock()
+    }
+
+    /// Lock the run queue and running context from an interrupt handler
+    pub fn lock_irq(&self) -> IrqMutexGuard<'_, SchedulerState> {
+        self.state.lock_irq()
+    }
+
     /// Selects and returns the next context to run.
     ///
     /// This is the core scheduling function implementing MuQSS virtual deadline.
-    pub fn schedule(&mut self, token: &mut CleanLockToken) -> Option<ContextRef> {
+    pub fn schedule(&self, token: &mut CleanLockToken) -> Option<ContextRef> {
         #[cfg(target_arch = "x86_64")]
         let start_tsc = unsafe { core::arch::x86_64::_rdtsc() };
 
+        // Take over contexts other CPUs added or woke for us, before locking the run queue as
+        // woken ones may be boosted
+        let incoming = core::mem::take(&mut *self.incoming.lock());
+        for context_ref in incoming {
+            self.context_unblocked(context_ref, token);
+        }
+
+        let mut guard = self.lock();
+        let state = &mut *guard;
+
         // Handle the currently running context
-        if let Some(current_ctx_ref) = &self.current_context {
-            self.handle_current_context(current_ctx_ref, token);
+        if let Some(current_ctx_ref) = &state.current_context {
+            self.handle_current_context(&mut state.run_queue, current_ctx_ref, token);
         }
 
+        // Give throttled deadline tasks their budget back once their period starts
+        state.run_queue.replenish(monotonic() as u64, token);
+
         // Select next context
-        let next_context = self.run_queue.next();
+        let next_context = state.run_queue.next();
 
         // Set up the next context
         if let Some(next_ctx_ref) = &next_context {
             self.setup_next_context(next_ctx_ref, token);
+        } else {
+            self.current_deadline.store(u64::MAX, Ordering::Relaxed);
         }
 
-        self.current_context = next_context.clone();
+        let previous = core::mem::replace(&mut state.current_context, next_context.clone());
+        drop(guard);
+        drop(previous);
 
         // Record stats
         #[cfg(target_arch = "x86_64")]
@@ -488,7 +912,12 @@ impl Scheduler {
     }
 
     /// Handle the currently running context before switching
-    fn handle_current_context(&mut self, current_ctx_ref: &ContextRef, token: &mut CleanLockToken) {
+    fn handle_current_context(
+        &self,
+        run_queue: &mut RunQueue,
+        current_ctx_ref: &ContextRef,
+        token: &mut CleanLockToken,
+    ) {
         let mut current_ctx = current_ctx_ref.write(token.token());
         let now = monotonic();
         let time_spent = now.saturating_sub(current_ctx.switch_time);
@@ -499,8 +928,13 @@ impl Scheduler {
         // Update CPU time accounting
         current_ctx.cpu_time = current_ctx.cpu_time.saturating_add(time_spent);
 
+        // Debit the budget of deadline tasks, `add` throttles them once it is used up
+        if let Some(state) = &mut current_ctx.sched_deadline {
+            state.charge(time_spent as u64);
+        }
+
         // Update virtual deadline for non-RT tasks
-        if !current_ctx.is_realtime {
+        if !current_ctx.is_realtime && current_ctx.sched_deadline.is_none() {
             // MuQSS-style virtual deadline calculation:
             // vd = vd + (time_spent * BASE_TIME_SLICE) / (priority_weight + 1)
             let priority_factor = current_ctx.priority.effective_priority() as u64 + 1;
@@ -518,19 +952,24 @@ impl Scheduler {
         // Check priority boost expiration
         current_ctx.priority.check_boost_expired();
 
-        // Re-add to run queue if still runnable
+        // Re-add to run queue if still runnable, on another CPU if its affinity changed
         if current_ctx.status.is_runnable() {
+            let cpu = crate::cpu_id();
+            let target = target_cpu(&current_ctx.sched_affinity, Some(cpu), cpu);
             drop(current_ctx); // Drop lock before adding to queue
-            self.run_queue.add(current_ctx_ref.clone(), token);
+            if let Some(context_ref) = send_to(target, current_ctx_ref.clone()) {
+                run_queue.add(context_ref, token);
+            }
         }
     }
 
     /// Set up the next context to run
-    fn setup_next_context(&mut self, next_ctx_ref: &ContextRef, token: &mut CleanLockToken) {
+    fn setup_next_context(&self, next_ctx_ref: &ContextRef, token: &mut CleanLockToken) {
         let mut next_ctx = next_ctx_ref.write(token.token());
 
         // Record switch time
         next_ctx.switch_time = monotonic();
+        next_ctx.switch_count = next_ctx.switch_count.wrapping_add(1);
 
         // Update scheduler state
         let priority = next_ctx.priority.effective_priority();
@@ -541,10 +980,21 @@ impl Scheduler {
             self.current_virtual_deadline
                 .store(next_ctx.virtual_deadline, Ordering::Relaxed);
         }
-
-        // Set up tickless timer for time slice
-        let time_slice = if next_ctx.is_realtime {
+        self.current_deadline.store(
+            next_ctx
+                .sched_deadline
+                .map_or(u64::MAX, |state| state.deadline),
+            Ordering::Relaxed,
+        );
+
+        // Set up tickless timer for time slice, deadline tasks run until their budget is used up
+        let time_slice = if let Some(state) = next_ctx.sched_deadline {
+            state.budget
+        } else if next_ctx.is_realtime {
             RT_TIME_SLICE_NS
+        } else if core::mem::take(&mut next_ctx.wakeup_boost) {
+            // Boosted tasks run soon, but only briefly so that the boost stays fair
+            MIN_TIME_SLICE_NS
         } else {
             Self::calculate_time_slice(priority)
         };
@@ -570,31 +1020,87 @@ impl Scheduler {
     }
 
     /// Called when a context is blocked
-    pub fn context_blocked(&mut self, context_id: usize) {
-        self.run_queue.remove(context_id);
+    pub fn context_blocked(&self, context_id: usize) {
+        // Dropped once unlocked, in case it is the last reference to the context
+        let removed = self.lock().run_queue.remove(context_id);
+        drop(removed);
     }
 
-    /// Called when a context becomes runnable
-    pub fn context_unblocked(&mut self, context_ref: ContextRef, token: &mut CleanLockToken) {
-        self.run_queue.add(context_ref, token);
+    /// Called when a context becomes runnable, see [`wake`]
+    pub fn context_unblocked(&self, context_ref: ContextRef, token: &mut CleanLockToken) {
+        // The context is locked to be boosted, which must not happen under the run queue lock
+        let min_deadline = self.min_virtual_deadline(&self.lock().run_queue);
+        self.boost_wakeup(min_deadline, &context_ref, token);
+        self.lock().run_queue.add(context_ref, token);
     }
 
-    /// Check if preemption of current context is needed
-    pub fn should_preempt(&self, token: &mut CleanLockToken) -> bool {
+    /// Let a non-RT task that slept for at least [`WAKEUP_BOOST_SLEEP_NS`] run soon, so that
+    /// interactive tasks do not wait behind CPU hogs with the deadline they blocked with
+    ///
+    /// The time it blocked at is consumed either way, so that a later handover of the context to
+    /// another CPU is not mistaken for a wakeup.
+    fn boost_wakeup(
+        &self,
+        min_deadline: u64,
+        context_ref: &ContextRef,
+        token: &mut CleanLockToken,
+    ) {
+        let now = monotonic();
+        let mut context = context_ref.write(token.token());
+        let Some(blocked_at) = context.last_blocked_at.take() else {
+            return;
+        };
+        if !WAKEUP_BOOST.load(Ordering::Relaxed)
+            || context.is_realtime
+            || context.sched_deadline.is_some()
+            || now.saturating_sub(blocked_at) < u128::from(WAKEUP_BOOST_SLEEP_NS)
+        {
+            return;
+        }
+        context.virtual_deadline = boosted_deadline(context.virtual_deadline, min_deadline);
+        context.wakeup_boost = true;
+        self.stats
+            .interactive_boosts
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The earliest virtual deadline of the running and queued non-RT tasks
+    fn min_virtual_deadline(&self, run_queue: &RunQueue) -> u64 {
+        let current = self.current_virtual_deadline.load(Ordering::Relaxed);
+        run_queue
+            .non_rt_queue
+            .first()
+            .map_or(current, |(vdeadline, _)| vdeadline.min(current))
+    }
+
+    /// Check if preemption of current context is needed, by a context in `run_queue`
+    pub fn should_preempt(&self, run_queue: &RunQueue, token: &mut CleanLockToken) -> bool {
         let current_priority = self.current_priority.load(Ordering::Relaxed) as u8;
+        let current_deadline = self.current_deadline.load(Ordering::Relaxed);
+
+        // Deadline tasks preempt the other classes, and each other by earliest deadline
+        if let Some((deadline, _)) = run_queue.dl_queue.first() {
+            if deadline < current_deadline {
+                return true;
+            }
+        }
+        // Nothing else preempts a deadline task
+        if current_deadline != u64::MAX {
+            return false;
+        }
 
         // Always preempt for higher priority RT task
-        if self.run_queue.has_higher_priority(current_priority) {
+        if run_queue.has_higher_priority(current_priority) {
             return true;
         }
 
         // Check if run queue flagged preemption
         // Check if run queue flagged preemption
-        if self.run_queue.check_preempt() {
+        if run_queue.check_preempt() {
             // For non-RT, only preempt if the waiting task has an earlier deadline
-            if let Some(front) = self.run_queue.non_rt_queue.front() {
+            if let Some((vdeadline, _)) = run_queue.non_rt_queue.first() {
                 let current_deadline = self.current_virtual_deadline.load(Ordering::Relaxed);
-                if front.vdeadline < current_deadline {
+                if vdeadline < current_deadline {
                     return true;
                 }
                 // If RT queue has task, has_higher_priority handled it.
@@ -609,7 +1115,7 @@ impl Scheduler {
     }
 
     /// Attempt load balancing with other CPUs
-    pub fn try_balance(&mut self, token: &mut CleanLockToken) {
+    pub fn try_balance(&self, token: &mut CleanLockToken) {
         let now = monotonic() as u64;
         let last = self.last_balance_time.load(Ordering::Relaxed);
 
@@ -620,8 +1126,10 @@ impl Scheduler {
         self.last_balance_time.store(now, Ordering::Relaxed);
 
         // Get our load
-        let my_load = self.run_queue.load();
-        let my_count = self.run_queue.len();
+        let (my_load, my_count) = {
+            let state = self.lock();
+            (state.run_queue.load(), state.run_queue.len())
+        };
 
         // Only try to steal if we have few tasks
         if my_count > 1 {
@@ -634,23 +1142,54 @@ impl Scheduler {
     }
 
     /// Get next timer event for tickless operation
-    pub fn get_next_timer(&self) -> Option<u64> {
+    ///
+    /// This is the end of the current time slice, or the start of the next period of a
+    /// throttled deadline task in `run_queue` if that comes first.
+    pub fn get_next_timer(&self, run_queue: &RunQueue) -> Option<u64> {
         let event = self.next_timer_event.load(Ordering::Acquire);
-        if event > 0 {
-            Some(event)
-        } else {
-            None
+        let event = if event > 0 { Some(event) } else { None };
+        match (event, run_queue.next_replenish()) {
+            (Some(event), Some(replenish)) => Some(event.min(replenish)),
+            (event, replenish) => event.or(replenish),
         }
     }
 }
 
+/// Base priority of a non-RT task with nice value `nice`, if it is in range
+pub fn nice_to_priority(nice: i32) -> Option<u8> {
+    if !(NICE_MIN..=NICE_MAX).contains(&nice) {
+        return None;
+    }
+    u8::try_from(i32::from(Priority::Normal.as_u8()).saturating_add(nice)).ok()
+}
+
+/// Nice value of a non-RT task with base priority `priority`
+pub fn priority_to_nice(priority: u8) -> i32 {
+    let nice = i32::from(priority).saturating_sub(i32::from(Priority::Normal.as_u8()));
+    nice.clamp(NICE_MIN, NICE_MAX)
+}
+
+/// Virtual deadline of a task woken with a wakeup boost, no later than `WAKEUP_BOOST_OFFSET_NS`
+/// past `min_deadline`. A deadline that is already earlier is kept.
+fn boosted_deadline(vdeadline: u64, min_deadline: u64) -> u64 {
+    vdeadline.min(min_deadline.saturating_add(WAKEUP_BOOST_OFFSET_NS))
+}
+
 // =============================================================================
 // Global Scheduler Functions
 // =============================================================================
 
+/// Apply the scheduler boot parameters
+pub fn init_from_params() {
+    if crate::startup::params::param_bool("nowakeboost") == Some(true) {
+        info!("Scheduler: wakeup boost disabled by nowakeboost");
+        WAKEUP_BOOST.store(false, Ordering::Relaxed);
+    }
+}
+
 /// Get the per-CPU scheduler instance
-pub fn scheduler() -> &'static mut Scheduler {
-    &mut PercpuBlock::current().scheduler
+pub fn scheduler() -> &'static Scheduler {
+    &PercpuBlock::current().scheduler
 }
 
 /// Schedule the next context to run
@@ -658,12 +1197,34 @@ pub fn schedule_next(token: &mut CleanLockToken) -> Option<ContextRef> {
     scheduler().schedule(token)
 }
 
+/// Pick the CPU a context should be queued on
+///
+/// The CPU it last ran on is preferred for cache locality, then the current one, as long as the
+/// affinity allows it. Otherwise it goes to the lowest CPU in its affinity, or stays on the
+/// current CPU if the affinity is empty.
+fn target_cpu(
+    affinity: &LogicalCpuSet,
+    last_cpu_id: Option<LogicalCpuId>,
+    current: LogicalCpuId,
+) -> LogicalCpuId {
+    last_cpu_id
+        .into_iter()
+        .chain([current])
+        .find(|&id| affinity.contains(id))
+        .or_else(|| {
+            (0..MAX_CPU_COUNT as u32)
+                .map(LogicalCpuId::new)
+                .find(|&id| affinity.contains(id))
+        })
+        .unwrap_or(current)
+}
+
 /// Add a context to the scheduler
 pub fn add_context(context_ref: ContextRef, token: &mut CleanLockToken) {
-    // Determine target CPU for cache locality
-    let target_cpu = {
+    let current = crate::cpu_id();
+    let target = {
         let context = context_ref.read(token.token());
-        context.last_cpu_id.unwrap_or_else(crate::cpu_id)
+        target_cpu(&context.sched_affinity, context.last_cpu_id, current)
     };
 
     // Initialize virtual deadline for new non-RT contexts
@@ -671,36 +1232,155 @@ pub fn add_context(context_ref: ContextRef, token: &mut CleanLockToken) {
         let mut context = context_ref.write(token.token());
         if !context.is_realtime && context.virtual_deadline == 0 {
             // Start with current minimum deadline to be fair
-            let current_vd = PercpuBlock::current()
-                .scheduler
-                .current_virtual_deadline
-                .load(Ordering::Relaxed);
+            let current_vd = scheduler().current_virtual_deadline.load(Ordering::Relaxed);
             context.virtual_deadline = current_vd;
         }
     }
 
-    // Add to appropriate CPU's queue
-    if target_cpu == crate::cpu_id() {
-        scheduler().run_queue.add(context_ref, token);
-    } else {
-        // Cross-CPU migration: add to current and let balancing handle it
-        // In a full implementation, this would use IPI
-        scheduler().run_queue.add(context_ref, token);
+    if let Some(context_ref) = send_to(target, context_ref) {
+        scheduler().lock().run_queue.add(context_ref, token);
+    }
+}
+
+/// Mark a context runnable and queue it if it was blocked, see [`wake`]
+///
+/// Returns whether it was blocked. The caller must not have the context locked.
+pub fn unblock(context_ref: &ContextRef, token: &mut CleanLockToken) -> bool {
+    let unblocked = context_ref.write(token.token()).unblock();
+    if unblocked {
+        wake(context_ref.clone(), token);
+    }
+    unblocked
+}
+
+/// Queue a context that [`Context::unblock`](crate::context::Context::unblock) just made
+/// runnable
+///
+/// The caller must not have the context locked, as it may be boosted, see
+/// [`Scheduler::context_unblocked`]. A context that blocked and was woken before its CPU
+/// switched away from it is left to that CPU, which queues it again as it switches.
+pub fn wake(context_ref: ContextRef, token: &mut CleanLockToken) {
+    let current = crate::cpu_id();
+    let (target, cpu) = {
+        let context = context_ref.read(token.token());
+        (
+            target_cpu(&context.sched_affinity, context.last_cpu_id, current),
+            context.cpu_id,
+        )
+    };
+
+    // Serialized with the switch by the scheduler lock of that CPU
+    if let Some(block) = cpu.and_then(percpu::get_percpu_block) {
+        let running = block
+            .scheduler
+            .lock()
+            .current_context
+            .as_ref()
+            .is_some_and(|running| Arc::ptr_eq(running, &context_ref));
+        if running {
+            return;
+        }
+    }
+
+    if let Some(context_ref) = send_to(target, context_ref) {
+        scheduler().context_unblocked(context_ref, token);
+    }
+}
+
+/// Hand a context over to the run queue of `target`, giving it back if that is the current CPU
+fn send_to(target: LogicalCpuId, context_ref: ContextRef) -> Option<ContextRef> {
+    if target == crate::cpu_id() {
+        return Some(context_ref);
+    }
+
+    // Run queues are only touched by their own CPU, so hand the context over and wake it
+    match percpu::get_percpu_block(target) {
+        Some(block) => {
+            block.scheduler.incoming.lock().push_back(context_ref);
+            ipi_single(IpiKind::Wakeup, block);
+            None
+        }
+        // The target CPU never came up, better to run here than not at all
+        None => Some(context_ref),
     }
 }
 
 /// Remove a context from the scheduler
 pub fn remove_context(context_id: &usize) {
-    scheduler().run_queue.remove(*context_id);
+    scheduler().context_blocked(*context_id);
+}
+
+/// Move a context to the queue matching its current scheduling class.
+///
+/// Must be called after `is_realtime`, `sched_deadline` or the priority of a context changes,
+/// since run queue entries cache them. The context is requeued under the scheduler lock of
+/// whichever CPU has it queued, and that CPU is kicked to reschedule. Contexts that are not queued
+/// pick up the new values the next time they are added.
+pub fn requeue_context(context_ref: &ContextRef, token: &mut CleanLockToken) {
+    let id = context_ref.read(token.token()).id();
+    let current = crate::cpu_id();
+
+    for cpu in (0..MAX_CPU_COUNT as u32).map(LogicalCpuId::new) {
+        let Some(block) = percpu::get_percpu_block(cpu) else {
+            continue;
+        };
+        let requeued = {
+            let mut state = block.scheduler.lock();
+            match state.run_queue.remove(id) {
+                Some(context_ref) => {
+                    state.run_queue.add(context_ref, token);
+                    true
+                }
+                None => false,
+            }
+        };
+        if requeued {
+            // It may run next there now, which that CPU only notices once it reschedules
+            if cpu != current {
+                ipi_single(IpiKind::Switch, block);
+            }
+            return;
+        }
+    }
+}
+
+/// Move a context off the CPUs its affinity no longer allows.
+///
+/// Must be called after `sched_affinity` changes. A context queued on this CPU is handed to one
+/// it allows, and a running one is made to switch away, after which it is queued the same way.
+/// Contexts queued on other CPUs move the next time they run there.
+pub fn affinity_changed(context_ref: &ContextRef, token: &mut CleanLockToken) {
+    let current = crate::cpu_id();
+    let (id, affinity, running_on) = {
+        let context = context_ref.read(token.token());
+        let running_on = context.cpu_id.filter(|_| context.running);
+        (context.id(), context.sched_affinity, running_on)
+    };
+
+    match running_on {
+        Some(cpu) if !affinity.contains(cpu) => {
+            if cpu == current {
+                ipi(IpiKind::Switch, IpiTarget::Current);
+            } else if let Some(block) = percpu::get_percpu_block(cpu) {
+                ipi_single(IpiKind::Switch, block);
+            }
+        }
+        Some(_) => {}
+        None if !affinity.contains(current) => {
+            let removed = scheduler().lock().run_queue.remove(id);
+            if let Some(context_ref) = removed {
+                add_context(context_ref, token);
+            }
+        }
+        None => {}
+    }
 }
 
 /// Request preemption of current context if needed
 pub fn request_preemption(token: &mut CleanLockToken) {
-    if scheduler().should_preempt(token) {
-        scheduler()
-            .stats
-            .preemptions
-            .fetch_add(1, Ordering::Relaxed);
+    let scheduler = scheduler();
+    if scheduler.should_preempt(&scheduler.lock().run_queue, token) {
+        scheduler.stats.preemptions.fetch_add(1, Ordering::Relaxed);
         // IPI to trigger reschedule on current CPU
         ipi(IpiKind::Switch, IpiTarget::Current);
     }
@@ -715,6 +1395,40 @@ pub fn balance(token: &mut CleanLockToken) {
 // Tests
 // =============================================================================
 
+#[cfg(feature = "selftest")]
+pub mod selftests {
+    use super::*;
+    use crate::selftest::{check, check_eq, SelftestResult};
+
+    pub fn run_queue_order(_token: &mut CleanLockToken) -> SelftestResult {
+        // Real-time: by priority, then first come first served
+        let mut rt = RtQueue::new();
+        check!(rt.insert(1, 50, 'a'));
+        check!(!rt.insert(2, 50, 'b'));
+        check!(rt.insert(3, 10, 'c'));
+        check!(!rt.insert(4, 200, 'd'));
+        check_eq!(rt.first(), Some(&'c'));
+        check_eq!(rt.remove(1), Some('a'));
+        check_eq!(rt.remove(1), None);
+        check_eq!(rt.pop_first(), Some('c'));
+        check_eq!(rt.pop_first(), Some('b'));
+        check_eq!(rt.pop_first(), Some('d'));
+        check!(rt.is_empty());
+
+        // Deadline: earliest deadline first, ties in insertion order
+        let mut deadline = DeadlineQueue::new();
+        check!(deadline.insert(1, 300, 'a'));
+        check!(deadline.insert(2, 100, 'b'));
+        check!(!deadline.insert(3, 100, 'c'));
+        check_eq!(deadline.first(), Some((100, &'b')));
+        check_eq!(deadline.pop_last(), Some('a'));
+        check_eq!(deadline.pop_first(), Some('b'));
+        check_eq!(deadline.pop_first(), Some('c'));
+        check!(deadline.is_empty());
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -726,6 +1440,20 @@ mod tests {
         assert_eq!(queue.len(), 0);
     }
 
+    #[test]
+    fn test_nice_priority() {
+        assert_eq!(nice_to_priority(0), Some(Priority::Normal.as_u8()));
+        assert_eq!(nice_to_priority(NICE_MIN), Some(80));
+        assert_eq!(nice_to_priority(NICE_MAX), Some(Priority::Low.as_u8()));
+        assert_eq!(nice_to_priority(NICE_MAX + 1), None);
+        assert_eq!(nice_to_priority(NICE_MIN - 1), None);
+        for nice in NICE_MIN..=NICE_MAX {
+            assert_eq!(priority_to_nice(nice_to_priority(nice).unwrap()), nice);
+        }
+        // RT priorities are out of range
+        assert_eq!(priority_to_nice(0), NICE_MIN);
+    }
+
     #[test]
     fn test_priority_to_weight() {
         // Higher priority (lower number) should get higher weight
@@ -749,6 +1477,26 @@ mod tests {
         assert_eq!(stats.max_latency_ns.load(Ordering::Relaxed), 100);
     }
 
+    #[test]
+    fn test_wakeup_boost_deadline() {
+        // A task that slept with a late deadline is placed just behind the earliest one
+        assert_eq!(
+            boosted_deadline(50_000_000, 1_000_000),
+            1_000_000 + WAKEUP_BOOST_OFFSET_NS
+        );
+        // An earlier deadline is not pushed back
+        assert_eq!(boosted_deadline(500_000, 1_000_000), 500_000);
+        assert_eq!(boosted_deadline(u64::MAX, u64::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn test_sched_policy_from_raw() {
+        assert_eq!(SchedPolicy::from_raw(1), Some(SchedPolicy::Fifo));
+        assert_eq!(SchedPolicy::from_raw(4), None);
+        assert!(SchedPolicy::RoundRobin.is_realtime());
+        assert!(!SchedPolicy::Normal.is_realtime());
+    }
+
     #[test]
     fn test_time_slice_calculation() {
         let rt_slice = Scheduler::calculate_time_slice(0);
@@ -760,4 +1508,172 @@ mod tests {
         assert!(rt_slice >= MIN_TIME_SLICE_NS);
         assert!(low_slice <= MAX_TIME_SLICE_NS);
     }
+
+    #[test]
+    fn test_target_cpu_respects_affinity() {
+        let cpu = LogicalCpuId::new;
+        let mut affinity = LogicalCpuSet::new();
+        affinity.add(cpu(2));
+        affinity.add(cpu(3));
+
+        assert_eq!(target_cpu(&affinity, Some(cpu(3)), cpu(2)), cpu(3));
+        assert_eq!(target_cpu(&affinity, Some(cpu(1)), cpu(2)), cpu(2));
+        assert_eq!(target_cpu(&affinity, None, cpu(2)), cpu(2));
+        assert_eq!(target_cpu(&affinity, Some(cpu(1)), cpu(0)), cpu(2));
+        assert_eq!(
+            target_cpu(&LogicalCpuSet::new(), Some(cpu(1)), cpu(0)),
+            cpu(0)
+        );
+    }
+
+    #[test]
+    fn test_rt_queue_priority_then_fifo() {
+        let mut queue = RtQueue::new();
+        assert!(queue.insert(1, 50, 'a'));
+        assert!(!queue.insert(2, 50, 'b'));
+        assert!(queue.insert(3, 10, 'c'));
+        assert!(!queue.insert(4, 200, 'd'));
+        assert_eq!(queue.len(), 4);
+
+        assert_eq!(queue.first(), Some(&'c'));
+        assert_eq!(queue.remove(1), Some('a'));
+        assert_eq!(queue.remove(1), None);
+        assert_eq!(queue.pop_first(), Some('c'));
+        assert_eq!(queue.pop_first(), Some('b'));
+        assert_eq!(queue.pop_first(), Some('d'));
+        assert_eq!(queue.pop_first(), None);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_deadline_queue_order() {
+        let mut queue = DeadlineQueue::new();
+        assert!(queue.insert(1, 300, 'a'));
+        assert!(queue.insert(2, 100, 'b'));
+        assert!(!queue.insert(3, 100, 'c'));
+        assert!(!queue.insert(4, 200, 'd'));
+
+        assert_eq!(queue.first(), Some((100, &'b')));
+        assert_eq!(queue.remove(4), Some('d'));
+        assert_eq!(queue.remove(4), None);
+        assert_eq!(queue.pop_last(), Some('a'));
+        assert_eq!(queue.pop_first(), Some('b'));
+        assert_eq!(queue.pop_first(), Some('c'));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_deadline_params() {
+        let params = |runtime, deadline, period| DeadlineParams {
+            runtime,
+            deadline,
+            period,
+        };
+        assert!(params(2, 5, 10).is_valid());
+        assert!(params(10, 10, 10).is_valid());
+        assert!(!params(0, 5, 10).is_valid());
+        assert!(!params(6, 5, 10).is_valid());
+        assert!(!params(2, 11, 10).is_valid());
+
+        assert_eq!(params(1, 3, 3).utilization(), 333_334);
+        assert_eq!(params(10, 10, 10).utilization(), UTILIZATION_SCALE);
+    }
+
+    #[test]
+    fn test_deadline_budget_and_throttling() {
+        let params = DeadlineParams {
+            runtime: 200,
+            deadline: 500,
+            period: 1000,
+        };
+        let mut state = DeadlineState::new(params, 1000);
+        assert_eq!((state.budget, state.deadline), (200, 1500));
+
+        state.charge(150);
+        state.refresh(1150);
+        assert_eq!((state.budget, state.deadline), (50, 1500));
+        assert!(!state.throttled);
+
+        // Overrunning throttles until the next period instead of going negative
+        state.charge(80);
+        assert!(state.throttled);
+        state.refresh(1900);
+        assert!(state.throttled);
+        assert_eq!(state.next_period(), 2000);
+        state.refresh(2000);
+        assert!(!state.throttled);
+        assert_eq!((state.budget, state.deadline), (200, 2500));
+
+        // Waking up too close to the deadline for the budget left starts a new period
+        state.charge(10);
+        state.refresh(2450);
+        assert_eq!((state.budget, state.deadline), (200, 2950));
+    }
+
+    #[test]
+    fn test_deadline_admission() {
+        let cap = 75 * (UTILIZATION_SCALE / 100);
+        assert_eq!(admit(0, 0, 500_000, cap), Some(500_000));
+        assert_eq!(admit(500_000, 0, 300_000, cap), None);
+        assert_eq!(admit(500_000, 500_000, 700_000, cap), Some(700_000));
+        assert_eq!(admit(500_000, 0, 250_000, cap), Some(cap));
+        assert_eq!(admit(u64::MAX, 0, 1, u64::MAX), None);
+    }
+
+    /// Add, remove and next at 10k tasks, against the sorted deque used before
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture bench_run_queue`.
+    #[test]
+    #[ignore]
+    fn bench_run_queue_10k_tasks() {
+        const TASKS: usize = 10_000;
+        use std::{println, time::Instant};
+
+        // Deadlines and priorities spread over their range in a scrambled order
+        let vdeadline = |i: usize| (i as u64 * 7919) % TASKS as u64;
+        let priority = |i: usize| ((i * 31) % RT_PRIORITY_LEVELS) as u8;
+
+        let start = Instant::now();
+        let mut sorted = VecDeque::new();
+        for i in 0..TASKS {
+            let pos = sorted
+                .iter()
+                .position(|&(_, vd)| vdeadline(i) < vd)
+                .unwrap_or(sorted.len());
+            sorted.insert(pos, (i, vdeadline(i)));
+        }
+        for i in (0..TASKS).step_by(2) {
+            let pos = sorted.iter().position(|&(id, _)| id == i).unwrap();
+            sorted.remove(pos);
+        }
+        while sorted.pop_front().is_some() {}
+        let sorted_time = start.elapsed();
+
+        let start = Instant::now();
+        let mut deadlines = DeadlineQueue::new();
+        for i in 0..TASKS {
+            deadlines.insert(i, vdeadline(i), i);
+        }
+        for i in (0..TASKS).step_by(2) {
+            deadlines.remove(i).unwrap();
+        }
+        while deadlines.pop_first().is_some() {}
+        let deadline_time = start.elapsed();
+
+        let start = Instant::now();
+        let mut rt = RtQueue::new();
+        for i in 0..TASKS {
+            rt.insert(i, priority(i), i);
+        }
+        for i in (0..TASKS).step_by(2) {
+            rt.remove(i).unwrap();
+        }
+        while rt.pop_first().is_some() {}
+        let rt_time = start.elapsed();
+
+        println!(
+            "{} tasks: sorted deque {:?}, deadline queue {:?}, RT buckets {:?}",
+            TASKS, sorted_time, deadline_time, rt_time
+        );
+    }
 }
@@ -1,10 +1,10 @@
 use core::{
     convert::TryInto,
-    str,
+    slice, str,
     sync::atomic::{self, AtomicUsize},
 };
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, format, string::String, vec::Vec};
 
 use hashbrown::{hash_map::DefaultHashBuilder, HashMap};
 use spin::{Mutex, Once};
@@ -14,7 +14,7 @@ use syscall::{
 };
 
 use crate::{
-    acpi::{RxsdtEnum, RXSDT_ENUM},
+    acpi::{self, sdt::Sdt, RxsdtEnum, RXSDT_ENUM},
     context::file::InternalFlags,
     event,
     sync::{CleanLockToken, RwLock, WaitCondition, L1},
@@ -33,6 +33,10 @@ use crate::syscall::{
 use super::{CallerCtx, GlobalSchemes, KernelScheme, OpenResult};
 
 /// A scheme used to access the RSDT or XSDT, which is needed for e.g. `acpid` to function.
+///
+/// `acpi:tables/<SIG>` additionally gives read-only access to the raw bytes, header included, of
+/// any table listed in the RSDT or XSDT. Where a signature appears more than once, like SSDTs,
+/// `<SIG>@<n>` picks the n-th table in listing order and `<SIG>` the first.
 pub struct AcpiScheme;
 
 #[derive(Clone, Copy)]
@@ -44,6 +48,9 @@ enum HandleKind {
     TopLevel,
     Rxsdt,
     ShutdownPipe,
+    Tables,
+    /// Index into `TABLES`
+    Table(usize),
 }
 
 static HANDLES: RwLock<L1, HashMap<usize, Handle>> =
@@ -51,6 +58,7 @@ static HANDLES: RwLock<L1, HashMap<usize, Handle>> =
 static NEXT_FD: AtomicUsize = AtomicUsize::new(0);
 
 static DATA: Once<Box<[u8]>> = Once::new();
+static TABLES: Once<Vec<&'static Sdt>> = Once::new();
 
 static KSTOP_WAITCOND: WaitCondition = WaitCondition::new();
 static KSTOP_FLAG: Mutex<bool> = Mutex::new(false);
@@ -105,6 +113,46 @@ impl AcpiScheme {
         if !data_init {
             error!("AcpiScheme::init called multiple times");
         }
+
+        TABLES.call_once(acpi::tables);
+    }
+}
+
+fn tables() -> &'static [&'static Sdt] {
+    TABLES.get().map_or(&[], Vec::as_slice)
+}
+
+fn table_bytes(index: usize) -> Result<&'static [u8]> {
+    let sdt = *tables().get(index).ok_or(Error::new(EBADFD))?;
+    Ok(unsafe { slice::from_raw_parts((sdt as *const Sdt).cast::<u8>(), sdt.length as usize) })
+}
+
+/// Find the table named `SIG` or `SIG@n`
+fn find_table(name: &str) -> Option<usize> {
+    let (signature, nth) = match name.split_once('@') {
+        Some((signature, nth)) => (signature, nth.parse().ok()?),
+        None => (name, 0),
+    };
+    tables()
+        .iter()
+        .enumerate()
+        .filter(|(_, sdt)| sdt.signature == signature.as_bytes())
+        .nth(nth)
+        .map(|(index, _)| index)
+}
+
+/// Name of a table, with its position among tables of the same signature if there are several
+fn table_name(index: usize) -> String {
+    let tables = tables();
+    let signature = tables[index].signature;
+    let same = |sdt: &&&Sdt| sdt.signature == signature;
+    let signature = String::from_utf8_lossy(&signature);
+
+    if tables.iter().filter(same).count() == 1 {
+        signature.into_owned()
+    } else {
+        let nth = tables[..index].iter().filter(same).count();
+        format!("{}@{}", signature, nth)
     }
 }
 
@@ -150,7 +198,20 @@ impl KernelScheme for AcpiScheme {
                 }
                 (HandleKind::ShutdownPipe, InternalFlags::empty())
             }
-            _ => return Err(Error::new(ENOENT)),
+            "tables" => {
+                if flags & O_DIRECTORY != O_DIRECTORY && flags & O_STAT != O_STAT {
+                    return Err(Error::new(EISDIR));
+                }
+                (HandleKind::Tables, InternalFlags::POSITIONED)
+            }
+            _ => {
+                let name = path.strip_prefix("tables/").ok_or(Error::new(ENOENT))?;
+                let index = find_table(name).ok_or(Error::new(ENOENT))?;
+                if flags & O_DIRECTORY == O_DIRECTORY && flags & O_STAT != O_STAT {
+                    return Err(Error::new(ENOTDIR));
+                }
+                (HandleKind::Table(index), InternalFlags::POSITIONED)
+            }
         };
 
         let fd = NEXT_FD.fetch_add(1, atomic::Ordering::Relaxed);
@@ -167,7 +228,8 @@ impl KernelScheme for AcpiScheme {
         Ok(match handle.kind {
             HandleKind::Rxsdt => DATA.get().ok_or(Error::new(EBADFD))?.len() as u64,
             HandleKind::ShutdownPipe => 1,
-            HandleKind::TopLevel => 0,
+            HandleKind::Table(index) => table_bytes(index)?.len() as u64,
+            HandleKind::TopLevel | HandleKind::Tables => 0,
         })
     }
     // TODO
@@ -225,7 +287,8 @@ impl KernelScheme for AcpiScheme {
                 return dst_buf.copy_exactly(&[0x42]).map(|()| 1);
             }
             HandleKind::Rxsdt => DATA.get().ok_or(Error::new(EBADFD))?,
-            HandleKind::TopLevel => return Err(Error::new(EISDIR)),
+            HandleKind::Table(index) => table_bytes(index)?,
+            HandleKind::TopLevel | HandleKind::Tables => return Err(Error::new(EISDIR)),
         };
 
         let src_offset = core::cmp::min(offset, data.len());
@@ -243,15 +306,28 @@ impl KernelScheme for AcpiScheme {
         opaque: u64,
         token: &mut CleanLockToken,
     ) -> Result<usize> {
-        let Some(Handle {
-            kind: HandleKind::TopLevel,
-            ..
-        }) = HANDLES.read(token.token()).get(&id)
-        else {
-            return Err(Error::new(ENOTDIR));
-        };
+        let kind = HANDLES
+            .read(token.token())
+            .get(&id)
+            .ok_or(Error::new(EBADF))?
+            .kind;
 
         let mut buf = DirentBuf::new(buf, header_size).ok_or(Error::new(EIO))?;
+        match kind {
+            HandleKind::TopLevel => (),
+            HandleKind::Tables => {
+                for index in (opaque as usize)..tables().len() {
+                    buf.entry(DirEntry {
+                        kind: DirentKind::Regular,
+                        name: &table_name(index),
+                        inode: 0,
+                        next_opaque_id: index as u64 + 1,
+                    })?;
+                }
+                return Ok(buf.finalize());
+            }
+            _ => return Err(Error::new(ENOTDIR)),
+        }
         if opaque == 0 {
             buf.entry(DirEntry {
                 kind: DirentKind::Regular,
@@ -265,6 +341,14 @@ impl KernelScheme for AcpiScheme {
                 kind: DirentKind::Socket,
                 name: "kstop",
                 inode: 0,
+                next_opaque_id: 2,
+            })?;
+        }
+        if opaque <= 2 {
+            buf.entry(DirEntry {
+                kind: DirentKind::Directory,
+                name: "tables",
+                inode: 0,
                 next_opaque_id: u64::MAX,
             })?;
         }
@@ -274,10 +358,12 @@ impl KernelScheme for AcpiScheme {
         let handles = HANDLES.read(token.token());
         let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
 
-        let path = match handle.kind {
-            HandleKind::TopLevel => "acpi:",
-            HandleKind::Rxsdt => "acpi:rxsdt",
-            HandleKind::ShutdownPipe => "acpi:kstop",
+        let path: String = match handle.kind {
+            HandleKind::TopLevel => "acpi:".into(),
+            HandleKind::Rxsdt => "acpi:rxsdt".into(),
+            HandleKind::ShutdownPipe => "acpi:kstop".into(),
+            HandleKind::Tables => "acpi:tables".into(),
+            HandleKind::Table(index) => format!("acpi:tables/{}", table_name(index)),
         };
 
         buf.copy_common_bytes_from_slice(path.as_bytes())
@@ -296,7 +382,12 @@ impl KernelScheme for AcpiScheme {
                     ..Default::default()
                 }
             }
-            HandleKind::TopLevel => Stat {
+            HandleKind::Table(index) => Stat {
+                st_mode: MODE_FILE,
+                st_size: table_bytes(index)?.len() as u64,
+                ..Default::default()
+            },
+            HandleKind::TopLevel | HandleKind::Tables => Stat {
                 st_mode: MODE_DIR,
                 st_size: 0,
                 ..Default::default()
@@ -0,0 +1,213 @@
+//! Lock-free lookup of schemes by ID
+//!
+//! Every file operation looks up its scheme by [`SchemeId`], and taking the read lock of
+//! [`super::SCHEMES`] for that bounces the lock's cacheline between all CPUs doing file I/O.
+//! Registration is rare, so [`super::SchemeList`] instead publishes a table indexed by ID whenever
+//! a scheme is added or removed, and lookups index the current table without taking any lock.
+//! Resolving names still goes through the list.
+//!
+//! A replaced table is freed once no lookup can still be using it. Lookups count themselves in
+//! a counter of the CPU they run on, and after swapping in the new table the writer waits until
+//! it has seen every counter at zero. A lookup counted after that has loaded the new table, as
+//! the counters and the table pointer are all accessed sequentially consistently.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    hint, ptr,
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+
+use super::{KernelSchemes, SchemeId};
+use crate::cpu_set::MAX_CPU_COUNT;
+
+/// Schemes indexed by ID, `None` for IDs that were never used or have been removed
+pub type Snapshot = Vec<Option<Arc<KernelSchemes>>>;
+
+/// Lookups in progress on one CPU, on a cacheline of its own
+#[repr(align(64))]
+struct Readers(AtomicUsize);
+
+pub struct SchemeCache {
+    /// From [`Arc::into_raw`], or null before the first publish
+    snapshot: AtomicPtr<Snapshot>,
+    readers: [Readers; MAX_CPU_COUNT],
+}
+
+impl SchemeCache {
+    pub const fn new() -> Self {
+        Self {
+            snapshot: AtomicPtr::new(ptr::null_mut()),
+            readers: [const { Readers(AtomicUsize::new(0)) }; MAX_CPU_COUNT],
+        }
+    }
+
+    /// Scheme registered under `id` when the last change was published
+    pub fn get(&self, id: SchemeId) -> Option<Arc<KernelSchemes>> {
+        // Decrement the same counter even if this context migrates in between
+        let readers = &self.readers[reader_slot()].0;
+        readers.fetch_add(1, Ordering::SeqCst);
+
+        let snapshot = self.snapshot.load(Ordering::SeqCst);
+        let scheme = unsafe { snapshot.as_ref() }
+            .and_then(|snapshot| snapshot.get(id.get()))
+            .and_then(Option::clone);
+
+        readers.fetch_sub(1, Ordering::Release);
+        scheme
+    }
+
+    /// Replace the table, and free the previous one once no lookup uses it anymore
+    ///
+    /// Publishers must be serialized, which holding the write lock of the scheme list does.
+    pub fn publish(&self, snapshot: Snapshot) {
+        let new = Arc::into_raw(Arc::new(snapshot)).cast_mut();
+        let old = self.snapshot.swap(new, Ordering::SeqCst);
+        if old.is_null() {
+            return;
+        }
+
+        // Lookups are a few instructions long, so each counter drops to zero quickly
+        for readers in &self.readers {
+            while readers.0.load(Ordering::SeqCst) != 0 {
+                hint::spin_loop();
+            }
+        }
+        drop(unsafe { Arc::from_raw(old) });
+    }
+}
+
+#[cfg(not(test))]
+fn reader_slot() -> usize {
+    crate::cpu_id().get() as usize
+}
+
+/// Host threads stand in for CPUs in tests
+#[cfg(test)]
+fn reader_slot() -> usize {
+    use std::sync::atomic::AtomicUsize;
+
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    std::thread_local! {
+        static SLOT: usize = NEXT.fetch_add(1, Ordering::Relaxed) % MAX_CPU_COUNT;
+    }
+    SLOT.with(|slot| *slot)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        boxed::Box,
+        collections::BTreeMap,
+        sync::RwLock,
+        thread,
+        time::{Duration, Instant},
+        vec,
+    };
+
+    use super::*;
+    use crate::scheme::GlobalSchemes;
+
+    fn table(len: usize) -> Snapshot {
+        (0..len)
+            .map(|id| (id % 2 == 1).then(|| Arc::new(KernelSchemes::Global(GlobalSchemes::Pipe))))
+            .collect()
+    }
+
+    #[test]
+    fn lookup_follows_published_table() {
+        let cache = SchemeCache::new();
+        assert!(cache.get(SchemeId::new(1)).is_none());
+
+        cache.publish(table(4));
+        assert!(cache.get(SchemeId::new(1)).is_some());
+        assert!(cache.get(SchemeId::new(2)).is_none());
+        assert!(cache.get(SchemeId::new(5)).is_none());
+
+        cache.publish(table(8));
+        assert!(cache.get(SchemeId::new(5)).is_some());
+    }
+
+    #[test]
+    fn replaced_table_is_freed() {
+        let cache = SchemeCache::new();
+        let scheme = Arc::new(KernelSchemes::Global(GlobalSchemes::Pipe));
+        cache.publish(vec![None, Some(Arc::clone(&scheme))]);
+        assert_eq!(Arc::strong_count(&scheme), 2);
+
+        cache.publish(vec![None, None]);
+        assert_eq!(Arc::strong_count(&scheme), 1);
+    }
+
+    #[test]
+    fn lookups_race_with_publish() {
+        let cache: &'static SchemeCache = Box::leak(Box::new(SchemeCache::new()));
+        cache.publish(table(2));
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                thread::spawn(move || {
+                    for _ in 0..100_000 {
+                        assert!(cache.get(SchemeId::new(1)).is_some());
+                    }
+                })
+            })
+            .collect();
+        for len in 2..200 {
+            cache.publish(table(len));
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    }
+
+    /// Throughput of [`SchemeCache::get`] on 4 threads, against the read-locked map lookups used
+    /// before
+    ///
+    /// This only measures the lookup itself. The open/close throughput across CPUs that the cache
+    /// is meant to improve needs a running kernel, and is not measured yet.
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture bench_lookup_only`.
+    #[test]
+    #[ignore]
+    fn bench_lookup_only_4_threads() {
+        const THREADS: usize = 4;
+        const LOOKUPS: usize = 1_000_000;
+
+        let map: &'static RwLock<BTreeMap<SchemeId, Arc<KernelSchemes>>> =
+            Box::leak(Box::new(RwLock::new(BTreeMap::new())));
+        let cache: &'static SchemeCache = Box::leak(Box::new(SchemeCache::new()));
+        let snapshot = table(32);
+        for (id, scheme) in snapshot.iter().enumerate() {
+            if let Some(scheme) = scheme {
+                map.write()
+                    .unwrap()
+                    .insert(SchemeId::new(id), Arc::clone(scheme));
+            }
+        }
+        cache.publish(snapshot);
+
+        fn run(lookup: impl Fn(usize) -> bool + Copy + Send + 'static) -> Duration {
+            let start = Instant::now();
+            let threads: Vec<_> = (0..THREADS)
+                .map(|_| thread::spawn(move || (0..LOOKUPS).filter(|&i| lookup(i)).count()))
+                .collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            start.elapsed()
+        }
+        let locked = run(move |i| {
+            let id = SchemeId::new(i % 32);
+            map.read().unwrap().get(&id).cloned().is_some()
+        });
+        let cached = run(move |i| cache.get(SchemeId::new(i % 32)).is_some());
+
+        std::println!(
+            "{} lookups on {} threads: locked map {:?}, cache {:?}",
+            THREADS * LOOKUPS,
+            THREADS,
+            locked,
+            cached
+        );
+    }
+}
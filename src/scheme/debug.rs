@@ -4,11 +4,11 @@ use crate::{
     context::file::InternalFlags,
     devices::graphical_debug,
     event,
-    log::Writer,
+    log::{Writer, LOG, SCROLLBACK_SIZE},
     scheme::*,
-    sync::{CleanLockToken, RwLock, WaitQueue, L1},
+    sync::{CleanLockToken, OptimizedWaitQueue, RwLock, L1},
     syscall::{
-        error::{EBADF, EINVAL, ENOENT, EPERM},
+        error::{EBADF, EINVAL, ENOENT, EPERM, ESPIPE},
         flag::{EventFlags, EVENT_READ, O_NONBLOCK},
         usercopy::{UserSliceRo, UserSliceWo},
     },
@@ -18,11 +18,13 @@ use hashbrown::{hash_map::DefaultHashBuilder, HashMap};
 static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
 
 /// Input queue
-static INPUT: WaitQueue<u8> = WaitQueue::new();
+static INPUT: spin::Lazy<OptimizedWaitQueue<u8>> = spin::Lazy::new(OptimizedWaitQueue::new);
 
 #[derive(Clone, Copy)]
 struct Handle {
     num: usize,
+    /// For `debug:scrollback`, the scrollback position of offset 0
+    base: u64,
 }
 
 static HANDLES: RwLock<L1, HashMap<usize, Handle>> =
@@ -44,6 +46,7 @@ pub fn debug_notify(token: &mut CleanLockToken) {
 pub struct DebugScheme;
 
 const DEBUG_SCHEME_PATH: &[u8] = b"debug:";
+const DEBUG_SCROLLBACK_PATH: &[u8] = b"debug:scrollback";
 
 #[repr(usize)]
 enum SpecialFds {
@@ -53,6 +56,50 @@ enum SpecialFds {
 
     #[cfg(feature = "profiling")]
     CtlProfiling = !0 - 3,
+
+    #[cfg(feature = "debugger")]
+    Gdb = !0 - 4,
+
+    Scrollback = !0 - 5,
+}
+
+/// Read `debug:scrollback` at `offset`. Offsets of a new handle run from the oldest byte in the
+/// scrollback to the newest, so fsize is the amount buffered. A read from before what is left in
+/// the scrollback gets a line saying how many bytes were lost, followed by the oldest ones, and
+/// the handle's offsets are moved along so that the next read continues after them.
+fn read_scrollback(
+    id: usize,
+    handle: Handle,
+    buf: UserSliceWo,
+    offset: u64,
+    token: &mut CleanLockToken,
+) -> Result<usize> {
+    let pos = handle.base.wrapping_add(offset);
+    let mut data = vec![0_u8; buf.len().min(SCROLLBACK_SIZE)];
+    // Copied out first, as a fault in usercopy may log and take the same lock
+    let (count, lost) = match *LOG.lock() {
+        Some(ref log) => log.scrollback.read(pos, &mut data),
+        None => (0, 0),
+    };
+    data.truncate(count);
+    if lost == 0 {
+        return buf.copy_common_bytes_from_slice(&data);
+    }
+
+    let mut out = format!("[debug: {} bytes lost]\n", lost).into_bytes();
+    let marker_len = out.len();
+    out.extend_from_slice(&data);
+    let read = buf.copy_common_bytes_from_slice(&out)?;
+
+    // The next offset, `offset + read`, is to follow the data read
+    let marker_read = read.min(marker_len);
+    if let Some(handle) = HANDLES.write(token.token()).get_mut(&id) {
+        handle.base = handle
+            .base
+            .wrapping_add(lost)
+            .wrapping_sub(marker_read as u64);
+    }
+    Ok(read)
 }
 
 impl KernelScheme for DebugScheme {
@@ -70,6 +117,8 @@ impl KernelScheme for DebugScheme {
         let num = match path {
             "" => SpecialFds::Default as usize,
 
+            "scrollback" => SpecialFds::Scrollback as usize,
+
             "no-preserve" => SpecialFds::NoPreserve as usize,
 
             "disable-graphical-debug" => SpecialFds::DisableGraphicalDebug as usize,
@@ -82,13 +131,32 @@ impl KernelScheme for DebugScheme {
             #[cfg(feature = "profiling")]
             "ctl-profiling" => SpecialFds::CtlProfiling as usize,
 
+            #[cfg(feature = "debugger")]
+            p if p.starts_with("gdb/") => SpecialFds::Gdb as usize,
+
             _ => return Err(Error::new(ENOENT)),
         };
 
         let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
-        HANDLES.write(token.token()).insert(id, Handle { num });
 
-        Ok(OpenResult::SchemeLocal(id, InternalFlags::empty()))
+        #[cfg(feature = "debugger")]
+        if num == SpecialFds::Gdb as usize {
+            let pid = path[4..].parse().map_err(|_| Error::new(ENOENT))?;
+            crate::debugger::gdb::attach(id, pid, token)?;
+        }
+
+        let (base, internal_flags) = if num == SpecialFds::Scrollback as usize {
+            let base = LOG.lock().as_ref().map_or(0, |log| log.scrollback.start());
+            (base, InternalFlags::POSITIONED)
+        } else {
+            (0, InternalFlags::empty())
+        };
+
+        HANDLES
+            .write(token.token())
+            .insert(id, Handle { num, base });
+
+        Ok(OpenResult::SchemeLocal(id, internal_flags))
     }
 
     fn fevent(
@@ -102,6 +170,11 @@ impl KernelScheme for DebugScheme {
             *handles.get(&id).ok_or(Error::new(EBADF))?
         };
 
+        #[cfg(feature = "debugger")]
+        if _handle.num == SpecialFds::Gdb as usize {
+            return crate::debugger::gdb::events(id, token);
+        }
+
         Ok(EventFlags::empty())
     }
 
@@ -120,6 +193,12 @@ impl KernelScheme for DebugScheme {
             handles.remove(&id).ok_or(Error::new(EBADF))?
         };
 
+        #[cfg(feature = "debugger")]
+        if _handle.num == SpecialFds::Gdb as usize {
+            // Already detached if gdb sent D or k
+            let _ = crate::debugger::gdb::detach(id, token);
+        }
+
         Ok(())
     }
     fn kread(
@@ -127,7 +206,7 @@ impl KernelScheme for DebugScheme {
         id: usize,
         buf: UserSliceWo,
         flags: u32,
-        _stored_flags: u32,
+        stored_flags: u32,
         token: &mut CleanLockToken,
     ) -> Result<usize> {
         let handle = {
@@ -135,10 +214,20 @@ impl KernelScheme for DebugScheme {
             *handles.get(&id).ok_or(Error::new(EBADF))?
         };
 
-        if handle.num == SpecialFds::DisableGraphicalDebug as usize {
+        if handle.num == SpecialFds::DisableGraphicalDebug as usize
+            || handle.num == SpecialFds::Scrollback as usize
+        {
             return Err(Error::new(EBADF));
         }
 
+        // The stored flags reflect any later fcntl(F_SETFL) on the description
+        let nonblock = (flags | stored_flags) & O_NONBLOCK as u32 != 0;
+
+        #[cfg(feature = "debugger")]
+        if handle.num == SpecialFds::Gdb as usize {
+            return crate::debugger::gdb::read(id, buf, nonblock, token);
+        }
+
         #[cfg(feature = "profiling")]
         if handle.num == SpecialFds::CtlProfiling as usize {
             return Err(Error::new(EBADF));
@@ -152,12 +241,30 @@ impl KernelScheme for DebugScheme {
             );
         }
 
-        INPUT.receive_into_user(
-            buf,
-            flags & O_NONBLOCK as u32 == 0,
-            "DebugScheme::read",
-            token,
-        )
+        INPUT.receive_into_user(buf, !nonblock, "DebugScheme::read", token)
+    }
+
+    fn kreadoff(
+        &self,
+        id: usize,
+        buf: UserSliceWo,
+        offset: u64,
+        flags: u32,
+        stored_flags: u32,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        // Only debug:scrollback is positioned
+        if offset == NO_OFFSET {
+            return self.kread(id, buf, flags, stored_flags, token);
+        }
+        let handle = {
+            let handles = HANDLES.read(token.token());
+            *handles.get(&id).ok_or(Error::new(EBADF))?
+        };
+        if handle.num != SpecialFds::Scrollback as usize {
+            return Err(Error::new(ESPIPE));
+        }
+        read_scrollback(id, handle, buf, offset, token)
     }
 
     fn kwrite(
@@ -189,6 +296,11 @@ impl KernelScheme for DebugScheme {
             return Ok(1);
         }
 
+        #[cfg(feature = "debugger")]
+        if handle.num == SpecialFds::Gdb as usize {
+            return crate::debugger::gdb::write(id, buf, token);
+        }
+
         if handle.num == SpecialFds::DisableGraphicalDebug as usize {
             graphical_debug::fini();
 
@@ -210,26 +322,60 @@ impl KernelScheme for DebugScheme {
             // The reason why a new writer is created for each iteration, is because the page fault
             // handler in usercopy might use the same lock when printing for debug purposes, and
             // although it most likely won't, it would be dangerous to rely on that assumption.
-            Writer::new().write(tmp_bytes, handle.num != SpecialFds::NoPreserve as usize);
+            Writer::new().write_debug(tmp_bytes, handle.num != SpecialFds::NoPreserve as usize);
         }
 
         Ok(buf.len())
     }
+
+    fn kwriteoff(
+        &self,
+        id: usize,
+        buf: UserSliceRo,
+        offset: u64,
+        flags: u32,
+        stored_flags: u32,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        // Only debug:scrollback is positioned, and it is read-only
+        if offset != NO_OFFSET {
+            return Err(Error::new(EBADF));
+        }
+        self.kwrite(id, buf, flags, stored_flags, token)
+    }
+
+    fn fsize(&self, id: usize, token: &mut CleanLockToken) -> Result<u64> {
+        let handle = {
+            let handles = HANDLES.read(token.token());
+            *handles.get(&id).ok_or(Error::new(EBADF))?
+        };
+        if handle.num != SpecialFds::Scrollback as usize {
+            return Err(Error::new(ESPIPE));
+        }
+        Ok(LOG
+            .lock()
+            .as_ref()
+            .map_or(0, |log| log.scrollback.buffered() as u64))
+    }
     fn kfpath(&self, id: usize, buf: UserSliceWo, token: &mut CleanLockToken) -> Result<usize> {
         let handle = {
             let handles = HANDLES.read(token.token());
             *handles.get(&id).ok_or(Error::new(EBADF))?
         };
-        if handle.num != SpecialFds::Default as usize
-            && handle.num != SpecialFds::NoPreserve as usize
+        let path = if handle.num == SpecialFds::Scrollback as usize {
+            DEBUG_SCROLLBACK_PATH
+        } else if handle.num == SpecialFds::Default as usize
+            || handle.num == SpecialFds::NoPreserve as usize
         {
+            DEBUG_SCHEME_PATH
+        } else {
             return Err(Error::new(EINVAL));
-        }
+        };
 
-        let byte_count = core::cmp::min(buf.len(), DEBUG_SCHEME_PATH.len());
+        let byte_count = core::cmp::min(buf.len(), path.len());
         buf.limit(byte_count)
             .expect("must succeed")
-            .copy_from_slice(&DEBUG_SCHEME_PATH[..byte_count])?;
+            .copy_from_slice(&path[..byte_count])?;
 
         Ok(byte_count)
     }
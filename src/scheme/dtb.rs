@@ -1,8 +1,10 @@
 use core::sync::atomic::{self, AtomicUsize};
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, format, string::String, vec::Vec};
+use fdt::{node::FdtNode, Fdt};
 use hashbrown::{hash_map::DefaultHashBuilder, HashMap};
 use spin::Once;
+use syscall::dirent::{DirEntry, DirentBuf, DirentKind};
 
 use super::{CallerCtx, KernelScheme, OpenResult};
 use crate::{
@@ -12,16 +14,30 @@ use crate::{
     syscall::{
         data::Stat,
         error::*,
-        flag::{MODE_FILE, O_STAT},
+        flag::{MODE_DIR, MODE_FILE, O_ACCMODE, O_CREAT, O_DIRECTORY, O_RDONLY, O_STAT},
         usercopy::UserSliceWo,
     },
 };
 
+/// Read-only access to the flattened device tree
+///
+/// Opening the scheme root gives the raw blob. `/<node path>` is a directory listing the child
+/// nodes and properties of that node, `/<node path>/<property>` the raw bytes of a property, and
+/// `compatible/<string>` the newline-separated paths of every node compatible with `<string>`.
 pub struct DtbScheme;
 
-#[derive(Eq, PartialEq)]
 enum HandleKind {
     RawData,
+    /// Contents computed at open, of a property or a compatible search
+    Data {
+        path: String,
+        data: Box<[u8]>,
+    },
+    /// Child nodes followed by properties of a node
+    Node {
+        path: String,
+        entries: Vec<(String, DirentKind)>,
+    },
 }
 
 struct Handle {
@@ -34,6 +50,72 @@ static HANDLES: RwLock<L1, HashMap<usize, Handle>> =
 static NEXT_FD: AtomicUsize = AtomicUsize::new(0);
 static DATA: Once<Box<[u8]>> = Once::new();
 
+fn fdt() -> Result<Fdt<'static>> {
+    let data = DATA.get().ok_or(Error::new(EBADFD))?;
+    Fdt::new(data).map_err(|_| Error::new(ENOENT))
+}
+
+fn node_entries(node: &FdtNode) -> Vec<(String, DirentKind)> {
+    let children = node
+        .children()
+        .map(|child| (String::from(child.name), DirentKind::Directory));
+    let properties = node
+        .properties()
+        .map(|property| (String::from(property.name), DirentKind::Regular));
+    children.chain(properties).collect()
+}
+
+/// Append the paths of `node` and its descendants compatible with `compatible` to `out`
+fn find_compatible(node: FdtNode, path: &str, compatible: &str, out: &mut Vec<u8>) {
+    if node
+        .compatible()
+        .is_some_and(|list| list.all().any(|c| c == compatible))
+    {
+        out.extend_from_slice(if path.is_empty() { "/" } else { path }.as_bytes());
+        out.push(b'\n');
+    }
+    for child in node.children() {
+        let child_path = format!("{}/{}", path, child.name);
+        find_compatible(child, &child_path, compatible, out);
+    }
+}
+
+/// Resolve a path relative to the scheme root, other than the root itself
+fn lookup(path: &str) -> Result<HandleKind> {
+    let fdt = fdt()?;
+
+    if let Some(compatible) = path.strip_prefix("compatible/") {
+        let root = fdt.find_node("/").ok_or(Error::new(ENOENT))?;
+        let mut data = Vec::new();
+        find_compatible(root, "", compatible, &mut data);
+        if data.is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+        return Ok(HandleKind::Data {
+            path: String::from(path),
+            data: data.into(),
+        });
+    }
+
+    let node_path = format!("/{}", path);
+    if let Some(node) = fdt.find_node(&node_path) {
+        return Ok(HandleKind::Node {
+            path: String::from(path),
+            entries: node_entries(&node),
+        });
+    }
+
+    let (parent, name) = node_path.rsplit_once('/').ok_or(Error::new(ENOENT))?;
+    let parent = fdt
+        .find_node(if parent.is_empty() { "/" } else { parent })
+        .ok_or(Error::new(ENOENT))?;
+    let property = parent.property(name).ok_or(Error::new(ENOENT))?;
+    Ok(HandleKind::Data {
+        path: String::from(path),
+        data: property.value.into(),
+    })
+}
+
 impl DtbScheme {
     pub fn init() {
         let mut data_init = false;
@@ -54,28 +136,39 @@ impl KernelScheme for DtbScheme {
     fn kopen(
         &self,
         path: &str,
-        _flags: usize,
+        flags: usize,
         _ctx: CallerCtx,
         token: &mut CleanLockToken,
     ) -> Result<OpenResult> {
         let path = path.trim_matches('/');
+        let stat = flags & O_STAT == O_STAT;
 
-        if path.is_empty() {
-            let id = NEXT_FD.fetch_add(1, atomic::Ordering::Relaxed);
-
-            let mut handles_guard = HANDLES.write(token.token());
+        if flags & O_CREAT == O_CREAT || (flags & O_ACCMODE != O_RDONLY && !stat) {
+            return Err(Error::new(EROFS));
+        }
 
-            let _ = handles_guard.insert(
-                id,
-                Handle {
-                    kind: HandleKind::RawData,
-                    stat: _flags & O_STAT == O_STAT,
-                },
-            );
-            return Ok(OpenResult::SchemeLocal(id, InternalFlags::POSITIONED));
+        let kind = if path.is_empty() {
+            HandleKind::RawData
+        } else {
+            lookup(path)?
+        };
+        match kind {
+            HandleKind::Node { .. } if flags & O_DIRECTORY != O_DIRECTORY && !stat => {
+                return Err(Error::new(EISDIR));
+            }
+            HandleKind::RawData | HandleKind::Data { .. }
+                if flags & O_DIRECTORY == O_DIRECTORY && !stat =>
+            {
+                return Err(Error::new(ENOTDIR));
+            }
+            _ => (),
         }
 
-        Err(Error::new(ENOENT))
+        let id = NEXT_FD.fetch_add(1, atomic::Ordering::Relaxed);
+        HANDLES
+            .write(token.token())
+            .insert(id, Handle { kind, stat });
+        Ok(OpenResult::SchemeLocal(id, InternalFlags::POSITIONED))
     }
 
     fn fsize(&self, id: usize, token: &mut CleanLockToken) -> Result<u64> {
@@ -88,6 +181,8 @@ impl KernelScheme for DtbScheme {
 
         let file_len = match handle.kind {
             HandleKind::RawData => DATA.get().ok_or(Error::new(EBADFD))?.len(),
+            HandleKind::Data { ref data, .. } => data.len(),
+            HandleKind::Node { .. } => 0,
         };
 
         Ok(file_len as u64)
@@ -118,6 +213,8 @@ impl KernelScheme for DtbScheme {
 
         let data = match handle.kind {
             HandleKind::RawData => DATA.get().ok_or(Error::new(EBADFD))?,
+            HandleKind::Data { ref data, .. } => data,
+            HandleKind::Node { .. } => return Err(Error::new(EISDIR)),
         };
 
         let src_offset = core::cmp::min(offset.try_into().unwrap(), data.len());
@@ -128,12 +225,44 @@ impl KernelScheme for DtbScheme {
         dst_buf.copy_common_bytes_from_slice(src_buf)
     }
 
+    fn getdents(
+        &self,
+        id: usize,
+        buf: UserSliceWo,
+        header_size: u16,
+        first_index: u64,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        let Ok(first_index) = usize::try_from(first_index) else {
+            return Ok(0);
+        };
+        let handles = HANDLES.read(token.token());
+        let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+        let HandleKind::Node { ref entries, .. } = handle.kind else {
+            return Err(Error::new(ENOTDIR));
+        };
+
+        let mut buf = DirentBuf::new(buf, header_size).ok_or(Error::new(EIO))?;
+        for (index, (name, kind)) in entries.iter().enumerate().skip(first_index) {
+            buf.entry(DirEntry {
+                inode: 0,
+                next_opaque_id: index as u64 + 1,
+                kind: *kind,
+                name,
+            })?;
+        }
+        Ok(buf.finalize())
+    }
+
     fn kfpath(&self, id: usize, buf: UserSliceWo, token: &mut CleanLockToken) -> Result<usize> {
         let handles = HANDLES.read(token.token());
         let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
 
         let path = match handle.kind {
             HandleKind::RawData => "kernel.dtb:",
+            HandleKind::Data { ref path, .. } | HandleKind::Node { ref path, .. } => {
+                return buf.copy_common_bytes_from_slice(format!("dtb:/{}", path).as_bytes());
+            }
         };
 
         buf.copy_common_bytes_from_slice(path.as_bytes())
@@ -153,6 +282,15 @@ impl KernelScheme for DtbScheme {
                     ..Default::default()
                 }
             }
+            HandleKind::Data { ref data, .. } => Stat {
+                st_mode: MODE_FILE | 0o444,
+                st_size: data.len() as u64,
+                ..Default::default()
+            },
+            HandleKind::Node { .. } => Stat {
+                st_mode: MODE_DIR | 0o555,
+                ..Default::default()
+            },
         })?;
 
         Ok(())
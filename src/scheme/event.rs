@@ -3,7 +3,7 @@ use core::mem;
 use syscall::{EventFlags, O_NONBLOCK};
 
 use crate::{
-    context::file::InternalFlags,
+    context::{self, file::InternalFlags},
     event::{next_queue_id, queues, queues_mut, EventQueue, EventQueueId},
     sync::CleanLockToken,
     syscall::{
@@ -15,6 +15,12 @@ use crate::{
 
 use super::{CallerCtx, KernelScheme, OpenResult};
 
+/// fcntl command letting an event queue hold `arg` pending events before it overflows, and
+/// returning the new capacity
+pub const F_SETEVENTQ_SZ: usize = 1050;
+/// fcntl command returning the number of pending events an event queue holds
+pub const F_GETEVENTQ_SZ: usize = 1051;
+
 pub struct EventScheme;
 
 impl KernelScheme for EventScheme {
@@ -22,11 +28,11 @@ impl KernelScheme for EventScheme {
         &self,
         _path: &str,
         _flags: usize,
-        _ctx: CallerCtx,
+        ctx: CallerCtx,
         token: &mut CleanLockToken,
     ) -> Result<OpenResult> {
         let id = next_queue_id();
-        queues_mut(token.token()).insert(id, Arc::new(EventQueue::new(id)));
+        queues_mut(token.token()).insert(id, Arc::new(EventQueue::new(id, ctx.uid)));
 
         Ok(OpenResult::SchemeLocal(id.get(), InternalFlags::empty()))
     }
@@ -87,8 +93,36 @@ impl KernelScheme for EventScheme {
         Ok(events_written * mem::size_of::<Event>())
     }
 
-    fn kfpath(&self, _id: usize, buf: UserSliceWo, _token: &mut CleanLockToken) -> Result<usize> {
-        buf.copy_common_bytes_from_slice(b"/scheme/event/")
+    fn fcntl(
+        &self,
+        id: usize,
+        cmd: usize,
+        arg: usize,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        let id = EventQueueId::from(id);
+
+        let queue = {
+            let handles = queues(token.token());
+            let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+            handle.clone()
+        };
+
+        match cmd {
+            F_SETEVENTQ_SZ => {
+                let uid = context::current().read(token.token()).euid;
+                queue.set_capacity(arg, uid)
+            }
+            F_GETEVENTQ_SZ => Ok(queue.capacity()),
+            _ => Ok(0),
+        }
+    }
+
+    fn kfpath(&self, id: usize, buf: UserSliceWo, token: &mut CleanLockToken) -> Result<usize> {
+        if !queues(token.token()).contains_key(&EventQueueId::from(id)) {
+            return Err(Error::new(EBADF));
+        }
+        buf.copy_path(format!("event:{}", id).as_bytes())
     }
 
     fn fevent(
@@ -10,6 +10,7 @@
 //! - **Memory-mapped I/O** for efficient GPU access
 //! - **Priority-aware GPU scheduling**
 //! - **Zero-copy buffer sharing** between processes
+//! - **Completion events** so clients can wait on a queue instead of polling
 //!
 //! ## Security Model
 //!
@@ -18,7 +19,12 @@
 //! - Memory regions are isolated per-process
 //! - Root namespace only for privileged operations
 
-use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
 use core::{
     num::NonZeroUsize,
     sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
@@ -29,17 +35,25 @@ use crate::{
     context::{
         self,
         file::InternalFlags,
-        memory::{AddrSpaceWrapper, Grant, PageSpan},
-        ContextId,
+        kthread,
+        memory::{handle_notify_files, AddrSpaceWrapper, Grant, PageSpan},
+        timeout, ContextId,
+    },
+    cpu_set::LogicalCpuSet,
+    event,
+    memory::{
+        allocate_reserved_frame, deallocate_frame, Frame, PhysicalAddress, RmmA, RmmArch, PAGE_SIZE,
     },
-    memory::{allocate_frame, deallocate_frame, Frame, PhysicalAddress, RmmA, RmmArch, PAGE_SIZE},
     paging::{Page, PageFlags, VirtualAddress},
     scheme::{CallerCtx, FileHandle, KernelScheme, OpenResult, SchemeId},
-    sync::{CleanLockToken, IpcCriticalGuard, OptimizedWaitQueue},
+    sync::{CleanLockToken, IpcCriticalGuard, OptimizedWaitQueue, Priority, WaitCondition},
     syscall::{
         data::Map,
-        error::{Error, Result, EACCES, EBADF, EBUSY, EINVAL, ENOMEM, ENOSYS, EPERM},
-        flag::{MapFlags, O_CLOEXEC, O_RDWR},
+        error::{
+            Error, Result, EACCES, EAGAIN, EBADF, EBUSY, EINTR, EINVAL, ENOMEM, ENOSYS, EPERM,
+            ETIMEDOUT,
+        },
+        flag::{EventFlags, MapFlags, CLOCK_MONOTONIC, EVENT_READ, O_CLOEXEC, O_NONBLOCK, O_RDWR},
         usercopy::{UserSliceRo, UserSliceWo},
     },
 };
@@ -180,7 +194,7 @@ impl VramBuffer {
         let mut frames = Vec::with_capacity(num_pages);
 
         for _ in 0..num_pages {
-            let frame = allocate_frame().ok_or(Error::new(ENOMEM))?;
+            let frame = allocate_reserved_frame().ok_or(Error::new(ENOMEM))?;
             // Zero the frame for security
             unsafe {
                 let ptr = RmmA::phys_to_virt(frame.base()).data() as *mut u8;
@@ -261,6 +275,29 @@ pub enum CmdBufState {
     Error,
 }
 
+/// Completion record returned by reading a GAL handle
+///
+/// Layout, little endian: command buffer id (u32), final [`CmdBufState`] (u32), fence (u64).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Completion {
+    pub cmdbuf_id: u32,
+    pub state: CmdBufState,
+    pub fence: u64,
+}
+
+impl Completion {
+    /// Size of one record in the byte stream read from a handle
+    pub const SIZE: usize = 16;
+
+    fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[0..4].copy_from_slice(&self.cmdbuf_id.to_le_bytes());
+        bytes[4..8].copy_from_slice(&(self.state as u32).to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.fence.to_le_bytes());
+        bytes
+    }
+}
+
 /// A GPU command buffer
 pub struct CommandBuffer {
     /// Unique command buffer ID
@@ -286,7 +323,7 @@ pub struct CommandBuffer {
 impl CommandBuffer {
     /// Create a new command buffer
     fn new(id: u32, owner_pid: usize, priority: u8) -> Result<Self> {
-        let frame = allocate_frame().ok_or(Error::new(ENOMEM))?;
+        let frame = allocate_reserved_frame().ok_or(Error::new(ENOMEM))?;
 
         // Zero the frame
         unsafe {
@@ -360,6 +397,8 @@ struct GalHandle {
     vram_buffers: BTreeMap<u32, Arc<VramBuffer>>,
     /// Command buffers owned by this handle
     cmd_buffers: BTreeMap<u32, Arc<CommandBuffer>>,
+    /// Completions not yet read, at most one per command buffer
+    completions: VecDeque<Completion>,
     /// Total VRAM allocated
     vram_used: AtomicUsize,
     /// Next buffer ID
@@ -372,6 +411,7 @@ impl GalHandle {
             pid,
             vram_buffers: BTreeMap::new(),
             cmd_buffers: BTreeMap::new(),
+            completions: VecDeque::new(),
             vram_used: AtomicUsize::new(0),
             next_id: AtomicU32::new(1),
         }
@@ -380,6 +420,13 @@ impl GalHandle {
     fn alloc_id(&self) -> u32 {
         self.next_id.fetch_add(1, Ordering::Relaxed)
     }
+
+    /// Queue a completion for reading, replacing an unread one of the same command buffer
+    fn push_completion(&mut self, completion: Completion) {
+        self.completions
+            .retain(|queued| queued.cmdbuf_id != completion.cmdbuf_id);
+        self.completions.push_back(completion);
+    }
 }
 
 // =============================================================================
@@ -388,14 +435,18 @@ impl GalHandle {
 
 /// Graphics Abstraction Layer Scheme
 pub struct GalScheme {
+    /// Scheme ID under which events are triggered
+    scheme_id: SchemeId,
     /// Open handles indexed by scheme handle ID
     handles: RwLock<BTreeMap<usize, Arc<RwLock<GalHandle>>>>,
     /// Next handle ID
     next_handle_id: AtomicUsize,
     /// Global fence counter
     global_fence: AtomicU64,
-    /// Command submission queue
-    submit_queue: OptimizedWaitQueue<u32>,
+    /// Command submission queue, of handle ID and command buffer ID
+    submit_queue: OptimizedWaitQueue<(usize, u32)>,
+    /// Notified whenever command buffers complete
+    completion: WaitCondition,
     /// GPU info cache
     gpu_info: GpuInfo,
 }
@@ -443,12 +494,14 @@ impl Default for GpuInfo {
 
 impl GalScheme {
     /// Create a new GAL scheme
-    pub fn new() -> Self {
+    pub fn new(scheme_id: SchemeId) -> Self {
         GalScheme {
+            scheme_id,
             handles: RwLock::new(BTreeMap::new()),
             next_handle_id: AtomicUsize::new(1),
             global_fence: AtomicU64::new(0),
             submit_queue: OptimizedWaitQueue::new(),
+            completion: WaitCondition::new(),
             gpu_info: GpuInfo::default(),
         }
     }
@@ -519,6 +572,7 @@ impl GalScheme {
     /// Submit a command buffer for execution
     fn submit_cmdbuf(
         &self,
+        handle_id: usize,
         handle: &Arc<RwLock<GalHandle>>,
         cmdbuf_id: u32,
         token: &mut CleanLockToken,
@@ -547,11 +601,62 @@ impl GalScheme {
         let _ipc_guard = IpcCriticalGuard::new(&ctx_guard.priority);
 
         // Add to submission queue
-        self.submit_queue.send(cmdbuf_id, token);
+        self.submit_queue.send((handle_id, cmdbuf_id), token);
 
         Ok(fence)
     }
 
+    /// Consume the submission queue, returning how many command buffers finished
+    ///
+    /// Each buffer is moved to Complete or Error, a completion is queued on its handle and
+    /// EVENT_READ is triggered there, and waiters in [`Self::wait_complete`] are woken. Only
+    /// blocks while nothing has been consumed yet, if `block` is set.
+    pub fn process_submissions(&self, block: bool, token: &mut CleanLockToken) -> usize {
+        let mut consumed = 0;
+        let mut finished = 0;
+
+        while let Ok((handle_id, cmdbuf_id)) = self.submit_queue.receive(
+            block && consumed == 0,
+            "GalScheme::process_submissions",
+            token,
+        ) {
+            consumed += 1;
+            // The handle may have been closed since submission
+            let Some(handle) = self.handles.read().get(&handle_id).cloned() else {
+                continue;
+            };
+
+            {
+                let mut handle_guard = handle.write();
+                let Some(cmdbuf) = handle_guard.cmd_buffers.get(&cmdbuf_id).cloned() else {
+                    continue;
+                };
+
+                // No GPU backend executes the commands yet, so a submission finishes as soon
+                // as it is consumed, failing if the buffer no longer validates
+                let state = match cmdbuf.validate() {
+                    Ok(()) => CmdBufState::Complete,
+                    Err(_) => CmdBufState::Error,
+                };
+                // Set under the handle lock, see wait_complete
+                cmdbuf.set_state(state);
+                handle_guard.push_completion(Completion {
+                    cmdbuf_id,
+                    state,
+                    fence: cmdbuf.fence.load(Ordering::Acquire),
+                });
+            }
+
+            event::trigger(self.scheme_id, handle_id, EVENT_READ, token);
+            finished += 1;
+        }
+
+        if finished > 0 {
+            self.completion.notify(token);
+        }
+        finished
+    }
+
     /// Wait for a command buffer to complete
     fn wait_complete(
         &self,
@@ -560,31 +665,65 @@ impl GalScheme {
         timeout_ns: u64,
         token: &mut CleanLockToken,
     ) -> Result<()> {
-        let handle_guard = handle.read();
-
-        let cmdbuf = handle_guard
+        let cmdbuf = handle
+            .read()
             .cmd_buffers
             .get(&cmdbuf_id)
+            .cloned()
             .ok_or(Error::new(EBADF))?;
 
-        let deadline = crate::time::monotonic() as u64 + timeout_ns;
+        let deadline = crate::time::monotonic() + timeout_ns as u128;
 
-        loop {
+        let context_lock = context::current();
+        let weak = Arc::downgrade(&context_lock);
+        timeout::register_wakeup(weak.clone(), CLOCK_MONOTONIC, deadline, token);
+
+        let result = loop {
+            // Completions change the state under the handle lock, which is only released once
+            // this context is queued on the condition, so none can be missed
+            let handle_guard = handle.read();
             match cmdbuf.state() {
-                CmdBufState::Complete => return Ok(()),
-                CmdBufState::Error => return Err(Error::new(EINVAL)),
+                CmdBufState::Complete => break Ok(()),
+                CmdBufState::Error => break Err(Error::new(EINVAL)),
                 _ => {}
             }
 
-            if crate::time::monotonic() as u64 > deadline {
-                return Err(Error::new(crate::syscall::error::ETIMEDOUT));
+            if crate::time::monotonic() >= deadline {
+                break Err(Error::new(ETIMEDOUT));
+            }
+
+            // Makes the timer fire in time for the registered wakeup
+            context_lock.write(token.token()).wake = Some(deadline);
+            if !self
+                .completion
+                .wait(handle_guard, "GalScheme::wait_complete", token)
+                && crate::time::monotonic() < deadline
+            {
+                break Err(Error::new(EINTR));
             }
+        };
 
-            // Yield
-            drop(handle_guard);
-            unsafe { context::switch(token) };
-            return self.wait_complete(handle, cmdbuf_id, timeout_ns, token);
+        timeout::cancel_wakeup(&weak, token);
+        context_lock.write(token.token()).wake = None;
+
+        result
+    }
+
+    /// Copy as many unread completions of a handle as fit in `buf`
+    fn read_completions(&self, handle: &RwLock<GalHandle>, buf: UserSliceWo) -> Result<usize> {
+        let mut handle_guard = handle.write();
+        let mut bytes_read = 0;
+
+        for chunk in buf.in_exact_chunks(Completion::SIZE) {
+            let Some(completion) = handle_guard.completions.front() else {
+                break;
+            };
+            chunk.copy_from_slice(&completion.to_bytes())?;
+            handle_guard.completions.pop_front();
+            bytes_read += Completion::SIZE;
         }
+
+        Ok(bytes_read)
     }
 }
 
@@ -615,7 +754,7 @@ impl KernelScheme for GalScheme {
         addr_space: &Arc<AddrSpaceWrapper>,
         map: &Map,
         _consume: bool,
-        _token: &mut CleanLockToken,
+        token: &mut CleanLockToken,
     ) -> Result<usize> {
         let handles = self.handles.read();
         let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
@@ -638,12 +777,13 @@ impl KernelScheme for GalScheme {
         // Get the first frame for mapping
         let first_frame = buffer.frames.first().ok_or(Error::new(EINVAL))?;
 
+        let mut notify_files = Vec::new();
         let base_page = addr_space.acquire_write().mmap(
             (map.address != 0)
                 .then_some(Page::containing_address(VirtualAddress::new(map.address))),
             page_count,
             map.flags,
-            &mut Vec::new(),
+            &mut notify_files,
             |dst_page, page_flags, dst_mapper, dst_flusher| {
                 Grant::physmap(
                     *first_frame,
@@ -652,12 +792,16 @@ impl KernelScheme for GalScheme {
                     dst_mapper,
                     dst_flusher,
                 )
+                .map(|grant| grant.named("gal-vram"))
             },
         )?;
 
         let addr = base_page.start_address().data();
         buffer.mapped_addr.store(addr, Ordering::Release);
         buffer.set_state(VramState::Mapped);
+        drop(handle_guard);
+        drop(handles);
+        handle_notify_files(notify_files, token);
 
         Ok(addr)
     }
@@ -670,13 +814,11 @@ impl KernelScheme for GalScheme {
         _stored_flags: u32,
         token: &mut CleanLockToken,
     ) -> Result<usize> {
-        if buf.len() < 8 {
-            return Err(Error::new(EINVAL));
-        }
-
         // Parse command from buffer
         let mut cmd_data = [0u8; 8];
-        buf.copy_to_slice(&mut cmd_data)?;
+        buf.limit(cmd_data.len())
+            .ok_or(Error::new(EINVAL))?
+            .copy_to_slice(&mut cmd_data)?;
 
         let cmd_code = u32::from_le_bytes([cmd_data[0], cmd_data[1], cmd_data[2], cmd_data[3]]);
         let cmd_param = u32::from_le_bytes([cmd_data[4], cmd_data[5], cmd_data[6], cmd_data[7]]);
@@ -688,7 +830,9 @@ impl KernelScheme for GalScheme {
 
         match command {
             GalCommand::AllocVram => {
-                let size = cmd_param as usize * PAGE_SIZE;
+                let size = (cmd_param as usize)
+                    .checked_mul(PAGE_SIZE)
+                    .ok_or(Error::new(EINVAL))?;
                 let flags = VramFlags::empty();
                 let buffer_id = self.alloc_vram(handle, size, flags)?;
                 Ok(buffer_id as usize)
@@ -703,43 +847,115 @@ impl KernelScheme for GalScheme {
                 Ok(cmdbuf_id as usize)
             }
             GalCommand::SubmitCmdBuf => {
-                let fence = self.submit_cmdbuf(handle, cmd_param, token)?;
+                let fence = self.submit_cmdbuf(id, handle, cmd_param, token)?;
                 Ok(fence as usize)
             }
             GalCommand::WaitComplete => {
                 let timeout_ns = 5_000_000_000u64; // 5 second default
-                self.wait_complete(handle, cmd_param, timeout_ns, token)?;
+                let handle = Arc::clone(handle);
+                drop(handles);
+                self.wait_complete(&handle, cmd_param, timeout_ns, token)?;
                 Ok(0)
             }
+            GalCommand::QueryInfo => match cmd_param {
+                0 => Ok(self.gpu_info.vendor_id as usize),
+                1 => Ok(self.gpu_info.device_id as usize),
+                2 => Ok(self.gpu_info.vram_size as usize),
+                3 => Ok(self.gpu_info.capabilities.bits() as usize),
+                _ => Err(Error::new(EINVAL)),
+            },
             _ => Err(Error::new(ENOSYS)),
         }
     }
 
-    fn kread(
+    fn fevent(
         &self,
         id: usize,
-        buf: UserSliceWo,
-        _flags: u32,
-        _stored_flags: u32,
+        flags: EventFlags,
         _token: &mut CleanLockToken,
-    ) -> Result<usize> {
-        // Read GPU info
+    ) -> Result<EventFlags> {
         let handles = self.handles.read();
-        let _handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+        let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+
+        let mut ready = EventFlags::empty();
+        if flags.contains(EVENT_READ) && !handle.read().completions.is_empty() {
+            ready |= EVENT_READ;
+        }
+        Ok(ready)
+    }
 
-        if buf.len() < 16 {
+    /// Read completion records, see [`Completion`]
+    ///
+    /// GPU information is queried with [`GalCommand::QueryInfo`] instead.
+    fn kread(
+        &self,
+        id: usize,
+        buf: UserSliceWo,
+        flags: u32,
+        stored_flags: u32,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        if buf.len() < Completion::SIZE {
             return Err(Error::new(EINVAL));
         }
 
-        // Return GPU info as bytes
-        let mut info_data = [0u8; 16];
-        info_data[0..4].copy_from_slice(&self.gpu_info.vendor_id.to_le_bytes());
-        info_data[4..8].copy_from_slice(&self.gpu_info.device_id.to_le_bytes());
-        info_data[8..16].copy_from_slice(&self.gpu_info.vram_size.to_le_bytes());
+        let handle = self
+            .handles
+            .read()
+            .get(&id)
+            .cloned()
+            .ok_or(Error::new(EBADF))?;
+        let nonblock = (flags | stored_flags) & O_NONBLOCK as u32 != 0;
 
-        buf.copy_from_slice(&info_data)?;
+        loop {
+            let bytes_read = self.read_completions(&handle, buf)?;
+            if bytes_read > 0 {
+                return Ok(bytes_read);
+            }
+            if nonblock {
+                return Err(Error::new(EAGAIN));
+            }
 
-        Ok(16)
+            // Completions are queued under the handle lock, held until we are queued
+            let handle_guard = handle.read();
+            if !handle_guard.completions.is_empty() {
+                continue;
+            }
+            if !self.completion.wait(handle_guard, "GalScheme::read", token) {
+                return Err(Error::new(EINTR));
+            }
+        }
+    }
+}
+
+// =============================================================================
+// Submission worker
+// =============================================================================
+
+/// The scheme registered as `gal:`, whose submissions the worker consumes
+static GAL: spin::Once<Arc<GalScheme>> = spin::Once::new();
+
+/// Create the scheme registered under `scheme_id`, to be driven by [`spawn_worker`]
+pub fn init(scheme_id: SchemeId) -> Arc<GalScheme> {
+    GAL.call_once(|| Arc::new(GalScheme::new(scheme_id)))
+        .clone()
+}
+
+/// Spawn the thread completing command buffers submitted to `gal:`
+pub fn spawn_worker(token: &mut CleanLockToken) {
+    match kthread::spawn("[gal]", LogicalCpuSet::all(), Priority::High, worker, token) {
+        Ok(handle) => handle.detach(token),
+        Err(err) => warn!("Failed to spawn the GAL submission thread: {}", err),
+    }
+}
+
+fn worker() {
+    let mut token = unsafe { CleanLockToken::new() };
+    let Some(scheme) = GAL.get() else {
+        return;
+    };
+    while !kthread::should_stop() {
+        scheme.process_submissions(true, &mut token);
     }
 }
 
@@ -766,6 +982,38 @@ mod tests {
         assert!(!caps.contains(GpuCapabilities::COMPUTE));
     }
 
+    #[test]
+    fn test_completion_record() {
+        let completion = Completion {
+            cmdbuf_id: 7,
+            state: CmdBufState::Complete,
+            fence: 0x0102_0304_0506_0708,
+        };
+        let bytes = completion.to_bytes();
+        assert_eq!(&bytes[0..4], &7u32.to_le_bytes());
+        assert_eq!(&bytes[4..8], &(CmdBufState::Complete as u32).to_le_bytes());
+        assert_eq!(&bytes[8..16], &0x0102_0304_0506_0708u64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_completions_coalesce_per_cmdbuf() {
+        let mut handle = GalHandle::new(1);
+        for (cmdbuf_id, fence) in [(1, 10), (2, 11), (1, 12)] {
+            handle.push_completion(Completion {
+                cmdbuf_id,
+                state: CmdBufState::Complete,
+                fence,
+            });
+        }
+
+        let fences = handle
+            .completions
+            .iter()
+            .map(|c| (c.cmdbuf_id, c.fence))
+            .collect::<Vec<_>>();
+        assert_eq!(fences, [(2, 11), (1, 12)]);
+    }
+
     #[test]
     fn test_gal_command() {
         assert_eq!(GalCommand::try_from(0x4001), Ok(GalCommand::AllocVram));
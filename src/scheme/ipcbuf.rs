@@ -0,0 +1,180 @@
+//! Userspace access to the IPC shared buffer pool
+//!
+//! Opening `ipcbuf:` allocates a buffer owned by the caller, while `ipcbuf:<id>` attaches to an
+//! existing one, which only its owner or a peer it has been shared with may do. Reading a handle
+//! returns the buffer ID as a little-endian u32, and writing a peer's process ID as a usize
+//! shares the buffer with that peer. fmap maps the buffer read-write into the caller.
+//!
+//! Every handle and every grant mapping a buffer holds a reference to it, which goes back to the
+//! pool once the last one is dropped. Grants own theirs through [`Provider::IpcBuffer`], so forks
+//! and partial unmaps need no bookkeeping here.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    mem::size_of,
+    num::NonZeroUsize,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use hashbrown::{hash_map::DefaultHashBuilder, HashMap};
+
+use crate::{
+    context::{
+        self,
+        file::InternalFlags,
+        memory::{handle_notify_files, AddrSpaceWrapper, Grant, PageSpan, Provider},
+    },
+    ipc::{self, BufferRef, SharedBufferPool, SHARED_BUFFER_SIZE},
+    paging::{Page, VirtualAddress, PAGE_SIZE},
+    sync::{CleanLockToken, RwLock, L1},
+    syscall::{
+        data::Map,
+        error::{Error, Result, EBADF, EINVAL, ENODEV, ENOENT, ENOMEM},
+        flag::MapFlags,
+        usercopy::{UserSliceRo, UserSliceWo},
+    },
+};
+
+use super::{CallerCtx, KernelScheme, OpenResult};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Pages of a shared buffer, checked to be at least one when building
+const BUFFER_PAGES: NonZeroUsize = match NonZeroUsize::new(SHARED_BUFFER_SIZE / PAGE_SIZE) {
+    Some(pages) => pages,
+    None => panic!("shared buffers must be at least a page"),
+};
+
+/// Buffer ID of every open handle
+static HANDLES: RwLock<L1, HashMap<usize, u32>> =
+    RwLock::new(HashMap::with_hasher(DefaultHashBuilder::new()));
+
+fn pool() -> Result<&'static SharedBufferPool> {
+    ipc::registry().buffer_pool().ok_or(Error::new(ENODEV))
+}
+
+fn current_pid(token: &mut CleanLockToken) -> usize {
+    context::current().read(token.token()).pid
+}
+
+pub struct IpcBufScheme;
+
+impl KernelScheme for IpcBufScheme {
+    fn kopen(
+        &self,
+        path: &str,
+        _flags: usize,
+        ctx: CallerCtx,
+        token: &mut CleanLockToken,
+    ) -> Result<OpenResult> {
+        let pool = pool()?;
+        let path = path.trim_start_matches('/');
+
+        let buffer_id = if path.is_empty() {
+            pool.allocate(ctx.pid).ok_or(Error::new(ENOMEM))?
+        } else {
+            let buffer_id = path.parse::<u32>().map_err(|_| Error::new(ENOENT))?;
+            pool.retain(buffer_id, ctx.pid)?;
+            buffer_id
+        };
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        HANDLES.write(token.token()).insert(id, buffer_id);
+
+        Ok(OpenResult::SchemeLocal(id, InternalFlags::empty()))
+    }
+
+    fn close(&self, id: usize, token: &mut CleanLockToken) -> Result<()> {
+        let buffer_id = HANDLES
+            .write(token.token())
+            .remove(&id)
+            .ok_or(Error::new(EBADF))?;
+        pool()?.put(buffer_id);
+        Ok(())
+    }
+
+    fn kread(
+        &self,
+        id: usize,
+        buf: UserSliceWo,
+        _flags: u32,
+        _stored_flags: u32,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        let buffer_id = *HANDLES
+            .read(token.token())
+            .get(&id)
+            .ok_or(Error::new(EBADF))?;
+        buf.write_u32(buffer_id)?;
+        Ok(size_of::<u32>())
+    }
+
+    fn kwrite(
+        &self,
+        id: usize,
+        buf: UserSliceRo,
+        _flags: u32,
+        _stored_flags: u32,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        let buffer_id = *HANDLES
+            .read(token.token())
+            .get(&id)
+            .ok_or(Error::new(EBADF))?;
+        let peer = buf.read_usize()?;
+        if peer == 0 {
+            return Err(Error::new(EINVAL));
+        }
+
+        let caller = current_pid(token);
+        let buffer = pool()?.get(buffer_id).ok_or(Error::new(EBADF))?;
+        buffer.share(caller, peer)?;
+
+        Ok(size_of::<usize>())
+    }
+
+    fn kfmap(
+        &self,
+        id: usize,
+        addr_space: &Arc<AddrSpaceWrapper>,
+        map: &Map,
+        _consume: bool,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        if map.offset != 0 || map.size == 0 || map.size > SHARED_BUFFER_SIZE {
+            return Err(Error::new(EINVAL));
+        }
+        let buffer_id = *HANDLES
+            .read(token.token())
+            .get(&id)
+            .ok_or(Error::new(EBADF))?;
+
+        // The handle may have been passed on, so check whoever is mapping it now
+        let pool = pool()?;
+        let frame = pool.retain(buffer_id, current_pid(token))?.frame();
+        let buffer = BufferRef::new(buffer_id);
+        let page_count = BUFFER_PAGES;
+
+        let mut notify_files = Vec::new();
+        let base = addr_space.acquire_write().mmap(
+            (map.address != 0)
+                .then_some(Page::containing_address(VirtualAddress::new(map.address))),
+            page_count,
+            map.flags | MapFlags::PROT_READ | MapFlags::PROT_WRITE,
+            &mut notify_files,
+            |dst_page, page_flags, dst_mapper, dst_flusher| {
+                let mut grant = Grant::physmap(
+                    frame,
+                    PageSpan::new(dst_page, page_count.get()),
+                    page_flags,
+                    dst_mapper,
+                    dst_flusher,
+                )?;
+                grant.provider = Provider::IpcBuffer { buffer };
+                Ok(grant)
+            },
+        )?;
+        handle_notify_files(notify_files, token);
+
+        Ok(base.start_address().data())
+    }
+}
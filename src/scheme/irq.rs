@@ -4,7 +4,7 @@
 use core::{
     mem, str,
     str::FromStr,
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 
 use alloc::{string::String, vec::Vec};
@@ -50,6 +50,13 @@ const BASE_IRQ_COUNT: u8 = 16;
 /// are only freed when the file descriptor is closed.
 const TOTAL_IRQ_COUNT: u8 = 224;
 
+/// The largest number of vectors a single `irq:msi/<count>` handle may allocate, matching the
+/// 32 vector limit of multiple-message MSI.
+const MAX_MSI_COUNT: u8 = 32;
+
+/// Serializes searching for and reserving a block of free vectors for MSI.
+static MSI_ALLOC: Mutex<()> = Mutex::new(());
+
 const INO_TOPLEVEL: u64 = 0x8002_0000_0000_0000;
 const INO_AVAIL: u64 = 0x8000_0000_0000_0000;
 const INO_BSP: u64 = 0x8001_0000_0000_0000;
@@ -62,17 +69,7 @@ pub fn irq_trigger(irq: u8, token: &mut CleanLockToken) {
     let fds: Vec<usize> = HANDLES
         .read(token.token())
         .iter()
-        .filter_map(|(fd, handle)| {
-            handle.as_irq_handle().and_then(
-                |(_, handle_irq)| {
-                    if handle_irq == irq {
-                        Some(*fd)
-                    } else {
-                        None
-                    }
-                },
-            )
-        })
+        .filter_map(|(fd, handle)| handle.receives(irq).then_some(*fd))
         .collect();
 
     for fd in fds {
@@ -82,16 +79,51 @@ pub fn irq_trigger(irq: u8, token: &mut CleanLockToken) {
 
 #[allow(dead_code)]
 enum Handle {
-    Irq { ack: AtomicUsize, irq: u8 },
+    Irq {
+        ack: AtomicUsize,
+        irq: u8,
+    },
+    /// A block of `count` IRQs starting at `base`, allocated for MSI on the BSP. The first read
+    /// returns the base interrupt vector, after which it behaves like `Irq` for the whole block.
+    Msi {
+        ack: AtomicUsize,
+        base: u8,
+        count: u8,
+        base_read: AtomicBool,
+    },
     Avail(LogicalCpuId),
     TopLevel,
     Phandle(u8, Vec<u8>),
     Bsp,
 }
 impl Handle {
-    fn as_irq_handle(&self) -> Option<(&AtomicUsize, u8)> {
-        match self {
-            &Self::Irq { ref ack, irq } => Some((ack, irq)),
+    /// Returns true if an occurrence of `irq` should be delivered to this handle
+    fn receives(&self, irq: u8) -> bool {
+        match *self {
+            Self::Irq {
+                irq: handle_irq, ..
+            } => handle_irq == irq,
+            Self::Msi { base, count, .. } => (base..base + count).contains(&irq),
+            _ => false,
+        }
+    }
+
+    /// The IRQ counter and acknowledged value for handles that deliver interrupts
+    fn irq_counts(&self) -> Option<(&AtomicUsize, usize)> {
+        match *self {
+            Self::Irq { ref ack, irq } => Some((ack, COUNTS.lock()[irq as usize])),
+            Self::Msi {
+                ref ack,
+                base,
+                count,
+                ..
+            } => {
+                let counts = COUNTS.lock();
+                let current = counts[usize::from(base)..usize::from(base + count)]
+                    .iter()
+                    .fold(0usize, |sum, count| sum.wrapping_add(*count));
+                Some((ack, current))
+            }
             _ => None,
         }
     }
@@ -211,6 +243,44 @@ impl IrqScheme {
     }
 }
 
+impl IrqScheme {
+    /// Reserve `count` contiguous vectors on the BSP for MSI, returning the first IRQ number.
+    ///
+    /// Multiple-message MSI devices modify the low bits of the message data, so the block is
+    /// aligned to the next power of two of `count`.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn open_msi(count_str: &str) -> Result<(Handle, InternalFlags)> {
+        let count = u8::from_str(count_str).or(Err(Error::new(ENOENT)))?;
+        if count == 0 || count > MAX_MSI_COUNT {
+            return Err(Error::new(EINVAL));
+        }
+        let align = count.next_power_of_two();
+
+        let _guard = MSI_ALLOC.lock();
+
+        let base = (BASE_IRQ_COUNT..=TOTAL_IRQ_COUNT - count)
+            .filter(|irq| irq_to_vector(*irq) % align == 0)
+            .find(|&base| {
+                (base..base + count).all(|irq| !is_reserved(LogicalCpuId::BSP, irq_to_vector(irq)))
+            })
+            .ok_or(Error::new(ENOMEM))?;
+
+        for irq in base..base + count {
+            set_reserved(LogicalCpuId::BSP, irq_to_vector(irq), true);
+        }
+
+        Ok((
+            Handle::Msi {
+                ack: AtomicUsize::new(0),
+                base,
+                count,
+                base_read: AtomicBool::new(false),
+            },
+            InternalFlags::empty(),
+        ))
+    }
+}
+
 const fn irq_to_vector(irq: u8) -> u8 {
     irq + 32
 }
@@ -258,6 +328,16 @@ impl crate::scheme::KernelScheme for IrqScheme {
             (Handle::TopLevel, InternalFlags::POSITIONED)
         } else if path_str == "bsp" {
             (Handle::Bsp, InternalFlags::empty())
+        } else if let Some(count_str) = path_str.strip_prefix("msi/") {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            {
+                Self::open_msi(count_str)?
+            }
+            #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+            {
+                let _ = count_str;
+                return Err(Error::new(ENOENT));
+            }
         } else if path_str.starts_with("cpu-") {
             let path_str = &path_str[4..];
             let cpu_id = u8::from_str_radix(&path_str[..2], 16).or(Err(Error::new(ENOENT)))?;
@@ -395,11 +475,26 @@ impl crate::scheme::KernelScheme for IrqScheme {
 
     fn fevent(
         &self,
-        _id: usize,
-        _flags: EventFlags,
-        _token: &mut CleanLockToken,
+        id: usize,
+        flags: EventFlags,
+        token: &mut CleanLockToken,
     ) -> Result<EventFlags> {
-        Ok(EventFlags::empty())
+        let handles_guard = HANDLES.read(token.token());
+        let handle = handles_guard.get(&id).ok_or(Error::new(EBADF))?;
+
+        let pending = match handle {
+            Handle::Msi { base_read, .. } if !base_read.load(Ordering::SeqCst) => true,
+            Handle::Msi { .. } => handle
+                .irq_counts()
+                .is_some_and(|(ack, current)| ack.load(Ordering::SeqCst) != current),
+            _ => false,
+        };
+
+        Ok(if pending {
+            flags & EVENT_READ
+        } else {
+            EventFlags::empty()
+        })
     }
 
     fn fsync(&self, _file: usize, _token: &mut CleanLockToken) -> Result<()> {
@@ -407,16 +502,26 @@ impl crate::scheme::KernelScheme for IrqScheme {
     }
 
     fn close(&self, id: usize, token: &mut CleanLockToken) -> Result<()> {
-        let handles_guard = HANDLES.read(token.token());
-        let handle = handles_guard.get(&id).ok_or(Error::new(EBADF))?;
+        let handle = HANDLES
+            .write(token.token())
+            .remove(&id)
+            .ok_or(Error::new(EBADF))?;
 
-        if let &Handle::Irq {
-            irq: handle_irq, ..
-        } = handle
-        {
-            if handle_irq > BASE_IRQ_COUNT {
-                set_reserved(LogicalCpuId::BSP, irq_to_vector(handle_irq), false);
+        match handle {
+            Handle::Irq {
+                irq: handle_irq, ..
+            } => {
+                if handle_irq > BASE_IRQ_COUNT {
+                    set_reserved(LogicalCpuId::BSP, irq_to_vector(handle_irq), false);
+                }
+            }
+            Handle::Msi { base, count, .. } => {
+                let _guard = MSI_ALLOC.lock();
+                for irq in base..base + count {
+                    set_reserved(LogicalCpuId::BSP, irq_to_vector(irq), false);
+                }
             }
+            _ => (),
         }
         Ok(())
     }
@@ -451,6 +556,20 @@ impl crate::scheme::KernelScheme for IrqScheme {
                 }
                 Ok(mem::size_of::<usize>())
             }
+            Handle::Msi { .. } => {
+                if buffer.len() < mem::size_of::<usize>() {
+                    return Err(Error::new(EINVAL));
+                }
+                let ack = buffer.read_usize()?;
+                let (handle_ack, current) = handle.irq_counts().expect("MSI handles have counts");
+
+                if ack != current {
+                    return Ok(0);
+                }
+                // MSIs are edge triggered and EOI'd by the generic handler, nothing to unmask
+                handle_ack.store(ack, Ordering::SeqCst);
+                Ok(mem::size_of::<usize>())
+            }
             _ => Err(Error::new(EBADF)),
         }
     }
@@ -471,6 +590,15 @@ impl crate::scheme::KernelScheme for IrqScheme {
                 st_nlink: 1,
                 ..Default::default()
             },
+            Handle::Msi { base, .. } => Stat {
+                st_mode: MODE_CHR | 0o600,
+                st_size: mem::size_of::<usize>() as u64,
+                st_blocks: 1,
+                st_blksize: mem::size_of::<usize>() as u32,
+                st_ino: base.into(),
+                st_nlink: 1,
+                ..Default::default()
+            },
             Handle::Bsp => Stat {
                 st_mode: MODE_CHR | 0o400,
                 st_size: mem::size_of::<usize>() as u64,
@@ -511,6 +639,7 @@ impl crate::scheme::KernelScheme for IrqScheme {
 
         let scheme_path = match handle {
             Handle::Irq { irq, .. } => format!("irq:{}", irq),
+            Handle::Msi { count, .. } => format!("irq:msi/{}", count),
             Handle::Bsp => format!("irq:bsp"),
             Handle::Avail(cpu_id) => format!("irq:cpu-{:2x}", cpu_id.get()),
             Handle::Phandle(phandle, _) => format!("irq:phandle-{}", phandle),
@@ -549,6 +678,26 @@ impl crate::scheme::KernelScheme for IrqScheme {
                     Ok(0)
                 }
             }
+            Handle::Msi {
+                base,
+                ref base_read,
+                ..
+            } => {
+                if buffer.len() < mem::size_of::<usize>() {
+                    return Err(Error::new(EINVAL));
+                }
+                if !base_read.swap(true, Ordering::SeqCst) {
+                    buffer.write_usize(irq_to_vector(base).into())?;
+                    return Ok(mem::size_of::<usize>());
+                }
+                let (handle_ack, current) = handle.irq_counts().expect("MSI handles have counts");
+                if handle_ack.load(Ordering::SeqCst) != current {
+                    buffer.write_usize(current)?;
+                    Ok(mem::size_of::<usize>())
+                } else {
+                    Ok(0)
+                }
+            }
             Handle::Bsp => {
                 if buffer.len() < mem::size_of::<usize>() {
                     return Err(Error::new(EINVAL));
@@ -1,16 +1,28 @@
-use core::num::NonZeroUsize;
+use core::{
+    num::NonZeroUsize,
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-use alloc::{sync::Arc, vec::Vec};
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use hashbrown::{hash_map::DefaultHashBuilder, HashMap};
 use rmm::PhysicalAddress;
 
 use crate::{
     context::{
+        self,
         file::InternalFlags,
-        memory::{handle_notify_files, AddrSpace, AddrSpaceWrapper, Grant, PageSpan},
+        memory::{handle_notify_files, page_flags, AddrSpace, AddrSpaceWrapper, Grant, PageSpan},
     },
-    memory::{free_frames, used_frames, Frame, PAGE_SIZE},
-    paging::VirtualAddress,
-    sync::CleanLockToken,
+    memory::{free_frames, init_p2frame, used_frames, Frame, RaiiFrame, PAGE_SIZE},
+    paging::{Page, PageFlags, RmmA, RmmArch, VirtualAddress},
+    sync::{CleanLockToken, RwLock, L1},
     syscall::usercopy::UserSliceRw,
 };
 
@@ -19,14 +31,211 @@ use crate::paging::entry::EntryFlags;
 use crate::syscall::{
     data::{Map, StatVfs},
     error::*,
-    flag::MapFlags,
+    flag::{MapFlags, MODE_CHR},
     usercopy::UserSliceWo,
+    MAP_DEVICE_MEMORY, MAP_HUGE, MAP_UNCACHEABLE, MAP_WRITE_COMBINING,
 };
 
-use super::{CallerCtx, KernelScheme, OpenResult};
+use super::{CallerCtx, HandleOwner, KernelScheme, OpenResult};
 
 pub struct MemoryScheme;
 
+/// Largest buffer a single open of `memory:phys_contiguous` may allocate.
+pub const MAX_PHYS_CONTIGUOUS_SIZE: usize = 2 * 1024 * 1024;
+/// Physical address `memory:phys_contiguous` buffers end at or below, so that devices that can
+/// only address 32 bits can reach them.
+pub const PHYS_CONTIGUOUS_LIMIT: u64 = 1 << 32;
+/// Blocks allocated above [`PHYS_CONTIGUOUS_LIMIT`] before a buffer fails with ENOMEM
+const PHYS_CONTIGUOUS_ATTEMPTS: usize = 8;
+
+/// A physically contiguous allocation, owned by an open `memory:phys_contiguous` handle.
+///
+/// Mappings of the buffer hold references of their own to its frames, which are shared with a
+/// forked child like any other shared memory. Each frame is only freed once the handle is closed
+/// and the last mapping of it is gone.
+struct ContiguousBuffer {
+    frames: Vec<RaiiFrame>,
+}
+
+impl ContiguousBuffer {
+    fn base(&self) -> Option<Frame> {
+        self.frames.first().map(RaiiFrame::get)
+    }
+
+    fn size(&self) -> usize {
+        self.frames.len().saturating_mul(PAGE_SIZE)
+    }
+}
+
+/// Whether `size` bytes from `base` end at or below [`PHYS_CONTIGUOUS_LIMIT`]
+fn below_contiguous_limit(base: PhysicalAddress, size: usize) -> bool {
+    (base.data() as u64)
+        .checked_add(size as u64)
+        .is_some_and(|end| end <= PHYS_CONTIGUOUS_LIMIT)
+}
+
+static NEXT_KEY: AtomicUsize = AtomicUsize::new(1);
+static CONTIGUOUS_BUFFERS: RwLock<L1, HashMap<usize, ContiguousBuffer>> =
+    RwLock::new(HashMap::with_hasher(DefaultHashBuilder::new()));
+
+/// Anonymous objects of `memory:zeroed` handles by handle ID, created by the first ftruncate
+static OBJECTS: RwLock<L1, HashMap<usize, AnonObject>> =
+    RwLock::new(HashMap::with_hasher(DefaultHashBuilder::new()));
+
+/// Every open handle by ID, whose bits above the handle type are a key unique to the handle
+static HANDLES: RwLock<L1, HashMap<usize, Handle>> =
+    RwLock::new(HashMap::with_hasher(DefaultHashBuilder::new()));
+
+struct Handle {
+    /// Type, memory type and flags as decoded by [`from_raw`], unused for contiguous buffers
+    raw: u32,
+    owner: HandleOwner,
+}
+
+/// Type, memory type and flags of a handle other than a contiguous buffer
+fn decode(id: usize, token: &mut CleanLockToken) -> Result<(HandleTy, MemoryType, HandleFlags)> {
+    let raw = HANDLES
+        .read(token.token())
+        .get(&id)
+        .ok_or(Error::new(EBADF))?
+        .raw;
+    from_raw(raw).ok_or(Error::new(EBADF))
+}
+
+/// Resizable shared memory behind a `memory:zeroed` handle, as used for shm_open
+///
+/// Until the handle is truncated to a nonzero length, mapping it hands out fresh zeroed memory
+/// each time as before. Afterwards every mapping shares the object's pages, whose frames are only
+/// allocated once a range covering them is mapped. Mappings hold their own references to the
+/// frames, so the object can go away with its handle while still mapped.
+#[derive(Default)]
+struct AnonObject {
+    len: usize,
+    /// Frames allocated so far, by page index
+    frames: BTreeMap<usize, RaiiFrame>,
+    /// Where the object has been mapped, so that truncating it can unmap the tail
+    mappings: Vec<ObjectMapping>,
+}
+
+struct ObjectMapping {
+    addr_space: Weak<AddrSpaceWrapper>,
+    base: Page,
+    /// Pages of the object mapped from `base` on
+    pages: Range<usize>,
+}
+
+impl ObjectMapping {
+    /// Page mapping `index` of the object, if this mapping covers it
+    fn page(&self, index: usize) -> Option<Page> {
+        self.pages
+            .contains(&index)
+            .then(|| self.base.next_by(index - self.pages.start))
+    }
+}
+
+/// Pages of an object of length `len` covered by a mapping of `size` bytes at `offset`
+///
+/// The whole mapping must lie within the object, rounded up to whole pages.
+fn object_pages(offset: usize, size: usize, len: usize) -> Result<Range<usize>> {
+    let end = offset.checked_add(size).ok_or(Error::new(EINVAL))?;
+    if offset % PAGE_SIZE != 0 || size == 0 || end > len.next_multiple_of(PAGE_SIZE) {
+        return Err(Error::new(EINVAL));
+    }
+    Ok(offset / PAGE_SIZE..end.div_ceil(PAGE_SIZE))
+}
+
+impl AnonObject {
+    /// Frames backing `pages`, allocating zeroed ones for pages not touched before
+    fn frames(&mut self, pages: Range<usize>) -> Result<Vec<Frame>> {
+        pages
+            .map(|index| {
+                if let Some(frame) = self.frames.get(&index) {
+                    return Ok(frame.get());
+                }
+                let frame = RaiiFrame::allocate()?;
+                unsafe {
+                    (RmmA::phys_to_virt(frame.get().base()).data() as *mut u8)
+                        .write_bytes(0, PAGE_SIZE);
+                }
+                let base = frame.get();
+                self.frames.insert(index, frame);
+                Ok(base)
+            })
+            .collect()
+    }
+
+    /// Change the length, unmapping and releasing the pages past a shorter one
+    ///
+    /// Fails with EBUSY without changing anything if any page to be released is mlocked.
+    fn truncate(&mut self, len: usize) -> Result<()> {
+        self.mappings
+            .retain(|mapping| mapping.addr_space.strong_count() > 0);
+
+        let keep = len.div_ceil(PAGE_SIZE);
+        if len < self.len {
+            let tail: Vec<usize> = self.frames.range(keep..).map(|(&index, _)| index).collect();
+            // Only pages still mapping the object's own frame are its, the user may have mapped
+            // something else over them since
+            let mapped = |addr_space: &AddrSpaceWrapper, page: Page, index: usize| {
+                let phys = addr_space
+                    .acquire_read()
+                    .table
+                    .utable
+                    .translate(page.start_address());
+                phys == Some(self.frames[&index].get().base())
+            };
+
+            for mapping in &self.mappings {
+                let Some(addr_space) = mapping.addr_space.upgrade() else {
+                    continue;
+                };
+                for &index in &tail {
+                    if let Some(page) = mapping.page(index)
+                        && mapped(&addr_space, page, index)
+                        && addr_space.acquire_read().is_locked(page, 1)
+                    {
+                        return Err(Error::new(EBUSY));
+                    }
+                }
+            }
+            for mapping in &self.mappings {
+                let Some(addr_space) = mapping.addr_space.upgrade() else {
+                    continue;
+                };
+                for &index in &tail {
+                    if let Some(page) = mapping.page(index)
+                        && mapped(&addr_space, page, index)
+                    {
+                        // Shoots down the TLB entries of every CPU using the address space
+                        addr_space
+                            .acquire_write()
+                            .munmap(PageSpan::new(page, 1), false)?;
+                    }
+                }
+            }
+
+            // The mappings dropped their references, so this frees the frames
+            drop(self.frames.split_off(&keep));
+            // Whatever the last page holds past the new length reads as zero if it grows again
+            if let Some(frame) = self.frames.get(&(keep.saturating_sub(1)))
+                && len % PAGE_SIZE != 0
+            {
+                unsafe {
+                    let page = RmmA::phys_to_virt(frame.get().base()).data() as *mut u8;
+                    page.add(len % PAGE_SIZE)
+                        .write_bytes(0, PAGE_SIZE - len % PAGE_SIZE);
+                }
+            }
+            for mapping in &mut self.mappings {
+                mapping.pages.end = mapping.pages.end.min(keep);
+            }
+            self.mappings.retain(|mapping| !mapping.pages.is_empty());
+        }
+        self.len = len;
+        Ok(())
+    }
+}
+
 // FIXME: Use crate that autogenerates conversion functions.
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -34,6 +243,12 @@ enum HandleTy {
     Allocated = 0,
     PhysBorrow = 1,
     Translation = 2,
+    // The remaining bits of the id are also a key into CONTIGUOUS_BUFFERS.
+    Contiguous = 3,
+}
+
+fn contiguous_key(id: usize) -> Option<usize> {
+    (id & 0xFF == HandleTy::Contiguous as usize).then_some(id >> 8)
 }
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -50,6 +265,72 @@ bitflags! {
     }
 }
 
+/// The memory type the cache attribute flags of an mmap call ask for, write-back without any
+fn map_memory_type(flags: MapFlags) -> MemoryType {
+    if flags.contains(MAP_DEVICE_MEMORY) {
+        MemoryType::DeviceMemory
+    } else if flags.contains(MAP_WRITE_COMBINING) {
+        MemoryType::WriteCombining
+    } else if flags.contains(MAP_UNCACHEABLE) {
+        MemoryType::Uncacheable
+    } else {
+        MemoryType::Writeback
+    }
+}
+
+/// `page_flags` with the cache attributes of `memory_type`, where the architecture has them
+fn cache_flags(page_flags: PageFlags<RmmA>, memory_type: MemoryType) -> PageFlags<RmmA> {
+    match memory_type {
+        // Default
+        MemoryType::Writeback => page_flags,
+
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))] // FIXME: AARCH64
+        MemoryType::WriteCombining => page_flags.custom_flag(EntryFlags::HUGE_PAGE.bits(), true),
+
+        MemoryType::Uncacheable => page_flags.custom_flag(EntryFlags::NO_CACHE.bits(), true),
+
+        // MemoryType::DeviceMemory doesn't exist on x86 && x86_64, which instead support
+        // uncacheable, write-combining, write-through, write-protect, and write-back.
+        #[cfg(target_arch = "aarch64")]
+        MemoryType::DeviceMemory => page_flags.custom_flag(EntryFlags::DEV_MEM.bits(), true),
+
+        _ => page_flags,
+    }
+}
+
+fn mem_ty_suffix(mem_ty: MemoryType) -> &'static str {
+    match mem_ty {
+        MemoryType::Writeback => "",
+        MemoryType::Uncacheable => "@uc",
+        MemoryType::WriteCombining => "@wc",
+        MemoryType::DeviceMemory => "@dev",
+    }
+}
+
+/// `memory:<kind>/<id>`, followed by `?` and the comma-separated `params` if there are any
+fn handle_path(id: usize, handle_ty: HandleTy, mem_ty: MemoryType, params: &[String]) -> String {
+    let kind = match handle_ty {
+        HandleTy::Allocated => "zeroed",
+        HandleTy::PhysBorrow => "physical",
+        HandleTy::Translation => "translation",
+        HandleTy::Contiguous => "phys_contiguous",
+    };
+    let mut path = format!("memory:{}{}/{}", kind, mem_ty_suffix(mem_ty), id);
+    if !params.is_empty() {
+        path.push('?');
+        path.push_str(&params.join(","));
+    }
+    path
+}
+
+/// Parse a size given either in decimal or as 0x-prefixed hexadecimal
+fn parse_size(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
 fn from_raw(raw: u32) -> Option<(HandleTy, MemoryType, HandleFlags)> {
     Some((
         match raw & 0xFF {
@@ -72,6 +353,78 @@ fn from_raw(raw: u32) -> Option<(HandleTy, MemoryType, HandleFlags)> {
 }
 
 impl MemoryScheme {
+    /// Allocate a zeroed, physically contiguous buffer of at least `size` bytes, lying below
+    /// [`PHYS_CONTIGUOUS_LIMIT`].
+    fn allocate_contiguous(size: usize) -> Result<ContiguousBuffer> {
+        if size == 0 || size > MAX_PHYS_CONTIGUOUS_SIZE {
+            return Err(Error::new(EINVAL));
+        }
+        let order = size
+            .div_ceil(PAGE_SIZE)
+            .next_power_of_two()
+            .trailing_zeros();
+
+        // Blocks above the limit are only freed once done, so that the allocator hands out others
+        let mut above_limit = Vec::new();
+        for _ in 0..PHYS_CONTIGUOUS_ATTEMPTS {
+            let buffer = ContiguousBuffer {
+                frames: init_p2frame(order)?,
+            };
+            if buffer
+                .base()
+                .is_some_and(|base| below_contiguous_limit(base.base(), buffer.size()))
+            {
+                return Ok(buffer);
+            }
+            above_limit.push(buffer);
+        }
+        Err(Error::new(ENOMEM))
+    }
+
+    /// Map part of the contiguous buffer `key`, with the cache attributes asked for by the
+    /// flags of the mmap call
+    fn fmap_contiguous(
+        key: usize,
+        addr_space: &Arc<AddrSpaceWrapper>,
+        map: &Map,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        let base = match map.address {
+            0 => None,
+            address => Some(
+                PageSpan::validate_nonempty(VirtualAddress::new(address), map.size)
+                    .ok_or(Error::new(EINVAL))?
+                    .base,
+            ),
+        };
+
+        let frames: Vec<Frame> = {
+            let buffers = CONTIGUOUS_BUFFERS.read(token.token());
+            let buffer = buffers.get(&key).ok_or(Error::new(EBADF))?;
+            let pages = object_pages(map.offset, map.size, buffer.size())?;
+            buffer
+                .frames
+                .get(pages)
+                .ok_or(Error::new(EINVAL))?
+                .iter()
+                .map(RaiiFrame::get)
+                .collect()
+        };
+
+        let flags = cache_flags(page_flags(map.flags), map_memory_type(map.flags));
+        let mut notify_files = Vec::new();
+        let page = addr_space.acquire_write().mmap_shared_frames(
+            base,
+            &frames,
+            map.flags,
+            flags,
+            &mut notify_files,
+        )?;
+        handle_notify_files(notify_files, token);
+
+        Ok(page.start_address().data())
+    }
+
     pub fn fmap_anonymous(
         addr_space: &Arc<AddrSpaceWrapper>,
         map: &Map,
@@ -89,6 +442,20 @@ impl MemoryScheme {
             return Err(Error::new(EOPNOTSUPP));
         }
 
+        if map.flags.contains(MAP_HUGE) {
+            if is_phys_contiguous {
+                return Err(Error::new(EOPNOTSUPP));
+            }
+            let page = addr_space.acquire_write().mmap_huge(
+                (map.address != 0).then_some(span.base),
+                page_count,
+                map.flags,
+                &mut notify_files,
+            )?;
+            handle_notify_files(notify_files, token);
+            return Ok(page.start_address().data());
+        }
+
         let page = addr_space.acquire_write().mmap(
             (map.address != 0).then_some(span.base),
             page_count,
@@ -114,6 +481,49 @@ impl MemoryScheme {
 
         Ok(page.start_address().data())
     }
+    /// Map part of the anonymous object of handle `id`, allocating the pages not touched before
+    fn fmap_object(
+        id: usize,
+        addr_space: &Arc<AddrSpaceWrapper>,
+        map: &Map,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        // Private mappings would have to copy the object on write
+        if !map.flags.contains(MapFlags::MAP_SHARED) {
+            return Err(Error::new(EOPNOTSUPP));
+        }
+        let base = match map.address {
+            0 => None,
+            address => Some(
+                PageSpan::validate_nonempty(VirtualAddress::new(address), map.size)
+                    .ok_or(Error::new(EINVAL))?
+                    .base,
+            ),
+        };
+
+        let mut objects = OBJECTS.write(token.token());
+        let object = objects.get_mut(&id).ok_or(Error::new(EBADF))?;
+        let pages = object_pages(map.offset, map.size, object.len)?;
+        let frames = object.frames(pages.clone())?;
+
+        let mut notify_files = Vec::new();
+        let page = addr_space.acquire_write().mmap_shared_frames(
+            base,
+            &frames,
+            map.flags,
+            page_flags(map.flags),
+            &mut notify_files,
+        )?;
+        object.mappings.push(ObjectMapping {
+            addr_space: Arc::downgrade(addr_space),
+            base: page,
+            pages,
+        });
+        drop(objects);
+        handle_notify_files(notify_files, token);
+
+        Ok(page.start_address().data())
+    }
     pub fn physmap(
         physical_address: usize,
         size: usize,
@@ -123,8 +533,10 @@ impl MemoryScheme {
     ) -> Result<usize> {
         // FIXME: Check physical_address against the real MAXPHYADDR.
         let end = 1 << 52;
-        if (physical_address.saturating_add(size) as u64) > end || physical_address % PAGE_SIZE != 0
-        {
+        let phys_end = physical_address
+            .checked_add(size)
+            .map(|phys_end| phys_end as u64);
+        if phys_end.is_none_or(|phys_end| phys_end > end) || physical_address % PAGE_SIZE != 0 {
             return Err(Error::new(EINVAL));
         }
 
@@ -142,34 +554,11 @@ impl MemoryScheme {
         let base_page = current_addrsp.acquire_write().mmap_anywhere(
             page_count,
             flags,
-            |dst_page, mut page_flags, dst_mapper, dst_flusher| {
-                match memory_type {
-                    // Default
-                    MemoryType::Writeback => (),
-
-                    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))] // FIXME: AARCH64
-                    MemoryType::WriteCombining => {
-                        page_flags = page_flags.custom_flag(EntryFlags::HUGE_PAGE.bits(), true)
-                    }
-
-                    MemoryType::Uncacheable => {
-                        page_flags = page_flags.custom_flag(EntryFlags::NO_CACHE.bits(), true)
-                    }
-
-                    // MemoryType::DeviceMemory doesn't exist on x86 && x86_64, which instead support
-                    // uncacheable, write-combining, write-through, write-protect, and write-back.
-                    #[cfg(target_arch = "aarch64")]
-                    MemoryType::DeviceMemory => {
-                        page_flags = page_flags.custom_flag(EntryFlags::DEV_MEM.bits(), true)
-                    }
-
-                    _ => (),
-                }
-
+            |dst_page, page_flags, dst_mapper, dst_flusher| {
                 Grant::physmap(
                     Frame::containing(PhysicalAddress::new(physical_address)),
                     PageSpan::new(dst_page, page_count.get()),
-                    page_flags,
+                    cache_flags(page_flags, memory_type),
                     dst_mapper,
                     dst_flusher,
                 )
@@ -184,7 +573,7 @@ impl KernelScheme for MemoryScheme {
         path: &str,
         _flags: usize,
         ctx: CallerCtx,
-        _token: &mut CleanLockToken,
+        token: &mut CleanLockToken,
     ) -> Result<OpenResult> {
         if path.len() > 64 {
             return Err(Error::new(ENOENT));
@@ -193,11 +582,17 @@ impl KernelScheme for MemoryScheme {
 
         let (before_memty, memty_str) = path.split_once('@').unwrap_or((path, ""));
         let (before_ty, type_str) = memty_str.split_once('?').unwrap_or((memty_str, ""));
+        // Without a memory type, the options follow the handle type directly
+        let (before_memty, type_str) = match before_memty.split_once('?') {
+            Some((before_memty, opts)) if memty_str.is_empty() => (before_memty, opts),
+            _ => (before_memty, type_str),
+        };
 
         let handle_ty = match before_memty {
             "" | "zeroed" => HandleTy::Allocated,
             "physical" => HandleTy::PhysBorrow,
             "translation" => HandleTy::Translation,
+            "phys_contiguous" => HandleTy::Contiguous,
 
             _ => return Err(Error::new(ENOENT)),
         };
@@ -210,6 +605,35 @@ impl KernelScheme for MemoryScheme {
             _ => return Err(Error::new(ENOENT)),
         };
 
+        if handle_ty == HandleTy::Contiguous {
+            if ctx.uid != 0 {
+                return Err(Error::new(EACCES));
+            }
+            // The memory type is chosen per mapping, by the flags passed to mmap
+            if mem_ty != MemoryType::Writeback {
+                return Err(Error::new(EINVAL));
+            }
+            let size = type_str
+                .split(',')
+                .find_map(|opt| opt.strip_prefix("size="))
+                .and_then(parse_size)
+                .ok_or(Error::new(EINVAL))?;
+
+            let buffer = Self::allocate_contiguous(size)?;
+            let key = NEXT_KEY.fetch_add(1, Ordering::Relaxed);
+            CONTIGUOUS_BUFFERS.write(token.token()).insert(key, buffer);
+
+            let id = (key << 8) | HandleTy::Contiguous as usize;
+            HANDLES.write(token.token()).insert(
+                id,
+                Handle {
+                    raw: HandleTy::Contiguous as u32,
+                    owner: HandleOwner::new(&ctx),
+                },
+            );
+            return Ok(OpenResult::SchemeLocal(id, InternalFlags::empty()));
+        }
+
         let flags = type_str
             .split(',')
             .filter_map(|ty_str| match ty_str {
@@ -232,10 +656,32 @@ impl KernelScheme for MemoryScheme {
             return Err(Error::new(EACCES));
         }
 
-        Ok(OpenResult::SchemeLocal(
-            (handle_ty as usize) | ((mem_ty as usize) << 8) | (usize::from(flags.bits()) << 16),
-            InternalFlags::empty(),
-        ))
+        let key = NEXT_KEY.fetch_add(1, Ordering::Relaxed);
+        let id = (key << 8) | handle_ty as usize;
+        HANDLES.write(token.token()).insert(
+            id,
+            Handle {
+                raw: (handle_ty as u32) | ((mem_ty as u32) << 8) | (u32::from(flags.bits()) << 16),
+                owner: HandleOwner::new(&ctx),
+            },
+        );
+        Ok(OpenResult::SchemeLocal(id, InternalFlags::empty()))
+    }
+    fn close(&self, id: usize, token: &mut CleanLockToken) -> Result<()> {
+        HANDLES
+            .write(token.token())
+            .remove(&id)
+            .ok_or(Error::new(EBADF))?;
+        // Mappings of the object keep their own references to its frames
+        OBJECTS.write(token.token()).remove(&id);
+        if let Some(key) = contiguous_key(id) {
+            // Mappings of the buffer keep their own references to its frames
+            CONTIGUOUS_BUFFERS
+                .write(token.token())
+                .remove(&key)
+                .ok_or(Error::new(EBADF))?;
+        }
+        Ok(())
     }
     fn kcall(
         &self,
@@ -245,10 +691,10 @@ impl KernelScheme for MemoryScheme {
         _metadata: &[u64],
         token: &mut CleanLockToken,
     ) -> Result<usize> {
-        let (handle_ty, _, _) = u32::try_from(id)
-            .ok()
-            .and_then(from_raw)
-            .ok_or(Error::new(EBADF))?;
+        if contiguous_key(id).is_some() {
+            return Err(Error::new(EOPNOTSUPP));
+        }
+        let (handle_ty, _, _) = decode(id, token)?;
 
         match handle_ty {
             HandleTy::Translation => {
@@ -277,12 +723,21 @@ impl KernelScheme for MemoryScheme {
         _consume: bool,
         token: &mut CleanLockToken,
     ) -> Result<usize> {
-        let (handle_ty, mem_ty, flags) = u32::try_from(id)
-            .ok()
-            .and_then(from_raw)
-            .ok_or(Error::new(EBADF))?;
+        if let Some(key) = contiguous_key(id) {
+            return Self::fmap_contiguous(key, addr_space, map, token);
+        }
+
+        let (handle_ty, mem_ty, flags) = decode(id, token)?;
 
         match handle_ty {
+            HandleTy::Allocated
+                if OBJECTS
+                    .read(token.token())
+                    .get(&id)
+                    .is_some_and(|object| object.len > 0) =>
+            {
+                Self::fmap_object(id, addr_space, map, token)
+            }
             HandleTy::Allocated => Self::fmap_anonymous(
                 addr_space,
                 map,
@@ -290,36 +745,91 @@ impl KernelScheme for MemoryScheme {
                 token,
             ),
             HandleTy::PhysBorrow => Self::physmap(map.offset, map.size, map.flags, mem_ty, token),
-            HandleTy::Translation => Err(Error::new(EOPNOTSUPP)),
+            HandleTy::Translation | HandleTy::Contiguous => Err(Error::new(EOPNOTSUPP)),
         }
     }
-    fn kfpath(&self, id: usize, buf: UserSliceWo, _token: &mut CleanLockToken) -> Result<usize> {
-        let (handle_ty, mem_ty, flags) = u32::try_from(id)
-            .ok()
-            .and_then(from_raw)
-            .ok_or(Error::new(EBADF))?;
-
-        let mut path = Vec::new();
-        path.extend_from_slice(b"memory:");
-
-        match handle_ty {
-            HandleTy::Allocated => path.extend_from_slice(b"zeroed"),
-            HandleTy::PhysBorrow => path.extend_from_slice(b"physical"),
-            HandleTy::Translation => path.extend_from_slice(b"translation"),
+    fn ftruncate(&self, id: usize, len: usize, token: &mut CleanLockToken) -> Result<()> {
+        if contiguous_key(id).is_some() {
+            return Err(Error::new(EINVAL));
         }
-
-        match mem_ty {
-            MemoryType::Writeback => (),
-            MemoryType::Uncacheable => path.extend_from_slice(b"@uc"),
-            MemoryType::WriteCombining => path.extend_from_slice(b"@wc"),
-            MemoryType::DeviceMemory => path.extend_from_slice(b"@dev"),
+        match decode(id, token)? {
+            (HandleTy::Allocated, MemoryType::Writeback, flags) if flags.is_empty() => OBJECTS
+                .write(token.token())
+                .entry(id)
+                .or_default()
+                .truncate(len),
+            _ => Err(Error::new(EINVAL)),
         }
+    }
+    fn fsize(&self, id: usize, token: &mut CleanLockToken) -> Result<u64> {
+        if contiguous_key(id).is_some() {
+            return Err(Error::new(ESPIPE));
+        }
+        match decode(id, token)? {
+            (HandleTy::Allocated, _, _) => Ok(OBJECTS
+                .read(token.token())
+                .get(&id)
+                .map_or(0, |object| object.len as u64)),
+            _ => Err(Error::new(ESPIPE)),
+        }
+    }
+    fn kfpath(&self, id: usize, buf: UserSliceWo, token: &mut CleanLockToken) -> Result<usize> {
+        if let Some(key) = contiguous_key(id) {
+            let buffers = CONTIGUOUS_BUFFERS.read(token.token());
+            let buffer = buffers.get(&key).ok_or(Error::new(EBADF))?;
+            let base = buffer.base().ok_or(Error::new(EBADF))?;
+            // The physical base is what the driver needs to program the device with
+            let path = handle_path(
+                id,
+                HandleTy::Contiguous,
+                MemoryType::Writeback,
+                &[
+                    format!("size={:#x}", buffer.size()),
+                    format!("phys={:#x}", base.base().data()),
+                ],
+            );
+            return buf.copy_path(path.as_bytes());
+        }
+
+        let (handle_ty, mem_ty, flags) = decode(id, token)?;
 
+        let mut params = Vec::new();
         if flags.contains(HandleFlags::PHYS_CONTIGUOUS) {
-            path.extend_from_slice(b"?phys_contiguous");
+            params.push(String::from("phys_contiguous"));
         }
+        if let Some(object) = OBJECTS.read(token.token()).get(&id) {
+            params.push(format!("size={:#x}", object.len));
+        }
+
+        buf.copy_path(handle_path(id, handle_ty, mem_ty, &params).as_bytes())
+    }
+    fn kfstat(&self, id: usize, buf: UserSliceWo, token: &mut CleanLockToken) -> Result<()> {
+        let owner = HANDLES
+            .read(token.token())
+            .get(&id)
+            .ok_or(Error::new(EBADF))?
+            .owner;
+        buf.copy_exactly(&owner.stat(MODE_CHR))?;
 
-        buf.copy_common_bytes_from_slice(&path)
+        Ok(())
+    }
+    fn fchmod(&self, id: usize, mode: u16, token: &mut CleanLockToken) -> Result<()> {
+        let caller = context::current().read(token.token()).caller_ctx();
+        HANDLES
+            .write(token.token())
+            .get_mut(&id)
+            .ok_or(Error::new(EBADF))?
+            .owner
+            .chmod(&caller, mode)
+    }
+    fn fchown(&self, id: usize, uid: u32, gid: u32, token: &mut CleanLockToken) -> Result<()> {
+        let caller = context::current().read(token.token()).caller_ctx();
+        HANDLES
+            .write(token.token())
+            .get_mut(&id)
+            .ok_or(Error::new(EBADF))?
+            .owner
+            .chown(&caller, uid, gid)
     }
     fn kfstatvfs(&self, _file: usize, dst: UserSliceWo, _token: &mut CleanLockToken) -> Result<()> {
         let used = used_frames() as u64;
@@ -336,3 +846,186 @@ impl KernelScheme for MemoryScheme {
         Ok(())
     }
 }
+
+#[cfg(feature = "selftest")]
+pub mod selftests {
+    use super::*;
+    use crate::{
+        scheme::SchemeNamespace,
+        selftest::{self, check_eq, SelftestResult},
+        syscall::usercopy::UserSlice,
+    };
+
+    /// An anonymous object truncated up, mapped and written through the mapping, then truncated
+    /// down: the page it keeps still holds what was written, while the truncated tail is unmapped
+    /// and faults with EFAULT
+    pub fn ftruncate_unmaps_tail(token: &mut CleanLockToken) -> SelftestResult {
+        selftest::with_user_page(truncate_object, token)
+    }
+
+    fn truncate_object(_page: usize, token: &mut CleanLockToken) -> SelftestResult {
+        let ctx = CallerCtx {
+            uid: 0,
+            gid: 0,
+            pid: 0,
+            ns: SchemeNamespace::from(0),
+            mode: 0,
+        };
+        let id = match MemoryScheme.kopen("zeroed", 0, ctx, token) {
+            Ok(OpenResult::SchemeLocal(id, _)) => id,
+            _ => return Err("failed to open memory:zeroed".into()),
+        };
+
+        let result = map_and_truncate(id, token);
+
+        let _ = MemoryScheme.close(id, token);
+        result
+    }
+
+    fn map_and_truncate(id: usize, token: &mut CleanLockToken) -> SelftestResult {
+        check_eq!(MemoryScheme.ftruncate(id, 2 * PAGE_SIZE, token), Ok(()));
+        check_eq!(MemoryScheme.fsize(id, token), Ok(2 * PAGE_SIZE as u64));
+
+        let addr_space =
+            AddrSpace::current(token).map_err(|err| format!("no address space: {}", err))?;
+        let map = Map {
+            offset: 0,
+            size: 2 * PAGE_SIZE,
+            address: 0,
+            flags: MapFlags::PROT_READ | MapFlags::PROT_WRITE | MapFlags::MAP_SHARED,
+        };
+        let base = MemoryScheme
+            .kfmap(id, &addr_space, &map, false, token)
+            .map_err(|err| format!("failed to map the object: {}", err))?;
+
+        let result = check_truncation(id, base, token);
+
+        let span = PageSpan::new(Page::containing_address(VirtualAddress::new(base)), 2);
+        let _ = addr_space.munmap(span, false);
+        result
+    }
+
+    fn check_truncation(id: usize, base: usize, token: &mut CleanLockToken) -> SelftestResult {
+        let tail = base.saturating_add(PAGE_SIZE);
+        let write = |addr: usize, byte: u8| UserSlice::wo(addr, 1)?.copy_from_slice(&[byte]);
+        let read = |addr: usize| {
+            let mut byte = [0];
+            UserSlice::ro(addr, 1)?.copy_to_slice(&mut byte)?;
+            Ok::<_, Error>(byte)
+        };
+
+        check_eq!(write(base, 1), Ok(()));
+        check_eq!(write(tail, 2), Ok(()));
+        check_eq!(read(tail), Ok([2]));
+
+        check_eq!(MemoryScheme.ftruncate(id, PAGE_SIZE, token), Ok(()));
+        check_eq!(MemoryScheme.fsize(id, token), Ok(PAGE_SIZE as u64));
+        check_eq!(read(base), Ok([1]));
+        check_eq!(read(tail).map_err(|err| err.errno), Err(EFAULT));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handle_paths() {
+        assert_eq!(
+            handle_path(0x100, HandleTy::Allocated, MemoryType::Writeback, &[]),
+            "memory:zeroed/256"
+        );
+        assert_eq!(
+            handle_path(
+                0x203,
+                HandleTy::Contiguous,
+                MemoryType::Writeback,
+                &["size=0x2000".into(), "phys=0x100000".into()],
+            ),
+            "memory:phys_contiguous/515?size=0x2000,phys=0x100000"
+        );
+        assert_eq!(
+            handle_path(
+                0x300,
+                HandleTy::Allocated,
+                MemoryType::Writeback,
+                &["phys_contiguous".into(), "size=0x1001".into()],
+            ),
+            "memory:zeroed/768?phys_contiguous,size=0x1001"
+        );
+    }
+
+    #[test]
+    fn contiguous_buffers_end_below_4gib() {
+        const SIZE: usize = MAX_PHYS_CONTIGUOUS_SIZE;
+        const LAST_BASE: usize = (PHYS_CONTIGUOUS_LIMIT - SIZE as u64) as usize;
+        assert!(below_contiguous_limit(PhysicalAddress::new(0), SIZE));
+        assert!(below_contiguous_limit(
+            PhysicalAddress::new(LAST_BASE),
+            SIZE
+        ));
+        assert!(!below_contiguous_limit(
+            PhysicalAddress::new(LAST_BASE + PAGE_SIZE),
+            SIZE
+        ));
+    }
+
+    #[test]
+    fn map_flags_choose_memory_type() {
+        let rw = MapFlags::PROT_READ | MapFlags::PROT_WRITE;
+        assert_eq!(map_memory_type(rw), MemoryType::Writeback);
+        assert_eq!(
+            map_memory_type(rw | MAP_UNCACHEABLE),
+            MemoryType::Uncacheable
+        );
+        assert_eq!(
+            map_memory_type(rw | MAP_WRITE_COMBINING),
+            MemoryType::WriteCombining
+        );
+        assert_eq!(
+            map_memory_type(rw | MAP_DEVICE_MEMORY | MAP_UNCACHEABLE),
+            MemoryType::DeviceMemory
+        );
+    }
+
+    #[test]
+    fn object_pages_must_lie_within_object() {
+        assert_eq!(object_pages(0, PAGE_SIZE, 0).unwrap_err().errno, EINVAL);
+
+        // ftruncate up, then map the whole object and its partial last page
+        let len = 3 * PAGE_SIZE + 1;
+        assert_eq!(object_pages(0, 4 * PAGE_SIZE, len).unwrap(), 0..4);
+        assert_eq!(object_pages(PAGE_SIZE, 2, len).unwrap(), 1..2);
+        assert_eq!(object_pages(1, PAGE_SIZE, len).unwrap_err().errno, EINVAL);
+        assert_eq!(object_pages(0, 0, len).unwrap_err().errno, EINVAL);
+
+        // ftruncate down, after which the tail can no longer be mapped
+        let len = PAGE_SIZE;
+        assert_eq!(object_pages(0, PAGE_SIZE, len).unwrap(), 0..1);
+        assert_eq!(
+            object_pages(PAGE_SIZE, PAGE_SIZE, len).unwrap_err().errno,
+            EINVAL
+        );
+        assert_eq!(
+            object_pages(usize::MAX & !(PAGE_SIZE - 1), PAGE_SIZE, len)
+                .unwrap_err()
+                .errno,
+            EINVAL
+        );
+    }
+
+    #[test]
+    fn mapping_page_of_object_page() {
+        let base = Page::containing_address(VirtualAddress::new(0x10_0000));
+        let mapping = ObjectMapping {
+            addr_space: Weak::new(),
+            base,
+            pages: 2..5,
+        };
+        assert_eq!(mapping.page(1), None);
+        assert_eq!(mapping.page(2), Some(base));
+        assert_eq!(mapping.page(4), Some(base.next_by(2)));
+        assert_eq!(mapping.page(5), None);
+    }
+}
@@ -0,0 +1,89 @@
+//! Per-scheme call latency histograms, gathered when the `scheme_metrics` feature is enabled
+//!
+//! Each scheme has a log2 histogram of call latencies in nanoseconds per operation, the last
+//! bucket collecting everything from 2^30 ns (about a second) up. Calls are timed by
+//! [`scheme_timed!`] at the syscall layer, and the table is read through `sys:scheme_metrics`.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::SchemeId;
+
+/// Number of histogram buckets, bucket `n` counting latencies in `[2^n, 2^(n+1))` ns
+///
+/// 2^30 ns is just over a second, so the last bucket holds every call taking a second or more.
+pub const BUCKETS: usize = 31;
+
+/// Schemes with an ID at or above this are not tracked
+///
+/// Scheme IDs are never reused, so a kernel that keeps registering schemes will eventually stop
+/// gathering metrics for new ones.
+pub const MAX_SCHEMES: usize = 256;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Op {
+    Open,
+    Read,
+    Write,
+    Fmap,
+}
+
+impl Op {
+    pub const ALL: [Op; 4] = [Op::Open, Op::Read, Op::Write, Op::Fmap];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Op::Open => "open",
+            Op::Read => "read",
+            Op::Write => "write",
+            Op::Fmap => "fmap",
+        }
+    }
+}
+
+type Histogram = [AtomicU64; BUCKETS];
+
+static TABLE: [[Histogram; Op::ALL.len()]; MAX_SCHEMES] =
+    [const { [const { [const { AtomicU64::new(0) }; BUCKETS] }; Op::ALL.len()] }; MAX_SCHEMES];
+
+fn bucket(nanos: u128) -> usize {
+    let log2 = nanos.checked_ilog2().unwrap_or(0) as usize;
+    log2.min(BUCKETS - 1)
+}
+
+/// Account a call to `op` on `scheme_id` that started at monotonic time `start`
+#[inline]
+pub fn record(scheme_id: SchemeId, op: Op, start: u128) {
+    let elapsed = crate::time::monotonic().saturating_sub(start);
+    if let Some(ops) = TABLE.get(scheme_id.get()) {
+        ops[op as usize][bucket(elapsed)].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Snapshot of the histogram of `op` on `scheme_id`
+pub fn histogram(scheme_id: SchemeId, op: Op) -> [u64; BUCKETS] {
+    let mut counts = [0; BUCKETS];
+    if let Some(ops) = TABLE.get(scheme_id.get()) {
+        for (count, bucket) in counts.iter_mut().zip(&ops[op as usize]) {
+            *count = bucket.load(Ordering::Relaxed);
+        }
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(1), 0);
+        assert_eq!(bucket(2), 1);
+        assert_eq!(bucket(1023), 9);
+        assert_eq!(bucket(1024), 10);
+        assert_eq!(bucket(999_999_999), 29);
+        assert_eq!(bucket(1_000_000_000), 29);
+        assert_eq!(bucket(1 << 30), 30);
+        assert_eq!(bucket(u128::MAX), BUCKETS - 1);
+    }
+}
@@ -15,7 +15,7 @@ use crate::{
     sync::CleanLockToken,
     syscall::{
         data::{Map, Stat},
-        error::{Error, Result, ENODEV, ENOSYS},
+        error::{Error, Result, EEXIST, ENODEV, ENOSYS, EPERM, ESPIPE},
         flag::{CallFlags, EventFlags, MapFlags, MunmapFlags},
         usercopy::{UserSliceRo, UserSliceRw, UserSliceWo},
     },
@@ -23,12 +23,17 @@ use crate::{
 
 #[cfg(feature = "acpi")]
 pub mod acpi;
+pub mod cache;
 pub mod debug;
 #[cfg(dtb)]
 pub mod dtb;
 pub mod event;
+pub mod gal;
+pub mod ipcbuf;
 pub mod irq;
 pub mod memory;
+#[cfg(feature = "scheme_metrics")]
+pub mod metrics;
 pub mod pipe;
 pub mod proc;
 pub mod ring;
@@ -39,7 +44,7 @@ pub mod sys;
 pub mod time;
 pub mod user;
 
-pub use self::ring::RingScheme;
+pub use self::{cache::SchemeCache, ring::RingScheme};
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
 pub struct SchemeId(usize);
@@ -103,6 +108,67 @@ pub struct CallerCtx {
     pub uid: u32,
     pub gid: u32,
     pub pid: usize,
+    /// Scheme namespace the caller resolves names in
+    pub ns: SchemeNamespace,
+    /// Mode a file created by this open gets, with the caller's umask applied, or 0 if the call
+    /// does not create
+    pub mode: u16,
+}
+
+/// Permission bits and owner of a kernel scheme handle, as changed by fchmod and fchown
+///
+/// This is bookkeeping so that fstat reflects those calls, access is not checked against it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HandleOwner {
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl HandleOwner {
+    /// Readable and writable only by whoever opened the handle
+    pub fn new(ctx: &CallerCtx) -> Self {
+        Self {
+            mode: 0o600,
+            uid: ctx.uid,
+            gid: ctx.gid,
+        }
+    }
+
+    /// Replace the permission bits, which only the owner may do
+    pub fn chmod(&mut self, caller: &CallerCtx, mode: u16) -> Result<()> {
+        if caller.uid != self.uid {
+            return Err(Error::new(EPERM));
+        }
+        self.mode = mode & 0o7777;
+        Ok(())
+    }
+
+    /// Change the owner and group, which only the owner or root may do
+    ///
+    /// As with chown(2), passing `u32::MAX` leaves the owner or group unchanged.
+    pub fn chown(&mut self, caller: &CallerCtx, uid: u32, gid: u32) -> Result<()> {
+        if caller.uid != self.uid && caller.uid != 0 {
+            return Err(Error::new(EPERM));
+        }
+        if uid != u32::MAX {
+            self.uid = uid;
+        }
+        if gid != u32::MAX {
+            self.gid = gid;
+        }
+        Ok(())
+    }
+
+    /// Stat of a handle of this owner, `file_type` being one of the `MODE_*` type bits
+    pub fn stat(&self, file_type: u16) -> Stat {
+        Stat {
+            st_mode: file_type | self.mode,
+            st_uid: self.uid,
+            st_gid: self.gid,
+            ..Default::default()
+        }
+    }
 }
 
 pub enum OpenResult {
@@ -140,6 +206,9 @@ impl<'a> From<&'a [u8]> for StrOrBytes<'a> {
     }
 }
 
+/// Offset passed to `kreadoff` and `kwriteoff` for handles whose position is not tracked
+pub const NO_OFFSET: u64 = u64::MAX;
+
 /// Kernel scheme trait
 pub trait KernelScheme: Send + Sync {
     fn kopen(
@@ -179,16 +248,22 @@ pub trait KernelScheme: Send + Sync {
     ) -> Result<usize> {
         Err(Error::new(ENOSYS))
     }
+    /// Read at `offset`, which is `NO_OFFSET` unless the handle was opened `POSITIONED`
+    ///
+    /// Schemes without positioned handles need only implement `kread`.
     fn kreadoff(
         &self,
-        _file: usize,
-        _buf: UserSliceWo,
-        _offset: u64,
-        _flags: u32,
-        _stored_flags: u32,
-        _token: &mut CleanLockToken,
+        file: usize,
+        buf: UserSliceWo,
+        offset: u64,
+        flags: u32,
+        stored_flags: u32,
+        token: &mut CleanLockToken,
     ) -> Result<usize> {
-        Err(Error::new(ENOSYS))
+        if offset != NO_OFFSET {
+            return Err(Error::new(ESPIPE));
+        }
+        self.kread(file, buf, flags, stored_flags, token)
     }
     fn kwrite(
         &self,
@@ -200,16 +275,22 @@ pub trait KernelScheme: Send + Sync {
     ) -> Result<usize> {
         Err(Error::new(ENOSYS))
     }
+    /// Write at `offset`, which is `NO_OFFSET` unless the handle was opened `POSITIONED`
+    ///
+    /// Schemes without positioned handles need only implement `kwrite`.
     fn kwriteoff(
         &self,
-        _file: usize,
-        _buf: UserSliceRo,
-        _offset: u64,
-        _flags: u32,
-        _stored_flags: u32,
-        _token: &mut CleanLockToken,
+        file: usize,
+        buf: UserSliceRo,
+        offset: u64,
+        flags: u32,
+        stored_flags: u32,
+        token: &mut CleanLockToken,
     ) -> Result<usize> {
-        Err(Error::new(ENOSYS))
+        if offset != NO_OFFSET {
+            return Err(Error::new(ESPIPE));
+        }
+        self.kwrite(file, buf, flags, stored_flags, token)
     }
     fn legacy_seek(
         &self,
@@ -323,6 +404,9 @@ pub trait KernelScheme: Send + Sync {
     ) -> Result<()> {
         Err(Error::new(ENOSYS))
     }
+    /// Open a new handle through `file`, as `SYS_DUP` does with a non-empty buffer, whose meaning
+    /// is up to the scheme. A local handle gets the open flags of `file` and the internal flags
+    /// returned here. Close-on-exec is a property of the descriptor, not the scheme's concern.
     fn kdup(
         &self,
         _file: usize,
@@ -379,6 +463,7 @@ pub trait KernelScheme: Send + Sync {
 pub enum GlobalSchemes {
     Debug,
     Event,
+    IpcBuf,
     Memory,
     Pipe,
     Proc,
@@ -392,6 +477,7 @@ pub enum GlobalSchemes {
     #[cfg(dtb)]
     Dtb,
     Root(Arc<root::RootScheme>),
+    Gal(Arc<gal::GalScheme>),
 }
 
 impl GlobalSchemes {
@@ -399,6 +485,7 @@ impl GlobalSchemes {
         let name = match self {
             Self::Debug => "debug",
             Self::Event => "event",
+            Self::IpcBuf => "ipcbuf",
             Self::Memory => "memory",
             Self::Pipe => "pipe",
             Self::Proc => "proc",
@@ -412,6 +499,7 @@ impl GlobalSchemes {
             #[cfg(dtb)]
             Self::Dtb => "dtb",
             Self::Root(_) => "root",
+            Self::Gal(_) => "gal",
         };
         SCHEMES.read().get_id(name).unwrap_or(SchemeId(0))
     }
@@ -428,6 +516,10 @@ macro_rules! forward_scheme {
                 let $s = &event::EventScheme;
                 $expr
             }
+            GlobalSchemes::IpcBuf => {
+                let $s = &ipcbuf::IpcBufScheme;
+                $expr
+            }
             GlobalSchemes::Memory => {
                 let $s = &memory::MemoryScheme;
                 $expr
@@ -475,6 +567,10 @@ macro_rules! forward_scheme {
                 let $s = s;
                 $expr
             }
+            GlobalSchemes::Gal(s) => {
+                let $s = s;
+                $expr
+            }
         }
     };
 }
@@ -1031,10 +1127,17 @@ impl KernelScheme for KernelSchemes {
     }
 }
 
+/// Global schemes every new namespace starts out with, see [`SchemeList::new_ns`]
+const NEW_NS_SCHEMES: &[&str] = &["pipe", "event", "memory"];
+
+/// Global schemes live in namespace 0, and a namespace only sees the schemes registered in it
 pub struct SchemeList {
     map: BTreeMap<SchemeId, Arc<KernelSchemes>>,
     names: BTreeMap<SchemeNamespace, BTreeMap<Box<str>, SchemeId>>,
     next_id: AtomicUsize,
+    next_ns: AtomicUsize,
+    /// Where changes to `map` are published for lookups by ID, see [`scheme`]
+    cache: Option<&'static SchemeCache>,
 }
 
 impl SchemeList {
@@ -1043,8 +1146,22 @@ impl SchemeList {
             map: BTreeMap::new(),
             names: BTreeMap::new(),
             next_id: AtomicUsize::new(1),
+            next_ns: AtomicUsize::new(1),
+            cache: None,
         }
     }
+    /// Publish `map` to the lookup cache, after every change to it
+    fn publish(&self) {
+        let Some(cache) = self.cache else {
+            return;
+        };
+        let len = self.map.keys().next_back().map_or(0, |id| id.0 + 1);
+        let mut snapshot = alloc::vec![None; len];
+        for (id, scheme) in &self.map {
+            snapshot[id.0] = Some(Arc::clone(scheme));
+        }
+        cache.publish(snapshot);
+    }
     pub fn get(&self, id: SchemeId) -> Option<&Arc<KernelSchemes>> {
         self.map.get(&id)
     }
@@ -1062,6 +1179,10 @@ impl SchemeList {
         }
         None
     }
+    /// ID of a scheme registered in namespace 0
+    ///
+    /// A namespace created by [`Self::new_ns`] or [`Self::make_ns`] shares the IDs of the schemes
+    /// it was given, so this also identifies global schemes seen from other namespaces.
     pub fn get_id(&self, name: &str) -> Option<SchemeId> {
         self.names
             .get(&SchemeNamespace(0))
@@ -1071,13 +1192,21 @@ impl SchemeList {
     pub fn iter_name(&self, ns: SchemeNamespace) -> impl Iterator<Item = (&Box<str>, &SchemeId)> {
         self.names.get(&ns).into_iter().flat_map(|m| m.iter())
     }
+    /// Register a global scheme in namespace 0
     pub fn insert(&mut self, name: Box<str>, scheme: KernelSchemes) -> SchemeId {
+        self.insert_ns(SchemeNamespace(0), name, scheme)
+    }
+
+    pub fn insert_ns(
+        &mut self,
+        ns: SchemeNamespace,
+        name: Box<str>,
+        scheme: KernelSchemes,
+    ) -> SchemeId {
         let id = SchemeId(self.next_id.fetch_add(1, Ordering::Relaxed));
         self.map.insert(id, Arc::new(scheme));
-        self.names
-            .entry(SchemeNamespace(0))
-            .or_default()
-            .insert(name, id);
+        self.names.entry(ns).or_default().insert(name, id);
+        self.publish();
         id
     }
 
@@ -1085,6 +1214,14 @@ impl SchemeList {
     where
         F: FnOnce(SchemeId) -> Result<(KernelSchemes, T)>,
     {
+        if self
+            .names
+            .get(&ns)
+            .is_some_and(|names| names.contains_key(name))
+        {
+            return Err(Error::new(EEXIST));
+        }
+
         let id = SchemeId(self.next_id.fetch_add(1, Ordering::Relaxed));
         let (scheme, t) = f(id)?;
         self.map.insert(id, Arc::new(scheme));
@@ -1092,32 +1229,89 @@ impl SchemeList {
             .entry(ns)
             .or_default()
             .insert(Box::from(name), id);
+        self.publish();
         Ok((id, t))
     }
 
+    /// Unregister a scheme, freeing its names in every namespace
+    ///
+    /// IDs are never reused, so descriptions still referring to `id` keep failing to find it
+    /// rather than reaching a scheme registered later.
     pub fn remove(&mut self, id: SchemeId) {
         if self.map.remove(&id).is_some() {
             for names in self.names.values_mut() {
                 names.retain(|_, v| *v != id);
             }
+            self.publish();
         }
     }
 
+    /// Create a namespace with its own root scheme, and of the global schemes only those in
+    /// [`NEW_NS_SCHEMES`]
+    pub fn new_ns(&mut self) -> SchemeNamespace {
+        let ns = SchemeNamespace(self.next_ns.fetch_add(1, Ordering::Relaxed));
+
+        let global = self.names.get(&SchemeNamespace(0));
+        let names = NEW_NS_SCHEMES
+            .iter()
+            .filter_map(|&name| Some((Box::from(name), *global?.get(name)?)))
+            .collect();
+        self.names.insert(ns, names);
+
+        self.insert_and_pass(ns, "root", |id| {
+            let root = Arc::new(root::RootScheme::new(id));
+            Ok((KernelSchemes::Global(GlobalSchemes::Root(root)), ()))
+        })
+        .expect("a new namespace has no root scheme yet");
+
+        ns
+    }
+
+    /// Create a namespace as by [`Self::new_ns`], that also sees the schemes `names` of `from`
     pub fn make_ns(
         &mut self,
-        _from: SchemeNamespace,
-        _names: Vec<Box<str>>,
+        from: SchemeNamespace,
+        names: Vec<Box<str>>,
     ) -> Result<SchemeNamespace> {
-        Ok(SchemeNamespace(0)) // Placeholder
+        let ids = names
+            .into_iter()
+            .map(|name| {
+                let id = self
+                    .names
+                    .get(&from)
+                    .and_then(|names| names.get(&name))
+                    .copied()
+                    .ok_or(Error::new(ENODEV))?;
+                Ok((name, id))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let ns = self.new_ns();
+        let names = self.names.entry(ns).or_default();
+        for (name, id) in ids {
+            names.entry(name).or_insert(id);
+        }
+        Ok(ns)
     }
 }
 
+static SCHEME_CACHE: SchemeCache = SchemeCache::new();
+
 pub static SCHEMES: RwLock<SchemeList> = RwLock::new(SchemeList {
     map: BTreeMap::new(),
     names: BTreeMap::new(),
     next_id: AtomicUsize::new(1),
+    next_ns: AtomicUsize::new(1),
+    cache: Some(&SCHEME_CACHE),
 });
 
+/// Look up a scheme by ID without locking [`SCHEMES`]
+///
+/// This is the path every operation on an open file takes, resolving names still needs the list.
+pub fn scheme(id: SchemeId) -> Option<Arc<KernelSchemes>> {
+    SCHEME_CACHE.get(id)
+}
+
 pub fn schemes<L: crate::sync::Level>(
     _token: &crate::sync::LockToken<'_, L>,
 ) -> spin::RwLockReadGuard<'static, SchemeList> {
@@ -1131,9 +1325,6 @@ pub fn schemes_mut<L: crate::sync::Level>(
 }
 
 pub fn init_schemes() {
-    // Run benchmark temporarily
-    ring_bench::benchmark_ring();
-
     let mut schemes = SCHEMES.write();
     let ring = Arc::new(RingScheme::new());
 
@@ -1145,6 +1336,10 @@ pub fn init_schemes() {
         Box::from("event"),
         KernelSchemes::Global(GlobalSchemes::Event),
     );
+    schemes.insert(
+        Box::from("ipcbuf"),
+        KernelSchemes::Global(GlobalSchemes::IpcBuf),
+    );
     schemes.insert(
         Box::from("memory"),
         KernelSchemes::Global(GlobalSchemes::Memory),
@@ -1179,16 +1374,159 @@ pub fn init_schemes() {
     #[cfg(dtb)]
     schemes.insert(Box::from("dtb"), KernelSchemes::Global(GlobalSchemes::Dtb));
 
-    // Manually insert root scheme to get the ID
-    let root_id = SchemeId(schemes.next_id.fetch_add(1, Ordering::Relaxed));
-    let root = Arc::new(root::RootScheme::new(SchemeNamespace(0), root_id));
-    schemes.map.insert(
-        root_id,
-        Arc::new(KernelSchemes::Global(GlobalSchemes::Root(root))),
-    );
     schemes
-        .names
-        .entry(SchemeNamespace(0))
-        .or_default()
-        .insert(Box::from("root"), root_id);
+        .insert_and_pass(SchemeNamespace(0), "root", |id| {
+            let root = Arc::new(root::RootScheme::new(id));
+            Ok((KernelSchemes::Global(GlobalSchemes::Root(root)), ()))
+        })
+        .expect("root scheme is registered only once");
+    schemes
+        .insert_and_pass(SchemeNamespace(0), "gal", |id| {
+            Ok((KernelSchemes::Global(GlobalSchemes::Gal(gal::init(id))), ()))
+        })
+        .expect("gal scheme is registered only once");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn caller(uid: u32, gid: u32) -> CallerCtx {
+        CallerCtx {
+            uid,
+            gid,
+            pid: 1,
+            ns: SchemeNamespace(0),
+            mode: 0,
+        }
+    }
+
+    #[test]
+    fn handle_owner_defaults_to_opener() {
+        let owner = HandleOwner::new(&caller(1000, 100));
+        let stat = owner.stat(0o010000);
+        assert_eq!(stat.st_mode, 0o010600);
+        assert_eq!((stat.st_uid, stat.st_gid), (1000, 100));
+    }
+
+    #[test]
+    fn handle_owner_chmod_only_by_owner() {
+        let mut owner = HandleOwner::new(&caller(1000, 100));
+        assert_eq!(
+            owner.chmod(&caller(1001, 100), 0o644),
+            Err(Error::new(EPERM))
+        );
+        assert_eq!(owner.chmod(&caller(0, 0), 0o644), Err(Error::new(EPERM)));
+        assert_eq!(owner.mode, 0o600);
+
+        owner.chmod(&caller(1000, 100), 0o170644).unwrap();
+        assert_eq!(owner.mode, 0o644);
+    }
+
+    #[test]
+    fn handle_owner_chown_by_owner_or_root() {
+        let mut owner = HandleOwner::new(&caller(1000, 100));
+        assert_eq!(
+            owner.chown(&caller(1001, 100), 1001, 100),
+            Err(Error::new(EPERM))
+        );
+        assert_eq!((owner.uid, owner.gid), (1000, 100));
+
+        owner.chown(&caller(1000, 100), u32::MAX, 200).unwrap();
+        assert_eq!((owner.uid, owner.gid), (1000, 200));
+
+        owner.chown(&caller(0, 0), 1001, u32::MAX).unwrap();
+        assert_eq!((owner.uid, owner.gid), (1001, 200));
+
+        // The previous owner gave it away
+        assert_eq!(
+            owner.chmod(&caller(1000, 100), 0o666),
+            Err(Error::new(EPERM))
+        );
+    }
+
+    fn global_list() -> SchemeList {
+        let mut list = SchemeList::new();
+        for (name, scheme) in [
+            ("event", GlobalSchemes::Event),
+            ("irq", GlobalSchemes::Irq),
+            ("memory", GlobalSchemes::Memory),
+            ("pipe", GlobalSchemes::Pipe),
+        ] {
+            list.insert(Box::from(name), KernelSchemes::Global(scheme));
+        }
+        list
+    }
+
+    fn resolve(list: &SchemeList, ns: SchemeNamespace, name: &str) -> Result<SchemeId> {
+        list.get_name(ns, name)
+            .map(|(id, _)| id)
+            .ok_or(Error::new(ENODEV))
+    }
+
+    #[test]
+    fn new_namespace_only_sees_allowed_schemes() {
+        let mut list = global_list();
+        let ns = list.new_ns();
+
+        assert_eq!(resolve(&list, ns, "irq").unwrap_err().errno, ENODEV);
+        assert_eq!(
+            resolve(&list, ns, "pipe").unwrap(),
+            resolve(&list, SchemeNamespace(0), "pipe").unwrap()
+        );
+        assert!(resolve(&list, ns, "root").is_ok());
+        assert!(resolve(&list, SchemeNamespace(0), "irq").is_ok());
+    }
+
+    #[test]
+    fn make_ns_adds_requested_schemes() {
+        let mut list = global_list();
+        let ns = list
+            .make_ns(SchemeNamespace(0), vec![Box::from("irq")])
+            .unwrap();
+        assert!(resolve(&list, ns, "irq").is_ok());
+        assert!(resolve(&list, ns, "pipe").is_ok());
+
+        let err = list
+            .make_ns(SchemeNamespace(0), vec![Box::from("missing")])
+            .unwrap_err();
+        assert_eq!(err.errno, ENODEV);
+    }
+
+    #[test]
+    fn removed_scheme_frees_its_name() {
+        let mut list = global_list();
+        let ns = list.new_ns();
+        let register = |list: &mut SchemeList| {
+            list.insert_and_pass(ns, "disk", |_| {
+                Ok((KernelSchemes::Global(GlobalSchemes::Irq), ()))
+            })
+            .map(|(id, ())| id)
+        };
+
+        let old = register(&mut list).unwrap();
+        assert_eq!(register(&mut list).unwrap_err().errno, EEXIST);
+        list.remove(old);
+        assert!(list.get(old).is_none());
+
+        let new = register(&mut list).unwrap();
+        assert_ne!(new, old);
+        assert_eq!(resolve(&list, ns, "disk").unwrap(), new);
+    }
+
+    #[test]
+    fn names_are_per_namespace() {
+        let mut list = global_list();
+        let ns = list.new_ns();
+        let register = |list: &mut SchemeList, ns| {
+            list.insert_and_pass(ns, "disk", |_| {
+                Ok((KernelSchemes::Global(GlobalSchemes::Sys), ()))
+            })
+        };
+
+        let (id, ()) = register(&mut list, ns).unwrap();
+        assert_eq!(resolve(&list, ns, "disk").unwrap(), id);
+        assert!(resolve(&list, SchemeNamespace(0), "disk").is_err());
+        assert_eq!(register(&mut list, ns).unwrap_err().errno, EEXIST);
+    }
 }
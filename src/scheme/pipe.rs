@@ -1,21 +1,38 @@
-use alloc::{collections::VecDeque, sync::Arc};
-use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    mem::size_of,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
 use hashbrown::{hash_map::DefaultHashBuilder, HashMap};
 use spin::Mutex;
 
 use crate::{
-    context::file::InternalFlags,
+    context::{
+        self,
+        file::{FileDescription, FileDescriptor, InternalFlags},
+        memory::AddrSpace,
+    },
     event,
+    memory::RaiiFrame,
+    paging::{Page, RmmA, RmmArch, VirtualAddress, PAGE_SIZE},
     sync::{CleanLockToken, RwLock, WaitCondition, L1},
     syscall::{
         data::Stat,
-        error::{Error, Result, EAGAIN, EBADF, EINTR, EINVAL, ENOENT, EPIPE},
-        flag::{EventFlags, EVENT_READ, EVENT_WRITE, MODE_FIFO, O_NONBLOCK},
-        usercopy::{UserSliceRo, UserSliceWo},
+        error::{
+            Error, Result, EAGAIN, EBADF, EBUSY, EINTR, EINVAL, EMSGSIZE, ENOENT, ENOMEM, EPERM,
+            EPIPE,
+        },
+        flag::{CallFlags, EventFlags, EVENT_READ, EVENT_WRITE, MODE_FIFO, O_CLOEXEC, O_NONBLOCK},
+        usercopy::{UserSliceRo, UserSliceRw, UserSliceWo},
     },
 };
 
-use super::{CallerCtx, GlobalSchemes, KernelScheme, OpenResult, StrOrBytes};
+use super::{CallerCtx, GlobalSchemes, HandleOwner, KernelScheme, OpenResult, StrOrBytes};
 
 static PIPE_NEXT_ID: AtomicUsize = AtomicUsize::new(0);
 
@@ -23,7 +40,34 @@ static PIPE_NEXT_ID: AtomicUsize = AtomicUsize::new(0);
 static PIPES: RwLock<L1, HashMap<usize, Arc<Pipe>>> =
     RwLock::new(HashMap::with_hasher(DefaultHashBuilder::new()));
 
-const MAX_QUEUE_SIZE: usize = 65536;
+/// Capacity of a new pipe, in bytes
+const DEFAULT_PIPE_SIZE: usize = 65536;
+/// Largest capacity users other than root can give a pipe through F_SETPIPE_SZ
+const MAX_PIPE_SIZE: usize = 1024 * 1024;
+/// Capacity beyond the default that a user other than root can hold across all their pipes
+const PIPE_USER_QUOTA: usize = 16 * 1024 * 1024;
+
+/// Most descriptors a single kfdwrite can pass over a pipe
+const MAX_FDS_PER_MESSAGE: usize = 8;
+/// Most kfdwrite messages a pipe holds before its reader has received them
+const MAX_FD_MESSAGES: usize = 64;
+
+/// Capacity beyond the default charged to each user, see [`PIPE_USER_QUOTA`]
+static PIPE_CHARGES: Mutex<PipeCharges> = Mutex::new(PipeCharges(BTreeMap::new()));
+
+/// fcntl command enabling splice mode on a pipe end if `arg` is nonzero, or disabling it.
+///
+/// In splice mode, whole pages written from a page-aligned buffer are moved into the pipe
+/// instead of copied, and the writer is left with zeroed pages in their place. A reader in
+/// splice mode gets such pages mapped into a page-aligned buffer, again without copying.
+pub const F_SETSPLICE: usize = 1040;
+/// fcntl command returning 1 if splice mode is enabled on a pipe end, or 0 otherwise
+pub const F_GETSPLICE: usize = 1041;
+/// fcntl command setting the capacity of a pipe to `arg` bytes, rounded up to whole pages, and
+/// returning the new capacity. The value is the same as on Linux.
+pub const F_SETPIPE_SZ: usize = 1031;
+/// fcntl command returning the capacity of a pipe
+pub const F_GETPIPE_SZ: usize = 1032;
 
 // In almost all places where Rust (and LLVM) uses pointers, they are limited to nonnegative isize,
 // so this is fine.
@@ -33,21 +77,20 @@ fn from_raw_id(id: usize) -> (bool, usize) {
     (id & WRITE_NOT_READ_BIT != 0, id & !WRITE_NOT_READ_BIT)
 }
 
-pub fn pipe(token: &mut CleanLockToken) -> Result<(usize, usize)> {
+/// Path of a pipe end, naming the pipe by the ID both of its ends share
+fn end_path(id: usize) -> String {
+    let (is_write, key) = from_raw_id(id);
+    format!("pipe:{}/{}", if is_write { "write" } else { "read" }, key)
+}
+
+/// Create a pipe owned by `ctx`, returning the IDs of its read and write ends
+pub fn pipe(ctx: &CallerCtx, token: &mut CleanLockToken) -> Result<(usize, usize)> {
     // Bit 0 is used for WRITE_NOT_READ_BIT
     let id = PIPE_NEXT_ID.fetch_add(2, Ordering::Relaxed);
 
-    PIPES.write(token.token()).insert(
-        id,
-        Arc::new(Pipe {
-            queue: Mutex::new(VecDeque::new()),
-            read_condition: WaitCondition::new(),
-            write_condition: WaitCondition::new(),
-            writer_is_alive: AtomicBool::new(true),
-            reader_is_alive: AtomicBool::new(true),
-            has_run_dup: AtomicBool::new(false),
-        }),
-    );
+    PIPES
+        .write(token.token())
+        .insert(id, Arc::new(Pipe::new(HandleOwner::new(ctx))));
 
     Ok((id, id | WRITE_NOT_READ_BIT))
 }
@@ -71,16 +114,21 @@ impl KernelScheme for PipeScheme {
 
         let mut ready = EventFlags::empty();
 
+        if is_writer_not_reader {
+            pipe.writer_interest.store(flags.bits(), Ordering::Release);
+        } else {
+            pipe.reader_interest.store(flags.bits(), Ordering::Release);
+        }
+
         if is_writer_not_reader
             && flags.contains(EVENT_WRITE)
-            && (pipe.queue.lock().len() <= MAX_QUEUE_SIZE
-                || !pipe.reader_is_alive.load(Ordering::Acquire))
+            && (!pipe.queue.lock().is_full() || !pipe.reader_is_alive.load(Ordering::Acquire))
         {
             ready |= EventFlags::EVENT_WRITE;
         }
         if !is_writer_not_reader
             && flags.contains(EVENT_READ)
-            && (!pipe.queue.lock().is_empty() || !pipe.writer_is_alive.load(Ordering::Acquire))
+            && (pipe.queue.lock().has_data() || !pipe.writer_is_alive.load(Ordering::Acquire))
         {
             ready |= EventFlags::EVENT_READ;
         }
@@ -88,6 +136,52 @@ impl KernelScheme for PipeScheme {
         Ok(ready)
     }
 
+    fn fcntl(
+        &self,
+        id: usize,
+        cmd: usize,
+        arg: usize,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        let (is_writer_not_reader, key) = from_raw_id(id);
+        let pipe = Arc::clone(
+            PIPES
+                .read(token.token())
+                .get(&key)
+                .ok_or(Error::new(EBADF))?,
+        );
+
+        let splice = if is_writer_not_reader {
+            &pipe.writer_splice
+        } else {
+            &pipe.reader_splice
+        };
+        match cmd {
+            F_SETSPLICE => {
+                splice.store(arg != 0, Ordering::Relaxed);
+                Ok(0)
+            }
+            F_GETSPLICE => Ok(usize::from(splice.load(Ordering::Relaxed))),
+            F_SETPIPE_SZ => {
+                let uid = context::current().read(token.token()).euid;
+                let (capacity, events) = pipe.resize(arg, uid)?;
+                if !events.is_empty() {
+                    event::trigger(
+                        GlobalSchemes::Pipe.scheme_id(),
+                        key | WRITE_NOT_READ_BIT,
+                        events,
+                        token,
+                    );
+                }
+                // Blocked writers check the new capacity once woken
+                pipe.write_condition.notify(token);
+                Ok(capacity)
+            }
+            F_GETPIPE_SZ => Ok(pipe.queue.lock().capacity()),
+            _ => Ok(0),
+        }
+    }
+
     fn close(&self, id: usize, token: &mut CleanLockToken) -> Result<()> {
         let (is_write_not_read, key) = from_raw_id(id);
 
@@ -99,22 +193,36 @@ impl KernelScheme for PipeScheme {
         );
         let scheme_id = GlobalSchemes::Pipe.scheme_id();
 
+        // Closing one end is a hangup for the other, which sees EOF or EPIPE on its next call, so
+        // everything it is waiting for becomes ready.
         let can_remove = if is_write_not_read {
             pipe.writer_is_alive.store(false, Ordering::SeqCst);
-            event::trigger(scheme_id, key, EVENT_READ, token);
+            let hangup = pipe.reader_hangup_events();
+            if !hangup.is_empty() {
+                event::trigger(scheme_id, key, hangup, token);
+            }
             pipe.read_condition.notify(token);
 
             !pipe.reader_is_alive.load(Ordering::SeqCst)
         } else {
             pipe.reader_is_alive.store(false, Ordering::SeqCst);
-            event::trigger(scheme_id, key | WRITE_NOT_READ_BIT, EVENT_WRITE, token);
+            let hangup = pipe.writer_hangup_events();
+            if !hangup.is_empty() {
+                event::trigger(scheme_id, key | WRITE_NOT_READ_BIT, hangup, token);
+            }
             pipe.write_condition.notify(token);
 
+            // Descriptors still in the pipe can no longer be received. Writers check that the
+            // reader is alive with the queue locked, so none are added after these are taken.
+            let undelivered = pipe.queue.lock().take_fds();
+            close_fds(undelivered, token);
+
             !pipe.writer_is_alive.load(Ordering::SeqCst)
         };
 
         if can_remove {
             let _ = PIPES.write(token.token()).remove(&key);
+            pipe.uncharge();
         }
 
         Ok(())
@@ -159,14 +267,14 @@ impl KernelScheme for PipeScheme {
         &self,
         path: &str,
         _flags: usize,
-        _ctx: CallerCtx,
+        ctx: CallerCtx,
         token: &mut CleanLockToken,
     ) -> Result<OpenResult> {
         if !path.trim_start_matches('/').is_empty() {
             return Err(Error::new(ENOENT));
         }
 
-        let (read_id, _) = pipe(token)?;
+        let (read_id, _) = pipe(&ctx, token)?;
 
         Ok(OpenResult::SchemeLocal(read_id, InternalFlags::empty()))
     }
@@ -224,33 +332,87 @@ impl KernelScheme for PipeScheme {
                 .ok_or(Error::new(EBADF))?,
         );
 
+        let addr_space = if pipe.reader_splice.load(Ordering::Relaxed) {
+            Some(AddrSpace::current(token)?)
+        } else {
+            None
+        };
+
         loop {
             let mut vec = pipe.queue.lock();
 
-            let (s1, s2) = vec.as_slices();
-            let s1_count = core::cmp::min(user_buf.len(), s1.len());
+            // Passed descriptors are received at the position they were sent, so reads stop short
+            // of them. A read starting there with bytes behind them discards them instead, as a
+            // read of an SCM_RIGHTS message does on Unix.
+            if vec.readable() == 0 && !vec.is_empty() && !user_buf.is_empty() {
+                let discarded = vec.pop_fds().unwrap_or_default();
+                drop(vec);
+                close_fds(discarded, token);
+                continue;
+            }
+            let old_len = vec.len();
 
-            let (s1_dst, s2_buf) = user_buf
-                .split_at(s1_count)
-                .expect("s1_count <= user_buf.len()");
-            s1_dst.copy_from_slice(&s1[..s1_count])?;
+            let mut bytes_read = 0;
+            while bytes_read < user_buf.len() {
+                let dst = user_buf
+                    .advance(bytes_read)
+                    .expect("bytes_read < user_buf.len()");
+                let readable = vec.readable();
 
-            let s2_count = core::cmp::min(s2_buf.len(), s2.len());
-            s2_buf
-                .limit(s2_count)
-                .expect("s2_count <= s2_buf.len()")
-                .copy_from_slice(&s2[..s2_count])?;
+                if let Some(addr_space) = &addr_space {
+                    if dst.addr() % PAGE_SIZE == 0
+                        && dst.len() >= PAGE_SIZE
+                        && readable >= PAGE_SIZE
+                    {
+                        if let Some(page) = vec.pop_page() {
+                            let dst_page =
+                                Page::containing_address(VirtualAddress::new(dst.addr()));
+                            // The address space lock is not taken under the pipe lock
+                            drop(vec);
+                            let swapped = addr_space
+                                .acquire_write()
+                                .swap_frame(dst_page, page.0)
+                                .map(drop);
+                            vec = pipe.queue.lock();
+                            match swapped {
+                                Ok(()) => {
+                                    bytes_read += PAGE_SIZE;
+                                    continue;
+                                }
+                                Err(frame) => vec.unpop_page(PipePage(frame)),
+                            }
+                        }
+                    }
+                }
 
-            let bytes_read = s1_count + s2_count;
-            let _ = vec.drain(..bytes_read);
+                let src = vec.front_bytes();
+                let count = src.len().min(dst.len()).min(readable);
+                if count == 0 {
+                    break;
+                }
+                match dst
+                    .limit(count)
+                    .expect("count <= dst.len()")
+                    .copy_from_slice(&src[..count])
+                {
+                    Ok(()) => vec.consume(count),
+                    Err(_) if bytes_read > 0 => break,
+                    Err(error) => return Err(error),
+                }
+                bytes_read += count;
+            }
 
             if bytes_read > 0 {
-                event::trigger(
-                    GlobalSchemes::Pipe.scheme_id(),
-                    key | WRITE_NOT_READ_BIT,
-                    EVENT_WRITE,
-                    token,
-                );
+                let events = pipe.writer_events_after_read(old_len, vec.capacity());
+                drop(vec);
+                if !events.is_empty() {
+                    event::trigger(
+                        GlobalSchemes::Pipe.scheme_id(),
+                        key | WRITE_NOT_READ_BIT,
+                        events,
+                        token,
+                    );
+                }
                 pipe.write_condition.notify(token);
 
                 return Ok(bytes_read);
@@ -287,37 +449,77 @@ impl KernelScheme for PipeScheme {
                 .ok_or(Error::new(EBADF))?,
         );
 
+        let addr_space = if pipe.writer_splice.load(Ordering::Relaxed) {
+            Some(AddrSpace::current(token)?)
+        } else {
+            None
+        };
+
         loop {
             let mut vec = pipe.queue.lock();
 
             if !pipe.reader_is_alive.load(Ordering::Relaxed) {
                 return Err(Error::new(EPIPE));
             }
-
-            let bytes_left = MAX_QUEUE_SIZE.saturating_sub(vec.len());
-            let bytes_to_write = core::cmp::min(bytes_left, user_buf.len());
-            let src_buf = user_buf
-                .limit(bytes_to_write)
-                .expect("bytes_to_write <= user_buf.len()");
+            let old_len = vec.len();
 
             const TMPBUF_SIZE: usize = 512;
             let mut tmp_buf = [0_u8; TMPBUF_SIZE];
 
             let mut bytes_written = 0;
 
-            // TODO: Modify VecDeque so that the unwritten portions can be accessed directly?
-            for (idx, chunk) in src_buf.in_variable_chunks(TMPBUF_SIZE).enumerate() {
-                let chunk_byte_count = match chunk.copy_common_bytes_to_slice(&mut tmp_buf) {
-                    Ok(c) => c,
-                    Err(_) if idx > 0 => break,
+            // The capacity may have changed while this writer was blocked, so it is checked anew
+            while bytes_written < user_buf.len() {
+                let bytes_left = vec.room();
+                if bytes_left == 0 {
+                    break;
+                }
+                let src = user_buf
+                    .advance(bytes_written)
+                    .expect("bytes_written < user_buf.len()");
+
+                if let Some(addr_space) = &addr_space {
+                    let whole_page = src.addr() % PAGE_SIZE == 0 && src.len() >= PAGE_SIZE;
+                    if whole_page && bytes_left >= PAGE_SIZE {
+                        let src_page = Page::containing_address(VirtualAddress::new(src.addr()));
+                        // The address space lock is not taken under the pipe lock. Another writer
+                        // filling the pipe meanwhile can take it past capacity by this page.
+                        drop(vec);
+                        let page = take_page(addr_space, src_page);
+                        vec = pipe.queue.lock();
+                        if let Some(page) = page {
+                            vec.push_page(page);
+                            bytes_written += PAGE_SIZE;
+                            continue;
+                        }
+                    }
+                }
+
+                // Stop at the next page boundary, so that a whole page following can be moved
+                let count = [
+                    TMPBUF_SIZE,
+                    bytes_left,
+                    src.len(),
+                    PAGE_SIZE - src.addr() % PAGE_SIZE,
+                ]
+                .into_iter()
+                .min()
+                .expect("array is not empty");
+                let chunk = src.limit(count).expect("count <= src.len()");
+                match chunk.copy_to_slice(&mut tmp_buf[..count]) {
+                    Ok(()) => vec.push_bytes(&tmp_buf[..count]),
+                    Err(_) if bytes_written > 0 => break,
                     Err(error) => return Err(error),
-                };
-                vec.extend(&tmp_buf[..chunk_byte_count]);
-                bytes_written += chunk_byte_count;
+                }
+                bytes_written += count;
             }
 
             if bytes_written > 0 {
-                event::trigger(GlobalSchemes::Pipe.scheme_id(), key, EVENT_READ, token);
+                let events = pipe.reader_events_after_write(old_len);
+                drop(vec);
+                if !events.is_empty() {
+                    event::trigger(GlobalSchemes::Pipe.scheme_id(), key, events, token);
+                }
                 pipe.read_condition.notify(token);
 
                 return Ok(bytes_written);
@@ -332,25 +534,837 @@ impl KernelScheme for PipeScheme {
             }
         }
     }
+    /// Pass up to [`MAX_FDS_PER_MESSAGE`] descriptions to the read end, at the current position in
+    /// the stream. They are closed if they cannot be sent.
+    fn kfdwrite(
+        &self,
+        id: usize,
+        descs: Vec<PassedFd>,
+        _flags: CallFlags,
+        _arg: u64,
+        _metadata: &[u64],
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        let (is_write_not_read, key) = from_raw_id(id);
+        let pipe = PIPES.read(token.token()).get(&key).map(Arc::clone);
+        let Some(pipe) = pipe.filter(|_| is_write_not_read) else {
+            close_fds(descs, token);
+            return Err(Error::new(EBADF));
+        };
+
+        let mut vec = pipe.queue.lock();
+        let error = if descs.is_empty() || descs.len() > MAX_FDS_PER_MESSAGE {
+            EINVAL
+        } else if !pipe.reader_is_alive.load(Ordering::Relaxed) {
+            EPIPE
+        } else if vec.fds.is_full() {
+            EAGAIN
+        } else {
+            let was_ready = vec.has_data();
+            let count = descs.len();
+            vec.push_fds(descs);
+            drop(vec);
+
+            if !was_ready {
+                let events = pipe.reader_events_after_write(0);
+                if !events.is_empty() {
+                    event::trigger(GlobalSchemes::Pipe.scheme_id(), key, events, token);
+                }
+            }
+            pipe.read_condition.notify(token);
+            return Ok(count);
+        };
+        drop(vec);
+        close_fds(descs, token);
+        Err(Error::new(error))
+    }
+    /// Receive the descriptions passed at the current position in the stream into the file table,
+    /// writing their file descriptors to `payload`. Returns 0 if none were passed there, waiting
+    /// for the writer first if the pipe is empty. They are close-on-exec if `metadata[0]` has
+    /// `O_CLOEXEC` set.
+    fn kfdread(
+        &self,
+        id: usize,
+        payload: UserSliceRw,
+        flags: CallFlags,
+        metadata: &[u64],
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        let (is_write_not_read, key) = from_raw_id(id);
+
+        if is_write_not_read {
+            return Err(Error::new(EBADF));
+        }
+        if flags.contains(CallFlags::FD_UPPER) || payload.len() % size_of::<usize>() != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        let cloexec = metadata
+            .first()
+            .is_some_and(|&flags| flags & O_CLOEXEC as u64 != 0);
+        let pipe = Arc::clone(
+            PIPES
+                .read(token.token())
+                .get(&key)
+                .ok_or(Error::new(EBADF))?,
+        );
+
+        loop {
+            let mut vec = pipe.queue.lock();
+
+            if let Some(fds) = vec.front_fds() {
+                if fds.len() > payload.len() / size_of::<usize>() {
+                    return Err(Error::new(EMSGSIZE));
+                }
+                // The file table is not touched under the pipe lock, so the descriptions stay
+                // queued until they are in the file table and their numbers in `payload`
+                let files = fds
+                    .iter()
+                    .map(|description| FileDescriptor {
+                        description: Arc::clone(description),
+                        cloexec,
+                    })
+                    .collect();
+                let id = vec.front_fds_id();
+                drop(vec);
+
+                let handles = context::current()
+                    .read(token.token())
+                    .bulk_add_files_posix(files)?;
+
+                let payload_chunks = payload.in_exact_chunks(size_of::<usize>());
+                let copied = handles
+                    .iter()
+                    .zip(payload_chunks)
+                    .try_for_each(|(handle, chunk)| {
+                        chunk.copy_from_slice(&(handle.get() as usize).to_ne_bytes())
+                    });
+                if copied.is_ok() && pipe.queue.lock().commit_fds(id) {
+                    return Ok(handles.len());
+                }
+
+                // Either `payload` faulted, or another reader received the descriptions first
+                let removed = context::current()
+                    .read(token.token())
+                    .bulk_remove_files(&handles);
+                for file in removed.into_iter().flatten() {
+                    let _ = file.close(token);
+                }
+                copied?;
+                continue;
+            }
+
+            if !vec.is_empty() || !pipe.writer_is_alive.load(Ordering::SeqCst) {
+                return Ok(0);
+            } else if !pipe.read_condition.wait(vec, "PipeRead::fdread", token) {
+                return Err(Error::new(EINTR));
+            }
+        }
+    }
     fn kfpath(&self, id: usize, buf: UserSliceWo, token: &mut CleanLockToken) -> Result<usize> {
-        //TODO: construct useful path?
-        buf.copy_common_bytes_from_slice("/scheme/pipe/".as_bytes())
+        let (_, key) = from_raw_id(id);
+        if !PIPES.read(token.token()).contains_key(&key) {
+            return Err(Error::new(EBADF));
+        }
+        buf.copy_path(end_path(id).as_bytes())
     }
-    fn kfstat(&self, _id: usize, buf: UserSliceWo, _token: &mut CleanLockToken) -> Result<()> {
-        buf.copy_exactly(&Stat {
-            st_mode: MODE_FIFO | 0o666,
-            ..Default::default()
-        })?;
+    fn kfstat(&self, id: usize, buf: UserSliceWo, token: &mut CleanLockToken) -> Result<()> {
+        let (_, key) = from_raw_id(id);
+        let owner = *PIPES
+            .read(token.token())
+            .get(&key)
+            .ok_or(Error::new(EBADF))?
+            .owner
+            .lock();
+
+        buf.copy_exactly(&owner.stat(MODE_FIFO))?;
 
         Ok(())
     }
+    fn fchmod(&self, id: usize, mode: u16, token: &mut CleanLockToken) -> Result<()> {
+        let (_, key) = from_raw_id(id);
+        let caller = context::current().read(token.token()).caller_ctx();
+
+        PIPES
+            .read(token.token())
+            .get(&key)
+            .ok_or(Error::new(EBADF))?
+            .owner
+            .lock()
+            .chmod(&caller, mode)
+    }
+    fn fchown(&self, id: usize, uid: u32, gid: u32, token: &mut CleanLockToken) -> Result<()> {
+        let (_, key) = from_raw_id(id);
+        let caller = context::current().read(token.token()).caller_ctx();
+
+        PIPES
+            .read(token.token())
+            .get(&key)
+            .ok_or(Error::new(EBADF))?
+            .owner
+            .lock()
+            .chown(&caller, uid, gid)
+    }
 }
 
 pub struct Pipe {
     read_condition: WaitCondition, // signals whether there are available bytes to read
     write_condition: WaitCondition, // signals whether there is room for additional bytes
-    queue: Mutex<VecDeque<u8>>,
+    queue: Mutex<PipeQueue>,
     reader_is_alive: AtomicBool, // starts set, unset when reader closes
     writer_is_alive: AtomicBool, // starts set, unset when writer closes
     has_run_dup: AtomicBool,
+    reader_interest: AtomicUsize, // EventFlags last registered through fevent on the read end
+    writer_interest: AtomicUsize, // EventFlags last registered through fevent on the write end
+    reader_splice: AtomicBool,    // set through F_SETSPLICE on the read end
+    writer_splice: AtomicBool,    // set through F_SETSPLICE on the write end
+    owner: Mutex<HandleOwner>,    // shared by both ends, so fstat agrees on either
+    charge: Mutex<Option<Charge>>, // capacity charged to the user who last set it
+}
+
+impl Pipe {
+    fn new(owner: HandleOwner) -> Self {
+        Pipe {
+            queue: Mutex::new(PipeQueue::new()),
+            read_condition: WaitCondition::new(),
+            write_condition: WaitCondition::new(),
+            writer_is_alive: AtomicBool::new(true),
+            reader_is_alive: AtomicBool::new(true),
+            has_run_dup: AtomicBool::new(false),
+            reader_interest: AtomicUsize::new(0),
+            writer_interest: AtomicUsize::new(0),
+            reader_splice: AtomicBool::new(false),
+            writer_splice: AtomicBool::new(false),
+            owner: Mutex::new(owner),
+            charge: Mutex::new(None),
+        }
+    }
+
+    /// Set the capacity to `size` bytes rounded up to whole pages, on behalf of user `uid`.
+    /// Returns the new capacity, and the events for the write end if it is no longer full.
+    fn resize(&self, size: usize, uid: u32) -> Result<(usize, EventFlags)> {
+        let capacity = size
+            .max(1)
+            .checked_next_multiple_of(PAGE_SIZE)
+            .ok_or(Error::new(EINVAL))?;
+        if capacity > MAX_PIPE_SIZE && uid != 0 {
+            return Err(Error::new(EPERM));
+        }
+
+        let mut queue = self.queue.lock();
+        let was_full = queue.is_full();
+        if capacity < queue.len() {
+            return Err(Error::new(EBUSY));
+        }
+
+        let mut charge = self.charge.lock();
+        let bytes = if uid == 0 {
+            0
+        } else {
+            capacity.saturating_sub(DEFAULT_PIPE_SIZE)
+        };
+        PIPE_CHARGES.lock().recharge(&mut charge, uid, bytes)?;
+        queue.set_capacity(capacity)?;
+
+        let events = if was_full && !queue.is_full() {
+            self.writer_interest() & EVENT_WRITE
+        } else {
+            EventFlags::empty()
+        };
+        Ok((capacity, events))
+    }
+
+    /// Give back the capacity charged for this pipe, once it is gone
+    fn uncharge(&self) {
+        let mut charge = self.charge.lock();
+        let _ = PIPE_CHARGES.lock().recharge(&mut charge, 0, 0);
+    }
+
+    fn reader_interest(&self) -> EventFlags {
+        EventFlags::from_bits_truncate(self.reader_interest.load(Ordering::Acquire))
+    }
+    fn writer_interest(&self) -> EventFlags {
+        EventFlags::from_bits_truncate(self.writer_interest.load(Ordering::Acquire))
+    }
+
+    /// Events for the read end after a write grew the queue from `old_len` bytes. Only the
+    /// empty to non-empty transition is reported, as the reader was already notified otherwise.
+    fn reader_events_after_write(&self, old_len: usize) -> EventFlags {
+        if old_len == 0 {
+            self.reader_interest() & EVENT_READ
+        } else {
+            EventFlags::empty()
+        }
+    }
+
+    /// Events for the write end after a read shrank the queue from `old_len` bytes, out of
+    /// `capacity`. Only the full to non-full transition is reported.
+    fn writer_events_after_read(&self, old_len: usize, capacity: usize) -> EventFlags {
+        if old_len >= capacity {
+            self.writer_interest() & EVENT_WRITE
+        } else {
+            EventFlags::empty()
+        }
+    }
+
+    /// Events for the read end once the write end has been closed
+    fn reader_hangup_events(&self) -> EventFlags {
+        self.reader_interest()
+    }
+    /// Events for the write end once the read end has been closed
+    fn writer_hangup_events(&self) -> EventFlags {
+        self.writer_interest()
+    }
+}
+
+/// Pipe capacity charged to a user
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Charge {
+    uid: u32,
+    bytes: usize,
+}
+
+/// Total capacity charged to each user with a nonzero charge
+struct PipeCharges(BTreeMap<u32, usize>);
+
+impl PipeCharges {
+    /// Replace the charge `old` with one of `bytes` to `uid`, failing with `ENOMEM` and leaving
+    /// `old` in place if that takes a user other than root past [`PIPE_USER_QUOTA`]
+    fn recharge(&mut self, old: &mut Option<Charge>, uid: u32, bytes: usize) -> Result<()> {
+        let total = self.0.get(&uid).copied().unwrap_or(0);
+        let refund = old.filter(|old| old.uid == uid).map_or(0, |old| old.bytes);
+        if uid != 0 && total.saturating_sub(refund).saturating_add(bytes) > PIPE_USER_QUOTA {
+            return Err(Error::new(ENOMEM));
+        }
+
+        if let Some(old) = old.take() {
+            let total = self.0.entry(old.uid).or_insert(0);
+            *total = total.saturating_sub(old.bytes);
+            if *total == 0 {
+                self.0.remove(&old.uid);
+            }
+        }
+        if bytes > 0 {
+            let total = self.0.entry(uid).or_insert(0);
+            *total = total.saturating_add(bytes);
+            *old = Some(Charge { uid, bytes });
+        }
+        Ok(())
+    }
+}
+
+/// A description passed over a pipe
+type PassedFd = Arc<spin::RwLock<FileDescription>>;
+
+/// Close descriptions passed over a pipe that will never be received
+fn close_fds(fds: Vec<PassedFd>, token: &mut CleanLockToken) {
+    for description in fds {
+        let _ = FileDescriptor {
+            description,
+            cloexec: false,
+        }
+        .close(token);
+    }
+}
+
+/// Take over the writer's page at `page`, leaving a zeroed page in its place
+fn take_page(addr_space: &AddrSpace, page: Page) -> Option<PipePage> {
+    let zeroed = RaiiFrame::allocate().ok()?;
+    unsafe {
+        (RmmA::phys_to_virt(zeroed.get().base()).data() as *mut u8).write_bytes(0, PAGE_SIZE);
+    }
+    addr_space
+        .acquire_write()
+        .swap_frame(page, zeroed)
+        .ok()
+        .map(PipePage)
+}
+
+/// A frame moved into the pipe by a writer in splice mode
+struct PipePage(RaiiFrame);
+
+impl AsRef<[u8]> for PipePage {
+    fn as_ref(&self) -> &[u8] {
+        let virt = unsafe { RmmA::phys_to_virt(self.0.get().base()) };
+        unsafe { core::slice::from_raw_parts(virt.data() as *const u8, PAGE_SIZE) }
+    }
+}
+
+/// The bytes buffered in a pipe, in the order they were written.
+///
+/// Runs of copied bytes and whole pages moved in by the writer alternate, so that pages can be
+/// moved out to the reader again as long as nothing has been read from them. Descriptions passed
+/// through kfdwrite are kept apart, along with the position in the stream they were sent at.
+struct PipeQueue<P = PipePage> {
+    chunks: VecDeque<Chunk<P>>,
+    len: usize,
+    /// Number of bytes writers may fill the queue up to
+    capacity: usize,
+    /// Number of bytes read since the pipe was created
+    consumed: u64,
+    fds: FdQueue<PassedFd>,
+}
+
+/// Batches of descriptions passed over a pipe, in the order they were sent
+struct FdQueue<F> {
+    messages: VecDeque<FdMessage<F>>,
+    /// Number of batches removed so far, which identifies the next one
+    removed: u64,
+}
+
+struct FdMessage<F> {
+    /// Number of bytes written to the pipe before the batch was sent
+    at: u64,
+    fds: Vec<F>,
+}
+
+impl<F> FdQueue<F> {
+    fn new() -> Self {
+        Self {
+            messages: VecDeque::new(),
+            removed: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+    fn is_full(&self) -> bool {
+        self.messages.len() >= MAX_FD_MESSAGES
+    }
+
+    fn push(&mut self, at: u64, fds: Vec<F>) {
+        self.messages.push_back(FdMessage { at, fds });
+    }
+    /// Position in the stream of the next batch
+    fn next_at(&self) -> Option<u64> {
+        self.messages.front().map(|message| message.at)
+    }
+    /// The next batch, if it was sent at position `at`
+    fn front_at(&self, at: u64) -> Option<&[F]> {
+        self.messages
+            .front()
+            .filter(|message| message.at == at)
+            .map(|message| message.fds.as_slice())
+    }
+    /// Remove the next batch, if it was sent at position `at`
+    fn pop_at(&mut self, at: u64) -> Option<Vec<F>> {
+        self.front_at(at)?;
+        self.removed = self.removed.wrapping_add(1);
+        self.messages.pop_front().map(|message| message.fds)
+    }
+    /// Identifies the next batch, which stays the same until it is removed
+    fn next_id(&self) -> u64 {
+        self.removed
+    }
+    /// Remove the next batch, if it is still the one `next_id` returned `id` for and was sent at
+    /// position `at`
+    fn pop_id(&mut self, at: u64, id: u64) -> Option<Vec<F>> {
+        if id != self.removed {
+            return None;
+        }
+        self.pop_at(at)
+    }
+    /// Remove every batch
+    fn take(&mut self) -> Vec<F> {
+        self.removed = self.removed.wrapping_add(self.messages.len() as u64);
+        self.messages
+            .drain(..)
+            .flat_map(|message| message.fds)
+            .collect()
+    }
+}
+
+enum Chunk<P> {
+    Bytes(VecDeque<u8>),
+    /// A page of which the first `consumed` bytes have been read
+    Page {
+        page: P,
+        consumed: usize,
+    },
+}
+
+impl<P: AsRef<[u8]>> PipeQueue<P> {
+    fn new() -> Self {
+        Self {
+            chunks: VecDeque::new(),
+            len: 0,
+            capacity: DEFAULT_PIPE_SIZE,
+            consumed: 0,
+            fds: FdQueue::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+    /// Change the capacity, failing with `EBUSY` if more than that is buffered
+    fn set_capacity(&mut self, capacity: usize) -> Result<()> {
+        if capacity < self.len {
+            return Err(Error::new(EBUSY));
+        }
+        self.capacity = capacity;
+        Ok(())
+    }
+    /// Number of bytes writers may add before the queue is full
+    fn room(&self) -> usize {
+        self.capacity.saturating_sub(self.len)
+    }
+    fn is_full(&self) -> bool {
+        self.room() == 0
+    }
+
+    /// Whether a reader has bytes or descriptions to receive
+    fn has_data(&self) -> bool {
+        !self.is_empty() || !self.fds.is_empty()
+    }
+    /// Number of bytes that can be read before reaching passed descriptions
+    fn readable(&self) -> usize {
+        match self.fds.next_at() {
+            Some(at) => usize::try_from(at.saturating_sub(self.consumed))
+                .unwrap_or(usize::MAX)
+                .min(self.len),
+            None => self.len,
+        }
+    }
+    /// Pass `fds` at the end of the bytes written so far
+    fn push_fds(&mut self, fds: Vec<PassedFd>) {
+        let at = self.consumed.saturating_add(self.len as u64);
+        self.fds.push(at, fds);
+    }
+    /// The descriptions passed at the current read position
+    fn front_fds(&self) -> Option<&[PassedFd]> {
+        self.fds.front_at(self.consumed)
+    }
+    fn pop_fds(&mut self) -> Option<Vec<PassedFd>> {
+        self.fds.pop_at(self.consumed)
+    }
+    /// Identifies the descriptions `front_fds` returns, for `commit_fds`
+    fn front_fds_id(&self) -> u64 {
+        self.fds.next_id()
+    }
+    /// Remove the descriptions `front_fds` returned along with `id`, unless they were removed
+    /// since. Returns whether they were.
+    fn commit_fds(&mut self, id: u64) -> bool {
+        self.fds.pop_id(self.consumed, id).is_some()
+    }
+    /// Remove every description not yet received
+    fn take_fds(&mut self) -> Vec<PassedFd> {
+        self.fds.take()
+    }
+
+    fn push_bytes(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        match self.chunks.back_mut() {
+            Some(Chunk::Bytes(queue)) => queue.extend(bytes),
+            _ => self
+                .chunks
+                .push_back(Chunk::Bytes(bytes.iter().copied().collect())),
+        }
+        self.len += bytes.len();
+    }
+    fn push_page(&mut self, page: P) {
+        self.len += page.as_ref().len();
+        self.chunks.push_back(Chunk::Page { page, consumed: 0 });
+    }
+
+    /// Remove the page at the front of the queue, provided none of it has been read yet
+    fn pop_page(&mut self) -> Option<P> {
+        if !matches!(self.chunks.front(), Some(Chunk::Page { consumed: 0, .. })) {
+            return None;
+        }
+        let Some(Chunk::Page { page, .. }) = self.chunks.pop_front() else {
+            unreachable!("front chunk was just checked");
+        };
+        self.len -= page.as_ref().len();
+        self.consumed = self.consumed.saturating_add(page.as_ref().len() as u64);
+        Some(page)
+    }
+    /// Put back a page returned by `pop_page`
+    fn unpop_page(&mut self, page: P) {
+        self.len += page.as_ref().len();
+        self.consumed = self.consumed.saturating_sub(page.as_ref().len() as u64);
+        self.chunks.push_front(Chunk::Page { page, consumed: 0 });
+    }
+
+    /// The unread bytes at the front of the queue that are contiguous in memory
+    fn front_bytes(&self) -> &[u8] {
+        match self.chunks.front() {
+            Some(Chunk::Bytes(queue)) => queue.as_slices().0,
+            Some(Chunk::Page { page, consumed }) => &page.as_ref()[*consumed..],
+            None => &[],
+        }
+    }
+    /// Drop `count` bytes, which must not exceed `front_bytes().len()`
+    fn consume(&mut self, count: usize) {
+        let exhausted = match self.chunks.front_mut() {
+            Some(Chunk::Bytes(queue)) => {
+                let _ = queue.drain(..count);
+                queue.is_empty()
+            }
+            Some(Chunk::Page { page, consumed }) => {
+                *consumed += count;
+                *consumed == page.as_ref().len()
+            }
+            None => return,
+        };
+        if exhausted {
+            self.chunks.pop_front();
+        }
+        self.len -= count;
+        self.consumed = self.consumed.saturating_add(count as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheme::SchemeId;
+    use alloc::vec;
+
+    fn new_pipe() -> Pipe {
+        Pipe::new(HandleOwner {
+            mode: 0o600,
+            uid: 0,
+            gid: 0,
+        })
+    }
+
+    #[test]
+    fn end_paths_name_the_pipe() {
+        assert_eq!(end_path(6), "pipe:read/6");
+        assert_eq!(end_path(6 | WRITE_NOT_READ_BIT), "pipe:write/6");
+    }
+
+    #[test]
+    fn read_event_only_on_empty_to_nonempty() {
+        let pipe = new_pipe();
+        assert!(pipe.reader_events_after_write(0).is_empty());
+
+        pipe.reader_interest
+            .store(EVENT_READ.bits(), Ordering::Release);
+        assert_eq!(pipe.reader_events_after_write(0), EVENT_READ);
+        assert!(pipe.reader_events_after_write(1).is_empty());
+    }
+
+    #[test]
+    fn write_event_only_on_full_to_nonfull() {
+        let pipe = new_pipe();
+        pipe.writer_interest
+            .store(EVENT_WRITE.bits(), Ordering::Release);
+        assert_eq!(
+            pipe.writer_events_after_read(DEFAULT_PIPE_SIZE, DEFAULT_PIPE_SIZE),
+            EVENT_WRITE
+        );
+        assert!(pipe
+            .writer_events_after_read(DEFAULT_PIPE_SIZE - 1, DEFAULT_PIPE_SIZE)
+            .is_empty());
+        assert_eq!(
+            pipe.writer_events_after_read(PAGE_SIZE, PAGE_SIZE),
+            EVENT_WRITE
+        );
+    }
+
+    #[test]
+    fn hangup_wakes_registered_interest() {
+        let pipe = new_pipe();
+        assert!(pipe.reader_hangup_events().is_empty());
+
+        pipe.reader_interest
+            .store(EVENT_READ.bits(), Ordering::Release);
+        assert_eq!(pipe.reader_hangup_events(), EVENT_READ);
+    }
+
+    fn page(byte: u8) -> Vec<u8> {
+        vec![byte; PAGE_SIZE]
+    }
+
+    fn drain(queue: &mut PipeQueue<Vec<u8>>) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            let count = queue.front_bytes().len();
+            if count == 0 {
+                break;
+            }
+            out.extend_from_slice(queue.front_bytes());
+            queue.consume(count);
+        }
+        out
+    }
+
+    #[test]
+    fn interleaved_writes_keep_their_order() {
+        let mut queue = PipeQueue::new();
+        queue.push_bytes(b"ab");
+        queue.push_page(page(b'x'));
+        queue.push_bytes(b"cd");
+        queue.push_bytes(b"ef");
+        queue.push_page(page(b'y'));
+        queue.push_page(page(b'z'));
+        assert_eq!(queue.len(), 6 + 3 * PAGE_SIZE);
+
+        let mut expected = b"ab".to_vec();
+        expected.extend(page(b'x'));
+        expected.extend_from_slice(b"cdef");
+        expected.extend(page(b'y'));
+        expected.extend(page(b'z'));
+        assert_eq!(drain(&mut queue), expected);
+        assert!(queue.is_empty());
+        assert!(queue.chunks.is_empty());
+    }
+
+    #[test]
+    fn small_writes_share_a_chunk() {
+        let mut queue = PipeQueue::<Vec<u8>>::new();
+        queue.push_bytes(b"ab");
+        queue.push_bytes(b"");
+        queue.push_bytes(b"cd");
+        assert_eq!(queue.chunks.len(), 1);
+        assert_eq!(queue.len(), 4);
+    }
+
+    #[test]
+    fn only_unread_pages_are_popped() {
+        let mut queue = PipeQueue::new();
+        queue.push_bytes(b"ab");
+        queue.push_page(page(b'x'));
+        queue.push_page(page(b'y'));
+        assert!(queue.pop_page().is_none());
+
+        queue.consume(2);
+        assert_eq!(queue.pop_page(), Some(page(b'x')));
+        assert_eq!(queue.len(), PAGE_SIZE);
+
+        // Once part of a page has been copied out, the rest must be copied as well
+        queue.consume(10);
+        assert!(queue.pop_page().is_none());
+        assert_eq!(queue.front_bytes().len(), PAGE_SIZE - 10);
+        queue.consume(PAGE_SIZE - 10);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn unpopped_page_is_read_first() {
+        let mut queue = PipeQueue::new();
+        queue.push_page(page(b'x'));
+        queue.push_bytes(b"ab");
+
+        let popped = queue.pop_page().expect("page is at the front");
+        queue.unpop_page(popped);
+        assert_eq!(queue.len(), PAGE_SIZE + 2);
+
+        let mut expected = page(b'x');
+        expected.extend_from_slice(b"ab");
+        assert_eq!(drain(&mut queue), expected);
+    }
+
+    fn description(number: usize) -> PassedFd {
+        Arc::new(spin::RwLock::new(FileDescription {
+            offset: 0,
+            scheme: SchemeId::new(0),
+            number,
+            flags: 0,
+            internal_flags: InternalFlags::empty(),
+        }))
+    }
+
+    #[test]
+    fn passed_fds_are_received_where_they_were_sent() {
+        let mut queue = PipeQueue::<Vec<u8>>::new();
+        queue.push_bytes(b"ab");
+        queue.push_fds(vec![description(1), description(2)]);
+        queue.push_bytes(b"cd");
+        assert!(queue.has_data());
+        assert!(queue.front_fds().is_none());
+        assert_eq!(queue.readable(), 2);
+
+        queue.consume(2);
+        assert_eq!(queue.readable(), 0);
+        let fds = queue.pop_fds().expect("fds were sent after two bytes");
+        let numbers = fds.iter().map(|fd| fd.read().number).collect::<Vec<_>>();
+        assert_eq!(numbers, [1, 2]);
+        assert_eq!(queue.readable(), 2);
+        assert_eq!(drain(&mut queue), b"cd");
+
+        // Descriptions sent to an empty pipe are ready on their own
+        queue.push_fds(vec![description(3)]);
+        assert!(queue.is_empty() && queue.has_data());
+        assert_eq!(queue.take_fds().len(), 1);
+        assert!(!queue.has_data());
+    }
+
+    #[test]
+    fn fd_batches_keep_their_order() {
+        let mut fds = FdQueue::new();
+        fds.push(0, vec![1]);
+        fds.push(0, vec![2, 3]);
+        fds.push(5, vec![4]);
+        assert!(fds.front_at(5).is_none());
+
+        assert_eq!(fds.pop_at(0), Some(vec![1]));
+        // A batch is only received once, by the first reader to commit it
+        let id = fds.next_id();
+        assert_eq!(fds.front_at(0), Some(&[2, 3][..]));
+        assert_eq!(fds.pop_id(0, id), Some(vec![2, 3]));
+        assert_eq!(fds.pop_id(0, id), None);
+        assert_eq!(fds.pop_at(0), None);
+        assert_eq!(fds.next_at(), Some(5));
+
+        fds.push(7, vec![5]);
+        assert_eq!(fds.take(), vec![4, 5]);
+        assert!(fds.is_empty());
+    }
+
+    #[test]
+    fn capacity_cannot_shrink_below_buffered_bytes() {
+        let mut queue = PipeQueue::<Vec<u8>>::new();
+        queue.push_bytes(&[0; 100]);
+        assert_eq!(queue.room(), DEFAULT_PIPE_SIZE - 100);
+
+        assert_eq!(queue.set_capacity(99), Err(Error::new(EBUSY)));
+        assert_eq!(queue.capacity(), DEFAULT_PIPE_SIZE);
+        assert_eq!(queue.set_capacity(100), Ok(()));
+        assert!(queue.is_full());
+
+        // The buffered bytes survive the change
+        queue.set_capacity(2 * DEFAULT_PIPE_SIZE).unwrap();
+        assert_eq!(drain(&mut queue), vec![0; 100]);
+    }
+
+    #[test]
+    fn charges_are_limited_per_user() {
+        let mut charges = PipeCharges(BTreeMap::new());
+        let mut first = None;
+        let mut second = None;
+
+        charges.recharge(&mut first, 1000, PIPE_USER_QUOTA).unwrap();
+        assert_eq!(
+            charges.recharge(&mut second, 1000, 1),
+            Err(Error::new(ENOMEM))
+        );
+        assert_eq!(second, None);
+
+        // Shrinking the first pipe makes room, and other users are not affected
+        charges
+            .recharge(&mut first, 1000, PIPE_USER_QUOTA / 2)
+            .unwrap();
+        charges
+            .recharge(&mut second, 1000, PIPE_USER_QUOTA / 2)
+            .unwrap();
+        charges.recharge(&mut None, 1001, PIPE_USER_QUOTA).unwrap();
+        charges.recharge(&mut None, 0, 2 * PIPE_USER_QUOTA).unwrap();
+
+        charges.recharge(&mut first, 0, 0).unwrap();
+        charges.recharge(&mut second, 0, 0).unwrap();
+        assert_eq!(first, None);
+        assert_eq!(charges.0.get(&1000), None);
+    }
 }
@@ -4,28 +4,34 @@ use crate::{
         self,
         context::{HardBlockedReason, SignalState},
         file::InternalFlags,
-        memory::{handle_notify_files, AddrSpace, AddrSpaceWrapper, Grant, PageSpan},
+        name::CONTEXT_NAME_CAPAC,
+        memory::{
+            handle_notify_files, AddrSpace, AddrSpaceWrapper, Grant, PageSpan, Provider,
+            GRANT_NAME_MAX,
+        },
         Context, ContextLock, Status,
     },
+    cpu_set::LogicalCpuSet,
     memory::PAGE_SIZE,
-    ptrace,
+    ptrace, scheduler,
     scheme::{self, FileHandle, KernelScheme},
     sync::{CleanLockToken, RwLock, L1},
     syscall::{
         data::{GrantDesc, GrantFlags, Map, SetSighandlerData, Stat},
         error::*,
         flag::*,
-        usercopy::{UserSliceRo, UserSliceRw, UserSliceWo},
+        usercopy::{UserSlice, UserSliceRo, UserSliceRw, UserSliceWo},
         EnvRegisters, FloatRegisters, IntRegisters,
     },
 };
 
-use crate::context::context::FdTbl;
+use crate::context::context::{FdTbl, FileLimit};
 
-use super::{CallerCtx, GlobalSchemes, KernelSchemes, OpenResult};
+use super::{CallerCtx, GlobalSchemes, KernelSchemes, OpenResult, SchemeId};
 use ::syscall::{ProcSchemeAttrs, SigProcControl, Sigcontrol};
 use alloc::{
     boxed::Box,
+    format,
     string::String,
     sync::{Arc, Weak},
     vec::Vec,
@@ -41,6 +47,14 @@ use hashbrown::{
     HashMap,
 };
 
+/// Address space operation labelling the grants in a range, taking the range's base and size
+/// and the address and length of the name, where a length of 0 removes the label. It follows the
+/// operations defined by the syscall crate, which has no number for it yet.
+const ADDRSPACE_OP_SET_NAME: usize = 4;
+
+/// Most of `proc:<pid>/mem` copied by one read or write, through a kernel buffer
+const MEM_CHUNK_SIZE: usize = 16 * PAGE_SIZE;
+
 fn read_from(dst: UserSliceWo, src: &[u8], offset: u64) -> Result<usize> {
     let avail_src = usize::try_from(offset)
         .ok()
@@ -49,6 +63,114 @@ fn read_from(dst: UserSliceWo, src: &[u8], offset: u64) -> Result<usize> {
     dst.copy_common_bytes_from_slice(avail_src)
 }
 
+/// Format the `stat` line of a context.
+///
+/// Fields are space separated, with the name last since it may itself contain spaces:
+/// `status priority is_realtime cpu_time_ns last_cpu virtual_deadline switches name`. A context
+/// that has exited yields ESRCH rather than its final statistics.
+fn format_stat(context: &ContextLock, token: &mut CleanLockToken) -> Result<String> {
+    let (status, priority, is_realtime, cpu_time, last_cpu, virtual_deadline, switches, name) = {
+        let context = context.read(token.token());
+        let status = match context.status {
+            Status::Runnable => "Runnable",
+            Status::Blocked => "Blocked",
+            Status::HardBlocked {
+                reason: HardBlockedReason::Stopped,
+            } => "Stopped",
+            Status::HardBlocked { .. } => "HardBlocked",
+            Status::Zombie { .. } | Status::Dead { .. } => return Err(Error::new(ESRCH)),
+        };
+        (
+            status,
+            context.priority.effective_priority(),
+            context.is_realtime,
+            context.cpu_time,
+            context.last_cpu_id,
+            context.virtual_deadline,
+            context.switch_count,
+            context.name.get(),
+        )
+    };
+    let last_cpu = last_cpu.map_or(-1, |cpu| i64::from(cpu.get()));
+
+    Ok(format!(
+        "{status} {priority} {} {cpu_time} {last_cpu} {virtual_deadline} {switches} {name}\n",
+        u8::from(is_realtime),
+    ))
+}
+
+/// Format `proc:<pid>/maps`, one line per grant of the context's address space.
+///
+/// Fields are space separated, with the label last since it may itself contain spaces:
+/// `start-end perms provider locked pages label`. `perms` is `r`, `w` and `x` or `-` for each,
+/// `provider` one of `allocated`, `physborrowed`, `external` or `fmap`, followed by `:` and the
+/// scheme name for file mappings whose scheme is still registered, and `locked` is `L` for
+/// mlocked grants and `-` otherwise. A context that has exited yields ESRCH.
+fn format_maps(context: &ContextLock, token: &mut CleanLockToken) -> Result<String> {
+    use core::fmt::Write;
+
+    let (addr_space, ens) = {
+        let context = context.read(token.token());
+        if matches!(context.status, Status::Zombie { .. } | Status::Dead { .. }) {
+            return Err(Error::new(ESRCH));
+        }
+        (Arc::clone(context.addr_space()?), context.ens)
+    };
+
+    // Grants are snapshot under the read lock, and scheme names looked up once it is released
+    let grants = addr_space
+        .acquire_read()
+        .grants
+        .values()
+        .map(|grant| {
+            let (provider, scheme_id) = match &grant.provider {
+                Provider::Allocated { .. } => ("allocated", None),
+                Provider::PhysBorrowed { .. } => ("physborrowed", None),
+                Provider::IpcBuffer { .. } => ("ipcbuf", None),
+                Provider::FmapBorrowed { file_ref } => {
+                    ("fmap", Some(file_ref.description.read().scheme))
+                }
+            };
+            let start = grant.start_address().data();
+            (
+                start,
+                start + grant.page_count() * PAGE_SIZE,
+                grant.grant_flags(),
+                provider,
+                scheme_id,
+                grant.locked,
+                grant.page_count(),
+                grant.name().map(Box::<str>::from),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let schemes = scheme::schemes(&token.token());
+    let mut maps = String::new();
+    for (start, end, flags, provider, scheme_id, locked, pages, name) in grants {
+        let perm = |flag: MapFlags, c: char| if flags.contains(flag) { c } else { '-' };
+        let _ = write!(
+            maps,
+            "{start:016x}-{end:016x} {}{}{} {provider}",
+            perm(MapFlags::PROT_READ, 'r'),
+            perm(MapFlags::PROT_WRITE, 'w'),
+            perm(MapFlags::PROT_EXEC, 'x'),
+        );
+        if let Some((scheme_name, _)) =
+            scheme_id.and_then(|scheme_id| schemes.iter_name(ens).find(|&(_, &id)| id == scheme_id))
+        {
+            let _ = write!(maps, ":{scheme_name}");
+        }
+        let _ = writeln!(
+            maps,
+            " {} {pages} {}",
+            if locked { 'L' } else { '-' },
+            name.as_deref().unwrap_or(""),
+        );
+    }
+    Ok(maps)
+}
+
 fn try_stop_context<T>(
     context_ref: Arc<ContextLock>,
     token: &mut CleanLockToken,
@@ -143,6 +265,25 @@ enum ContextHandle {
     SchedAffinity,
 
     MmapMinAddr(Arc<AddrSpaceWrapper>),
+
+    /// `proc:<pid>/stat`, one line of scheduler statistics
+    Stat,
+
+    /// `proc:<pid>/maps`, one line per grant of the address space
+    Maps,
+
+    /// `proc:<pid>/mem`, the memory of the context at offsets equal to its virtual addresses.
+    /// Read-only memory can only be written by its tracer, while the context is in ptrace-stop.
+    Mem,
+
+    /// `proc:<pid>/trace`, attaching the process that opened it as the tracer of the context
+    /// until it is closed
+    Trace(Arc<ptrace::Session>),
+
+    /// `proc:<pid>/ctl`, accepts "kill", "interrupt", "unblock", "rlimit nofile <soft> <hard>",
+    /// "rlimit memlock <bytes>", "affinity <hexmask>" and "nice <value>", and reads back the
+    /// limits, affinity and nice value in the same form
+    Ctl,
 }
 #[derive(Clone)]
 struct Handle {
@@ -182,6 +323,24 @@ fn new_handle(
     Ok((id, fl))
 }
 
+/// The context `proc:<pid>/...` refers to, which only root and its owner may open
+fn per_pid_context(
+    pid: &str,
+    ctx: &CallerCtx,
+    token: &mut CleanLockToken,
+) -> Result<Arc<ContextLock>> {
+    let pid = pid.parse::<usize>().map_err(|_| Error::new(ENOENT))?;
+    let context = context::contexts()
+        .read()
+        .get(&pid)
+        .cloned()
+        .ok_or(Error::new(ESRCH))?;
+    if ctx.uid != 0 && ctx.uid != context.read(token.token()).euid {
+        return Err(Error::new(EACCES));
+    }
+    Ok(context)
+}
+
 enum OpenTy {
     Ctxt(Arc<ContextLock>),
     Auth,
@@ -215,7 +374,8 @@ impl ProcScheme {
             ),
             "current-addrspace" => (ContextHandle::CurrentAddrSpace, false),
             "current-filetable" => (ContextHandle::CurrentFiletable, false),
-            "regs/float" => (ContextHandle::Regs(RegsKind::Float), false),
+            // `fpregs` is the name ptrace users look for
+            "regs/float" | "fpregs" => (ContextHandle::Regs(RegsKind::Float), false),
             "regs/int" => (ContextHandle::Regs(RegsKind::Int), false),
             "regs/env" => (ContextHandle::Regs(RegsKind::Env), false),
             "sighandler" => (ContextHandle::Sighandler, false),
@@ -232,6 +392,10 @@ impl ProcScheme {
             ),
             "sched-affinity" => (ContextHandle::SchedAffinity, true),
             "status" => (ContextHandle::Status { privileged: false }, false),
+            "stat" => (ContextHandle::Stat, true),
+            "maps" => (ContextHandle::Maps, true),
+            "mem" => (ContextHandle::Mem, true),
+            "ctl" => (ContextHandle::Ctl, true),
             _ if path.starts_with("auth-") => {
                 let nonprefix = &path["auth-".len()..];
                 let next_dash = nonprefix.find('-').ok_or(Error::new(ENOENT))?;
@@ -288,6 +452,8 @@ impl ProcScheme {
                         let id = NonZeroUsize::new(NEXT_ID.fetch_add(1, Ordering::Relaxed))
                             .ok_or(Error::new(EMFILE))?;
                         let context = context::spawn(true, Some(id), || ret(), token)?;
+                        let inherited = context::current().read(token.token()).inherited();
+                        context.write(token.token()).inherit(inherited);
                         HANDLES.write(token.token()).insert(
                             id.get(),
                             Handle {
@@ -336,11 +502,7 @@ impl ProcScheme {
                     use core::fmt::Write;
 
                     let mut data = String::new();
-                    for index in filetable
-                        .read()
-                        .enumerate()
-                        .filter_map(|(idx, val)| val.as_ref().map(|_| idx))
-                    {
+                    for (index, _) in filetable.read().enumerate() {
                         writeln!(data, "{}", index).unwrap();
                     }
                     data.into_bytes().into_boxed_slice()
@@ -369,9 +531,46 @@ impl KernelScheme for ProcScheme {
         &self,
         path: &str,
         _flags: usize,
-        _ctx: CallerCtx,
+        ctx: CallerCtx,
         token: &mut CleanLockToken,
     ) -> Result<OpenResult> {
+        let per_pid = path
+            .strip_suffix("/stat")
+            .map(|pid| (pid, ContextHandle::Stat, InternalFlags::POSITIONED))
+            .or_else(|| {
+                path.strip_suffix("/maps")
+                    .map(|pid| (pid, ContextHandle::Maps, InternalFlags::POSITIONED))
+            })
+            .or_else(|| {
+                path.strip_suffix("/mem")
+                    .map(|pid| (pid, ContextHandle::Mem, InternalFlags::POSITIONED))
+            })
+            .or_else(|| {
+                path.strip_suffix("/ctl")
+                    .map(|pid| (pid, ContextHandle::Ctl, InternalFlags::POSITIONED))
+            });
+        if let Some((pid, kind, flags)) = per_pid {
+            let context = per_pid_context(pid, &ctx, token)?;
+            let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+            HANDLES
+                .write(token.token())
+                .insert(id, Handle { context, kind });
+            return Ok(OpenResult::SchemeLocal(id, flags));
+        }
+        if let Some(pid) = path.strip_suffix("/trace") {
+            let context = per_pid_context(pid, &ctx, token)?;
+            let tracee = context.read(token.token()).id();
+            let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+            let session = ptrace::attach(tracee, ctx.pid, id, token)?;
+            HANDLES.write(token.token()).insert(
+                id,
+                Handle {
+                    context,
+                    kind: ContextHandle::Trace(session),
+                },
+            );
+            return Ok(OpenResult::SchemeLocal(id, InternalFlags::empty()));
+        }
         if path != "authority" {
             return Err(Error::new(ENOENT));
         }
@@ -442,6 +641,13 @@ impl KernelScheme for ProcScheme {
             } => {
                 context.write(token.token()).files = new_ft;
             }
+            Handle {
+                kind: ContextHandle::Trace(session),
+                context,
+            } => {
+                let tracee = context.read(token.token()).id();
+                ptrace::detach(tracee, &session, token);
+            }
             _ => (),
         }
         Ok(())
@@ -610,13 +816,11 @@ impl KernelScheme for ProcScheme {
         &self,
         id: usize,
         buf: UserSliceRo,
-        _offset: u64,
+        offset: u64,
         _fcntl_flags: u32,
         _stored_flags: u32,
         token: &mut CleanLockToken,
     ) -> Result<usize> {
-        // TODO: offset
-
         // Don't hold a global lock during the context switch later on
         let handle = {
             let mut handles = HANDLES.write(token.token());
@@ -625,7 +829,7 @@ impl KernelScheme for ProcScheme {
         };
 
         let Handle { context, kind } = handle;
-        kind.kwriteoff(id, context, buf, token)
+        kind.kwriteoff(id, context, buf, offset, token)
     }
 
     fn kfpath(&self, id: usize, buf: UserSliceWo, token: &mut CleanLockToken) -> Result<usize> {
@@ -732,7 +936,7 @@ impl KernelScheme for ProcScheme {
                     }
                     let filetable = filetable.upgrade().ok_or(Error::new(EOWNERDEAD))?;
 
-                    let new_filetable = Arc::new(spin::RwLock::new(filetable.read().clone()));
+                    let new_filetable = Arc::new(spin::RwLock::new(filetable.read().try_clone()?));
 
                     handle(
                         Handle {
@@ -798,22 +1002,44 @@ impl KernelScheme for ProcScheme {
         .map(|(r, fl)| OpenResult::SchemeLocal(r, fl))
     }
 }
+/// Handle a command written to a file table, which can only be "cloexec". Exec, which is done in
+/// userspace, writes it to close the descriptors marked close-on-exec before the new image runs.
+fn write_filetable(
+    filetable: &spin::RwLock<FdTbl>,
+    buf: UserSliceRo,
+    token: &mut CleanLockToken,
+) -> Result<usize> {
+    let mut command = [0_u8; 7];
+    if buf.len() != command.len() {
+        return Err(Error::new(EINVAL));
+    }
+    buf.copy_to_slice(&mut command)?;
+    if &command != b"cloexec" {
+        return Err(Error::new(EINVAL));
+    }
+    crate::syscall::process::close_on_exec(filetable, token);
+    Ok(buf.len())
+}
+
 fn extract_scheme_number(
     fd: usize,
     token: &mut CleanLockToken,
 ) -> Result<(Arc<KernelSchemes>, usize)> {
+    extract_scheme_id_number(fd, token).map(|(_, scheme, number)| (scheme, number))
+}
+fn extract_scheme_id_number(
+    fd: usize,
+    token: &mut CleanLockToken,
+) -> Result<(SchemeId, Arc<KernelSchemes>, usize)> {
     let file_descriptor = context::current()
         .read(token.token())
         .get_file(FileHandle::from(fd))
         .ok_or(Error::new(EBADF))?;
     let desc = file_descriptor.description.read();
     let (scheme_id, number) = (desc.scheme, desc.number);
-    let scheme = scheme::schemes(&token.token())
-        .get(scheme_id)
-        .ok_or(Error::new(ENODEV))?
-        .clone();
+    let scheme = scheme::scheme(scheme_id).ok_or(Error::new(EIO))?;
 
-    Ok((scheme, number))
+    Ok((scheme_id, scheme, number))
 }
 fn verify_scheme(scheme: &KernelSchemes) -> Result<()> {
     if !matches!(scheme, KernelSchemes::Global(GlobalSchemes::Proc)) {
@@ -836,6 +1062,7 @@ impl ContextHandle {
         id: usize,
         context: Arc<ContextLock>,
         buf: UserSliceRo,
+        offset: u64,
         token: &mut CleanLockToken,
     ) -> Result<usize> {
         match self {
@@ -858,27 +1085,35 @@ impl ContextHandle {
                             return Err(Error::new(EOPNOTSUPP));
                         }
 
-                        let (scheme, number) = extract_scheme_number(fd, token)?;
+                        let (scheme_id, scheme, number) = extract_scheme_id_number(fd, token)?;
 
                         let scheme: Arc<dyn KernelScheme> = scheme;
-                        scheme.kfmap(
-                            number,
-                            &addrspace,
-                            &Map {
-                                offset,
-                                size: page_span.count * PAGE_SIZE,
-                                address: page_span.base.start_address().data(),
-                                flags,
-                            },
-                            op == ADDRSPACE_OP_TRANSFER,
-                            token,
+                        scheme_timed!(
+                            scheme_id,
+                            Fmap,
+                            scheme.kfmap(
+                                number,
+                                &addrspace,
+                                &Map {
+                                    offset,
+                                    size: page_span.count * PAGE_SIZE,
+                                    address: page_span.base.start_address().data(),
+                                    flags,
+                                },
+                                op == ADDRSPACE_OP_TRANSFER,
+                                token,
+                            )
                         )?;
+
+                        // MAP_FIXED may have replaced locked pages
+                        let locked_pages = addrspace.acquire_read().locked_pages();
+                        context.write(token.token()).memory_locked_count = locked_pages;
                     }
                     ADDRSPACE_OP_MUNMAP => {
                         let page_span = crate::syscall::validate_region(next()??, next()??)?;
 
                         let unpin = false;
-                        addrspace.acquire_write().munmap(page_span, unpin)?;
+                        addrspace.munmap_for(page_span, unpin, &context, token)?;
                     }
                     ADDRSPACE_OP_MPROTECT => {
                         let page_span = crate::syscall::validate_region(next()??, next()??)?;
@@ -886,6 +1121,24 @@ impl ContextHandle {
 
                         addrspace.acquire_write().mprotect(page_span.base, page_span.count, flags)?;
                     }
+                    ADDRSPACE_OP_SET_NAME => {
+                        let page_span = crate::syscall::validate_region(next()??, next()??)?;
+                        let (name_base, name_len) = (next()??, next()??);
+
+                        let mut name = [0_u8; GRANT_NAME_MAX];
+                        let name = if name_len == 0 {
+                            None
+                        } else {
+                            let name = name.get_mut(..name_len).ok_or(Error::new(EINVAL))?;
+                            UserSlice::ro(name_base, name_len)?.copy_to_slice(name)?;
+                            Some(str::from_utf8(name).map_err(|_| Error::new(EINVAL))?)
+                        };
+                        addrspace.acquire_write().set_name(
+                            page_span.base,
+                            page_span.count,
+                            name,
+                        )?;
+                    }
                     _ => return Err(Error::new(EINVAL)),
                 }
                 Ok(words_read * mem::size_of::<usize>())
@@ -984,17 +1237,22 @@ impl ContextHandle {
 
                 Ok(mem::size_of::<SetSighandlerData>())
             }
-            ContextHandle::Start => match context.write(token.token()).status {
-                ref mut status @ Status::HardBlocked {
-                    reason: HardBlockedReason::NotYetStarted,
-                } => {
-                    *status = Status::Runnable;
-                    Ok(buf.len())
+            ContextHandle::Start => {
+                match context.write(token.token()).status {
+                    ref mut status @ Status::HardBlocked {
+                        reason: HardBlockedReason::NotYetStarted,
+                    } => *status = Status::Runnable,
+                    _ => return Err(Error::new(EINVAL)),
                 }
-                _ => Err(Error::new(EINVAL)),
-            },
-            ContextHandle::Filetable { .. } | ContextHandle::NewFiletable { .. } => {
-                Err(Error::new(EBADF))
+                scheduler::wake(context, token);
+                Ok(buf.len())
+            }
+            ContextHandle::Filetable { filetable, .. } => {
+                let filetable = filetable.upgrade().ok_or(Error::new(EOWNERDEAD))?;
+                write_filetable(&filetable, buf, token)
+            }
+            ContextHandle::NewFiletable { filetable, .. } => {
+                write_filetable(&filetable, buf, token)
             }
 
             ContextHandle::CurrentFiletable => {
@@ -1002,6 +1260,16 @@ impl ContextHandle {
                 let (hopefully_this_scheme, number) = extract_scheme_number(filetable_fd, token)?;
                 verify_scheme(&hopefully_this_scheme)?;
 
+                // The files already in the table count against the limit of the context that
+                // switches to it, as they would had it opened them itself
+                let limit = context.read(token.token()).nofile.soft;
+                let check_limit = |ft: &spin::RwLock<FdTbl>| {
+                    if ft.read().open_count() > limit {
+                        return Err(Error::new(EMFILE));
+                    }
+                    Ok(())
+                };
+
                 let mut handles = HANDLES.write(token.token());
                 let Entry::Occupied(mut entry) = handles.entry(number) else {
                     return Err(Error::new(EBADF));
@@ -1010,7 +1278,11 @@ impl ContextHandle {
                     Handle {
                         kind: ContextHandle::Filetable { ref filetable, .. },
                         ..
-                    } => filetable.upgrade().ok_or(Error::new(EOWNERDEAD))?,
+                    } => {
+                        let ft = filetable.upgrade().ok_or(Error::new(EOWNERDEAD))?;
+                        check_limit(&ft)?;
+                        ft
+                    }
                     Handle {
                         kind:
                             ContextHandle::NewFiletable {
@@ -1019,6 +1291,7 @@ impl ContextHandle {
                             },
                         ..
                     } => {
+                        check_limit(filetable)?;
                         let ft = Arc::clone(filetable);
                         *entry.get_mut() = Handle {
                             kind: ContextHandle::Filetable {
@@ -1087,6 +1360,123 @@ impl ContextHandle {
 
                 Ok(mem::size_of_val(&mask))
             }
+            ContextHandle::Mem => {
+                let addr = usize::try_from(offset).map_err(|_| Error::new(EFAULT))?;
+                let mut data = vec![0_u8; buf.len().min(MEM_CHUNK_SIZE)];
+                let len = buf.copy_common_bytes_to_slice(&mut data)?;
+                let (addr_space, stopped, tracee) = {
+                    let context = context.read(token.token());
+                    if context.status.has_exited() {
+                        return Err(Error::new(ESRCH));
+                    }
+                    (
+                        Arc::clone(context.addr_space()?),
+                        ptrace::is_stopped(&context),
+                        context.id(),
+                    )
+                };
+                // Read after, as the target may be the caller itself
+                let caller = context::current().read(token.token()).pid;
+                let traced = stopped && ptrace::is_tracer(tracee, caller, token);
+                let data = data.get(..len).unwrap_or(&[]);
+                addr_space.acquire_write().write_mem(addr, data, traced)
+            }
+            ContextHandle::Ctl => {
+                let mut command = [0_u8; 48];
+                let len = buf.copy_common_bytes_to_slice(&mut command)?;
+                let command = core::str::from_utf8(&command[..len])
+                    .map_err(|_| Error::new(EINVAL))?
+                    .trim();
+
+                // Read first, as the target may be the caller itself
+                let euid = context::current().read(token.token()).euid;
+                if let Some(mask) = command.strip_prefix("affinity ") {
+                    let cpuset = LogicalCpuSet::from_hex(mask).ok_or(Error::new(EINVAL))?;
+                    if !cpuset.is_subset(&LogicalCpuSet::all()) {
+                        return Err(Error::new(EINVAL));
+                    }
+                    let id = {
+                        let guard = context.read(token.token());
+                        if guard.status.has_exited() {
+                            return Err(Error::new(ESRCH));
+                        }
+                        if euid != 0 && euid != guard.euid {
+                            return Err(Error::new(EPERM));
+                        }
+                        guard.id()
+                    };
+                    crate::topology::thread_set_affinity(id, cpuset, token)?;
+                    scheduler::affinity_changed(&context, token);
+                    return Ok(buf.len());
+                }
+                if let Some(nice) = command.strip_prefix("nice ") {
+                    let nice = nice.parse::<i32>().map_err(|_| Error::new(EINVAL))?;
+                    let priority = scheduler::nice_to_priority(nice).ok_or(Error::new(EINVAL))?;
+                    {
+                        let mut guard = context.write(token.token());
+                        if guard.status.has_exited() {
+                            return Err(Error::new(ESRCH));
+                        }
+                        // Only root may raise a priority above the default
+                        if euid != 0 && (euid != guard.euid || nice < 0) {
+                            return Err(Error::new(EPERM));
+                        }
+                        // RT and deadline contexts are ordered by their own priorities
+                        if guard.is_realtime || guard.sched_deadline.is_some() {
+                            return Err(Error::new(EINVAL));
+                        }
+                        guard.priority.set_base_priority_raw(priority);
+                    }
+                    scheduler::requeue_context(&context, token);
+                    scheduler::request_preemption(token);
+                    return Ok(buf.len());
+                }
+                let mut guard = context.write(token.token());
+                if guard.status.has_exited() {
+                    return Err(Error::new(ESRCH));
+                }
+                if let Some(limits) = command.strip_prefix("rlimit nofile ") {
+                    let mut limits = limits.split_ascii_whitespace().map(str::parse::<usize>);
+                    let (Some(Ok(soft)), Some(Ok(hard)), None) =
+                        (limits.next(), limits.next(), limits.next())
+                    else {
+                        return Err(Error::new(EINVAL));
+                    };
+                    guard.nofile.set(FileLimit { soft, hard }, euid)?;
+                    return Ok(buf.len());
+                }
+                if let Some(limit) = command.strip_prefix("rlimit memlock ") {
+                    let limit = limit
+                        .trim()
+                        .parse::<usize>()
+                        .map_err(|_| Error::new(EINVAL))?;
+                    // Only root may raise the limit
+                    if limit > guard.memlock_limit && euid != 0 {
+                        return Err(Error::new(EPERM));
+                    }
+                    guard.memlock_limit = limit;
+                    return Ok(buf.len());
+                }
+                let unblocked = match command {
+                    "kill" => {
+                        // Exits at its next syscall boundary, which breaking its wait brings on
+                        guard.being_sigkilled = true;
+                        guard.interrupt_pending = true;
+                        guard.unblock()
+                    }
+                    "interrupt" => {
+                        guard.interrupt_pending = true;
+                        guard.unblock()
+                    }
+                    "unblock" => guard.unblock(),
+                    _ => return Err(Error::new(EINVAL)),
+                };
+                drop(guard);
+                if unblocked {
+                    scheduler::wake(context, token);
+                }
+                Ok(buf.len())
+            }
             ContextHandle::Status { privileged } => {
                 let mut args = buf.usizes();
 
@@ -1104,7 +1494,9 @@ impl ContextHandle {
                         let mut guard = context.write(token.token());
 
                         match guard.status {
-                            Status::Dead { .. } => return Err(Error::new(EOWNERDEAD)),
+                            Status::Zombie { .. } | Status::Dead { .. } => {
+                                return Err(Error::new(EOWNERDEAD))
+                            }
                             Status::HardBlocked {
                                 reason: HardBlockedReason::AwaitingMmap { .. },
                             } => return Err(Error::new(EBUSY)),
@@ -1119,17 +1511,23 @@ impl ContextHandle {
                     ContextVerb::Unstop => {
                         let mut guard = context.write(token.token());
 
-                        if let Status::HardBlocked {
-                            reason: HardBlockedReason::Stopped,
-                        } = guard.status
-                        {
+                        let stopped = matches!(
+                            guard.status,
+                            Status::HardBlocked {
+                                reason: HardBlockedReason::Stopped,
+                            }
+                        );
+                        if stopped {
                             guard.status = Status::Runnable;
                         }
+                        drop(guard);
+                        if stopped {
+                            scheduler::wake(context, token);
+                        }
                         Ok(size_of::<usize>())
                     }
                     ContextVerb::Interrupt => {
-                        let mut guard = context.write(token.token());
-                        guard.unblock();
+                        scheduler::unblock(&context, token);
                         Ok(size_of::<usize>())
                     }
                     ContextVerb::ForceKill => {
@@ -1161,9 +1559,17 @@ impl ContextHandle {
                             crate::syscall::exit_this_context(0);
                         } else {
                             let mut ctxt = context.write(token.token());
+                            if ctxt.status.has_exited() {
+                                return Err(Error::new(ESRCH));
+                            }
                             //trace!("FORCEKILL NONSELF={} {}, SELF={}", ctxt.debug_id, ctxt.pid, context::current().read().debug_id);
+                            let blocked = !ctxt.status.is_runnable();
                             ctxt.status = context::Status::Runnable;
                             ctxt.being_sigkilled = true;
+                            drop(ctxt);
+                            if blocked {
+                                scheduler::wake(context, token);
+                            }
                             Ok(mem::size_of::<usize>())
                         }
                     }
@@ -1178,11 +1584,10 @@ impl ContextHandle {
                     .iter()
                     .position(|c| *c == 0)
                     .unwrap_or(info.debug_name.len())
-                    .min(guard.name.capacity());
+                    .min(CONTEXT_NAME_CAPAC);
                 let debug_name = core::str::from_utf8(&info.debug_name[..len])
                     .map_err(|_| Error::new(EINVAL))?;
-                guard.name.clear();
-                guard.name.push_str(debug_name);
+                guard.set_name(debug_name);
 
                 guard.pid = info.pid as usize;
                 guard.ens = (info.ens as usize).into();
@@ -1294,6 +1699,47 @@ impl ContextHandle {
                 buf.write_usize(addrspace.acquire_read().mmap_min)?;
                 Ok(mem::size_of::<usize>())
             }
+            ContextHandle::Stat => {
+                let line = format_stat(&context, token)?;
+                read_from(buf, line.as_bytes(), offset)
+            }
+            ContextHandle::Maps => {
+                let maps = format_maps(&context, token)?;
+                read_from(buf, maps.as_bytes(), offset)
+            }
+            ContextHandle::Mem => {
+                let addr = usize::try_from(offset).map_err(|_| Error::new(EFAULT))?;
+                let addr_space = {
+                    let context = context.read(token.token());
+                    if context.status.has_exited() {
+                        return Err(Error::new(ESRCH));
+                    }
+                    Arc::clone(context.addr_space()?)
+                };
+                // Copied through a kernel buffer, as faulting on the caller's buffer with the
+                // address space locked would deadlock if it is the caller's own
+                let mut data = vec![0_u8; buf.len().min(MEM_CHUNK_SIZE)];
+                let len = addr_space.acquire_read().read_mem(addr, &mut data)?;
+                buf.copy_common_bytes_from_slice(data.get(..len).unwrap_or(&[]))
+            }
+            ContextHandle::Ctl => {
+                let lines = {
+                    let context = context.read(token.token());
+                    let mut lines = format!(
+                        "rlimit nofile {} {}\nrlimit memlock {}\naffinity {:x}\n",
+                        context.nofile.soft,
+                        context.nofile.hard,
+                        context.memlock_limit,
+                        context.sched_affinity
+                    );
+                    if !context.is_realtime && context.sched_deadline.is_none() {
+                        let nice = scheduler::priority_to_nice(context.priority.base_priority());
+                        lines.push_str(&format!("nice {}\n", nice));
+                    }
+                    lines
+                };
+                read_from(buf, lines.as_bytes(), offset)
+            }
             ContextHandle::SchedAffinity => {
                 let mask = context.read(token.token()).sched_affinity.to_raw();
 
@@ -1304,13 +1750,20 @@ impl ContextHandle {
                 let status = {
                     let context = context.read(token.token());
                     match context.status {
-                        Status::Runnable | Status::Dead { excp: None }
+                        Status::Runnable
+                        | Status::Zombie { excp: None, .. }
+                        | Status::Dead { excp: None }
                             if context.being_sigkilled =>
                         {
                             ContextStatus::ForceKilled
                         }
-                        Status::Dead { excp: None } => ContextStatus::Dead,
-                        Status::Dead { excp: Some(excp) } => {
+                        Status::Zombie { excp: None, .. } | Status::Dead { excp: None } => {
+                            ContextStatus::Dead
+                        }
+                        Status::Zombie {
+                            excp: Some(excp), ..
+                        }
+                        | Status::Dead { excp: Some(excp) } => {
                             let (status, payload) =
                                 buf.split_at(size_of::<usize>()).ok_or(Error::new(EINVAL))?;
                             status.copy_from_slice(
@@ -1336,7 +1789,7 @@ impl ContextHandle {
                 let mut debug_name = [0; 32];
                 let c = &context.read(token.token());
                 let (euid, egid, ens, pid, name) =
-                    (c.euid, c.egid, c.ens.get() as u32, c.pid as u32, c.name);
+                    (c.euid, c.egid, c.ens.get() as u32, c.pid as u32, c.name.get());
                 let min = name.len().min(debug_name.len());
                 debug_name[..min].copy_from_slice(&name.as_bytes()[..min]);
                 buf.copy_common_bytes_from_slice(&ProcSchemeAttrs {
@@ -14,7 +14,7 @@ use crate::{
     context::{
         self,
         file::InternalFlags,
-        memory::{AddrSpaceWrapper, Grant, PageSpan},
+        memory::{handle_notify_files, AddrSpaceWrapper, Grant, PageSpan},
         ContextId,
     },
     memory::{
@@ -26,10 +26,10 @@ use crate::{
     sync::{CleanLockToken, OptimizedWaitQueue, IpcCriticalGuard},
     syscall::{
         data::Map,
-        error::{Error, Result, EBADF, EINVAL, EIO, ENOMEM, ESPIPE},
+        error::{Error, Result, EBADF, EFAULT, EINVAL, EIO, ENOMEM, ESPIPE},
         flag::{MapFlags, O_CLOEXEC, O_RDWR},
         number::*,
-        usercopy::UserSliceRo,
+        usercopy::{UserSliceRo, UserSliceRw},
     },
 };
 use alloc::vec::Vec;
@@ -68,6 +68,21 @@ pub struct Cqe {
     pub flags: u32,
 }
 
+/// Submission queue entries, which are followed by the completion queue in the ring page
+pub const SQ_ENTRIES: usize = 64;
+/// Completion queue entries, as many as fit in the rest of the page
+pub const CQ_ENTRIES: usize = 64;
+
+const SQ_MASK: u32 = (SQ_ENTRIES - 1) as u32;
+const CQ_MASK: u32 = (CQ_ENTRIES - 1) as u32;
+
+const _: () = assert!(
+    core::mem::size_of::<IpcRing>()
+        + SQ_ENTRIES * core::mem::size_of::<Sqe>()
+        + CQ_ENTRIES * core::mem::size_of::<Cqe>()
+        <= PAGE_SIZE
+);
+
 pub const IORING_OP_NOP: u8 = 0;
 pub const IORING_OP_READ: u8 = 1;
 pub const IORING_OP_WRITE: u8 = 2;
@@ -86,6 +101,16 @@ pub struct RingHandle {
     pub completion_wait_queue: OptimizedWaitQueue<()>,
 }
 
+impl RingHandle {
+    pub fn sq_ptr(&self) -> *mut Sqe {
+        unsafe { self.ring_ptr.add(1).cast() }
+    }
+
+    pub fn cq_ptr(&self) -> *mut Cqe {
+        unsafe { self.sq_ptr().add(SQ_ENTRIES).cast() }
+    }
+}
+
 // Safety: RingHandle owns the frame and pointer implies access to shared memory.
 unsafe impl Send for RingHandle {}
 unsafe impl Sync for RingHandle {}
@@ -103,6 +128,10 @@ impl RingScheme {
         }
     }
 
+    pub(super) fn handle(&self, id: usize) -> Option<Arc<RingHandle>> {
+        self.handles.read().get(&id).cloned()
+    }
+
     /// **Task 4.1:** Handles the asynchronous dispatch of an SQE.
     fn process_sqe(
         &self,
@@ -124,6 +153,20 @@ impl RingScheme {
 
             // These operations MUST be passed to a driver in a microkernel
             IORING_OP_READ | IORING_OP_WRITE | IORING_OP_CLOSE => {
+                // Catch buffers outside user memory here rather than in the driver, which the
+                // address could otherwise reach
+                if sqe.opcode != IORING_OP_CLOSE
+                    && UserSliceRw::from_u64(sqe.addr, u64::from(sqe.len)).is_err()
+                {
+                    let cqe = Cqe {
+                        user_data: sqe.user_data,
+                        res: -(EFAULT as i32),
+                        flags: 0,
+                    };
+                    Self::ring_complete(handle, &cqe, token);
+                    return Ok(());
+                }
+
                 let consumer_pid = handle.consumer_pid.load(Ordering::Relaxed);
 
                 if consumer_pid == 0 {
@@ -143,7 +186,7 @@ impl RingScheme {
                 handle.driver_queue.send((), token);
 
                 // Wake up the consumers of the driver_queue (the userspace driver)
-                handle.driver_queue.wake_one();
+                handle.driver_queue.wake_one(token);
 
                 Ok(())
             }
@@ -166,19 +209,16 @@ impl RingScheme {
         // Boost priority for the duration of this critical IPC completion
         let context_lock = context::current();
         let context = context_lock.read(token.token());
-        let _ipc_guard = IpcCriticalGuard::new(&context.priority);
+        let ipc_guard = IpcCriticalGuard::new(&context.priority);
 
         let ring = unsafe { &*handle.ring_ptr };
 
         // 1. Write CQE to the Completion Queue
         let mut cq_tail = ring.cq_tail.load(Ordering::Relaxed);
-        let cq_mask = ring.cq_mask;
+        let cq_mask = CQ_MASK;
         let cq_idx = (cq_tail & cq_mask) as usize;
 
-        let cqe_ptr = unsafe {
-            let sq_region_end = (handle.ring_ptr.add(1) as *mut Sqe).add(64); // Fixed offset
-            (sq_region_end as *mut Cqe).add(cq_idx)
-        };
+        let cqe_ptr = unsafe { handle.cq_ptr().add(cq_idx) };
 
         unsafe {
             cqe_ptr.write(*cqe);
@@ -187,10 +227,12 @@ impl RingScheme {
         // 2. Commit CQE and update tail (Release ordering)
         ring.cq_tail
             .store(cq_tail.wrapping_add(1), Ordering::Release);
+        drop(ipc_guard);
+        drop(context);
 
         // 3. Wake up userspace process waiting on completion
         // The process that originally submitted the command is waiting on `completion_wait_queue`.
-        handle.completion_wait_queue.wake_one();
+        handle.completion_wait_queue.wake_one(token);
     }
 }
 
@@ -207,11 +249,7 @@ impl KernelScheme for RingScheme {
         let data = unsafe { RmmA::phys_to_virt(frame.base()).data() as *mut u8 };
         let ring_ptr = data as *mut IpcRing;
 
-        const SQ_SIZE: usize = 48;
-        const CQ_SIZE: usize = 128;
         // NOTE: Masks are (SIZE - 1)
-        const SQ_MASK: u32 = (SQ_SIZE - 1) as u32;
-        const CQ_MASK: u32 = (CQ_SIZE - 1) as u32;
 
         unsafe {
             (*ring_ptr).sq_head.store(0, Ordering::Relaxed);
@@ -232,8 +270,8 @@ impl KernelScheme for RingScheme {
         let handle = Arc::new(RingHandle {
             frame,
             ring_ptr,
-            sq_entries: SQ_SIZE,
-            cq_entries: CQ_SIZE,
+            sq_entries: SQ_ENTRIES,
+            cq_entries: CQ_ENTRIES,
             driver_queue: OptimizedWaitQueue::new(),
             consumer_pid: AtomicUsize::new(0),
             completion_wait_queue: OptimizedWaitQueue::new(),
@@ -260,18 +298,17 @@ impl KernelScheme for RingScheme {
         addr_space: &Arc<AddrSpaceWrapper>,
         map: &Map,
         _consume: bool,
-        _token: &mut CleanLockToken,
+        token: &mut CleanLockToken,
     ) -> Result<usize> {
-        let handles = self.handles.read();
-        let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
-        let frame = handle.frame;
+        let frame = self.handles.read().get(&id).ok_or(Error::new(EBADF))?.frame;
         let page_count = NonZeroUsize::new(1).unwrap();
 
+        let mut notify_files = Vec::new();
         let base_page = addr_space.acquire_write().mmap(
             (map.address != 0).then_some(Page::containing_address(VirtualAddress::new(map.address))),
             page_count,
             map.flags,
-            &mut Vec::new(),
+            &mut notify_files,
             |dst_page, page_flags, dst_mapper, dst_flusher| {
                 Grant::physmap(
                     frame,
@@ -280,8 +317,10 @@ impl KernelScheme for RingScheme {
                     dst_mapper,
                     dst_flusher,
                 )
+                .map(|grant| grant.named("ring"))
             },
         )?;
+        handle_notify_files(notify_files, token);
         Ok(base_page.start_address().data())
     }
 
@@ -300,16 +339,21 @@ impl KernelScheme for RingScheme {
 
         let ring = unsafe { &*handle.ring_ptr };
 
+        // The ring page is writable from userspace, so only the indices are taken from it, and
+        // those are reduced with the kernel's own mask
         let mut head = ring.sq_head.load(Ordering::Acquire);
         let tail = ring.sq_tail.load(Ordering::Acquire);
-        let mask = ring.sq_mask;
+        let mask = SQ_MASK;
+        if tail.wrapping_sub(head) as usize > handle.sq_entries {
+            return Err(Error::new(EINVAL));
+        }
 
         let mut processed = 0;
 
-        while head < tail {
+        while head != tail {
             let idx = (head & mask) as usize;
 
-            let sqe_ptr = unsafe { (handle.ring_ptr.add(1) as *const Sqe).add(idx) };
+            let sqe_ptr = unsafe { handle.sq_ptr().add(idx) };
             let sqe = unsafe { *sqe_ptr };
 
             // ASYNCHRONOUS DISPATCH
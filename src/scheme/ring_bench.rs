@@ -1,35 +1,327 @@
+//! On-demand benchmark of the ring scheme, run by writing to sys:bench/ring
+//!
+//! `run [iterations]` submits NOPs on the current CPU and reaps each completion before
+//! submitting the next, so every sample is one submission, doorbell and completion round trip.
+//! `run-smp [iterations]` keeps submitting from the current CPU while a kernel context pinned to
+//! another CPU reaps the completions, measuring the time until each is seen there. Reading the
+//! file returns the results of the last run.
 
-use crate::scheme::{RingScheme, KernelScheme, CallerCtx};
-use crate::sync::CleanLockToken;
-use crate::syscall::flag::{O_RDWR, O_CREAT};
-use crate::scheme::OpenResult;
-use crate::syscall::usercopy::UserSliceRo;
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::{
+    fmt::Write,
+    hint,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use spin::Mutex;
 
-pub fn benchmark_ring() {
-    let ring_scheme = RingScheme::new();
+use crate::{
+    context::{self, SpawnOptions},
+    cpu_set::{LogicalCpuId, LogicalCpuSet},
+    scheme::{
+        ring::{Cqe, RingHandle, Sqe, CQ_ENTRIES, IORING_OP_NOP},
+        CallerCtx, KernelScheme, OpenResult, RingScheme, SchemeNamespace,
+    },
+    sync::CleanLockToken,
+    syscall::{
+        error::{Error, Result, EBUSY, EINVAL, EIO, EOPNOTSUPP, ETIMEDOUT},
+        flag::{O_CREAT, O_RDWR},
+        usercopy::UserSliceRo,
+    },
+    time::monotonic,
+};
+
+const DEFAULT_ITERATIONS: usize = 10_000;
+/// Bounds the memory used for latency samples
+const MAX_ITERATIONS: usize = 1_000_000;
+/// A cross-CPU run is abandoned if the consumer makes no progress for this long
+const STALL_TIMEOUT_NS: u64 = 1_000_000_000;
+
+/// Text returned by reads, from the last run
+static RESULTS: Mutex<String> = Mutex::new(String::new());
+/// Only one run at a time, since they share the state below and each occupies a CPU
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Ring and samples shared with the consumer of a cross-CPU run
+static SMP_RING: Mutex<Option<Arc<RingHandle>>> = Mutex::new(None);
+static SMP_ITERATIONS: AtomicUsize = AtomicUsize::new(0);
+static SMP_REAPED: AtomicUsize = AtomicUsize::new(0);
+static SMP_STOP: AtomicBool = AtomicBool::new(false);
+static SMP_LATENCIES: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Command {
+    Run(usize),
+    RunSmp(usize),
+}
+
+fn parse_command(buf: &[u8]) -> Option<Command> {
+    let text = core::str::from_utf8(buf).ok()?;
+    let mut words = text.split_ascii_whitespace();
+    let command = words.next()?;
+    let iterations = match words.next() {
+        Some(word) => word.parse().ok()?,
+        None => DEFAULT_ITERATIONS,
+    };
+    if words.next().is_some() || !(1..=MAX_ITERATIONS).contains(&iterations) {
+        return None;
+    }
+    match command {
+        "run" => Some(Command::Run(iterations)),
+        "run-smp" => Some(Command::RunSmp(iterations)),
+        _ => None,
+    }
+}
+
+/// Run the benchmark written to sys:bench/ring, keeping its results for later reads
+pub fn write(buf: &[u8], token: &mut CleanLockToken) -> Result<usize> {
+    let command = parse_command(buf).ok_or(Error::new(EINVAL))?;
+    if RUNNING.swap(true, Ordering::Acquire) {
+        return Err(Error::new(EBUSY));
+    }
+    let report = match command {
+        Command::Run(iterations) => run_local(iterations, token),
+        Command::RunSmp(iterations) => run_smp(iterations, token),
+    };
+    RUNNING.store(false, Ordering::Release);
+    *RESULTS.lock() = report?;
+    Ok(buf.len())
+}
+
+/// Results of the last run, empty if there was none
+pub fn results() -> Vec<u8> {
+    RESULTS.lock().as_bytes().to_vec()
+}
+
+/// Open a ring on a private instance of the scheme
+fn open_ring(scheme: &RingScheme, token: &mut CleanLockToken) -> Result<(usize, Arc<RingHandle>)> {
+    let ctx = CallerCtx {
+        uid: 0,
+        gid: 0,
+        pid: 1,
+        ns: SchemeNamespace::from(0),
+        mode: 0,
+    };
+    match scheme.kopen("ring:", O_RDWR | O_CREAT, ctx, token)? {
+        OpenResult::SchemeLocal(id, _) => {
+            let handle = scheme.handle(id).ok_or(Error::new(EIO))?;
+            Ok((id, handle))
+        }
+        _ => Err(Error::new(EIO)),
+    }
+}
+
+/// Queue a NOP carrying `user_data`, as userspace would through the shared page
+fn submit_nop(handle: &RingHandle, user_data: u64) {
+    let ring = unsafe { &*handle.ring_ptr };
+    let tail = ring.sq_tail.load(Ordering::Relaxed);
+    let sqe = Sqe {
+        opcode: IORING_OP_NOP,
+        flags: 0,
+        ioprio: 0,
+        fd: 0,
+        addr: 0,
+        len: 0,
+        user_data,
+    };
+    unsafe {
+        handle
+            .sq_ptr()
+            .add((tail & ring.sq_mask) as usize)
+            .write(sqe)
+    };
+    ring.sq_tail.store(tail.wrapping_add(1), Ordering::Release);
+}
+
+/// Take the next completion, if there is one
+fn reap(handle: &RingHandle) -> Option<Cqe> {
+    let ring = unsafe { &*handle.ring_ptr };
+    let head = ring.cq_head.load(Ordering::Relaxed);
+    if head == ring.cq_tail.load(Ordering::Acquire) {
+        return None;
+    }
+    let cqe = unsafe { handle.cq_ptr().add((head & ring.cq_mask) as usize).read() };
+    ring.cq_head.store(head.wrapping_add(1), Ordering::Release);
+    Some(cqe)
+}
+
+fn doorbell(scheme: &RingScheme, id: usize, token: &mut CleanLockToken) -> Result<()> {
+    scheme.kwrite(id, UserSliceRo::empty(), 0, 0, token)?;
+    Ok(())
+}
+
+fn run_local(iterations: usize, token: &mut CleanLockToken) -> Result<String> {
+    let scheme = RingScheme::new();
+    let (id, handle) = open_ring(&scheme, token)?;
+
+    let mut latencies = Vec::with_capacity(iterations);
+    let mut result = Ok(());
+    let start = monotonic();
+    for i in 0..iterations {
+        let submitted = monotonic();
+        submit_nop(&handle, i as u64);
+        if let Err(err) = doorbell(&scheme, id, token) {
+            result = Err(err);
+            break;
+        }
+        if reap(&handle).is_none() {
+            result = Err(Error::new(EIO));
+            break;
+        }
+        latencies.push((monotonic() - submitted) as u64);
+    }
+    let elapsed = (monotonic() - start) as u64;
+
+    let _ = scheme.close(id, token);
+    result?;
+    Ok(report("local", iterations, elapsed, &mut latencies))
+}
+
+fn run_smp(iterations: usize, token: &mut CleanLockToken) -> Result<String> {
+    let current = crate::cpu_id();
+    let Some(consumer_cpu) = (0..crate::cpu_count())
+        .map(LogicalCpuId::new)
+        .find(|&cpu| cpu != current)
+    else {
+        return Err(Error::new(EOPNOTSUPP));
+    };
+
+    let scheme = RingScheme::new();
+    let (id, handle) = open_ring(&scheme, token)?;
+
+    *SMP_RING.lock() = Some(Arc::clone(&handle));
+    *SMP_LATENCIES.lock() = Vec::with_capacity(iterations);
+    SMP_ITERATIONS.store(iterations, Ordering::Relaxed);
+    SMP_REAPED.store(0, Ordering::Relaxed);
+    SMP_STOP.store(false, Ordering::Release);
+
+    let mut affinity = LogicalCpuSet::new();
+    affinity.add(consumer_cpu);
+    let options = SpawnOptions {
+        affinity,
+        name: "[ring_bench]",
+        ..SpawnOptions::default()
+    };
+    if let Err(err) = context::spawn_with(options, smp_consumer, token) {
+        *SMP_RING.lock() = None;
+        let _ = scheme.close(id, token);
+        return Err(err);
+    }
+
+    let elapsed = produce(&scheme, id, &handle, iterations, token);
+
+    // The consumer exits once it has reaped everything or is told to stop
+    SMP_STOP.store(true, Ordering::Release);
+    while SMP_RING.lock().is_some() {
+        hint::spin_loop();
+    }
+    let _ = scheme.close(id, token);
+
+    let mut latencies = core::mem::take(&mut *SMP_LATENCIES.lock());
+    Ok(report("smp", iterations, elapsed?, &mut latencies))
+}
+
+/// Submit `iterations` NOPs stamped with their submission time, without overrunning the
+/// completion queue, and wait until the consumer has reaped them all
+fn produce(
+    scheme: &RingScheme,
+    id: usize,
+    handle: &RingHandle,
+    iterations: usize,
+    token: &mut CleanLockToken,
+) -> Result<u64> {
+    let start = monotonic();
+    let mut progress = start;
+    let mut last_reaped = 0;
+    let mut submitted = 0;
+    loop {
+        let reaped = SMP_REAPED.load(Ordering::Acquire);
+        if reaped == iterations {
+            return Ok((monotonic() - start) as u64);
+        }
+        let now = monotonic();
+        if reaped != last_reaped {
+            last_reaped = reaped;
+            progress = now;
+        } else if (now - progress) as u64 > STALL_TIMEOUT_NS {
+            return Err(Error::new(ETIMEDOUT));
+        }
+
+        if submitted < iterations && submitted - reaped < CQ_ENTRIES {
+            submit_nop(handle, now as u64);
+            doorbell(scheme, id, token)?;
+            submitted += 1;
+        } else {
+            hint::spin_loop();
+        }
+    }
+}
+
+/// Reaps the completions of a cross-CPU run on the CPU it is pinned to
+fn smp_consumer() {
     let mut token = unsafe { CleanLockToken::new() };
-    let ctx = CallerCtx { uid: 0, gid: 0, pid: 1 };
-
-    // Benchmark Open
-    let start = crate::time::monotonic();
-    let open_res = ring_scheme.kopen("ring:", O_RDWR | O_CREAT, ctx, &mut token);
-    let end = crate::time::monotonic();
-    
-    if let Ok(OpenResult::SchemeLocal(id, _flags)) = open_res {
-        crate::println!("Ring Open: {} ns", end - start);
-        
-        // Benchmark Write (Doorbell)
-        let buf = [0u8; 8];
-        if let Ok(user_buf) = UserSliceRo::new(buf.as_ptr() as usize, buf.len()) {
-             let start_write = crate::time::monotonic();
-             let _ = ring_scheme.kwrite(id, user_buf, 0, 0, &mut token);
-             let end_write = crate::time::monotonic();
-             crate::println!("Ring Write: {} ns", end_write - start_write);
+    if let Some(handle) = SMP_RING.lock().clone() {
+        let iterations = SMP_ITERATIONS.load(Ordering::Relaxed);
+        let mut latencies = Vec::with_capacity(iterations);
+        while latencies.len() < iterations && !SMP_STOP.load(Ordering::Acquire) {
+            match reap(&handle) {
+                Some(cqe) => {
+                    latencies.push((monotonic() as u64).saturating_sub(cqe.user_data));
+                    SMP_REAPED.store(latencies.len(), Ordering::Release);
+                }
+                None => hint::spin_loop(),
+            }
         }
+        *SMP_LATENCIES.lock() = latencies;
+    }
+    *SMP_RING.lock() = None;
+    crate::syscall::process::exit_this_context(0, None, &mut token);
+}
+
+/// Format the throughput and latency percentiles of a run
+fn report(mode: &str, iterations: usize, elapsed_ns: u64, latencies: &mut [u64]) -> String {
+    latencies.sort_unstable();
+    let percentile = |p: usize| {
+        latencies
+            .get((latencies.len().saturating_sub(1)) * p / 100)
+            .copied()
+            .unwrap_or(0)
+    };
+    let ops_per_sec = (iterations as u128 * 1_000_000_000) / u128::from(elapsed_ns.max(1));
+
+    let mut string = String::new();
+    let _ = writeln!(string, "mode: {}", mode);
+    let _ = writeln!(string, "iterations: {}", iterations);
+    let _ = writeln!(string, "ops/sec: {}", ops_per_sec);
+    let _ = writeln!(string, "p50_ns: {}", percentile(50));
+    let _ = writeln!(string, "p99_ns: {}", percentile(99));
+    string
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse_command(b"run 500\n"), Some(Command::Run(500)));
+        assert_eq!(
+            parse_command(b"run"),
+            Some(Command::Run(DEFAULT_ITERATIONS))
+        );
+        assert_eq!(parse_command(b"run-smp 20"), Some(Command::RunSmp(20)));
+        assert_eq!(parse_command(b"run 0"), None);
+        assert_eq!(parse_command(b"run 10 20"), None);
+        assert_eq!(parse_command(b"walk 10"), None);
+        assert_eq!(parse_command(b""), None);
+    }
 
-        // Close
-        let _ = ring_scheme.close(id, &mut token);
-    } else {
-        crate::println!("Ring Open Failed");
+    #[test]
+    fn reports_percentiles() {
+        let mut latencies: Vec<u64> = (1..=100).rev().collect();
+        let text = report("local", 100, 1_000_000, &mut latencies);
+        assert_eq!(
+            text,
+            "mode: local\niterations: 100\nops/sec: 100000\np50_ns: 50\np99_ns: 99\n"
+        );
     }
 }
@@ -1,4 +1,4 @@
-use alloc::{boxed::Box, string::ToString, sync::Arc, vec::Vec};
+use alloc::{boxed::Box, collections::BTreeMap, string::ToString, sync::Arc, vec::Vec};
 use core::{
     str,
     sync::atomic::{AtomicUsize, Ordering},
@@ -31,20 +31,31 @@ use super::{CallerCtx, KernelScheme, KernelSchemes, OpenResult};
 enum Handle {
     Scheme(Arc<UserInner>),
     File(Arc<Box<[u8]>>),
-    List { ens: SchemeNamespace },
+    List {
+        ens: SchemeNamespace,
+        cookies: Arc<spin::Mutex<ListCookies>>,
+    },
+}
+
+/// Continuation cookies of a scheme listing
+///
+/// Schemes come and go between getdents calls, so a cookie resumes after the name it was handed
+/// out for rather than at an index. Only the cookies of the last call are kept.
+#[derive(Default)]
+struct ListCookies {
+    next: u64,
+    names: BTreeMap<u64, Box<str>>,
 }
 
 pub struct RootScheme {
-    scheme_ns: SchemeNamespace,
     scheme_id: SchemeId,
     next_id: AtomicUsize,
     handles: RwLock<L1, HashMap<usize, Handle>>,
 }
 
 impl RootScheme {
-    pub fn new(scheme_ns: SchemeNamespace, scheme_id: SchemeId) -> RootScheme {
+    pub fn new(scheme_id: SchemeId) -> RootScheme {
         RootScheme {
-            scheme_ns,
             scheme_id,
             next_id: AtomicUsize::new(0),
             handles: RwLock::new(HashMap::new()),
@@ -93,25 +104,24 @@ impl KernelScheme for RootScheme {
                     );*/
                 }
 
-                let (_scheme_id, inner) =
-                    schemes.insert_and_pass(self.scheme_ns, path, |scheme_id| {
-                        let inner = Arc::new(UserInner::new(
-                            self.scheme_id,
-                            scheme_id,
-                            // TODO: This is a hack, but eventually the legacy interface will be
-                            // removed.
-                            v2,
-                            new_close,
-                            id,
-                            path_box,
-                            flags,
-                            context,
-                        ));
-                        Ok((
-                            KernelSchemes::User(UserScheme::new(Arc::downgrade(&inner))),
-                            inner,
-                        ))
-                    })?;
+                let (_scheme_id, inner) = schemes.insert_and_pass(ctx.ns, path, |scheme_id| {
+                    let inner = Arc::new(UserInner::new(
+                        self.scheme_id,
+                        scheme_id,
+                        // TODO: This is a hack, but eventually the legacy interface will be
+                        // removed.
+                        v2,
+                        new_close,
+                        id,
+                        path_box,
+                        flags,
+                        context,
+                    ));
+                    Ok((
+                        KernelSchemes::User(UserScheme::new(Arc::downgrade(&inner))),
+                        inner,
+                    ))
+                })?;
 
                 inner
             };
@@ -122,12 +132,14 @@ impl KernelScheme for RootScheme {
 
             Ok(OpenResult::SchemeLocal(id, InternalFlags::empty()))
         } else if path.is_empty() {
-            let ens = context::current().read(token.token()).ens;
-
             let id = self.next_id.fetch_add(1, Ordering::Relaxed);
-            self.handles
-                .write(token.token())
-                .insert(id, Handle::List { ens });
+            self.handles.write(token.token()).insert(
+                id,
+                Handle::List {
+                    ens: ctx.ns,
+                    cookies: Arc::default(),
+                },
+            );
             Ok(OpenResult::SchemeLocal(id, InternalFlags::POSITIONED))
         } else {
             let inner = Arc::new(path.as_bytes().to_vec().into_boxed_slice());
@@ -161,7 +173,10 @@ impl KernelScheme for RootScheme {
                 .ok_or(Error::new(ENOENT))?
         };
 
-        inner.unmount(token)
+        // Free the name right away, so that a restarted provider can register it again
+        inner.unmount(token)?;
+        scheme::schemes_mut(&token.token()).remove(inner.scheme_id);
+        Ok(())
     }
 
     fn fsize(&self, file: usize, token: &mut CleanLockToken) -> Result<u64> {
@@ -245,7 +260,9 @@ impl KernelScheme for RootScheme {
             .write(token.token())
             .remove(&file)
             .ok_or(Error::new(EBADF))?;
+        // The provider closing its root handle, usually by exiting, unregisters the scheme
         if let Handle::Scheme(inner) = handle {
+            inner.unmount(token)?;
             scheme::schemes_mut(&token.token()).remove(inner.scheme_id);
         }
         Ok(())
@@ -256,7 +273,7 @@ impl KernelScheme for RootScheme {
         buf: UserSliceWo,
         _offset: u64,
         flags: u32,
-        _stored_flags: u32,
+        stored_flags: u32,
         token: &mut CleanLockToken,
     ) -> Result<usize> {
         let handle = {
@@ -266,7 +283,8 @@ impl KernelScheme for RootScheme {
         };
 
         match handle {
-            Handle::Scheme(inner) => inner.read(buf, flags, token),
+            // O_NONBLOCK may come from this call or from fcntl on the handle
+            Handle::Scheme(inner) => inner.read(buf, flags | stored_flags, token),
             Handle::File(_) => Err(Error::new(EBADF)),
             Handle::List { .. } => Err(Error::new(EISDIR)),
         }
@@ -279,31 +297,60 @@ impl KernelScheme for RootScheme {
         opaque: u64,
         token: &mut CleanLockToken,
     ) -> Result<usize> {
-        let Handle::List { ens } = *self
+        let Handle::List { ens, cookies } = self
             .handles
             .read(token.token())
             .get(&id)
             .ok_or(Error::new(EBADF))?
+            .clone()
         else {
             return Err(Error::new(ENOTDIR));
         };
 
-        let mut buf = DirentBuf::new(buf, header_size).ok_or(Error::new(EIO))?;
-        {
-            let schemes = scheme::schemes(&token.token());
-            for (i, (name, _)) in schemes
-                .iter_name(ens)
-                .enumerate()
-                .skip_while(|(i, _)| (*i as u64) < opaque)
-                .filter(|(_, (name, _))| !name.is_empty())
-            {
-                buf.entry(DirEntry {
-                    kind: DirentKind::Unspecified,
-                    name,
-                    inode: 0,
-                    next_opaque_id: i as u64 + 1,
-                })?;
+        let after = {
+            let mut cookies = cookies.lock();
+            let after = match opaque {
+                0 => None,
+                cookie => Some(cookies.names.remove(&cookie).ok_or(Error::new(EINVAL))?),
+            };
+            // Keep the cookie we were given valid in case this call fails
+            cookies.names.clear();
+            if let Some(after) = &after {
+                cookies.names.insert(opaque, after.clone());
             }
+            after
+        };
+
+        let names: Vec<Box<str>> = scheme::schemes(&token.token())
+            .iter_name(ens)
+            .map(|(name, _)| name)
+            .skip_while(|name| after.as_ref().is_some_and(|after| *name <= after))
+            .filter(|name| !name.is_empty())
+            .cloned()
+            .collect();
+
+        // Hand out the cookies up front, so the lock isn't held while writing to userspace
+        let entries: Vec<(u64, Box<str>)> = {
+            let mut cookies = cookies.lock();
+            names
+                .into_iter()
+                .map(|name| {
+                    cookies.next += 1;
+                    let cookie = cookies.next;
+                    cookies.names.insert(cookie, name.clone());
+                    (cookie, name)
+                })
+                .collect()
+        };
+
+        let mut buf = DirentBuf::new(buf, header_size).ok_or(Error::new(EIO))?;
+        for (cookie, name) in entries {
+            buf.entry(DirEntry {
+                kind: DirentKind::Directory,
+                name: &name,
+                inode: 0,
+                next_opaque_id: cookie,
+            })?;
         }
 
         Ok(buf.finalize())
@@ -347,7 +394,7 @@ impl KernelScheme for RootScheme {
                 ..Default::default()
             },
             Handle::List { .. } => Stat {
-                st_mode: MODE_DIR,
+                st_mode: MODE_DIR | 0o555,
                 ..Default::default()
             },
         })?;
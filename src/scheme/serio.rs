@@ -1,16 +1,21 @@
 //! PS/2 unfortunately requires a kernel driver to prevent race conditions due
 //! to how status is utilized
+//!
+//! Every port has its own input queue, opened as `serio:<port>`, so that the keyboard and mouse
+//! can be served by separate drivers. `serio:` itself lists the ports.
+use alloc::string::String;
 use core::{
     str,
     sync::atomic::{AtomicUsize, Ordering},
 };
 
 use hashbrown::{hash_map::DefaultHashBuilder, HashMap};
+use syscall::dirent::{DirEntry, DirentBuf, DirentKind};
 
 use crate::{
     event,
     scheme::*,
-    sync::{CleanLockToken, RwLock, OptimizedWaitQueue, L1},
+    sync::{CleanLockToken, OptimizedWaitQueue, RwLock, L1},
     syscall::{
         error::*,
         flag::{EventFlags, EVENT_READ, O_NONBLOCK},
@@ -18,26 +23,55 @@ use crate::{
     },
 };
 
+/// First PS/2 port, usually the keyboard
+pub const PORT_KEYBOARD: usize = 0;
+/// Second (auxiliary) PS/2 port, usually the mouse
+pub const PORT_AUX: usize = 1;
+/// Number of serio ports
+pub const PORT_COUNT: usize = 2;
+
 static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
 
-/// Input queue
-static INPUT: spin::Lazy<[OptimizedWaitQueue<u8>; 2]> = spin::Lazy::new(|| [OptimizedWaitQueue::new(), OptimizedWaitQueue::new()]);
+/// Input queue of every port
+static INPUT: spin::Lazy<[OptimizedWaitQueue<u8>; PORT_COUNT]> =
+    spin::Lazy::new(|| core::array::from_fn(|_| OptimizedWaitQueue::new()));
 
 #[derive(Clone, Copy)]
-struct Handle {
-    index: usize,
+enum Handle {
+    /// `serio:`, listing the ports
+    Root,
+    Port(usize),
 }
 
 static HANDLES: RwLock<L1, HashMap<usize, Handle>> =
     RwLock::new(HashMap::with_hasher(DefaultHashBuilder::new()));
 
-/// Add to the input queue
-pub fn serio_input(index: usize, data: u8, token: &mut CleanLockToken) {
-    crate::profiling::serio_command(index, data);
+/// Add to the input queue of `port` once interrupts are enabled again. Called by the PS/2
+/// interrupt handlers, which only read the byte.
+pub fn serio_irq_input(port: usize, data: u8) {
+    crate::deferred::defer(deferred_input, port.wrapping_shl(8) | usize::from(data));
+}
 
-    INPUT[index].send(data, token);
+fn deferred_input(arg: usize, token: &mut CleanLockToken) {
+    serio_input(arg.wrapping_shr(8), arg as u8, token);
+}
 
-    let ids: Vec<usize> = HANDLES.read(token.token()).keys().copied().collect();
+/// Add to the input queue of `port`, notifying only the handles open on it
+pub fn serio_input(port: usize, data: u8, token: &mut CleanLockToken) {
+    let Some(queue) = INPUT.get(port) else {
+        warn!("serio: input for nonexistent port {}", port);
+        return;
+    };
+    crate::profiling::serio_command(port, data);
+
+    queue.send(data, token);
+
+    let ids: Vec<usize> = HANDLES
+        .read(token.token())
+        .iter()
+        .filter(|(_, handle)| matches!(handle, Handle::Port(p) if *p == port))
+        .map(|(&id, _)| id)
+        .collect();
     for id in ids {
         event::trigger(GlobalSchemes::Serio.scheme_id(), id, EVENT_READ, token);
     }
@@ -57,13 +91,19 @@ impl KernelScheme for SerioScheme {
             return Err(Error::new(EPERM));
         }
 
-        let index = path.parse::<usize>().or(Err(Error::new(ENOENT)))?;
-        if index >= INPUT.len() {
-            return Err(Error::new(ENOENT));
-        }
+        let path = path.trim_matches('/');
+        let handle = if path.is_empty() {
+            Handle::Root
+        } else {
+            let port = path.parse::<usize>().or(Err(Error::new(ENOENT)))?;
+            if port >= PORT_COUNT {
+                return Err(Error::new(ENOENT));
+            }
+            Handle::Port(port)
+        };
 
         let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
-        HANDLES.write(token.token()).insert(id, Handle { index });
+        HANDLES.write(token.token()).insert(id, handle);
 
         Ok(OpenResult::SchemeLocal(id, InternalFlags::empty()))
     }
@@ -74,12 +114,16 @@ impl KernelScheme for SerioScheme {
         _flags: EventFlags,
         token: &mut CleanLockToken,
     ) -> Result<EventFlags> {
-        let _handle = {
+        let handle = {
             let handles = HANDLES.read(token.token());
             *handles.get(&id).ok_or(Error::new(EBADF))?
         };
 
-        Ok(EventFlags::empty())
+        // Report input that arrived before the registration, later input triggers an event
+        match handle {
+            Handle::Port(port) if !INPUT[port].is_currently_empty() => Ok(EVENT_READ),
+            _ => Ok(EventFlags::empty()),
+        }
     }
 
     fn fsync(&self, id: usize, token: &mut CleanLockToken) -> Result<()> {
@@ -105,20 +149,53 @@ impl KernelScheme for SerioScheme {
         id: usize,
         buf: UserSliceWo,
         flags: u32,
-        _stored_flags: u32,
+        stored_flags: u32,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        let handle = {
+            let handles = HANDLES.read(token.token());
+            *handles.get(&id).ok_or(Error::new(EBADF))?
+        };
+
+        let Handle::Port(port) = handle else {
+            return Err(Error::new(EISDIR));
+        };
+
+        // The stored flags reflect any later fcntl(F_SETFL) on the description
+        let nonblock = (flags | stored_flags) & O_NONBLOCK as u32 != 0;
+        INPUT[port].receive_into_user(buf, !nonblock, "SerioScheme::read", token)
+    }
+
+    fn getdents(
+        &self,
+        id: usize,
+        buf: UserSliceWo,
+        header_size: u16,
+        opaque_id_start: u64,
         token: &mut CleanLockToken,
     ) -> Result<usize> {
         let handle = {
             let handles = HANDLES.read(token.token());
             *handles.get(&id).ok_or(Error::new(EBADF))?
         };
+        if !matches!(handle, Handle::Root) {
+            return Err(Error::new(ENOTDIR));
+        }
+        let Ok(first_port) = usize::try_from(opaque_id_start) else {
+            return Ok(0);
+        };
 
-        INPUT[handle.index].receive_into_user(
-            buf,
-            flags & O_NONBLOCK as u32 == 0,
-            "SerioScheme::read",
-            token,
-        )
+        let mut buf = DirentBuf::new(buf, header_size).ok_or(Error::new(EIO))?;
+        for port in first_port..PORT_COUNT {
+            let name = format!("{}", port);
+            buf.entry(DirEntry {
+                inode: port as u64,
+                next_opaque_id: port as u64 + 1,
+                kind: DirentKind::CharDev,
+                name: &name,
+            })?;
+        }
+        Ok(buf.finalize())
     }
 
     fn kfpath(&self, id: usize, buf: UserSliceWo, token: &mut CleanLockToken) -> Result<usize> {
@@ -126,7 +203,11 @@ impl KernelScheme for SerioScheme {
             let handles = HANDLES.read(token.token());
             *handles.get(&id).ok_or(Error::new(EBADF))?
         };
-        let path = format!("serio:{}", handle.index).into_bytes();
+        let path = match handle {
+            Handle::Root => String::from("serio:"),
+            Handle::Port(port) => format!("serio:{}", port),
+        }
+        .into_bytes();
 
         buf.copy_common_bytes_from_slice(&path)
     }
@@ -12,7 +12,7 @@ pub fn resource(token: &mut CleanLockToken) -> Result<Vec<u8>> {
             let contexts = context::contexts().read();
             for context_lock in contexts.values() {
                 let context = context_lock.read(token.token());
-                rows.push((context.pid, context.name.clone(), context.status_reason));
+                rows.push((context.pid, context.name.get(), context.status_reason));
             }
         }
         rows.sort_by_key(|row| row.0);
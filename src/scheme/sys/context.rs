@@ -4,109 +4,167 @@ use alloc::{
 };
 use core::fmt::Write;
 
-use crate::{context, paging::PAGE_SIZE, sync::CleanLockToken, syscall::error::Result};
+use crate::{
+    context::{self, memory::AddrSpaceWrapper},
+    paging::PAGE_SIZE,
+    sync::CleanLockToken,
+    syscall::error::Result,
+};
+
+/// Page counts of an address space, as reported in the RES and LOCKED columns
+#[derive(Default)]
+struct MemoryUsage {
+    /// Pages of grants backed by memory allocated for the address space
+    resident: usize,
+    /// Pages of grants locked in memory
+    locked: usize,
+}
+
+impl MemoryUsage {
+    fn of(addr_space: &AddrSpaceWrapper) -> (bool, Self) {
+        let inner = addr_space.acquire_read();
+        let usage = Self {
+            resident: inner.allocated_pages(),
+            locked: inner
+                .grants
+                .values()
+                .filter(|info| info.locked)
+                .map(|info| info.page_count())
+                .sum(),
+        };
+        (inner.grants.is_empty(), usage)
+    }
+}
 
 pub fn resource(token: &mut CleanLockToken) -> Result<Vec<u8>> {
+    // Columns, in order: PID EUID EGID ENS STAT CPU AFFINITY TIME MEM RES LOCKED NAME, where RES
+    // and LOCKED are the resident and locked page counts of the address space. STAT starts with
+    // T for kernel threads, and otherwise K, U or R for the kind of address space.
     let mut string = format!(
-        "{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<11}{:<12}{:<8}{}\n",
-        "PID", "EUID", "EGID", "ENS", "STAT", "CPU", "AFFINITY", "TIME", "MEM", "NAME"
+        "{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<11}{:<12}{:<8}{:<8}{:<8}{}\n",
+        "PID",
+        "EUID",
+        "EGID",
+        "ENS",
+        "STAT",
+        "CPU",
+        "AFFINITY",
+        "TIME",
+        "MEM",
+        "RES",
+        "LOCKED",
+        "NAME"
     );
 
+    // Walking every address space can be slow, so snapshot the contexts instead of holding the
+    // list locked throughout
+    let context_refs = context::contexts()
+        .read()
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+
     let mut rows = Vec::new();
-    {
-        let contexts = context::contexts();
-        let contexts_guard = contexts.read();
-        for context_ref in contexts_guard.values() {
-            let context = context_ref.read(token.token());
-
-            let mut stat_string = String::new();
-            // TODO: All user programs must have some grant in order for executable memory to even
-            // exist, but is this a good indicator of whether it is user or kernel?
-            stat_string.push(match context.addr_space() {
-                Ok(addr_space) => {
-                    if addr_space.acquire_read().grants.is_empty() {
-                        'K'
-                    } else {
-                        'U'
-                    }
-                }
-                _ => 'R',
-            });
-            match context.status {
-                context::Status::Runnable => {
-                    stat_string.push('R');
-                }
-                context::Status::Blocked | context::Status::HardBlocked { .. } => {
-                    if context.wake.is_some() {
-                        stat_string.push('S');
-                    } else {
-                        stat_string.push('B');
-                    }
-                }
-                context::Status::Dead { .. } => {
-                    stat_string.push('Z');
+    for context_ref in context_refs.iter() {
+        let context = context_ref.read(token.token());
+
+        // Each address space is locked on its own, after the context lock is released
+        let addr_space = context.addr_space().ok().cloned();
+
+        let mut stat_string = String::new();
+        match context.status {
+            context::Status::Runnable => {
+                stat_string.push('R');
+            }
+            context::Status::Blocked | context::Status::HardBlocked { .. } => {
+                if context.wake.is_some() {
+                    stat_string.push('S');
+                } else {
+                    stat_string.push('B');
                 }
             }
-            if context.running {
-                stat_string.push('+');
+            context::Status::Zombie { .. } | context::Status::Dead { .. } => {
+                stat_string.push('Z');
             }
+        }
+        if context.running {
+            stat_string.push('+');
+        }
 
-            let cpu_string = match context.cpu_id {
-                Some(cpu_id) => {
-                    format!("{}", cpu_id)
-                }
-                _ => {
-                    format!("?")
-                }
-            };
-            let affinity = context.sched_affinity.to_string();
-
-            let cpu_time_s = context.cpu_time / crate::time::NANOS_PER_SEC;
-            let cpu_time_ns = context.cpu_time % crate::time::NANOS_PER_SEC;
-            let cpu_time_string = format!(
-                "{:02}:{:02}:{:02}.{:02}",
-                cpu_time_s / 3600,
-                (cpu_time_s / 60) % 60,
-                cpu_time_s % 60,
-                cpu_time_ns / 10_000_000
-            );
-
-            let mut memory: usize = context.kfx.len();
-            if let Some(ref kstack) = context.kstack {
-                memory += kstack.len();
+        let cpu_string = match context.cpu_id {
+            Some(cpu_id) => {
+                format!("{}", cpu_id)
             }
-            if let Ok(addr_space) = context.addr_space() {
-                for (_base, info) in addr_space.acquire_read().grants.iter() {
-                    // TODO: method
-                    if matches!(info.provider, context::memory::Provider::Allocated { .. }) {
-                        memory += info.page_count() * PAGE_SIZE;
-                    }
-                }
+            _ => {
+                format!("?")
             }
+        };
+        let affinity = context.sched_affinity.to_string();
 
-            let memory_string = if memory >= 1024 * 1024 * 1024 {
-                format!("{} GB", memory / 1024 / 1024 / 1024)
-            } else if memory >= 1024 * 1024 {
-                format!("{} MB", memory / 1024 / 1024)
-            } else if memory >= 1024 {
-                format!("{} KB", memory / 1024)
-            } else {
-                format!("{} B", memory)
-            };
-
-            rows.push((
-                context.pid,
-                context.euid,
-                context.egid,
-                context.ens.get(),
-                stat_string,
-                cpu_string,
-                affinity,
-                cpu_time_string,
-                memory_string,
-                context.name,
-            ));
+        let cpu_time_s = context.cpu_time / crate::time::NANOS_PER_SEC;
+        let cpu_time_ns = context.cpu_time % crate::time::NANOS_PER_SEC;
+        let cpu_time_string = format!(
+            "{:02}:{:02}:{:02}.{:02}",
+            cpu_time_s / 3600,
+            (cpu_time_s / 60) % 60,
+            cpu_time_s % 60,
+            cpu_time_ns / 10_000_000
+        );
+
+        let mut memory: usize = context.kfx.len();
+        if let Some(ref kstack) = context.kstack {
+            memory += kstack.len();
         }
+        let kthread = context::kthread::is_kthread(context.id());
+        let pid = context.pid;
+        let euid = context.euid;
+        let egid = context.egid;
+        let ens = context.ens.get();
+        let name = context.name.get();
+        drop(context);
+
+        // TODO: All user programs must have some grant in order for executable memory to even
+        // exist, but is this a good indicator of whether it is user or kernel?
+        let usage = match addr_space {
+            Some(addr_space) => {
+                let (is_kernel, usage) = MemoryUsage::of(&addr_space);
+                stat_string.insert(0, if is_kernel { 'K' } else { 'U' });
+                usage
+            }
+            None => {
+                stat_string.insert(0, 'R');
+                MemoryUsage::default()
+            }
+        };
+        // Kernel threads spawned through context::kthread, whatever their address space
+        if kthread {
+            stat_string.replace_range(..1, "T");
+        }
+        memory += usage.resident * PAGE_SIZE;
+
+        let memory_string = if memory >= 1024 * 1024 * 1024 {
+            format!("{} GB", memory / 1024 / 1024 / 1024)
+        } else if memory >= 1024 * 1024 {
+            format!("{} MB", memory / 1024 / 1024)
+        } else if memory >= 1024 {
+            format!("{} KB", memory / 1024)
+        } else {
+            format!("{} B", memory)
+        };
+
+        rows.push((
+            pid,
+            euid,
+            egid,
+            ens,
+            stat_string,
+            cpu_string,
+            affinity,
+            cpu_time_string,
+            memory_string,
+            usage,
+            name,
+        ));
     }
     rows.sort_by_key(|row| row.0);
 
@@ -120,12 +178,13 @@ pub fn resource(token: &mut CleanLockToken) -> Result<Vec<u8>> {
         affinity,
         cpu_time_string,
         memory_string,
+        usage,
         name,
     ) in rows
     {
         let _ = writeln!(
             string,
-            "{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<11}{:<12}{:<8}{}",
+            "{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<11}{:<12}{:<8}{:<8}{:<8}{}",
             pid,
             euid,
             egid,
@@ -135,6 +194,8 @@ pub fn resource(token: &mut CleanLockToken) -> Result<Vec<u8>> {
             affinity,
             cpu_time_string,
             memory_string,
+            usage.resident,
+            usage.locked,
             name,
         );
     }
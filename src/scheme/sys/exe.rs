@@ -3,9 +3,6 @@ use alloc::vec::Vec;
 use crate::{context, sync::CleanLockToken, syscall::error::Result};
 
 pub fn resource(token: &mut CleanLockToken) -> Result<Vec<u8>> {
-    Ok(context::current()
-        .read(token.token())
-        .name
-        .as_bytes()
-        .to_vec())
+    let name = context::current().read(token.token()).name.get();
+    Ok(name.as_bytes().to_vec())
 }
@@ -40,7 +40,7 @@ pub fn resource(token: &mut CleanLockToken) -> Result<Vec<u8>> {
         let files = context.files.read();
         writeln!(report, "'{}' {{", context.name).unwrap();
 
-        for file in files.iter().filter_map(|f| f.clone()) {
+        for file in files.iter().cloned() {
             writeln!(
                 report,
                 "\tS{}W{}",
@@ -69,7 +69,7 @@ pub fn resource(token: &mut CleanLockToken) -> Result<Vec<u8>> {
             descr
                 .owners
                 .entry(Ref(a))
-                .or_insert(context.name.clone().into_owned());
+                .or_insert(context.name.get().as_str().into());
             descr.scheme = scheme.unwrap_or(Box::from("[unknown]"));
         }
         writeln!(report, "}}").unwrap();
@@ -26,7 +26,13 @@ fn inner(fpath_user: UserSliceRw, token: &mut CleanLockToken) -> Result<Vec<u8>>
             let contexts_guard = contexts.read();
             for context_ref in contexts_guard.values() {
                 let context = context_ref.read(token.token());
-                rows.push((context.pid, context.name, context.files.read().clone()));
+                let files: Vec<_> = context
+                    .files
+                    .read()
+                    .enumerate()
+                    .map(|(fd, f)| (fd, f.clone()))
+                    .collect();
+                rows.push((context.pid, context.name.get(), files));
             }
         }
         rows.sort_by_key(|row| row.0);
@@ -34,12 +40,7 @@ fn inner(fpath_user: UserSliceRw, token: &mut CleanLockToken) -> Result<Vec<u8>>
         for (id, name, fs) in rows.iter() {
             let _ = writeln!(string, "{}: {}", id, name);
 
-            for (fd, f) in fs.enumerate() {
-                let file: crate::context::file::FileDescriptor = match *f {
-                    None => continue,
-                    Some(ref file) => file.clone(),
-                };
-
+            for &(fd, ref file) in fs.iter() {
                 let description = file.description.read();
 
                 let _ = write!(
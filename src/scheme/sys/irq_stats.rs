@@ -0,0 +1,68 @@
+use alloc::{string::String, vec::Vec};
+use core::fmt::Write;
+
+use crate::{
+    cpu_set::LogicalCpuId,
+    cpu_stats::IRQ_VECTOR_COUNT,
+    percpu::{get_all_irq_counts, get_percpu_block},
+    sync::CleanLockToken,
+    syscall::error::Result,
+};
+
+/// Get the sys:irq_stats data, a matrix of interrupt counts with a column per CPU, followed by
+/// the state of each CPU's deferred work.
+pub fn resource(_token: &mut CleanLockToken) -> Result<Vec<u8>> {
+    // Only CPUs that came up have a per-CPU block, so APs that failed to start are left out
+    let cpus = get_all_irq_counts();
+    let mut string = format_matrix(&cpus);
+    format_deferred(&mut string, cpus.iter().map(|(id, _)| *id));
+    Ok(string.into_bytes())
+}
+
+/// Add the backlog, dropped and run counts of the deferred work of every CPU in `cpus`.
+fn format_deferred(string: &mut String, cpus: impl Iterator<Item = LogicalCpuId> + Clone) {
+    let _ = writeln!(string);
+    let _ = write!(string, "{:>8}", "deferred");
+    for id in cpus.clone() {
+        let _ = write!(string, " {:>12}", format_args!("cpu{}", id.get()));
+    }
+    let _ = writeln!(string);
+
+    let rows: [(&str, fn(&crate::deferred::DeferQueue) -> u64); 3] = [
+        ("backlog", |queue| queue.backlog() as u64),
+        ("dropped", |queue| queue.dropped()),
+        ("ran", |queue| queue.ran()),
+    ];
+    for (name, count) in rows {
+        let _ = write!(string, "{:>8}", name);
+        for id in cpus.clone() {
+            let value = get_percpu_block(id).map_or(0, |block| count(&block.deferred));
+            let _ = write!(string, " {:>12}", value);
+        }
+        let _ = writeln!(string);
+    }
+}
+
+/// Format one row per interrupt that any CPU has seen, skipping all-zero rows.
+fn format_matrix(cpus: &[(LogicalCpuId, [u64; IRQ_VECTOR_COUNT])]) -> String {
+    let mut string = String::new();
+
+    let _ = write!(string, "{:>6}", "irq");
+    for (id, _) in cpus {
+        let _ = write!(string, " {:>12}", format_args!("cpu{}", id.get()));
+    }
+    let _ = writeln!(string);
+
+    for irq in 0..IRQ_VECTOR_COUNT {
+        if cpus.iter().all(|(_, counts)| counts[irq] == 0) {
+            continue;
+        }
+        let _ = write!(string, "{:>6}", irq);
+        for (_, counts) in cpus {
+            let _ = write!(string, " {:>12}", counts[irq]);
+        }
+        let _ = writeln!(string);
+    }
+
+    string
+}
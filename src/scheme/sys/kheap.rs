@@ -0,0 +1,50 @@
+use alloc::{string::String, vec::Vec};
+use core::fmt::Write;
+
+use crate::{
+    allocator::stats::{heap_stats, size_class_limit},
+    sync::CleanLockToken,
+    syscall::error::Result,
+};
+
+pub fn resource(_token: &mut CleanLockToken) -> Result<Vec<u8>> {
+    // Snapshot first, so that formatting does not show its own allocations
+    let stats = heap_stats();
+
+    let mut string = String::new();
+    let _ = writeln!(string, "current_bytes: {}", stats.current_bytes);
+    let _ = writeln!(string, "peak_bytes: {}", stats.peak_bytes);
+    for (class, count) in stats.allocs.iter().enumerate() {
+        match size_class_limit(class) {
+            Some(limit) => {
+                let _ = writeln!(string, "allocs_le_{}: {}", limit, count);
+            }
+            None => {
+                let _ = writeln!(string, "allocs_larger: {}", count);
+            }
+        }
+    }
+    let _ = writeln!(string, "frees: {}", stats.frees);
+    #[cfg(feature = "kheap_fault_injection")]
+    {
+        use crate::allocator::inject;
+        let _ = writeln!(string, "fail_after: {}", inject::remaining());
+        let _ = writeln!(string, "failures_injected: {}", inject::injected());
+    }
+
+    Ok(string.into_bytes())
+}
+
+/// Arm the failure injection with "fail-after <n> [<min>-<max>]". Root only, as anyone can read
+/// the statistics.
+#[cfg(feature = "kheap_fault_injection")]
+pub fn write(arg: &[u8], token: &mut CleanLockToken) -> Result<usize> {
+    use crate::syscall::error::{Error, EINVAL, EPERM};
+
+    if crate::context::current().read(token.token()).euid != 0 {
+        return Err(Error::new(EPERM));
+    }
+    let command = core::str::from_utf8(arg).map_err(|_| Error::new(EINVAL))?;
+    crate::allocator::inject::command(command).ok_or(Error::new(EINVAL))?;
+    Ok(arg.len())
+}
@@ -0,0 +1,50 @@
+use alloc::format;
+
+use crate::{
+    log::{KMSG_WAIT, LOG},
+    sync::CleanLockToken,
+    syscall::{
+        error::{Error, Result, EAGAIN, EINTR, EINVAL},
+        usercopy::UserSliceWo,
+    },
+};
+
+/// The cursor a new sys:kmsg handle starts at, the oldest record still in the buffer.
+pub fn first_cursor() -> u64 {
+    LOG.lock().as_ref().map_or(0, |log| log.kmsg.first_seq())
+}
+
+/// Read the record at `cursor`, waiting for it to be written unless `nonblock` is set.
+///
+/// Each read returns one record as `<seq>,<usecs>,<dropped>;<text>\n`, where `dropped` counts
+/// the records overwritten before the reader got to them. Returns the number of bytes read and
+/// the cursor for the next read.
+pub fn read(
+    cursor: u64,
+    buf: UserSliceWo,
+    nonblock: bool,
+    token: &mut CleanLockToken,
+) -> Result<(usize, u64)> {
+    let record = loop {
+        let log = LOG.lock();
+        if let Some(record) = log.as_ref().and_then(|log| log.kmsg.read(cursor)) {
+            break record;
+        }
+        if nonblock {
+            return Err(Error::new(EAGAIN));
+        } else if !KMSG_WAIT.wait(log, "kmsg::read", token) {
+            return Err(Error::new(EINTR));
+        }
+    };
+
+    let dropped = record.seq - cursor;
+    let mut line = format!("{},{},{};", record.seq, record.time / 1000, dropped).into_bytes();
+    line.extend_from_slice(&record.text);
+    line.push(b'\n');
+
+    // Records are never split, so that the next read starts at a record boundary
+    let dst = buf.limit(line.len()).ok_or(Error::new(EINVAL))?;
+    dst.copy_from_slice(&line)?;
+
+    Ok((line.len(), record.seq + 1))
+}
@@ -0,0 +1,65 @@
+use alloc::{string::String, vec::Vec};
+use core::{fmt::Write, mem::size_of};
+use rustc_demangle::demangle;
+
+use crate::ksyms::{self, Symbol};
+
+/// Most bytes formatted by one read, which are buffered in the kernel before being copied out
+const READ_MAX: usize = 64 * 1024;
+
+/// Where a sys:ksyms reader left off: the offset of the first line it has not read completely and
+/// the index of its symbol, so that reading on does not format the table from the start
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cursor {
+    line_start: u64,
+    index: usize,
+}
+
+fn format_line(line: &mut String, symbol: &Symbol) {
+    line.clear();
+    let _ = writeln!(
+        line,
+        "{:0width$x} {:x} {:#}",
+        symbol.addr,
+        symbol.size,
+        demangle(symbol.name),
+        width = size_of::<usize>() * 2
+    );
+}
+
+/// Format the symbol table as text from `pos`, one "address size name" line per function sorted by
+/// address, into a kernel buffer of at most `len` (and `READ_MAX`) bytes. Returns the text with the
+/// cursor to store once it has been copied out.
+pub fn read(len: usize, pos: u64, cursor: Cursor) -> (Vec<u8>, Cursor) {
+    let len = len.min(READ_MAX);
+    let mut at = if pos >= cursor.line_start {
+        cursor
+    } else {
+        Cursor::default()
+    };
+    let mut line = String::new();
+    let mut out = Vec::new();
+
+    while out.len() < len
+        && let Some(symbol) = ksyms::symbol(at.index)
+    {
+        format_line(&mut line, &symbol);
+        let line_end = at.line_start.saturating_add(line.len() as u64);
+        let wanted = pos.saturating_add(out.len() as u64);
+        if line_end > wanted {
+            let skip = usize::try_from(wanted.saturating_sub(at.line_start)).unwrap_or(usize::MAX);
+            let rest = line.as_bytes().get(skip..).unwrap_or(&[]);
+            let take = rest.len().min(len.saturating_sub(out.len()));
+            out.extend_from_slice(rest.get(..take).unwrap_or(&[]));
+            if take < rest.len() {
+                break;
+            }
+        }
+        at = Cursor {
+            line_start: line_end,
+            index: at.index.saturating_add(1),
+        };
+    }
+
+    (out, at)
+}
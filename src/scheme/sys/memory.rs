@@ -0,0 +1,62 @@
+use alloc::{string::String, vec::Vec};
+use core::fmt::Write;
+
+use crate::{
+    memory::{buddy_stats, free_frames, pressure, total_frames, PAGE_SIZE},
+    percpu::tlb_stats,
+    sync::CleanLockToken,
+    syscall::error::Result,
+};
+
+pub fn resource(_token: &mut CleanLockToken) -> Result<Vec<u8>> {
+    // Snapshot first, so that the freelist is not locked while formatting
+    let stats = buddy_stats();
+    let total = total_frames();
+    let tlb = tlb_stats();
+    let used = stats.used_frames + stats.bump_frames;
+
+    let mut string = String::new();
+    let _ = writeln!(string, "page_size: {}", PAGE_SIZE);
+    let _ = writeln!(string, "total_frames: {}", total);
+    let _ = writeln!(string, "used_frames: {}", used);
+    let _ = writeln!(string, "free_frames: {}", total.saturating_sub(used));
+    let _ = writeln!(string, "bump_frames: {}", stats.bump_frames);
+    for (order, count) in stats.free_blocks.iter().enumerate() {
+        let _ = writeln!(string, "order{}_free_blocks: {}", order, count);
+    }
+    for (node, node_stats) in stats.nodes.iter().enumerate() {
+        if node_stats.total_frames == 0 {
+            continue;
+        }
+        let _ = writeln!(
+            string,
+            "node{}_total_frames: {}",
+            node, node_stats.total_frames
+        );
+        let _ = writeln!(
+            string,
+            "node{}_free_frames: {}",
+            node, node_stats.free_frames
+        );
+    }
+    let _ = writeln!(string, "tlb_shootdowns: {}", tlb.shootdowns);
+    let _ = writeln!(string, "tlb_pages_flushed: {}", tlb.pages_flushed);
+    let _ = writeln!(string, "tlb_full_flushes: {}", tlb.full_flushes);
+
+    Ok(string.into_bytes())
+}
+
+/// Contents of sys:memory_pressure, generated on every read since it is polled
+pub fn pressure_state() -> Vec<u8> {
+    let mut string = String::new();
+    let _ = writeln!(
+        string,
+        "under_pressure: {}",
+        pressure::under_pressure() as u8
+    );
+    let _ = writeln!(string, "free_frames: {}", free_frames());
+    let _ = writeln!(string, "low_watermark: {}", pressure::low_watermark());
+    let _ = writeln!(string, "reserved_frames: {}", pressure::reserved_frames());
+    let _ = writeln!(string, "episodes: {}", pressure::episodes());
+    string.into_bytes()
+}
@@ -18,16 +18,17 @@ use hashbrown::{hash_map::DefaultHashBuilder, HashMap};
 use crate::arch::interrupt;
 use crate::{
     context::file::InternalFlags,
+    event,
     sync::{CleanLockToken, RwLock, L1},
     syscall::{
         data::Stat,
-        error::{Error, Result, EBADF, ENOENT},
-        flag::{MODE_DIR, MODE_FILE},
+        error::{Error, Result, EBADF, ENOENT, ENOSYS},
+        flag::{EventFlags, EVENT_READ, MODE_DIR, MODE_FILE, O_NONBLOCK},
         usercopy::{UserSliceRo, UserSliceWo},
     },
 };
 
-use super::{CallerCtx, KernelScheme, OpenResult};
+use super::{ring_bench, CallerCtx, GlobalSchemes, KernelScheme, OpenResult};
 
 mod block;
 mod context;
@@ -39,11 +40,22 @@ mod fdstat;
 mod exe;
 mod iostat;
 mod irq;
+mod irq_stats;
+mod kheap;
+mod kmsg;
+#[cfg(feature = "ksyms")]
+mod ksyms;
 mod log;
+mod memory;
+mod profile;
 mod scheme;
+#[cfg(feature = "scheme_metrics")]
+mod scheme_metrics;
 mod scheme_num;
 mod stat;
 mod syscall;
+mod topology;
+mod trace;
 mod uname;
 
 enum Handle {
@@ -52,14 +64,70 @@ enum Handle {
         path: &'static str,
         data: Option<Vec<u8>>,
     },
+    /// A sys:kmsg reader, with the sequence number of the next record it reads
+    Kmsg {
+        cursor: u64,
+    },
+    /// A sys:ksyms reader, with where its last read ended
+    #[cfg(feature = "ksyms")]
+    Ksyms {
+        cursor: ksyms::Cursor,
+    },
+    /// A sys:profile stream, which starts with a header
+    Profile {
+        header_sent: bool,
+    },
+    /// A sys:trace stream, which starts with a header
+    Trace {
+        header_sent: bool,
+    },
+    /// sys:bench/ring, which runs the benchmark written to it
+    RingBench,
+    /// sys:memory_pressure, readable whenever pressure starts or ends
+    MemoryPressure,
 }
 
 enum Kind {
     Rd(fn(&mut CleanLockToken) -> Result<Vec<u8>>),
     Wr(fn(&[u8], &mut CleanLockToken) -> Result<usize>),
+    /// Read like `Rd` by anyone, and written like `Wr`, where the handler checks permissions
+    #[cfg(feature = "kheap_fault_injection")]
+    RdWr(
+        fn(&mut CleanLockToken) -> Result<Vec<u8>>,
+        fn(&[u8], &mut CleanLockToken) -> Result<usize>,
+    ),
+    /// The kernel message buffer, read one record at a time
+    Kmsg,
+    /// The kernel symbol table, formatted as it is read
+    #[cfg(feature = "ksyms")]
+    Ksyms,
+    /// Profiler samples, drained as binary records, and sampling control
+    Profile,
+    /// Context switch records, drained as binary records, and tracing control
+    Trace,
+    /// Ring scheme benchmark commands, and the results of the last run
+    RingBench,
+    /// Memory pressure state, with an event whenever it changes
+    MemoryPressure,
 }
 use Kind::*;
 
+/// Wake the readers of sys:memory_pressure if pressure started or ended since the last call
+pub fn notify_memory_pressure(token: &mut CleanLockToken) {
+    if !crate::memory::pressure::take_change() {
+        return;
+    }
+    let ids: Vec<usize> = HANDLES
+        .read(token.token())
+        .iter()
+        .filter(|(_, handle)| matches!(handle, Handle::MemoryPressure))
+        .map(|(&id, _)| id)
+        .collect();
+    for id in ids {
+        event::trigger(GlobalSchemes::Sys.scheme_id(), id, EVENT_READ, token);
+    }
+}
+
 /// System information scheme
 pub struct SysScheme;
 static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
@@ -67,6 +135,7 @@ static HANDLES: RwLock<L1, HashMap<usize, Handle>> =
     RwLock::new(HashMap::with_hasher(DefaultHashBuilder::new()));
 
 const FILES: &[(&str, Kind)] = &[
+    ("bench/ring", RingBench),
     ("block", Rd(block::resource)),
     ("context", Rd(context::resource)),
     ("cpu", Rd(cpu::resource)),
@@ -75,10 +144,25 @@ const FILES: &[(&str, Kind)] = &[
     ("exe", Rd(exe::resource)),
     ("iostat", Rd(iostat::resource)),
     ("irq", Rd(irq::resource)),
+    ("irq_stats", Rd(irq_stats::resource)),
+    #[cfg(not(feature = "kheap_fault_injection"))]
+    ("kheap", Rd(kheap::resource)),
+    #[cfg(feature = "kheap_fault_injection")]
+    ("kheap", RdWr(kheap::resource, kheap::write)),
+    ("kmsg", Kmsg),
+    #[cfg(feature = "ksyms")]
+    ("ksyms", Ksyms),
     ("log", Rd(log::resource)),
+    ("memory", Rd(memory::resource)),
+    ("memory_pressure", MemoryPressure),
+    ("profile", Profile),
     ("scheme", Rd(scheme::resource)),
+    #[cfg(feature = "scheme_metrics")]
+    ("scheme_metrics", Rd(scheme_metrics::resource)),
     ("scheme_num", Rd(scheme_num::resource)),
     ("syscall", Rd(syscall::resource)),
+    ("topology", Rd(topology::resource)),
+    ("trace", Trace),
     ("uname", Rd(uname::resource)),
     ("env", Rd(|_| Ok(Vec::from(crate::init_env())))),
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
@@ -91,6 +175,7 @@ const FILES: &[(&str, Kind)] = &[
         Ok(Vec::new())
     })),
     */
+    ("deadline_cap", Wr(crate::scheduler::sys_set_deadline_cap)),
     (
         "update_time_offset",
         Wr(crate::time::sys_update_time_offset),
@@ -131,14 +216,61 @@ impl KernelScheme for SysScheme {
                 .find(|(entry_path, _)| *entry_path == path)
                 .ok_or(Error::new(ENOENT))?;
 
-            if matches!(entry.1, Wr(_)) && ctx.uid != 0 {
+            // The boot environment may contain secrets, and kernel addresses help exploits
+            let root_only = matches!(entry.1, Wr(_) | Profile | Trace | RingBench)
+                || entry.0 == "env"
+                || entry.0 == "ksyms";
+            if root_only && ctx.uid != 0 {
                 return Err(Error::new(EPERM));
             }
 
             let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+            if matches!(entry.1, Kmsg) {
+                let cursor = kmsg::first_cursor();
+                HANDLES
+                    .write(token.token())
+                    .insert(id, Handle::Kmsg { cursor });
+                return Ok(OpenResult::SchemeLocal(id, InternalFlags::empty()));
+            }
+            #[cfg(feature = "ksyms")]
+            if matches!(entry.1, Ksyms) {
+                HANDLES.write(token.token()).insert(
+                    id,
+                    Handle::Ksyms {
+                        cursor: ksyms::Cursor::default(),
+                    },
+                );
+                return Ok(OpenResult::SchemeLocal(id, InternalFlags::POSITIONED));
+            }
+            if matches!(entry.1, Profile) {
+                HANDLES
+                    .write(token.token())
+                    .insert(id, Handle::Profile { header_sent: false });
+                return Ok(OpenResult::SchemeLocal(id, InternalFlags::empty()));
+            }
+            if matches!(entry.1, Trace) {
+                HANDLES
+                    .write(token.token())
+                    .insert(id, Handle::Trace { header_sent: false });
+                return Ok(OpenResult::SchemeLocal(id, InternalFlags::empty()));
+            }
+            if matches!(entry.1, RingBench) {
+                HANDLES.write(token.token()).insert(id, Handle::RingBench);
+                return Ok(OpenResult::SchemeLocal(id, InternalFlags::POSITIONED));
+            }
+            if matches!(entry.1, MemoryPressure) {
+                HANDLES
+                    .write(token.token())
+                    .insert(id, Handle::MemoryPressure);
+                return Ok(OpenResult::SchemeLocal(id, InternalFlags::POSITIONED));
+            }
             let data = match entry.1 {
                 Rd(r) => Some(r(token)?),
-                Wr(_) => None,
+                #[cfg(feature = "kheap_fault_injection")]
+                RdWr(r, _) => Some(r(token)?),
+                #[cfg(feature = "ksyms")]
+                Ksyms => None,
+                Wr(_) | Kmsg | Profile | Trace | RingBench | MemoryPressure => None,
             };
             HANDLES.write(token.token()).insert(
                 id,
@@ -157,7 +289,14 @@ impl KernelScheme for SysScheme {
             .get(&id)
             .ok_or(Error::new(EBADF))?
         {
-            Handle::TopLevel => Ok(0),
+            Handle::TopLevel
+            | Handle::Kmsg { .. }
+            | Handle::Profile { .. }
+            | Handle::Trace { .. } => Ok(0),
+            #[cfg(feature = "ksyms")]
+            Handle::Ksyms { .. } => Ok(0),
+            Handle::RingBench => Ok(ring_bench::results().len() as u64),
+            Handle::MemoryPressure => Ok(memory::pressure_state().len() as u64),
             Handle::Resource { data, .. } => Ok(data.as_ref().map_or(0, |d| d.len() as u64)),
         }
     }
@@ -174,6 +313,13 @@ impl KernelScheme for SysScheme {
         let path = match handles.get(&id).ok_or(Error::new(EBADF))? {
             Handle::TopLevel => "",
             Handle::Resource { path, .. } => path,
+            Handle::Kmsg { .. } => "kmsg",
+            #[cfg(feature = "ksyms")]
+            Handle::Ksyms { .. } => "ksyms",
+            Handle::Profile { .. } => "profile",
+            Handle::Trace { .. } => "trace",
+            Handle::RingBench => "bench/ring",
+            Handle::MemoryPressure => "memory_pressure",
         };
 
         const FIRST: &[u8] = b"sys:";
@@ -190,10 +336,71 @@ impl KernelScheme for SysScheme {
         id: usize,
         buffer: UserSliceWo,
         pos: u64,
-        _flags: u32,
+        flags: u32,
         _stored_flags: u32,
         token: &mut CleanLockToken,
     ) -> Result<usize> {
+        let kmsg_cursor = match HANDLES.read(token.token()).get(&id) {
+            Some(&Handle::Kmsg { cursor }) => Some(cursor),
+            _ => None,
+        };
+        if let Some(cursor) = kmsg_cursor {
+            // Blocks without holding the handle table, so the cursor is stored afterwards
+            let nonblock = flags & O_NONBLOCK as u32 != 0;
+            let (bytes_read, cursor) = kmsg::read(cursor, buffer, nonblock, token)?;
+            if let Some(Handle::Kmsg { cursor: stored }) = HANDLES.write(token.token()).get_mut(&id)
+            {
+                *stored = cursor;
+            }
+            return Ok(bytes_read);
+        }
+        let profile_header_sent = match HANDLES.read(token.token()).get(&id) {
+            Some(&Handle::Profile { header_sent }) => Some(header_sent),
+            _ => None,
+        };
+        if let Some(mut header_sent) = profile_header_sent {
+            // Copies out without holding the handle table, which a fault could need
+            let bytes_read = profile::read(buffer, &mut header_sent)?;
+            if let Some(Handle::Profile {
+                header_sent: stored,
+            }) = HANDLES.write(token.token()).get_mut(&id)
+            {
+                *stored = header_sent;
+            }
+            return Ok(bytes_read);
+        }
+        let trace_header_sent = match HANDLES.read(token.token()).get(&id) {
+            Some(&Handle::Trace { header_sent }) => Some(header_sent),
+            _ => None,
+        };
+        if let Some(mut header_sent) = trace_header_sent {
+            let bytes_read = trace::read(buffer, &mut header_sent)?;
+            if let Some(Handle::Trace {
+                header_sent: stored,
+            }) = HANDLES.write(token.token()).get_mut(&id)
+            {
+                *stored = header_sent;
+            }
+            return Ok(bytes_read);
+        }
+        #[cfg(feature = "ksyms")]
+        {
+            let ksyms_cursor = match HANDLES.read(token.token()).get(&id) {
+                Some(&Handle::Ksyms { cursor }) => Some(cursor),
+                _ => None,
+            };
+            if let Some(cursor) = ksyms_cursor {
+                let (text, cursor) = ksyms::read(buffer.len(), pos, cursor);
+                let bytes_read = buffer.copy_common_bytes_from_slice(&text)?;
+                if let Some(Handle::Ksyms { cursor: stored }) =
+                    HANDLES.write(token.token()).get_mut(&id)
+                {
+                    *stored = cursor;
+                }
+                return Ok(bytes_read);
+            }
+        }
+
         let Ok(pos) = usize::try_from(pos) else {
             return Ok(0);
         };
@@ -203,6 +410,19 @@ impl KernelScheme for SysScheme {
             .get(&id)
             .ok_or(Error::new(EBADF))?
         {
+            Handle::Kmsg { .. } | Handle::Profile { .. } | Handle::Trace { .. } => {
+                unreachable!("sys:kmsg, sys:profile and sys:trace reads are handled above")
+            }
+            #[cfg(feature = "ksyms")]
+            Handle::Ksyms { .. } => unreachable!("sys:ksyms reads are handled above"),
+            Handle::RingBench => {
+                let results = ring_bench::results();
+                buffer.copy_common_bytes_from_slice(results.get(pos..).unwrap_or(&[]))
+            }
+            Handle::MemoryPressure => {
+                let state = memory::pressure_state();
+                buffer.copy_common_bytes_from_slice(state.get(pos..).unwrap_or(&[]))
+            }
             Handle::TopLevel | Handle::Resource { data: None, .. } => Err(Error::new(EISDIR)),
             &Handle::Resource {
                 data: Some(ref data),
@@ -228,8 +448,45 @@ impl KernelScheme for SysScheme {
             .get(&id)
             .ok_or(Error::new(EBADF))?
         {
-            Handle::TopLevel | Handle::Resource { data: Some(_), .. } => {
-                return Err(Error::new(EISDIR))
+            Handle::TopLevel => return Err(Error::new(EISDIR)),
+            #[cfg(feature = "kheap_fault_injection")]
+            Handle::Resource {
+                data: Some(_),
+                path,
+            } => {
+                let Some((_, RdWr(_, handler))) =
+                    FILES.iter().find(|(entry_path, _)| entry_path == path)
+                else {
+                    return Err(Error::new(EISDIR));
+                };
+                let mut intermediate = [0_u8; 256];
+                let len = buffer.copy_common_bytes_to_slice(&mut intermediate)?;
+                (*handler, intermediate, len)
+            }
+            #[cfg(not(feature = "kheap_fault_injection"))]
+            Handle::Resource { data: Some(_), .. } => return Err(Error::new(EISDIR)),
+            Handle::Kmsg { .. } | Handle::MemoryPressure => return Err(Error::new(EBADF)),
+            #[cfg(feature = "ksyms")]
+            Handle::Ksyms { .. } => return Err(Error::new(EBADF)),
+            Handle::Profile { .. } => {
+                let mut intermediate = [0_u8; 32];
+                let len = buffer.copy_common_bytes_to_slice(&mut intermediate)?;
+                return profile::write(&intermediate[..len]);
+            }
+            Handle::Trace { .. } => {
+                let mut intermediate = [0_u8; 32];
+                let len = buffer.copy_common_bytes_to_slice(&mut intermediate)?;
+                return trace::write(&intermediate[..len]);
+            }
+            // Runs once the handle table is unlocked
+            Handle::RingBench => {
+                let mut intermediate = [0_u8; 256];
+                let len = buffer.copy_common_bytes_to_slice(&mut intermediate)?;
+                (
+                    ring_bench::write as fn(&[u8], &mut CleanLockToken) -> _,
+                    intermediate,
+                    len,
+                )
             }
             Handle::Resource { data: None, path } => {
                 let mut intermediate = [0_u8; 256];
@@ -246,6 +503,23 @@ impl KernelScheme for SysScheme {
         };
         handler(&intermediate[..len], token)
     }
+    fn fevent(
+        &self,
+        id: usize,
+        _flags: EventFlags,
+        token: &mut CleanLockToken,
+    ) -> Result<EventFlags> {
+        match HANDLES
+            .read(token.token())
+            .get(&id)
+            .ok_or(Error::new(EBADF))?
+        {
+            // Pressure that started before the registration is reported right away
+            Handle::MemoryPressure if crate::memory::pressure::under_pressure() => Ok(EVENT_READ),
+            Handle::MemoryPressure => Ok(EventFlags::empty()),
+            _ => Err(Error::new(ENOSYS)),
+        }
+    }
     fn getdents(
         &self,
         id: usize,
@@ -262,7 +536,14 @@ impl KernelScheme for SysScheme {
             .get(&id)
             .ok_or(Error::new(EBADF))?
         {
-            Handle::Resource { .. } => Err(Error::new(ENOTDIR)),
+            Handle::Resource { .. }
+            | Handle::Kmsg { .. }
+            | Handle::Profile { .. }
+            | Handle::Trace { .. }
+            | Handle::RingBench
+            | Handle::MemoryPressure => Err(Error::new(ENOTDIR)),
+            #[cfg(feature = "ksyms")]
+            Handle::Ksyms { .. } => Err(Error::new(ENOTDIR)),
             Handle::TopLevel => {
                 let mut buf = DirentBuf::new(buf, header_size).ok_or(Error::new(EIO))?;
                 for (this_idx, (name, _)) in FILES.iter().enumerate().skip(first_index) {
@@ -291,6 +572,24 @@ impl KernelScheme for SysScheme {
                 st_size: data.as_ref().map_or(0, |d| d.len() as u64),
                 ..Default::default()
             },
+            Handle::Kmsg { .. } | Handle::MemoryPressure => Stat {
+                st_mode: 0o444 | MODE_FILE,
+                ..Default::default()
+            },
+            Handle::Profile { .. } | Handle::Trace { .. } => Stat {
+                st_mode: 0o600 | MODE_FILE,
+                ..Default::default()
+            },
+            #[cfg(feature = "ksyms")]
+            Handle::Ksyms { .. } => Stat {
+                st_mode: 0o400 | MODE_FILE,
+                ..Default::default()
+            },
+            Handle::RingBench => Stat {
+                st_mode: 0o600 | MODE_FILE,
+                st_size: ring_bench::results().len() as u64,
+                ..Default::default()
+            },
             Handle::TopLevel => Stat {
                 st_mode: 0o444 | MODE_DIR,
                 st_uid: 0,
@@ -0,0 +1,132 @@
+use alloc::{collections::VecDeque, vec::Vec};
+use core::{mem::size_of, str, sync::atomic::Ordering};
+
+use crate::{
+    profiling::{self, ProfileRecord, BUFS, RECORD_DROPPED},
+    syscall::{
+        error::{Error, Result, EINVAL},
+        usercopy::UserSliceWo,
+    },
+};
+
+/// Magic bytes at the start of the sys:profile stream
+pub const MAGIC: [u8; 4] = *b"KPRF";
+/// Version of the header and record layout
+pub const VERSION: u16 = 1;
+
+/// Header sent once before the first record of a sys:profile stream
+///
+/// Userspace subtracts `text_start` from sampled instruction pointers to get offsets into the
+/// kernel ELF's text section.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct Header {
+    magic: [u8; 4],
+    version: u16,
+    record_size: u16,
+    text_start: u64,
+}
+
+/// Serializes readers of the per-CPU sample rings, each of which allows only one at a time.
+static DRAIN_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+/// Samples drained by a read that faulted copying them out, read first by the next one
+static REQUEUED: spin::Mutex<VecDeque<ProfileRecord>> = spin::Mutex::new(VecDeque::new());
+
+fn bytes_of<T: Copy>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts((value as *const T).cast::<u8>(), size_of::<T>()) }
+}
+
+/// Drain as many whole records as fit in `buf` from the per-CPU sample rings, preceded by the
+/// header if it has not been sent yet.
+///
+/// Each CPU that lost samples since the last read first gets a `RECORD_DROPPED` record with the
+/// count. Returns the number of bytes read, 0 meaning no samples are pending right now. The
+/// records are gathered in a kernel buffer first, and requeued if copying them out faults.
+pub fn read(buf: UserSliceWo, header_sent: &mut bool) -> Result<usize> {
+    let mut out = Vec::new();
+    if !*header_sent {
+        let header = Header {
+            magic: MAGIC,
+            version: VERSION,
+            record_size: size_of::<ProfileRecord>() as u16,
+            text_start: crate::kernel_executable_offsets::__text_start() as u64,
+        };
+        if buf.len() < size_of::<Header>() {
+            return Err(Error::new(EINVAL));
+        }
+        out.extend_from_slice(bytes_of(&header));
+    }
+
+    let mut room = (buf.len() - out.len()) / size_of::<ProfileRecord>();
+    let mut records = Vec::new();
+    {
+        let _guard = DRAIN_LOCK.lock();
+        let mut requeued = REQUEUED.lock();
+        while room > 0
+            && let Some(record) = requeued.pop_front()
+        {
+            records.push(record);
+            room -= 1;
+        }
+        drop(requeued);
+
+        for (cpu, ring) in BUFS.iter().enumerate() {
+            if room == 0 {
+                break;
+            }
+            let Some(ring) = (unsafe { ring.load(Ordering::Relaxed).as_ref() }) else {
+                continue;
+            };
+            let dropped = ring.samples.take_dropped();
+            if dropped > 0 {
+                let record = ProfileRecord {
+                    kind: RECORD_DROPPED,
+                    cpu: cpu as u32,
+                    context: dropped,
+                    ..ProfileRecord::default()
+                };
+                records.push(record);
+                room -= 1;
+            }
+            while room > 0 {
+                // DRAIN_LOCK is held, so this is the only reader
+                let Some(record) = (unsafe { ring.samples.pop() }) else {
+                    break;
+                };
+                records.push(record);
+                room -= 1;
+            }
+        }
+    }
+
+    for record in &records {
+        out.extend_from_slice(bytes_of(record));
+    }
+    let copied = buf
+        .limit(out.len())
+        .ok_or(Error::new(EINVAL))?
+        .copy_from_slice(&out);
+    if let Err(err) = copied {
+        let mut requeued = REQUEUED.lock();
+        for record in records.into_iter().rev() {
+            requeued.push_front(record);
+        }
+        return Err(err);
+    }
+    *header_sent = true;
+    Ok(out.len())
+}
+
+/// Control sampling with "start <hz>" or "stop"
+pub fn write(buf: &[u8]) -> Result<usize> {
+    let command = str::from_utf8(buf).map_err(|_| Error::new(EINVAL))?.trim();
+    match command.split_once(' ') {
+        Some(("start", hz)) => {
+            let hz = hz.trim().parse().map_err(|_| Error::new(EINVAL))?;
+            profiling::start(hz)?;
+        }
+        None if command == "stop" => profiling::stop(),
+        _ => return Err(Error::new(EINVAL)),
+    }
+    Ok(buf.len())
+}
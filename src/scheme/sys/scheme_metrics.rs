@@ -0,0 +1,59 @@
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use core::fmt::Write;
+
+use crate::{
+    context,
+    scheme::{
+        self,
+        metrics::{self, Op, BUCKETS, MAX_SCHEMES},
+        SchemeId,
+    },
+    sync::CleanLockToken,
+    syscall::error::Result,
+};
+
+/// Get the sys:scheme_metrics data, one row per scheme and operation that has been called
+///
+/// Bucket `n` counts calls that took between 2^n and 2^(n+1) ns, the last one everything slower.
+/// Schemes not visible in the caller's namespace are listed by ID.
+pub fn resource(token: &mut CleanLockToken) -> Result<Vec<u8>> {
+    let scheme_ns = context::current().read(token.token()).ens;
+    let names: BTreeMap<SchemeId, String> = scheme::schemes(&token.token())
+        .iter_name(scheme_ns)
+        .map(|(name, &id)| (id, String::from(&**name)))
+        .collect();
+
+    let mut string = String::new();
+
+    let _ = write!(string, "{:<16} {:<5} {:>10}", "scheme", "op", "calls");
+    for bucket in 0..BUCKETS {
+        let _ = write!(string, " {:>8}", format_args!("2^{}", bucket));
+    }
+    let _ = writeln!(string);
+
+    for id in (1..MAX_SCHEMES).map(SchemeId::new) {
+        for op in Op::ALL {
+            let counts = metrics::histogram(id, op);
+            let calls: u64 = counts.iter().sum();
+            if calls == 0 {
+                continue;
+            }
+
+            match names.get(&id) {
+                Some(name) => {
+                    let _ = write!(string, "{:<16}", name);
+                }
+                None => {
+                    let _ = write!(string, "{:<16}", format_args!("#{}", id.get()));
+                }
+            }
+            let _ = write!(string, " {:<5} {:>10}", op.name(), calls);
+            for count in counts {
+                let _ = write!(string, " {:>8}", count);
+            }
+            let _ = writeln!(string);
+        }
+    }
+
+    Ok(string.into_bytes())
+}
@@ -1,5 +1,5 @@
 use crate::{
-    context::{contexts, ContextRef, Status},
+    context::{context::open_files, contexts, ContextRef, Status, MAX_OPEN_FILES},
     cpu_stats::{get_context_switch_count, get_contexts_count, irq_counts},
     percpu::get_all_stats,
     sync::CleanLockToken,
@@ -19,11 +19,13 @@ pub fn resource(token: &mut CleanLockToken) -> Result<Vec<u8>> {
         context_switches: {}\n\
         contexts_created: {}\n\
         contexts_running: {contexts_running}\n\
-        contexts_blocked: {contexts_blocked}",
+        contexts_blocked: {contexts_blocked}\n\
+        open_files: {} {MAX_OPEN_FILES}",
         get_cpu_stats(),
         get_irq_stats(),
         get_context_switch_count(),
         get_contexts_count(),
+        open_files(),
     );
 
     Ok(res.into_bytes())
@@ -84,7 +86,7 @@ fn get_contexts_stats(token: &mut CleanLockToken) -> (u64, u64) {
     for status in statuses {
         if matches!(status, Status::Runnable) {
             running += 1;
-        } else if !matches!(status, Status::Dead { .. }) {
+        } else if !status.has_exited() {
             blocked += 1;
         }
     }
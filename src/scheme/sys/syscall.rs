@@ -12,7 +12,7 @@ pub fn resource(token: &mut CleanLockToken) -> Result<Vec<u8>> {
             let contexts = context::contexts().read();
             for context_ref in contexts.values() {
                 let context = context_ref.read(token.token());
-                rows.push((context.pid, context.name.clone(), context.current_syscall()));
+                rows.push((context.pid, context.name.get(), context.current_syscall()));
             }
         }
         rows.sort_by_key(|row| row.0);
@@ -0,0 +1,81 @@
+use alloc::{string::String, vec::Vec};
+use core::{fmt::Write, ops::Range};
+
+use crate::{
+    cpu_set::LogicalCpuId,
+    sync::CleanLockToken,
+    syscall::error::Result,
+    topology::{CpuLocation, NumaNodeId, CPU_TOPOLOGY},
+};
+
+/// Get the sys:topology data, a row per CPU followed by the NUMA node of each memory range.
+pub fn resource(_token: &mut CleanLockToken) -> Result<Vec<u8>> {
+    Ok(format_topology(&CPU_TOPOLOGY.cpus(), &CPU_TOPOLOGY.memory()).into_bytes())
+}
+
+fn format_topology(
+    cpus: &[(LogicalCpuId, CpuLocation)],
+    memory: &[(Range<usize>, NumaNodeId)],
+) -> String {
+    let mut string = String::new();
+
+    let _ = writeln!(
+        string,
+        "{:>6} {:>8} {:>6} {:>6} {:>6}",
+        "cpu", "package", "core", "thread", "node"
+    );
+    for (id, location) in cpus {
+        let _ = writeln!(
+            string,
+            "{:>6} {:>8} {:>6} {:>6} {:>6}",
+            id.get(),
+            location.package,
+            location.core,
+            location.thread,
+            location.node.0
+        );
+    }
+
+    for (range, node) in memory {
+        let _ = writeln!(
+            string,
+            "memory {:#018x}:{:#018x} node {}",
+            range.start, range.end, node
+        );
+    }
+
+    string
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_per_cpu_and_memory_range() {
+        let location = |core, thread, node| CpuLocation {
+            package: 0,
+            core,
+            thread,
+            node: NumaNodeId(node),
+        };
+        let text = format_topology(
+            &[
+                (LogicalCpuId::new(0), location(0, 0, 0)),
+                (LogicalCpuId::new(1), location(0, 1, 0)),
+                (LogicalCpuId::new(2), location(1, 0, 1)),
+            ],
+            &[(0x10_0000..0x8000_0000, NumaNodeId(0))],
+        );
+
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "   cpu  package   core thread   node");
+        assert_eq!(lines[2], "     1        0      0      1      0");
+        assert_eq!(lines[3], "     2        0      1      0      1");
+        assert_eq!(
+            lines[4],
+            "memory 0x0000000000100000:0x0000000080000000 node 0"
+        );
+        assert_eq!(lines.len(), 5);
+    }
+}
@@ -0,0 +1,99 @@
+use alloc::{collections::VecDeque, vec::Vec};
+use core::{mem::size_of, str};
+
+use crate::{
+    context::trace::{self, TraceRecord, TRACE_CAPACITY},
+    syscall::{
+        error::{Error, Result, EINVAL},
+        usercopy::UserSliceWo,
+    },
+};
+
+/// Magic bytes at the start of the sys:trace stream
+pub const MAGIC: [u8; 4] = *b"KTRC";
+/// Version of the header and record layout, see [`crate::context::trace`]
+pub const VERSION: u16 = 1;
+
+/// Header sent once before the first record of a sys:trace stream
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct Header {
+    magic: [u8; 4],
+    version: u16,
+    record_size: u16,
+    capacity: u64,
+}
+
+/// Records drained by a read that faulted copying them out, read first by the next one
+static REQUEUED: spin::Mutex<VecDeque<TraceRecord>> = spin::Mutex::new(VecDeque::new());
+
+fn bytes_of<T: Copy>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts((value as *const T).cast::<u8>(), size_of::<T>()) }
+}
+
+/// Drain as many whole records as fit in `buf`, preceded by the header if it has not been sent
+/// yet. Returns the number of bytes read, 0 meaning no switches are pending right now. The records
+/// are gathered in a kernel buffer first, and requeued if copying them out faults.
+pub fn read(buf: UserSliceWo, header_sent: &mut bool) -> Result<usize> {
+    let mut out = Vec::new();
+    if !*header_sent {
+        let header = Header {
+            magic: MAGIC,
+            version: VERSION,
+            record_size: size_of::<TraceRecord>() as u16,
+            capacity: TRACE_CAPACITY as u64,
+        };
+        if buf.len() < size_of::<Header>() {
+            return Err(Error::new(EINVAL));
+        }
+        out.extend_from_slice(bytes_of(&header));
+    }
+
+    let mut room = (buf.len() - out.len()) / size_of::<TraceRecord>();
+    let mut records = Vec::new();
+    {
+        let mut requeued = REQUEUED.lock();
+        while room > 0
+            && let Some(record) = requeued.pop_front()
+        {
+            records.push(record);
+            room -= 1;
+        }
+    }
+    trace::drain(|record| {
+        if room == 0 {
+            return false;
+        }
+        records.push(record);
+        room -= 1;
+        true
+    });
+
+    for record in &records {
+        out.extend_from_slice(bytes_of(record));
+    }
+    let copied = buf
+        .limit(out.len())
+        .ok_or(Error::new(EINVAL))?
+        .copy_from_slice(&out);
+    if let Err(err) = copied {
+        let mut requeued = REQUEUED.lock();
+        for record in records.into_iter().rev() {
+            requeued.push_front(record);
+        }
+        return Err(err);
+    }
+    *header_sent = true;
+    Ok(out.len())
+}
+
+/// Control tracing with "start", "stop" or "clear"
+pub fn write(buf: &[u8]) -> Result<usize> {
+    match str::from_utf8(buf).map_err(|_| Error::new(EINVAL))?.trim() {
+        "start" => trace::start(),
+        "stop" => trace::stop(),
+        "clear" => trace::clear(),
+        _ => return Err(Error::new(EINVAL)),
+    }
+    Ok(buf.len())
+}
@@ -1,11 +1,15 @@
 use crate::{sync::CleanLockToken, syscall::error::Result};
 use alloc::vec::Vec;
 
+/// Get the sys:uname data, one field per line: kernel name, host name, release, version,
+/// machine and the number of processors.
 pub fn resource(_token: &mut CleanLockToken) -> Result<Vec<u8>> {
     Ok(format!(
-        "Redox\n\n{}\n\n{}\n",
+        "Redox\n\n{}+{}\n\n{}\n{}\n",
         env!("CARGO_PKG_VERSION"),
-        env!("TARGET").split('-').next().unwrap()
+        env!("GIT_HASH"),
+        env!("TARGET").split('-').next().unwrap_or(env!("TARGET")),
+        crate::cpu_count()
     )
     .into_bytes())
 }
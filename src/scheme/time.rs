@@ -1,16 +1,25 @@
+use alloc::{sync::Arc, vec::Vec};
 use core::{
-    mem, str,
+    mem,
+    num::NonZeroUsize,
+    str,
     sync::atomic::{AtomicUsize, Ordering},
 };
 use hashbrown::{hash_map::DefaultHashBuilder, HashMap};
 
 use crate::{
-    context::{file::InternalFlags, timeout},
+    context::{
+        self,
+        file::InternalFlags,
+        memory::{handle_notify_files, AddrSpaceWrapper, Grant, PageSpan},
+        timeout,
+    },
+    paging::{Page, VirtualAddress, PAGE_SIZE},
     sync::{CleanLockToken, RwLock, L1},
     syscall::{
-        data::TimeSpec,
+        data::{Map, TimeSpec},
         error::*,
-        flag::{EventFlags, CLOCK_MONOTONIC, CLOCK_REALTIME},
+        flag::{EventFlags, MapFlags, CLOCK_MONOTONIC, CLOCK_REALTIME, EVENT_READ, O_NONBLOCK},
         usercopy::{UserSliceRo, UserSliceWo},
     },
     time,
@@ -18,10 +27,187 @@ use crate::{
 
 use super::{CallerCtx, GlobalSchemes, KernelScheme, OpenResult};
 
+#[derive(Clone, Copy)]
+enum Handle {
+    /// Reads and timeouts on a clock
+    Clock(usize),
+    /// A timer on a clock, with its state in `TIMERS`
+    Timer(usize),
+    /// The shared time page, only useful to fmap
+    Page,
+}
+
+/// Periodic timer of a [`Handle::Timer`], with times in nanoseconds on its clock
+#[derive(Clone, Copy, Debug, Default)]
+struct Timer {
+    /// First expiration, or 0 while disarmed
+    first: u128,
+    /// Time between expirations, or 0 to expire once
+    interval: u128,
+    /// Expirations already returned by reads
+    consumed: u64,
+    /// The timeout delivering an event at each expiration
+    timeout: Option<u64>,
+}
+
+impl Timer {
+    /// Expirations up to `now`, read or not
+    fn expirations(&self, now: u128) -> u64 {
+        if self.first == 0 || now < self.first {
+            0
+        } else if self.interval == 0 {
+            1
+        } else {
+            u64::try_from((now - self.first) / self.interval + 1).unwrap_or(u64::MAX)
+        }
+    }
+
+    /// Time of the first expiration not read yet
+    fn next_expiration(&self) -> Option<u128> {
+        match (self.first, self.interval) {
+            (0, _) => None,
+            (first, 0) => (self.consumed == 0).then_some(first),
+            (first, interval) => Some(first + u128::from(self.consumed) * interval),
+        }
+    }
+}
+
 static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
-static HANDLES: RwLock<L1, HashMap<usize, usize>> =
+static HANDLES: RwLock<L1, HashMap<usize, Handle>> =
+    RwLock::new(HashMap::with_hasher(DefaultHashBuilder::new()));
+static TIMERS: RwLock<L1, HashMap<usize, Timer>> =
     RwLock::new(HashMap::with_hasher(DefaultHashBuilder::new()));
 
+fn clock(handle: Handle) -> Result<usize> {
+    match handle {
+        Handle::Clock(clock) => Ok(clock),
+        Handle::Timer(_) | Handle::Page => Err(Error::new(EINVAL)),
+    }
+}
+
+fn now(clock: usize) -> u128 {
+    match clock {
+        CLOCK_REALTIME => time::realtime(),
+        _ => time::monotonic(),
+    }
+}
+
+/// Read the expirations of timer `id` not read yet, waiting for one unless `nonblock` is set
+fn read_timer(
+    id: usize,
+    clock: usize,
+    buf: UserSliceWo,
+    nonblock: bool,
+    token: &mut CleanLockToken,
+) -> Result<usize> {
+    if buf.len() < mem::size_of::<u64>() {
+        return Err(Error::new(EINVAL));
+    }
+    loop {
+        let next = {
+            let mut timers = TIMERS.write(token.token());
+            let timer = timers.get_mut(&id).ok_or(Error::new(EBADF))?;
+            let total = timer.expirations(now(clock));
+            if total > timer.consumed {
+                let count = total - timer.consumed;
+                timer.consumed = total;
+                drop(timers);
+                buf.limit(mem::size_of::<u64>())
+                    .expect("length checked above")
+                    .copy_from_slice(&count.to_ne_bytes())?;
+                return Ok(mem::size_of::<u64>());
+            }
+            timer.next_expiration()
+        };
+        // Arming does not wake readers, so a disarmed timer cannot be waited for
+        let Some(next) = next.filter(|_| !nonblock) else {
+            return Err(Error::new(EAGAIN));
+        };
+        sleep_until(clock, next, token)?;
+    }
+}
+
+/// Block the current context until `clock` reaches `deadline`, failing with EINTR if anything
+/// else unblocks it first
+fn sleep_until(clock: usize, deadline: u128, token: &mut CleanLockToken) -> Result<()> {
+    // The scheduler's wake time is on the monotonic clock
+    let wake = deadline.saturating_sub(now(clock)) + time::monotonic();
+
+    let context_lock = context::current();
+    {
+        let mut context = context_lock.write(token.token());
+        if let Some((tctl, pctl, _)) = context.sigcontrol() {
+            if tctl.currently_pending_unblocked(pctl) != 0 {
+                return Err(Error::new(EINTR));
+            }
+        }
+        context.wake = Some(wake);
+        context.block("TimeScheme::read");
+    }
+
+    let weak = Arc::downgrade(&context_lock);
+    timeout::register_wakeup(weak.clone(), clock, deadline, token);
+    time::set_next_timer_event(wake as u64);
+
+    unsafe { context::switch(token) };
+
+    timeout::cancel_wakeup(&weak, token);
+    context_lock.write(token.token()).wake = None;
+
+    if now(clock) < deadline {
+        return Err(Error::new(EINTR));
+    }
+    Ok(())
+}
+
+/// Arm timer `id` from an `(interval, initial_expiration)` pair, or disarm it if the initial
+/// expiration is 0
+fn arm_timer(
+    id: usize,
+    clock: usize,
+    buf: UserSliceRo,
+    token: &mut CleanLockToken,
+) -> Result<usize> {
+    if buf.len() != mem::size_of::<[u64; 2]>() {
+        return Err(Error::new(EINVAL));
+    }
+    let [interval, first] = unsafe { buf.read_exact::<[u64; 2]>()? }.map(u128::from);
+
+    // Register before publishing, so that of concurrent writes the last one to publish wins and
+    // each cancels the timeout it replaced
+    let timeout = (first != 0).then(|| {
+        timeout::register_periodic(
+            GlobalSchemes::Time.scheme_id(),
+            id,
+            clock,
+            first,
+            interval,
+            token,
+        )
+    });
+    let replaced = match TIMERS.write(token.token()).get_mut(&id) {
+        Some(timer) => {
+            mem::replace(
+                timer,
+                Timer {
+                    first,
+                    interval,
+                    consumed: 0,
+                    timeout,
+                },
+            )
+            .timeout
+        }
+        // Closed meanwhile
+        None => timeout,
+    };
+    if let Some(replaced) = replaced {
+        timeout::cancel(replaced, token);
+    }
+
+    Ok(buf.len())
+}
+
 pub struct TimeScheme;
 
 impl KernelScheme for TimeScheme {
@@ -32,16 +218,26 @@ impl KernelScheme for TimeScheme {
         _ctx: CallerCtx,
         token: &mut CleanLockToken,
     ) -> Result<OpenResult> {
-        let clock = path.parse::<usize>().map_err(|_| Error::new(ENOENT))?;
-
-        match clock {
-            CLOCK_REALTIME => (),
-            CLOCK_MONOTONIC => (),
-            _ => return Err(Error::new(ENOENT)),
-        }
+        let parse_clock = |clock: &str| match clock.parse::<usize>() {
+            Ok(clock @ (CLOCK_REALTIME | CLOCK_MONOTONIC)) => Ok(clock),
+            _ => Err(Error::new(ENOENT)),
+        };
+        let handle = match path {
+            "page" => {
+                time::time_page_frame().ok_or(Error::new(ENODEV))?;
+                Handle::Page
+            }
+            _ => match path.strip_suffix("/timer") {
+                Some(clock) => Handle::Timer(parse_clock(clock)?),
+                None => Handle::Clock(parse_clock(path)?),
+            },
+        };
 
         let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
-        HANDLES.write(token.token()).insert(id, clock);
+        if let Handle::Timer(_) = handle {
+            TIMERS.write(token.token()).insert(id, Timer::default());
+        }
+        HANDLES.write(token.token()).insert(id, handle);
 
         Ok(OpenResult::SchemeLocal(id, InternalFlags::empty()))
     }
@@ -62,11 +258,20 @@ impl KernelScheme for TimeScheme {
         _flags: EventFlags,
         token: &mut CleanLockToken,
     ) -> Result<EventFlags> {
-        HANDLES
+        let handle = *HANDLES
             .read(token.token())
             .get(&id)
-            .ok_or(Error::new(EBADF))
-            .and(Ok(EventFlags::empty()))
+            .ok_or(Error::new(EBADF))?;
+
+        // Each later expiration triggers an event through the timer's timeout
+        if let Handle::Timer(clock) = handle {
+            let timers = TIMERS.read(token.token());
+            let timer = timers.get(&id).ok_or(Error::new(EBADF))?;
+            if timer.expirations(now(clock)) > timer.consumed {
+                return Ok(EVENT_READ);
+            }
+        }
+        Ok(EventFlags::empty())
     }
 
     fn fsync(&self, id: usize, token: &mut CleanLockToken) -> Result<()> {
@@ -78,24 +283,36 @@ impl KernelScheme for TimeScheme {
     }
 
     fn close(&self, id: usize, token: &mut CleanLockToken) -> Result<()> {
-        HANDLES
+        let handle = HANDLES
             .write(token.token())
             .remove(&id)
-            .ok_or(Error::new(EBADF))
-            .and(Ok(()))
+            .ok_or(Error::new(EBADF))?;
+
+        if let Handle::Timer(_) = handle {
+            let timer = TIMERS.write(token.token()).remove(&id);
+            if let Some(timeout) = timer.and_then(|timer| timer.timeout) {
+                timeout::cancel(timeout, token);
+            }
+        }
+        Ok(())
     }
     fn kread(
         &self,
         id: usize,
         buf: UserSliceWo,
-        _flags: u32,
-        _stored_flags: u32,
+        flags: u32,
+        stored_flags: u32,
         token: &mut CleanLockToken,
     ) -> Result<usize> {
-        let clock = *HANDLES
+        let handle = *HANDLES
             .read(token.token())
             .get(&id)
             .ok_or(Error::new(EBADF))?;
+        if let Handle::Timer(clock) = handle {
+            let nonblock = (flags | stored_flags) & O_NONBLOCK as u32 != 0;
+            return read_timer(id, clock, buf, nonblock, token);
+        }
+        let clock = clock(handle)?;
 
         let mut bytes_read = 0;
 
@@ -125,10 +342,14 @@ impl KernelScheme for TimeScheme {
         _stored_flags: u32,
         token: &mut CleanLockToken,
     ) -> Result<usize> {
-        let clock = *HANDLES
+        let handle = *HANDLES
             .read(token.token())
             .get(&id)
             .ok_or(Error::new(EBADF))?;
+        if let Handle::Timer(clock) = handle {
+            return arm_timer(id, clock, buf, token);
+        }
+        let clock = clock(handle)?;
 
         let mut bytes_written = 0;
 
@@ -143,12 +364,95 @@ impl KernelScheme for TimeScheme {
         Ok(bytes_written)
     }
     fn kfpath(&self, id: usize, buf: UserSliceWo, token: &mut CleanLockToken) -> Result<usize> {
-        let clock = *HANDLES
+        let handle = *HANDLES
             .read(token.token())
             .get(&id)
             .ok_or(Error::new(EBADF))?;
 
-        let scheme_path = format!("/scheme/time/{}", clock).into_bytes();
-        buf.copy_common_bytes_from_slice(&scheme_path)
+        let scheme_path = match handle {
+            Handle::Clock(clock) => format!("/scheme/time/{}", clock),
+            Handle::Timer(clock) => format!("/scheme/time/{}/timer", clock),
+            Handle::Page => "/scheme/time/page".into(),
+        };
+        buf.copy_common_bytes_from_slice(scheme_path.as_bytes())
+    }
+
+    fn kfmap(
+        &self,
+        id: usize,
+        addr_space: &Arc<AddrSpaceWrapper>,
+        map: &Map,
+        _consume: bool,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        match HANDLES.read(token.token()).get(&id) {
+            Some(Handle::Page) => (),
+            Some(Handle::Clock(_) | Handle::Timer(_)) => return Err(Error::new(EINVAL)),
+            None => return Err(Error::new(EBADF)),
+        }
+        if map.offset != 0 || map.size == 0 || map.size > PAGE_SIZE {
+            return Err(Error::new(EINVAL));
+        }
+        // Shared by every process, so it can never be written from userspace
+        if map
+            .flags
+            .intersects(MapFlags::PROT_WRITE | MapFlags::PROT_EXEC)
+        {
+            return Err(Error::new(EACCES));
+        }
+
+        let frame = time::time_page_frame().ok_or(Error::new(ENODEV))?;
+        let page_count = NonZeroUsize::MIN;
+
+        let mut notify_files = Vec::new();
+        let base = addr_space.acquire_write().mmap(
+            (map.address != 0)
+                .then_some(Page::containing_address(VirtualAddress::new(map.address))),
+            page_count,
+            map.flags | MapFlags::PROT_READ,
+            &mut notify_files,
+            |dst_page, page_flags, dst_mapper, dst_flusher| {
+                Grant::physmap(
+                    frame,
+                    PageSpan::new(dst_page, page_count.get()),
+                    page_flags,
+                    dst_mapper,
+                    dst_flusher,
+                )
+            },
+        )?;
+        handle_notify_files(notify_files, token);
+
+        Ok(base.start_address().data())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timer_expirations() {
+        let mut timer = Timer {
+            first: 100,
+            interval: 10,
+            ..Timer::default()
+        };
+        assert_eq!(timer.expirations(99), 0);
+        assert_eq!(timer.expirations(100), 1);
+        assert_eq!(timer.expirations(135), 4);
+
+        timer.consumed = 4;
+        assert_eq!(timer.next_expiration(), Some(140));
+
+        let once = Timer {
+            first: 100,
+            ..Timer::default()
+        };
+        assert_eq!(once.expirations(1_000), 1);
+        assert_eq!(once.next_expiration(), Some(100));
+
+        assert_eq!(Timer::default().expirations(1_000), 0);
+        assert_eq!(Timer::default().next_expiration(), None);
     }
 }
@@ -1,5 +1,6 @@
 use alloc::{
     boxed::Box,
+    collections::{BTreeMap, BTreeSet},
     sync::{Arc, Weak},
     vec::Vec,
 };
@@ -7,7 +8,7 @@ use core::{
     mem,
     mem::size_of,
     num::NonZeroUsize,
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 use slab::Slab;
 use spin::{Mutex, RwLock};
@@ -32,8 +33,9 @@ use crate::{
     event,
     memory::Frame,
     paging::{Page, VirtualAddress, PAGE_SIZE},
+    scheduler,
     scheme::SchemeId,
-    sync::{CleanLockToken, OptimizedWaitQueue},
+    sync::{CleanLockToken, OptimizedWaitQueue, WaitCondition},
     syscall::{
         data::{Map, Packet},
         error::*,
@@ -54,9 +56,15 @@ pub struct UserInner {
     supports_on_close: bool,
     context: Weak<ContextLock>,
     todo: OptimizedWaitQueue<Sqe>,
+    /// EventFlags last registered through fevent on the root handle, EVENT_READ until then
+    event_interest: AtomicUsize,
 
     // FIXME: custom packed radix tree data structure
     states: Mutex<Slab<State>>,
+    /// Writes forwarded to the handler, so that fsync can wait for the ones issued before it
+    writes: Mutex<WriteTracker>,
+    /// Fsync callers waiting for earlier writes to be answered
+    writes_answered: WaitCondition,
 
     unmounting: AtomicBool,
 }
@@ -80,6 +88,68 @@ pub enum Response {
     MultipleFds(Option<Vec<Arc<RwLock<FileDescription>>>>),
 }
 
+/// Sequence numbers of the writes forwarded on each handle. Writes pass the low 32 bits of
+/// their number to the handler in the upper half of their flags argument, and fsync passes the
+/// number of the last write issued before it as its second argument.
+///
+/// A handler may answer an fsync before writes that were queued ahead of it, so the fsync caller
+/// only returns once every write up to that number has been answered.
+#[derive(Debug, Default)]
+struct WriteTracker {
+    files: BTreeMap<usize, FileWrites>,
+    /// Handle and sequence number of each unanswered write, by request tag
+    tags: BTreeMap<u32, (usize, u64)>,
+}
+
+#[derive(Debug, Default)]
+struct FileWrites {
+    /// Number of the last write issued, or 0 before the first
+    last: u64,
+    /// Numbers of the writes not answered yet
+    pending: BTreeSet<u64>,
+}
+
+impl WriteTracker {
+    /// Number the write sent on `file` as request `tag`
+    fn issue(&mut self, file: usize, tag: u32) -> u64 {
+        let writes = self.files.entry(file).or_default();
+        writes.last += 1;
+        writes.pending.insert(writes.last);
+        self.tags.insert(tag, (file, writes.last));
+        writes.last
+    }
+
+    /// Note the answer to request `tag`, returning whether it was a write
+    fn answer(&mut self, tag: u32) -> bool {
+        let Some((file, seq)) = self.tags.remove(&tag) else {
+            return false;
+        };
+        if let Some(writes) = self.files.get_mut(&file) {
+            writes.pending.remove(&seq);
+        }
+        true
+    }
+
+    /// Number of the last write issued on `file`, which an fsync must wait for
+    fn barrier(&self, file: usize) -> u64 {
+        self.files.get(&file).map_or(0, |writes| writes.last)
+    }
+
+    /// Whether every write on `file` up to `barrier` has been answered
+    fn reached(&self, file: usize, barrier: u64) -> bool {
+        self.files
+            .get(&file)
+            .and_then(|writes| writes.pending.first())
+            .is_none_or(|&oldest| oldest > barrier)
+    }
+
+    /// Drop the numbering of a closed handle, whose ID the handler may reuse
+    fn forget(&mut self, file: usize) {
+        self.files.remove(&file);
+        self.tags.retain(|_, (tag_file, _)| *tag_file != file);
+    }
+}
+
 const ONE: NonZeroUsize = match NonZeroUsize::new(1) {
     Some(one) => one,
     None => unreachable!(),
@@ -124,10 +194,10 @@ impl ParsedCqe {
                     flags: EventFlags::from_bits_truncate(packet.c),
                 },
                 _ => {
+                    let name = context::current().read(token.token()).name.get();
                     warn!(
                         "Unknown scheme -> kernel message {} from {}",
-                        packet.a,
-                        context::current().read(token.token()).name
+                        packet.a, name
                     );
 
                     // Some schemes don't implement cancellation properly yet, so we temporarily
@@ -219,8 +289,11 @@ impl UserInner {
             scheme_id,
             context,
             todo: OptimizedWaitQueue::new(),
+            event_interest: AtomicUsize::new(EVENT_READ.bits()),
             unmounting: AtomicBool::new(false),
             states: Mutex::new(Slab::with_capacity(32)),
+            writes: Mutex::new(WriteTracker::default()),
+            writes_answered: WaitCondition::new(),
         }
     }
 
@@ -229,15 +302,35 @@ impl UserInner {
         self.unmounting.store(true, Ordering::SeqCst);
 
         // Wake up any blocked scheme handler
-        self.todo.wake_one();
+        self.todo.wake_one(token);
+
+        // Callers still waiting will never get a response, so let them fail with EIO
+        for (_, state) in self.states.lock().iter() {
+            if let State::Waiting { context, .. } = state {
+                if let Some(context) = context.upgrade() {
+                    scheduler::unblock(&context, token);
+                }
+            }
+        }
+
+        // Writes will not be answered anymore, so fsync callers fail with EIO
+        self.writes_answered.notify(token);
 
         // Tell the scheme handler to read
-        event::trigger(self.root_id, self.handle_id, EVENT_READ, token);
+        self.notify_handler(token);
 
         //TODO: wait for all todo and done to be processed?
         Ok(())
     }
 
+    /// Tell an event queue watching the scheme handler's root handle that requests are pending
+    fn notify_handler(&self, token: &mut CleanLockToken) {
+        let interest = EventFlags::from_bits_truncate(self.event_interest.load(Ordering::Acquire));
+        if interest.contains(EVENT_READ) {
+            event::trigger(self.root_id, self.handle_id, EVENT_READ, token);
+        }
+    }
+
     fn next_id(&self) -> Result<u32> {
         let idx = {
             let mut states = self.states.lock();
@@ -263,6 +356,70 @@ impl UserInner {
         }
     }
 
+    /// Forward a write on `file`, numbered so that a later fsync on it waits for the answer. The
+    /// flags in `args[4]` get the sequence number in their upper half.
+    fn call_write(
+        &self,
+        file: usize,
+        mut args: [u64; 5],
+        caller_responsible: &mut PageSpan,
+        token: &mut CleanLockToken,
+    ) -> Result<usize> {
+        let ctx = { context::current().read(token.token()).caller_ctx() };
+        let tag = self.next_id()?;
+        let seq = self.writes.lock().issue(file, tag);
+        args[4] |= (seq & 0xFFFF_FFFF) << 32;
+
+        let mut args = args.args();
+        args[5] = uid_gid_hack_merge([ctx.uid, ctx.gid]);
+        let sqe = Sqe {
+            opcode: Opcode::Write as u8,
+            sqe_flags: SqeFlags::empty(),
+            _rsvd: 0,
+            tag,
+            caller: ctx.pid as u64,
+            args,
+        };
+        let response = self.call_extended_inner(None, sqe, caller_responsible, token);
+        // The caller was killed or the handler is gone, either way no fsync should wait for it
+        if response.is_err() && self.writes.lock().answer(tag) {
+            self.writes_answered.notify(token);
+        }
+        match response? {
+            Response::Regular(code, _) => Error::demux(code),
+            Response::Fd(_) => Err(Error::new(EIO)),
+            Response::MultipleFds(_) => Err(Error::new(EIO)),
+        }
+    }
+
+    /// Forward an fsync on `file`, and hold back its answer until the writes issued before it
+    /// have been answered too. Fails with EIO if the handler goes away in the meantime.
+    fn call_fsync(&self, file: usize, token: &mut CleanLockToken) -> Result<()> {
+        let barrier = self.writes.lock().barrier(file);
+        self.call(
+            Opcode::Fsync,
+            [file as u64, barrier & 0xFFFF_FFFF],
+            &mut PageSpan::empty(),
+            token,
+        )?;
+
+        loop {
+            let writes = self.writes.lock();
+            if writes.reached(file, barrier) {
+                return Ok(());
+            }
+            if self.unmounting.load(Ordering::SeqCst) {
+                return Err(Error::new(EIO));
+            }
+            if !self
+                .writes_answered
+                .wait(writes, "UserScheme::fsync", token)
+            {
+                return Err(Error::new(EINTR));
+            }
+        }
+    }
+
     pub fn call_extended(
         &self,
         ctx: CallerCtx,
@@ -300,7 +457,7 @@ impl UserInner {
         token: &mut CleanLockToken,
     ) -> Result<Response> {
         if self.unmounting.load(Ordering::SeqCst) {
-            return Err(Error::new(ENODEV));
+            return Err(Error::new(EIO));
         }
 
         {
@@ -324,7 +481,7 @@ impl UserInner {
         }
         self.todo.send(sqe, token);
 
-        event::trigger(self.root_id, self.handle_id, EVENT_READ, token);
+        self.notify_handler(token);
 
         loop {
             unsafe { context::switch(token) };
@@ -347,6 +504,12 @@ impl UserInner {
                 };
 
                 let mut states = self.states.lock();
+                if self.unmounting.load(Ordering::SeqCst)
+                    && matches!(states.get(sqe.tag as usize), Some(State::Waiting { .. }))
+                {
+                    states.remove(sqe.tag as usize);
+                    return Err(Error::new(EIO));
+                }
                 match states.get_mut(sqe.tag as usize) {
                     // invalid state
                     None => return Err(Error::new(EBADFD)),
@@ -400,7 +563,7 @@ impl UserInner {
                                 },
                                 token,
                             );
-                            event::trigger(self.root_id, self.handle_id, EVENT_READ, token);
+                            self.notify_handler(token);
                             context::current()
                                 .write(token.token())
                                 .block("UserInner::call");
@@ -441,7 +604,7 @@ impl UserInner {
             Arc::clone(
                 self.context
                     .upgrade()
-                    .ok_or(Error::new(ENODEV))?
+                    .ok_or(Error::new(EIO))?
                     .read(token.token())
                     .addr_space()?,
             )
@@ -961,7 +1124,7 @@ impl UserInner {
             },
             token,
         );
-        event::trigger(self.root_id, self.handle_id, EVENT_READ, token);
+        self.notify_handler(token);
 
         Ok(())
     }
@@ -1011,15 +1174,14 @@ impl UserInner {
                             description,
                             cloexec: true,
                         },
-                    );
+                    )?;
                 } else {
                     let fd = context::current()
                         .read(token.token())
                         .add_file(FileDescriptor {
                             description,
                             cloexec: true,
-                        })
-                        .ok_or(Error::new(EMFILE))?;
+                        })?;
                     UserSlice::wo(dst_fd_or_ptr, size_of::<usize>())?.write_usize(fd.get())?;
                 }
             }
@@ -1077,15 +1239,22 @@ impl UserInner {
                     .translate(base_addr)
                     .ok_or(Error::new(EFAULT))?;
 
-                {
+                let awaiting = {
                     let mut context = context.write(token.token());
-                    if let Status::HardBlocked {
-                        reason: HardBlockedReason::AwaitingMmap { .. },
-                    } = context.status
-                    {
+                    let awaiting = matches!(
+                        context.status,
+                        Status::HardBlocked {
+                            reason: HardBlockedReason::AwaitingMmap { .. },
+                        }
+                    );
+                    if awaiting {
                         context.status = Status::Runnable
                     }
                     context.fmap_ret = Some(Frame::containing(frame));
+                    awaiting
+                };
+                if awaiting {
+                    scheduler::wake(context, token);
                 }
             }
             ParsedCqe::TriggerFevent { number, flags } => {
@@ -1096,6 +1265,7 @@ impl UserInner {
     }
     fn respond(&self, tag: u32, mut response: Response, token: &mut CleanLockToken) -> Result<()> {
         let to_close: Vec<FileDescription>;
+        let answered_write;
 
         {
             let mut states = self.states.lock();
@@ -1138,6 +1308,8 @@ impl UserInner {
                         if let Response::MultipleFds(ref mut response_fds) = response {
                             *response_fds = fds.take();
                         }
+                        // Noted before the tag can be freed and reused
+                        answered_write = self.writes.lock().answer(tag);
                         to_close = fds
                             .into_iter()
                             .flatten()
@@ -1147,7 +1319,7 @@ impl UserInner {
 
                         match context.upgrade() {
                             Some(context) => {
-                                context.write(token.token()).unblock();
+                                scheduler::unblock(&context, token);
                                 *o = State::Responded(response);
                             }
                             _ => {
@@ -1155,8 +1327,10 @@ impl UserInner {
                             }
                         }
 
-                        let unpin = true;
-                        AddrSpace::current(token)?.munmap(callee_responsible, unpin)?;
+                        if !callee_responsible.is_empty() {
+                            let unpin = true;
+                            AddrSpace::current(token)?.munmap(callee_responsible, unpin)?;
+                        }
                     }
                 },
                 // invalid state
@@ -1164,15 +1338,35 @@ impl UserInner {
             }
         }
 
+        if answered_write {
+            self.writes_answered.notify(token);
+        }
         for fd in to_close {
             let _ = fd.try_close(token);
         }
         Ok(())
     }
 
+    /// Wait for the next request as the handler would read it, for in-kernel mock handlers
+    #[cfg(any(feature = "kcall_test", feature = "selftest"))]
+    pub fn next_request(&self, token: &mut CleanLockToken) -> Result<Sqe> {
+        self.todo.receive(true, "UserInner::next_request", token)
+    }
+
+    /// Answer request `tag` with `result` as the handler would, for in-kernel mock handlers
+    #[cfg(any(feature = "kcall_test", feature = "selftest"))]
+    pub fn respond_regular(
+        &self,
+        tag: u32,
+        result: Result<usize>,
+        token: &mut CleanLockToken,
+    ) -> Result<()> {
+        self.respond(tag, Response::Regular(Error::mux(result), 0), token)
+    }
+
     pub fn fevent(&self, flags: EventFlags) -> Result<EventFlags> {
-        // TODO: Should the root scheme also suppress events if `flags` does not contain
-        // `EVENT_READ`?
+        self.event_interest.store(flags.bits(), Ordering::Release);
+
         Ok(if self.todo.is_currently_empty() {
             EventFlags::empty()
         } else {
@@ -1213,7 +1407,7 @@ impl UserInner {
             Arc::clone(
                 self.context
                     .upgrade()
-                    .ok_or(Error::new(ENODEV))?
+                    .ok_or(Error::new(EIO))?
                     .read(token.token())
                     .addr_space()?,
             )
@@ -1476,9 +1670,7 @@ impl UserInner {
                 cloexec: true,
             })
             .collect();
-        let handles = current
-            .bulk_add_files_posix(files)
-            .ok_or(Error::new(EMFILE))?;
+        let handles = current.bulk_add_files_posix(files)?;
         let payload_chunks = payload.in_exact_chunks(size_of::<usize>());
         for (handle, chunk) in handles.iter().zip(payload_chunks) {
             let bytes = (handle.get() as usize).to_ne_bytes();
@@ -1514,9 +1706,7 @@ impl UserInner {
 
         if first_fd == usize::MAX {
             let files = files_iter.collect::<Vec<_>>();
-            let handles = current
-                .bulk_insert_files_upper(files)
-                .ok_or(Error::new(EMFILE))?;
+            let handles = current.bulk_insert_files_upper(files)?;
             let payload_chunks = payload.in_exact_chunks(size_of::<usize>());
             for (handle, chunk) in handles.iter().zip(payload_chunks) {
                 let bytes = (handle.get() as usize).to_ne_bytes();
@@ -1605,6 +1795,26 @@ impl<const READ: bool, const WRITE: bool> Drop for CaptureGuard<READ, WRITE> {
         let _ = self.release_inner();
     }
 }
+/// Most metadata words a call can pass to a scheme handler
+///
+/// The call SQE has six argument words, the first three carrying the handle and payload.
+pub const MAX_CALL_METADATA: usize = 3;
+
+/// Call flags passed to the handler in the otherwise reserved field of the call SQE
+///
+/// The FD flags only apply to calls on the scheme socket itself and are consumed by the kernel.
+const CALL_FORWARDED_FLAGS: CallFlags = CallFlags::READ.union(CallFlags::WRITE);
+
+/// Argument words of a call SQE on `id`, with the payload base and length left for the caller
+fn call_args(id: usize, metadata: &[u64]) -> Result<[u64; 6]> {
+    if metadata.len() > MAX_CALL_METADATA {
+        return Err(Error::new(EINVAL));
+    }
+    let mut args = [id as u64, 0, 0, 0, 0, 0];
+    args[3..][..metadata.len()].copy_from_slice(metadata);
+    Ok(args)
+}
+
 /// base..base+size => page..page+page_count*PAGE_SIZE, offset
 fn page_range_containing(base: usize, size: usize) -> (Page, usize, usize) {
     let first_page = Page::containing_address(VirtualAddress::new(base));
@@ -1633,7 +1843,7 @@ impl KernelScheme for UserScheme {
         ctx: CallerCtx,
         token: &mut CleanLockToken,
     ) -> Result<OpenResult> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
         let mut address = inner.copy_and_capture_tail(path.as_bytes(), token)?;
         match inner.call_extended(
             ctx,
@@ -1664,7 +1874,7 @@ impl KernelScheme for UserScheme {
         ctx: CallerCtx,
         token: &mut CleanLockToken,
     ) -> Result<OpenResult> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
         let mut address = inner.copy_and_capture_tail(path.as_bytes(), token)?;
         let result = inner.call_extended(
             ctx,
@@ -1691,7 +1901,7 @@ impl KernelScheme for UserScheme {
     }
 
     fn rmdir(&self, path: &str, _ctx: CallerCtx, token: &mut CleanLockToken) -> Result<()> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
         let mut address = inner.copy_and_capture_tail(path.as_bytes(), token)?;
         inner.call(
             Opcode::Rmdir,
@@ -1703,7 +1913,7 @@ impl KernelScheme for UserScheme {
     }
 
     fn unlink(&self, path: &str, _ctx: CallerCtx, token: &mut CleanLockToken) -> Result<()> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
         let mut address = inner.copy_and_capture_tail(path.as_bytes(), token)?;
         inner.call(
             Opcode::Unlink,
@@ -1715,7 +1925,7 @@ impl KernelScheme for UserScheme {
     }
 
     fn fsize(&self, file: usize, token: &mut CleanLockToken) -> Result<u64> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
         if !inner.v2 {
             return Err(Error::new(ESPIPE));
         }
@@ -1725,7 +1935,7 @@ impl KernelScheme for UserScheme {
     }
 
     fn fchmod(&self, file: usize, mode: u16, token: &mut CleanLockToken) -> Result<()> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
         inner.call(
             Opcode::Fchmod,
             [file, mode as usize],
@@ -1744,7 +1954,7 @@ impl KernelScheme for UserScheme {
             }
         }
 
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
         inner.call(
             Opcode::Fchown,
             [file, uid as usize, gid as usize],
@@ -1761,7 +1971,7 @@ impl KernelScheme for UserScheme {
         arg: usize,
         token: &mut CleanLockToken,
     ) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
         inner.call(
             Opcode::Fcntl,
             [file, cmd, arg],
@@ -1776,7 +1986,7 @@ impl KernelScheme for UserScheme {
         flags: EventFlags,
         token: &mut CleanLockToken,
     ) -> Result<EventFlags> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
         inner
             .call(
                 Opcode::Fevent,
@@ -1794,7 +2004,7 @@ impl KernelScheme for UserScheme {
         _ctx: CallerCtx,
         token: &mut CleanLockToken,
     ) -> Result<()> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
         let mut address = inner.copy_and_capture_tail(path.as_bytes(), token)?;
         inner.call(
             Opcode::Flink,
@@ -1812,7 +2022,7 @@ impl KernelScheme for UserScheme {
         _ctx: CallerCtx,
         token: &mut CleanLockToken,
     ) -> Result<()> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
         let mut address = inner.copy_and_capture_tail(path.as_bytes(), token)?;
         inner.call(
             Opcode::Frename,
@@ -1824,13 +2034,12 @@ impl KernelScheme for UserScheme {
     }
 
     fn fsync(&self, file: usize, token: &mut CleanLockToken) -> Result<()> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
-        inner.call(Opcode::Fsync, [file], &mut PageSpan::empty(), token)?;
-        Ok(())
+        let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
+        inner.call_fsync(file, token)
     }
 
     fn ftruncate(&self, file: usize, len: usize, token: &mut CleanLockToken) -> Result<()> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
         inner.call(
             Opcode::Ftruncate,
             [file, len],
@@ -1841,9 +2050,10 @@ impl KernelScheme for UserScheme {
     }
 
     fn close(&self, id: usize, token: &mut CleanLockToken) -> Result<()> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
+        inner.writes.lock().forget(id);
         if !inner.supports_on_close {
-            let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+            let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
             inner.call(Opcode::Close, [id], &mut PageSpan::empty(), token)?;
             return Ok(());
         }
@@ -1860,7 +2070,7 @@ impl KernelScheme for UserScheme {
             token,
         );
 
-        event::trigger(inner.root_id, inner.handle_id, EVENT_READ, token);
+        inner.notify_handler(token);
 
         Ok(())
     }
@@ -1871,7 +2081,7 @@ impl KernelScheme for UserScheme {
         ctx: CallerCtx,
         token: &mut CleanLockToken,
     ) -> Result<OpenResult> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
         let mut address = inner.capture_user(buf, token)?;
         let result = inner.call_extended(
             ctx,
@@ -1897,7 +2107,7 @@ impl KernelScheme for UserScheme {
         }
     }
     fn kfpath(&self, file: usize, buf: UserSliceWo, token: &mut CleanLockToken) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
         let mut address = inner.capture_user(buf, token)?;
         let result = inner.call(
             Opcode::Fpath,
@@ -1918,7 +2128,7 @@ impl KernelScheme for UserScheme {
         stored_flags: u32,
         token: &mut CleanLockToken,
     ) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
 
         if call_flags != stored_flags && !inner.v2 {
             self.fcntl(file, F_SETFL, call_flags as usize, token)?;
@@ -1955,14 +2165,14 @@ impl KernelScheme for UserScheme {
         stored_flags: u32,
         token: &mut CleanLockToken,
     ) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
         if call_flags != stored_flags && !inner.v2 {
             self.fcntl(file, F_SETFL, call_flags as usize, token)?;
         }
 
         let mut address = inner.capture_user(buf, token)?;
-        let result = inner.call(
-            Opcode::Write,
+        let result = inner.call_write(
+            file,
             [
                 file as u64,
                 address.base() as u64,
@@ -2005,7 +2215,7 @@ impl KernelScheme for UserScheme {
         buf: UserSliceRo,
         token: &mut CleanLockToken,
     ) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
         let mut address = inner.capture_user(buf, token)?;
         let result = inner.call(
             Opcode::Futimens,
@@ -2024,7 +2234,7 @@ impl KernelScheme for UserScheme {
         opaque_id_start: u64,
         token: &mut CleanLockToken,
     ) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
         let mut address = inner.capture_user(buf, token)?;
         // TODO: Support passing the 16-byte record_len of the last dent, to make it possible to
         // iterate backwards without first interating forward? The last entry will contain the
@@ -2046,7 +2256,7 @@ impl KernelScheme for UserScheme {
         result
     }
     fn kfstat(&self, file: usize, stat: UserSliceWo, token: &mut CleanLockToken) -> Result<()> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
         let mut address = inner.capture_user(stat, token)?;
         let result = inner.call(
             Opcode::Fstat,
@@ -2058,7 +2268,7 @@ impl KernelScheme for UserScheme {
         result.map(|_| ())
     }
     fn kfstatvfs(&self, file: usize, stat: UserSliceWo, token: &mut CleanLockToken) -> Result<()> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
         let mut address = inner.capture_user(stat, token)?;
         let result = inner.call(
             Opcode::Fstatvfs,
@@ -2077,7 +2287,7 @@ impl KernelScheme for UserScheme {
         _consume: bool,
         token: &mut CleanLockToken,
     ) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
 
         inner.fmap_inner(Arc::clone(addr_space), file, map, token)
     }
@@ -2089,9 +2299,35 @@ impl KernelScheme for UserScheme {
         flags: MunmapFlags,
         token: &mut CleanLockToken,
     ) -> Result<()> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
 
         let ctx = { context::current().read(token.token()).caller_ctx() };
+
+        // Without NEEDS_SYNC the whole address space is going away, so the provider only needs to
+        // hear about it, and an exiting process must not wait on it
+        if !flags.contains(MunmapFlags::NEEDS_SYNC) {
+            inner.todo.send(
+                Sqe {
+                    opcode: Opcode::Munmap as u8,
+                    sqe_flags: SqeFlags::ONEWAY,
+                    _rsvd: 0,
+                    tag: 0,
+                    args: [
+                        number as u64,
+                        size as u64,
+                        flags.bits() as u64,
+                        offset as u64,
+                        0,
+                        uid_gid_hack_merge([ctx.uid, ctx.gid]),
+                    ],
+                    caller: ctx.pid as u64,
+                },
+                token,
+            );
+            inner.notify_handler(token);
+            return Ok(());
+        }
+
         let res = inner.call_extended(
             ctx,
             None,
@@ -2111,39 +2347,43 @@ impl KernelScheme for UserScheme {
         &self,
         id: usize,
         payload: UserSliceRw,
-        _flags: CallFlags,
+        flags: CallFlags,
         metadata: &[u64],
         token: &mut CleanLockToken,
     ) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
+
+        // Legacy packets have no room for the call, the handler would fail to read the request
+        if !inner.v2 {
+            return Err(Error::new(EOPNOTSUPP));
+        }
+        let mut args = call_args(id, metadata)?;
+        if inner.unmounting.load(Ordering::SeqCst) {
+            return Err(Error::new(EIO));
+        }
 
         let mut address = inner.capture_user(payload, token)?;
+        args[1] = address.base() as u64;
+        args[2] = address.len() as u64;
         let ctx = { context::current().read(token.token()).caller_ctx() };
 
-        let mut sqe = Sqe {
+        let sqe = Sqe {
             opcode: Opcode::Call as u8,
             sqe_flags: SqeFlags::empty(),
-            _rsvd: 0,
+            _rsvd: (flags & CALL_FORWARDED_FLAGS).bits() as u16,
             tag: inner.next_id()?,
             caller: ctx.pid as u64,
-            args: [
-                id as u64,
-                address.base() as u64,
-                address.len() as u64,
-                0,
-                0,
-                0,
-            ],
+            args,
         };
-        {
-            let dst = &mut sqe.args[3..];
-            let len = dst.len().min(metadata.len());
-            dst[..len].copy_from_slice(&metadata[..len]);
-        }
+        // Fails with EIO if the handler went away with the call in flight
         let res = inner.call_extended_inner(None, sqe, address.span(), token)?;
 
         match res {
-            Response::Regular(res, _) => Error::demux(res),
+            Response::Regular(res, _) => {
+                // Copy what the handler wrote to the unaligned head and tail back to the caller
+                address.release()?;
+                Error::demux(res)
+            }
             Response::Fd(_) => Err(Error::new(EIO)),
             Response::MultipleFds(_) => Err(Error::new(EIO)),
         }
@@ -2157,7 +2397,7 @@ impl KernelScheme for UserScheme {
         _metadata: &[u64],
         token: &mut CleanLockToken,
     ) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
 
         let mut sendfd_flags = SendFdFlags::empty();
         if flags.contains(CallFlags::FD_EXCLUSIVE) {
@@ -2189,7 +2429,7 @@ impl KernelScheme for UserScheme {
         _metadata: &[u64],
         token: &mut CleanLockToken,
     ) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.inner.upgrade().ok_or(Error::new(EIO))?;
         if payload.len() % mem::size_of::<usize>() != 0 {
             return Err(Error::new(EINVAL));
         }
@@ -2261,3 +2501,221 @@ fn current_uid_gid(token: &mut CleanLockToken) -> [u32; 2] {
     let p = &ctx.read(token.token());
     [p.euid, p.egid]
 }
+
+#[cfg(feature = "selftest")]
+pub mod selftests {
+    use alloc::{format, string::String};
+
+    use super::*;
+    use crate::{
+        context::{kthread, ContextRef},
+        cpu_set::{LogicalCpuId, LogicalCpuSet},
+        selftest::{self, check, check_eq, SelftestResult},
+        sync::Priority,
+        time,
+    };
+
+    /// Handle the requests are made on
+    const FILE: usize = 3;
+    /// How long the fsync caller is given to return early, which it must not
+    const HOLD_NS: u128 = 5_000_000;
+
+    /// The scheme the callers make their requests on, while the test runs
+    static INNER: spin::Mutex<Option<Arc<UserInner>>> = spin::Mutex::new(None);
+    /// What each caller's request returned, once it is done
+    static RESULTS: spin::Mutex<[Option<Result<usize>>; 5]> = spin::Mutex::new([const { None }; 5]);
+    /// The writer that is killed with its write unanswered
+    static VICTIM: spin::Mutex<Option<ContextRef>> = spin::Mutex::new(None);
+
+    /// Make a request on the test scheme, storing what it returned in `slot` of [`RESULTS`]
+    fn call(slot: usize, request: fn(&UserInner, &mut CleanLockToken) -> Result<usize>) {
+        let mut token = unsafe { CleanLockToken::new() };
+        let inner = INNER.lock().clone();
+        let result = match inner {
+            Some(inner) => request(&inner, &mut token),
+            None => Err(Error::new(ENODEV)),
+        };
+        if let Some(result_slot) = RESULTS.lock().get_mut(slot) {
+            *result_slot = Some(result);
+        }
+    }
+
+    fn write(inner: &UserInner, token: &mut CleanLockToken) -> Result<usize> {
+        let args = [FILE as u64, 0, 0, 0, 0];
+        inner.call_write(FILE, args, &mut PageSpan::empty(), token)
+    }
+
+    fn fsync(inner: &UserInner, token: &mut CleanLockToken) -> Result<usize> {
+        inner.call_fsync(FILE, token).map(|()| 0)
+    }
+
+    fn spawn(name: &str, entry: fn(), token: &mut CleanLockToken) -> SelftestResult {
+        let mut bsp = LogicalCpuSet::new();
+        bsp.add(LogicalCpuId::BSP);
+        kthread::spawn(name, bsp, Priority::Normal, entry, token)
+            .map_err(|err| format!("failed to spawn {}: {}", name, err))?
+            .detach(token);
+        Ok(())
+    }
+
+    /// Take the next request the callers made, as the handler would
+    fn next_request(inner: &UserInner, token: &mut CleanLockToken) -> Result<Sqe, String> {
+        inner
+            .next_request(token)
+            .map_err(|err| format!("failed to receive a request: {}", err))
+    }
+
+    fn respond(
+        inner: &UserInner,
+        sqe: &Sqe,
+        result: Result<usize>,
+        token: &mut CleanLockToken,
+    ) -> SelftestResult {
+        inner
+            .respond_regular(sqe.tag, result, token)
+            .map_err(|err| format!("failed to respond to {}: {}", sqe.tag, err))
+    }
+
+    /// A mock handler answers an fsync before the two writes queued ahead of it, and the fsync
+    /// caller only returns once they are answered too. A write whose caller is killed before its
+    /// answer then no longer holds back the next fsync.
+    pub fn fsync_waits_for_earlier_writes(token: &mut CleanLockToken) -> SelftestResult {
+        let inner = Arc::new(UserInner::new(
+            SchemeId::new(usize::MAX),
+            SchemeId::new(usize::MAX),
+            true,
+            true,
+            0,
+            "selftest".into(),
+            0,
+            Weak::new(),
+        ));
+        *INNER.lock() = Some(Arc::clone(&inner));
+        *RESULTS.lock() = [const { None }; 5];
+        *VICTIM.lock() = None;
+
+        let result = mock_handler(&inner, token);
+
+        // Callers left waiting by a failed check fail with EIO rather than hang
+        let _ = inner.unmount(token);
+        *INNER.lock() = None;
+        result
+    }
+
+    fn mock_handler(inner: &UserInner, token: &mut CleanLockToken) -> SelftestResult {
+        spawn("[selftest_write0]", || call(0, write), token)?;
+        spawn("[selftest_write1]", || call(1, write), token)?;
+        check!(selftest::switch_until(|| inner.todo.len() == 2, token));
+        spawn("[selftest_fsync]", || call(2, fsync), token)?;
+        check!(selftest::switch_until(|| inner.todo.len() == 3, token));
+
+        let first = next_request(inner, token)?;
+        let second = next_request(inner, token)?;
+        let fsync = next_request(inner, token)?;
+        check_eq!(first.opcode, Opcode::Write as u8);
+        check_eq!(second.opcode, Opcode::Write as u8);
+        check_eq!(fsync.opcode, Opcode::Fsync as u8);
+        // The fsync names the last write issued before it
+        check_eq!(fsync.args[1], 2);
+
+        respond(inner, &fsync, Ok(0), token)?;
+        let hold = time::monotonic().saturating_add(HOLD_NS);
+        selftest::switch_until(|| time::monotonic() >= hold, token);
+        check!(RESULTS.lock()[2].is_none());
+
+        respond(inner, &second, Ok(1), token)?;
+        respond(inner, &first, Ok(1), token)?;
+        check!(selftest::switch_until(
+            || RESULTS.lock().iter().take(3).all(Option::is_some),
+            token
+        ));
+        check_eq!(RESULTS.lock()[..3], [Some(Ok(1)), Some(Ok(1)), Some(Ok(0))]);
+
+        spawn(
+            "[selftest_victim]",
+            || {
+                *VICTIM.lock() = Some(context::current());
+                call(3, write);
+            },
+            token,
+        )?;
+        check!(selftest::switch_until(|| inner.todo.len() == 1, token));
+        let unanswered = next_request(inner, token)?;
+        let victim = VICTIM.lock().take().ok_or("the victim did not run")?;
+        victim.write(token.token()).being_sigkilled = true;
+        scheduler::unblock(&victim, token);
+        check!(selftest::switch_until(
+            || RESULTS.lock()[3].is_some(),
+            token
+        ));
+        check_eq!(RESULTS.lock()[3], Some(Err(Error::new(EINTR))));
+
+        spawn("[selftest_fsync2]", || call(4, fsync), token)?;
+        check!(selftest::switch_until(|| inner.todo.len() == 1, token));
+        let fsync = next_request(inner, token)?;
+        respond(inner, &fsync, Ok(0), token)?;
+        check!(selftest::switch_until(
+            || RESULTS.lock()[4].is_some(),
+            token
+        ));
+        check_eq!(RESULTS.lock()[4], Some(Ok(0)));
+
+        // The handler answers the dead caller's write eventually
+        respond(inner, &unanswered, Ok(1), token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_call_args() {
+        assert_eq!(call_args(7, &[]), Ok([7, 0, 0, 0, 0, 0]));
+        assert_eq!(call_args(7, &[1, 2]), Ok([7, 0, 0, 1, 2, 0]));
+        assert_eq!(call_args(7, &[1, 2, 3]), Ok([7, 0, 0, 1, 2, 3]));
+    }
+
+    #[test]
+    fn test_call_args_too_much_metadata() {
+        assert_eq!(call_args(7, &[1, 2, 3, 4]), Err(Error::new(EINVAL)));
+    }
+
+    #[test]
+    fn test_call_forwarded_flags() {
+        let flags = CallFlags::READ | CallFlags::FD | CallFlags::FD_EXCLUSIVE | CallFlags::CONSUME;
+        assert_eq!(flags & CALL_FORWARDED_FLAGS, CallFlags::READ);
+    }
+
+    #[test]
+    fn test_fsync_held_back_until_writes_answered() {
+        let mut writes = WriteTracker::default();
+        assert_eq!(writes.barrier(3), 0);
+        assert!(writes.reached(3, 0));
+
+        // Two writes are queued, then an fsync, which the mock handler answers first
+        assert_eq!(writes.issue(3, 10), 1);
+        assert_eq!(writes.issue(3, 11), 2);
+        let barrier = writes.barrier(3);
+        assert_eq!(barrier, 2);
+        assert!(!writes.reached(3, barrier));
+
+        // A write issued after the fsync, and writes on other handles, do not hold it back
+        writes.issue(3, 12);
+        writes.issue(4, 13);
+        assert!(writes.answer(11));
+        assert!(!writes.reached(3, barrier));
+        assert!(writes.answer(10));
+        assert!(writes.reached(3, barrier));
+        assert!(!writes.reached(3, writes.barrier(3)));
+
+        // Answers to other requests are not writes
+        assert!(!writes.answer(10));
+        assert!(!writes.answer(99));
+
+        writes.forget(3);
+        assert!(!writes.answer(12));
+        assert_eq!(writes.barrier(3), 0);
+        assert!(!writes.reached(4, 1));
+    }
+}
@@ -0,0 +1,287 @@
+//! # In-kernel Self-tests
+//!
+//! Tests that need the real target, run by kmain when the kernel is built with the `selftest`
+//! feature and booted with `selftest=1`, or with `selftest=halt` to stop the BSP once they are
+//! done instead of going on to userspace. Each test is logged as it passes or fails, followed by
+//! a summary.
+//!
+//! A test is a function returning [`SelftestResult`], using [`check!`] and [`check_eq!`] in place
+//! of `assert!`, so that one failing test does not stop the others. Tests of logic private to a
+//! module live in a `selftests` module next to it, and are listed in [`selftests!`] below.
+
+use alloc::{format, string::String, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    arch::interrupt,
+    context::{
+        self, kthread,
+        memory::{page_flags, AddrSpaceWrapper, PageSpan},
+    },
+    cpu_set::{LogicalCpuId, LogicalCpuSet},
+    memory::{self, Frame, RefCount},
+    paging::{RmmA, RmmArch, PAGE_SIZE},
+    sync::{CleanLockToken, Priority, WaitCondition},
+    syscall::flag::MapFlags,
+};
+
+/// The outcome of a self-test, with what went wrong if it failed
+pub type SelftestResult = Result<(), String>;
+
+type Selftest = fn(&mut CleanLockToken) -> SelftestResult;
+
+/// Fail the test if `$cond` does not hold
+macro_rules! check {
+    ($cond:expr) => {
+        if !$cond {
+            return Err(alloc::format!(
+                "{}:{}: {}",
+                file!(),
+                line!(),
+                stringify!($cond)
+            ));
+        }
+    };
+}
+
+/// Fail the test if `$left` and `$right` differ
+macro_rules! check_eq {
+    ($left:expr, $right:expr) => {
+        match (&$left, &$right) {
+            (left, right) => {
+                if left != right {
+                    return Err(alloc::format!(
+                        "{}:{}: {:?} != {:?}",
+                        file!(),
+                        line!(),
+                        left,
+                        right
+                    ));
+                }
+            }
+        }
+    };
+}
+
+pub(crate) use check;
+pub(crate) use check_eq;
+
+macro_rules! selftests(
+    ($($test:path),* $(,)?) => {
+        /// Every self-test, with its name
+        static SELFTESTS: &[(&str, Selftest)] = &[$((stringify!($test), $test)),*];
+    }
+);
+
+selftests!(
+    crate::scheduler::selftests::run_queue_order,
+    crate::memory::selftests::refcount_from_raw,
+    crate::memory::selftests::p2frame_encoding,
+    crate::memory::selftests::page_info_transitions,
+    crate::context::memory::selftests::mem_pattern_round_trip,
+    crate::context::reap::selftests::waitpid_reports_exit_code,
+    crate::deferred::selftests::ring_index_wraparound,
+    crate::scheme::memory::selftests::ftruncate_unmaps_tail,
+    crate::event::selftests::pipe_edge_and_oneshot,
+    crate::event::selftests::pipe_write_wakes_blocked_reader,
+    crate::scheme::user::selftests::fsync_waits_for_earlier_writes,
+    crate::syscall::personality::selftests::linux_write_round_trip,
+    crate::syscall::time::selftests::sleep_wakes_at_deadline,
+    crate::syscall::usercopy::selftests::unmapped_copies_fault,
+    mixed_order_frames,
+    wait_condition_ping_pong,
+);
+
+/// Run the self-tests if the `selftest` boot parameter asks for it, and halt afterwards if it is
+/// `halt`
+pub fn run_from_params(token: &mut CleanLockToken) {
+    let halt = crate::startup::params::param_str("selftest") == Some("halt");
+    if !halt && crate::startup::params::param_bool("selftest") != Some(true) {
+        return;
+    }
+
+    let failed = run(token);
+    if !halt {
+        return;
+    }
+    println!("SELFTEST: halting, {} failed", failed);
+    loop {
+        unsafe {
+            interrupt::disable();
+            interrupt::halt();
+        }
+    }
+}
+
+/// Run every self-test, and return how many failed
+fn run(token: &mut CleanLockToken) -> usize {
+    println!("SELFTEST: running {} tests", SELFTESTS.len());
+    let mut failed = 0_usize;
+    for (name, test) in SELFTESTS {
+        let name = name.strip_prefix("crate::").unwrap_or(name);
+        match test(token) {
+            Ok(()) => println!("SELFTEST: {} ... ok", name),
+            Err(err) => {
+                println!("SELFTEST: {} ... FAILED: {}", name, err);
+                failed = failed.saturating_add(1);
+            }
+        }
+    }
+    println!(
+        "SELFTEST: {} passed, {} failed",
+        SELFTESTS.len().saturating_sub(failed),
+        failed
+    );
+    failed
+}
+
+/// Let the kernel threads a test spawned on the BSP run until `done` holds, which kmain has to
+/// switch away from itself for. Gives up after 10 seconds, returning false.
+pub fn switch_until(done: impl Fn() -> bool, token: &mut CleanLockToken) -> bool {
+    const TIMEOUT_NS: u128 = 10_000_000_000;
+
+    let deadline = crate::time::monotonic().saturating_add(TIMEOUT_NS);
+    while !done() {
+        if crate::time::monotonic() > deadline {
+            return false;
+        }
+        unsafe {
+            interrupt::disable();
+            let _ = context::switch(token);
+            interrupt::enable_and_nop();
+        }
+    }
+    true
+}
+
+/// Run `test` with a zeroed, writable page of user memory at the address it is passed, in an
+/// address space made current for the duration, so that it can make calls taking user slices
+pub fn with_user_page(
+    test: impl FnOnce(usize, &mut CleanLockToken) -> SelftestResult,
+    token: &mut CleanLockToken,
+) -> SelftestResult {
+    let addr_space = AddrSpaceWrapper::new().map_err(|err| format!("no address space: {}", err))?;
+    let frame = memory::init_frame(RefCount::One).map_err(|err| format!("no frame: {:?}", err))?;
+    unsafe {
+        let virt = RmmA::phys_to_virt(frame.base()).data() as *mut u8;
+        core::ptr::write_bytes(virt, 0, PAGE_SIZE);
+    }
+
+    let flags = MapFlags::PROT_READ | MapFlags::PROT_WRITE;
+    let mapped = {
+        let mut space = addr_space.acquire_write();
+        space.mmap_shared_frames(None, &[frame], flags, page_flags(flags), &mut Vec::new())
+    };
+    let result = match mapped {
+        Ok(page) => {
+            let current = context::current();
+            let old = current
+                .write(token.token())
+                .set_addr_space(Some(addr_space.clone()));
+            let result = test(page.start_address().data(), token);
+            current.write(token.token()).set_addr_space(old);
+
+            let _ = addr_space.munmap(PageSpan::new(page, 1), false);
+            result
+        }
+        Err(err) => Err(format!("failed to map the page: {}", err)),
+    };
+
+    // Unmapping drops the reference the mapping took, leaving the one taken above
+    if memory::get_page_info(frame).is_some_and(|info| info.remove_ref().is_none()) {
+        unsafe { memory::deallocate_frame(frame) };
+    }
+    result
+}
+
+/// Allocate and free 1000 blocks of mixed orders in a scrambled order, checking the buddy
+/// freelists along the way. The checker panics on a corrupted freelist.
+fn mixed_order_frames(_token: &mut CleanLockToken) -> SelftestResult {
+    const BLOCKS: usize = 1000;
+    const MAX_LIVE: usize = 32;
+
+    // xorshift, only to scramble the orders and the order of frees
+    let mut state = 0x2545_f491_u32;
+    let mut random = move || {
+        state ^= state.wrapping_shl(13);
+        state ^= state.wrapping_shr(17);
+        state ^= state.wrapping_shl(5);
+        state
+    };
+
+    let mut live: Vec<(Frame, u32)> = Vec::with_capacity(MAX_LIVE);
+    let mut result = Ok(());
+    for i in 0..BLOCKS {
+        let order = random() % 5;
+        let Some(frame) = memory::allocate_p2frame(order) else {
+            result = Err(format!("allocation {} of order {} failed", i, order));
+            break;
+        };
+        live.push((frame, order));
+        if !frame.is_aligned_to_order(order) {
+            result = Err(format!("{:?} is not aligned to order {}", frame, order));
+            break;
+        }
+
+        if live.len() == MAX_LIVE {
+            let (frame, order) = live.swap_remove(random() as usize % MAX_LIVE);
+            unsafe { memory::deallocate_p2frame(frame, order) };
+        }
+        if i % 100 == 0 {
+            memory::check_freelist_integrity();
+        }
+    }
+
+    for (frame, order) in live {
+        unsafe { memory::deallocate_p2frame(frame, order) };
+    }
+    memory::check_freelist_integrity();
+    result
+}
+
+const PING_PONG_ROUNDS: usize = 1000;
+/// Exchanges so far, the ping side goes on even turns and the pong side on odd ones
+static TURN: spin::Mutex<usize> = spin::Mutex::new(0);
+static TURN_CHANGED: WaitCondition = WaitCondition::new();
+/// Sides done with all their rounds
+static FINISHED: AtomicUsize = AtomicUsize::new(0);
+
+fn play(side: usize) {
+    let mut token = unsafe { CleanLockToken::new() };
+    for _ in 0..PING_PONG_ROUNDS {
+        let mut turn = TURN.lock();
+        while *turn % 2 != side {
+            TURN_CHANGED.wait(turn, "selftest::ping_pong", &mut token);
+            turn = TURN.lock();
+        }
+        *turn = turn.saturating_add(1);
+        drop(turn);
+        TURN_CHANGED.notify(&mut token);
+    }
+    FINISHED.fetch_add(1, Ordering::Release);
+}
+
+/// Two kernel threads on the BSP take turns through a [`WaitCondition`], so that every turn
+/// blocks one and wakes the other
+fn wait_condition_ping_pong(token: &mut CleanLockToken) -> SelftestResult {
+    *TURN.lock() = 0;
+    FINISHED.store(0, Ordering::Relaxed);
+
+    let mut bsp = LogicalCpuSet::new();
+    bsp.add(LogicalCpuId::BSP);
+    let sides: [(&str, fn()); 2] = [
+        ("[selftest_ping]", || play(0)),
+        ("[selftest_pong]", || play(1)),
+    ];
+    for (name, entry) in sides {
+        kthread::spawn(name, bsp, Priority::Normal, entry, token)
+            .map_err(|err| format!("failed to spawn {}: {}", name, err))?
+            .detach(token);
+    }
+
+    if !switch_until(|| FINISHED.load(Ordering::Acquire) == 2, token) {
+        return Err(format!("timed out after {} turns", *TURN.lock()));
+    }
+    check_eq!(*TURN.lock(), 2 * PING_PONG_ROUNDS);
+    Ok(())
+}
@@ -1,6 +1,7 @@
 use core::slice;
 
 pub mod memory;
+pub mod params;
 
 #[repr(C, packed(8))]
 pub(crate) struct KernelArgs {
@@ -0,0 +1,174 @@
+//! # Boot Parameters
+//!
+//! Kernel options given in the bootstrap environment, one `key=value` per line as the bootloader
+//! passes it. A key without `=` is a flag. Keys with upper case letters are environment variables
+//! for userspace, which reads them through sys:env, and are left alone.
+//!
+//! The environment is parsed once, by the x86 start code as soon as logging works, as `nocet` is
+//! needed before kmain, and by kmain elsewhere. Values borrow from it, so nothing is allocated.
+//! Malformed lines are reported and skipped, and a key given more than once takes its last value.
+
+use core::str;
+use spin::Once;
+
+/// Boot parameters the kernel understands
+const KNOWN: [&str; 7] = [
+    "loglevel",
+    "nocet",
+    "nowakeboost",
+    "nowatchdog",
+    "profile_hz",
+    "selftest",
+    "watchdog_thresh",
+];
+
+static PARAMS: Once<Params> = Once::new();
+
+#[derive(Debug, Default)]
+struct Params {
+    /// The value of each parameter in [`KNOWN`], `Some(None)` if it was given without one
+    values: [Option<Option<&'static str>>; KNOWN.len()],
+}
+
+impl Params {
+    fn parse(env: &'static [u8]) -> Self {
+        let mut params = Params::default();
+        for (key, value) in entries(env).flatten() {
+            if let Some(slot) = index(key).and_then(|i| params.values.get_mut(i)) {
+                *slot = Some(value);
+            }
+        }
+        params
+    }
+
+    fn get(&self, key: &str) -> Option<Option<&'static str>> {
+        self.values.get(index(key)?).copied().flatten()
+    }
+}
+
+fn index(key: &str) -> Option<usize> {
+    KNOWN.iter().position(|known| *known == key)
+}
+
+/// Split the environment into its entries, a key and the value if there is one. Lines that are
+/// not UTF-8 or have no key are returned as errors.
+fn entries(env: &[u8]) -> impl Iterator<Item = Result<(&str, Option<&str>), &[u8]>> {
+    env.split(|&b| b == b'\n')
+        .map(<[u8]>::trim_ascii)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let entry = str::from_utf8(line).map_err(|_| line)?;
+            let (key, value) = match entry.split_once('=') {
+                Some((key, value)) => (key.trim(), Some(value.trim())),
+                None => (entry, None),
+            };
+            if key.is_empty() {
+                return Err(line);
+            }
+            Ok((key, value))
+        })
+}
+
+/// Whether `key` is an environment variable for userspace rather than a boot parameter
+fn is_env_var(key: &str) -> bool {
+    key.bytes().any(|b| b.is_ascii_uppercase())
+}
+
+fn parse_bool(value: Option<&str>) -> Option<bool> {
+    match value {
+        None | Some("1" | "true" | "yes" | "on") => Some(true),
+        Some("0" | "false" | "no" | "off") => Some(false),
+        Some(_) => None,
+    }
+}
+
+fn parse_u64(value: Option<&str>) -> Option<u64> {
+    value?.parse().ok()
+}
+
+/// Parse the boot parameters out of the bootstrap environment, reporting lines the kernel does
+/// not understand
+pub fn init(env: &'static [u8]) {
+    PARAMS.call_once(|| {
+        for entry in entries(env) {
+            match entry {
+                Ok((key, _)) if index(key).is_none() && !is_env_var(key) => {
+                    warn!("Unknown boot parameter {:?}", key);
+                }
+                Err(line) => warn!("Malformed boot parameter \"{}\"", line.escape_ascii()),
+                Ok(_) => {}
+            }
+        }
+        Params::parse(env)
+    });
+}
+
+/// Whether flag `key` is set. A flag given with a value must be one of 1, 0, true, false, yes,
+/// no, on or off. Returns `None` if it was not given, or before [`init`].
+pub fn param_bool(key: &str) -> Option<bool> {
+    let value = PARAMS.get()?.get(key)?;
+    let parsed = parse_bool(value);
+    if parsed.is_none() {
+        warn!("Boot parameter {} is not a flag: {:?}", key, value);
+    }
+    parsed
+}
+
+/// The number given as parameter `key`. Returns `None` if it was not given or is not a number,
+/// or before [`init`].
+pub fn param_u64(key: &str) -> Option<u64> {
+    let value = PARAMS.get()?.get(key)?;
+    let parsed = parse_u64(value);
+    if parsed.is_none() {
+        warn!("Boot parameter {} is not a number: {:?}", key, value);
+    }
+    parsed
+}
+
+/// The string given as parameter `key`. Returns `None` if it was not given or has no value, or
+/// before [`init`].
+pub fn param_str(key: &str) -> Option<&'static str> {
+    PARAMS.get()?.get(key)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_params_last_duplicate_wins() {
+        let params = Params::parse(b"profile_hz=100\nloglevel=debug\nprofile_hz=250\n");
+        assert_eq!(params.get("profile_hz"), Some(Some("250")));
+        assert_eq!(params.get("loglevel"), Some(Some("debug")));
+        assert_eq!(params.get("nocet"), None);
+    }
+
+    #[test]
+    fn test_params_missing_values() {
+        let params = Params::parse(b"nocet\nprofile_hz=\nloglevel");
+        assert_eq!(params.get("nocet"), Some(None));
+        assert_eq!(parse_bool(params.get("nocet").flatten()), Some(true));
+        assert_eq!(params.get("profile_hz"), Some(Some("")));
+        assert_eq!(parse_u64(params.get("profile_hz").flatten()), None);
+        assert_eq!(params.get("loglevel"), Some(None));
+    }
+
+    #[test]
+    fn test_params_malformed_lines_skipped() {
+        let env = b"=1\n\xFF\xFEnocet\r\n\n  profile_hz = 1000 \r\nREDOXFS_UUID=abc\nbogus=1";
+        let params = Params::parse(env);
+        assert_eq!(params.get("nocet"), None);
+        assert_eq!(params.get("profile_hz"), Some(Some("1000")));
+
+        let errors = entries(env).filter(Result::is_err).count();
+        assert_eq!(errors, 2);
+    }
+
+    #[test]
+    fn test_params_bool_values() {
+        assert_eq!(parse_bool(Some("off")), Some(false));
+        assert_eq!(parse_bool(Some("1")), Some(true));
+        assert_eq!(parse_bool(Some("maybe")), None);
+        assert_eq!(parse_u64(Some("0x10")), None);
+    }
+}
@@ -0,0 +1,82 @@
+//! Spinlocks for data shared between threads and interrupt handlers of the same CPU.
+//!
+//! An interrupt arriving while its CPU holds an ordinary spinlock deadlocks if the handler takes
+//! the same lock, and a handler that reaches the data some other way than through the lock
+//! races with the interrupted code. An [`IrqMutex`] keeps interrupts disabled while it is held,
+//! so neither can happen.
+
+use core::{
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+};
+use spin::{Mutex as SpinMutex, MutexGuard as SpinMutexGuard};
+
+use crate::arch::interrupt;
+
+/// A spinlock that disables interrupts on its CPU while held
+pub struct IrqMutex<T: ?Sized> {
+    inner: SpinMutex<T>,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: SpinMutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> IrqMutex<T> {
+    /// Disable interrupts and take the lock. Interrupts are enabled again once the guard is
+    /// dropped, if they were enabled before.
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let restore = interrupt::are_enabled();
+        unsafe { interrupt::disable() };
+        IrqMutexGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            restore,
+        }
+    }
+
+    /// Take the lock from an interrupt handler, where interrupts are already disabled
+    pub fn lock_irq(&self) -> IrqMutexGuard<'_, T> {
+        debug_assert!(
+            !interrupt::are_enabled(),
+            "IrqMutex::lock_irq with interrupts enabled"
+        );
+        IrqMutexGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            restore: false,
+        }
+    }
+}
+
+pub struct IrqMutexGuard<'a, T: ?Sized> {
+    guard: ManuallyDrop<SpinMutexGuard<'a, T>>,
+    /// Whether interrupts were enabled before the lock was taken
+    restore: bool,
+}
+
+impl<T: ?Sized> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // Unlock before interrupts come back on, so a handler arriving right away can lock
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.restore {
+            unsafe { interrupt::enable_and_nop() };
+        }
+    }
+}
@@ -54,6 +54,11 @@ impl<T> LockFreeQueue<T> {
         queue.pop_front()
     }
 
+    /// Put an item back at the front of the queue, to be dequeued next.
+    pub fn requeue_front(&self, value: T) {
+        self.inner.lock().push_front(value);
+    }
+
     /// Check if queue is empty.
     pub fn is_empty_approx(&self) -> bool {
         self.inner.lock().is_empty()
@@ -2,6 +2,7 @@
 //!
 //! This module contains synchronization types essential for thread safety and real-time guarantees.
 
+use alloc::collections::VecDeque;
 use core::{
     cell::UnsafeCell,
     ops::{Deref, DerefMut},
@@ -9,9 +10,13 @@ use core::{
 };
 use spin::{Mutex as SpinMutex, MutexGuard as SpinMutexGuard};
 
-use crate::context::{self, ContextRef};
+use crate::{
+    context::{self, ContextRef},
+    scheduler,
+};
 
 // Declare submodules
+mod irq;
 mod ordered;
 mod wait_condition;
 mod wait_queue;
@@ -23,10 +28,13 @@ mod priority;
 
 // Re-export ordered lock types
 pub use ordered::{
-    check_no_locks, CleanLockToken, Level, LockToken, Lower, Mutex as OrderedMutex,
-    MutexGuard as OrderedMutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, L0, L1, L2,
+    check_no_locks, CleanLockToken, InterruptedLocks, Level, LockToken, Lower,
+    Mutex as OrderedMutex, MutexGuard as OrderedMutexGuard, RwLock, RwLockReadGuard,
+    RwLockWriteGuard, L0, L1, L2,
 };
 
+pub use irq::{IrqMutex, IrqMutexGuard};
+
 // Re-export wait queue types
 pub use wait_condition::WaitCondition;
 pub use wait_queue::{WaitQueue, Waitable};
@@ -40,8 +48,8 @@ pub use priority::{IpcCriticalGuard, Priority, PriorityTracker};
 pub struct Mutex<T: ?Sized> {
     /// The ID of the context currently holding the lock.
     owner_id: AtomicUsize,
-    /// A wait queue for contexts trying to acquire this mutex, ordered by priority.
-    wait_queue: WaitQueue<ContextRef>,
+    /// The contexts trying to acquire this mutex, woken in order of priority.
+    waiters: MutexWaiters<ContextRef>,
     /// The actual spinlock protecting the data.
     inner: SpinMutex<T>,
 }
@@ -51,13 +59,15 @@ impl<T> Mutex<T> {
         Mutex {
             inner: SpinMutex::new(user_data),
             owner_id: AtomicUsize::new(0), // 0 indicates no owner
-            wait_queue: WaitQueue::new(),
+            waiters: MutexWaiters::new(),
         }
     }
 
     /// Locks the mutex, implementing Priority Inheritance.
-    pub fn lock(&self) -> MutexGuard<'_, T> {
-        let mut token = unsafe { CleanLockToken::new() };
+    ///
+    /// The guard keeps `token`, as releasing the mutex touches the contexts involved, and lends
+    /// it out again through [`MutexGuard::token`].
+    pub fn lock<'a>(&'a self, token: &'a mut CleanLockToken) -> MutexGuard<'a, T> {
         let current_context_ref = context::current();
         let current_context_id = current_context_ref.read(token.token()).id();
         let current_priority = current_context_ref.read(token.token()).priority.effective_priority();
@@ -67,6 +77,7 @@ impl<T> Mutex<T> {
             return MutexGuard {
                 mutex: self,
                 guard: self.inner.lock(),
+                token,
             };
         }
 
@@ -82,24 +93,106 @@ impl<T> Mutex<T> {
                 }
             }
 
-            // Add ourselves to the wait queue. The queue should be priority-ordered.
-            self.wait_queue.send(current_context_ref.clone(), &mut token);
-
-            // Block and wait to be woken up.
-            context::current().write(token.token()).block("Mutex::lock");
-            unsafe { context::switch(&mut token) };
-
-            // When we wake up, try to acquire the lock again.
-            if self.owner_id.compare_exchange(0, current_context_id, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+            // Either take the lock after all, or queue ourselves and block until a release
+            // dequeues and wakes us.
+            let acquired = self.waiters.acquire_or_enqueue(
+                &self.owner_id,
+                current_context_id,
+                || current_context_ref.clone(),
+                || {
+                    current_context_ref
+                        .write(token.token())
+                        .block("Mutex::lock");
+                },
+            );
+            if acquired {
                 return MutexGuard {
                     mutex: self,
                     guard: self.inner.lock(),
+                    token,
                 };
             }
+            unsafe { context::switch(token) };
+
+            // A signal wakes us without dequeuing us, so a release must not find us again.
+            self.waiters.cancel(current_context_id);
         }
     }
 }
 
+/// A context queued on a [`Mutex`].
+struct MutexWaiter<W> {
+    id: usize,
+    waiter: W,
+}
+
+/// The contexts waiting for a [`Mutex`], in the order they started waiting.
+///
+/// A release wakes the most urgent waiter, comparing priorities at that point rather than when
+/// the waiters were queued, since inheritance may have changed them in the meantime.
+///
+/// The queue lock orders releasing the mutex against waiters going to sleep: a waiter queues
+/// itself and blocks only after failing to take the mutex under the lock, and a release clears
+/// the owner and dequeues one waiter under it too. So each release either comes before the
+/// waiter's last attempt or finds it queued. Waiters are dequeued when woken, so a context is
+/// queued at most once and only ever woken while it is still waiting.
+struct MutexWaiters<W> {
+    queue: SpinMutex<VecDeque<MutexWaiter<W>>>,
+}
+
+impl<W> MutexWaiters<W> {
+    const fn new() -> Self {
+        MutexWaiters {
+            queue: SpinMutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Takes `owner` for the context `id`, or queues it and calls `block` if it is taken.
+    ///
+    /// Returns true if the mutex was taken. Otherwise the caller must sleep until woken, call
+    /// [`Self::cancel`] and try again.
+    fn acquire_or_enqueue(
+        &self,
+        owner: &AtomicUsize,
+        id: usize,
+        waiter: impl FnOnce() -> W,
+        block: impl FnOnce(),
+    ) -> bool {
+        let mut queue = self.queue.lock();
+        if owner
+            .compare_exchange(0, id, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            return true;
+        }
+        if !queue.iter().any(|queued| queued.id == id) {
+            queue.push_back(MutexWaiter {
+                id,
+                waiter: waiter(),
+            });
+        }
+        block();
+        false
+    }
+
+    /// Dequeues the context `id` if it is still queued, after it was woken by something else
+    /// than a release.
+    fn cancel(&self, id: usize) {
+        self.queue.lock().retain(|queued| queued.id != id);
+    }
+
+    /// Releases `owner`, returning the waiter that has to be woken.
+    ///
+    /// That is the one `priority` finds most urgent, or the longest waiting of those that are
+    /// equally urgent.
+    fn release(&self, owner: &AtomicUsize, mut priority: impl FnMut(&W) -> u8) -> Option<W> {
+        let mut queue = self.queue.lock();
+        owner.store(0, Ordering::Release);
+        let index = wait_queue::most_urgent(queue.iter().map(|queued| priority(&queued.waiter)))?;
+        queue.remove(index).map(|queued| queued.waiter)
+    }
+}
+
 impl<T: ?Sized + core::fmt::Debug> core::fmt::Debug for Mutex<T> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self.inner.try_lock() {
@@ -113,27 +206,57 @@ impl<T: ?Sized + core::fmt::Debug> core::fmt::Debug for Mutex<T> {
 pub struct MutexGuard<'a, T: ?Sized> {
     mutex: &'a Mutex<T>,
     guard: SpinMutexGuard<'a, T>,
+    token: &'a mut CleanLockToken,
 }
 
-impl<'a, T: ?Sized> Drop for MutexGuard<'a, T> {
-    fn drop(&mut self) {
-        let mut token = unsafe { CleanLockToken::new() };
-        let current_context_ref = context::current();
-        let mut current_context = current_context_ref.write(token.token());
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    /// The token the mutex was locked with, for locks taken while holding it
+    pub fn token(&mut self) -> &mut CleanLockToken {
+        self.token
+    }
 
-        // Restore original priority.
-        current_context.priority.restore_priority(self.mutex as *const _ as usize);
+    /// Wait on `condition` like [`WaitCondition::wait`], releasing the mutex once the current
+    /// context is queued on it
+    pub fn wait(self, condition: &WaitCondition, reason: &'static str) -> bool {
+        let this = core::mem::ManuallyDrop::new(self);
+        // SAFETY: `this` is never dropped, so the guard and the token are moved out only once
+        let (mutex, guard, token) = unsafe {
+            (
+                this.mutex,
+                core::ptr::read(&this.guard),
+                core::ptr::read(&this.token),
+            )
+        };
+        // Woken waiters spin on the inner lock until the guard is dropped by the wait
+        mutex.release(token);
+        condition.wait(guard, reason, token)
+    }
+}
 
-        // Release the lock.
-        self.mutex.owner_id.store(0, Ordering::Release);
+impl<T: ?Sized> Mutex<T> {
+    /// Restore the priority of the current context and hand the mutex to the most urgent
+    /// waiter, waking it
+    fn release(&self, token: &mut CleanLockToken) {
+        context::current()
+            .write(token.token())
+            .priority
+            .restore_priority(self as *const _ as *const () as usize);
 
-        // Wake up the highest-priority waiting task.
-        if let Ok(next_waiter_ref) = self.mutex.wait_queue.receive(false, "Mutex::drop", &mut token) {
-            next_waiter_ref.write(token.token()).unblock();
+        let next_waiter = self.waiters.release(&self.owner_id, |waiter| {
+            waiter.read(token.token()).priority.effective_priority()
+        });
+        if let Some(next_waiter_ref) = next_waiter {
+            scheduler::unblock(&next_waiter_ref, token);
         }
     }
 }
 
+impl<'a, T: ?Sized> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.release(self.token);
+    }
+}
+
 impl<'a, T: ?Sized> Deref for MutexGuard<'a, T> {
     type Target = T;
     fn deref(&self) -> &T {
@@ -146,3 +269,130 @@ impl<'a, T: ?Sized> DerefMut for MutexGuard<'a, T> {
         &mut self.guard
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::{Arc, Condvar, Mutex as StdMutex},
+        thread,
+        time::Duration,
+        vec::Vec,
+    };
+
+    /// Stands in for a context, blocking the thread that owns it
+    struct Parker {
+        blocked: StdMutex<bool>,
+        condvar: Condvar,
+    }
+
+    impl Parker {
+        fn block(&self) {
+            let mut blocked = self.blocked.lock().unwrap();
+            assert!(!*blocked, "blocked twice without being woken");
+            *blocked = true;
+        }
+        fn unblock(&self) {
+            let mut blocked = self.blocked.lock().unwrap();
+            assert!(*blocked, "woke a context that was not waiting");
+            *blocked = false;
+            self.condvar.notify_one();
+        }
+        fn sleep(&self) {
+            let blocked = self.blocked.lock().unwrap();
+            let (blocked, timeout) = self
+                .condvar
+                .wait_timeout_while(blocked, Duration::from_secs(10), |blocked| *blocked)
+                .unwrap();
+            assert!(!timeout.timed_out() && !*blocked, "wakeup was lost");
+        }
+    }
+
+    #[test]
+    fn contended_mutex_wakes_each_waiter_once() {
+        const THREADS: usize = 4;
+        const ROUNDS: usize = 2000;
+
+        let owner = Arc::new(AtomicUsize::new(0));
+        let waiters = Arc::new(MutexWaiters::<Arc<Parker>>::new());
+        let inside = Arc::new(AtomicUsize::new(0));
+        let total = Arc::new(AtomicUsize::new(0));
+
+        let threads: Vec<_> = (1..=THREADS)
+            .map(|id| {
+                let (owner, waiters) = (Arc::clone(&owner), Arc::clone(&waiters));
+                let (inside, total) = (Arc::clone(&inside), Arc::clone(&total));
+                thread::spawn(move || {
+                    let parker = Arc::new(Parker {
+                        blocked: StdMutex::new(false),
+                        condvar: Condvar::new(),
+                    });
+                    for _ in 0..ROUNDS {
+                        while !waiters.acquire_or_enqueue(
+                            &owner,
+                            id,
+                            || Arc::clone(&parker),
+                            || parker.block(),
+                        ) {
+                            parker.sleep();
+                            waiters.cancel(id);
+                        }
+
+                        assert_eq!(inside.fetch_add(1, Ordering::SeqCst), 0);
+                        total.fetch_add(1, Ordering::Relaxed);
+                        inside.fetch_sub(1, Ordering::SeqCst);
+
+                        if let Some(next) = waiters.release(&owner, |_| 0) {
+                            next.unblock();
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(total.load(Ordering::Relaxed), THREADS * ROUNDS);
+        assert_eq!(owner.load(Ordering::Relaxed), 0);
+        assert!(waiters.queue.lock().is_empty());
+    }
+
+    #[test]
+    fn waiters_are_queued_once_in_priority_order() {
+        let owner = AtomicUsize::new(1);
+        let waiters = MutexWaiters::new();
+        let priority = |&id: &usize| [0, 0, 20, 10, 20][id];
+
+        assert!(!waiters.acquire_or_enqueue(&owner, 2, || 2, || ()));
+        assert!(!waiters.acquire_or_enqueue(&owner, 3, || 3, || ()));
+        assert!(!waiters.acquire_or_enqueue(&owner, 4, || 4, || ()));
+        // Woken spuriously and retrying before being dequeued
+        assert!(!waiters.acquire_or_enqueue(&owner, 2, || 2, || ()));
+        assert_eq!(waiters.queue.lock().len(), 3);
+
+        assert_eq!(waiters.release(&owner, priority), Some(3));
+        assert_eq!(owner.load(Ordering::Relaxed), 0);
+        waiters.cancel(2);
+        assert_eq!(waiters.release(&owner, priority), Some(4));
+        assert_eq!(waiters.release(&owner, priority), None);
+    }
+
+    #[test]
+    fn release_wakes_by_priority_at_wake_time() {
+        let owner = AtomicUsize::new(1);
+        let waiters = MutexWaiters::new();
+        // Low, normal and high priority waiters, queued least urgent first
+        let mut priorities = [30, 20, 10];
+        for id in 0..3 {
+            assert!(!waiters.acquire_or_enqueue(&owner, id, || id, || ()));
+        }
+
+        assert_eq!(waiters.release(&owner, |&id| priorities[id]), Some(2));
+        // The low priority waiter inherits a higher priority while queued
+        priorities[0] = 5;
+        assert_eq!(waiters.release(&owner, |&id| priorities[id]), Some(0));
+        assert_eq!(waiters.release(&owner, |&id| priorities[id]), Some(1));
+        assert_eq!(waiters.release(&owner, |&id| priorities[id]), None);
+    }
+}
@@ -93,9 +93,13 @@ impl<T> OptimizedWaitQueue<T> {
                 return Ok(value);
             }
 
-            // Block on condition variable
+            // Block on condition variable, unless already interrupted
             {
-                current_context_ref.write(token.token()).block(reason);
+                let mut context = current_context_ref.write(token.token());
+                if core::mem::take(&mut context.interrupt_pending) {
+                    return Err(Error::new(EINTR));
+                }
+                context.block(reason);
             }
 
             // Wait for notification
@@ -105,12 +109,17 @@ impl<T> OptimizedWaitQueue<T> {
 
             // Do NOT clear waiters flag here blindly.
 
-            // Check for signals
+            // Check for signals and interrupts from proc:<pid>/ctl
             {
-                let context = current_context_ref.read(token.token());
-                if let Some((control, pctl)) = context.sig.as_ref().map(|sig| {
-                    crate::context::context::Context::sigcontrol_raw_const(sig)
-                }) {
+                let mut context = current_context_ref.write(token.token());
+                if core::mem::take(&mut context.interrupt_pending) {
+                    return Err(Error::new(EINTR));
+                }
+                if let Some((control, pctl)) = context
+                    .sig
+                    .as_ref()
+                    .map(|sig| crate::context::context::Context::sigcontrol_raw_const(sig))
+                {
                     if control.currently_pending_unblocked(pctl) != 0 {
                         return Err(Error::new(EINTR));
                     }
@@ -135,21 +144,19 @@ impl<T> OptimizedWaitQueue<T> {
         len
     }
 
+    /// Return a value taken by [`Self::receive`] that could not be delivered, so that the next
+    /// receive sees it again
+    pub fn unreceive(&self, value: T) {
+        self.queue.requeue_front(value);
+    }
+
     /// Wake one waiter
     ///
     /// This is called when an item is already in the queue.
     #[inline]
-    pub fn wake_one(&self) {
+    pub fn wake_one(&self, token: &mut CleanLockToken) {
         if self.has_waiters.load(Ordering::Acquire) {
-            // SAFETY: CleanLockToken::new() is unsafe because it creates a token that implies
-            // no locks are held. We are in wake_one which might be called from various contexts,
-            // but we are only using it to notify a condition variable which is generally safe.
-            // However, the caller must ensure this doesn't violate lock ordering if called from
-            // a critical section.
-            unsafe {
-                let mut token = CleanLockToken::new();
-                self.condition.notify(&mut token);
-            }
+            self.condition.notify(token);
         }
     }
 
@@ -167,6 +174,11 @@ impl<T> Default for OptimizedWaitQueue<T> {
 }
 
 impl<T: Copy> OptimizedWaitQueue<T> {
+    /// Receive as many values as fit in `buf`
+    ///
+    /// Only blocks while nothing has been received yet. When not blocking and the queue is empty,
+    /// returns EAGAIN rather than a zero-length read. A value that cannot be copied to `buf` is
+    /// put back at the front of the queue instead of being dropped.
     pub fn receive_into_user(
         &self,
         mut buf: UserSliceWo,
@@ -175,36 +187,72 @@ impl<T: Copy> OptimizedWaitQueue<T> {
         token: &mut CleanLockToken,
     ) -> Result<usize> {
         let mut total = 0;
-        
-        loop {
-             let do_block = block && total == 0;
-             match self.receive(do_block, reason, token) {
-                 Ok(val) => {
-                     let slice = unsafe {
-                         core::slice::from_raw_parts(&val as *const T as *const u8, core::mem::size_of::<T>())
-                     };
-                     match buf.copy_common_bytes_from_slice(slice) {
-                         Ok(written) => {
-                             total += written;
-                             if let Some(next) = buf.advance(written) {
-                                 buf = next;
-                             } else {
-                                 break;
-                             }
-                         }
-                         Err(e) => return Err(e),
-                     }
-                 }
-                 Err(Error { errno: EAGAIN }) => break,
-                 Err(e) => return Err(e),
-             }
-             
-             if buf.len() == 0 { break; }
+
+        while buf.len() >= core::mem::size_of::<T>() {
+            let val = match self.receive(block && total == 0, reason, token) {
+                Ok(val) => val,
+                Err(Error { errno: EAGAIN }) if total > 0 => break,
+                Err(err) => return Err(err),
+            };
+            let slice = unsafe {
+                core::slice::from_raw_parts(
+                    &val as *const T as *const u8,
+                    core::mem::size_of::<T>(),
+                )
+            };
+            if let Err(err) = buf.copy_common_bytes_from_slice(slice) {
+                self.unreceive(val);
+                return if total > 0 { Ok(total) } else { Err(err) };
+            }
+            total += slice.len();
+            buf = match buf.advance(slice.len()) {
+                Some(next) => next,
+                None => break,
+            };
         }
-        
+
         Ok(total)
     }
 }
 
 unsafe impl<T: Send> Send for OptimizedWaitQueue<T> {}
 unsafe impl<T: Send> Sync for OptimizedWaitQueue<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nonblocking_receive_on_empty_queue_is_eagain() {
+        let queue = OptimizedWaitQueue::<u8>::new();
+        let mut token = unsafe { CleanLockToken::new() };
+
+        assert_eq!(
+            queue.receive(false, "test", &mut token),
+            Err(Error::new(EAGAIN))
+        );
+
+        queue.send(1, &mut token);
+        assert_eq!(queue.receive(false, "test", &mut token), Ok(1));
+        assert_eq!(
+            queue.receive(false, "test", &mut token),
+            Err(Error::new(EAGAIN))
+        );
+    }
+
+    #[test]
+    fn unreceived_value_is_received_first() {
+        let queue = OptimizedWaitQueue::<u8>::new();
+        let mut token = unsafe { CleanLockToken::new() };
+
+        queue.send(1, &mut token);
+        queue.send(2, &mut token);
+
+        let first = queue.receive(false, "test", &mut token).unwrap();
+        queue.unreceive(first);
+
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.receive(false, "test", &mut token), Ok(1));
+        assert_eq!(queue.receive(false, "test", &mut token), Ok(2));
+    }
+}
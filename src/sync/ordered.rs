@@ -20,7 +20,10 @@ use crate::context::{self, ContextRef};
 /// We need some thread TA to hold a resource RA, and request a resource RB, while
 /// another thread TB holds RB, and requests RA. This is not possible with a lock
 /// hierarchy either RA or RB must be on a level that the other.
-pub trait Level {}
+pub trait Level {
+    /// Position in the hierarchy, 0 for [`L0`] where no lock is held
+    const LEVEL: usize;
+}
 
 /// Indicate that the implementor is lower that the level O
 pub trait Lower<O: Level>: Level {}
@@ -35,9 +38,15 @@ pub struct L1 {}
 #[derive(Debug)]
 pub struct L2 {}
 
-impl Level for L0 {}
-impl Level for L1 {}
-impl Level for L2 {}
+impl Level for L0 {
+    const LEVEL: usize = 0;
+}
+impl Level for L1 {
+    const LEVEL: usize = 1;
+}
+impl Level for L2 {
+    const LEVEL: usize = 2;
+}
 
 impl Lower<L1> for L0 {}
 impl Lower<L2> for L0 {}
@@ -76,6 +85,8 @@ impl CleanLockToken {
 
     /// Create a new instance
     ///
+    /// Debug builds panic if an ordered lock is held on this CPU.
+    ///
     /// # Safety
     ///
     /// This is safe to call as long as there are no currently acquired locks
@@ -84,10 +95,88 @@ impl CleanLockToken {
     ///
     /// A CleanLockToken
     pub unsafe fn new() -> Self {
+        #[cfg(all(debug_assertions, not(test)))]
+        {
+            let held = held_locks().get();
+            assert_eq!(
+                held, 0,
+                "CleanLockToken created with {held} ordered locks held"
+            );
+        }
+        CleanLockToken(())
+    }
+
+    /// Create a new instance while an ordered lock is held, without the check of [`Self::new`]
+    ///
+    /// # Safety
+    ///
+    /// Only for the context switch, which has to lock both contexts although they are on the
+    /// same level. Locks taken with the token must not be held past the locks already held.
+    pub unsafe fn new_nested() -> Self {
         CleanLockToken(())
     }
 }
 
+/// Number of ordered locks held on this CPU
+///
+/// Kept in debug builds only. A context is switched away from with none held but those of the
+/// switch itself, which [`crate::context::switch`] sets aside while the next context runs.
+#[cfg(all(debug_assertions, not(test)))]
+pub fn held_locks() -> &'static core::cell::Cell<usize> {
+    &crate::percpu::PercpuBlock::current().held_locks
+}
+
+/// The ordered locks held by the code an interrupt handler interrupted, set aside while the
+/// handler runs
+///
+/// The handler does not hold them, so that it can create clean tokens. Counted in debug builds
+/// only, like [`held_locks`].
+pub struct InterruptedLocks {
+    #[cfg(all(debug_assertions, not(test)))]
+    held: usize,
+}
+
+impl InterruptedLocks {
+    /// Set aside the locks held on this CPU until the returned value is dropped, on the way out
+    /// of the handler
+    pub fn set_aside() -> Self {
+        Self {
+            #[cfg(all(debug_assertions, not(test)))]
+            held: held_locks().replace(0),
+        }
+    }
+}
+
+impl Drop for InterruptedLocks {
+    fn drop(&mut self) {
+        #[cfg(all(debug_assertions, not(test)))]
+        held_locks().set(self.held);
+    }
+}
+
+fn note_acquired() {
+    #[cfg(all(debug_assertions, not(test)))]
+    held_locks().set(held_locks().get() + 1);
+}
+
+fn note_released() {
+    #[cfg(all(debug_assertions, not(test)))]
+    held_locks().set(held_locks().get() - 1);
+}
+
+/// Let others run while waiting for a lock
+///
+/// Switching needs a clean token, so only a waiter holding no other lock yields. Any other
+/// spins, as the switch would otherwise lock contexts below the locks it holds.
+fn wait_for_lock<LP: Level>(_lock_token: &mut LockToken<'_, LP>) {
+    if LP::LEVEL == L0::LEVEL {
+        // An L0 token mutably borrows the clean token it came from, so no lock is held
+        unsafe { crate::context::switch(&mut CleanLockToken(())) };
+    } else {
+        core::hint::spin_loop();
+    }
+}
+
 /// A mutual exclusion primitive useful for protecting shared data
 ///
 /// This mutex will block threads waiting for the lock to become available. The
@@ -140,6 +229,7 @@ impl<L: Level, T> Mutex<L, T> {
             if let Some(guard) = self.inner.try_lock() {
                 // Successfully acquired the lock
                 *self.holder.lock() = Some(current_context_ref.clone());
+                note_acquired();
                 return MutexGuard {
                     inner: guard,
                     lock_token: LockToken::downgraded(lock_token),
@@ -147,32 +237,13 @@ impl<L: Level, T> Mutex<L, T> {
                 };
             }
 
-            // Lock is held, check for priority inversion
-            let holder_context_ref_opt = self.holder.lock().clone();
-            if let Some(holder_context_ref) = holder_context_ref_opt {
-                let mut clean = unsafe { CleanLockToken::new() };
-                let current_priority = current_context_ref
-                    .read(clean.token())
-                    .priority
-                    .effective_priority();
-                let holder_priority = holder_context_ref
-                    .read(clean.token())
-                    .priority
-                    .effective_priority();
-
-                if current_priority < holder_priority {
-                    // Current thread has higher priority than holder, perform priority inheritance
-                    holder_context_ref
-                        .read(clean.token())
-                        .priority
-                        .inherit_priority(current_priority);
-                }
+            // Lock is held, lend our priority to the holder under this mutex's key
+            if let Some(holder_context_ref) = &*self.holder.lock() {
+                inherit_priority(holder_context_ref, &current_context_ref, lock_key(self));
             }
 
-            // Block the current thread until the lock is available
             // TODO: Use a proper wait queue for mutexes
-            // For now, just yield
-            unsafe { crate::context::switch(&mut CleanLockToken::new()) };
+            wait_for_lock(&mut lock_token);
         }
     }
 
@@ -191,32 +262,16 @@ impl<L: Level, T> Mutex<L, T> {
 
         if let Some(guard) = self.inner.try_lock() {
             *self.holder.lock() = Some(current_context_ref.clone());
+            note_acquired();
             Some(MutexGuard {
                 inner: guard,
                 lock_token: LockToken::downgraded(lock_token),
                 mutex: self,
             })
         } else {
-            // Lock is held, check for priority inversion
-            let holder_context_ref_opt = self.holder.lock().clone();
-            if let Some(holder_context_ref) = holder_context_ref_opt {
-                let mut clean = unsafe { CleanLockToken::new() };
-                let current_priority = current_context_ref
-                    .read(clean.token())
-                    .priority
-                    .effective_priority();
-                let holder_priority = holder_context_ref
-                    .read(clean.token())
-                    .priority
-                    .effective_priority();
-
-                if current_priority < holder_priority {
-                    // Current thread has higher priority than holder, perform priority inheritance
-                    holder_context_ref
-                        .read(clean.token())
-                        .priority
-                        .inherit_priority(current_priority);
-                }
+            // Lock is held, lend our priority to the holder under this mutex's key
+            if let Some(holder_context_ref) = &*self.holder.lock() {
+                inherit_priority(holder_context_ref, &current_context_ref, lock_key(self));
             }
             None
         }
@@ -262,19 +317,12 @@ impl<'a, L: Level, T: ?Sized + 'a> core::ops::DerefMut for MutexGuard<'a, L, T>
 
 impl<'a, L: Level, T: ?Sized + 'a> Drop for MutexGuard<'a, L, T> {
     fn drop(&mut self) {
-        // Clear the holder and restore priority
-        *self.mutex.holder.lock() = None;
-        // The priority of the thread holding the lock will be restored
-        // when its boost_deadline expires, or explicitly by other means.
-        // For a true PI mutex, we might need to explicitly restore here
-        // to the highest priority of any other locks it holds, or its base.
-        // For now, rely on expiration or explicit restore.
-        // TODO: Implement proper priority restoration for nested locks.
-        context::current()
-            .inner
-            .read()
-            .priority
-            .restore_base_priority();
+        // Clear the holder and drop only the boost lent through this mutex, keeping any
+        // inherited from other locks we still hold
+        let mut holder = self.mutex.holder.lock();
+        *holder = None;
+        restore_priority(lock_key(self.mutex));
+        note_released();
     }
 }
 
@@ -325,6 +373,14 @@ impl<L: Level, T> RwLock<L, T> {
         self.inner.into_inner()
     }
 
+    /// Returns a pointer to the underlying data, without locking it.
+    ///
+    /// While someone else may hold the lock, only data that tolerates concurrent access, such as
+    /// atomics, may be read through the pointer.
+    pub fn data_ptr(&self) -> *mut T {
+        self.inner.as_mut_ptr()
+    }
+
     /// Locks this RwLock with exclusive write access, blocking the current thread until it can be acquired.
     /// This function will not return while other writers or other readers currently have access to the lock.
     /// Returns an RAII guard which will drop the write access of this RwLock when dropped.
@@ -337,6 +393,7 @@ impl<L: Level, T> RwLock<L, T> {
         loop {
             if let Some(guard) = self.inner.try_write() {
                 *self.writer_holder.lock() = Some(current_context_ref.clone());
+                note_acquired();
                 return RwLockWriteGuard {
                     inner: guard,
                     lock_token: LockToken::downgraded(lock_token),
@@ -344,47 +401,15 @@ impl<L: Level, T> RwLock<L, T> {
                 };
             }
 
-            // Lock is held, check for priority inversion
-            let writer_context_ref_opt = self.writer_holder.lock().clone();
-            if let Some(writer_context_ref) = writer_context_ref_opt {
-                let mut clean = unsafe { CleanLockToken::new() };
-                let current_priority = current_context_ref
-                    .read(clean.token())
-                    .priority
-                    .effective_priority();
-                let writer_priority = writer_context_ref
-                    .read(clean.token())
-                    .priority
-                    .effective_priority();
-
-                if current_priority < writer_priority {
-                    writer_context_ref
-                        .read(clean.token())
-                        .priority
-                        .inherit_priority(current_priority);
-                }
+            // Lock is held, lend our priority to the writer or readers under this lock's key
+            if let Some(writer_context_ref) = &*self.writer_holder.lock() {
+                inherit_priority(writer_context_ref, &current_context_ref, lock_key(self));
             }
-            // Also check readers
             for reader_context_ref in self.reader_holders.lock().iter() {
-                let mut clean = unsafe { CleanLockToken::new() };
-                let current_priority = current_context_ref
-                    .read(clean.token())
-                    .priority
-                    .effective_priority();
-                let reader_priority = reader_context_ref
-                    .read(clean.token())
-                    .priority
-                    .effective_priority();
-
-                if current_priority < reader_priority {
-                    reader_context_ref
-                        .read(clean.token())
-                        .priority
-                        .inherit_priority(current_priority);
-                }
+                inherit_priority(reader_context_ref, &current_context_ref, lock_key(self));
             }
 
-            unsafe { crate::context::switch(&mut CleanLockToken::new()) };
+            wait_for_lock(&mut lock_token);
         }
     }
 
@@ -406,6 +431,7 @@ impl<L: Level, T> RwLock<L, T> {
         loop {
             if let Some(guard) = self.inner.try_read() {
                 self.reader_holders.lock().push(current_context_ref.clone());
+                note_acquired();
                 return RwLockReadGuard {
                     inner: guard,
                     lock_token: LockToken::downgraded(lock_token),
@@ -413,28 +439,12 @@ impl<L: Level, T> RwLock<L, T> {
                 };
             }
 
-            // Lock is held, check for priority inversion
-            let writer_context_ref_opt = self.writer_holder.lock().clone();
-            if let Some(writer_context_ref) = writer_context_ref_opt {
-                let mut clean = unsafe { CleanLockToken::new() };
-                let current_priority = current_context_ref
-                    .read(clean.token())
-                    .priority
-                    .effective_priority();
-                let writer_priority = writer_context_ref
-                    .read(clean.token())
-                    .priority
-                    .effective_priority();
-
-                if current_priority < writer_priority {
-                    writer_context_ref
-                        .read(clean.token())
-                        .priority
-                        .inherit_priority(current_priority);
-                }
+            // Lock is held, lend our priority to the writer under this lock's key
+            if let Some(writer_context_ref) = &*self.writer_holder.lock() {
+                inherit_priority(writer_context_ref, &current_context_ref, lock_key(self));
             }
 
-            unsafe { crate::context::switch(&mut CleanLockToken::new()) };
+            wait_for_lock(&mut lock_token);
         }
     }
 }
@@ -470,12 +480,10 @@ impl<L: Level, T> core::ops::DerefMut for RwLockWriteGuard<'_, L, T> {
 
 impl<'a, L: Level, T> Drop for RwLockWriteGuard<'a, L, T> {
     fn drop(&mut self) {
-        *self.rwlock.writer_holder.lock() = None;
-        context::current()
-            .inner
-            .read()
-            .priority
-            .restore_base_priority();
+        let mut writer = self.rwlock.writer_holder.lock();
+        *writer = None;
+        restore_priority(lock_key(self.rwlock));
+        note_released();
     }
 }
 
@@ -505,16 +513,48 @@ impl<L: Level, T> core::ops::Deref for RwLockReadGuard<'_, L, T> {
 impl<'a, L: Level, T> Drop for RwLockReadGuard<'a, L, T> {
     fn drop(&mut self) {
         let current_context_ref = context::current();
-        self.rwlock
-            .reader_holders
-            .lock()
-            .retain(|ctx| !Arc::ptr_eq(ctx, &current_context_ref));
-        context::current()
-            .inner
-            .read()
-            .priority
-            .restore_base_priority();
+        let mut readers = self.rwlock.reader_holders.lock();
+        readers.retain(|ctx| !Arc::ptr_eq(ctx, &current_context_ref));
+        restore_priority(lock_key(self.rwlock));
+        note_released();
+    }
+}
+
+/// The key a lock registers its priority boosts under in [`PriorityTracker`](super::PriorityTracker)
+fn lock_key<T: ?Sized>(lock: &T) -> usize {
+    lock as *const T as *const () as usize
+}
+
+/// Lend the priority of `waiter` to `holder` under `key`, if it is higher.
+///
+/// Must be called with the lock's holder list locked, so that the boost cannot be registered
+/// after the holder has already released the lock and restored its priority.
+fn inherit_priority(holder: &ContextRef, waiter: &ContextRef, key: usize) {
+    // Context locks are themselves ordered locks, and a context waiting on its own lock, or
+    // on the lock of the context that holds it, cannot touch that context's priority.
+    if Arc::ptr_eq(holder, waiter) || key == lock_key(&**holder) || key == lock_key(&**waiter) {
+        return;
+    }
+
+    let waiter_priority = waiter.inner.read().priority.effective_priority();
+    let holder = holder.inner.read();
+    if waiter_priority < holder.priority.effective_priority() {
+        holder.priority.inherit_priority(key, waiter_priority);
+    }
+}
+
+/// Drop the boost the current context inherited through the lock identified by `key`.
+fn restore_priority(key: usize) {
+    let current_context_ref = context::current();
+    // See inherit_priority, no boost is ever registered under a context's own lock
+    if key == lock_key(&*current_context_ref) {
+        return;
     }
+    current_context_ref
+        .inner
+        .read()
+        .priority
+        .restore_priority(key);
 }
 
 /// This function can only be called if no lock is held by the calling thread/task
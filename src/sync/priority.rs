@@ -47,7 +47,9 @@ pub struct PriorityTracker {
     /// Effective priority (may be boosted)
     effective_priority: AtomicU8,
     /// List of inherited priorities from other contexts, with a key to identify the source.
-    inherited_priorities: VecDeque<(usize, u8)>,
+    ///
+    /// Behind its own lock so that waiters can lend priority while only holding the context for reading.
+    inherited_priorities: spin::Mutex<VecDeque<(usize, u8)>>,
     /// Priority boost deadline (TSC timestamp, 0 = no boost)
     boost_deadline: AtomicU64,
     /// Count of critical IPC operations in progress
@@ -60,7 +62,7 @@ impl PriorityTracker {
         PriorityTracker {
             base_priority: AtomicU8::new(prio),
             effective_priority: AtomicU8::new(prio),
-            inherited_priorities: VecDeque::new(),
+            inherited_priorities: spin::Mutex::new(VecDeque::new()),
             boost_deadline: AtomicU64::new(0),
             ipc_critical_count: AtomicU8::new(0),
         }
@@ -73,7 +75,7 @@ impl PriorityTracker {
     }
 
     /// Check if a priority boost has expired and recalculate if it has.
-    pub fn check_boost_expired(&mut self) {
+    pub fn check_boost_expired(&self) {
         let deadline = self.boost_deadline.load(Ordering::Relaxed);
 
         if deadline != 0 {
@@ -90,10 +92,14 @@ impl PriorityTracker {
     }
 
     /// Recalculates the effective priority based on base priority and inherited priorities.
-    fn recalculate_effective_priority(&mut self) {
+    fn recalculate_effective_priority(&self) {
+        self.recalculate_with(&self.inherited_priorities.lock());
+    }
+
+    fn recalculate_with(&self, inherited: &VecDeque<(usize, u8)>) {
         let base = self.base_priority.load(Ordering::Relaxed);
         // Find the highest priority (lowest value) among inherited priorities.
-        let highest_inherited = self.inherited_priorities.iter().map(|(_, p)| *p).min().unwrap_or(base);
+        let highest_inherited = inherited.iter().map(|(_, p)| *p).min().unwrap_or(base);
         let new_effective = core::cmp::min(base, highest_inherited);
         self.effective_priority.store(new_effective, Ordering::Release);
     }
@@ -146,27 +152,39 @@ impl PriorityTracker {
 
     /// Inherit a priority from a synchronization object.
     /// The key should uniquely identify the synchronization object.
-    pub fn inherit_priority(&mut self, key: usize, donor_priority: u8) {
-        // If a priority from this key is already present, remove it before adding the new one.
-        if let Some(pos) = self.inherited_priorities.iter().position(|(k, _)| *k == key) {
-            self.inherited_priorities.remove(pos);
+    ///
+    /// Lending again under the same key keeps only the highest priority lent through it.
+    pub fn inherit_priority(&self, key: usize, donor_priority: u8) {
+        let mut inherited = self.inherited_priorities.lock();
+        match inherited.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, prio)) => *prio = core::cmp::min(*prio, donor_priority),
+            None => inherited.push_back((key, donor_priority)),
         }
-        self.inherited_priorities.push_back((key, donor_priority));
-        self.recalculate_effective_priority();
+        self.recalculate_with(&inherited);
     }
 
     /// Restore priority after releasing a resource.
-    /// The key should be the same as the one used in `inherit_priority`.
-    pub fn restore_priority(&mut self, key: usize) {
-        if let Some(pos) = self.inherited_priorities.iter().position(|(k, _)| *k == key) {
-            self.inherited_priorities.remove(pos);
-        }
-        self.recalculate_effective_priority();
+    /// The key should be the same as the one used in `inherit_priority`. Boosts lent through
+    /// other keys are kept.
+    pub fn restore_priority(&self, key: usize) {
+        let mut inherited = self.inherited_priorities.lock();
+        inherited.retain(|(k, _)| *k != key);
+        self.recalculate_with(&inherited);
+    }
+
+    /// Get base priority
+    #[inline]
+    pub fn base_priority(&self) -> u8 {
+        self.base_priority.load(Ordering::Relaxed)
     }
 
     /// Set base priority
     pub fn set_base_priority(&mut self, priority: Priority) {
-        let prio = priority.as_u8();
+        self.set_base_priority_raw(priority.as_u8());
+    }
+
+    /// Set base priority to an exact level rather than one of the [`Priority`] classes
+    pub fn set_base_priority_raw(&mut self, prio: u8) {
         self.base_priority.store(prio, Ordering::Relaxed);
         self.recalculate_effective_priority();
     }
@@ -197,3 +215,49 @@ impl<'a> Drop for IpcCriticalGuard<'a> {
         self.tracker.exit_ipc_critical();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCK_A: usize = 0x1000;
+    const LOCK_B: usize = 0x2000;
+
+    #[test]
+    fn releasing_unrelated_lock_keeps_boost() {
+        let tracker = PriorityTracker::new(Priority::Low);
+
+        // A realtime waiter on A and a high priority waiter on B both boost the holder.
+        tracker.inherit_priority(LOCK_A, Priority::Realtime.as_u8());
+        tracker.inherit_priority(LOCK_B, Priority::High.as_u8());
+        assert_eq!(tracker.effective_priority(), Priority::Realtime.as_u8());
+
+        // Releasing B must not drop the boost lent through A.
+        tracker.restore_priority(LOCK_B);
+        assert_eq!(tracker.effective_priority(), Priority::Realtime.as_u8());
+
+        tracker.restore_priority(LOCK_A);
+        assert_eq!(tracker.effective_priority(), Priority::Low.as_u8());
+    }
+
+    #[test]
+    fn effective_priority_is_best_of_base_and_remaining_keys() {
+        let tracker = PriorityTracker::new(Priority::Normal);
+
+        // A lower priority donor never lowers the holder.
+        tracker.inherit_priority(LOCK_A, Priority::Low.as_u8());
+        assert_eq!(tracker.effective_priority(), Priority::Normal.as_u8());
+
+        // Repeated lending under one key keeps the best priority for that key.
+        tracker.inherit_priority(LOCK_B, Priority::High.as_u8());
+        tracker.inherit_priority(LOCK_B, Priority::Normal.as_u8());
+        assert_eq!(tracker.effective_priority(), Priority::High.as_u8());
+
+        tracker.restore_priority(LOCK_B);
+        assert_eq!(tracker.effective_priority(), Priority::Normal.as_u8());
+
+        // Unknown keys are a no-op.
+        tracker.restore_priority(0x3000);
+        assert_eq!(tracker.effective_priority(), Priority::Normal.as_u8());
+    }
+}
@@ -3,9 +3,11 @@ use alloc::{
     sync::{Arc, Weak},
     vec::Vec,
 };
+use core::mem;
 
 use crate::{
-    context::{self, ContextLock},
+    context::{self, Context, ContextLock},
+    scheduler,
     sync::{CleanLockToken, OrderedMutex, L1},
 };
 
@@ -24,80 +26,49 @@ impl WaitCondition {
 
     // Notify all waiters
     pub fn notify(&self, token: &mut CleanLockToken) -> usize {
-        let mut contexts_map = self.contexts.lock(token.token());
-        let (contexts_map, mut token) = contexts_map.token_split();
-        let mut notified_count = 0;
-
-        // Iterate through priorities from highest (lowest u8 value) to lowest
-        let mut priorities_to_remove = Vec::new();
-        for (priority, contexts) in contexts_map.iter_mut() {
-            for context_weak in contexts.drain(..) {
-                if let Some(context_ref) = context_weak.upgrade() {
-                    context_ref.write(token.token()).unblock();
-                    notified_count += 1;
-                }
-            }
-            priorities_to_remove.push(*priority);
-        }
-
-        // Remove empty priority vectors
-        for priority in priorities_to_remove {
-            contexts_map.remove(&priority);
-        }
-
-        notified_count
+        self.notify_with(|_| {}, token)
     }
 
     // Notify all waiters and boost their priority
     pub fn notify_boosted(&self, token: &mut CleanLockToken) -> usize {
-        let mut contexts_map = self.contexts.lock(token.token());
-        let (contexts_map, mut token) = contexts_map.token_split();
-        let mut notified_count = 0;
-
-        let mut priorities_to_remove = Vec::new();
-        for (priority, contexts) in contexts_map.iter_mut() {
-            for context_weak in contexts.drain(..) {
-                if let Some(context_ref) = context_weak.upgrade() {
-                    let mut context = context_ref.write(token.token());
-                    context.unblock();
-                    // Boost priority for IPC completion (approx 10k cycles)
-                    context.priority.boost_for_ipc(10000);
-                    notified_count += 1;
-                }
-            }
-            priorities_to_remove.push(*priority);
-        }
-
-        // Remove empty priority vectors
-        for priority in priorities_to_remove {
-            contexts_map.remove(&priority);
-        }
-
-        notified_count
+        // Boost priority for IPC completion (approx 10k cycles)
+        self.notify_with(|context| context.priority.boost_for_ipc(10000), token)
     }
 
     // Notify as though a signal woke the waiters
     pub unsafe fn notify_signal(&self, token: &mut CleanLockToken) -> usize {
-        let mut contexts_map = self.contexts.lock(token.token());
-        let (contexts_map, mut token) = contexts_map.token_split();
+        self.notify_with(|_| {}, token)
+    }
+
+    /// Unblock every waiter, calling `f` on each, and queue those that were blocked once the
+    /// waiters are no longer locked
+    fn notify_with(&self, f: impl Fn(&mut Context), token: &mut CleanLockToken) -> usize {
+        let mut guard = self.contexts.lock(token.token());
+        let (contexts_map, mut split_token) = guard.token_split();
         let mut notified_count = 0;
+        let mut woken = Vec::new();
 
-        let mut priorities_to_remove = Vec::new();
-        for (priority, contexts) in contexts_map.iter_mut() {
+        // Iterate through priorities from highest (lowest u8 value) to lowest
+        for contexts in contexts_map.values_mut() {
             for context_weak in contexts.drain(..) {
                 if let Some(context_ref) = context_weak.upgrade() {
-                    context_ref.write(token.token()).unblock();
+                    let mut context = context_ref.write(split_token.token());
+                    let unblocked = context.unblock();
+                    f(&mut *context);
+                    drop(context);
+                    if unblocked {
+                        woken.push(context_ref);
+                    }
                     notified_count += 1;
                 }
             }
-            priorities_to_remove.push(*priority);
         }
+        contexts_map.clear();
+        drop(guard);
 
-        // Remove empty priority vectors
-        for priority in priorities_to_remove {
-            contexts_map.remove(&priority);
+        for context_ref in woken {
+            scheduler::wake(context_ref, token);
         }
-
         notified_count
     }
 
@@ -112,6 +83,9 @@ impl WaitCondition {
                 {
                     return false;
                 }
+                if mem::take(&mut context.interrupt_pending) {
+                    return false;
+                }
                 context.block(reason);
             }
 
@@ -152,6 +126,12 @@ impl WaitCondition {
                 contexts_map.remove(&effective_priority);
             }
         }
+        drop(contexts_map);
+
+        // Interrupted through proc:<pid>/ctl, even if a notify raced with it
+        if mem::take(&mut current_context_ref.write(token.token()).interrupt_pending) {
+            waited = false;
+        }
 
         waited
     }
@@ -49,6 +49,49 @@ impl<T> WaitQueue<T> {
         self.inner.lock().is_empty()
     }
 
+    /// Like [`Self::receive`], but dequeues the waiter whose context is most urgent, rather than
+    /// the first one.
+    ///
+    /// Priorities are read when dequeuing, since inheritance may change them while queued.
+    /// Waiters of equal priority are dequeued in the order they were sent.
+    pub fn receive_highest_priority(
+        &self,
+        block: bool,
+        reason: &'static str,
+        token: &mut CleanLockToken,
+    ) -> Result<T>
+    where
+        T: AsRef<ContextRef>,
+    {
+        loop {
+            let mut inner = self.inner.lock();
+
+            let index = most_urgent(inner.iter().map(|waiter| {
+                waiter
+                    .as_ref()
+                    .as_ref()
+                    .read(token.token())
+                    .priority
+                    .effective_priority()
+            }));
+            match index.and_then(|index| inner.remove(index)) {
+                Some(t) => {
+                    return Ok(t.into_inner());
+                }
+                _ => {
+                    if block {
+                        if !self.condition.wait(inner, reason, token) {
+                            return Err(Error::new(EINTR));
+                        }
+                        continue;
+                    } else {
+                        return Err(Error::new(EAGAIN));
+                    }
+                }
+            }
+        }
+    }
+
     pub fn receive(
         &self,
         block: bool,
@@ -121,24 +164,38 @@ impl<T> WaitQueue<T> {
         }
     }
 
-    pub fn send(&self, value: T, token: &mut CleanLockToken) -> usize where T: AsRef<ContextRef> {
+    /// Queue `value` behind the others, returning the new length.
+    ///
+    /// Use [`Self::receive_highest_priority`] to dequeue by priority.
+    pub fn send(&self, value: T, token: &mut CleanLockToken) -> usize {
         let len = {
             let mut inner = self.inner.lock();
-            let priority = value.as_ref().read(token.token()).priority.effective_priority();
-
-            // Insert the task into the queue based on priority.
-            let mut i = 0;
-            while i < inner.len() {
-                let other_priority = inner[i].as_ref().as_ref().read(token.token()).priority.effective_priority();
-                if priority < other_priority {
-                    break;
-                }
-                i += 1;
-            }
-            inner.insert(i, Waitable::new(value));
+            inner.push_back(Waitable::new(value));
             inner.len()
         };
         self.condition.notify(token);
         len
     }
 }
+
+/// Index of the most urgent of `priorities`, where lower values are more urgent, or the first of
+/// them if several are equally urgent.
+pub(super) fn most_urgent(priorities: impl IntoIterator<Item = u8>) -> Option<usize> {
+    priorities
+        .into_iter()
+        .enumerate()
+        .min_by_key(|&(_, priority)| priority)
+        .map(|(index, _)| index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn most_urgent_breaks_ties_in_order() {
+        assert_eq!(most_urgent([20, 10, 30, 10]), Some(1));
+        assert_eq!(most_urgent([5, 5, 5]), Some(0));
+        assert_eq!(most_urgent([]), None);
+    }
+}
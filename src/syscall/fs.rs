@@ -9,10 +9,10 @@ use crate::{
     context::{
         self,
         file::{FileDescription, FileDescriptor, InternalFlags},
-        memory::{AddrSpace, Grant, PageSpan, TlbShootdownActions},
+        memory::{handle_notify_files, AddrSpace, Grant, PageSpan, TlbShootdownActions},
     },
     paging::{Page, VirtualAddress, PAGE_SIZE},
-    scheme::{self, CallerCtx, FileHandle, KernelScheme, OpenResult, StrOrBytes},
+    scheme::{self, CallerCtx, FileHandle, KernelScheme, OpenResult, StrOrBytes, NO_OFFSET},
     sync::CleanLockToken,
     syscall::{data::Stat, error::*, flag::*},
 };
@@ -45,11 +45,7 @@ pub fn file_op_generic_ext<T>(
         (file, desc)
     };
 
-    let schemes_guard = scheme::schemes(&token.token());
-    let scheme = schemes_guard
-        .get(desc.scheme)
-        .ok_or(Error::new(EBADF))?;
-    let scheme_clone = Arc::clone(scheme) as Arc<dyn KernelScheme>;
+    let scheme_clone: Arc<dyn KernelScheme> = scheme::scheme(desc.scheme).ok_or(Error::new(EIO))?;
 
     op(&*scheme_clone, file.description, desc, token)
 }
@@ -77,10 +73,10 @@ fn is_legacy(path_buf: &String) -> bool {
 
 /// Open syscall
 pub fn open(raw_path: UserSliceRo, flags: usize, token: &mut CleanLockToken) -> Result<FileHandle> {
-    let (pid, uid, gid, scheme_ns) = {
+    let ((caller, flags), scheme_ns) = {
         let ctx = context::current();
         let cx = &ctx.read(token.token());
-        (cx.pid, cx.euid, cx.egid, cx.ens)
+        (cx.open_caller_ctx(flags), cx.ens)
     };
 
     // TODO: BorrowedHtBuf!
@@ -94,7 +90,7 @@ pub fn open(raw_path: UserSliceRo, flags: usize, token: &mut CleanLockToken) ->
     // Display a deprecation warning for any usage of the legacy scheme syntax (scheme:/path)
     // FIXME remove entries from this list as the respective programs get updated
     if path_buf.contains(':') && !is_legacy(&path_buf) {
-        let name = context::current().read(token.token()).name;
+        let name = context::current().read(token.token()).name.get();
         if path_buf == "event:" || path_buf.starts_with("time:") {
             // FIXME winit issues
         } else {
@@ -113,12 +109,12 @@ pub fn open(raw_path: UserSliceRo, flags: usize, token: &mut CleanLockToken) ->
             (scheme_id, Arc::clone(scheme) as Arc<dyn KernelScheme>)
         };
 
-        match scheme.kopen(
-            reference.as_ref(),
-            flags,
-            CallerCtx { uid, gid, pid },
-            token,
-        )? {
+        let opened = scheme_timed!(
+            scheme_id,
+            Open,
+            scheme.kopen(reference.as_ref(), flags, caller, token)
+        );
+        match opened? {
             OpenResult::SchemeLocal(number, internal_flags) => {
                 Arc::new(RwLock::new(FileDescription {
                     scheme: scheme_id,
@@ -138,7 +134,6 @@ pub fn open(raw_path: UserSliceRo, flags: usize, token: &mut CleanLockToken) ->
             description,
             cloexec: flags & O_CLOEXEC == O_CLOEXEC,
         })
-        .ok_or(Error::new(EMFILE))
 }
 
 pub const F_DUPFD_CLOEXEC: usize = 1030;
@@ -164,14 +159,13 @@ pub fn openat(
 
     let description = pipe.description.read();
 
-    let caller_ctx = context::current().read(token.token()).caller_ctx();
+    let (caller_ctx, flags) = context::current()
+        .read(token.token())
+        .open_caller_ctx(flags);
 
     let new_description = {
-        let schemes_guard = scheme::schemes(&token.token());
-        let scheme = schemes_guard
-            .get(description.scheme)
-            .ok_or(Error::new(EBADF))?;
-        let scheme_clone = Arc::clone(scheme) as Arc<dyn KernelScheme>;
+        let scheme_clone: Arc<dyn KernelScheme> =
+            scheme::scheme(description.scheme).ok_or(Error::new(EIO))?;
 
         let res = scheme_clone.kopenat(
             description.number,
@@ -182,18 +176,7 @@ pub fn openat(
             token,
         );
 
-        match res? {
-            OpenResult::SchemeLocal(number, internal_flags) => {
-                Arc::new(RwLock::new(FileDescription {
-                    offset: 0,
-                    internal_flags,
-                    scheme: description.scheme,
-                    number,
-                    flags: description.flags,
-                }))
-            }
-            OpenResult::External(desc) => desc,
-        }
+        derived_description(&description, res?)
     };
 
     context::current()
@@ -202,7 +185,6 @@ pub fn openat(
             description: new_description,
             cloexec: false,
         })
-        .ok_or(Error::new(EMFILE))
 }
 /// rmdir syscall
 pub fn rmdir(raw_path: UserSliceRo, token: &mut CleanLockToken) -> Result<()> {
@@ -266,6 +248,29 @@ pub fn close(fd: FileHandle, token: &mut CleanLockToken) -> Result<()> {
     file.close(token)
 }
 
+/// The description of a file a scheme opened through another, by openat or dup. A handle local to
+/// the scheme gets the open flags of `parent` but the internal flags the scheme returned, as the
+/// new handle may be positioned differently, and starts at offset 0. A description passed back
+/// by a user scheme is used as is.
+fn derived_description(
+    parent: &FileDescription,
+    result: OpenResult,
+) -> Arc<RwLock<FileDescription>> {
+    match result {
+        OpenResult::SchemeLocal(number, internal_flags) => Arc::new(RwLock::new(FileDescription {
+            offset: 0,
+            internal_flags,
+            scheme: parent.scheme,
+            number,
+            flags: parent.flags,
+        })),
+        OpenResult::External(desc) => desc,
+    }
+}
+
+/// Duplicate `fd` into a new descriptor, not yet in the file table. An empty `user_buf` shares
+/// the description, and with it the offset and flags. Otherwise the scheme interprets `user_buf`
+/// and opens a new description. Either way `cloexec` only applies to the new descriptor.
 fn duplicate_file(
     fd: FileHandle,
     user_buf: UserSliceRo,
@@ -290,24 +295,11 @@ fn duplicate_file(
         let description = { *file.description.read() };
 
         let new_description = {
-            let schemes_guard = scheme::schemes(&token.token());
-            let scheme = schemes_guard
-                .get(description.scheme)
-                .ok_or(Error::new(EBADF))?;
-            let scheme_clone = Arc::clone(scheme) as Arc<dyn KernelScheme>;
-
-            match scheme_clone.kdup(description.number, user_buf, caller_ctx, token)? {
-                OpenResult::SchemeLocal(number, internal_flags) => {
-                    Arc::new(RwLock::new(FileDescription {
-                        offset: 0,
-                        internal_flags,
-                        scheme: description.scheme,
-                        number,
-                        flags: description.flags,
-                    }))
-                }
-                OpenResult::External(desc) => desc,
-            }
+            let scheme_clone: Arc<dyn KernelScheme> =
+                scheme::scheme(description.scheme).ok_or(Error::new(EIO))?;
+
+            let result = scheme_clone.kdup(description.number, user_buf, caller_ctx, token)?;
+            derived_description(&description, result)
         };
 
         Ok(FileDescriptor {
@@ -319,12 +311,7 @@ fn duplicate_file(
 
 /// Duplicate file descriptor
 pub fn dup(fd: FileHandle, buf: UserSliceRo, token: &mut CleanLockToken) -> Result<FileHandle> {
-    let new_file = duplicate_file(fd, buf, false, token)?;
-
-    context::current()
-        .read(token.token())
-        .add_file(new_file)
-        .ok_or(Error::new(EMFILE))
+    dup3(fd, None, 0, buf, token)
 }
 
 /// Duplicate file descriptor, replacing another
@@ -337,17 +324,50 @@ pub fn dup2(
     if fd == new_fd {
         Ok(new_fd)
     } else {
-        let _ = close(new_fd, token);
-        let new_file = duplicate_file(fd, buf, false, token)?;
+        dup3(fd, Some(new_fd), 0, buf, token)
+    }
+}
 
-        let context_ref = context::current();
-        let context = context_ref.read(token.token());
+/// Duplicate file descriptor into `new_fd`, replacing what is there, or into the lowest free slot
+/// if it is `None`. With `O_CLOEXEC` in `flags`, the new descriptor is close-on-exec from the
+/// moment it appears in the file table.
+pub fn dup3(
+    fd: FileHandle,
+    new_fd: Option<FileHandle>,
+    flags: usize,
+    buf: UserSliceRo,
+    token: &mut CleanLockToken,
+) -> Result<FileHandle> {
+    if flags & !O_CLOEXEC != 0 || new_fd == Some(fd) {
+        return Err(Error::new(EINVAL));
+    }
+    let new_file = duplicate_file(fd, buf, flags & O_CLOEXEC == O_CLOEXEC, token)?;
 
-        context
-            .insert_file(new_fd, new_file)
-            .ok_or(Error::new(EMFILE))
+    // The table gets a copy, so that the new file can still be closed if adding it fails
+    let result = match new_fd {
+        None => context::current()
+            .read(token.token())
+            .add_file(new_file.clone()),
+        Some(new_fd) => {
+            // The old file is only closed once the new one could be made
+            let (replaced, result) = {
+                let context_ref = context::current();
+                let context = context_ref.read(token.token());
+                let replaced = context.remove_file(new_fd);
+                (replaced, context.insert_file(new_fd, new_file.clone()))
+            };
+            if let Some(replaced) = replaced {
+                let _ = replaced.close(token);
+            }
+            result
+        }
+    };
+    if result.is_err() {
+        let _ = new_file.close(token);
     }
+    result
 }
+
 pub fn call(
     fd: FileHandle,
     payload: UserSliceRw,
@@ -355,7 +375,10 @@ pub fn call(
     metadata: UserSliceRo,
     token: &mut CleanLockToken,
 ) -> Result<usize> {
-    let mut meta = [0_u64; 3];
+    let mut meta = [0_u64; scheme::user::MAX_CALL_METADATA];
+    if metadata.len() > size_of::<u64>() * meta.len() {
+        return Err(Error::new(EINVAL));
+    }
 
     // TODO: bytemuck/plain
     let copied = metadata.copy_common_bytes_to_slice(unsafe {
@@ -393,11 +416,7 @@ fn call_normal(
         let desc = file.description.read();
         (desc.scheme, desc.number)
     };
-    let schemes_guard = scheme::schemes(&token.token());
-    let scheme = schemes_guard
-        .get(scheme_id)
-        .ok_or(Error::new(EBADFD))?;
-    let scheme_clone = Arc::clone(scheme) as Arc<dyn KernelScheme>;
+    let scheme_clone: Arc<dyn KernelScheme> = scheme::scheme(scheme_id).ok_or(Error::new(EIO))?;
 
     scheme_clone.kcall(number, payload, flags, metadata, token)
 }
@@ -441,11 +460,7 @@ fn fdwrite_inner(
             let desc = &file_descriptor.description.read();
             (desc.scheme, desc.number)
         };
-        let schemes_guard = scheme::schemes(&token.token());
-        let scheme = schemes_guard
-            .get(scheme)
-            .ok_or(Error::new(ENODEV))?;
-        let scheme_clone = Arc::clone(scheme) as Arc<dyn KernelScheme>;
+        let scheme_clone: Arc<dyn KernelScheme> = scheme::scheme(scheme).ok_or(Error::new(EIO))?;
 
         let current_lock = context::current();
         let current = current_lock.read(token.token());
@@ -496,11 +511,7 @@ fn call_fdread(
             let desc = file_descriptor.description.read();
             (desc.scheme, desc.number)
         };
-        let schemes_guard = scheme::schemes(&token.token());
-        let scheme = schemes_guard
-            .get(scheme)
-            .ok_or(Error::new(ENODEV))?;
-        let scheme_clone = Arc::clone(scheme) as Arc<dyn KernelScheme>;
+        let scheme_clone: Arc<dyn KernelScheme> = scheme::scheme(scheme).ok_or(Error::new(EIO))?;
 
         (scheme_clone, number)
     };
@@ -542,19 +553,13 @@ pub fn fcntl(fd: FileHandle, cmd: usize, arg: usize, token: &mut CleanLockToken)
         let context_lock = context::current();
         let context = context_lock.read(token.token());
 
-        return context
-            .add_file_min(new_file, arg)
-            .ok_or(Error::new(EMFILE))
-            .map(FileHandle::into);
+        return context.add_file_min(new_file, arg).map(FileHandle::into);
     }
 
     // Communicate fcntl with scheme
     if cmd != F_GETFD && cmd != F_SETFD {
-        let schemes_guard = scheme::schemes(&token.token());
-        let scheme = schemes_guard
-            .get(description.scheme)
-            .ok_or(Error::new(EBADF))?;
-        let scheme_clone = Arc::clone(scheme) as Arc<dyn KernelScheme>;
+        let scheme_clone: Arc<dyn KernelScheme> =
+            scheme::scheme(description.scheme).ok_or(Error::new(EIO))?;
 
         scheme_clone.fcntl(description.number, cmd, arg, token)?;
     };
@@ -565,8 +570,8 @@ pub fn fcntl(fd: FileHandle, cmd: usize, arg: usize, token: &mut CleanLockToken)
         let context = context_lock.read(token.token());
 
         let mut files = context.files.write();
-        match *files.get_mut(fd.get()).ok_or(Error::new(EBADF))? {
-            Some(ref mut file) => match cmd {
+        match files.get_mut(fd.get()) {
+            Some(file) => match cmd {
                 F_GETFD => {
                     if file.cloexec {
                         Ok(O_CLOEXEC)
@@ -593,7 +598,16 @@ pub fn fcntl(fd: FileHandle, cmd: usize, arg: usize, token: &mut CleanLockToken)
     }
 }
 
-pub fn flink(fd: FileHandle, raw_path: UserSliceRo, token: &mut CleanLockToken) -> Result<()> {
+/// Run `op` on the scheme of `fd` with the reference of `raw_path` within that scheme
+///
+/// Links and renames cannot cross schemes, so this fails with EXDEV before calling into the
+/// scheme if `raw_path` names another one.
+fn same_scheme_path_op(
+    fd: FileHandle,
+    raw_path: UserSliceRo,
+    token: &mut CleanLockToken,
+    op: impl FnOnce(&dyn KernelScheme, usize, &str, CallerCtx, &mut CleanLockToken) -> Result<()>,
+) -> Result<()> {
     let (caller_ctx, scheme_ns) = {
         let ctx = context::current();
         let cx = &ctx.read(token.token());
@@ -604,10 +618,6 @@ pub fn flink(fd: FileHandle, raw_path: UserSliceRo, token: &mut CleanLockToken)
         .get_file(fd)
         .ok_or(Error::new(EBADF))?;
 
-    /*
-    let mut path_buf = BorrowedHtBuf::head()?;
-    let path = path_buf.use_for_string(raw_path)?;
-    */
     let path_buf = copy_path_to_buf(raw_path, PATH_MAX)?;
     let path = RedoxPath::from_absolute(&path_buf).ok_or(Error::new(EINVAL))?;
     let (scheme_name, reference) = path.as_parts().ok_or(Error::new(EINVAL))?;
@@ -620,49 +630,30 @@ pub fn flink(fd: FileHandle, raw_path: UserSliceRo, token: &mut CleanLockToken)
         (scheme_id, Arc::clone(scheme) as Arc<dyn KernelScheme>)
     };
 
-    let description = file.description.read();
-
-    if scheme_id != description.scheme {
+    // Copy the description out, user schemes block until their daemon responds
+    let (description_scheme, number) = {
+        let description = file.description.read();
+        (description.scheme, description.number)
+    };
+    if scheme_id != description_scheme {
         return Err(Error::new(EXDEV));
     }
 
-    scheme.flink(description.number, reference.as_ref(), caller_ctx, token)
+    op(&*scheme, number, reference.as_ref(), caller_ctx, token)
 }
 
-pub fn frename(fd: FileHandle, raw_path: UserSliceRo, token: &mut CleanLockToken) -> Result<()> {
-    let (caller_ctx, scheme_ns) = {
-        let ctx = context::current();
-        let cx = &ctx.read(token.token());
-        (cx.caller_ctx(), cx.ens)
-    };
-    let file = context::current()
-        .read(token.token())
-        .get_file(fd)
-        .ok_or(Error::new(EBADF))?;
-
-    /*
-    let mut path_buf = BorrowedHtBuf::head()?;
-    let path = path_buf.use_for_string(raw_path)?;
-    */
-    let path_buf = copy_path_to_buf(raw_path, PATH_MAX)?;
-    let path = RedoxPath::from_absolute(&path_buf).ok_or(Error::new(EINVAL))?;
-    let (scheme_name, reference) = path.as_parts().ok_or(Error::new(EINVAL))?;
-
-    let (scheme_id, scheme) = {
-        let schemes_guard = scheme::schemes(&token.token());
-        let (scheme_id, scheme) = schemes_guard
-            .get_name(scheme_ns, scheme_name.as_ref())
-            .ok_or(Error::new(ENODEV))?;
-        (scheme_id, Arc::clone(scheme) as Arc<dyn KernelScheme>)
-    };
-
-    let description = file.description.read();
-
-    if scheme_id != description.scheme {
-        return Err(Error::new(EXDEV));
-    }
+/// Create a hard link to the file of `fd` at `raw_path`
+pub fn flink(fd: FileHandle, raw_path: UserSliceRo, token: &mut CleanLockToken) -> Result<()> {
+    same_scheme_path_op(fd, raw_path, token, |scheme, number, path, ctx, token| {
+        scheme.flink(number, path, ctx, token)
+    })
+}
 
-    scheme.frename(description.number, reference.as_ref(), caller_ctx, token)
+/// Atomically move the file of `fd` to `raw_path`, replacing any file already there
+pub fn frename(fd: FileHandle, raw_path: UserSliceRo, token: &mut CleanLockToken) -> Result<()> {
+    same_scheme_path_op(fd, raw_path, token, |scheme, number, path, ctx, token| {
+        scheme.frename(number, path, ctx, token)
+    })
 }
 
 /// File status
@@ -703,13 +694,18 @@ pub fn funmap(virtual_address: usize, length: usize, token: &mut CleanLockToken)
         );
     }
 
-    let addr_space = Arc::clone(context::current().read(token.token()).addr_space()?);
+    let current = context::current();
+    let addr_space = Arc::clone(current.read(token.token()).addr_space()?);
     let span = PageSpan::validate_nonempty(VirtualAddress::new(virtual_address), length_aligned)
         .ok_or(Error::new(EINVAL))?;
     let unpin = false;
-    let notify = addr_space.munmap(span, unpin)?;
+    let notify = addr_space.munmap_for(span, unpin, &current, token)?;
 
     for map in notify {
+        // The provider gets to write back before the frames go, but cannot keep them if it fails
+        if let Some(unmapped) = map.unmap_result(MunmapFlags::NEEDS_SYNC) {
+            let _ = unmapped.unmap(token);
+        }
         let _ = map.unmap();
     }
 
@@ -762,21 +758,24 @@ pub fn mremap(
             return Err(Error::new(EOPNOTSUPP));
         }
 
-        let raii_frame = addr_space.acquire_write().borrow_frame_enforce_rw_allocated(src_span.base, token)?;
+        let raii_frame = addr_space
+            .acquire_write()
+            .borrow_frame_enforce_rw_allocated(src_span.base, token)?;
 
+        let mut notify_files = Vec::new();
         let base = addr_space.acquire_write().mmap(
             requested_dst_base,
             NonZeroUsize::new(1).unwrap(),
             map_flags,
-            &mut Vec::new(),
+            &mut notify_files,
             |page, page_flags, mapper, flusher| {
                 let frame = raii_frame.take();
                 // XXX: add_ref(RefKind::Shared) is internally done by borrow_frame_enforce_rw_allocated(src_span.base).
                 // The page does not get unref-ed as we call take() on the `raii_frame`.
                 unsafe {
                     mapper
-                            .get_mut()
-                            .expect("failed to get mutable mapper")
+                        .get_mut()
+                        .expect("failed to get mutable mapper")
                         .map_phys(page.start_address(), frame.base(), page_flags)
                         .ok_or(Error::new(ENOMEM))?
                         .ignore();
@@ -787,6 +786,7 @@ pub fn mremap(
                 Ok(Grant::allocated_one_page_nomap(page, page_flags))
             },
         )?;
+        handle_notify_files(notify_files, token);
 
         Ok(base.start_address().data())
     } else {
@@ -803,6 +803,36 @@ pub fn mremap(
     }
 }
 
+/// Offset of the next plain read or write, or `NO_OFFSET` if the scheme keeps no position
+fn current_offset(desc: &FileDescription) -> u64 {
+    if desc.internal_flags.contains(InternalFlags::POSITIONED) {
+        desc.offset
+    } else {
+        NO_OFFSET
+    }
+}
+
+/// Advance the shared offset past what a plain read or write transferred
+fn advance_offset(desc_arc: &RwLock<FileDescription>, desc: &FileDescription, count: usize) {
+    if desc.internal_flags.contains(InternalFlags::POSITIONED) {
+        let offset = &mut desc_arc.write().offset;
+        *offset = offset.saturating_add(count as u64)
+    }
+}
+
+/// Offset given to pread or pwrite, which only positioned descriptions accept
+fn explicit_offset(desc: &FileDescription, offset: u64) -> Result<u64> {
+    if !desc.internal_flags.contains(InternalFlags::POSITIONED) {
+        return Err(Error::new(ESPIPE));
+    }
+    if offset == NO_OFFSET {
+        return Err(Error::new(EINVAL));
+    }
+    Ok(offset)
+}
+
+/// Reposition a description. The offset is kept here for positioned descriptions; otherwise only
+/// legacy user schemes that track their own may seek, and everything else fails with ESPIPE.
 pub fn lseek(fd: FileHandle, pos: i64, whence: usize, token: &mut CleanLockToken) -> Result<usize> {
     enum Ret {
         Legacy(usize),
@@ -812,6 +842,8 @@ pub fn lseek(fd: FileHandle, pos: i64, whence: usize, token: &mut CleanLockToken
         Ok(
             if let Some(new_off) = scheme.legacy_seek(desc.number, pos as isize, whence, token) {
                 Ret::Legacy(new_off?)
+            } else if !desc.internal_flags.contains(InternalFlags::POSITIONED) {
+                return Err(Error::new(ESPIPE));
             } else if whence == SEEK_END {
                 Ret::Fsize((Some(scheme.fsize(desc.number, token)?), desc_arc))
             } else {
@@ -843,66 +875,114 @@ pub fn lseek(fd: FileHandle, pos: i64, whence: usize, token: &mut CleanLockToken
 pub fn sys_read(fd: FileHandle, buf: UserSliceWo, token: &mut CleanLockToken) -> Result<usize> {
     let (bytes_read, desc_arc, desc) =
         file_op_generic_ext(fd, token, |scheme, desc_arc, desc, token| {
-            let offset = if desc.internal_flags.contains(InternalFlags::POSITIONED) {
-                desc.offset
-            } else {
-                u64::MAX
-            };
+            let offset = current_offset(&desc);
             Ok((
-                scheme.kreadoff(desc.number, buf, offset, desc.flags, desc.flags, token)?,
+                scheme_timed!(
+                    desc.scheme,
+                    Read,
+                    scheme.kreadoff(desc.number, buf, offset, desc.flags, desc.flags, token)
+                )?,
                 desc_arc,
                 desc,
             ))
         })?;
-    if desc.internal_flags.contains(InternalFlags::POSITIONED) {
-        let offset = &mut desc_arc.write().offset;
-        *offset = offset.saturating_add(bytes_read as u64)
-    }
+    advance_offset(&desc_arc, &desc, bytes_read);
     Ok(bytes_read)
 }
 pub fn sys_write(fd: FileHandle, buf: UserSliceRo, token: &mut CleanLockToken) -> Result<usize> {
     let (bytes_written, desc_arc, desc) =
         file_op_generic_ext(fd, token, |scheme, desc_arc, desc, token| {
-            let offset = if desc.internal_flags.contains(InternalFlags::POSITIONED) {
-                desc.offset
-            } else {
-                u64::MAX
-            };
+            let offset = current_offset(&desc);
             Ok((
-                scheme.kwriteoff(desc.number, buf, offset, desc.flags, desc.flags, token)?,
+                scheme_timed!(
+                    desc.scheme,
+                    Write,
+                    scheme.kwriteoff(desc.number, buf, offset, desc.flags, desc.flags, token)
+                )?,
                 desc_arc,
                 desc,
             ))
         })?;
-    if desc.internal_flags.contains(InternalFlags::POSITIONED) {
-        let offset = &mut desc_arc.write().offset;
-        *offset = offset.saturating_add(bytes_written as u64)
-    }
+    advance_offset(&desc_arc, &desc, bytes_written);
     Ok(bytes_written)
 }
+/// Read at `offset` without moving the description's offset
+pub fn sys_pread(
+    fd: FileHandle,
+    buf: UserSliceWo,
+    offset: u64,
+    token: &mut CleanLockToken,
+) -> Result<usize> {
+    file_op_generic_ext(fd, token, |scheme, _, desc, token| {
+        let offset = explicit_offset(&desc, offset)?;
+        scheme_timed!(
+            desc.scheme,
+            Read,
+            scheme.kreadoff(desc.number, buf, offset, desc.flags, desc.flags, token)
+        )
+    })
+}
+/// Write at `offset` without moving the description's offset
+pub fn sys_pwrite(
+    fd: FileHandle,
+    buf: UserSliceRo,
+    offset: u64,
+    token: &mut CleanLockToken,
+) -> Result<usize> {
+    file_op_generic_ext(fd, token, |scheme, _, desc, token| {
+        let offset = explicit_offset(&desc, offset)?;
+        scheme_timed!(
+            desc.scheme,
+            Write,
+            scheme.kwriteoff(desc.number, buf, offset, desc.flags, desc.flags, token)
+        )
+    })
+}
+
+/// The pages overlapping `len` bytes at `addr`, as mlock and munlock take them
+fn lock_span(addr: usize, len: usize) -> Result<PageSpan> {
+    if len == 0 {
+        return Ok(PageSpan::empty());
+    }
+    let last = addr
+        .checked_add(len.saturating_sub(1))
+        .ok_or(Error::new(ENOMEM))?;
+    let base = Page::containing_address(VirtualAddress::new(addr));
+    let end = Page::containing_address(VirtualAddress::new(last)).next();
+    Ok(PageSpan::new(base, end.offset_from(base)))
+}
 
 /// mlock syscall
 pub fn sys_mlock(addr: usize, len: usize, token: &mut CleanLockToken) -> Result<usize> {
+    let span = lock_span(addr, len)?;
     let current_context_ref = context::current();
-    let addr_space = Arc::clone(current_context_ref.read(token.token()).addr_space()?);
-
-    addr_space.acquire_write().mlock(VirtualAddress::new(addr), len)?;
+    let (addr_space, limit) = {
+        let context = current_context_ref.read(token.token());
+        (Arc::clone(context.addr_space()?), context.lock_limit())
+    };
 
-    let count = current_context_ref.read(token.token()).memory_locked_count;
-    current_context_ref.write(token.token()).memory_locked_count = count.saturating_add(1);
+    let locked_pages = {
+        let mut addr_space = addr_space.acquire_write();
+        addr_space.mlock(span.base, span.count, limit)?;
+        addr_space.locked_pages()
+    };
+    current_context_ref.write(token.token()).memory_locked_count = locked_pages;
 
     Ok(0)
 }
 
 /// munlock syscall
 pub fn sys_munlock(addr: usize, len: usize, token: &mut CleanLockToken) -> Result<usize> {
+    let span = lock_span(addr, len)?;
     let current_context_ref = context::current();
     let addr_space = Arc::clone(current_context_ref.read(token.token()).addr_space()?);
 
-    addr_space.acquire_write().munlock(VirtualAddress::new(addr), len)?;
-
-    let count = current_context_ref.read(token.token()).memory_locked_count;
-    current_context_ref.write(token.token()).memory_locked_count = count.saturating_sub(1);
+    let locked_pages = {
+        let mut addr_space = addr_space.acquire_write();
+        addr_space.munlock(span.base, span.count)?;
+        addr_space.locked_pages()
+    };
+    current_context_ref.write(token.token()).memory_locked_count = locked_pages;
 
     Ok(0)
 }
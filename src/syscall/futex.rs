@@ -2,12 +2,8 @@
 //! Futex or Fast Userspace Mutex is "a method for waiting until a certain condition becomes true."
 //!
 //! For more information about futexes, please read [this](https://eli.thegreenplace.net/2018/basics-of-futexes/) blog post, and the [futex(2)](http://man7.org/linux/man-pages/man2/futex.2.html) man page
-use alloc::{
-    sync::{Arc, Weak},
-    vec::Vec,
-};
+use alloc::{sync::Arc, vec::Vec};
 use core::sync::atomic::{AtomicU32, Ordering};
-use hashbrown::{hash_map::DefaultHashBuilder, HashMap};
 use rmm::Arch;
 use syscall::EINTR;
 
@@ -15,43 +11,64 @@ use crate::{
     context::{
         self,
         memory::{AddrSpace, AddrSpaceInner, AddrSpaceWrapper},
-        ContextLock,
+        timeout, ContextLock,
     },
     memory::PhysicalAddress,
     paging::{Page, VirtualAddress},
-    sync::{CleanLockToken, OrderedMutex, L1},
+    scheduler,
+    sync::{CleanLockToken, WaitQueue, Waitable},
     time,
 };
 
 use crate::syscall::{
     data::TimeSpec,
     error::{Error, Result, EAGAIN, EFAULT, EINVAL, ETIMEDOUT},
-    flag::{FUTEX_WAIT, FUTEX_WAIT64, FUTEX_WAKE},
+    flag::{CLOCK_MONOTONIC, FUTEX_WAIT, FUTEX_WAIT64, FUTEX_WAKE},
 };
 
 use super::usercopy::UserSlice;
 
-// Physical address used as key, required if synchronizing across address spaces
-// (necessitates MAP_SHARED since CoW would invalidate this address).
-type FutexList = HashMap<PhysicalAddress, Vec<FutexEntry>>;
+/// Number of hash buckets waiters are spread over
+const FUTEX_BUCKET_COUNT: usize = 64;
+
+/// Identifies a futex word. The virtual address is used rather than the physical one, so that a
+/// CoW fault on the page does not separate waiters from wakers.
+// TODO: Process-shared futexes, which need a key that is stable across address spaces.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct FutexKey {
+    addr_space: usize,
+    virt: VirtualAddress,
+}
+
+impl FutexKey {
+    fn new(addr_space: &Arc<AddrSpaceWrapper>, virt: VirtualAddress) -> Self {
+        Self {
+            addr_space: Arc::as_ptr(addr_space) as usize,
+            virt,
+        }
+    }
+
+    fn bucket_index(&self) -> usize {
+        let hash = (self.addr_space >> 4) ^ (self.virt.data() >> 2);
+        (hash ^ (hash >> 6) ^ (hash >> 12)) % FUTEX_BUCKET_COUNT
+    }
+
+    fn bucket(&self) -> &'static WaitQueue<FutexEntry> {
+        &FUTEX_BUCKETS[self.bucket_index()]
+    }
+}
 
+#[derive(Debug)]
 pub struct FutexEntry {
-    // Virtual address, required if synchronizing across the same address space, if the memory is
-    // CoW.
-    // TODO: FUTEX_REQUEUE
-    target_virtaddr: VirtualAddress,
-    // Context to wake up, and compare address spaces.
+    key: FutexKey,
+    // Context to wake up
     context_lock: Arc<ContextLock>,
-    // address space to check against if virt matches but not phys
-    addr_space: Weak<AddrSpaceWrapper>,
 }
 
-// TODO: Process-private futexes? In that case, put the futex table in each AddrSpace, or just
-// implement that fully in userspace. Although futex is probably the best API for process-shared
-// POSIX synchronization primitives, a local hash table and wait-for-thread kernel APIs (e.g.
-// lwp_park/lwp_unpark from NetBSD) could be a simpler replacement.
-static FUTEXES: OrderedMutex<L1, FutexList> =
-    OrderedMutex::new(FutexList::with_hasher(DefaultHashBuilder::new()));
+// Only the queue of each bucket is used; its lock is what makes the value check in WAIT atomic
+// with respect to WAKE. The condition would wake every waiter in the bucket at once.
+static FUTEX_BUCKETS: [WaitQueue<FutexEntry>; FUTEX_BUCKET_COUNT] =
+    [const { WaitQueue::new() }; FUTEX_BUCKET_COUNT];
 
 fn validate_and_translate_virt(
     space: &AddrSpaceInner,
@@ -70,6 +87,98 @@ fn validate_and_translate_virt(
     Some(phys.add(off))
 }
 
+/// Atomically load the futex word at `phys`, 64 bits wide if `wide` is set.
+///
+/// The word must be naturally aligned, so that it cannot cross into another page.
+fn load_futex(phys: PhysicalAddress, wide: bool) -> Result<u64> {
+    // On systems where virtual memory is not abundant, we might instead add an atomic usercopy
+    // function.
+    let accessible_addr = unsafe { crate::paging::RmmA::phys_to_virt(phys) }.data();
+
+    if !wide {
+        return Ok(u64::from(unsafe {
+            (*(accessible_addr as *const AtomicU32)).load(Ordering::SeqCst)
+        }));
+    }
+    #[cfg(target_has_atomic = "64")]
+    {
+        use core::sync::atomic::AtomicU64;
+
+        Ok(unsafe { (*(accessible_addr as *const AtomicU64)).load(Ordering::SeqCst) })
+    }
+    #[cfg(not(target_has_atomic = "64"))]
+    {
+        Err(Error::new(crate::syscall::error::EOPNOTSUPP))
+    }
+}
+
+fn read_deadline(addr: usize) -> Result<Option<u128>> {
+    let timeout_opt = UserSlice::ro(addr, core::mem::size_of::<TimeSpec>())?
+        .none_if_null()
+        .map(|buf| unsafe { buf.read_exact::<TimeSpec>() })
+        .transpose()?;
+
+    Ok(timeout_opt
+        .map(|TimeSpec { tv_sec, tv_nsec }| tv_sec as u128 * time::NANOS_PER_SEC + tv_nsec as u128))
+}
+
+/// Block the current context until it is woken or `deadline` passes.
+///
+/// Must be called with the context already queued in its buckets, and the bucket locks released.
+fn sleep(context_lock: &Arc<ContextLock>, deadline: Option<u128>, token: &mut CleanLockToken) {
+    let weak = Arc::downgrade(context_lock);
+    if let Some(deadline) = deadline {
+        timeout::register_wakeup(weak.clone(), CLOCK_MONOTONIC, deadline, token);
+    }
+
+    unsafe { context::switch(token) };
+
+    if deadline.is_some() {
+        timeout::cancel_wakeup(&weak, token);
+        context_lock.write(token.token()).wake = None;
+    }
+}
+
+/// Remove the entry of `context_lock` for `key`, returning true if it was still queued, i.e. no
+/// WAKE got to it first.
+fn dequeue(key: FutexKey, context_lock: &Arc<ContextLock>) -> bool {
+    let mut bucket = key.bucket().inner.lock();
+    let old_len = bucket.len();
+    bucket.retain(|waiter| {
+        let entry = waiter.as_ref();
+        entry.key != key || !Arc::ptr_eq(&entry.context_lock, context_lock)
+    });
+    bucket.len() != old_len
+}
+
+/// The error for a waiter nobody woke: either its deadline passed, or it was interrupted.
+fn not_woken(deadline: Option<u128>) -> Error {
+    match deadline {
+        Some(deadline) if time::monotonic() >= deadline => Error::new(ETIMEDOUT),
+        _ => Error::new(EINTR),
+    }
+}
+
+/// Mark the current context blocked, unless a signal is already pending.
+fn block_current(
+    context_lock: &Arc<ContextLock>,
+    deadline: Option<u128>,
+    reason: &'static str,
+    token: &mut CleanLockToken,
+) -> Result<()> {
+    let mut context = context_lock.write(token.token());
+
+    if let Some((tctl, pctl, _)) = context.sigcontrol() {
+        if tctl.currently_pending_unblocked(pctl) != 0 {
+            return Err(Error::new(EINTR));
+        }
+    }
+    // Makes the timer fire in time for the wakeup registered in `sleep`
+    context.wake = deadline;
+    context.block(reason);
+    Ok(())
+}
+
 pub fn futex(
     addr: usize,
     op: usize,
@@ -79,139 +188,80 @@ pub fn futex(
     token: &mut CleanLockToken,
 ) -> Result<usize> {
     let current_addrsp = AddrSpace::current(token)?;
-
-    // Keep the address space locked so we can safely read from the physical address. Unlock it
-    // before context switching.
-    let addr_space_guard = current_addrsp.acquire_read();
-
     let target_virtaddr = VirtualAddress::new(addr);
-    let target_physaddr = validate_and_translate_virt(&addr_space_guard, target_virtaddr)
-        .ok_or(Error::new(EFAULT))?;
+    let key = FutexKey::new(&current_addrsp, target_virtaddr);
 
     match op {
         // TODO: FUTEX_WAIT_MULTIPLE?
         FUTEX_WAIT | FUTEX_WAIT64 => {
-            let timeout_opt = UserSlice::ro(val2, core::mem::size_of::<TimeSpec>())?
-                .none_if_null()
-                .map(|buf| unsafe { buf.read_exact::<TimeSpec>() })
-                .transpose()?;
+            let wide = op == FUTEX_WAIT64;
+            // Must be aligned, otherwise it could cross a page boundary and mess up the (simpler)
+            // validation below.
+            if addr % if wide { 8 } else { 4 } != 0 {
+                return Err(Error::new(EINVAL));
+            }
+            let deadline = read_deadline(val2)?;
+            let context_lock = context::current();
 
             {
-                let mut futexes = FUTEXES.lock(token.token());
-                let (futexes, mut token) = futexes.token_split();
-
-                let context_lock = context::current();
-
-                let (fetched, expected) = if op == FUTEX_WAIT {
-                    // Must be aligned, otherwise it could cross a page boundary and mess up the
-                    // (simpler) validation we did in the first place.
-                    if addr % 4 != 0 {
-                        return Err(Error::new(EINVAL));
-                    }
-
-                    // On systems where virtual memory is not abundant, we might instead add an
-                    // atomic usercopy function.
-                    let accessible_addr =
-                        unsafe { crate::paging::RmmA::phys_to_virt(target_physaddr) }.data();
-
-                    (
-                        u64::from(unsafe {
-                            (*(accessible_addr as *const AtomicU32)).load(Ordering::SeqCst)
-                        }),
-                        u64::from(val as u32),
-                    )
+                // Keep the address space locked so we can safely read from the physical address.
+                // Unlock it before context switching.
+                let addr_space_guard = current_addrsp.acquire_read();
+                let target_physaddr =
+                    validate_and_translate_virt(&addr_space_guard, target_virtaddr)
+                        .ok_or(Error::new(EFAULT))?;
+
+                // Held across both the check and the enqueue: a WAKE racing with us either finds
+                // this waiter, or ran before the check and so saw the value change first.
+                let mut bucket = key.bucket().inner.lock();
+
+                let expected = if wide {
+                    val as u64
                 } else {
-                    #[cfg(target_has_atomic = "64")]
-                    {
-                        use core::sync::atomic::AtomicU64;
-
-                        // op == FUTEX_WAIT64
-                        if addr % 8 != 0 {
-                            return Err(Error::new(EINVAL));
-                        }
-                        (
-                            unsafe { (*(addr as *const AtomicU64)).load(Ordering::SeqCst) },
-                            val as u64,
-                        )
-                    }
-                    #[cfg(not(target_has_atomic = "64"))]
-                    {
-                        return Err(Error::new(crate::syscall::error::EOPNOTSUPP));
-                    }
+                    u64::from(val as u32)
                 };
-                if fetched != expected {
+                if load_futex(target_physaddr, wide)? != expected {
                     return Err(Error::new(EAGAIN));
                 }
 
-                {
-                    let mut context = context_lock.write(token.token());
-
-                    context.wake = timeout_opt.map(|TimeSpec { tv_sec, tv_nsec }| {
-                        tv_sec as u128 * time::NANOS_PER_SEC + tv_nsec as u128
-                    });
-                    if let Some((tctl, pctl, _)) = context.sigcontrol() {
-                        if tctl.currently_pending_unblocked(pctl) != 0 {
-                            return Err(Error::new(EINTR));
-                        }
-                    }
-
-                    context.block("futex");
-                }
-
-                futexes
-                    .entry(target_physaddr)
-                    .or_insert_with(|| Vec::new())
-                    .push(FutexEntry {
-                        target_virtaddr,
-                        context_lock,
-                        addr_space: Arc::downgrade(&current_addrsp),
-                    });
+                block_current(&context_lock, deadline, "futex", token)?;
+                bucket.push_back(Waitable::new(FutexEntry {
+                    key,
+                    context_lock: Arc::clone(&context_lock),
+                }));
             }
 
-            drop(addr_space_guard);
+            sleep(&context_lock, deadline, token);
 
-            unsafe { context::switch(token) };
-
-            if timeout_opt.is_some() {
-                context::current().write(token.token()).wake = None;
-                Err(Error::new(ETIMEDOUT))
-            } else {
-                Ok(0)
+            if dequeue(key, &context_lock) {
+                return Err(not_woken(deadline));
             }
+            Ok(0)
         }
         FUTEX_WAKE => {
-            let mut woken = 0;
+            validate_and_translate_virt(&current_addrsp.acquire_read(), target_virtaddr)
+                .ok_or(Error::new(EFAULT))?;
 
-            {
-                let mut futexes_map = FUTEXES.lock(token.token());
-                let (futexes_map, mut token) = futexes_map.token_split();
-
-                let is_empty = if let Some(futexes) = futexes_map.get_mut(&target_physaddr) {
-                    let mut i = 0;
-                    let current_addrsp_weak = Arc::downgrade(&current_addrsp);
-
-                    // TODO: Use something like retain, once it is possible to tell it when to stop iterating...
-                    while i < futexes.len() && woken < val {
-                        if futexes[i].target_virtaddr != target_virtaddr
-                            || !current_addrsp_weak.ptr_eq(&futexes[i].addr_space)
-                        {
-                            i += 1;
-                            continue;
-                        }
-                        futexes[i].context_lock.write(token.token()).unblock();
-                        futexes.swap_remove(i);
-                        woken += 1;
-                    }
-
-                    futexes.is_empty()
-                } else {
-                    false
-                };
-                if is_empty {
-                    futexes_map.remove(&target_physaddr);
+            let mut woken = 0;
+            let mut unblocked = Vec::new();
+            let mut bucket = key.bucket().inner.lock();
+            bucket.retain(|waiter| {
+                let entry = waiter.as_ref();
+                if woken >= val || entry.key != key {
+                    return true;
                 }
-            }
+                if entry.context_lock.write(token.token()).unblock() {
+                    unblocked.push(Arc::clone(&entry.context_lock));
+                }
+                woken += 1;
+                false
+            });
+            drop(bucket);
 
+            // Queued once the bucket is unlocked, as waking may lock the scheduler
+            for context_lock in unblocked {
+                scheduler::wake(context_lock, token);
+            }
             Ok(woken)
         }
         _ => Err(Error::new(EINVAL)),
@@ -227,6 +277,7 @@ pub struct FutexWaitv {
     pub __reserved: u32,
 }
 
+/// Wait on up to 128 futexes at once, returning the index of one that was woken.
 pub fn futex_waitv(
     waiters_addr: usize,
     nr_futexes: usize,
@@ -243,10 +294,7 @@ pub fn futex_waitv(
         return Err(Error::new(EINVAL));
     }
 
-    let timeout_opt = UserSlice::ro(timeout_addr, core::mem::size_of::<TimeSpec>())?
-        .none_if_null()
-        .map(|buf| unsafe { buf.read_exact::<TimeSpec>() })
-        .transpose()?;
+    let deadline = read_deadline(timeout_addr)?;
 
     let waiters_slice = UserSlice::ro(
         waiters_addr,
@@ -263,108 +311,69 @@ pub fn futex_waitv(
     }
 
     let current_addrsp = AddrSpace::current(token)?;
-
-    // Keep the address space locked so we can safely read from the physical address. Unlock it
-    // before context switching.
-    let addr_space_guard = current_addrsp.acquire_read();
+    let context_lock = context::current();
+    let keys = waiters
+        .iter()
+        .map(|waiter| FutexKey::new(&current_addrsp, VirtualAddress::new(waiter.uaddr)))
+        .collect::<Vec<_>>();
 
     {
-        let mut futexes_locked = FUTEXES.lock(token.token());
-        let (futexes, mut token) = futexes_locked.token_split();
-        let context_lock = context::current();
+        // Keep the address space locked so we can safely read from the physical address. Unlock
+        // it before context switching.
+        let addr_space_guard = current_addrsp.acquire_read();
+
+        // Lock every bucket involved, always in index order so that two waitv calls cannot
+        // deadlock against each other.
+        let mut indices = keys.iter().map(FutexKey::bucket_index).collect::<Vec<_>>();
+        indices.sort_unstable();
+        indices.dedup();
+        let mut buckets = indices
+            .iter()
+            .map(|&i| (i, FUTEX_BUCKETS[i].inner.lock()))
+            .collect::<Vec<_>>();
 
         // 1. Validate all values first
         for waiter in waiters.iter() {
-            let addr = waiter.uaddr;
-            let val = waiter.val;
-
-            if addr % 4 != 0 {
+            // Assume 32-bit (FUTEX_32 default)
+            if waiter.uaddr % 4 != 0 {
                 return Err(Error::new(EINVAL));
             }
+            let target_physaddr =
+                validate_and_translate_virt(&addr_space_guard, VirtualAddress::new(waiter.uaddr))
+                    .ok_or(Error::new(EFAULT))?;
 
-            let target_virtaddr = VirtualAddress::new(addr);
-            let target_physaddr = validate_and_translate_virt(&addr_space_guard, target_virtaddr)
-                .ok_or(Error::new(EFAULT))?;
-
-            // Assume 32-bit (FUTEX_32 default)
-            let accessible_addr =
-                unsafe { crate::paging::RmmA::phys_to_virt(target_physaddr) }.data();
-            let fetched = u64::from(unsafe {
-                (*(accessible_addr as *const AtomicU32)).load(Ordering::SeqCst)
-            });
-
-            if fetched != val {
+            if load_futex(target_physaddr, false)? != waiter.val {
                 return Err(Error::new(EAGAIN));
             }
         }
 
-        // 2. Add to all lists
-        for waiter in waiters.iter() {
-            let addr = waiter.uaddr;
-            let target_virtaddr = VirtualAddress::new(addr);
-            // We know it translates from step 1
-            let target_physaddr =
-                validate_and_translate_virt(&addr_space_guard, target_virtaddr).unwrap();
-
-            futexes
-                .entry(target_physaddr)
-                .or_insert_with(|| Vec::new())
-                .push(FutexEntry {
-                    target_virtaddr,
-                    context_lock: context_lock.clone(),
-                    addr_space: Arc::downgrade(&current_addrsp),
-                });
-        }
-
-        // 3. Block
-        {
-            let mut context = context_lock.write(token.token());
-
-            context.wake = timeout_opt.map(|TimeSpec { tv_sec, tv_nsec }| {
-                tv_sec as u128 * time::NANOS_PER_SEC + tv_nsec as u128
-            });
-
-            if let Some((tctl, pctl, _)) = context.sigcontrol() {
-                if tctl.currently_pending_unblocked(pctl) != 0 {
-                    return Err(Error::new(EINTR));
-                }
-            }
-
-            context.block("futex_waitv");
+        // 2. Block and add to all buckets, before any of them is unlocked
+        block_current(&context_lock, deadline, "futex_waitv", token)?;
+        for &key in keys.iter() {
+            let (_, bucket) = buckets
+                .iter_mut()
+                .find(|(i, _)| *i == key.bucket_index())
+                .expect("bucket was locked above");
+            bucket.push_back(Waitable::new(FutexEntry {
+                key,
+                context_lock: Arc::clone(&context_lock),
+            }));
         }
     }
 
-    drop(addr_space_guard);
+    sleep(&context_lock, deadline, token);
 
-    unsafe { context::switch(token) };
-
-    // 4. Cleanup (remove from all lists)
-    let _addr_space_guard = current_addrsp.acquire_read();
-    {
-        let mut futexes_locked = FUTEXES.lock(token.token());
-        let (futexes, mut token) = futexes_locked.token_split();
-        let context_lock = context::current();
-
-        for waiter in waiters.iter() {
-            let addr = waiter.uaddr;
-            if let Some(target_physaddr) =
-                validate_and_translate_virt(&_addr_space_guard, VirtualAddress::new(addr))
-            {
-                if let Some(list) = futexes.get_mut(&target_physaddr) {
-                    let my_context_ptr = Arc::as_ptr(&context_lock);
-                    list.retain(|entry| Arc::as_ptr(&entry.context_lock) != my_context_ptr);
-
-                    if list.is_empty() {
-                        futexes.remove(&target_physaddr);
-                    }
-                }
-            }
+    // 3. Cleanup, noting which futex, if any, woke us
+    let mut woken = None;
+    for (index, &key) in keys.iter().enumerate() {
+        // A futex listed twice was dequeued in one go the first time
+        if keys[..index].contains(&key) {
+            continue;
+        }
+        if !dequeue(key, &context_lock) && woken.is_none() {
+            woken = Some(index);
         }
     }
 
-    if timeout_opt.is_some() {
-        context::current().write(token.token()).wake = None;
-    }
-
-    Ok(0)
+    woken.ok_or_else(|| not_woken(deadline))
 }
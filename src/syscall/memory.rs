@@ -4,6 +4,7 @@
 
 use crate::{
     memory::{mlockall as mlockall_impl, munlockall as munlockall_impl, MlockFlags},
+    sync::CleanLockToken,
     syscall::error::{Error, Result},
 };
 
@@ -11,15 +12,15 @@ use crate::{
 ///
 /// This function locks all of the calling process's virtual address space into RAM,
 /// preventing that memory from being paged to the swap area.
-pub fn sys_mlockall(flags: usize) -> Result<usize> {
+pub fn sys_mlockall(flags: usize, token: &mut CleanLockToken) -> Result<usize> {
     let flags = MlockFlags::from_bits(flags as u32).ok_or(Error::new(syscall::EINVAL))?;
-    mlockall_impl(flags).map(|_| 0)
+    mlockall_impl(flags, token).map(|_| 0)
 }
 
 /// The `munlockall` system call.
 ///
 /// This function unlocks all of the calling process's virtual address space,
 /// allowing that memory to be paged to the swap area.
-pub fn sys_munlockall() -> Result<usize> {
-    munlockall_impl().map(|_| 0)
+pub fn sys_munlockall(token: &mut CleanLockToken) -> Result<usize> {
+    munlockall_impl(token).map(|_| 0)
 }
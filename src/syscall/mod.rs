@@ -32,10 +32,67 @@ pub mod time;
 pub mod usercopy;
 
 use crate::{
+    context,
+    scheme::FileHandle,
     sync::CleanLockToken,
-    syscall::error::{Error, ENOSYS},
+    syscall::{
+        error::{Error, ENOSYS},
+        usercopy::UserSliceRo,
+    },
 };
 
+// Not yet allocated by the redox_syscall crate, unlike `number::SYS_NANOSLEEP` which is
+// dispatched under its own number; these follow the Linux x86_64 numbering.
+/// Set the scheduling policy and priority of a context (`pid, policy, priority`).
+pub const SYS_SCHED_SETSCHEDULER: usize = 144;
+/// Get the scheduling policy of a context (`pid, *mut u32 priority`).
+pub const SYS_SCHED_GETSCHEDULER: usize = 145;
+/// Sleep on a clock, relative or until an absolute time (`clock, flags, *const TimeSpec req,
+/// *mut TimeSpec rem`).
+pub const SYS_CLOCK_NANOSLEEP: usize = 230;
+/// Exit the calling context (`code`).
+pub const SYS_EXIT: usize = 60;
+/// Wait for a child context to exit (`pid, *mut u32 status, options`), numbered as `wait4`
+/// without its resource usage argument.
+pub const SYS_WAITPID: usize = 61;
+/// Set the caller's file mode creation mask (`mask`), returning the previous mask.
+pub const SYS_UMASK: usize = 95;
+/// Lock the pages overlapping a range of the caller's memory (`addr, len`) into RAM.
+pub const SYS_MLOCK: usize = 149;
+/// Unlock the pages overlapping a range locked by `SYS_MLOCK` (`addr, len`).
+pub const SYS_MUNLOCK: usize = 150;
+/// Lock all of the caller's memory into RAM (`flags`, `MCL_CURRENT` and/or `MCL_FUTURE`).
+pub const SYS_MLOCKALL: usize = 151;
+/// Unlock all of the caller's memory and stop locking future mappings.
+pub const SYS_MUNLOCKALL: usize = 152;
+
+// Kernel extensions, numbered above anything Linux allocates.
+/// Register the caller's upcall handler (`entry, stack_base, stack_size`), or unregister it if
+/// `entry` is 0. See [`crate::context::upcall`].
+pub const SYS_UPCALL_REGISTER: usize = 1000;
+/// Queue a payload for the upcall handler of a context (`pid, payload`).
+pub const SYS_UPCALL_POST: usize = 1001;
+/// Resume the code interrupted by the running upcall, with all its registers.
+pub const SYS_UPCALL_RETURN: usize = 1002;
+/// Duplicate a file descriptor (`fd, new_fd, flags, buf, buf_len`) like `SYS_DUP2`, or like
+/// `SYS_DUP` if `new_fd` is `usize::MAX`. `O_CLOEXEC` is the only flag, and marks the new
+/// descriptor close-on-exec atomically.
+pub const SYS_DUP3: usize = 1003;
+/// Set the ABI the caller's later syscalls follow (`abi`, a [`personality::PersonalityABI`]).
+/// Exec makes this its last native syscall before entering a foreign image.
+pub const SYS_SET_PERSONALITY: usize = 1004;
+
+/// Back an anonymous mapping with huge (2 MiB) pages. Kernel extension of `MapFlags`, in a bit
+/// the redox_syscall crate does not use.
+pub const MAP_HUGE: flag::MapFlags = flag::MapFlags::from_bits_retain(1 << 20);
+/// Map with caching disabled. Kernel extension of `MapFlags` like [`MAP_HUGE`], honoured by
+/// mappings of `memory:phys_contiguous` buffers.
+pub const MAP_UNCACHEABLE: flag::MapFlags = flag::MapFlags::from_bits_retain(1 << 21);
+/// Map write-combining, otherwise like [`MAP_UNCACHEABLE`].
+pub const MAP_WRITE_COMBINING: flag::MapFlags = flag::MapFlags::from_bits_retain(1 << 22);
+/// Map as device memory, on architectures that have such a memory type.
+pub const MAP_DEVICE_MEMORY: flag::MapFlags = flag::MapFlags::from_bits_retain(1 << 23);
+
 /// The main syscall entry point.
 ///
 /// This function is called by the architecture-specific syscall entry code (e.g., in `arch/x86_64/syscall.rs`).
@@ -57,12 +114,16 @@ use crate::{
 /// The `Error::mux` function converts the `Result` into this `usize` representation.
 pub fn syscall(number: usize, a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -> usize {
     let mut token = unsafe { CleanLockToken::new() };
+    exit_if_killed(&mut token);
+    crate::scheme::sys::notify_memory_pressure(&mut token);
 
     // Check for foreign ABI syscalls
-    let abi = personality::detect_abi(&token);
+    let abi = personality::detect_abi(&mut token);
     if personality::is_foreign_syscall(abi, number) {
         let args = personality::SyscallArgs::new(number, a, b, c, d, e, f);
         let res = personality::redirect_foreign_syscall(abi, args, &mut token);
+        crate::deferred::run_pending(&mut token);
+        exit_if_killed(&mut token);
         return Error::mux(res);
     }
 
@@ -73,13 +134,54 @@ pub fn syscall(number: usize, a: usize, b: usize, c: usize, d: usize, e: usize,
         // We will assume 449 for now or a new constant if defined.
         449 => futex::futex_waitv(a, b, c, d, e, &mut token),
 
-        // TODO: Uncomment when SYS_MLOCKALL and SYS_MUNLOCKALL are added to redox_syscall crate
-        // number::SYS_MLOCKALL => memory::sys_mlockall(a),
-        // number::SYS_MUNLOCKALL => memory::sys_munlockall(),
+        SYS_SCHED_SETSCHEDULER => process::sched_setscheduler(a, b, c, &mut token),
+        SYS_SCHED_GETSCHEDULER => process::sched_getscheduler(a, b, &mut token),
+        number::SYS_NANOSLEEP => time::nanosleep(a, b, &mut token),
+        SYS_CLOCK_NANOSLEEP => time::clock_nanosleep(a, b, c, d, &mut token),
+        SYS_EXIT => process::exit(a, &mut token),
+        SYS_WAITPID => process::waitpid(a, b, c, &mut token),
+        SYS_UMASK => Ok(process::umask(a, &mut token)),
+        SYS_MLOCK => fs::sys_mlock(a, b, &mut token),
+        SYS_MUNLOCK => fs::sys_munlock(a, b, &mut token),
+        SYS_MLOCKALL => memory::sys_mlockall(a, &mut token),
+        SYS_MUNLOCKALL => memory::sys_munlockall(&mut token),
+        SYS_SET_PERSONALITY => personality::sys_set_personality(a, &mut token),
+        SYS_UPCALL_REGISTER => process::upcall_register(a, b, c, &mut token),
+        SYS_UPCALL_POST => process::upcall_post(a, b, &mut token),
+        SYS_UPCALL_RETURN => process::upcall_return(&mut token),
+        number::SYS_DUP => UserSliceRo::ro(b, c)
+            .and_then(|buf| fs::dup(FileHandle::from(a), buf, &mut token))
+            .map(FileHandle::into),
+        number::SYS_DUP2 => UserSliceRo::ro(c, d)
+            .and_then(|buf| fs::dup2(FileHandle::from(a), FileHandle::from(b), buf, &mut token))
+            .map(FileHandle::into),
+        SYS_DUP3 => UserSliceRo::ro(d, e)
+            .and_then(|buf| {
+                let new_fd = (b != usize::MAX).then(|| FileHandle::from(b));
+                fs::dup3(FileHandle::from(a), new_fd, c, buf, &mut token)
+            })
+            .map(FileHandle::into),
+        number::SYS_FLINK => UserSliceRo::ro(b, c)
+            .and_then(|path| fs::flink(FileHandle::from(a), path, &mut token))
+            .map(|()| 0),
+        number::SYS_FRENAME => UserSliceRo::ro(b, c)
+            .and_then(|path| fs::frename(FileHandle::from(a), path, &mut token))
+            .map(|()| 0),
         _ => {
             // Forward to other handlers if needed, or default
             Err(Error::new(ENOSYS))
         }
     };
+    // Run what interrupt handlers deferred before going back to userspace
+    crate::deferred::run_pending(&mut token);
+    exit_if_killed(&mut token);
     Error::mux(res)
 }
+
+/// Exit the current context at the syscall boundary if it was killed through `proc:<pid>/ctl`
+fn exit_if_killed(token: &mut CleanLockToken) {
+    let killed = context::current().read(token.token()).being_sigkilled;
+    if killed {
+        process::exit_this_context(0, None, token);
+    }
+}
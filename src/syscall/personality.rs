@@ -1,22 +1,36 @@
 //! Personality-based Syscall Redirection
 //!
 //! This module provides detection and redirection of foreign ABI syscalls
-//! (Windows NT, Linux, Android) to userspace personality servers.
+//! (Windows NT, Linux, Android) to personality servers.
 //!
 //! # Architecture
 //!
 //! When a process with a non-native personality executes a syscall:
-//! 1. `detect_abi()` examines the context to determine the ABI
-//! 2. `redirect_foreign_syscall()` packages arguments and sends to personality server
+//! 1. `detect_abi()` reads the personality exec gave the context with `set_personality()`
+//! 2. `redirect_foreign_syscall()` sends the arguments over the IPC channel of the server
+//!    registered for the ABI, and blocks for the reply
+//!
+//! Only kernel code registers servers so far, so `SYS_SET_PERSONALITY` refuses an ABI that has
+//! none with ENOSYS rather than leave the caller with syscalls that can never be served.
 //! 3. Personality server translates to native Redox calls
 //! 4. Response is returned to caller
+//!
+//! # Message ABI
+//!
+//! A call is a [`ZeroCopyMessage`] with `msg_type` set to the [`PersonalityABI`] and the
+//! [`SyscallArgs`] inline as native-endian words, see [`call_message`]. The server answers with
+//! [`reply_message`], which keeps the header of the call and carries the result inline as one
+//! word, encoded like the return value of a native syscall.
+
+use alloc::sync::Arc;
+use core::mem::size_of;
+use spin::RwLock;
 
 use crate::{
     context,
-    ipc::{MessageHeader, ZeroCopyMessage},
-    scheme::SchemeId,
+    ipc::{self, IpcChannel, MessageHeader, MessagePayload, ZeroCopyMessage, INLINE_MSG_MAX},
     sync::CleanLockToken,
-    syscall::error::{Error, Result, ENOSYS},
+    syscall::error::{Error, Result, EBADF, EEXIST, EINVAL, EIO, ENOSYS},
 };
 
 /// Supported ABI personalities
@@ -46,6 +60,9 @@ impl PersonalityABI {
     }
 }
 
+/// Number of personalities, native included
+const ABI_COUNT: usize = core::mem::variant_count::<PersonalityABI>();
+
 impl Default for PersonalityABI {
     fn default() -> Self {
         Self::Redox
@@ -65,6 +82,13 @@ pub struct SyscallArgs {
     pub arg5: usize,
 }
 
+/// Number of words in [`SyscallArgs`]
+const ARG_WORDS: usize = 7;
+const WORD: usize = size_of::<usize>();
+
+const _: () = assert!(size_of::<SyscallArgs>() == ARG_WORDS * WORD);
+const _: () = assert!(size_of::<SyscallArgs>() <= INLINE_MSG_MAX);
+
 impl SyscallArgs {
     pub fn new(number: usize, a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -> Self {
         Self {
@@ -77,36 +101,35 @@ impl SyscallArgs {
             arg5: f,
         }
     }
-}
 
-/// Personality server descriptor
-#[derive(Clone, Debug)]
-pub struct PersonalityServer {
-    /// Scheme ID for the personality server
-    pub scheme_id: SchemeId,
-    /// File handle for communication
-    pub handle: usize,
-    /// Whether the server is active
-    pub active: bool,
+    /// The number and arguments, in the order they are sent in
+    fn to_words(self) -> [usize; ARG_WORDS] {
+        [
+            self.number,
+            self.arg0,
+            self.arg1,
+            self.arg2,
+            self.arg3,
+            self.arg4,
+            self.arg5,
+        ]
+    }
+
+    fn from_words(words: [usize; ARG_WORDS]) -> Self {
+        let [number, a, b, c, d, e, f] = words;
+        Self::new(number, a, b, c, d, e, f)
+    }
 }
 
 /// Per-context personality state
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub struct PersonalityState {
-    /// The ABI personality for this context
+    /// The ABI the context's syscalls are decoded with
     pub abi: PersonalityABI,
-    /// Registered personality servers
-    pub servers: [Option<PersonalityServer>; 4],
 }
 
-impl Default for PersonalityState {
-    fn default() -> Self {
-        Self {
-            abi: PersonalityABI::Redox,
-            servers: [None, None, None, None],
-        }
-    }
-}
+/// The IPC channel of the server registered for each ABI, indexed by `PersonalityABI as usize`
+static SERVERS: RwLock<[Option<u64>; ABI_COUNT]> = RwLock::new([None; ABI_COUNT]);
 
 /// Linux syscall numbers that need redirection
 pub mod linux_syscall {
@@ -147,25 +170,15 @@ pub mod windows_syscall {
 
 /// Detect the ABI personality of the current context
 ///
-/// This examines the context's registered personality and returns
-/// the appropriate ABI type. Called early in syscall dispatch.
-pub fn detect_abi(token: &CleanLockToken) -> PersonalityABI {
-    let current = context::current();
-    let guard = current.read(token.ticket());
-
-    // Check if context has a registered personality
-    // For now, default to Redox unless explicitly set
-    // TODO: Check ELF header magic, PE signature, etc.
-
-    // Placeholder: would read from context's personality state
-    PersonalityABI::Redox
+/// Called early in syscall dispatch, so only the personality stored in the context is looked at.
+pub fn detect_abi(token: &mut CleanLockToken) -> PersonalityABI {
+    context::current().read(token.token()).personality.abi
 }
 
 /// Check if a syscall number belongs to a foreign ABI
 ///
-/// Linux syscall numbers > 400 are typically Redox-specific
-/// Windows NT syscalls are routed through int 0x2e or different entry
-pub fn is_foreign_syscall(abi: PersonalityABI, syscall_number: usize) -> bool {
+/// Every syscall of a foreign personality is translated, whatever its number.
+pub fn is_foreign_syscall(abi: PersonalityABI, _syscall_number: usize) -> bool {
     match abi {
         PersonalityABI::Redox => false,
         PersonalityABI::Linux => true, // All Linux syscalls need translation
@@ -176,95 +189,235 @@ pub fn is_foreign_syscall(abi: PersonalityABI, syscall_number: usize) -> bool {
 
 /// Redirect a foreign syscall to the appropriate personality server
 ///
-/// This packages the syscall arguments and sends them via IPC to the
-/// personality server, which performs the translation and execution.
+/// This packages the syscall arguments, sends them to the server registered for `abi` and blocks
+/// until it replies with the result. Fails with ENOSYS if no server is registered, and with EINTR
+/// if a signal arrives before the reply, which the server's late reply then does not reach.
 pub fn redirect_foreign_syscall(
     abi: PersonalityABI,
     args: SyscallArgs,
     token: &mut CleanLockToken,
 ) -> Result<usize> {
-    match abi {
-        PersonalityABI::Redox => {
-            // Should not happen - native syscalls don't need redirection
-            Err(Error::new(ENOSYS))
-        }
-        PersonalityABI::Linux => redirect_to_linux_server(args, token),
-        PersonalityABI::Windows => redirect_to_windows_server(args, token),
-        PersonalityABI::Android => redirect_to_android_server(args, token),
-    }
-}
+    let channel = server_channel(abi).ok_or(Error::new(ENOSYS))?;
+    let src_ctx = context::current().read(token.token()).id() as u64;
 
-/// Redirect to Linux compatibility server
-fn redirect_to_linux_server(args: SyscallArgs, _token: &mut CleanLockToken) -> Result<usize> {
-    // Package args into IPC message
-    let msg = create_syscall_message(PersonalityABI::Linux, args);
+    let reply = channel.call(call_message(abi, args, src_ctx), token)?;
+    decode_reply(&reply)
+}
 
-    // Send to linux-compat-server scheme
-    // For now, return ENOSYS until server is implemented
-    // TODO: IPC send to "linux:" scheme
+/// The channel of the server registered for `abi`, if it is still open
+fn server_channel(abi: PersonalityABI) -> Option<Arc<IpcChannel>> {
+    let channel = SERVERS.read().get(abi as usize).copied().flatten()?;
+    ipc::registry().get_channel(channel)
+}
 
-    Err(Error::new(ENOSYS))
+/// The first `N` words of an inline payload
+fn inline_words<const N: usize>(msg: &ZeroCopyMessage) -> [usize; N] {
+    // Every bit pattern is a valid byte array, whichever field was written last
+    let inline = unsafe { msg.payload.inline };
+    let mut words = [0; N];
+    for (word, bytes) in words.iter_mut().zip(inline.chunks_exact(WORD)) {
+        let mut buf = [0; WORD];
+        buf.copy_from_slice(bytes);
+        *word = usize::from_ne_bytes(buf);
+    }
+    words
 }
 
-/// Redirect to Windows compatibility server
-fn redirect_to_windows_server(args: SyscallArgs, _token: &mut CleanLockToken) -> Result<usize> {
-    let msg = create_syscall_message(PersonalityABI::Windows, args);
+/// The message asking the server for `abi` to run the syscall `args` for context `src_ctx`
+pub fn call_message(abi: PersonalityABI, args: SyscallArgs, src_ctx: u64) -> ZeroCopyMessage {
+    let mut inline = [0_u8; INLINE_MSG_MAX];
+    for (bytes, word) in inline.chunks_exact_mut(WORD).zip(args.to_words()) {
+        bytes.copy_from_slice(&word.to_ne_bytes());
+    }
 
-    // TODO: IPC send to "windows:" scheme
-    Err(Error::new(ENOSYS))
+    ZeroCopyMessage {
+        header: MessageHeader {
+            src_ctx,
+            msg_type: abi as u32,
+            payload_len: size_of::<SyscallArgs>() as u32,
+            ..MessageHeader::default()
+        },
+        payload: MessagePayload { inline },
+    }
 }
 
-/// Redirect to Android compatibility server
-fn redirect_to_android_server(args: SyscallArgs, _token: &mut CleanLockToken) -> Result<usize> {
-    let msg = create_syscall_message(PersonalityABI::Android, args);
-
-    // TODO: IPC send to "android:" scheme
-    Err(Error::new(ENOSYS))
+/// The personality and syscall of a message made by [`call_message`], as a server decodes it
+pub fn decode_call(msg: &ZeroCopyMessage) -> Option<(PersonalityABI, SyscallArgs)> {
+    let abi = u8::try_from(msg.header.msg_type)
+        .ok()
+        .and_then(PersonalityABI::from_u8)?;
+    if msg.header.payload_len as usize != size_of::<SyscallArgs>() {
+        return None;
+    }
+    Some((abi, SyscallArgs::from_words(inline_words(msg))))
 }
 
-/// Create an IPC message for syscall forwarding
-fn create_syscall_message(abi: PersonalityABI, args: SyscallArgs) -> ZeroCopyMessage {
-    let mut header = MessageHeader::default();
-    header.msg_type = abi as u32;
-    header.payload_size = core::mem::size_of::<SyscallArgs>() as u32;
-
-    let mut msg = ZeroCopyMessage::default();
-    msg.header = header;
-
-    // Copy syscall args to inline payload
-    let bytes = unsafe {
-        core::slice::from_raw_parts(
-            &args as *const SyscallArgs as *const u8,
-            core::mem::size_of::<SyscallArgs>(),
-        )
-    };
+/// The reply of a server to `call`, with the result of the translated syscall
+pub fn reply_message(call: &ZeroCopyMessage, result: Result<usize>) -> ZeroCopyMessage {
+    let mut inline = [0_u8; INLINE_MSG_MAX];
+    if let Some(bytes) = inline.get_mut(..WORD) {
+        bytes.copy_from_slice(&Error::mux(result).to_ne_bytes());
+    }
 
-    unsafe {
-        msg.payload.inline[..bytes.len()].copy_from_slice(bytes);
+    ZeroCopyMessage {
+        header: MessageHeader {
+            payload_len: WORD as u32,
+            ..call.header
+        },
+        payload: MessagePayload { inline },
     }
+}
 
-    msg
+/// The result carried by a reply made by [`reply_message`]
+fn decode_reply(reply: &ZeroCopyMessage) -> Result<usize> {
+    if reply.header.payload_len as usize != WORD {
+        return Err(Error::new(EIO));
+    }
+    let [value] = inline_words(reply);
+    Error::demux(value)
 }
 
 /// Set the personality for the current context
+///
+/// Exec calls this right before entering a foreign image, since the syscalls that follow are no
+/// longer native ones.
 pub fn set_personality(abi: PersonalityABI, token: &mut CleanLockToken) -> Result<()> {
-    // TODO: Store personality in context
-    // This would be called by exec() when loading a foreign binary
+    context::current().write(token.token()).personality.abi = abi;
     Ok(())
 }
 
-/// Register a personality server for handling foreign syscalls
-pub fn register_personality_server(
-    abi: PersonalityABI,
-    scheme_id: SchemeId,
-    handle: usize,
-    _token: &mut CleanLockToken,
-) -> Result<()> {
-    // TODO: Store server registration globally
-    // Personality servers register themselves on startup
+/// `SYS_SET_PERSONALITY`, setting the caller's personality to the raw [`PersonalityABI`] `abi`
+///
+/// Fails with ENOSYS for a foreign ABI that no server is registered for.
+pub fn sys_set_personality(abi: usize, token: &mut CleanLockToken) -> Result<usize> {
+    let abi = u8::try_from(abi)
+        .ok()
+        .and_then(PersonalityABI::from_u8)
+        .ok_or(Error::new(EINVAL))?;
+    if abi != PersonalityABI::Redox && server_channel(abi).is_none() {
+        return Err(Error::new(ENOSYS));
+    }
+    set_personality(abi, token)?;
+    Ok(0)
+}
+
+/// Register the IPC channel of the server handling the foreign syscalls of `abi`
+///
+/// Personality servers register themselves on startup, and only one may serve each ABI.
+pub fn register_personality_server(abi: PersonalityABI, channel: u64) -> Result<()> {
+    if abi == PersonalityABI::Redox {
+        return Err(Error::new(EINVAL));
+    }
+    if ipc::registry().get_channel(channel).is_none() {
+        return Err(Error::new(EBADF));
+    }
+
+    let mut servers = SERVERS.write();
+    let server = servers.get_mut(abi as usize).ok_or(Error::new(EINVAL))?;
+    if server.is_some() {
+        return Err(Error::new(EEXIST));
+    }
+    *server = Some(channel);
     Ok(())
 }
 
+/// Unregister the server of `abi`, returning its channel. Syscalls of the ABI fail with ENOSYS
+/// until another one registers.
+pub fn unregister_personality_server(abi: PersonalityABI) -> Option<u64> {
+    SERVERS.write().get_mut(abi as usize)?.take()
+}
+
+#[cfg(feature = "selftest")]
+pub mod selftests {
+    use alloc::format;
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+    use crate::{
+        context::kthread,
+        cpu_set::{LogicalCpuId, LogicalCpuSet},
+        ipc::DEFAULT_IPC_TIMEOUT_NS,
+        selftest::{self, check_eq, SelftestResult},
+        sync::Priority,
+    };
+
+    /// The channel the echo server answers on
+    static ECHO_CHANNEL: AtomicU64 = AtomicU64::new(0);
+    /// What the Linux caller's syscall returned, once it is done
+    static RESULT: spin::Mutex<Option<usize>> = spin::Mutex::new(None);
+
+    /// A Linux personality server answering one call, a `write` with the number of bytes it was
+    /// asked to write and anything else with ENOSYS
+    fn echo_server() {
+        let mut token = unsafe { CleanLockToken::new() };
+        let channel = ECHO_CHANNEL.load(Ordering::Acquire);
+        let Some(channel) = ipc::registry().get_channel(channel) else {
+            return;
+        };
+        let Ok(call) = channel.recv_blocking(&mut token, DEFAULT_IPC_TIMEOUT_NS) else {
+            return;
+        };
+        let result = match decode_call(&call) {
+            Some((PersonalityABI::Linux, args)) if args.number == linux_syscall::SYS_WRITE => {
+                Ok(args.arg2)
+            }
+            _ => Err(Error::new(ENOSYS)),
+        };
+        let _ = channel.reply(reply_message(&call, result), &mut token);
+    }
+
+    /// A context taking the Linux personality, as exec would, then making a Linux `write`
+    fn linux_caller() {
+        let mut token = unsafe { CleanLockToken::new() };
+        let _ = set_personality(PersonalityABI::Linux, &mut token);
+        drop(token);
+        let result = crate::syscall::syscall(linux_syscall::SYS_WRITE, 1, 0, 5, 0, 0, 0);
+        *RESULT.lock() = Some(result);
+    }
+
+    /// A Linux-numbered syscall goes through the real syscall entry, is sent to the registered
+    /// echo server and comes back with its reply
+    pub fn linux_write_round_trip(token: &mut CleanLockToken) -> SelftestResult {
+        let channel = ipc::registry()
+            .create_channel()
+            .map_err(|err| format!("failed to create a channel: {}", err))?;
+        ECHO_CHANNEL.store(channel, Ordering::Release);
+        *RESULT.lock() = None;
+        if let Err(err) = register_personality_server(PersonalityABI::Linux, channel) {
+            let _ = ipc::registry().close_channel(channel, token);
+            return Err(format!("failed to register the echo server: {}", err));
+        }
+
+        let mut bsp = LogicalCpuSet::new();
+        bsp.add(LogicalCpuId::BSP);
+        let threads: [(&str, fn()); 2] = [
+            ("[selftest_echo]", echo_server),
+            ("[selftest_linux]", linux_caller),
+        ];
+        let mut spawned = Ok(());
+        for (name, entry) in threads {
+            match kthread::spawn(name, bsp, Priority::Normal, entry, token) {
+                Ok(handle) => handle.detach(token),
+                Err(err) => {
+                    spawned = Err(format!("failed to spawn {}: {}", name, err));
+                    break;
+                }
+            }
+        }
+        let done = spawned.is_ok() && selftest::switch_until(|| RESULT.lock().is_some(), token);
+
+        unregister_personality_server(PersonalityABI::Linux);
+        let _ = ipc::registry().close_channel(channel, token);
+        spawned?;
+        if !done {
+            return Err("timed out waiting for the Linux caller".into());
+        }
+        let result = RESULT.lock().map(Error::demux);
+        check_eq!(result, Some(Ok(5)));
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -292,4 +445,36 @@ mod tests {
         assert!(is_foreign_syscall(PersonalityABI::Linux, 100));
         assert!(is_foreign_syscall(PersonalityABI::Windows, 100));
     }
+
+    #[test]
+    fn test_call_message_round_trip() {
+        let args = SyscallArgs::new(linux_syscall::SYS_WRITE, 1, 0x1000, 5, 0, 0, usize::MAX);
+        let msg = call_message(PersonalityABI::Linux, args, 42);
+        assert_eq!(msg.header.src_ctx, 42);
+        assert_eq!(msg.header.payload_len as usize, size_of::<SyscallArgs>());
+
+        let (abi, decoded) = decode_call(&msg).unwrap();
+        assert_eq!(abi, PersonalityABI::Linux);
+        assert_eq!(decoded.to_words(), args.to_words());
+
+        let mut unknown = msg;
+        unknown.header.msg_type = 7;
+        assert!(decode_call(&unknown).is_none());
+    }
+
+    #[test]
+    fn test_reply_message() {
+        let mut call = call_message(PersonalityABI::Linux, SyscallArgs::default(), 1);
+        call.header.seq = 9;
+
+        let reply = reply_message(&call, Ok(5));
+        assert_eq!(reply.header.seq, 9);
+        assert_eq!(decode_reply(&reply), Ok(5));
+
+        let reply = reply_message(&call, Err(Error::new(ENOSYS)));
+        assert_eq!(decode_reply(&reply), Err(Error::new(ENOSYS)));
+
+        // A call sent back unanswered is not a reply
+        assert_eq!(decode_reply(&call), Err(Error::new(EIO)));
+    }
 }
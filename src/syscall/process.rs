@@ -7,12 +7,13 @@ use spin::RwLock;
 use crate::{
     context::{
         context::SyscallFrame,
-        memory::{AddrSpace, Grant, PageSpan},
-        ContextRef,
+        memory::{handle_notify_files, AddrSpace, Grant, PageSpan},
+        upcall, ContextRef,
     },
     event,
+    scheduler::{self, DeadlineParams, DeadlineState, SchedPolicy, RT_PRIORITY_LEVELS},
     scheme::GlobalSchemes,
-    sync::CleanLockToken,
+    sync::{CleanLockToken, Priority},
     syscall::EventFlags,
 };
 
@@ -24,9 +25,186 @@ use crate::{
     Bootstrap, CurrentRmmArch,
 };
 
-use super::usercopy::UserSliceWo;
+use super::usercopy::{UserSlice, UserSliceWo};
 
-pub fn exit_this_context(excp: Option<syscall::Exception>, token: &mut CleanLockToken) -> ! {
+/// Look up the context targeted by a scheduling or upcall syscall, where pid 0 means the caller
+fn sched_target(pid: usize) -> Result<ContextRef> {
+    if pid == 0 {
+        return Ok(context::current());
+    }
+    context::contexts()
+        .read()
+        .get(&pid)
+        .cloned()
+        .ok_or(Error::new(ESRCH))
+}
+
+/// Set the scheduling policy and static priority of a context.
+///
+/// RT policies take a POSIX priority in `0..RT_PRIORITY_LEVELS`, where higher values are more
+/// urgent, and require uid 0. The deadline policy takes a pointer to a `DeadlineParams` in place
+/// of the priority, requires uid 0 as well, and fails with `EBUSY` if admitting the context would
+/// take deadline utilization over its cap. All other policies require a priority of 0.
+pub fn sched_setscheduler(
+    pid: usize,
+    policy: usize,
+    priority: usize,
+    token: &mut CleanLockToken,
+) -> Result<usize> {
+    let policy = SchedPolicy::from_raw(policy).ok_or(Error::new(EINVAL))?;
+    let deadline = if policy == SchedPolicy::Deadline {
+        let params = unsafe {
+            UserSlice::ro(priority, mem::size_of::<DeadlineParams>())?
+                .read_exact::<DeadlineParams>()?
+        };
+        if !params.is_valid() {
+            return Err(Error::new(EINVAL));
+        }
+        Some(params)
+    } else {
+        None
+    };
+    let base_priority = if policy.is_realtime() {
+        if priority >= RT_PRIORITY_LEVELS {
+            return Err(Error::new(EINVAL));
+        }
+        // The priority tracker uses lower = more urgent, POSIX uses the opposite
+        (RT_PRIORITY_LEVELS - 1 - priority) as u8
+    } else {
+        if deadline.is_none() && priority != 0 {
+            return Err(Error::new(EINVAL));
+        }
+        Priority::Normal.as_u8()
+    };
+
+    let caller_euid = context::current().read(token.token()).euid;
+    let context_ref = sched_target(pid)?;
+    {
+        let mut context = context_ref.write(token.token());
+        let privileged = policy.is_realtime() || deadline.is_some();
+        if caller_euid != 0 && (privileged || context.euid != caller_euid) {
+            return Err(Error::new(EPERM));
+        }
+
+        let old = context.sched_deadline.map(|state| state.params);
+        match deadline {
+            Some(params) => {
+                scheduler::admit_deadline(old.as_ref(), &params)?;
+                let now = crate::time::monotonic() as u64;
+                context.sched_deadline = Some(DeadlineState::new(params, now));
+            }
+            None => {
+                if let Some(old) = old {
+                    scheduler::release_deadline(&old);
+                }
+                context.sched_deadline = None;
+            }
+        }
+        context.sched_policy = policy;
+        context.set_realtime(policy.is_realtime());
+        context.priority.set_base_priority_raw(base_priority);
+    }
+
+    scheduler::requeue_context(&context_ref, token);
+    scheduler::request_preemption(token);
+
+    Ok(0)
+}
+
+/// Get the scheduling policy of a context.
+///
+/// The policy is returned, and if `param` is non-null the POSIX priority is written to it as a
+/// `u32`.
+pub fn sched_getscheduler(pid: usize, param: usize, token: &mut CleanLockToken) -> Result<usize> {
+    let context_ref = sched_target(pid)?;
+    let (policy, priority) = {
+        let context = context_ref.read(token.token());
+        let priority = if context.sched_policy.is_realtime() {
+            (RT_PRIORITY_LEVELS - 1).saturating_sub(usize::from(context.priority.base_priority()))
+        } else {
+            0
+        };
+        (context.sched_policy, priority)
+    };
+
+    if let Some(param) = UserSliceWo::wo(param, mem::size_of::<u32>())?.none_if_null() {
+        param.write_u32(priority as u32)?;
+    }
+
+    Ok(policy as usize)
+}
+
+/// Set the permission bits cleared from the mode of files the caller creates from now on, and
+/// return the previous mask. Bits other than the permission bits are ignored.
+pub fn umask(mask: usize, token: &mut CleanLockToken) -> usize {
+    let previous = context::current().write(token.token()).umask.replace(mask);
+    usize::from(previous.bits())
+}
+
+/// Register the caller's upcall handler, entered with `stack_size` bytes of stack at
+/// `stack_base`, or unregister it if `entry` is 0.
+///
+/// Fails with `EBUSY` while an upcall is running.
+pub fn upcall_register(
+    entry: usize,
+    stack_base: usize,
+    stack_size: usize,
+    token: &mut CleanLockToken,
+) -> Result<usize> {
+    upcall::register(entry, stack_base, stack_size, token)?;
+    Ok(0)
+}
+
+/// Queue `payload` for the upcall handler of a context.
+///
+/// Requires the same euid as the target, or uid 0. Fails with `EINVAL` if the target has no
+/// handler, and with `EAGAIN` if its queue is full.
+pub fn upcall_post(pid: usize, payload: usize, token: &mut CleanLockToken) -> Result<usize> {
+    let caller_euid = context::current().read(token.token()).euid;
+    let context_ref = sched_target(pid)?;
+    let euid = context_ref.read(token.token()).euid;
+    if caller_euid != 0 && euid != caller_euid {
+        return Err(Error::new(EPERM));
+    }
+    upcall::post(&context_ref, payload as u64, token)?;
+    Ok(0)
+}
+
+/// Finish the running upcall. On success the syscall does not return to its caller, but to the
+/// code the upcall interrupted.
+pub fn upcall_return(token: &mut CleanLockToken) -> Result<usize> {
+    upcall::upcall_return(token)?;
+    Ok(0)
+}
+
+/// Wait for a child context to exit, as `waitpid(pid, *mut u32 status, options)`.
+///
+/// Returns the id of the reaped child and writes its wait status to `status` if non-null, or
+/// returns 0 if `WNOHANG` is set and no child has exited yet.
+pub fn waitpid(
+    pid: usize,
+    status: usize,
+    options: usize,
+    token: &mut CleanLockToken,
+) -> Result<usize> {
+    if options & !context::reap::WNOHANG != 0 {
+        return Err(Error::new(EINVAL));
+    }
+    let status = UserSliceWo::wo(status, mem::size_of::<u32>())?.none_if_null();
+    let Some((child, wait_status)) = context::reap::waitpid(pid, options, token)? else {
+        return Ok(0);
+    };
+    if let Some(status) = status {
+        status.write_u32(wait_status as u32)?;
+    }
+    Ok(child)
+}
+
+pub fn exit_this_context(
+    code: u8,
+    excp: Option<syscall::Exception>,
+    token: &mut CleanLockToken,
+) -> ! {
     let mut close_files;
     let addrspace_opt;
 
@@ -42,15 +220,24 @@ pub fn exit_this_context(excp: Option<syscall::Exception>, token: &mut CleanLock
         drop(mem::replace(&mut context.syscall_tail, SyscallFrame::Dummy));
     }
 
+    // Providers of file-backed grants are told before the files, which may be all that keeps
+    // their schemes reachable, are closed
+    if let Some(addrspace) = &addrspace_opt {
+        handle_notify_files(addrspace.teardown_notifications(), token);
+    }
+
     // Files must be closed while context is valid so that messages can be passed
     close_files.force_close_all(token);
     drop(addrspace_opt);
     // TODO: Should status == Status::HardBlocked be handled differently?
     let owner = {
         let mut guard = context_lock.write(token.token());
-        guard.status = context::Status::Dead { excp };
+        if let Some(state) = guard.sched_deadline.take() {
+            scheduler::release_deadline(&state.params);
+        }
         guard.owner_proc_id
     };
+    context::reap::exit_current(code, excp, token);
     if let Some(owner) = owner {
         event::trigger(
             GlobalSchemes::Proc.scheme_id(),
@@ -59,14 +246,16 @@ pub fn exit_this_context(excp: Option<syscall::Exception>, token: &mut CleanLock
             token,
         );
     }
-    {
-        let id = context_lock.read(token.token()).id();
-        let _ = context::contexts_mut().remove(&id);
-    }
     unsafe { context::switch(token) };
     unreachable!();
 }
 
+/// Exit the current context, as `exit(code)`. Only the low 8 bits of `code` are kept, as the
+/// parent's waitpid reports them.
+pub fn exit(code: usize, token: &mut CleanLockToken) -> ! {
+    exit_this_context(code as u8, None, token)
+}
+
 pub fn mprotect(
     address: usize,
     size: usize,
@@ -78,12 +267,27 @@ pub fn mprotect(
     let span = PageSpan::validate_nonempty(VirtualAddress::new(address), size)
         .ok_or(Error::new(EINVAL))?;
 
-    AddrSpace::current(token)?.acquire_write().mprotect(span.base, span.count * PAGE_SIZE, flags)
+    AddrSpace::current(token)?.acquire_write().mprotect(span.base, span.count, flags)
+}
+
+/// Close the descriptors of `files` marked close-on-exec, as exec does before entering the new
+/// image
+pub fn close_on_exec(files: &RwLock<FdTbl>, token: &mut CleanLockToken) {
+    let closed = files.write().take_cloexec();
+    for file in closed {
+        let _ = file.close(token);
+    }
 }
 
+/// Pages initially committed to the bootstrap stack, below the top of user memory
+const BOOTSTRAP_STACK_PAGES: usize = 16;
+
 pub unsafe fn usermode_bootstrap(bootstrap: &Bootstrap, token: &mut CleanLockToken) {
     assert_ne!(bootstrap.page_count, 0);
 
+    // Orphaned contexts are reparented to the bootstrap context, which reaps them
+    context::reap::set_init(context::current().read(token.token()).id());
+
     {
         let addr_space = Arc::clone(
             context::current()
@@ -115,10 +319,23 @@ pub unsafe fn usermode_bootstrap(bootstrap: &Bootstrap, token: &mut CleanLockTok
                         mapper,
                         flusher,
                         shared,
-                    )?)
+                    )?
+                    .named("bootstrap"))
                 },
             )
             .expect("Failed to allocate bootstrap pages");
+
+        let stack_top = Page::containing_address(VirtualAddress::new(crate::USER_END_OFFSET));
+        let stack_pages =
+            NonZeroUsize::new(BOOTSTRAP_STACK_PAGES).expect("bootstrap stack must not be empty");
+        addr_space
+            .acquire_write()
+            .mmap_stack(
+                stack_top,
+                stack_pages,
+                MapFlags::PROT_READ | MapFlags::PROT_WRITE,
+            )
+            .expect("Failed to allocate bootstrap stack");
     }
 
     let bootstrap_slice = unsafe { bootstrap_mem(bootstrap) };
@@ -131,9 +348,12 @@ pub unsafe fn usermode_bootstrap(bootstrap: &Bootstrap, token: &mut CleanLockTok
     debug!("Bootstrap entry point: {:X}", bootstrap_entry);
     assert_ne!(bootstrap_entry, 0);
 
-    // Start in a minimal environment without any stack.
+    // Start in a minimal environment with nothing but a stack, which grows on demand.
 
     let ctx = context::current();
+    let files = Arc::clone(&ctx.read(token.token()).files);
+    close_on_exec(&files, token);
+
     let mut lock = ctx.write(token.token());
     let regs = &mut lock
         .regs_mut()
@@ -141,6 +361,7 @@ pub unsafe fn usermode_bootstrap(bootstrap: &Bootstrap, token: &mut CleanLockTok
     {
         regs.init();
         regs.set_instr_pointer(bootstrap_entry.try_into().unwrap());
+        regs.set_stack_pointer(crate::USER_END_OFFSET);
     }
 }
 
@@ -1,11 +1,24 @@
 //! # Time Syscalls
 
-use crate::syscall::error::{Error, EINVAL, Result};
-use crate::time;
+use core::mem::size_of;
+
+use crate::{
+    context::{self, timeout},
+    sync::CleanLockToken,
+    syscall::{
+        data::TimeSpec,
+        error::{Error, Result, EINTR, EINVAL},
+        usercopy::UserSlice,
+    },
+    time,
+};
 
 pub const CLOCK_REALTIME: usize = 0;
 pub const CLOCK_MONOTONIC: usize = 1;
 
+/// `clock_nanosleep` flag making the requested time an absolute deadline
+pub const TIMER_ABSTIME: usize = 1;
+
 pub fn clock_gettime(clock_id: usize, time: &mut time::TimeSpec) -> Result<usize> {
     match clock_id {
         CLOCK_REALTIME => {
@@ -22,4 +35,184 @@ pub fn clock_gettime(clock_id: usize, time: &mut time::TimeSpec) -> Result<usize
         }
         _ => Err(Error::new(EINVAL)),
     }
-}
\ No newline at end of file
+}
+
+/// Sleep for the duration at `req`, writing the time left to `rem` (if not null) when interrupted
+pub fn nanosleep(req: usize, rem: usize, token: &mut CleanLockToken) -> Result<usize> {
+    let duration = read_timespec(req)?;
+    sleep_until(time::monotonic().saturating_add(duration), rem, token)
+}
+
+/// Sleep on `clock` for the duration at `req`, or until it reads `req` with [`TIMER_ABSTIME`]
+///
+/// Only the monotonic clock is supported. As with POSIX, `rem` is not written for absolute
+/// sleeps, which can simply be restarted with the same deadline.
+pub fn clock_nanosleep(
+    clock: usize,
+    flags: usize,
+    req: usize,
+    rem: usize,
+    token: &mut CleanLockToken,
+) -> Result<usize> {
+    if clock != CLOCK_MONOTONIC || flags & !TIMER_ABSTIME != 0 {
+        return Err(Error::new(EINVAL));
+    }
+    let time = read_timespec(req)?;
+
+    if flags & TIMER_ABSTIME == TIMER_ABSTIME {
+        sleep_until(time, 0, token)
+    } else {
+        sleep_until(time::monotonic().saturating_add(time), rem, token)
+    }
+}
+
+fn read_timespec(addr: usize) -> Result<u128> {
+    let timespec = unsafe { UserSlice::ro(addr, size_of::<TimeSpec>())?.read_exact::<TimeSpec>()? };
+    timespec_to_nanos(&timespec)
+}
+
+fn timespec_to_nanos(timespec: &TimeSpec) -> Result<u128> {
+    let sec = u128::try_from(timespec.tv_sec).map_err(|_| Error::new(EINVAL))?;
+    let nsec = u128::try_from(timespec.tv_nsec).map_err(|_| Error::new(EINVAL))?;
+    if nsec >= time::NANOS_PER_SEC {
+        return Err(Error::new(EINVAL));
+    }
+    Ok(sec * time::NANOS_PER_SEC + nsec)
+}
+
+fn nanos_to_timespec(nanos: u128) -> TimeSpec {
+    TimeSpec {
+        tv_sec: (nanos / time::NANOS_PER_SEC) as i64,
+        tv_nsec: (nanos % time::NANOS_PER_SEC) as i32,
+    }
+}
+
+/// Block the current context until the monotonic clock reaches `deadline`
+///
+/// Only a pending signal interrupts the sleep, with EINTR and the time left written to `rem`.
+/// Any other wakeup before the deadline blocks again for the rest of it.
+fn sleep_until(deadline: u128, rem: usize, token: &mut CleanLockToken) -> Result<usize> {
+    let context_lock = context::current();
+    let weak = alloc::sync::Arc::downgrade(&context_lock);
+
+    loop {
+        let now = time::monotonic();
+        if now >= deadline {
+            return Ok(0);
+        }
+
+        {
+            let mut context = context_lock.write(token.token());
+            let interrupted = context
+                .sigcontrol()
+                .is_some_and(|(tctl, pctl, _)| tctl.currently_pending_unblocked(pctl) != 0);
+            if interrupted {
+                drop(context);
+                if let Some(rem) = UserSlice::wo(rem, size_of::<TimeSpec>())?.none_if_null() {
+                    rem.copy_exactly(&nanos_to_timespec(deadline - now))?;
+                }
+                return Err(Error::new(EINTR));
+            }
+            // Makes the timer fire in time for the registered wakeup
+            context.wake = Some(deadline);
+            context.block("nanosleep");
+        }
+
+        timeout::register_wakeup(weak.clone(), CLOCK_MONOTONIC, deadline, token);
+        // Program the deadline itself rather than waiting for a tick, which matters for short
+        // sleeps
+        time::set_next_timer_event(deadline as u64);
+
+        unsafe { context::switch(token) };
+
+        timeout::cancel_wakeup(&weak, token);
+        context_lock.write(token.token()).wake = None;
+    }
+}
+
+#[cfg(feature = "selftest")]
+pub mod selftests {
+    use alloc::format;
+
+    use super::*;
+    use crate::{
+        context::{kthread, ContextRef},
+        cpu_set::{LogicalCpuId, LogicalCpuSet},
+        scheduler,
+        selftest::{self, check, check_eq, SelftestResult},
+        sync::Priority,
+    };
+
+    /// How long the sleeper sleeps
+    const SLEEP_NS: u128 = 20_000_000;
+    /// How long into the sleep the sleeper is woken early
+    const EARLY_WAKEUP_NS: u128 = 5_000_000;
+
+    /// The sleeping context, once it is about to sleep
+    static SLEEPER: spin::Mutex<Option<ContextRef>> = spin::Mutex::new(None);
+    /// What the sleep returned and how long it took, once it is done
+    static SLEPT: spin::Mutex<Option<(Result<usize>, u128)>> = spin::Mutex::new(None);
+
+    fn sleeper() {
+        let mut token = unsafe { CleanLockToken::new() };
+        *SLEEPER.lock() = Some(context::current());
+        let start = time::monotonic();
+        let result = sleep_until(start.saturating_add(SLEEP_NS), 0, &mut token);
+        *SLEPT.lock() = Some((result, time::monotonic().saturating_sub(start)));
+    }
+
+    /// A kernel thread sleeps for 20ms and is woken by the timer, not before, even though it is
+    /// also unblocked early without a signal pending
+    pub fn sleep_wakes_at_deadline(token: &mut CleanLockToken) -> SelftestResult {
+        *SLEEPER.lock() = None;
+        *SLEPT.lock() = None;
+
+        let mut bsp = LogicalCpuSet::new();
+        bsp.add(LogicalCpuId::BSP);
+        kthread::spawn("[selftest_sleep]", bsp, Priority::Normal, sleeper, token)
+            .map_err(|err| format!("failed to spawn the sleeper: {}", err))?
+            .detach(token);
+
+        if !selftest::switch_until(|| SLEEPER.lock().is_some(), token) {
+            return Err("the sleeper never ran".into());
+        }
+        let early = time::monotonic().saturating_add(EARLY_WAKEUP_NS);
+        selftest::switch_until(|| time::monotonic() >= early, token);
+        let sleeper = SLEEPER.lock().take();
+        if let Some(sleeper) = sleeper {
+            check!(SLEPT.lock().is_some() || scheduler::unblock(&sleeper, token));
+        }
+
+        if !selftest::switch_until(|| SLEPT.lock().is_some(), token) {
+            return Err("the sleeper never woke up".into());
+        }
+        let (result, elapsed) = SLEPT.lock().take().unwrap_or((Ok(usize::MAX), 0));
+        check_eq!(result, Ok(0));
+        check!(elapsed >= SLEEP_NS);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timespec(tv_sec: i64, tv_nsec: i32) -> TimeSpec {
+        TimeSpec { tv_sec, tv_nsec }
+    }
+
+    #[test]
+    fn test_timespec_validation() {
+        assert_eq!(timespec_to_nanos(&timespec(1, 500)), Ok(1_000_000_500));
+        assert!(timespec_to_nanos(&timespec(-1, 0)).is_err());
+        assert!(timespec_to_nanos(&timespec(0, -1)).is_err());
+        assert!(timespec_to_nanos(&timespec(0, 1_000_000_000)).is_err());
+    }
+
+    #[test]
+    fn test_remaining_time() {
+        let rem = nanos_to_timespec(1_500_000_007);
+        assert_eq!((rem.tv_sec, rem.tv_nsec), (1, 500_000_007));
+        assert_eq!(timespec_to_nanos(&rem), Ok(1_500_000_007));
+    }
+}
@@ -6,9 +6,59 @@ use crate::{
     paging::{Page, VirtualAddress},
 };
 
+// Both copy `len` bytes from `src` to `dst` and return how many bytes were left uncopied, so 0
+// on success. They live between `__usercopy_start` and `__usercopy_end`, where a page fault on a
+// user address makes the fault handler return early from the copy instead of panicking.
 use crate::arch::{arch_copy_from_user, arch_copy_to_user};
 
-use crate::syscall::error::{Error, Result, EFAULT, EINVAL};
+use crate::syscall::error::{Error, Result, EFAULT, EINVAL, ENAMETOOLONG, ENOMEM};
+
+/// Error for a copy that stopped early, ENOMEM if the fault handler could not allocate the page
+fn copy_error() -> Error {
+    if crate::percpu::PercpuBlock::current()
+        .usercopy_enomem
+        .replace(false)
+    {
+        Error::new(ENOMEM)
+    } else {
+        Error::new(EFAULT)
+    }
+}
+
+/// Largest length a user slice can have unless the caller passes a lower cap
+pub const USER_SLICE_MAX_LEN: usize = isize::MAX as usize;
+
+/// Address space layout user slices are checked against
+#[derive(Clone, Copy, Debug)]
+struct Layout {
+    /// First address past user memory
+    user_end: u64,
+    /// Highest address of the target, as wide as `usize`
+    addr_max: u64,
+}
+
+const LAYOUT: Layout = Layout {
+    user_end: crate::USER_END_OFFSET as u64,
+    addr_max: usize::MAX as u64,
+};
+
+/// Check that `[base, base + len)` lies within user memory, without wrapping around the address
+/// space, and that `len` is at most `cap`
+///
+/// Works on `u64`, so that values that do not even fit the target's `usize` are caught as well.
+fn validate(base: u64, len: u64, cap: u64, layout: Layout) -> Result<()> {
+    if len > cap {
+        return Err(Error::new(EINVAL));
+    }
+    let end = base
+        .checked_add(len)
+        .filter(|&end| end <= layout.addr_max)
+        .ok_or(Error::new(EFAULT))?;
+    if base >= layout.user_end || end > layout.user_end {
+        return Err(Error::new(EFAULT));
+    }
+    Ok(())
+}
 
 #[derive(Clone, Copy)]
 pub struct UserSlice<const READ: bool, const WRITE: bool> {
@@ -32,13 +82,24 @@ impl<const READ: bool, const WRITE: bool> UserSlice<READ, WRITE> {
     pub fn addr(&self) -> usize {
         self.base
     }
+    /// Slice of user memory at `base`, failing with EFAULT if it reaches outside user memory
     pub fn new(base: usize, len: usize) -> Result<Self> {
-        if base >= crate::USER_END_OFFSET || base.saturating_add(len) >= crate::USER_END_OFFSET {
-            return Err(Error::new(EFAULT));
-        }
-
+        Self::new_capped(base, len, USER_SLICE_MAX_LEN)
+    }
+    /// Like [`Self::new`], also failing with EINVAL if `len` is above `cap`
+    pub fn new_capped(base: usize, len: usize, cap: usize) -> Result<Self> {
+        validate(base as u64, len as u64, cap as u64, LAYOUT)?;
         Ok(Self { base, len })
     }
+    /// Like [`Self::new`], for an address and length taken from a 64-bit field, which might not
+    /// fit a 32-bit `usize`
+    pub fn from_u64(base: u64, len: u64) -> Result<Self> {
+        validate(base, len, USER_SLICE_MAX_LEN as u64, LAYOUT)?;
+        Ok(Self {
+            base: base as usize,
+            len: len as usize,
+        })
+    }
     /// Split [0, end) into [0, idx) and [idx, end)
     pub fn split_at(self, idx: usize) -> Option<(Self, Self)> {
         if idx > self.len {
@@ -58,6 +119,10 @@ impl<const READ: bool, const WRITE: bool> UserSlice<READ, WRITE> {
     pub fn advance(self, by: usize) -> Option<Self> {
         Some(self.split_at(by)?.1)
     }
+    /// Like [`Self::advance`], failing with EINVAL past the end
+    pub fn checked_advance(self, by: usize) -> Result<Self> {
+        self.advance(by).ok_or(Error::new(EINVAL))
+    }
     pub fn limit(self, to: usize) -> Option<Self> {
         Some(self.split_at(to)?.0)
     }
@@ -105,7 +170,7 @@ impl<const WRITE: bool> UserSlice<true, WRITE> {
         {
             Ok(())
         } else {
-            Err(Error::new(EFAULT))
+            Err(copy_error())
         }
     }
     pub unsafe fn read_exact<T>(self) -> Result<T> {
@@ -169,7 +234,7 @@ impl<const READ: bool> UserSlice<READ, true> {
         if unsafe { arch_copy_to_user(self.base as *mut u8, slice.as_ptr(), self.len) } == 0 {
             Ok(())
         } else {
-            Err(Error::new(EFAULT))
+            Err(copy_error())
         }
     }
     pub fn copy_common_bytes_from_slice(self, slice: &[u8]) -> Result<usize> {
@@ -179,6 +244,15 @@ impl<const READ: bool> UserSlice<READ, true> {
             .copy_from_slice(&slice[..min])?;
         Ok(min)
     }
+    /// Copy a path for `kfpath`, failing with ENAMETOOLONG if it does not fit, as a truncated
+    /// path could name a different file
+    pub fn copy_path(self, path: &[u8]) -> Result<usize> {
+        if path.len() > self.len {
+            return Err(Error::new(ENAMETOOLONG));
+        }
+        self.copy_exactly(path)?;
+        Ok(path.len())
+    }
     pub fn copy_exactly(self, slice: &[u8]) -> Result<()> {
         self.limit(slice.len())
             .ok_or(Error::new(EINVAL))?
@@ -259,3 +333,144 @@ impl Buffer<'static> for UserSliceWo {
         Ok(())
     }
 }
+
+#[cfg(feature = "selftest")]
+pub mod selftests {
+    use super::*;
+    use crate::{
+        selftest::{self, check_eq, SelftestResult},
+        sync::CleanLockToken,
+    };
+
+    /// Copies from and to user memory that is not mapped fail with EFAULT instead of faulting
+    /// the kernel
+    pub fn unmapped_copies_fault(token: &mut CleanLockToken) -> SelftestResult {
+        selftest::with_user_page(copy_around_page, token)
+    }
+
+    fn copy_around_page(page: usize, _token: &mut CleanLockToken) -> SelftestResult {
+        let mut buf = [0_u8; 16];
+        // Nothing is mapped after the page in the otherwise empty address space
+        let unmapped = page.saturating_add(PAGE_SIZE);
+        let straddling = unmapped.saturating_sub(buf.len() / 2);
+
+        check_eq!(
+            UserSlice::ro(page, buf.len()).and_then(|user| user.copy_to_slice(&mut buf)),
+            Ok(())
+        );
+        check_eq!(
+            UserSlice::ro(unmapped, buf.len()).and_then(|user| user.copy_to_slice(&mut buf)),
+            Err(Error::new(EFAULT))
+        );
+        check_eq!(
+            UserSlice::wo(unmapped, buf.len()).and_then(|user| user.copy_from_slice(&buf)),
+            Err(Error::new(EFAULT))
+        );
+        check_eq!(
+            UserSlice::ro(straddling, buf.len()).and_then(|user| user.copy_to_slice(&mut buf)),
+            Err(Error::new(EFAULT))
+        );
+        check_eq!(
+            UserSlice::wo(straddling, buf.len()).and_then(|user| user.copy_from_slice(&buf)),
+            Err(Error::new(EFAULT))
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 32-bit x86, with the split at 2 GiB
+    const LAYOUT_32: Layout = Layout {
+        user_end: 0x8000_0000,
+        addr_max: u32::MAX as u64,
+    };
+    /// x86_64, with user memory ending a page below the top of the lower half
+    const LAYOUT_64: Layout = Layout {
+        user_end: 0x7FFF_FFFF_F000,
+        addr_max: u64::MAX,
+    };
+
+    fn check(base: u64, len: u64, layout: Layout) -> Result<()> {
+        validate(base, len, u64::MAX, layout)
+    }
+
+    #[test]
+    fn validate_boundaries() {
+        for layout in [LAYOUT_32, LAYOUT_64] {
+            let end = layout.user_end;
+            assert_eq!(check(end - 0x1000, 0x1000, layout), Ok(()));
+            assert_eq!(check(end - 1, 1, layout), Ok(()));
+            assert_eq!(check(end - 1, 2, layout), Err(Error::new(EFAULT)));
+            assert_eq!(check(end, 0, layout), Err(Error::new(EFAULT)));
+            assert_eq!(check(end - 0x1000, 0x1001, layout), Err(Error::new(EFAULT)));
+            assert_eq!(check(0, end, layout), Ok(()));
+        }
+    }
+
+    #[test]
+    fn validate_rejects_wraparound() {
+        for layout in [LAYOUT_32, LAYOUT_64] {
+            let usize_max = layout.addr_max;
+            assert_eq!(check(0x1000, usize_max, layout), Err(Error::new(EFAULT)));
+            assert_eq!(check(usize_max, 1, layout), Err(Error::new(EFAULT)));
+            assert_eq!(check(0, usize_max, layout), Err(Error::new(EFAULT)));
+        }
+        // Fits in 64 bits, but wraps a 32-bit address
+        assert_eq!(
+            check(0x7FFF_F000, 0x8000_1000, LAYOUT_32),
+            Err(Error::new(EFAULT))
+        );
+        // Does not fit a 32-bit usize at all
+        assert_eq!(
+            check(0x1_0000_1000, 0x10, LAYOUT_32),
+            Err(Error::new(EFAULT))
+        );
+        assert_eq!(
+            check(u64::MAX, u64::MAX, LAYOUT_64),
+            Err(Error::new(EFAULT))
+        );
+    }
+
+    #[test]
+    fn validate_applies_cap() {
+        assert_eq!(validate(0x1000, 0x100, 0x100, LAYOUT_64), Ok(()));
+        assert_eq!(
+            validate(0x1000, 0x101, 0x100, LAYOUT_64),
+            Err(Error::new(EINVAL))
+        );
+        assert_eq!(
+            UserSliceRo::new_capped(0x1000, 0x101, 0x100).err(),
+            Some(Error::new(EINVAL))
+        );
+    }
+
+    #[test]
+    fn advance_and_split_stay_inside() {
+        let buf = UserSliceRo::new(0x1000, 0x20).unwrap();
+        let (head, tail) = buf.split_at(0x8).unwrap();
+        assert_eq!((head.addr(), head.len()), (0x1000, 0x8));
+        assert_eq!((tail.addr(), tail.len()), (0x1008, 0x18));
+        assert!(buf.split_at(0x21).is_none());
+        assert_eq!(buf.checked_advance(0x20).map(|b| b.len()), Ok(0));
+        assert_eq!(
+            buf.checked_advance(usize::MAX).err(),
+            Some(Error::new(EINVAL))
+        );
+        assert!(UserSliceRo::new(0x1000, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn copy_path_rejects_short_buffer() {
+        // Checked before anything is copied, so these never touch the address
+        let buf = UserSliceWo::new(0x1000, 8).unwrap();
+        assert_eq!(buf.copy_path(b"pipe:read/2"), Err(Error::new(ENAMETOOLONG)));
+        assert_eq!(buf.copy_path(b"pipe:rea/"), Err(Error::new(ENAMETOOLONG)));
+        assert_eq!(
+            UserSliceWo::empty().copy_path(b"event:1"),
+            Err(Error::new(ENAMETOOLONG))
+        );
+    }
+}
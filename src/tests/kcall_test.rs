@@ -0,0 +1,191 @@
+//! Kcall Forwarding Test
+//!
+//! A kernel context stands in for a user scheme handler, registered through the root scheme. It
+//! answers the first call only after checking that the caller is still blocked, then closes its
+//! root handle with the second call outstanding, as a provider exiting would. That call must fail
+//! with EIO, and the name must be free to register again.
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+use syscall::{schemev2::Opcode, CallFlags, O_CREAT, O_FSYNC};
+
+use crate::{
+    context::{self, kthread, memory::DANGLING},
+    cpu_set::LogicalCpuSet,
+    scheme::{
+        self,
+        user::{UserInner, UserScheme},
+        CallerCtx, KernelScheme, KernelSchemes, OpenResult, SchemeNamespace,
+    },
+    sync::{CleanLockToken, Priority},
+    syscall::{error::*, usercopy::UserSlice},
+};
+
+static INNER: spin::Once<Arc<UserInner>> = spin::Once::new();
+/// The root scheme, and the mock handler's handle in it
+static ROOT: spin::Once<(Arc<KernelSchemes>, usize)> = spin::Once::new();
+static ANSWERED: AtomicBool = AtomicBool::new(false);
+
+const NAME: &str = "kcall_test";
+const FILE: usize = 7;
+const ANSWER: usize = 42;
+
+pub fn start_kcall_test() {
+    println!("KCALL TEST: Starting...");
+
+    let mut token = unsafe { CleanLockToken::new() };
+
+    let root = match scheme::schemes(&token.token()).get_name(SchemeNamespace::from(0), "root") {
+        Some((_, root)) => Arc::clone(root),
+        None => {
+            println!("KCALL TEST: FAILED, no root scheme");
+            return;
+        }
+    };
+    let handle = match register(&root, &mut token) {
+        Ok(handle) => handle,
+        Err(err) => {
+            println!("KCALL TEST: FAILED to register: {}", err);
+            return;
+        }
+    };
+    let inner = match scheme::schemes(&token.token()).get_name(SchemeNamespace::from(0), NAME) {
+        Some((_, scheme)) => match &**scheme {
+            KernelSchemes::User(user) => user.inner.upgrade(),
+            KernelSchemes::Global(_) => None,
+        },
+        None => None,
+    };
+    let Some(inner) = inner else {
+        println!("KCALL TEST: FAILED, registered scheme not found");
+        return;
+    };
+    INNER.call_once(|| inner);
+    ROOT.call_once(|| (root, handle));
+
+    for (call, name) in [
+        (mock_handler as fn(), "[kcall_test_handler]"),
+        (caller, "[kcall_test_caller]"),
+    ] {
+        match kthread::spawn(
+            name,
+            LogicalCpuSet::all(),
+            Priority::Normal,
+            call,
+            &mut token,
+        ) {
+            Ok(handle) => handle.detach(&mut token),
+            Err(err) => println!("KCALL TEST: FAILED to spawn {}: {}", name, err),
+        }
+    }
+}
+
+/// Register the mock scheme as a v2 provider, returning its root handle
+fn register(root: &KernelSchemes, token: &mut CleanLockToken) -> Result<usize> {
+    let ctx = CallerCtx {
+        uid: 0,
+        gid: 0,
+        pid: 1,
+        ns: SchemeNamespace::from(0),
+        mode: 0,
+    };
+    match root.kopen(NAME, O_CREAT | O_FSYNC, ctx, token)? {
+        OpenResult::SchemeLocal(handle, _) => Ok(handle),
+        _ => Err(Error::new(EIO)),
+    }
+}
+
+fn mock_handler() {
+    let mut token = unsafe { CleanLockToken::new() };
+    let inner = INNER.get().expect("kcall test not started");
+    let (root, handle) = ROOT.get().expect("kcall test not started");
+
+    match inner.next_request(&mut token) {
+        Ok(sqe) => {
+            let expected = [FILE as u64, DANGLING as u64, 0, 1, 2, 3];
+            if sqe.opcode != Opcode::Call as u8
+                || sqe.args != expected
+                || sqe._rsvd != CallFlags::READ.bits() as u16
+            {
+                println!("KCALL TEST: FAILED, unexpected request {:?}", sqe.args);
+            }
+            // The caller cannot have returned before its call was answered
+            if ANSWERED.load(Ordering::SeqCst) {
+                println!("KCALL TEST: FAILED, caller did not block");
+            }
+            if let Err(err) = inner.respond_regular(sqe.tag, Ok(ANSWER), &mut token) {
+                println!("KCALL TEST: FAILED to respond: {}", err);
+            }
+        }
+        Err(err) => println!("KCALL TEST: FAILED to receive first call: {}", err),
+    }
+
+    // Leave the second call unanswered, as a handler exiting mid-request would
+    if let Err(err) = inner.next_request(&mut token) {
+        println!("KCALL TEST: FAILED to receive second call: {}", err);
+    }
+    if let Err(err) = root.close(*handle, &mut token) {
+        println!("KCALL TEST: FAILED to close the root handle: {}", err);
+    }
+
+    // A restarted provider can take the name again
+    match register(root, &mut token) {
+        Ok(handle) => {
+            let _ = root.close(handle, &mut token);
+        }
+        Err(err) => println!("KCALL TEST: FAILED to register again: {}", err),
+    }
+
+    park();
+}
+
+fn caller() {
+    let mut token = unsafe { CleanLockToken::new() };
+    let scheme = UserScheme::new(Arc::downgrade(INNER.get().expect("kcall test not started")));
+
+    let res = scheme.kcall(
+        FILE,
+        UserSlice::empty(),
+        CallFlags::READ | CallFlags::CONSUME,
+        &[1, 2, 3],
+        &mut token,
+    );
+    ANSWERED.store(true, Ordering::SeqCst);
+    if res != Ok(ANSWER) {
+        println!("KCALL TEST: FAILED, first call returned {:?}", res);
+    }
+
+    let res = scheme.kcall(
+        FILE,
+        UserSlice::empty(),
+        CallFlags::empty(),
+        &[1],
+        &mut token,
+    );
+    if res != Err(Error::new(EIO)) {
+        println!(
+            "KCALL TEST: FAILED, call to exited handler returned {:?}",
+            res
+        );
+    }
+
+    let res = scheme.kcall(
+        FILE,
+        UserSlice::empty(),
+        CallFlags::empty(),
+        &[],
+        &mut token,
+    );
+    if res != Err(Error::new(EIO)) {
+        println!("KCALL TEST: FAILED, call after unmount returned {:?}", res);
+    }
+
+    println!("KCALL TEST: Completed.");
+    park();
+}
+
+fn park() -> ! {
+    loop {
+        unsafe { context::switch(&mut CleanLockToken::new()) };
+    }
+}
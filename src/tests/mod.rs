@@ -1 +1,6 @@
+#[cfg(feature = "kcall_test")]
+pub mod kcall_test;
+#[cfg(feature = "stress_test")]
 pub mod stress_test;
+#[cfg(feature = "usercopy_test")]
+pub mod usercopy_test;
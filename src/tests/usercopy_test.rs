@@ -0,0 +1,85 @@
+//! Usercopy Fault Test
+//!
+//! A kernel context reads sys:uname into a user address with nothing mapped at it, which must
+//! fail with EFAULT instead of crashing the kernel, and then copies from that address directly.
+//! On riscv64 it also checks that the failed copies left SSTATUS.SUM clear.
+
+use crate::{
+    context::kthread,
+    cpu_set::LogicalCpuSet,
+    memory::PAGE_SIZE,
+    scheme::{sys::SysScheme, CallerCtx, KernelScheme, OpenResult, SchemeNamespace},
+    sync::{CleanLockToken, Priority},
+    syscall::{error::*, flag::O_RDONLY, usercopy::UserSlice},
+};
+
+/// A user address kernel contexts have nothing mapped at
+const UNMAPPED: usize = PAGE_SIZE;
+
+pub fn start_usercopy_test() {
+    println!("USERCOPY TEST: Starting...");
+
+    let mut token = unsafe { CleanLockToken::new() };
+    match kthread::spawn(
+        "[usercopy_test]",
+        LogicalCpuSet::all(),
+        Priority::Normal,
+        run,
+        &mut token,
+    ) {
+        Ok(handle) => handle.detach(&mut token),
+        Err(err) => println!("USERCOPY TEST: FAILED to spawn: {}", err),
+    }
+}
+
+fn run() {
+    let mut token = unsafe { CleanLockToken::new() };
+
+    let ctx = CallerCtx {
+        uid: 0,
+        gid: 0,
+        pid: 1,
+        ns: SchemeNamespace::from(0),
+        mode: 0,
+    };
+    let id = match SysScheme.kopen("uname", O_RDONLY, ctx, &mut token) {
+        Ok(OpenResult::SchemeLocal(id, _)) => id,
+        Ok(_) => {
+            println!("USERCOPY TEST: FAILED, unexpected open result");
+            return;
+        }
+        Err(err) => {
+            println!("USERCOPY TEST: FAILED to open sys:uname: {}", err);
+            return;
+        }
+    };
+
+    let res = UserSlice::wo(UNMAPPED, 64)
+        .and_then(|buf| SysScheme.kreadoff(id, buf, 0, 0, 0, &mut token));
+    if res != Err(Error::new(EFAULT)) {
+        println!("USERCOPY TEST: FAILED, read returned {:?}", res);
+    }
+    let _ = SysScheme.close(id, &mut token);
+    check_sum_clear();
+
+    let mut bytes = [0_u8; 16];
+    let res = UserSlice::ro(UNMAPPED, bytes.len()).and_then(|buf| buf.copy_to_slice(&mut bytes));
+    if res != Err(Error::new(EFAULT)) {
+        println!("USERCOPY TEST: FAILED, copy from user returned {:?}", res);
+    }
+    check_sum_clear();
+
+    println!("USERCOPY TEST: Completed.");
+}
+
+#[cfg(target_arch = "riscv64")]
+fn check_sum_clear() {
+    let sstatus: usize;
+    unsafe { core::arch::asm!("csrr {}, sstatus", out(reg) sstatus, options(nomem, nostack)) };
+    if sstatus & crate::arch::SSTATUS_SUM != 0 {
+        println!("USERCOPY TEST: FAILED, SUM left set");
+    }
+}
+
+#[cfg(not(target_arch = "riscv64"))]
+fn check_sum_clear() {}
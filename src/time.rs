@@ -1,7 +1,13 @@
-use spin::Mutex;
+use core::{
+    ptr,
+    sync::atomic::{fence, AtomicU32, AtomicU64, Ordering},
+};
+use spin::{Mutex, Once};
 
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::arch::x86_shared::device::pit;
 use crate::{
-    arch::x86_shared::device::pit,
+    memory::{allocate_frame, Frame, RmmA, RmmArch, PAGE_SIZE},
     sync::CleanLockToken,
     syscall::error::{Error, Result, EINVAL},
 };
@@ -43,42 +49,280 @@ pub fn realtime() -> u128 {
 /// Updates the kernel's time offset.
 pub fn sys_update_time_offset(buf: &[u8], _token: &mut CleanLockToken) -> Result<usize> {
     let start = <[u8; 16]>::try_from(buf).map_err(|_| Error::new(EINVAL))?;
-    *START.lock() = u128::from_ne_bytes(start);
+    let start = u128::from_ne_bytes(start);
+    *START.lock() = start;
+    publish(|snapshot| snapshot.realtime_offset = start as u64);
     Ok(16)
 }
 
 /// Sets the next timer event to fire at the given deadline (in nanoseconds).
 pub fn set_next_timer_event(deadline: u64) {
-    let now = monotonic() as u64;
-    let delta = deadline.saturating_sub(now);
+    // The SBI timer is one-shot per hart, arch::time merges this with the time slice deadline
+    #[cfg(target_arch = "riscv64")]
+    crate::arch::time::set_wake_deadline(deadline);
 
-    let active_timer = ACTIVE_TIMER.lock();
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        let now = monotonic() as u64;
+        let delta = deadline.saturating_sub(now);
 
-    unsafe {
-        match *active_timer {
-            ActiveTimer::Pit => {
-                // PIT operates with a divisor. Calculate divisor from delta.
-                // 1.193182 MHz is PIT frequency.
-                let pit_frequency_hz = 1_193_182;
-                let nanoseconds_per_pit_tick = 1_000_000_000 / pit_frequency_hz;
-                let divisor = (delta / nanoseconds_per_pit_tick) as u16;
-                pit::oneshot(divisor.max(1)); // Divisor must be at least 1
+        let active_timer = ACTIVE_TIMER.lock();
+
+        unsafe {
+            match *active_timer {
+                ActiveTimer::Pit => {
+                    // PIT operates with a divisor. Calculate divisor from delta.
+                    // 1.193182 MHz is PIT frequency.
+                    let pit_frequency_hz = 1_193_182;
+                    let nanoseconds_per_pit_tick = 1_000_000_000 / pit_frequency_hz;
+                    let divisor = (delta / nanoseconds_per_pit_tick) as u16;
+                    pit::oneshot(divisor.max(1)); // Divisor must be at least 1
+                }
+                #[cfg(feature = "acpi")]
+                ActiveTimer::Hpet => {
+                    let hpet_ref = hpet::get_hpet_mut();
+                    let hpet_period_fs = hpet_ref.get_period_femtoseconds();
+                    let hpet_current_counter = hpet::read_main_counter();
+
+                    // Convert delta (ns) to femtoseconds, then to HPET ticks
+                    let delta_fs = delta as u128 * 1_000_000; // Convert ns to fs
+                    let target_ticks = hpet_current_counter + (delta_fs / hpet_period_fs as u128) as u64;
+
+                    hpet::set_comparator(hpet_ref, target_ticks);
+                }
+                ActiveTimer::None => {
+                    warn!("No active timer to set event for!");
+                }
             }
-            #[cfg(feature = "acpi")]
-            ActiveTimer::Hpet => {
-                let hpet_ref = hpet::get_hpet_mut();
-                let hpet_period_fs = hpet_ref.get_period_femtoseconds();
-                let hpet_current_counter = hpet::read_main_counter();
+        }
+    }
+}
+
+/// Layout version of [`TimePage`]
+pub const TIME_PAGE_VERSION: u32 = 1;
+
+/// Timekeeping parameters shared read-only with any process through `/scheme/time/page`
+///
+/// Userspace gets monotonic time without a syscall as
+/// `tick_ns + ((counter - tick_counter) * mult >> 32)`, reading `counter` with rdtsc, and
+/// realtime by adding `realtime_offset`. A `counter_hz` of 0 means no usable counter is
+/// published and the clock has to be read through the time scheme.
+///
+/// Fields are updated under a seqlock: readers retry while `seq` is odd or if it changed while
+/// they were reading, see [`TimePage::read`].
+#[repr(C)]
+#[derive(Debug)]
+pub struct TimePage {
+    pub seq: AtomicU32,
+    pub version: AtomicU32,
+    /// Monotonic time in nanoseconds at the last timer tick
+    pub tick_ns: AtomicU64,
+    /// Counter value at the last timer tick
+    pub tick_counter: AtomicU64,
+    /// Counter frequency in Hz
+    pub counter_hz: AtomicU64,
+    /// Nanoseconds per counter cycle, as a 32.32 fixed point number
+    pub mult: AtomicU64,
+    /// Realtime minus monotonic time, in nanoseconds
+    pub realtime_offset: AtomicU64,
+}
+
+/// Consistent copy of the fields of a [`TimePage`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TimeSnapshot {
+    pub tick_ns: u64,
+    pub tick_counter: u64,
+    pub counter_hz: u64,
+    pub mult: u64,
+    pub realtime_offset: u64,
+}
+
+impl TimeSnapshot {
+    const fn new() -> Self {
+        Self {
+            tick_ns: 0,
+            tick_counter: 0,
+            counter_hz: 0,
+            mult: 0,
+            realtime_offset: 0,
+        }
+    }
+
+    /// Monotonic time in nanoseconds at `counter`, as userspace computes it
+    pub fn monotonic(&self, counter: u64) -> u64 {
+        let elapsed = counter.wrapping_sub(self.tick_counter) as u128;
+        self.tick_ns + ((elapsed * self.mult as u128) >> 32) as u64
+    }
+}
+
+impl TimePage {
+    /// Store `snapshot`. Writers must be serialized, which [`publish`] does.
+    fn write(&self, snapshot: &TimeSnapshot) {
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        self.version.store(TIME_PAGE_VERSION, Ordering::Relaxed);
+        self.tick_ns.store(snapshot.tick_ns, Ordering::Relaxed);
+        self.tick_counter
+            .store(snapshot.tick_counter, Ordering::Relaxed);
+        self.counter_hz
+            .store(snapshot.counter_hz, Ordering::Relaxed);
+        self.mult.store(snapshot.mult, Ordering::Relaxed);
+        self.realtime_offset
+            .store(snapshot.realtime_offset, Ordering::Relaxed);
 
-                // Convert delta (ns) to femtoseconds, then to HPET ticks
-                let delta_fs = delta as u128 * 1_000_000; // Convert ns to fs
-                let target_ticks = hpet_current_counter + (delta_fs / hpet_period_fs as u128) as u64;
+        self.seq.store(seq.wrapping_add(2), Ordering::Release);
+    }
 
-                hpet::set_comparator(hpet_ref, target_ticks);
+    /// Read a consistent snapshot, the way userspace does
+    pub fn read(&self) -> TimeSnapshot {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq % 2 == 1 {
+                core::hint::spin_loop();
+                continue;
             }
-            ActiveTimer::None => {
-                warn!("No active timer to set event for!");
+            let snapshot = TimeSnapshot {
+                tick_ns: self.tick_ns.load(Ordering::Relaxed),
+                tick_counter: self.tick_counter.load(Ordering::Relaxed),
+                counter_hz: self.counter_hz.load(Ordering::Relaxed),
+                mult: self.mult.load(Ordering::Relaxed),
+                realtime_offset: self.realtime_offset.load(Ordering::Relaxed),
+            };
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == seq {
+                return snapshot;
             }
         }
     }
 }
+
+static TIME_PAGE: Once<Frame> = Once::new();
+/// Last published values, also serializing writers of the time page
+static PUBLISHED: Mutex<TimeSnapshot> = Mutex::new(TimeSnapshot::new());
+
+/// Allocate the time page and fill it with everything published so far, called once at boot
+pub fn init_time_page() {
+    let Some(frame) = allocate_frame() else {
+        warn!("time: failed to allocate time page, clocks are only readable by syscall");
+        return;
+    };
+    unsafe {
+        let virt = RmmA::phys_to_virt(frame.base()).data() as *mut u8;
+        ptr::write_bytes(virt, 0, PAGE_SIZE);
+    }
+    let published = PUBLISHED.lock();
+    TIME_PAGE.call_once(|| frame);
+    if let Some(page) = time_page() {
+        page.write(&published);
+    }
+}
+
+/// Frame of the time page, for mapping it into processes
+pub fn time_page_frame() -> Option<Frame> {
+    TIME_PAGE.get().copied()
+}
+
+fn time_page() -> Option<&'static TimePage> {
+    let frame = TIME_PAGE.get()?;
+    Some(unsafe { &*(RmmA::phys_to_virt(frame.base()).data() as *const TimePage) })
+}
+
+/// Update the published timekeeping parameters and the time page, if allocated
+fn publish(update: impl FnOnce(&mut TimeSnapshot)) {
+    let mut published = PUBLISHED.lock();
+    update(&mut published);
+    if let Some(page) = time_page() {
+        page.write(&published);
+    }
+}
+
+/// Counter readable by userspace that the time page is calibrated against
+fn counter() -> u64 {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        crate::arch::x86_shared::device::tsc::tsc_read()
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    {
+        0
+    }
+}
+
+/// Record the monotonic time and counter value of a timer tick, called from the tick interrupt
+pub fn publish_tick() {
+    // A writer interrupted on this CPU would deadlock us, the next tick catches up instead
+    let Some(mut published) = PUBLISHED.try_lock() else {
+        return;
+    };
+    published.tick_ns = monotonic() as u64;
+    published.tick_counter = counter();
+    if let Some(page) = time_page() {
+        page.write(&published);
+    }
+}
+
+/// Publish a newly calibrated counter frequency, 0 if the counter is unusable
+pub fn publish_counter_frequency(hz: u64) {
+    let tick_ns = monotonic() as u64;
+    let tick_counter = counter();
+    publish(|snapshot| {
+        snapshot.tick_ns = tick_ns;
+        snapshot.tick_counter = tick_counter;
+        snapshot.counter_hz = hz;
+        snapshot.mult = match hz {
+            0 => 0,
+            hz => ((NANOS_PER_SEC << 32) / hz as u128) as u64,
+        };
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page() -> TimePage {
+        TimePage {
+            seq: AtomicU32::new(0),
+            version: AtomicU32::new(0),
+            tick_ns: AtomicU64::new(0),
+            tick_counter: AtomicU64::new(0),
+            counter_hz: AtomicU64::new(0),
+            mult: AtomicU64::new(0),
+            realtime_offset: AtomicU64::new(0),
+        }
+    }
+
+    #[test]
+    fn test_time_page_write_read() {
+        let page = page();
+        let snapshot = TimeSnapshot {
+            tick_ns: 5_000,
+            tick_counter: 100,
+            counter_hz: 2_000_000_000,
+            mult: (NANOS_PER_SEC << 32) as u64 / 2_000_000_000,
+            realtime_offset: 7,
+        };
+        page.write(&snapshot);
+
+        assert_eq!(page.read(), snapshot);
+        assert_eq!(page.seq.load(Ordering::Relaxed), 2);
+        assert_eq!(page.version.load(Ordering::Relaxed), TIME_PAGE_VERSION);
+    }
+
+    #[test]
+    fn test_snapshot_monotonic() {
+        let snapshot = TimeSnapshot {
+            tick_ns: 1_000,
+            tick_counter: 500,
+            counter_hz: 2_000_000_000,
+            mult: ((NANOS_PER_SEC << 32) / 2_000_000_000) as u64,
+            realtime_offset: 0,
+        };
+
+        assert_eq!(snapshot.monotonic(500), 1_000);
+        // 2 GHz, so 2000 cycles are 1000ns
+        assert_eq!(snapshot.monotonic(2_500), 2_000);
+    }
+}
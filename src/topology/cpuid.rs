@@ -0,0 +1,108 @@
+//! Package, core and thread of x86_64 CPUs, from the CPUID extended topology leaves
+//!
+//! The x2APIC ID of a CPU is made of bit fields: the SMT thread in the low bits, then the core,
+//! then any module, tile or die levels, and the package in the remaining high bits. Leaf 0x1F, or
+//! 0xB on older CPUs, gives the width of each level. Levels between the core and the package are
+//! folded into the core number, so cores are unique within their package.
+
+use core::arch::x86_64::__cpuid_count;
+
+use super::{CpuPlace, CPU_TOPOLOGY};
+use crate::cpu_set::LogicalCpuId;
+
+/// Level type of the SMT level in ECX[15:8] of leaves 0xB and 0x1F
+const LEVEL_SMT: u32 = 1;
+
+/// Record the position of the calling CPU, which is `cpu`
+pub fn init_cpu(cpu: LogicalCpuId) {
+    let place = detect();
+    debug!(
+        "CPU {}: APIC ID {}, package {} core {} thread {}",
+        cpu, place.hw_id, place.package, place.core, place.thread
+    );
+    CPU_TOPOLOGY.add_cpu(cpu, place);
+}
+
+fn detect() -> CpuPlace {
+    let max_leaf = unsafe { __cpuid_count(0, 0) }.eax;
+    for leaf in [0x1F, 0xB] {
+        if leaf > max_leaf {
+            continue;
+        }
+        let mut levels = (0..)
+            .map(|subleaf| unsafe { __cpuid_count(leaf, subleaf) })
+            .take_while(|regs| (regs.ecx >> 8) & 0xFF != 0)
+            .peekable();
+        let Some(first) = levels.peek() else {
+            continue;
+        };
+        let apic_id = first.edx;
+        let (smt_shift, package_shift) =
+            shifts(levels.map(|regs| ((regs.ecx >> 8) & 0xFF, regs.eax & 0x1F)));
+        return decompose(apic_id, smt_shift, package_shift);
+    }
+
+    // Leaf 1 only gives the 8-bit APIC ID and the number of IDs reserved per package
+    let leaf1 = unsafe { __cpuid_count(1, 0) };
+    let apic_id = leaf1.ebx >> 24;
+    let per_package = ((leaf1.ebx >> 16) & 0xFF).max(1);
+    decompose(apic_id, 0, per_package.next_power_of_two().trailing_zeros())
+}
+
+/// Width of the thread field and of all fields below the package, from the (level type, shift to
+/// the next level) pairs of the topology leaf
+fn shifts(levels: impl Iterator<Item = (u32, u32)>) -> (u32, u32) {
+    let mut smt_shift = 0;
+    let mut package_shift = 0;
+    for (kind, shift) in levels {
+        if kind == LEVEL_SMT {
+            smt_shift = shift;
+        }
+        package_shift = shift;
+    }
+    (smt_shift, package_shift.max(smt_shift))
+}
+
+fn decompose(apic_id: u32, smt_shift: u32, package_shift: u32) -> CpuPlace {
+    let field = |shift: u32| apic_id.checked_shr(shift).unwrap_or(0);
+    CpuPlace {
+        hw_id: apic_id,
+        package: field(package_shift),
+        core: field(smt_shift) & ((1 << (package_shift - smt_shift)) - 1),
+        thread: apic_id & ((1 << smt_shift) - 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_of_apic_id() {
+        // 2 threads per core, 8 cores per package
+        let (smt, package) = shifts([(1, 1), (2, 4)].into_iter());
+        assert_eq!((smt, package), (1, 4));
+
+        let place = decompose(0b1_011_1, smt, package);
+        assert_eq!((place.package, place.core, place.thread), (1, 0b011, 1));
+    }
+
+    #[test]
+    fn die_levels_fold_into_core() {
+        // SMT, core, then a die level, as leaf 0x1F reports on multi-die packages
+        let (smt, package) = shifts([(1, 1), (2, 3), (5, 5)].into_iter());
+        assert_eq!((smt, package), (1, 5));
+
+        let place = decompose(0b10_1101_1, smt, package);
+        assert_eq!((place.package, place.core, place.thread), (0b10, 0b1101, 1));
+    }
+
+    #[test]
+    fn without_smt() {
+        let (smt, package) = shifts([(2, 2)].into_iter());
+        assert_eq!((smt, package), (0, 2));
+
+        let place = decompose(7, smt, package);
+        assert_eq!((place.package, place.core, place.thread), (1, 3, 0));
+    }
+}
@@ -0,0 +1,125 @@
+//! Topology from the device tree
+//!
+//! CPUs are the `/cpus/cpu@*` nodes, numbered in the order they appear, which is the order the
+//! rest of the kernel assumes for logical CPU IDs. Their position comes from `/cpus/cpu-map`,
+//! where each `socketN` is a package and every `coreN` under it, nested in any number of
+//! clusters, is a core with `threadN` children for SMT. CPUs and memory nodes carry their NUMA
+//! node in a `numa-node-id` property.
+
+use alloc::collections::BTreeMap;
+use fdt::{node::FdtNode, Fdt};
+
+use super::{CpuPlace, NumaNodeId, CPU_TOPOLOGY};
+use crate::cpu_set::LogicalCpuId;
+
+pub fn init(fdt: &Fdt) {
+    let Some(cpus) = fdt.find_node("/cpus") else {
+        warn!("devicetree has no /cpus node");
+        return;
+    };
+
+    // Position in the cpu-map by CPU phandle
+    let mut positions = BTreeMap::new();
+    if let Some(cpu_map) = cpus.children().find(|node| node.name == "cpu-map") {
+        let mut next_core = BTreeMap::new();
+        for (index, socket) in cpu_map
+            .children()
+            .filter(|node| node.name.starts_with("socket"))
+            .enumerate()
+        {
+            walk_cpu_map(socket, index as u32, &mut next_core, &mut positions);
+        }
+        // Older bindings place clusters directly in the cpu-map, all in one package
+        for cluster in cpu_map
+            .children()
+            .filter(|node| node.name.starts_with("cluster"))
+        {
+            walk_cpu_map(cluster, 0, &mut next_core, &mut positions);
+        }
+    }
+
+    for (index, cpu) in cpus
+        .children()
+        .filter(|node| node.name.starts_with("cpu@"))
+        .enumerate()
+    {
+        let Some(hw_id) = cpu.property("reg").and_then(|reg| reg.as_usize()) else {
+            continue;
+        };
+        let hw_id = hw_id as u32;
+        let (package, core, thread) = cpu
+            .property("phandle")
+            .and_then(|phandle| phandle.as_usize())
+            .and_then(|phandle| positions.get(&phandle).copied())
+            .unwrap_or((0, index as u32, 0));
+
+        CPU_TOPOLOGY.add_cpu(
+            LogicalCpuId::new(index as u32),
+            CpuPlace {
+                hw_id,
+                package,
+                core,
+                thread,
+            },
+        );
+        if let Some(node) = numa_node(&cpu) {
+            CPU_TOPOLOGY.set_cpu_node(hw_id, node);
+        }
+    }
+
+    for memory in fdt
+        .all_nodes()
+        .filter(|node| node.name.starts_with("memory"))
+    {
+        let (Some(node), Some(regions)) = (numa_node(&memory), memory.reg()) else {
+            continue;
+        };
+        for region in regions {
+            let base = region.starting_address as usize;
+            let size = region.size.unwrap_or(0);
+            CPU_TOPOLOGY.add_memory(base..base + size, node);
+        }
+    }
+
+    crate::memory::assign_nodes();
+}
+
+/// Assign every core below `node` the next core number of `package`
+fn walk_cpu_map(
+    node: FdtNode,
+    package: u32,
+    next_core: &mut BTreeMap<u32, u32>,
+    positions: &mut BTreeMap<usize, (u32, u32, u32)>,
+) {
+    for child in node.children() {
+        if child.name.starts_with("cluster") {
+            walk_cpu_map(child, package, next_core, positions);
+        } else if child.name.starts_with("core") {
+            let core = next_core.entry(package).or_insert(0);
+            let threads = child
+                .children()
+                .filter(|node| node.name.starts_with("thread"));
+            let mut any_thread = false;
+            for (thread, leaf) in threads.enumerate() {
+                any_thread = true;
+                if let Some(cpu) = cpu_phandle(&leaf) {
+                    positions.insert(cpu, (package, *core, thread as u32));
+                }
+            }
+            if !any_thread && let Some(cpu) = cpu_phandle(&child) {
+                positions.insert(cpu, (package, *core, 0));
+            }
+            *core += 1;
+        }
+    }
+}
+
+fn cpu_phandle(node: &FdtNode) -> Option<usize> {
+    node.property("cpu").and_then(|cpu| cpu.as_usize())
+}
+
+fn numa_node(node: &FdtNode) -> Option<NumaNodeId> {
+    node.property("numa-node-id")
+        .and_then(|id| id.as_usize())
+        .map(|id| NumaNodeId(id as u32))
+}
@@ -1,12 +1,234 @@
 //! # Topology and Affinity
+//!
+//! Records where each CPU sits in the machine: its package, core and SMT thread, and the NUMA
+//! node it and each range of physical memory belong to. The position of a CPU is reported by the
+//! CPU itself on x86_64 and by the device tree elsewhere, while NUMA affinity comes from the ACPI
+//! SRAT or the device tree. The two are joined by the hardware CPU ID (APIC ID, MPIDR or hart ID)
+//! when queried, so either may be recorded first.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{
+    fmt,
+    ops::Range,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use spin::RwLock;
 
 use crate::{
     context,
-    cpu_set::CpuSet,
+    cpu_set::{CpuSet, LogicalCpuId, MAX_CPU_COUNT},
     syscall::error::{Error, EINVAL, ESRCH},
 };
 
-pub use crate::stubs::topology::*;
+#[cfg(target_arch = "x86_64")]
+mod cpuid;
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+pub mod devicetree;
+
+#[cfg(target_arch = "x86_64")]
+pub use self::cpuid::init_cpu;
+
+/// NUMA node, numbered as the firmware numbers proximity domains
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NumaNodeId(pub u32);
+
+impl fmt::Display for NumaNodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Relative distance between nodes as the SLIT defines it, in which a node is 10 from itself
+pub const LOCAL_DISTANCE: u8 = 10;
+/// Distance assumed between different nodes when the firmware gives none
+pub const REMOTE_DISTANCE: u8 = 20;
+
+/// Position of a CPU as reported by the hardware
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuPlace {
+    /// APIC ID, MPIDR or hart ID
+    pub hw_id: u32,
+    pub package: u32,
+    /// Core within the package
+    pub core: u32,
+    /// SMT thread within the core
+    pub thread: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuLocation {
+    pub package: u32,
+    pub core: u32,
+    pub thread: u32,
+    /// Node 0 unless the firmware describes NUMA affinity for this CPU
+    pub node: NumaNodeId,
+}
+
+struct Map {
+    cpus: BTreeMap<LogicalCpuId, CpuPlace>,
+    /// NUMA node of each hardware CPU ID
+    cpu_nodes: BTreeMap<u32, NumaNodeId>,
+    memory: Vec<(Range<usize>, NumaNodeId)>,
+    /// Distance from each of the first `localities` nodes to each of them, row by row
+    distances: Vec<u8>,
+    localities: usize,
+}
+
+pub struct CpuTopology {
+    map: RwLock<Map>,
+    /// Node of each logical CPU, readable without the lock by the frame allocator, which may run
+    /// while the map is being changed
+    nodes: [AtomicU32; MAX_CPU_COUNT],
+}
+
+impl CpuTopology {
+    pub const fn new() -> Self {
+        Self {
+            map: RwLock::new(Map {
+                cpus: BTreeMap::new(),
+                cpu_nodes: BTreeMap::new(),
+                memory: Vec::new(),
+                distances: Vec::new(),
+                localities: 0,
+            }),
+            nodes: [const { AtomicU32::new(0) }; MAX_CPU_COUNT],
+        }
+    }
+
+    pub fn add_cpu(&self, cpu: LogicalCpuId, place: CpuPlace) {
+        let mut map = self.map.write();
+        map.cpus.insert(cpu, place);
+        self.cache_node(cpu, map.locate(&place).node);
+    }
+
+    pub fn set_cpu_node(&self, hw_id: u32, node: NumaNodeId) {
+        let mut map = self.map.write();
+        map.cpu_nodes.insert(hw_id, node);
+        for (&cpu, place) in &map.cpus {
+            if place.hw_id == hw_id {
+                self.cache_node(cpu, node);
+            }
+        }
+    }
+
+    fn cache_node(&self, cpu: LogicalCpuId, node: NumaNodeId) {
+        if let Some(slot) = self.nodes.get(cpu.get() as usize) {
+            slot.store(node.0, Ordering::Relaxed);
+        }
+    }
+
+    /// Set the distances between the first `localities` nodes, given row by row
+    pub fn set_distances(&self, localities: usize, distances: Vec<u8>) {
+        debug_assert_eq!(distances.len(), localities * localities);
+        let mut map = self.map.write();
+        map.localities = localities;
+        map.distances = distances;
+    }
+
+    /// Relative cost of accessing memory of `to` from `from`
+    pub fn distance(&self, from: NumaNodeId, to: NumaNodeId) -> u8 {
+        let map = self.map.read();
+        let (from, to) = (from.0 as usize, to.0 as usize);
+        if from < map.localities && to < map.localities {
+            map.distances[from * map.localities + to]
+        } else if from == to {
+            LOCAL_DISTANCE
+        } else {
+            REMOTE_DISTANCE
+        }
+    }
+
+    /// Record that the physical addresses in `range` are local to `node`
+    pub fn add_memory(&self, range: Range<usize>, node: NumaNodeId) {
+        self.map.write().memory.push((range, node));
+    }
+
+    pub fn location(&self, cpu: LogicalCpuId) -> Option<CpuLocation> {
+        let map = self.map.read();
+        map.cpus.get(&cpu).map(|place| map.locate(place))
+    }
+
+    /// All CPUs that have been registered, in logical ID order
+    pub fn cpus(&self) -> Vec<(LogicalCpuId, CpuLocation)> {
+        let map = self.map.read();
+        map.cpus
+            .iter()
+            .map(|(&cpu, place)| (cpu, map.locate(place)))
+            .collect()
+    }
+
+    /// Memory ranges with the node each is local to, in the order they were added
+    pub fn memory(&self) -> Vec<(Range<usize>, NumaNodeId)> {
+        self.map.read().memory.clone()
+    }
+
+    /// Other SMT threads of the core `cpu` runs on
+    pub fn siblings_of(&self, cpu: LogicalCpuId) -> CpuSet {
+        let map = self.map.read();
+        let mut siblings = CpuSet::new();
+        let Some(place) = map.cpus.get(&cpu) else {
+            return siblings;
+        };
+        for (&other, other_place) in &map.cpus {
+            if other != cpu
+                && other_place.package == place.package
+                && other_place.core == place.core
+            {
+                siblings.add(other);
+            }
+        }
+        siblings
+    }
+
+    /// Node the physical address `addr` is local to, if the firmware described it
+    pub fn node_of_address(&self, addr: usize) -> Option<NumaNodeId> {
+        self.map
+            .read()
+            .memory
+            .iter()
+            .find(|(range, _)| range.contains(&addr))
+            .map(|&(_, node)| node)
+    }
+
+    /// Whether any memory is known to be local to `node`
+    pub fn node_has_memory(&self, node: NumaNodeId) -> bool {
+        self.map.read().memory.iter().any(|&(_, n)| n == node)
+    }
+}
+
+impl Map {
+    fn locate(&self, place: &CpuPlace) -> CpuLocation {
+        CpuLocation {
+            package: place.package,
+            core: place.core,
+            thread: place.thread,
+            node: self
+                .cpu_nodes
+                .get(&place.hw_id)
+                .copied()
+                .unwrap_or_default(),
+        }
+    }
+}
+
+pub static CPU_TOPOLOGY: CpuTopology = CpuTopology::new();
+
+/// Other SMT threads of the core `cpu` runs on, which share its caches
+pub fn siblings_of(cpu: LogicalCpuId) -> CpuSet {
+    CPU_TOPOLOGY.siblings_of(cpu)
+}
+
+/// NUMA node of `cpu`, node 0 if unknown
+///
+/// Takes no lock, so that the frame allocator can use it.
+pub fn node_of(cpu: LogicalCpuId) -> NumaNodeId {
+    CPU_TOPOLOGY
+        .nodes
+        .get(cpu.get() as usize)
+        .map_or(NumaNodeId::default(), |node| {
+            NumaNodeId(node.load(Ordering::Relaxed))
+        })
+}
 
 pub fn thread_set_affinity(
     pid: usize,
@@ -24,3 +246,101 @@ pub fn thread_set_affinity(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn place(hw_id: u32, package: u32, core: u32, thread: u32) -> CpuPlace {
+        CpuPlace {
+            hw_id,
+            package,
+            core,
+            thread,
+        }
+    }
+
+    /// Two packages of two cores with two threads each
+    fn topology() -> CpuTopology {
+        let topology = CpuTopology::new();
+        for id in 0..8 {
+            topology.add_cpu(
+                LogicalCpuId::new(id),
+                place(id, id / 4, (id / 2) % 2, id % 2),
+            );
+        }
+        topology
+    }
+
+    #[test]
+    fn siblings_share_a_core() {
+        let topology = topology();
+        let siblings = topology.siblings_of(LogicalCpuId::new(2));
+        assert!(siblings.contains(LogicalCpuId::new(3)));
+        assert!(!siblings.contains(LogicalCpuId::new(2)));
+        for other in [0, 1, 4, 5, 6, 7] {
+            assert!(!siblings.contains(LogicalCpuId::new(other)));
+        }
+
+        assert_eq!(topology.siblings_of(LogicalCpuId::new(42)), CpuSet::new());
+    }
+
+    #[test]
+    fn nodes_default_to_zero() {
+        let topology = topology();
+        topology.set_cpu_node(5, NumaNodeId(1));
+
+        assert_eq!(
+            topology.location(LogicalCpuId::new(5)),
+            Some(CpuLocation {
+                package: 1,
+                core: 0,
+                thread: 1,
+                node: NumaNodeId(1),
+            })
+        );
+        assert_eq!(
+            topology.location(LogicalCpuId::new(4)).map(|l| l.node),
+            Some(NumaNodeId(0))
+        );
+        assert_eq!(topology.location(LogicalCpuId::new(8)), None);
+        assert_eq!(
+            topology.nodes[5].load(Ordering::Relaxed),
+            1,
+            "lock-free node cache missed the update"
+        );
+    }
+
+    #[test]
+    fn distances_default_without_slit() {
+        let topology = CpuTopology::new();
+        assert_eq!(
+            topology.distance(NumaNodeId(3), NumaNodeId(3)),
+            LOCAL_DISTANCE
+        );
+        assert_eq!(
+            topology.distance(NumaNodeId(0), NumaNodeId(3)),
+            REMOTE_DISTANCE
+        );
+
+        topology.set_distances(2, alloc::vec![10, 21, 21, 10]);
+        assert_eq!(topology.distance(NumaNodeId(1), NumaNodeId(0)), 21);
+        assert_eq!(
+            topology.distance(NumaNodeId(1), NumaNodeId(2)),
+            REMOTE_DISTANCE
+        );
+    }
+
+    #[test]
+    fn memory_ranges_map_to_nodes() {
+        let topology = CpuTopology::new();
+        topology.add_memory(0..0x8000_0000, NumaNodeId(0));
+        topology.add_memory(0x1_0000_0000..0x2_0000_0000, NumaNodeId(1));
+
+        assert_eq!(topology.node_of_address(0x1000), Some(NumaNodeId(0)));
+        assert_eq!(topology.node_of_address(0x1_8000_0000), Some(NumaNodeId(1)));
+        assert_eq!(topology.node_of_address(0x9000_0000), None);
+        assert!(topology.node_has_memory(NumaNodeId(1)));
+        assert!(!topology.node_has_memory(NumaNodeId(2)));
+    }
+}
@@ -0,0 +1,237 @@
+//! # Lockup Detector
+//!
+//! A CPU spinning with interrupts disabled takes no timer interrupts, while the rest of the
+//! system keeps running until it needs that CPU. So every CPU records a heartbeat in its
+//! [`PercpuBlock`] from the timer interrupt, and once a second whichever CPU gets there first
+//! checks the heartbeats of the others. A CPU whose heartbeat is older than the threshold is
+//! reported as stalled, along with the context it was last running.
+//!
+//! On x86_64 the stalled CPU is then sent an NMI, which gets through with interrupts disabled,
+//! to log its registers and a backtrace of the code it is stuck in.
+//!
+//! The `nowatchdog` boot parameter disables the detector, and `watchdog_thresh` sets the
+//! threshold in seconds, 0 disabling it as well.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::{
+    context,
+    cpu_set::LogicalCpuId,
+    percpu::{self, PercpuBlock},
+    startup::params,
+    time::monotonic,
+};
+
+const NS_PER_SEC: u64 = 1_000_000_000;
+/// Threshold used unless `watchdog_thresh` is given
+const DEFAULT_THRESHOLD_SECS: u64 = 10;
+/// Time between checks of the heartbeats
+pub const CHECK_INTERVAL_NS: u64 = NS_PER_SEC;
+/// Attempts at taking the console locks from the NMI handler
+#[cfg(target_arch = "x86_64")]
+const CONSOLE_TRIES: usize = 1 << 20;
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+static THRESHOLD_NS: AtomicU64 = AtomicU64::new(DEFAULT_THRESHOLD_SECS * NS_PER_SEC);
+/// Time of the last check, which any CPU may do
+static LAST_CHECK: AtomicU64 = AtomicU64::new(0);
+
+/// The watchdog state of a CPU
+#[derive(Debug, Default)]
+pub struct Heartbeat {
+    /// Time of the last timer interrupt, 0 before the first one
+    last: AtomicU64,
+    /// The context running at the last timer interrupt
+    context_id: AtomicUsize,
+    /// Set once a stall has been reported, until the CPU recovers
+    reported: AtomicBool,
+    /// Set before the NMI asking the CPU to dump its state is sent
+    nmi_requested: AtomicBool,
+}
+
+/// Apply the watchdog boot parameters
+pub fn init_from_params() {
+    if params::param_bool("nowatchdog") == Some(true) {
+        info!("Watchdog: disabled by nowatchdog");
+        ENABLED.store(false, Ordering::Relaxed);
+    }
+    match params::param_u64("watchdog_thresh") {
+        Some(0) => {
+            info!("Watchdog: disabled by watchdog_thresh=0");
+            ENABLED.store(false, Ordering::Relaxed);
+        }
+        Some(secs) => {
+            info!("Watchdog: threshold {}s", secs);
+            THRESHOLD_NS.store(secs.saturating_mul(NS_PER_SEC), Ordering::Relaxed);
+        }
+        None => {}
+    }
+}
+
+/// Whether the detector is on, in which case CPUs must take a timer interrupt at least every
+/// [`CHECK_INTERVAL_NS`], even when idle
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Record a heartbeat for this CPU, and check the other CPUs if that is due. Called from the
+/// timer interrupt.
+pub fn tick() {
+    if !enabled() {
+        return;
+    }
+    let now = monotonic() as u64;
+    let percpu = PercpuBlock::current();
+    let heartbeat = &percpu.watchdog;
+
+    heartbeat.last.store(now, Ordering::Relaxed);
+    heartbeat
+        .context_id
+        .store(percpu.context_id.get(), Ordering::Relaxed);
+    if heartbeat.reported.swap(false, Ordering::Relaxed) {
+        warn!("CPU {} is no longer stalled", percpu.cpu_id);
+    }
+
+    let last_check = LAST_CHECK.load(Ordering::Relaxed);
+    if now.saturating_sub(last_check) >= CHECK_INTERVAL_NS
+        && LAST_CHECK
+            .compare_exchange(last_check, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+    {
+        check(now);
+    }
+}
+
+/// Whether a CPU last beating at `last` has stalled by `now`. CPUs that never took a timer
+/// interrupt are not watched.
+fn is_stalled(last: u64, now: u64, threshold: u64) -> bool {
+    last != 0 && now.saturating_sub(last) >= threshold
+}
+
+/// Report the other CPUs that stalled since the last check
+fn check(now: u64) {
+    let threshold = THRESHOLD_NS.load(Ordering::Relaxed);
+    let current = crate::cpu_id();
+
+    for id in 0..crate::cpu_count() {
+        let cpu = LogicalCpuId::new(id);
+        let Some(block) = percpu::get_percpu_block(cpu).filter(|_| cpu != current) else {
+            continue;
+        };
+        let heartbeat = &block.watchdog;
+        let last = heartbeat.last.load(Ordering::Relaxed);
+        if !is_stalled(last, now, threshold) || heartbeat.reported.swap(true, Ordering::Relaxed) {
+            continue;
+        }
+
+        // The stalled CPU may hold the context list or the context, so neither is waited for
+        let context_id = heartbeat.context_id.load(Ordering::Relaxed);
+        let name = context::name_of(context_id);
+        warn!(
+            "CPU {} stalled: no timer interrupt for {}s, last running {} (ID {})",
+            cpu,
+            now.saturating_sub(last) / NS_PER_SEC,
+            name.as_deref().unwrap_or("<unknown>"),
+            context_id
+        );
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            heartbeat.nmi_requested.store(true, Ordering::Release);
+            crate::ipi::ipi_nmi(block);
+        }
+    }
+}
+
+/// Whether another CPU asked this one to dump its state, clearing the request. Called from the
+/// NMI handler.
+pub fn take_nmi_request() -> bool {
+    PercpuBlock::current()
+        .watchdog
+        .nmi_requested
+        .swap(false, Ordering::Acquire)
+}
+
+/// Log the registers of this CPU and a backtrace of the code the NMI interrupted.
+///
+/// Runs in NMI context, so no lock the stalled CPU may be holding is waited for. The console is
+/// only used if its locks can be taken for a while without waiting, as another CPU holding them
+/// lets go soon but this one never does, and frames are only followed as long as they stay
+/// within the kernel stack the NMI arrived on.
+#[cfg(target_arch = "x86_64")]
+pub fn dump_stalled(stack: &crate::interrupt::InterruptStack) {
+    use core::{fmt::Write, mem::size_of};
+
+    let Some(mut out) = (0..CONSOLE_TRIES).find_map(|_| {
+        let writer = crate::log::Writer::try_new();
+        if writer.is_none() {
+            core::hint::spin_loop();
+        }
+        writer
+    }) else {
+        return;
+    };
+
+    let _ = writeln!(out, "CPU {} stalled, interrupted at:", crate::cpu_id());
+    let _ = stack.dump_to(&mut out);
+    print_pc(&mut out, stack.rip as usize);
+
+    let sp = stack.stack_pointer();
+    let Some(kstack) = context::kstack::stack_containing(sp) else {
+        let _ = writeln!(out, "  <not on a kernel stack, no backtrace>");
+        return;
+    };
+    let mut fp = stack.frame_pointer();
+    for _ in 0..64 {
+        let frame_end = fp.saturating_add(2 * size_of::<usize>());
+        if fp < sp || fp % size_of::<usize>() != 0 || frame_end > kstack.end {
+            break;
+        }
+        let (next_fp, pc) = unsafe {
+            (
+                *(fp as *const usize),
+                *(fp.saturating_add(size_of::<usize>()) as *const usize),
+            )
+        };
+        if pc == 0 {
+            break;
+        }
+        print_pc(&mut out, pc);
+        // Each caller's frame is above its callee's
+        if next_fp <= fp {
+            break;
+        }
+        fp = next_fp;
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn print_pc(out: &mut crate::log::Writer, pc: usize) {
+    use core::fmt::Write;
+
+    let _ = writeln!(out, "  PC {:>016x}", pc);
+    #[cfg(feature = "ksyms")]
+    if let Some((name, offset)) = crate::ksyms::symbolize(pc) {
+        let _ = writeln!(
+            out,
+            "    {:#}+{:#x}",
+            rustc_demangle::demangle(name),
+            offset
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchdog_is_stalled() {
+        let threshold = DEFAULT_THRESHOLD_SECS * NS_PER_SEC;
+        assert!(!is_stalled(0, 100 * NS_PER_SEC, threshold));
+        assert!(!is_stalled(NS_PER_SEC, 10 * NS_PER_SEC, threshold));
+        assert!(is_stalled(NS_PER_SEC, 11 * NS_PER_SEC, threshold));
+        // A heartbeat newer than the check, from a CPU racing with it
+        assert!(!is_stalled(2 * NS_PER_SEC, NS_PER_SEC, threshold));
+    }
+}